@@ -20,3 +20,40 @@ pub enum DomainError {
 }
 
 pub type Result<T> = std::result::Result<T, DomainError>;
+
+impl DomainError {
+    /// Whether the error can go away by itself, e.g. a locked SQLite database, or a Postgres
+    /// server restarting: the operation can be tried again.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            DomainError::DatabaseError(error) => is_transient_database_error(error),
+            _ => false,
+        }
+    }
+
+    /// Whether the statement surely didn't run: unlike a lost connection, where a write may
+    /// have been committed, the operation can be tried again without doing it twice.
+    pub fn is_transient_before_execution(&self) -> bool {
+        match self {
+            DomainError::DatabaseError(sqlx::Error::Io(_)) => false,
+            error => error.is_transient(),
+        }
+    }
+}
+
+fn is_transient_database_error(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::PoolTimedOut | sqlx::Error::Io(_) => true,
+        sqlx::Error::Database(error) => match error.code() {
+            Some(code) => matches!(
+                code.as_ref(),
+                // SQLite: SQLITE_BUSY, SQLITE_LOCKED, and their extended codes.
+                "5" | "6" | "261" | "517" | "262"
+                // Postgres: serialization failure, deadlock, shutting down or starting up.
+                | "40001" | "40P01" | "57P01" | "57P02" | "57P03"
+            ),
+            None => false,
+        },
+        _ => false,
+    }
+}
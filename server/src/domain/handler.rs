@@ -1,9 +1,44 @@
 use super::error::*;
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::{collections::HashSet, sync::Mutex};
+use tokio::sync::watch;
+use uuid::Uuid;
 
-#[derive(PartialEq, Eq, Clone, Debug, Default, Serialize, Deserialize)]
+/// Namespace of the (name-based) UUIDs of the entries.
+pub const ENTRY_UUID_NAMESPACE: Uuid = Uuid::from_bytes([
+    0x9c, 0x3f, 0x6e, 0x0a, 0x5b, 0x1d, 0x4c, 0x8e, 0xa2, 0xf7, 0x3d, 0x61, 0xb0, 0x5e, 0x8a, 0x94,
+]);
+
+/// A stable UUID for an entry, derived from its kind and ID.
+pub fn make_entry_uuid(kind: &str, id: &str) -> String {
+    Uuid::new_v5(&ENTRY_UUID_NAMESPACE, format!("{}:{}", kind, id).as_bytes()).to_string()
+}
+
+/// The number of POSIX IDs of the users: the uidNumber of a user is the start of the range plus
+/// its `User::posix_id`.
+pub const POSIX_ID_RANGE: u32 = 1 << 30;
+
+/// The first POSIX ID from `start` that no user has, continuing from 0 at the end of the range.
+pub fn next_free_posix_id(used: &HashSet<i32>, start: i32) -> i32 {
+    let mut posix_id = start;
+    while used.contains(&posix_id) {
+        posix_id = (posix_id + 1) % POSIX_ID_RANGE as i32;
+    }
+    posix_id
+}
+
+/// The POSIX ID of a new user: the one after the highest one, so that the new users don't get
+/// the IDs of the deleted users, and their files, unless the last user created was deleted.
+pub fn allocate_posix_id(used: &HashSet<i32>) -> i32 {
+    let start = used
+        .iter()
+        .max()
+        .map_or(0, |posix_id| (posix_id + 1) % POSIX_ID_RANGE as i32);
+    next_free_posix_id(used, start)
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Default, Serialize, Deserialize)]
 #[cfg_attr(not(target_arch = "wasm32"), derive(sqlx::FromRow))]
 #[serde(from = "String")]
 pub struct UserId(String);
@@ -34,7 +69,7 @@ impl From<String> for UserId {
     }
 }
 
-#[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
 #[cfg_attr(not(target_arch = "wasm32"), derive(sqlx::FromRow))]
 pub struct User {
     pub user_id: UserId,
@@ -44,6 +79,11 @@ pub struct User {
     pub last_name: String,
     // pub avatar: ?,
     pub creation_date: chrono::DateTime<chrono::Utc>,
+    /// Set when the user is created, and kept when it is renamed.
+    pub uuid: String,
+    /// The position of the uidNumber of the user in the POSIX ID range: allocated when the user
+    /// is created, see `allocate_posix_id`, and kept when it is renamed.
+    pub posix_id: i32,
 }
 
 impl Default for User {
@@ -56,11 +96,22 @@ impl Default for User {
             first_name: String::new(),
             last_name: String::new(),
             creation_date: chrono::Utc.timestamp(0, 0),
+            uuid: String::new(),
+            posix_id: 0,
         }
     }
 }
 
-#[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+/// The state of the password of a user, for the administrators.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct PasswordMetadata {
+    /// When the password was last set, if it was after this started being tracked.
+    pub changed_time: Option<chrono::DateTime<chrono::Utc>>,
+    /// Whether a password reset was requested by email and not completed yet.
+    pub reset_pending: bool,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
 pub struct Group {
     pub id: GroupId,
     pub display_name: String,
@@ -73,6 +124,43 @@ pub struct BindRequest {
     pub password: String,
 }
 
+/// A substring assertion: the value starts with `initial`, contains all of `any` in order, and
+/// ends with `final_`.
+#[derive(PartialEq, Eq, Hash, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SubStringFilter {
+    pub initial: Option<String>,
+    pub any: Vec<String>,
+    pub final_: Option<String>,
+}
+
+impl SubStringFilter {
+    /// Case-insensitive match of the value.
+    pub fn matches(&self, value: &str) -> bool {
+        let value = value.to_lowercase();
+        let mut rest = value.as_str();
+        if let Some(initial) = &self.initial {
+            match rest.strip_prefix(initial.to_lowercase().as_str()) {
+                Some(r) => rest = r,
+                None => return false,
+            }
+        }
+        if let Some(final_) = &self.final_ {
+            match rest.strip_suffix(final_.to_lowercase().as_str()) {
+                Some(r) => rest = r,
+                None => return false,
+            }
+        }
+        for any in &self.any {
+            let any = any.to_lowercase();
+            match rest.find(any.as_str()) {
+                Some(i) => rest = &rest[i + any.len()..],
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
 pub enum UserRequestFilter {
     And(Vec<UserRequestFilter>),
@@ -80,18 +168,20 @@ pub enum UserRequestFilter {
     Not(Box<UserRequestFilter>),
     UserId(UserId),
     Equality(String, String),
+    SubString(String, SubStringFilter),
     // Check if a user belongs to a group identified by name.
     MemberOf(String),
     // Same, by id.
     MemberOfId(GroupId),
 }
 
-#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+#[derive(PartialEq, Eq, Hash, Debug, Serialize, Deserialize, Clone)]
 pub enum GroupRequestFilter {
     And(Vec<GroupRequestFilter>),
     Or(Vec<GroupRequestFilter>),
     Not(Box<GroupRequestFilter>),
     DisplayName(String),
+    DisplayNameSubString(SubStringFilter),
     GroupId(GroupId),
     // Check if the group contains a user identified by uid.
     Member(UserId),
@@ -134,14 +224,64 @@ pub struct GroupId(pub i32);
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, sqlx::FromRow)]
 pub struct GroupIdAndName(pub GroupId, pub String);
 
+/// Receives the ID of the last change of the users, the groups or the memberships: see
+/// `BackendHandler::subscribe_to_changes`.
+pub type ChangeReceiver = watch::Receiver<u64>;
+
+/// Counts the changes made through a backend, and notifies its subscribers.
+#[derive(Debug)]
+pub struct ChangeNotifier {
+    sender: Mutex<watch::Sender<u64>>,
+    /// Keeps the channel open while nobody is subscribed, so that the last ID is not lost.
+    receiver: ChangeReceiver,
+}
+
+impl ChangeNotifier {
+    /// The IDs start from the current time in microseconds, so that they keep increasing across
+    /// restarts.
+    pub fn new() -> Self {
+        let first_id = u64::try_from(chrono::Utc::now().timestamp_nanos() / 1000).unwrap_or(0);
+        let (sender, receiver) = watch::channel(first_id);
+        Self {
+            sender: Mutex::new(sender),
+            receiver,
+        }
+    }
+
+    pub fn notify(&self) {
+        let sender = self.sender.lock().unwrap();
+        let next_id = *self.receiver.borrow() + 1;
+        // The channel can't be closed: we hold a receiver.
+        let _ = sender.send(next_id);
+    }
+
+    pub fn subscribe(&self) -> ChangeReceiver {
+        self.receiver.clone()
+    }
+}
+
+impl Default for ChangeNotifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[async_trait]
 pub trait BackendHandler: Clone + Send {
     async fn list_users(&self, filters: Option<UserRequestFilter>) -> Result<Vec<User>>;
+    /// Same as `list_users`, for at most `limit` users with an ID greater than `after`.
+    async fn list_users_batch(
+        &self,
+        filters: Option<UserRequestFilter>,
+        after: Option<UserId>,
+        limit: usize,
+    ) -> Result<Vec<User>>;
     async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>>;
     async fn get_user_details(&self, user_id: &UserId) -> Result<User>;
     async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName>;
     async fn create_user(&self, request: CreateUserRequest) -> Result<()>;
     async fn update_user(&self, request: UpdateUserRequest) -> Result<()>;
+    async fn rename_user(&self, user_id: &UserId, new_user_id: &UserId) -> Result<()>;
     async fn update_group(&self, request: UpdateGroupRequest) -> Result<()>;
     async fn delete_user(&self, user_id: &UserId) -> Result<()>;
     async fn create_group(&self, group_name: &str) -> Result<GroupId>;
@@ -149,6 +289,13 @@ pub trait BackendHandler: Clone + Send {
     async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
     async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
     async fn get_user_groups(&self, user_id: &UserId) -> Result<HashSet<GroupIdAndName>>;
+    async fn get_password_metadata(&self, user_id: &UserId) -> Result<PasswordMetadata>;
+    async fn get_user_avatar(&self, user_id: &UserId) -> Result<Option<Vec<u8>>>;
+    /// Notified after every change made through this handler. The handlers that don't track
+    /// their changes never notify.
+    fn subscribe_to_changes(&self) -> ChangeReceiver {
+        watch::channel(0).1
+    }
 }
 
 #[cfg(test)]
@@ -160,16 +307,25 @@ mockall::mock! {
     #[async_trait]
     impl BackendHandler for TestBackendHandler {
         async fn list_users(&self, filters: Option<UserRequestFilter>) -> Result<Vec<User>>;
+        async fn list_users_batch(
+            &self,
+            filters: Option<UserRequestFilter>,
+            after: Option<UserId>,
+            limit: usize,
+        ) -> Result<Vec<User>>;
         async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>>;
         async fn get_user_details(&self, user_id: &UserId) -> Result<User>;
         async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName>;
         async fn create_user(&self, request: CreateUserRequest) -> Result<()>;
         async fn update_user(&self, request: UpdateUserRequest) -> Result<()>;
+        async fn rename_user(&self, user_id: &UserId, new_user_id: &UserId) -> Result<()>;
         async fn update_group(&self, request: UpdateGroupRequest) -> Result<()>;
         async fn delete_user(&self, user_id: &UserId) -> Result<()>;
         async fn create_group(&self, group_name: &str) -> Result<GroupId>;
         async fn delete_group(&self, group_id: GroupId) -> Result<()>;
         async fn get_user_groups(&self, user_id: &UserId) -> Result<HashSet<GroupIdAndName>>;
+        async fn get_password_metadata(&self, user_id: &UserId) -> Result<PasswordMetadata>;
+        async fn get_user_avatar(&self, user_id: &UserId) -> Result<Option<Vec<u8>>>;
         async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
         async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
     }
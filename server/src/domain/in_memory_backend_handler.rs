@@ -0,0 +1,392 @@
+//! A backend keeping the users and groups in memory, to test the protocol handlers against a
+//! real behavior without a database: e.g. create the users, then drive an `LdapHandler` with
+//! `handle_ldap_message` and check the responses.
+//!
+//! The passwords are kept in clear and only checked by the simple binds: the OPAQUE logins are
+//! not supported.
+use super::{
+    error::*,
+    handler::{
+        allocate_posix_id, make_entry_uuid, BackendHandler, BindRequest, ChangeNotifier,
+        ChangeReceiver, CreateUserRequest, Group, GroupId, GroupIdAndName, GroupRequestFilter,
+        LoginHandler, PasswordMetadata, UpdateGroupRequest, UpdateUserRequest, User, UserId,
+        UserRequestFilter,
+    },
+    opaque_handler::{login, registration, OpaqueHandler},
+};
+use async_trait::async_trait;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+/// The users and groups, by ID: sorted like in the database.
+#[derive(Default)]
+struct State {
+    users: BTreeMap<String, User>,
+    passwords: HashMap<UserId, String>,
+    groups: BTreeMap<i32, Group>,
+}
+
+/// The clones share the same users and groups.
+#[derive(Clone, Default)]
+pub struct InMemoryBackendHandler {
+    state: Arc<Mutex<State>>,
+    changes: Arc<ChangeNotifier>,
+}
+
+fn not_found() -> DomainError {
+    DomainError::DatabaseError(sqlx::Error::RowNotFound)
+}
+
+fn user_field<'a>(user: &'a User, field: &str) -> Option<&'a str> {
+    Some(match field {
+        "email" => &user.email,
+        "display_name" => &user.display_name,
+        "first_name" => &user.first_name,
+        "last_name" => &user.last_name,
+        "uuid" => &user.uuid,
+        _ => return None,
+    })
+}
+
+fn user_matches(state: &State, filter: &UserRequestFilter, user: &User) -> bool {
+    let member_of = |group: &Group| group.users.contains(&user.user_id);
+    match filter {
+        UserRequestFilter::And(filters) => filters.iter().all(|f| user_matches(state, f, user)),
+        UserRequestFilter::Or(filters) => filters.iter().any(|f| user_matches(state, f, user)),
+        UserRequestFilter::Not(filter) => !user_matches(state, filter, user),
+        UserRequestFilter::UserId(user_id) => *user_id == user.user_id,
+        // The display name is compared case-insensitively, like in the database.
+        UserRequestFilter::Equality(field, value) if field == "display_name" => {
+            user.display_name.eq_ignore_ascii_case(value)
+        }
+        UserRequestFilter::Equality(field, value) => {
+            user_field(user, field) == Some(value.as_str())
+        }
+        UserRequestFilter::SubString(field, substring) => {
+            user_field(user, field).map_or(false, |value| substring.matches(value))
+        }
+        UserRequestFilter::MemberOf(name) => state
+            .groups
+            .values()
+            .any(|group| group.display_name == *name && member_of(group)),
+        UserRequestFilter::MemberOfId(id) => state.groups.get(&id.0).map_or(false, member_of),
+    }
+}
+
+fn group_matches(filter: &GroupRequestFilter, group: &Group) -> bool {
+    match filter {
+        GroupRequestFilter::And(filters) => filters.iter().all(|f| group_matches(f, group)),
+        GroupRequestFilter::Or(filters) => filters.iter().any(|f| group_matches(f, group)),
+        GroupRequestFilter::Not(filter) => !group_matches(filter, group),
+        GroupRequestFilter::DisplayName(name) => *name == group.display_name,
+        GroupRequestFilter::DisplayNameSubString(substring) => {
+            substring.matches(&group.display_name)
+        }
+        GroupRequestFilter::GroupId(id) => *id == group.id,
+        GroupRequestFilter::Member(user_id) => group.users.contains(user_id),
+    }
+}
+
+impl InMemoryBackendHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the password checked by the simple binds of the user.
+    pub fn set_password(&self, user_id: &UserId, password: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .passwords
+            .insert(user_id.clone(), password.to_string());
+    }
+
+    /// Applies a change, and notifies the subscribers if it succeeded.
+    fn write<T>(&self, change: impl FnOnce(&mut State) -> Result<T>) -> Result<T> {
+        let result = change(&mut self.state.lock().unwrap());
+        if result.is_ok() {
+            self.changes.notify();
+        }
+        result
+    }
+
+    fn get_user_mut<'a>(state: &'a mut State, user_id: &UserId) -> Result<&'a mut User> {
+        state.users.get_mut(user_id.as_str()).ok_or_else(not_found)
+    }
+}
+
+#[async_trait]
+impl BackendHandler for InMemoryBackendHandler {
+    async fn list_users(&self, filters: Option<UserRequestFilter>) -> Result<Vec<User>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .users
+            .values()
+            .filter(|user| {
+                filters
+                    .as_ref()
+                    .map_or(true, |filter| user_matches(&state, filter, user))
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn list_users_batch(
+        &self,
+        filters: Option<UserRequestFilter>,
+        after: Option<UserId>,
+        limit: usize,
+    ) -> Result<Vec<User>> {
+        Ok(self
+            .list_users(filters)
+            .await?
+            .into_iter()
+            .filter(|user| {
+                after
+                    .as_ref()
+                    .map_or(true, |after| user.user_id.as_str() > after.as_str())
+            })
+            .take(limit)
+            .collect())
+    }
+
+    async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .groups
+            .values()
+            .filter(|group| {
+                filters
+                    .as_ref()
+                    .map_or(true, |filter| group_matches(filter, group))
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn get_user_details(&self, user_id: &UserId) -> Result<User> {
+        let state = self.state.lock().unwrap();
+        state
+            .users
+            .get(user_id.as_str())
+            .cloned()
+            .ok_or_else(not_found)
+    }
+
+    async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName> {
+        let state = self.state.lock().unwrap();
+        let group = state.groups.get(&group_id.0).ok_or_else(not_found)?;
+        Ok(GroupIdAndName(group.id, group.display_name.clone()))
+    }
+
+    async fn create_user(&self, request: CreateUserRequest) -> Result<()> {
+        self.write(|state| {
+            if state.users.contains_key(request.user_id.as_str()) {
+                return Err(DomainError::InternalError(format!(
+                    "User `{}` already exists",
+                    request.user_id
+                )));
+            }
+            let used_posix_ids = state.users.values().map(|u| u.posix_id).collect();
+            let user = User {
+                uuid: make_entry_uuid("user", request.user_id.as_str()),
+                posix_id: allocate_posix_id(&used_posix_ids),
+                user_id: request.user_id.clone(),
+                email: request.email,
+                display_name: request.display_name.unwrap_or_default(),
+                first_name: request.first_name.unwrap_or_default(),
+                last_name: request.last_name.unwrap_or_default(),
+                creation_date: chrono::Utc::now(),
+            };
+            state.users.insert(request.user_id.into_string(), user);
+            Ok(())
+        })
+    }
+
+    async fn update_user(&self, request: UpdateUserRequest) -> Result<()> {
+        self.write(|state| {
+            let user = Self::get_user_mut(state, &request.user_id)?;
+            if let Some(email) = request.email {
+                user.email = email;
+            }
+            if let Some(display_name) = request.display_name {
+                user.display_name = display_name;
+            }
+            if let Some(first_name) = request.first_name {
+                user.first_name = first_name;
+            }
+            if let Some(last_name) = request.last_name {
+                user.last_name = last_name;
+            }
+            Ok(())
+        })
+    }
+
+    async fn rename_user(&self, user_id: &UserId, new_user_id: &UserId) -> Result<()> {
+        self.write(|state| {
+            if state.users.contains_key(new_user_id.as_str()) {
+                return Err(DomainError::InternalError(format!(
+                    "User `{}` already exists",
+                    new_user_id
+                )));
+            }
+            let mut user = state.users.remove(user_id.as_str()).ok_or_else(not_found)?;
+            user.user_id = new_user_id.clone();
+            state.users.insert(new_user_id.to_string(), user);
+            if let Some(password) = state.passwords.remove(user_id) {
+                state.passwords.insert(new_user_id.clone(), password);
+            }
+            for group in state.groups.values_mut() {
+                for member in group.users.iter_mut().filter(|member| **member == *user_id) {
+                    *member = new_user_id.clone();
+                }
+            }
+            Ok(())
+        })
+    }
+
+    async fn update_group(&self, request: UpdateGroupRequest) -> Result<()> {
+        self.write(|state| {
+            let group = state
+                .groups
+                .get_mut(&request.group_id.0)
+                .ok_or_else(not_found)?;
+            if let Some(display_name) = request.display_name {
+                group.display_name = display_name;
+            }
+            Ok(())
+        })
+    }
+
+    async fn delete_user(&self, user_id: &UserId) -> Result<()> {
+        self.write(|state| {
+            state.users.remove(user_id.as_str()).ok_or_else(not_found)?;
+            state.passwords.remove(user_id);
+            for group in state.groups.values_mut() {
+                group.users.retain(|member| member != user_id);
+            }
+            Ok(())
+        })
+    }
+
+    async fn create_group(&self, group_name: &str) -> Result<GroupId> {
+        self.write(|state| {
+            let id = GroupId(state.groups.keys().last().map_or(1, |id| id + 1));
+            state.groups.insert(
+                id.0,
+                Group {
+                    id,
+                    display_name: group_name.to_string(),
+                    users: vec![],
+                },
+            );
+            Ok(id)
+        })
+    }
+
+    async fn delete_group(&self, group_id: GroupId) -> Result<()> {
+        self.write(|state| {
+            state.groups.remove(&group_id.0).ok_or_else(not_found)?;
+            Ok(())
+        })
+    }
+
+    async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()> {
+        self.write(|state| {
+            if !state.users.contains_key(user_id.as_str()) {
+                return Err(not_found());
+            }
+            let group = state.groups.get_mut(&group_id.0).ok_or_else(not_found)?;
+            if !group.users.contains(user_id) {
+                group.users.push(user_id.clone());
+                group.users.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+            }
+            Ok(())
+        })
+    }
+
+    async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()> {
+        self.write(|state| {
+            let group = state.groups.get_mut(&group_id.0).ok_or_else(not_found)?;
+            group.users.retain(|member| member != user_id);
+            Ok(())
+        })
+    }
+
+    async fn get_user_groups(&self, user_id: &UserId) -> Result<HashSet<GroupIdAndName>> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .groups
+            .values()
+            .filter(|group| group.users.contains(user_id))
+            .map(|group| GroupIdAndName(group.id, group.display_name.clone()))
+            .collect())
+    }
+
+    async fn get_password_metadata(&self, user_id: &UserId) -> Result<PasswordMetadata> {
+        self.get_user_details(user_id).await?;
+        Ok(PasswordMetadata::default())
+    }
+
+    async fn get_user_avatar(&self, user_id: &UserId) -> Result<Option<Vec<u8>>> {
+        self.get_user_details(user_id).await?;
+        Ok(None)
+    }
+
+    fn subscribe_to_changes(&self) -> ChangeReceiver {
+        self.changes.subscribe()
+    }
+}
+
+#[async_trait]
+impl LoginHandler for InMemoryBackendHandler {
+    async fn bind(&self, request: BindRequest) -> Result<()> {
+        match self.state.lock().unwrap().passwords.get(&request.name) {
+            Some(password) if *password == request.password => Ok(()),
+            _ => Err(DomainError::AuthenticationError(format!(
+                " for user '{}'",
+                request.name
+            ))),
+        }
+    }
+}
+
+fn opaque_not_supported<T>() -> Result<T> {
+    Err(DomainError::InternalError(
+        "The in-memory backend doesn't support OPAQUE".to_string(),
+    ))
+}
+
+#[async_trait]
+impl OpaqueHandler for InMemoryBackendHandler {
+    async fn login_start(
+        &self,
+        _request: login::ClientLoginStartRequest,
+    ) -> Result<login::ServerLoginStartResponse> {
+        opaque_not_supported()
+    }
+
+    async fn login_finish(&self, _request: login::ClientLoginFinishRequest) -> Result<UserId> {
+        opaque_not_supported()
+    }
+
+    async fn registration_start(
+        &self,
+        _request: registration::ClientRegistrationStartRequest,
+    ) -> Result<registration::ServerRegistrationStartResponse> {
+        opaque_not_supported()
+    }
+
+    async fn registration_finish(
+        &self,
+        _request: registration::ClientRegistrationFinishRequest,
+    ) -> Result<()> {
+        opaque_not_supported()
+    }
+}
@@ -1,6 +1,10 @@
 pub mod error;
 pub mod handler;
+#[cfg(test)]
+pub mod in_memory_backend_handler;
 pub mod opaque_handler;
+pub mod retrying_backend_handler;
+pub mod routing_backend_handler;
 pub mod sql_backend_handler;
 pub mod sql_opaque_handler;
 pub mod sql_tables;
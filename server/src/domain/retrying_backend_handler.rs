@@ -0,0 +1,372 @@
+//! A backend that tries the operations again on the transient database errors, e.g. a locked
+//! SQLite database or a Postgres failover, instead of failing them right away.
+use super::{
+    error::*,
+    handler::{
+        BackendHandler, BindRequest, ChangeReceiver, CreateUserRequest, Group, GroupId,
+        GroupIdAndName, GroupRequestFilter, LoginHandler, PasswordMetadata, UpdateGroupRequest,
+        UpdateUserRequest, User, UserId, UserRequestFilter,
+    },
+    opaque_handler::{login, registration, OpaqueHandler},
+};
+use async_trait::async_trait;
+use log::warn;
+use rand::Rng;
+use std::{collections::HashSet, future::Future, time::Duration};
+
+/// How many times, and how long to wait before each new attempt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryOptions {
+    /// The number of attempts after the first one: 0 never retries.
+    pub retries: u32,
+    /// The delay before the first retry, doubled for each following one.
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryOptions {
+    /// The exponential backoff, with a random jitter so that the connections that failed
+    /// together don't retry together.
+    fn get_delay(&self, retry: u32) -> Duration {
+        let delay = self
+            .initial_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_delay);
+        delay / 2 + delay.mul_f64(rand::thread_rng().gen_range(0.0..0.5))
+    }
+}
+
+/// Retries the transient errors of `backend`. The reads are retried on all of them, the writes
+/// only when the statement surely didn't run, so that they are not applied twice: the writes of
+/// several statements must run in a transaction. The other errors (not found, constraints...)
+/// are returned right away.
+#[derive(Clone)]
+pub struct RetryingBackendHandler<Backend> {
+    backend: Backend,
+    options: RetryOptions,
+}
+
+impl<Backend> RetryingBackendHandler<Backend> {
+    pub fn new(backend: Backend, options: RetryOptions) -> Self {
+        Self { backend, options }
+    }
+
+    async fn retry<T, F, Fut>(&self, name: &str, is_write: bool, mut operation: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut retry = 0;
+        loop {
+            match operation().await {
+                Err(e)
+                    if retry < self.options.retries
+                        && (if is_write {
+                            e.is_transient_before_execution()
+                        } else {
+                            e.is_transient()
+                        }) =>
+                {
+                    let delay = self.options.get_delay(retry);
+                    retry += 1;
+                    warn!(
+                        "Transient error during {}, retrying in {:?} ({}/{}): {}",
+                        name, delay, retry, self.options.retries, e
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn retry_read<T, F, Fut>(&self, name: &str, operation: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        self.retry(name, false, operation).await
+    }
+
+    async fn retry_write<T, F, Fut>(&self, name: &str, operation: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        self.retry(name, true, operation).await
+    }
+}
+
+#[async_trait]
+impl<Backend> BackendHandler for RetryingBackendHandler<Backend>
+where
+    Backend: BackendHandler + Sync,
+{
+    async fn list_users(&self, filters: Option<UserRequestFilter>) -> Result<Vec<User>> {
+        let backend = &self.backend;
+        self.retry_read("list_users", move || backend.list_users(filters.clone()))
+            .await
+    }
+
+    async fn list_users_batch(
+        &self,
+        filters: Option<UserRequestFilter>,
+        after: Option<UserId>,
+        limit: usize,
+    ) -> Result<Vec<User>> {
+        let backend = &self.backend;
+        self.retry_read("list_users_batch", move || {
+            backend.list_users_batch(filters.clone(), after.clone(), limit)
+        })
+        .await
+    }
+
+    async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>> {
+        let backend = &self.backend;
+        self.retry_read("list_groups", move || backend.list_groups(filters.clone()))
+            .await
+    }
+
+    async fn get_user_details(&self, user_id: &UserId) -> Result<User> {
+        let backend = &self.backend;
+        self.retry_read("get_user_details", move || {
+            backend.get_user_details(user_id)
+        })
+        .await
+    }
+
+    async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName> {
+        let backend = &self.backend;
+        self.retry_read("get_group_details", move || {
+            backend.get_group_details(group_id)
+        })
+        .await
+    }
+
+    async fn create_user(&self, request: CreateUserRequest) -> Result<()> {
+        let backend = &self.backend;
+        self.retry_write("create_user", move || backend.create_user(request.clone()))
+            .await
+    }
+
+    async fn update_user(&self, request: UpdateUserRequest) -> Result<()> {
+        let backend = &self.backend;
+        self.retry_write("update_user", move || backend.update_user(request.clone()))
+            .await
+    }
+
+    async fn rename_user(&self, user_id: &UserId, new_user_id: &UserId) -> Result<()> {
+        let backend = &self.backend;
+        self.retry_write("rename_user", move || {
+            backend.rename_user(user_id, new_user_id)
+        })
+        .await
+    }
+
+    async fn update_group(&self, request: UpdateGroupRequest) -> Result<()> {
+        let backend = &self.backend;
+        self.retry_write("update_group", move || {
+            backend.update_group(request.clone())
+        })
+        .await
+    }
+
+    async fn delete_user(&self, user_id: &UserId) -> Result<()> {
+        let backend = &self.backend;
+        self.retry_write("delete_user", move || backend.delete_user(user_id))
+            .await
+    }
+
+    async fn create_group(&self, group_name: &str) -> Result<GroupId> {
+        let backend = &self.backend;
+        self.retry_write("create_group", move || backend.create_group(group_name))
+            .await
+    }
+
+    async fn delete_group(&self, group_id: GroupId) -> Result<()> {
+        let backend = &self.backend;
+        self.retry_write("delete_group", move || backend.delete_group(group_id))
+            .await
+    }
+
+    async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()> {
+        let backend = &self.backend;
+        self.retry_write("add_user_to_group", move || {
+            backend.add_user_to_group(user_id, group_id)
+        })
+        .await
+    }
+
+    async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()> {
+        let backend = &self.backend;
+        self.retry_write("remove_user_from_group", move || {
+            backend.remove_user_from_group(user_id, group_id)
+        })
+        .await
+    }
+
+    async fn get_user_groups(&self, user_id: &UserId) -> Result<HashSet<GroupIdAndName>> {
+        let backend = &self.backend;
+        self.retry_read("get_user_groups", move || backend.get_user_groups(user_id))
+            .await
+    }
+
+    async fn get_password_metadata(&self, user_id: &UserId) -> Result<PasswordMetadata> {
+        let backend = &self.backend;
+        self.retry_read("get_password_metadata", move || {
+            backend.get_password_metadata(user_id)
+        })
+        .await
+    }
+
+    async fn get_user_avatar(&self, user_id: &UserId) -> Result<Option<Vec<u8>>> {
+        let backend = &self.backend;
+        self.retry_read("get_user_avatar", move || backend.get_user_avatar(user_id))
+            .await
+    }
+
+    fn subscribe_to_changes(&self) -> ChangeReceiver {
+        self.backend.subscribe_to_changes()
+    }
+}
+
+#[async_trait]
+impl<Backend> LoginHandler for RetryingBackendHandler<Backend>
+where
+    Backend: LoginHandler + Sync,
+{
+    async fn bind(&self, request: BindRequest) -> Result<()> {
+        let backend = &self.backend;
+        self.retry_read("bind", move || backend.bind(request.clone()))
+            .await
+    }
+}
+
+#[async_trait]
+impl<Backend> OpaqueHandler for RetryingBackendHandler<Backend>
+where
+    Backend: OpaqueHandler + Sync,
+{
+    async fn login_start(
+        &self,
+        request: login::ClientLoginStartRequest,
+    ) -> Result<login::ServerLoginStartResponse> {
+        let backend = &self.backend;
+        self.retry_read("login_start", move || backend.login_start(request.clone()))
+            .await
+    }
+
+    async fn login_finish(&self, request: login::ClientLoginFinishRequest) -> Result<UserId> {
+        let backend = &self.backend;
+        self.retry_read("login_finish", move || {
+            backend.login_finish(request.clone())
+        })
+        .await
+    }
+
+    async fn registration_start(
+        &self,
+        request: registration::ClientRegistrationStartRequest,
+    ) -> Result<registration::ServerRegistrationStartResponse> {
+        let backend = &self.backend;
+        self.retry_read("registration_start", move || {
+            backend.registration_start(request.clone())
+        })
+        .await
+    }
+
+    async fn registration_finish(
+        &self,
+        request: registration::ClientRegistrationFinishRequest,
+    ) -> Result<()> {
+        let backend = &self.backend;
+        self.retry_write("registration_finish", move || {
+            backend.registration_finish(request.clone())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::handler::MockTestBackendHandler;
+    use mockall::predicate::eq;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    fn make_options(retries: u32) -> RetryOptions {
+        RetryOptions {
+            retries,
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(2),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transient_errors_are_retried() {
+        let mut mock = MockTestBackendHandler::new();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counter = attempts.clone();
+        mock.expect_get_user_details()
+            .with(eq(UserId::new("bob")))
+            .times(3)
+            .returning(move |_| {
+                if counter.fetch_add(1, Ordering::SeqCst) < 2 {
+                    Err(DomainError::DatabaseError(sqlx::Error::PoolTimedOut))
+                } else {
+                    Ok(User::default())
+                }
+            });
+        let handler = RetryingBackendHandler::new(mock, make_options(2));
+        handler.get_user_details(&UserId::new("bob")).await.unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retries_are_bounded() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .times(3)
+            .returning(|_| Err(DomainError::DatabaseError(sqlx::Error::PoolTimedOut)));
+        let handler = RetryingBackendHandler::new(mock, make_options(2));
+        assert!(handler.list_groups(None).await.unwrap_err().is_transient());
+    }
+
+    #[tokio::test]
+    async fn test_other_errors_are_not_retried() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_user_details()
+            .times(1)
+            .returning(|_| Err(DomainError::DatabaseError(sqlx::Error::RowNotFound)));
+        // A write may have been applied before the connection was lost.
+        mock.expect_delete_user().times(1).returning(|_| {
+            Err(DomainError::DatabaseError(sqlx::Error::Io(
+                std::io::ErrorKind::ConnectionReset.into(),
+            )))
+        });
+        let handler = RetryingBackendHandler::new(mock, make_options(2));
+        handler
+            .get_user_details(&UserId::new("bob"))
+            .await
+            .unwrap_err();
+        handler.delete_user(&UserId::new("bob")).await.unwrap_err();
+    }
+
+    #[test]
+    fn test_get_delay() {
+        let options = RetryOptions {
+            retries: 5,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+        };
+        let delay = options.get_delay(0);
+        assert!(delay >= Duration::from_millis(50) && delay < Duration::from_millis(100));
+        let delay = options.get_delay(2);
+        assert!(delay >= Duration::from_millis(200) && delay < Duration::from_millis(400));
+        let delay = options.get_delay(10);
+        assert!(delay >= Duration::from_millis(500) && delay < Duration::from_millis(1000));
+    }
+}
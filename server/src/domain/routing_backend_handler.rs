@@ -0,0 +1,194 @@
+//! A backend that sends the reads and the writes to different handlers, e.g. to serve the
+//! searches from a read replica of the database, and apply the changes to the primary.
+use super::{
+    error::*,
+    handler::{
+        BackendHandler, BindRequest, ChangeReceiver, CreateUserRequest, Group, GroupId,
+        GroupIdAndName, GroupRequestFilter, LoginHandler, PasswordMetadata, UpdateGroupRequest,
+        UpdateUserRequest, User, UserId, UserRequestFilter,
+    },
+    opaque_handler::{login, registration, OpaqueHandler},
+};
+use async_trait::async_trait;
+use std::collections::HashSet;
+
+/// Reads from `read`, writes to `write`. The logins only read the password files, but the
+/// password registrations write them.
+///
+/// The reads can lag behind the writes, by the replication delay of the database.
+#[derive(Clone)]
+pub struct RoutingBackendHandler<Read, Write> {
+    read: Read,
+    write: Write,
+}
+
+impl<Read, Write> RoutingBackendHandler<Read, Write> {
+    pub fn new(read: Read, write: Write) -> Self {
+        Self { read, write }
+    }
+}
+
+#[async_trait]
+impl<Read, Write> BackendHandler for RoutingBackendHandler<Read, Write>
+where
+    Read: BackendHandler + Sync,
+    Write: BackendHandler + Sync,
+{
+    async fn list_users(&self, filters: Option<UserRequestFilter>) -> Result<Vec<User>> {
+        self.read.list_users(filters).await
+    }
+
+    async fn list_users_batch(
+        &self,
+        filters: Option<UserRequestFilter>,
+        after: Option<UserId>,
+        limit: usize,
+    ) -> Result<Vec<User>> {
+        self.read.list_users_batch(filters, after, limit).await
+    }
+
+    async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>> {
+        self.read.list_groups(filters).await
+    }
+
+    async fn get_user_details(&self, user_id: &UserId) -> Result<User> {
+        self.read.get_user_details(user_id).await
+    }
+
+    async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName> {
+        self.read.get_group_details(group_id).await
+    }
+
+    async fn create_user(&self, request: CreateUserRequest) -> Result<()> {
+        self.write.create_user(request).await
+    }
+
+    async fn update_user(&self, request: UpdateUserRequest) -> Result<()> {
+        self.write.update_user(request).await
+    }
+
+    async fn rename_user(&self, user_id: &UserId, new_user_id: &UserId) -> Result<()> {
+        self.write.rename_user(user_id, new_user_id).await
+    }
+
+    async fn update_group(&self, request: UpdateGroupRequest) -> Result<()> {
+        self.write.update_group(request).await
+    }
+
+    async fn delete_user(&self, user_id: &UserId) -> Result<()> {
+        self.write.delete_user(user_id).await
+    }
+
+    async fn create_group(&self, group_name: &str) -> Result<GroupId> {
+        self.write.create_group(group_name).await
+    }
+
+    async fn delete_group(&self, group_id: GroupId) -> Result<()> {
+        self.write.delete_group(group_id).await
+    }
+
+    async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()> {
+        self.write.add_user_to_group(user_id, group_id).await
+    }
+
+    async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()> {
+        self.write.remove_user_from_group(user_id, group_id).await
+    }
+
+    async fn get_user_groups(&self, user_id: &UserId) -> Result<HashSet<GroupIdAndName>> {
+        self.read.get_user_groups(user_id).await
+    }
+
+    async fn get_password_metadata(&self, user_id: &UserId) -> Result<PasswordMetadata> {
+        self.read.get_password_metadata(user_id).await
+    }
+
+    async fn get_user_avatar(&self, user_id: &UserId) -> Result<Option<Vec<u8>>> {
+        self.read.get_user_avatar(user_id).await
+    }
+
+    fn subscribe_to_changes(&self) -> ChangeReceiver {
+        self.write.subscribe_to_changes()
+    }
+}
+
+#[async_trait]
+impl<Read, Write> LoginHandler for RoutingBackendHandler<Read, Write>
+where
+    Read: LoginHandler + Sync,
+    Write: Clone + Send + Sync,
+{
+    async fn bind(&self, request: BindRequest) -> Result<()> {
+        self.read.bind(request).await
+    }
+}
+
+#[async_trait]
+impl<Read, Write> OpaqueHandler for RoutingBackendHandler<Read, Write>
+where
+    Read: OpaqueHandler + Sync,
+    Write: OpaqueHandler + Sync,
+{
+    async fn login_start(
+        &self,
+        request: login::ClientLoginStartRequest,
+    ) -> Result<login::ServerLoginStartResponse> {
+        self.read.login_start(request).await
+    }
+
+    async fn login_finish(&self, request: login::ClientLoginFinishRequest) -> Result<UserId> {
+        self.read.login_finish(request).await
+    }
+
+    async fn registration_start(
+        &self,
+        request: registration::ClientRegistrationStartRequest,
+    ) -> Result<registration::ServerRegistrationStartResponse> {
+        self.write.registration_start(request).await
+    }
+
+    async fn registration_finish(
+        &self,
+        request: registration::ClientRegistrationFinishRequest,
+    ) -> Result<()> {
+        self.write.registration_finish(request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::handler::MockTestBackendHandler;
+    use mockall::predicate::eq;
+
+    #[tokio::test]
+    async fn test_reads_and_writes_are_routed() {
+        let mut read = MockTestBackendHandler::new();
+        read.expect_get_user_details()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .return_once(|_| Ok(User::default()));
+        read.expect_list_groups()
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut write = MockTestBackendHandler::new();
+        write
+            .expect_delete_user()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .return_once(|_| Ok(()));
+        write
+            .expect_add_user_to_group()
+            .with(eq(UserId::new("bob")), eq(GroupId(1)))
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        let handler = RoutingBackendHandler::new(read, write);
+        handler.get_user_details(&UserId::new("bob")).await.unwrap();
+        handler.list_groups(None).await.unwrap();
+        handler
+            .add_user_to_group(&UserId::new("bob"), GroupId(1))
+            .await
+            .unwrap();
+        handler.delete_user(&UserId::new("bob")).await.unwrap();
+    }
+}
@@ -1,82 +1,191 @@
 use super::{error::*, handler::*, sql_tables::*};
-use crate::infra::configuration::Configuration;
+use crate::infra::{
+    configuration::Configuration, group_cache::GroupCache, jwt_sql_tables::PasswordResetTokens,
+};
 use async_trait::async_trait;
 use futures_util::StreamExt;
-use sea_query::{Expr, Iden, Order, Query, SimpleExpr};
+use sea_query::{Expr, Iden, Order, Query, SelectStatement, SimpleExpr};
 use sqlx::Row;
-use std::collections::HashSet;
+use std::{collections::HashSet, sync::Arc};
+use tracing::instrument;
 
 #[derive(Debug, Clone)]
 pub struct SqlBackendHandler {
     pub(crate) config: Configuration,
     pub(crate) sql_pool: Pool,
+    group_cache: Option<Arc<GroupCache>>,
+    /// Shared by the clones, so that the changes made through any of them are notified.
+    pub(crate) changes: Arc<ChangeNotifier>,
 }
 
 impl SqlBackendHandler {
     pub fn new(config: Configuration, sql_pool: Pool) -> Self {
-        SqlBackendHandler { config, sql_pool }
+        SqlBackendHandler {
+            config,
+            sql_pool,
+            group_cache: None,
+            changes: Arc::new(ChangeNotifier::new()),
+        }
+    }
+
+    /// The cache is invalidated on every change of the groups or memberships, whether it comes
+    /// from the web UI or from LDAP.
+    pub fn with_group_cache(mut self, group_cache: Arc<GroupCache>) -> Self {
+        self.group_cache = Some(group_cache);
+        self
+    }
+
+    fn invalidate_group_cache(&self) {
+        if let Some(cache) = &self.group_cache {
+            cache.invalidate();
+        }
+    }
+
+    async fn fetch_users(&self, query: &str) -> Result<Vec<User>> {
+        let results = sqlx::query_as::<_, User>(query)
+            .fetch(&self.sql_pool)
+            .collect::<Vec<sqlx::Result<User>>>()
+            .await;
+
+        Ok(results.into_iter().collect::<sqlx::Result<Vec<User>>>()?)
+    }
+}
+
+/// The query of `list_users`, ordered by user ID. None if the filter never matches.
+///
+/// Only the columns of `User` are read: the avatars, the largest values, are read one by one
+/// with `get_user_avatar`, for the searches asking for them.
+fn get_list_users_query(filters: Option<UserRequestFilter>) -> Option<SelectStatement> {
+    let mut query_builder = Query::select()
+        .column((Users::Table, Users::UserId))
+        .column(Users::Email)
+        .column((Users::Table, Users::DisplayName))
+        .column(Users::FirstName)
+        .column(Users::LastName)
+        .column(Users::CreationDate)
+        .column(Users::Uuid)
+        .column(Users::PosixId)
+        .from(Users::Table)
+        .order_by((Users::Table, Users::UserId), Order::Asc)
+        .to_owned();
+    if let Some(filter) = filters {
+        if filter == UserRequestFilter::Not(Box::new(UserRequestFilter::And(Vec::new()))) {
+            return None;
+        }
+        if filter != UserRequestFilter::And(Vec::new())
+            && filter != UserRequestFilter::Or(Vec::new())
+        {
+            query_builder.and_where(get_user_filter_expr(filter));
+        }
     }
+    Some(query_builder)
 }
 
-struct RequiresGroup(bool);
+// Escapes the LIKE wildcards (with a backslash) and the quotes of a substring.
+fn escape_like_pattern(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+        .replace('\'', "''")
+}
 
-// Returns the condition for the SQL query, and whether it requires joining with the groups table.
-fn get_user_filter_expr(filter: UserRequestFilter) -> (RequiresGroup, SimpleExpr) {
+// Returns the LIKE condition for a substring filter on the given column. SQLite's LIKE is
+// case-insensitive (for ASCII characters), which matches the LDAP caseIgnoreMatch.
+fn get_substring_filter_expr(column: &str, filter: SubStringFilter) -> SimpleExpr {
+    let mut pattern = filter
+        .initial
+        .as_deref()
+        .map(escape_like_pattern)
+        .unwrap_or_default();
+    for any in &filter.any {
+        pattern.push('%');
+        pattern.push_str(&escape_like_pattern(any));
+    }
+    pattern.push('%');
+    if let Some(final_) = &filter.final_ {
+        pattern.push_str(&escape_like_pattern(final_));
+    }
+    Expr::cust(&format!("{} LIKE '{}' ESCAPE '\\'", column, pattern))
+}
+
+// Returns the case-insensitive equality condition on the given column, with LIKE as for the
+// substrings, so that e.g. "cn=john smith" finds "John Smith".
+fn get_case_ignore_equality_expr(column: &str, value: &str) -> SimpleExpr {
+    Expr::cust(&format!(
+        "{} LIKE '{}' ESCAPE '\\'",
+        column,
+        escape_like_pattern(value)
+    ))
+}
+
+// Returns the condition for the SQL query.
+fn get_user_filter_expr(filter: UserRequestFilter) -> SimpleExpr {
     use UserRequestFilter::*;
     fn get_repeated_filter(
         fs: Vec<UserRequestFilter>,
         field: &dyn Fn(SimpleExpr, SimpleExpr) -> SimpleExpr,
-    ) -> (RequiresGroup, SimpleExpr) {
-        let mut requires_group = false;
+    ) -> SimpleExpr {
         let mut it = fs.into_iter();
         let first_expr = match it.next() {
-            None => return (RequiresGroup(false), Expr::value(true)),
-            Some(f) => {
-                let (group, filter) = get_user_filter_expr(f);
-                requires_group |= group.0;
-                filter
-            }
+            None => return Expr::value(true),
+            Some(f) => get_user_filter_expr(f),
         };
-        let filter = it.fold(first_expr, |e, f| {
-            let (group, filters) = get_user_filter_expr(f);
-            requires_group |= group.0;
-            field(e, filters)
-        });
-        (RequiresGroup(requires_group), filter)
+        it.fold(first_expr, |e, f| field(e, get_user_filter_expr(f)))
     }
     match filter {
         And(fs) => get_repeated_filter(fs, &SimpleExpr::and),
         Or(fs) => get_repeated_filter(fs, &SimpleExpr::or),
-        Not(f) => {
-            let (requires_group, filters) = get_user_filter_expr(*f);
-            (requires_group, Expr::not(Expr::expr(filters)))
-        }
-        UserId(user_id) => (
-            RequiresGroup(false),
-            Expr::col((Users::Table, Users::UserId)).eq(user_id),
-        ),
-        Equality(s1, s2) => (
-            RequiresGroup(false),
+        Not(f) => Expr::not(Expr::expr(get_user_filter_expr(*f))),
+        UserId(user_id) => Expr::col((Users::Table, Users::UserId)).eq(user_id),
+        Equality(s1, s2) => {
             if s1 == Users::DisplayName.to_string() {
-                Expr::col((Users::Table, Users::DisplayName)).eq(s2)
+                get_case_ignore_equality_expr(
+                    &format!(
+                        "{}.{}",
+                        Users::Table.to_string(),
+                        Users::DisplayName.to_string()
+                    ),
+                    &s2,
+                )
             } else if s1 == Users::UserId.to_string() {
                 panic!("User id should be wrapped")
             } else {
                 Expr::expr(Expr::cust(&s1)).eq(s2)
-            },
-        ),
-        MemberOf(group) => (
-            RequiresGroup(true),
-            Expr::col((Groups::Table, Groups::DisplayName)).eq(group),
+            }
+        }
+        SubString(field, filter) => get_substring_filter_expr(&field, filter),
+        // The memberships are checked with subqueries rather than a join, so that negations and
+        // conjunctions of memberships apply to the user rather than to each membership.
+        // WHERE (user_id in (SELECT user_id FROM memberships WHERE group_id in
+        //   (SELECT group_id FROM groups WHERE display_name = group)))
+        MemberOf(group) => Expr::col((Users::Table, Users::UserId)).in_subquery(
+            Query::select()
+                .column(Memberships::UserId)
+                .from(Memberships::Table)
+                .and_where(
+                    Expr::col(Memberships::GroupId).in_subquery(
+                        Query::select()
+                            .column(Groups::GroupId)
+                            .from(Groups::Table)
+                            .and_where(Expr::col(Groups::DisplayName).eq(group))
+                            .take(),
+                    ),
+                )
+                .take(),
         ),
-        MemberOfId(group_id) => (
-            RequiresGroup(true),
-            Expr::col((Groups::Table, Groups::GroupId)).eq(group_id),
+        // WHERE (user_id in (SELECT user_id FROM memberships WHERE group_id = group_id))
+        MemberOfId(group_id) => Expr::col((Users::Table, Users::UserId)).in_subquery(
+            Query::select()
+                .column(Memberships::UserId)
+                .from(Memberships::Table)
+                .and_where(Expr::col(Memberships::GroupId).eq(group_id))
+                .take(),
         ),
     }
 }
 
-// Returns the condition for the SQL query, and whether it requires joining with the groups table.
+// Returns the condition for the SQL query.
 fn get_group_filter_expr(filter: GroupRequestFilter) -> SimpleExpr {
     use GroupRequestFilter::*;
     fn get_repeated_filter(
@@ -94,7 +203,22 @@ fn get_group_filter_expr(filter: GroupRequestFilter) -> SimpleExpr {
         And(fs) => get_repeated_filter(fs, &SimpleExpr::and),
         Or(fs) => get_repeated_filter(fs, &SimpleExpr::or),
         Not(f) => Expr::not(Expr::expr(get_group_filter_expr(*f))),
-        DisplayName(name) => Expr::col((Groups::Table, Groups::DisplayName)).eq(name),
+        DisplayName(name) => get_case_ignore_equality_expr(
+            &format!(
+                "{}.{}",
+                Groups::Table.to_string(),
+                Groups::DisplayName.to_string()
+            ),
+            &name,
+        ),
+        DisplayNameSubString(filter) => get_substring_filter_expr(
+            &format!(
+                "{}.{}",
+                Groups::Table.to_string(),
+                Groups::DisplayName.to_string()
+            ),
+            filter,
+        ),
         GroupId(id) => Expr::col((Groups::Table, Groups::GroupId)).eq(id.0),
         // WHERE (group_id in (SELECT group_id FROM memberships WHERE user_id = user))
         Member(user) => Expr::col((Memberships::Table, Memberships::GroupId)).in_subquery(
@@ -109,55 +233,36 @@ fn get_group_filter_expr(filter: GroupRequestFilter) -> SimpleExpr {
 
 #[async_trait]
 impl BackendHandler for SqlBackendHandler {
+    #[instrument(skip(self), level = "debug")]
     async fn list_users(&self, filters: Option<UserRequestFilter>) -> Result<Vec<User>> {
-        let query = {
-            let mut query_builder = Query::select()
-                .column((Users::Table, Users::UserId))
-                .column(Users::Email)
-                .column((Users::Table, Users::DisplayName))
-                .column(Users::FirstName)
-                .column(Users::LastName)
-                .column(Users::Avatar)
-                .column(Users::CreationDate)
-                .from(Users::Table)
-                .order_by((Users::Table, Users::UserId), Order::Asc)
-                .to_owned();
-            if let Some(filter) = filters {
-                if filter == UserRequestFilter::Not(Box::new(UserRequestFilter::And(Vec::new()))) {
-                    return Ok(Vec::new());
-                }
-                if filter != UserRequestFilter::And(Vec::new())
-                    && filter != UserRequestFilter::Or(Vec::new())
-                {
-                    let (RequiresGroup(requires_group), condition) = get_user_filter_expr(filter);
-                    query_builder.and_where(condition);
-                    if requires_group {
-                        query_builder
-                            .left_join(
-                                Memberships::Table,
-                                Expr::tbl(Users::Table, Users::UserId)
-                                    .equals(Memberships::Table, Memberships::UserId),
-                            )
-                            .left_join(
-                                Groups::Table,
-                                Expr::tbl(Memberships::Table, Memberships::GroupId)
-                                    .equals(Groups::Table, Groups::GroupId),
-                            );
-                    }
-                }
-            }
-
-            query_builder.to_string(DbQueryBuilder {})
+        let query = match get_list_users_query(filters) {
+            Some(query_builder) => query_builder.to_string(DbQueryBuilder {}),
+            None => return Ok(Vec::new()),
         };
+        self.fetch_users(&query).await
+    }
 
-        let results = sqlx::query_as::<_, User>(&query)
-            .fetch(&self.sql_pool)
-            .collect::<Vec<sqlx::Result<User>>>()
-            .await;
-
-        Ok(results.into_iter().collect::<sqlx::Result<Vec<User>>>()?)
+    #[instrument(skip(self), level = "debug")]
+    async fn list_users_batch(
+        &self,
+        filters: Option<UserRequestFilter>,
+        after: Option<UserId>,
+        limit: usize,
+    ) -> Result<Vec<User>> {
+        let mut query_builder = match get_list_users_query(filters) {
+            Some(query_builder) => query_builder,
+            None => return Ok(Vec::new()),
+        };
+        if let Some(after) = after {
+            query_builder.and_where(Expr::tbl(Users::Table, Users::UserId).gt(after));
+        }
+        let query = query_builder
+            .limit(limit as u64)
+            .to_string(DbQueryBuilder {});
+        self.fetch_users(&query).await
     }
 
+    #[instrument(skip(self), level = "debug")]
     async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>> {
         let query: String = {
             let mut query_builder = Query::select()
@@ -219,6 +324,7 @@ impl BackendHandler for SqlBackendHandler {
         Ok(groups)
     }
 
+    #[instrument(skip(self), level = "debug")]
     async fn get_user_details(&self, user_id: &UserId) -> Result<User> {
         let query = Query::select()
             .column(Users::UserId)
@@ -226,8 +332,9 @@ impl BackendHandler for SqlBackendHandler {
             .column(Users::DisplayName)
             .column(Users::FirstName)
             .column(Users::LastName)
-            .column(Users::Avatar)
             .column(Users::CreationDate)
+            .column(Users::Uuid)
+            .column(Users::PosixId)
             .from(Users::Table)
             .and_where(Expr::col(Users::UserId).eq(user_id))
             .to_string(DbQueryBuilder {});
@@ -237,6 +344,7 @@ impl BackendHandler for SqlBackendHandler {
             .await?)
     }
 
+    #[instrument(skip(self), level = "debug")]
     async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName> {
         let query = Query::select()
             .column(Groups::GroupId)
@@ -250,6 +358,7 @@ impl BackendHandler for SqlBackendHandler {
             .await?)
     }
 
+    #[instrument(skip(self), level = "debug")]
     async fn get_user_groups(&self, user_id: &UserId) -> Result<HashSet<GroupIdAndName>> {
         if *user_id == self.config.ldap_user_dn {
             let mut groups = HashSet::new();
@@ -288,6 +397,55 @@ impl BackendHandler for SqlBackendHandler {
             .map_err(DomainError::DatabaseError)
     }
 
+    #[instrument(skip(self), level = "debug")]
+    async fn get_password_metadata(&self, user_id: &UserId) -> Result<PasswordMetadata> {
+        let query = Query::select()
+            .column(Users::PasswordModifiedDate)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::UserId).eq(user_id))
+            .to_string(DbQueryBuilder {});
+        let changed_time = sqlx::query(&query)
+            .fetch_one(&self.sql_pool)
+            .await?
+            .get::<Option<chrono::DateTime<chrono::Utc>>, _>(
+                &*Users::PasswordModifiedDate.to_string(),
+            );
+        let query = Query::select()
+            .column(PasswordResetTokens::Token)
+            .from(PasswordResetTokens::Table)
+            .and_where(Expr::col(PasswordResetTokens::UserId).eq(user_id))
+            .and_where(
+                Expr::col(PasswordResetTokens::ExpiryDate).gt(chrono::Utc::now().naive_utc()),
+            )
+            .to_string(DbQueryBuilder {});
+        let reset_pending = sqlx::query(&query)
+            .fetch_optional(&self.sql_pool)
+            .await?
+            .is_some();
+        Ok(PasswordMetadata {
+            changed_time,
+            reset_pending,
+        })
+    }
+
+    #[instrument(skip(self), level = "debug")]
+    async fn get_user_avatar(&self, user_id: &UserId) -> Result<Option<Vec<u8>>> {
+        let query = Query::select()
+            .column(Users::Avatar)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::UserId).eq(user_id))
+            .to_string(DbQueryBuilder {});
+        Ok(sqlx::query(&query)
+            .fetch_one(&self.sql_pool)
+            .await?
+            .get::<Option<Vec<u8>>, _>(&*Users::Avatar.to_string()))
+    }
+
+    fn subscribe_to_changes(&self) -> ChangeReceiver {
+        self.changes.subscribe()
+    }
+
+    #[instrument(skip(self), level = "debug")]
     async fn create_user(&self, request: CreateUserRequest) -> Result<()> {
         let columns = vec![
             Users::UserId,
@@ -296,24 +454,51 @@ impl BackendHandler for SqlBackendHandler {
             Users::FirstName,
             Users::LastName,
             Users::CreationDate,
+            Users::Uuid,
+            Users::PosixId,
         ];
+        let creation_date = chrono::Utc::now();
+        // The ID and the creation time identify the user, even if another user had the same ID
+        // before being renamed.
+        let uuid = make_entry_uuid(
+            "user",
+            &format!("{}:{}", request.user_id, creation_date.timestamp_nanos()),
+        );
+        // In a transaction, so that two new users can't get the same POSIX ID.
+        let mut transaction = self.sql_pool.begin().await?;
+        let query = Query::select()
+            .column(Users::PosixId)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::PosixId).is_not_null())
+            .to_string(DbQueryBuilder {});
+        let used_posix_ids = sqlx::query_as::<_, (i32,)>(&query)
+            .fetch_all(&mut transaction)
+            .await?
+            .into_iter()
+            .map(|(posix_id,)| posix_id)
+            .collect::<HashSet<_>>();
         let values = vec![
             request.user_id.into(),
             request.email.into(),
             request.display_name.unwrap_or_default().into(),
             request.first_name.unwrap_or_default().into(),
             request.last_name.unwrap_or_default().into(),
-            chrono::Utc::now().naive_utc().into(),
+            creation_date.naive_utc().into(),
+            uuid.into(),
+            allocate_posix_id(&used_posix_ids).into(),
         ];
         let query = Query::insert()
             .into_table(Users::Table)
             .columns(columns)
             .values_panic(values)
             .to_string(DbQueryBuilder {});
-        sqlx::query(&query).execute(&self.sql_pool).await?;
+        sqlx::query(&query).execute(&mut transaction).await?;
+        transaction.commit().await?;
+        self.changes.notify();
         Ok(())
     }
 
+    #[instrument(skip(self), level = "debug")]
     async fn update_user(&self, request: UpdateUserRequest) -> Result<()> {
         let mut values = Vec::new();
         if let Some(email) = request.email {
@@ -337,9 +522,41 @@ impl BackendHandler for SqlBackendHandler {
             .and_where(Expr::col(Users::UserId).eq(request.user_id))
             .to_string(DbQueryBuilder {});
         sqlx::query(&query).execute(&self.sql_pool).await?;
+        self.changes.notify();
+        Ok(())
+    }
+
+    #[instrument(skip(self), level = "debug")]
+    async fn rename_user(&self, user_id: &UserId, new_user_id: &UserId) -> Result<()> {
+        // In a transaction, so that a failed rename can be tried again.
+        let mut transaction = self.sql_pool.begin().await?;
+        let query = Query::update()
+            .table(Users::Table)
+            .values(vec![(Users::UserId, new_user_id.into())])
+            .and_where(Expr::col(Users::UserId).eq(user_id))
+            .to_string(DbQueryBuilder {});
+        let result = sqlx::query(&query).execute(&mut transaction).await?;
+        if result.rows_affected() == 0 {
+            return Err(DomainError::InternalError(format!(
+                "No such user: `{}`",
+                user_id
+            )));
+        }
+        // The foreign keys cascade the update, but they are only enforced on the connections
+        // where they were enabled.
+        let query = Query::update()
+            .table(Memberships::Table)
+            .values(vec![(Memberships::UserId, new_user_id.into())])
+            .and_where(Expr::col(Memberships::UserId).eq(user_id))
+            .to_string(DbQueryBuilder {});
+        sqlx::query(&query).execute(&mut transaction).await?;
+        transaction.commit().await?;
+        self.invalidate_group_cache();
+        self.changes.notify();
         Ok(())
     }
 
+    #[instrument(skip(self), level = "debug")]
     async fn update_group(&self, request: UpdateGroupRequest) -> Result<()> {
         let mut values = Vec::new();
         if let Some(display_name) = request.display_name {
@@ -354,43 +571,59 @@ impl BackendHandler for SqlBackendHandler {
             .and_where(Expr::col(Groups::GroupId).eq(request.group_id))
             .to_string(DbQueryBuilder {});
         sqlx::query(&query).execute(&self.sql_pool).await?;
+        self.invalidate_group_cache();
+        self.changes.notify();
         Ok(())
     }
 
+    #[instrument(skip(self), level = "debug")]
     async fn delete_user(&self, user_id: &UserId) -> Result<()> {
         let delete_query = Query::delete()
             .from_table(Users::Table)
             .and_where(Expr::col(Users::UserId).eq(user_id))
             .to_string(DbQueryBuilder {});
         sqlx::query(&delete_query).execute(&self.sql_pool).await?;
+        self.invalidate_group_cache();
+        self.changes.notify();
         Ok(())
     }
 
+    #[instrument(skip(self), level = "debug")]
     async fn create_group(&self, group_name: &str) -> Result<GroupId> {
+        // In a transaction, so that a failure to read the ID doesn't leave the group behind, and
+        // the creation can be tried again.
+        let mut transaction = self.sql_pool.begin().await?;
         let query = Query::insert()
             .into_table(Groups::Table)
             .columns(vec![Groups::DisplayName])
             .values_panic(vec![group_name.into()])
             .to_string(DbQueryBuilder {});
-        sqlx::query(&query).execute(&self.sql_pool).await?;
+        sqlx::query(&query).execute(&mut transaction).await?;
         let query = Query::select()
             .column(Groups::GroupId)
             .from(Groups::Table)
             .and_where(Expr::col(Groups::DisplayName).eq(group_name))
             .to_string(DbQueryBuilder {});
-        let row = sqlx::query(&query).fetch_one(&self.sql_pool).await?;
+        let row = sqlx::query(&query).fetch_one(&mut transaction).await?;
+        transaction.commit().await?;
+        self.invalidate_group_cache();
+        self.changes.notify();
         Ok(GroupId(row.get::<i32, _>(&*Groups::GroupId.to_string())))
     }
 
+    #[instrument(skip(self), level = "debug")]
     async fn delete_group(&self, group_id: GroupId) -> Result<()> {
         let delete_query = Query::delete()
             .from_table(Groups::Table)
             .and_where(Expr::col(Groups::GroupId).eq(group_id))
             .to_string(DbQueryBuilder {});
         sqlx::query(&delete_query).execute(&self.sql_pool).await?;
+        self.invalidate_group_cache();
+        self.changes.notify();
         Ok(())
     }
 
+    #[instrument(skip(self), level = "debug")]
     async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()> {
         let query = Query::insert()
             .into_table(Memberships::Table)
@@ -398,9 +631,12 @@ impl BackendHandler for SqlBackendHandler {
             .values_panic(vec![user_id.into(), group_id.into()])
             .to_string(DbQueryBuilder {});
         sqlx::query(&query).execute(&self.sql_pool).await?;
+        self.invalidate_group_cache();
+        self.changes.notify();
         Ok(())
     }
 
+    #[instrument(skip(self), level = "debug")]
     async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()> {
         let query = Query::delete()
             .from_table(Memberships::Table)
@@ -408,6 +644,8 @@ impl BackendHandler for SqlBackendHandler {
             .and_where(Expr::col(Memberships::UserId).eq(user_id))
             .to_string(DbQueryBuilder {});
         sqlx::query(&query).execute(&self.sql_pool).await?;
+        self.invalidate_group_cache();
+        self.changes.notify();
         Ok(())
     }
 }
@@ -605,6 +843,108 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_list_users_member_of() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "patrick", "pass").await;
+        insert_user(&handler, "John", "Pa33w0rd!").await;
+        let group_1 = insert_group(&handler, "Best Group").await;
+        let group_2 = insert_group(&handler, "Worst Group").await;
+        insert_membership(&handler, group_1, "bob").await;
+        insert_membership(&handler, group_1, "patrick").await;
+        insert_membership(&handler, group_2, "patrick").await;
+        let list_user_ids = |filter| {
+            let handler = handler.clone();
+            async move {
+                handler
+                    .list_users(Some(filter))
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|u| u.user_id.to_string())
+                    .collect::<Vec<_>>()
+            }
+        };
+        let member_of = |group: &str| UserRequestFilter::MemberOf(group.to_string());
+        assert_eq!(
+            list_user_ids(member_of("Best Group")).await,
+            vec!["bob", "patrick"]
+        );
+        assert_eq!(
+            list_user_ids(UserRequestFilter::MemberOfId(group_2)).await,
+            vec!["patrick"]
+        );
+        assert_eq!(
+            list_user_ids(UserRequestFilter::Not(Box::new(member_of("Worst Group")))).await,
+            vec!["bob", "john"]
+        );
+        assert_eq!(
+            list_user_ids(UserRequestFilter::And(vec![
+                member_of("Best Group"),
+                member_of("Worst Group"),
+            ]))
+            .await,
+            vec!["patrick"]
+        );
+        assert_eq!(
+            list_user_ids(UserRequestFilter::Or(vec![
+                member_of("Best Group"),
+                member_of("Worst Group"),
+            ]))
+            .await,
+            vec!["bob", "patrick"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_users_substring() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "patrick", "pass").await;
+        insert_user(&handler, "John", "Pa33w0rd!").await;
+        insert_user(&handler, "pat_2", "pass").await;
+        let list_user_ids = |initial: Option<&str>, any: &[&str], final_: Option<&str>| {
+            let handler = handler.clone();
+            let filter = UserRequestFilter::SubString(
+                "user_id".to_string(),
+                SubStringFilter {
+                    initial: initial.map(str::to_string),
+                    any: any.iter().map(|s| s.to_string()).collect(),
+                    final_: final_.map(str::to_string),
+                },
+            );
+            async move {
+                handler
+                    .list_users(Some(filter))
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|u| u.user_id.to_string())
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(
+            list_user_ids(Some("Pat"), &[], None).await,
+            vec!["pat_2", "patrick"]
+        );
+        assert_eq!(list_user_ids(None, &["o"], Some("n")).await, vec!["john"]);
+        assert_eq!(
+            list_user_ids(Some("p"), &["t", "c"], None).await,
+            vec!["patrick"]
+        );
+        // The wildcards are escaped.
+        assert_eq!(list_user_ids(None, &["_"], None).await, vec!["pat_2"]);
+        assert_eq!(
+            list_user_ids(None, &["%'"], None).await,
+            Vec::<String>::new()
+        );
+    }
+
     #[tokio::test]
     async fn test_list_groups() {
         let sql_pool = get_initialized_db().await;
@@ -788,4 +1128,274 @@ mod tests {
 
         assert_eq!(users, vec!["val"]);
     }
+
+    #[tokio::test]
+    async fn test_rename_user() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool.clone());
+        insert_user(&handler, "bob", "bob00").await;
+        let group_1 = insert_group(&handler, "Group1").await;
+        insert_membership(&handler, group_1, "bob").await;
+        let bob = handler.get_user_details(&UserId::new("bob")).await.unwrap();
+        let uuid = bob.uuid;
+
+        handler
+            .rename_user(&UserId::new("bob"), &UserId::new("robert"))
+            .await
+            .unwrap();
+
+        assert!(handler.get_user_details(&UserId::new("bob")).await.is_err());
+        let robert = handler
+            .get_user_details(&UserId::new("robert"))
+            .await
+            .unwrap();
+        assert_eq!(robert.user_id, UserId::new("robert"));
+        // And so does the uidNumber.
+        assert_eq!(robert.posix_id, bob.posix_id);
+        let mut robert_groups = HashSet::new();
+        robert_groups.insert(GroupIdAndName(group_1, "Group1".to_string()));
+        assert_eq!(
+            handler
+                .get_user_groups(&UserId::new("robert"))
+                .await
+                .unwrap(),
+            robert_groups
+        );
+        assert!(handler
+            .rename_user(&UserId::new("bob"), &UserId::new("bobby"))
+            .await
+            .is_err());
+        // The UUID stays with the user, and can be used to find it.
+        assert_eq!(
+            handler
+                .list_users(Some(UserRequestFilter::Equality(
+                    "uuid".to_string(),
+                    uuid.clone()
+                )))
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|u| u.user_id)
+                .collect::<Vec<_>>(),
+            vec![UserId::new("robert")]
+        );
+        // A new user with the old ID gets another UUID and uidNumber.
+        insert_user_no_password(&handler, "bob").await;
+        let new_bob = handler.get_user_details(&UserId::new("bob")).await.unwrap();
+        assert_ne!(new_bob.uuid, uuid);
+        assert_ne!(new_bob.posix_id, bob.posix_id);
+    }
+
+    #[tokio::test]
+    async fn test_posix_ids() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        insert_user_no_password(&handler, "john").await;
+        insert_user_no_password(&handler, "patrick").await;
+        handler.delete_user(&UserId::new("john")).await.unwrap();
+        insert_user_no_password(&handler, "jim").await;
+        let posix_ids = handler
+            .list_users(None)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|u| (u.user_id.to_string(), u.posix_id))
+            .collect::<Vec<_>>();
+        // The new users don't take the IDs of the deleted ones.
+        assert_eq!(
+            posix_ids,
+            vec![
+                ("bob".to_string(), 0),
+                ("jim".to_string(), 3),
+                ("patrick".to_string(), 2)
+            ]
+        );
+        let used = [0, 1, 3, POSIX_ID_RANGE as i32 - 1]
+            .into_iter()
+            .collect::<HashSet<_>>();
+        assert_eq!(next_free_posix_id(&used, 3), 4);
+        // Continues from the start of the range.
+        assert_eq!(allocate_posix_id(&used), 2);
+    }
+
+    #[tokio::test]
+    async fn test_get_password_metadata() {
+        use crate::infra::tcp_backend_handler::TcpBackendHandler;
+        let sql_pool = get_initialized_db().await;
+        crate::infra::jwt_sql_tables::init_table(&sql_pool)
+            .await
+            .unwrap();
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        assert_eq!(
+            handler
+                .get_password_metadata(&UserId::new("bob"))
+                .await
+                .unwrap(),
+            PasswordMetadata::default()
+        );
+
+        let before = chrono::Utc::now() - chrono::Duration::seconds(1);
+        insert_user(&handler, "patrick", "pass").await;
+        handler
+            .start_password_reset(&UserId::new("patrick"))
+            .await
+            .unwrap()
+            .unwrap();
+        let metadata = handler
+            .get_password_metadata(&UserId::new("patrick"))
+            .await
+            .unwrap();
+        assert!(metadata.changed_time.unwrap() > before);
+        assert!(metadata.reset_pending);
+
+        assert!(handler
+            .get_password_metadata(&UserId::new("nobody"))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get_user_avatar() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool.clone());
+        insert_user_no_password(&handler, "bob").await;
+        insert_user_no_password(&handler, "patrick").await;
+        sqlx::query(
+            &Query::update()
+                .table(Users::Table)
+                .values(vec![(Users::Avatar, vec![0xFF, 0xD8, 0xFF, 0xD9].into())])
+                .and_where(Expr::col(Users::UserId).eq("bob"))
+                .to_string(DbQueryBuilder {}),
+        )
+        .execute(&sql_pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            handler.get_user_avatar(&UserId::new("bob")).await.unwrap(),
+            Some(vec![0xFF, 0xD8, 0xFF, 0xD9])
+        );
+        assert_eq!(
+            handler
+                .get_user_avatar(&UserId::new("patrick"))
+                .await
+                .unwrap(),
+            None
+        );
+        // The user listings don't read the avatars.
+        let query = get_list_users_query(None)
+            .unwrap()
+            .to_string(DbQueryBuilder {});
+        assert!(!query.contains("avatar"), "{}", query);
+        assert_eq!(
+            handler.list_users(None).await.unwrap()[0].user_id,
+            UserId::new("bob")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_users_batch() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        for name in ["bob", "patrick", "john", "jim"] {
+            insert_user_no_password(&handler, name).await;
+        }
+        let batch = |after: Option<&str>, filters: Option<UserRequestFilter>| {
+            let handler = handler.clone();
+            let after = after.map(UserId::new);
+            async move {
+                handler
+                    .list_users_batch(filters, after, 2)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|u| u.user_id.to_string())
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(batch(None, None).await, vec!["bob", "jim"]);
+        assert_eq!(batch(Some("jim"), None).await, vec!["john", "patrick"]);
+        assert_eq!(batch(Some("patrick"), None).await, Vec::<String>::new());
+        assert_eq!(
+            batch(
+                Some("bob"),
+                Some(UserRequestFilter::Not(Box::new(UserRequestFilter::UserId(
+                    UserId::new("jim")
+                ))))
+            )
+            .await,
+            vec!["john", "patrick"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_display_name_equality_ignores_case() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        handler
+            .create_user(CreateUserRequest {
+                user_id: UserId::new("john"),
+                email: "john@example.com".to_string(),
+                display_name: Some("John Smith".to_string()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        insert_user_no_password(&handler, "bob").await;
+        insert_group(&handler, "Best Group").await;
+        insert_group(&handler, "Best_Group").await;
+        assert_eq!(
+            handler
+                .list_users(Some(UserRequestFilter::Equality(
+                    "display_name".to_string(),
+                    "john SMITH".to_string()
+                )))
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|u| u.user_id.to_string())
+                .collect::<Vec<_>>(),
+            vec!["john"]
+        );
+        assert_eq!(
+            handler
+                .list_groups(Some(GroupRequestFilter::DisplayName(
+                    "best group".to_string()
+                )))
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|g| g.display_name)
+                .collect::<Vec<_>>(),
+            vec!["Best Group"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_changes_are_notified() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool);
+        let mut changes = handler.subscribe_to_changes();
+        let first_id = *changes.borrow();
+        insert_user_no_password(&handler, "bob").await;
+        assert!(changes.changed().await.is_ok());
+        assert!(*changes.borrow() > first_id);
+        // The clones share the notifications, and the reads don't notify.
+        let last_id = *changes.borrow();
+        let group_id = insert_group(&handler.clone(), "admins").await;
+        assert!(*changes.borrow() > last_id);
+        let last_id = *changes.borrow();
+        handler.list_groups(None).await.unwrap();
+        handler.get_user_details(&UserId::new("bob")).await.unwrap();
+        assert_eq!(*changes.borrow(), last_id);
+        insert_membership(&handler, group_id, "bob").await;
+        assert!(*changes.borrow() > last_id);
+    }
 }
@@ -11,11 +11,20 @@ use log::*;
 use sea_query::{Expr, Iden, Query};
 use secstr::SecUtf8;
 use sqlx::Row;
+use tracing::instrument;
 
 type SqlOpaqueHandler = SqlBackendHandler;
 
+#[cfg(test)]
+thread_local! {
+    /// The number of dummy logins made by the thread, see `verify_dummy_password`.
+    static DUMMY_VERIFICATIONS: std::cell::Cell<usize> = std::cell::Cell::new(0);
+}
+
+/// Runs a whole OPAQUE login with the password. Without a password file, the login is made
+/// against a dummy one, and always fails after the same computations.
 fn passwords_match(
-    password_file_bytes: &[u8],
+    password_file_bytes: Option<&[u8]>,
     clear_password: &str,
     server_setup: &opaque::server::ServerSetup,
     username: &UserId,
@@ -24,12 +33,14 @@ fn passwords_match(
     let mut rng = rand::rngs::OsRng;
     let client_login_start_result = client::login::start_login(clear_password, &mut rng)?;
 
-    let password_file = server::ServerRegistration::deserialize(password_file_bytes)
+    let password_file = password_file_bytes
+        .map(server::ServerRegistration::deserialize)
+        .transpose()
         .map_err(opaque::AuthenticationError::ProtocolError)?;
     let server_login_start_result = server::login::start_login(
         &mut rng,
         server_setup,
-        Some(password_file),
+        password_file,
         client_login_start_result.message,
         username.as_str(),
     )?;
@@ -41,6 +52,24 @@ fn passwords_match(
 }
 
 impl SqlBackendHandler {
+    /// With `ldap_constant_time_auth`, spends on the binds of the unknown users (or without a
+    /// password) the same time as on a wrong password, so that the response time doesn't tell
+    /// whether the user exists.
+    fn verify_dummy_password(&self, request: &BindRequest) {
+        if !self.config.ldap_constant_time_auth {
+            return;
+        }
+        #[cfg(test)]
+        DUMMY_VERIFICATIONS.with(|count| count.set(count.get() + 1));
+        // It can only fail.
+        let _ = passwords_match(
+            None,
+            &request.password,
+            self.config.get_server_setup(),
+            &request.name,
+        );
+    }
+
     fn get_orion_secret_key(&self) -> Result<orion::aead::SecretKey> {
         Ok(orion::aead::SecretKey::from_slice(
             self.config.get_server_keys().private(),
@@ -82,6 +111,7 @@ impl SqlBackendHandler {
 
 #[async_trait]
 impl LoginHandler for SqlBackendHandler {
+    #[instrument(skip_all, level = "debug", fields(user_id = %request.name))]
     async fn bind(&self, request: BindRequest) -> Result<()> {
         if request.name == self.config.ldap_user_dn {
             if SecUtf8::from(request.password) == self.config.ldap_user_pass {
@@ -99,12 +129,12 @@ impl LoginHandler for SqlBackendHandler {
             .from(Users::Table)
             .and_where(Expr::col(Users::UserId).eq(&request.name))
             .to_string(DbQueryBuilder {});
-        if let Ok(row) = sqlx::query(&query).fetch_one(&self.sql_pool).await {
+        if let Some(row) = sqlx::query(&query).fetch_optional(&self.sql_pool).await? {
             if let Some(password_hash) =
                 row.get::<Option<Vec<u8>>, _>(&*Users::PasswordHash.to_string())
             {
                 if let Err(e) = passwords_match(
-                    &password_hash,
+                    Some(&password_hash),
                     &request.password,
                     self.config.get_server_setup(),
                     &request.name,
@@ -115,9 +145,11 @@ impl LoginHandler for SqlBackendHandler {
                 }
             } else {
                 debug!(r#"User "{}" has no password"#, &request.name);
+                self.verify_dummy_password(&request);
             }
         } else {
             debug!(r#"No user found for "{}""#, &request.name);
+            self.verify_dummy_password(&request);
         }
         Err(DomainError::AuthenticationError(format!(
             " for user '{}'",
@@ -128,6 +160,7 @@ impl LoginHandler for SqlBackendHandler {
 
 #[async_trait]
 impl OpaqueHandler for SqlOpaqueHandler {
+    #[instrument(skip_all, level = "debug", fields(username = %request.username))]
     async fn login_start(
         &self,
         request: login::ClientLoginStartRequest,
@@ -156,6 +189,7 @@ impl OpaqueHandler for SqlOpaqueHandler {
         })
     }
 
+    #[instrument(skip_all, level = "debug")]
     async fn login_finish(&self, request: login::ClientLoginFinishRequest) -> Result<UserId> {
         let secret_key = self.get_orion_secret_key()?;
         let login::ServerData {
@@ -174,6 +208,7 @@ impl OpaqueHandler for SqlOpaqueHandler {
         Ok(UserId::new(&username))
     }
 
+    #[instrument(skip_all, level = "debug", fields(username = %request.username))]
     async fn registration_start(
         &self,
         request: registration::ClientRegistrationStartRequest,
@@ -195,6 +230,7 @@ impl OpaqueHandler for SqlOpaqueHandler {
         })
     }
 
+    #[instrument(skip_all, level = "debug")]
     async fn registration_finish(
         &self,
         request: registration::ClientRegistrationFinishRequest,
@@ -211,14 +247,19 @@ impl OpaqueHandler for SqlOpaqueHandler {
             // Set the user password to the new password.
             let update_query = Query::update()
                 .table(Users::Table)
-                .values(vec![(
-                    Users::PasswordHash,
-                    password_file.serialize().into(),
-                )])
+                .values(vec![
+                    (Users::PasswordHash, password_file.serialize().into()),
+                    (
+                        Users::PasswordModifiedDate,
+                        chrono::Utc::now().naive_utc().into(),
+                    ),
+                ])
                 .and_where(Expr::col(Users::UserId).eq(username))
                 .to_string(DbQueryBuilder {});
             sqlx::query(&update_query).execute(&self.sql_pool).await?;
         }
+        // The password modification time is in the LDAP entries.
+        self.changes.notify();
         Ok(())
     }
 }
@@ -341,4 +382,76 @@ mod tests {
         attempt_login(&opaque_handler, "bob", "bob00").await?;
         Ok(())
     }
+
+    /// The number of dummy logins made by a bind with a wrong password.
+    async fn failed_bind_dummy_verifications(handler: &SqlBackendHandler, name: &str) -> usize {
+        let before = DUMMY_VERIFICATIONS.with(|count| count.get());
+        assert!(matches!(
+            handler
+                .bind(BindRequest {
+                    name: UserId::new(name),
+                    password: "wrong_password".to_string(),
+                })
+                .await,
+            Err(DomainError::AuthenticationError(_))
+        ));
+        DUMMY_VERIFICATIONS.with(|count| count.get()) - before
+    }
+
+    #[tokio::test]
+    async fn test_constant_time_bind() -> Result<()> {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.ldap_constant_time_auth = true;
+        let handler = SqlBackendHandler::new(config.clone(), sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        register_password(
+            &handler,
+            &UserId::new("bob"),
+            &secstr::SecUtf8::from("bob00"),
+        )
+        .await?;
+        insert_user_no_password(&handler, "john").await;
+        let password_file = handler.get_password_file_for_user("bob").await?.unwrap();
+        // A wrong password and a missing password file fail at the same step of the login.
+        assert!(matches!(
+            passwords_match(
+                Some(&password_file.serialize()),
+                "wrong_password",
+                config.get_server_setup(),
+                &UserId::new("bob"),
+            ),
+            Err(DomainError::AuthenticationProtocolError(_))
+        ));
+        assert!(matches!(
+            passwords_match(
+                None,
+                "wrong_password",
+                config.get_server_setup(),
+                &UserId::new("unknown"),
+            ),
+            Err(DomainError::AuthenticationProtocolError(_))
+        ));
+        // The real password file of bob is used, and the dummy one for the others.
+        assert_eq!(failed_bind_dummy_verifications(&handler, "bob").await, 0);
+        assert_eq!(failed_bind_dummy_verifications(&handler, "john").await, 1);
+        assert_eq!(
+            failed_bind_dummy_verifications(&handler, "unknown").await,
+            1
+        );
+        // Not without the option.
+        let handler = SqlBackendHandler::new(get_default_config(), handler.sql_pool.clone());
+        assert_eq!(failed_bind_dummy_verifications(&handler, "john").await, 0);
+        assert_eq!(
+            failed_bind_dummy_verifications(&handler, "unknown").await,
+            0
+        );
+        handler
+            .bind(BindRequest {
+                name: UserId::new("bob"),
+                password: "bob00".to_string(),
+            })
+            .await?;
+        Ok(())
+    }
 }
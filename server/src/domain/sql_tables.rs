@@ -1,5 +1,8 @@
-use super::handler::{GroupId, UserId};
+use super::handler::{
+    make_entry_uuid, next_free_posix_id, GroupId, UserId, ENTRY_UUID_NAMESPACE, POSIX_ID_RANGE,
+};
 use sea_query::*;
+use std::collections::HashSet;
 
 pub type Pool = sqlx::sqlite::SqlitePool;
 pub type PoolOptions = sqlx::sqlite::SqlitePoolOptions;
@@ -87,6 +90,9 @@ pub enum Users {
     PasswordHash,
     TotpSecret,
     MfaType,
+    PasswordModifiedDate,
+    Uuid,
+    PosixId,
 }
 
 #[derive(Iden)]
@@ -130,11 +136,141 @@ pub async fn init_table(pool: &Pool) -> sqlx::Result<()> {
             .col(ColumnDef::new(Users::PasswordHash).binary())
             .col(ColumnDef::new(Users::TotpSecret).string_len(64))
             .col(ColumnDef::new(Users::MfaType).string_len(64))
+            .col(ColumnDef::new(Users::PasswordModifiedDate).date_time())
+            .col(ColumnDef::new(Users::Uuid).string_len(36))
+            .col(ColumnDef::new(Users::PosixId).integer())
             .to_string(DbQueryBuilder {}),
     )
     .execute(pool)
     .await?;
 
+    // The password modification date was added later: add it to the existing databases.
+    if sqlx::query(
+        &Query::select()
+            .column(Users::PasswordModifiedDate)
+            .from(Users::Table)
+            .limit(1)
+            .to_string(DbQueryBuilder {}),
+    )
+    .fetch_all(pool)
+    .await
+    .is_err()
+    {
+        sqlx::query(
+            &Table::alter()
+                .table(Users::Table)
+                .add_column(ColumnDef::new(Users::PasswordModifiedDate).date_time())
+                .to_string(DbQueryBuilder {}),
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    // The UUIDs were added later too. The existing users get the UUID derived from their ID,
+    // which is what their entryUUID was until then.
+    if sqlx::query(
+        &Query::select()
+            .column(Users::Uuid)
+            .from(Users::Table)
+            .limit(1)
+            .to_string(DbQueryBuilder {}),
+    )
+    .fetch_all(pool)
+    .await
+    .is_err()
+    {
+        sqlx::query(
+            &Table::alter()
+                .table(Users::Table)
+                .add_column(ColumnDef::new(Users::Uuid).string_len(36))
+                .to_string(DbQueryBuilder {}),
+        )
+        .execute(pool)
+        .await?;
+    }
+    let users_without_uuid = sqlx::query_as::<_, (UserId,)>(
+        &Query::select()
+            .column(Users::UserId)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::Uuid).is_null())
+            .to_string(DbQueryBuilder {}),
+    )
+    .fetch_all(pool)
+    .await?;
+    for (user_id,) in users_without_uuid {
+        sqlx::query(
+            &Query::update()
+                .table(Users::Table)
+                .values(vec![(
+                    Users::Uuid,
+                    make_entry_uuid("user", user_id.as_str()).into(),
+                )])
+                .and_where(Expr::col(Users::UserId).eq(user_id))
+                .to_string(DbQueryBuilder {}),
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    // The POSIX IDs were added later too. They used to be derived from the user IDs: the existing
+    // users keep them, unless two users shared one.
+    if sqlx::query(
+        &Query::select()
+            .column(Users::PosixId)
+            .from(Users::Table)
+            .limit(1)
+            .to_string(DbQueryBuilder {}),
+    )
+    .fetch_all(pool)
+    .await
+    .is_err()
+    {
+        sqlx::query(
+            &Table::alter()
+                .table(Users::Table)
+                .add_column(ColumnDef::new(Users::PosixId).integer())
+                .to_string(DbQueryBuilder {}),
+        )
+        .execute(pool)
+        .await?;
+    }
+    let mut used_posix_ids = sqlx::query_as::<_, (i32,)>(
+        &Query::select()
+            .column(Users::PosixId)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::PosixId).is_not_null())
+            .to_string(DbQueryBuilder {}),
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|(posix_id,)| posix_id)
+    .collect::<HashSet<_>>();
+    let users_without_posix_id = sqlx::query_as::<_, (UserId,)>(
+        &Query::select()
+            .column(Users::UserId)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::PosixId).is_null())
+            .order_by(Users::CreationDate, Order::Asc)
+            .order_by(Users::UserId, Order::Asc)
+            .to_string(DbQueryBuilder {}),
+    )
+    .fetch_all(pool)
+    .await?;
+    for (user_id,) in users_without_posix_id {
+        let posix_id = next_free_posix_id(&used_posix_ids, get_legacy_posix_id(&user_id));
+        used_posix_ids.insert(posix_id);
+        sqlx::query(
+            &Query::update()
+                .table(Users::Table)
+                .values(vec![(Users::PosixId, posix_id.into())])
+                .and_where(Expr::col(Users::UserId).eq(user_id))
+                .to_string(DbQueryBuilder {}),
+        )
+        .execute(pool)
+        .await?;
+    }
+
     sqlx::query(
         &Table::create()
             .table(Groups::Table)
@@ -190,6 +326,13 @@ pub async fn init_table(pool: &Pool) -> sqlx::Result<()> {
     Ok(())
 }
 
+/// The POSIX ID that the user had when they were derived from the user IDs.
+fn get_legacy_posix_id(user_id: &UserId) -> i32 {
+    let hash = uuid::Uuid::new_v5(&ENTRY_UUID_NAMESPACE, user_id.as_str().as_bytes());
+    let bytes = hash.as_bytes();
+    (u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) % POSIX_ID_RANGE) as i32
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -222,4 +365,83 @@ mod tests {
         init_table(&sql_pool).await.unwrap();
         init_table(&sql_pool).await.unwrap();
     }
+
+    #[actix_rt::test]
+    async fn test_migrate_password_modified_date() {
+        let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
+        sqlx::query(
+            r#"CREATE TABLE users (user_id TEXT PRIMARY KEY, email TEXT NOT NULL,
+      display_name TEXT NOT NULL, first_name TEXT NOT NULL, last_name TEXT NOT NULL,
+      avatar BLOB, creation_date TEXT NOT NULL, password_hash BLOB, totp_secret TEXT,
+      mfa_type TEXT)"#,
+        )
+        .execute(&sql_pool)
+        .await
+        .unwrap();
+        init_table(&sql_pool).await.unwrap();
+        sqlx::query("SELECT password_modified_date FROM users")
+            .fetch_all(&sql_pool)
+            .await
+            .unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_migrate_uuid() {
+        let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
+        sqlx::query(
+            r#"CREATE TABLE users (user_id TEXT PRIMARY KEY, email TEXT NOT NULL,
+      display_name TEXT NOT NULL, first_name TEXT NOT NULL, last_name TEXT NOT NULL,
+      avatar BLOB, creation_date TEXT NOT NULL, password_hash BLOB, totp_secret TEXT,
+      mfa_type TEXT, password_modified_date TEXT)"#,
+        )
+        .execute(&sql_pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            r#"INSERT INTO users (user_id, email, display_name, first_name, last_name, creation_date)
+      VALUES ("bob", "bob@bob.bob", "Bob", "Bob", "Bobberson", "1970-01-01 00:00:00")"#,
+        )
+        .execute(&sql_pool)
+        .await
+        .unwrap();
+        init_table(&sql_pool).await.unwrap();
+        // The existing users keep the entryUUID they had, derived from their ID.
+        let row = sqlx::query(r#"SELECT uuid FROM users WHERE user_id = "bob""#)
+            .fetch_one(&sql_pool)
+            .await
+            .unwrap();
+        assert_eq!(row.get::<String, _>("uuid"), make_entry_uuid("user", "bob"));
+    }
+
+    #[actix_rt::test]
+    async fn test_migrate_posix_id() {
+        let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
+        sqlx::query(
+            r#"CREATE TABLE users (user_id TEXT PRIMARY KEY, email TEXT NOT NULL,
+      display_name TEXT NOT NULL, first_name TEXT NOT NULL, last_name TEXT NOT NULL,
+      avatar BLOB, creation_date TEXT NOT NULL, password_hash BLOB, totp_secret TEXT,
+      mfa_type TEXT, password_modified_date TEXT, uuid TEXT)"#,
+        )
+        .execute(&sql_pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            r#"INSERT INTO users (user_id, email, display_name, first_name, last_name, creation_date)
+      VALUES ("bob", "bob@bob.bob", "Bob", "Bob", "Bobberson", "1970-01-01 00:00:00")"#,
+        )
+        .execute(&sql_pool)
+        .await
+        .unwrap();
+        init_table(&sql_pool).await.unwrap();
+        // The existing users keep the uidNumber they had, derived from their ID.
+        let row = sqlx::query(r#"SELECT posix_id FROM users WHERE user_id = "bob""#)
+            .fetch_one(&sql_pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            row.get::<i32, _>("posix_id"),
+            get_legacy_posix_id(&UserId::new("bob"))
+        );
+        assert!((0..POSIX_ID_RANGE as i32).contains(&get_legacy_posix_id(&UserId::new("bob"))));
+    }
 }
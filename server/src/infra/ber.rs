@@ -0,0 +1,297 @@
+//! Minimal BER encoding and decoding, for the parts of the LDAP messages (mostly the controls)
+//! that are not handled by the LDAP protocol library.
+use anyhow::{bail, Context, Result};
+
+pub const TAG_BOOLEAN: u8 = 0x01;
+pub const TAG_INTEGER: u8 = 0x02;
+pub const TAG_OCTET_STRING: u8 = 0x04;
+pub const TAG_OID: u8 = 0x06;
+pub const TAG_ENUMERATED: u8 = 0x0A;
+pub const TAG_SEQUENCE: u8 = 0x30;
+pub const TAG_SET: u8 = 0x31;
+
+/// Tag of a context-specific, primitive element: `[number]`.
+pub const fn context_tag(number: u8) -> u8 {
+    0x80 | number
+}
+
+/// Tag of a context-specific, constructed element: `[number] SEQUENCE`.
+pub const fn context_constructed_tag(number: u8) -> u8 {
+    0xA0 | number
+}
+
+/// A single BER element, with its raw contents.
+///
+/// Only single-byte identifiers (tag numbers below 31) and definite lengths are supported, which
+/// is all that LDAP uses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BerElement {
+    pub tag: u8,
+    pub value: Vec<u8>,
+}
+
+/// Parses the header (tag and length) of an element. Returns the tag, the length of the header
+/// and the length of the contents, or None if more bytes are needed.
+pub fn parse_header(input: &[u8]) -> Result<Option<(u8, usize, usize)>> {
+    if input.len() < 2 {
+        return Ok(None);
+    }
+    let tag = input[0];
+    if tag & 0x1F == 0x1F {
+        bail!("Multi-byte BER tags are not supported");
+    }
+    let first_length_byte = input[1];
+    if first_length_byte < 0x80 {
+        return Ok(Some((tag, 2, first_length_byte as usize)));
+    }
+    let length_bytes = (first_length_byte & 0x7F) as usize;
+    if length_bytes == 0 {
+        bail!("Indefinite BER lengths are not allowed");
+    }
+    if length_bytes > std::mem::size_of::<u32>() {
+        bail!("BER length too big: {} bytes", length_bytes);
+    }
+    if input.len() < 2 + length_bytes {
+        return Ok(None);
+    }
+    let length = input[2..2 + length_bytes]
+        .iter()
+        .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+    Ok(Some((tag, 2 + length_bytes, length)))
+}
+
+impl BerElement {
+    /// Parses the element at the start of the input. Returns the element and the number of bytes
+    /// it used, or None if the input is incomplete.
+    pub fn parse(input: &[u8]) -> Result<Option<(BerElement, usize)>> {
+        let (tag, header_length, length) = match parse_header(input)? {
+            None => return Ok(None),
+            Some(header) => header,
+        };
+        let total_length = header_length + length;
+        if input.len() < total_length {
+            return Ok(None);
+        }
+        Ok(Some((
+            BerElement {
+                tag,
+                value: input[header_length..total_length].to_vec(),
+            },
+            total_length,
+        )))
+    }
+
+    /// Parses a complete element, without trailing bytes.
+    pub fn parse_complete(input: &[u8]) -> Result<BerElement> {
+        match BerElement::parse(input)? {
+            Some((element, length)) if length == input.len() => Ok(element),
+            Some(_) => bail!("Trailing bytes after the BER element"),
+            None => bail!("Truncated BER element"),
+        }
+    }
+
+    /// Parses the contents of a constructed element as a list of elements.
+    pub fn children(&self) -> Result<Vec<BerElement>> {
+        let mut children = Vec::new();
+        let mut rest = &self.value[..];
+        while !rest.is_empty() {
+            let (child, length) = BerElement::parse(rest)?.context("Truncated BER element")?;
+            children.push(child);
+            rest = &rest[length..];
+        }
+        Ok(children)
+    }
+
+    pub fn as_bool(&self) -> Result<bool> {
+        match self.value.as_slice() {
+            [b] => Ok(*b != 0),
+            _ => bail!("Invalid BER boolean"),
+        }
+    }
+
+    pub fn as_integer(&self) -> Result<i64> {
+        if self.value.is_empty() || self.value.len() > 8 {
+            bail!("Invalid BER integer");
+        }
+        // Sign-extend from the first byte.
+        let initial = if self.value[0] & 0x80 != 0 { -1i64 } else { 0 };
+        Ok(self
+            .value
+            .iter()
+            .fold(initial, |acc, b| (acc << 8) | *b as i64))
+    }
+
+    /// Decodes an object identifier, in dotted notation.
+    pub fn as_oid(&self) -> Result<String> {
+        let (first, rest) = self.value.split_first().context("Empty BER OID")?;
+        let mut components = vec![(first / 40).to_string(), (first % 40).to_string()];
+        let mut current = 0u64;
+        for byte in rest {
+            current = current
+                .checked_mul(128)
+                .context("BER OID component too big")?
+                | (byte & 0x7F) as u64;
+            if byte & 0x80 == 0 {
+                components.push(current.to_string());
+                current = 0;
+            }
+        }
+        Ok(components.join("."))
+    }
+
+    pub fn as_string(&self) -> Result<String> {
+        String::from_utf8(self.value.clone()).context("Invalid UTF-8 in BER string")
+    }
+
+    pub fn expect_tag(self, tag: u8) -> Result<BerElement> {
+        if self.tag != tag {
+            bail!(
+                "Unexpected BER tag: expected {:#04x}, got {:#04x}",
+                tag,
+                self.tag
+            );
+        }
+        Ok(self)
+    }
+
+    pub fn boolean(value: bool) -> BerElement {
+        BerElement {
+            tag: TAG_BOOLEAN,
+            value: vec![if value { 0xFF } else { 0x00 }],
+        }
+    }
+
+    pub fn integer_with_tag(tag: u8, value: i64) -> BerElement {
+        let bytes = value.to_be_bytes();
+        // Skip the redundant leading bytes, keeping the sign bit.
+        let mut start = 0;
+        while start < bytes.len() - 1
+            && ((bytes[start] == 0x00 && bytes[start + 1] & 0x80 == 0)
+                || (bytes[start] == 0xFF && bytes[start + 1] & 0x80 != 0))
+        {
+            start += 1;
+        }
+        BerElement {
+            tag,
+            value: bytes[start..].to_vec(),
+        }
+    }
+
+    pub fn integer(value: i64) -> BerElement {
+        BerElement::integer_with_tag(TAG_INTEGER, value)
+    }
+
+    pub fn octet_string<T: Into<Vec<u8>>>(value: T) -> BerElement {
+        BerElement {
+            tag: TAG_OCTET_STRING,
+            value: value.into(),
+        }
+    }
+
+    pub fn constructed(tag: u8, children: &[BerElement]) -> BerElement {
+        let mut value = Vec::new();
+        for child in children {
+            child.write_to(&mut value);
+        }
+        BerElement { tag, value }
+    }
+
+    pub fn sequence(children: &[BerElement]) -> BerElement {
+        BerElement::constructed(TAG_SEQUENCE, children)
+    }
+
+    pub fn write_to(&self, output: &mut Vec<u8>) {
+        output.push(self.tag);
+        let length = self.value.len();
+        if length < 0x80 {
+            output.push(length as u8);
+        } else {
+            let bytes = (length as u64).to_be_bytes();
+            let first_non_zero = bytes
+                .iter()
+                .position(|b| *b != 0)
+                .unwrap_or(bytes.len() - 1);
+            output.push(0x80 | (bytes.len() - first_non_zero) as u8);
+            output.extend_from_slice(&bytes[first_non_zero..]);
+        }
+        output.extend_from_slice(&self.value);
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut output = Vec::with_capacity(self.value.len() + 6);
+        self.write_to(&mut output);
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_integer_round_trip() {
+        for value in [
+            0,
+            1,
+            127,
+            128,
+            255,
+            256,
+            -1,
+            -128,
+            -129,
+            i32::MAX as i64,
+            i64::MIN,
+        ] {
+            let encoded = BerElement::integer(value).encode();
+            let decoded = BerElement::parse_complete(&encoded).unwrap();
+            assert_eq!(decoded.as_integer().unwrap(), value, "{:?}", encoded);
+        }
+        assert_eq!(
+            BerElement::integer(128).encode(),
+            vec![0x02, 0x02, 0x00, 0x80]
+        );
+        assert_eq!(BerElement::integer(-1).encode(), vec![0x02, 0x01, 0xFF]);
+    }
+
+    #[test]
+    fn test_long_length() {
+        let element = BerElement::octet_string(vec![b'a'; 300]);
+        let encoded = element.encode();
+        assert_eq!(&encoded[..4], &[0x04, 0x82, 0x01, 0x2C]);
+        assert_eq!(BerElement::parse_complete(&encoded).unwrap(), element);
+    }
+
+    #[test]
+    fn test_sequence() {
+        let sequence = BerElement::sequence(&[
+            BerElement::integer(5),
+            BerElement::octet_string("cookie"),
+            BerElement::boolean(true),
+        ]);
+        let decoded = BerElement::parse_complete(&sequence.encode()).unwrap();
+        let children = decoded.children().unwrap();
+        assert_eq!(children.len(), 3);
+        assert_eq!(children[0].as_integer().unwrap(), 5);
+        assert_eq!(children[1].as_string().unwrap(), "cookie");
+        assert!(children[2].as_bool().unwrap());
+    }
+
+    #[test]
+    fn test_oid() {
+        let oid = BerElement {
+            tag: TAG_OID,
+            value: vec![0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x09, 0x01],
+        };
+        assert_eq!(oid.as_oid().unwrap(), "1.2.840.113549.1.9.1");
+    }
+
+    #[test]
+    fn test_incomplete() {
+        let encoded = BerElement::octet_string("cookie").encode();
+        assert_eq!(BerElement::parse(&encoded[..3]).unwrap(), None);
+        assert_eq!(BerElement::parse(&encoded[..1]).unwrap(), None);
+        assert!(BerElement::parse_complete(&encoded[..3]).is_err());
+        assert!(BerElement::parse(&[0x30, 0x80]).is_err());
+    }
+}
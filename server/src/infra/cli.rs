@@ -66,6 +66,10 @@ pub struct RunOpts {
     #[clap(long, env = "LLDAP_HTTP_URL")]
     pub http_url: Option<String>,
 
+    /// Only check the LDAP configuration (base DN, admin user, LDAPS certificate), and exit.
+    #[clap(long)]
+    pub check_config: bool,
+
     #[clap(flatten)]
     pub smtp_opts: SmtpOpts,
 }
@@ -0,0 +1,234 @@
+//! Extraction of the identity (subject and alternative names) of a client TLS certificate.
+//!
+//! The certificate has already been validated during the TLS handshake: this only reads the
+//! fields needed to map it to a user.
+use crate::infra::ber::{
+    context_constructed_tag, context_tag, BerElement, TAG_OCTET_STRING, TAG_OID, TAG_SEQUENCE,
+    TAG_SET,
+};
+use anyhow::{Context, Result};
+
+const SUBJECT_ALT_NAME_OID: &str = "2.5.29.17";
+const VERSION_TAG: u8 = context_constructed_tag(0);
+const EXTENSIONS_TAG: u8 = context_constructed_tag(3);
+const RFC822_NAME_TAG: u8 = context_tag(1);
+
+/// Short names of the usual attributes of a certificate subject.
+fn attribute_name(oid: &str) -> Option<&'static str> {
+    Some(match oid {
+        "2.5.4.3" => "CN",
+        "2.5.4.6" => "C",
+        "2.5.4.7" => "L",
+        "2.5.4.8" => "ST",
+        "2.5.4.10" => "O",
+        "2.5.4.11" => "OU",
+        "0.9.2342.19200300.100.1.1" => "UID",
+        "0.9.2342.19200300.100.1.25" => "DC",
+        "1.2.840.113549.1.9.1" => "emailAddress",
+        _ => return None,
+    })
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CertificateIdentity {
+    /// The attributes of the subject, in the certificate order, e.g. `("CN", "bob")`. Unknown
+    /// attributes are named by their OID.
+    pub subject: Vec<(String, String)>,
+    /// The email addresses from the subject alternative names.
+    pub emails: Vec<String>,
+}
+
+impl CertificateIdentity {
+    pub fn subject_attribute(&self, name: &str) -> Option<&str> {
+        self.subject
+            .iter()
+            .find(|(attribute, _)| attribute.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The subject, formatted as an LDAP DN (most specific attribute first).
+    pub fn subject_dn(&self) -> String {
+        self.subject
+            .iter()
+            .rev()
+            .map(|(attribute, value)| format!("{}={}", attribute, value))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+fn parse_name(name: BerElement) -> Result<Vec<(String, String)>> {
+    let mut attributes = Vec::new();
+    for relative_name in name.expect_tag(TAG_SEQUENCE)?.children()? {
+        for attribute in relative_name.expect_tag(TAG_SET)?.children()? {
+            let mut fields = attribute.expect_tag(TAG_SEQUENCE)?.children()?.into_iter();
+            let oid = fields
+                .next()
+                .context("Missing attribute type")?
+                .expect_tag(TAG_OID)?
+                .as_oid()?;
+            let value = fields
+                .next()
+                .context("Missing attribute value")?
+                .as_string()?;
+            let name = attribute_name(&oid).map(str::to_string).unwrap_or(oid);
+            attributes.push((name, value));
+        }
+    }
+    Ok(attributes)
+}
+
+fn parse_alt_name_emails(extensions: BerElement) -> Result<Vec<String>> {
+    let mut emails = Vec::new();
+    let extensions = extensions.children()?.into_iter().next();
+    for extension in extensions
+        .map(|e| e.children())
+        .transpose()?
+        .unwrap_or_default()
+    {
+        let fields = extension.expect_tag(TAG_SEQUENCE)?.children()?;
+        let oid = fields
+            .first()
+            .context("Missing extension type")?
+            .clone()
+            .expect_tag(TAG_OID)?
+            .as_oid()?;
+        if oid != SUBJECT_ALT_NAME_OID {
+            continue;
+        }
+        // The value is the last field, after the optional criticality.
+        let value = fields
+            .last()
+            .context("Missing extension value")?
+            .clone()
+            .expect_tag(TAG_OCTET_STRING)?;
+        for general_name in BerElement::parse_complete(&value.value)?.children()? {
+            if general_name.tag == RFC822_NAME_TAG {
+                emails.push(general_name.as_string()?);
+            }
+        }
+    }
+    Ok(emails)
+}
+
+/// Reads the subject and the email alternative names of a DER-encoded X.509 certificate.
+pub fn parse_certificate_identity(der: &[u8]) -> Result<CertificateIdentity> {
+    let tbs_certificate = BerElement::parse_complete(der)?
+        .expect_tag(TAG_SEQUENCE)?
+        .children()?
+        .into_iter()
+        .next()
+        .context("Missing certificate contents")?
+        .expect_tag(TAG_SEQUENCE)?;
+    let mut fields = tbs_certificate.children()?;
+    if fields.first().map(|f| f.tag) == Some(VERSION_TAG) {
+        fields.remove(0);
+    }
+    // serialNumber, signature, issuer, validity, subject, subjectPublicKeyInfo, then the
+    // optional fields.
+    let subject = fields
+        .get(4)
+        .context("Missing certificate subject")?
+        .clone();
+    let emails = match fields.into_iter().find(|f| f.tag == EXTENSIONS_TAG) {
+        Some(extensions) => parse_alt_name_emails(extensions)?,
+        None => Vec::new(),
+    };
+    Ok(CertificateIdentity {
+        subject: parse_name(subject).context("while parsing the certificate subject")?,
+        emails,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oid(value: &[u8]) -> BerElement {
+        BerElement {
+            tag: TAG_OID,
+            value: value.to_vec(),
+        }
+    }
+
+    fn utf8_string(value: &str) -> BerElement {
+        BerElement {
+            tag: 0x0C,
+            value: value.as_bytes().to_vec(),
+        }
+    }
+
+    fn name(attributes: &[(BerElement, &str)]) -> BerElement {
+        BerElement::sequence(
+            &attributes
+                .iter()
+                .map(|(attribute_type, value)| {
+                    BerElement::constructed(
+                        TAG_SET,
+                        &[BerElement::sequence(&[
+                            attribute_type.clone(),
+                            utf8_string(value),
+                        ])],
+                    )
+                })
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[test]
+    fn test_parse_certificate_identity() {
+        let alt_names = BerElement::sequence(&[
+            BerElement {
+                tag: context_tag(2),
+                value: b"bob.example.com".to_vec(),
+            },
+            BerElement {
+                tag: RFC822_NAME_TAG,
+                value: b"bob@example.com".to_vec(),
+            },
+        ]);
+        let extensions = BerElement::constructed(
+            EXTENSIONS_TAG,
+            &[BerElement::sequence(&[BerElement::sequence(&[
+                oid(&[0x55, 0x1D, 0x11]),
+                BerElement::octet_string(alt_names.encode()),
+            ])])],
+        );
+        let algorithm =
+            BerElement::sequence(&[oid(&[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x04, 0x03, 0x02])]);
+        let tbs_certificate = BerElement::sequence(&[
+            BerElement::constructed(VERSION_TAG, &[BerElement::integer(2)]),
+            BerElement::integer(1234),
+            algorithm.clone(),
+            name(&[(oid(&[0x55, 0x04, 0x03]), "Example CA")]),
+            BerElement::sequence(&[]),
+            name(&[
+                (oid(&[0x55, 0x04, 0x0A]), "Example"),
+                (oid(&[0x55, 0x04, 0x03]), "bob"),
+            ]),
+            BerElement::sequence(&[]),
+            extensions,
+        ]);
+        let certificate = BerElement::sequence(&[
+            tbs_certificate,
+            algorithm,
+            BerElement {
+                tag: 0x03,
+                value: vec![0x00],
+            },
+        ]);
+        let identity = parse_certificate_identity(&certificate.encode()).unwrap();
+        assert_eq!(
+            identity,
+            CertificateIdentity {
+                subject: vec![
+                    ("O".to_string(), "Example".to_string()),
+                    ("CN".to_string(), "bob".to_string())
+                ],
+                emails: vec!["bob@example.com".to_string()],
+            }
+        );
+        assert_eq!(identity.subject_attribute("cn"), Some("bob"));
+        assert_eq!(identity.subject_dn(), "CN=bob,O=Example");
+    }
+}
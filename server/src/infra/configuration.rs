@@ -7,10 +7,52 @@ use figment::{
     providers::{Env, Format, Serialized, Toml},
     Figment,
 };
+use ipnet::IpNet;
 use lettre::message::Mailbox;
 use lldap_auth::opaque::{server::ServerSetup, KeyPair};
 use secstr::SecUtf8;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Format of the logs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines.
+    Text,
+    /// One JSON object per line, with an access log entry for each LDAP operation.
+    Json,
+}
+
+/// The attribute in the RDN of the user entries, e.g. "uid" in "uid=bob,ou=people,...".
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UserRdnAttribute {
+    /// The entries are named after the display name, and the user ID is accepted in both "cn"
+    /// and "uid" in the DNs sent by the clients.
+    Cn,
+    /// The user ID.
+    Uid,
+    /// The email.
+    Mail,
+}
+
+/// The oldest TLS version accepted by LDAPS and StartTLS. TLS 1.0 and 1.1 are never supported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+/// A certificate for the LDAPS clients asking for a hostname through SNI.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SniCertificate {
+    pub hostname: String,
+    pub cert_file: String,
+    pub key_file: String,
+}
 
 #[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
 #[builder(pattern = "owned")]
@@ -42,24 +84,184 @@ impl std::default::Default for MailOptions {
 #[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
 #[builder(pattern = "owned", build_fn(name = "private_build"))]
 pub struct Configuration {
+    #[builder(default = r#"String::from("0.0.0.0")"#)]
+    pub ldap_host: String,
     #[builder(default = "3890")]
     pub ldap_port: u16,
     #[builder(default = "6360")]
     pub ldaps_port: u16,
+    #[builder(default = "1024")]
+    pub ldap_tcp_backlog: u32,
+    #[builder(default = "None")]
+    pub ldap_unix_socket_path: Option<String>,
+    #[builder(default = r#""660".to_string()"#)]
+    pub ldap_unix_socket_permissions: String,
+    #[builder(default = "None")]
+    pub ldap_unix_socket_user: Option<UserId>,
+    #[builder(default = "None")]
+    pub ldaps_cert_file: Option<String>,
+    #[builder(default = "None")]
+    pub ldaps_key_file: Option<String>,
+    #[builder(default = "None")]
+    pub ldaps_client_ca_file: Option<String>,
+    #[builder(default = "false")]
+    pub ldaps_require_client_cert: bool,
+    #[builder(default = "TlsVersion::Tls12")]
+    pub ldaps_min_tls_version: TlsVersion,
+    #[builder(default = "None")]
+    pub ldaps_cipher_suites: Option<Vec<String>>,
+    #[builder(default)]
+    pub ldaps_sni_certificates: Vec<SniCertificate>,
+    #[builder(default = "true")]
+    pub ldaps_session_resumption: bool,
+    #[builder(default = "3600")]
+    pub ldaps_session_ticket_lifetime_seconds: u64,
+    #[builder(default = "256")]
+    pub ldaps_session_cache_size: usize,
+    #[builder(default = "None")]
+    pub ldap_idle_timeout_seconds: Option<u64>,
+    #[builder(default = "None")]
+    pub ldap_write_timeout_seconds: Option<u64>,
+    #[builder(default = "None")]
+    pub ldap_max_size_limit: Option<usize>,
+    #[builder(default = "None")]
+    pub ldap_search_timeout_seconds: Option<u64>,
+    #[builder(default = "true")]
+    pub ldap_allow_anonymous_bind: bool,
+    #[builder(default = "None")]
+    pub ldap_anonymous_readable_attributes: Option<Vec<String>>,
+    #[builder(default = "false")]
+    pub ldap_require_auth_for_search: bool,
+    #[builder(default)]
+    pub ldap_hidden_attributes: Vec<String>,
+    #[builder(default = "30")]
+    pub shutdown_grace_seconds: u64,
+    #[builder(default = r#""The server is shutting down, reconnect later".to_string()"#)]
+    pub ldap_shutdown_message: String,
+    #[builder(default = "vec![]")]
+    pub ldap_allowed_cidrs: Vec<IpNet>,
+    #[builder(default = "false")]
+    pub ldap_proxy_protocol: bool,
+    #[builder(default = "vec![]")]
+    pub ldap_proxy_protocol_trusted_cidrs: Vec<IpNet>,
+    #[builder(default = "None")]
+    pub ldap_max_binds_per_minute_per_ip: Option<u32>,
+    #[builder(default = "None")]
+    pub ldap_bind_failures_before_delay: Option<u32>,
+    #[builder(default = "30")]
+    pub ldap_bind_failure_max_delay_seconds: u64,
+    #[builder(default = "false")]
+    pub ldap_report_account_lockout: bool,
+    #[builder(default = "false")]
+    pub ldap_constant_time_auth: bool,
+    #[builder(default = "None")]
+    pub ldap_max_connections: Option<usize>,
+    #[builder(default = "None")]
+    pub ldap_max_connections_per_user: Option<usize>,
+    #[builder(default = "true")]
+    pub ldap_cache_bound_user: bool,
+    #[builder(default = "false")]
+    pub ldap_verbose_result_messages: bool,
+    #[builder(default = "None")]
+    pub ldap_operation_log_size: Option<usize>,
+    #[builder(default = "None")]
+    pub tcp_keepalive_seconds: Option<u64>,
+    #[builder(default = "crate::infra::ldap_codec::DEFAULT_MAX_MESSAGE_BYTES")]
+    pub ldap_max_message_bytes: usize,
+    #[builder(default = "crate::infra::ldap_handler::DEFAULT_MAX_PHOTO_BYTES")]
+    pub ldap_max_photo_bytes: usize,
+    #[builder(default = "crate::infra::ldap_handler::DEFAULT_MAX_ATTRIBUTE_VALUE_BYTES")]
+    pub ldap_max_attribute_value_bytes: usize,
+    #[builder(default = "crate::infra::ldap_handler::DEFAULT_MAX_PHOTO_BYTES")]
+    pub ldap_max_binary_attribute_value_bytes: usize,
+    #[builder(default = "None")]
+    pub ldap_member_range_threshold: Option<usize>,
+    #[builder(default = "None")]
+    pub ldap_max_filter_complexity: Option<usize>,
+    #[builder(default)]
+    pub ldap_disabled_operations: Vec<String>,
+    #[builder(default)]
+    pub ldap_referrals: HashMap<String, String>,
+    #[builder(default)]
+    pub ldap_search_restrictions: HashMap<String, Vec<String>>,
+    #[builder(
+        default = r#"vec!["inetOrgPerson".to_string(), "posixAccount".to_string(), "mailAccount".to_string(), "person".to_string()]"#
+    )]
+    pub ldap_user_object_classes: Vec<String>,
+    #[builder(default = r#"String::from("/home/{uid}")"#)]
+    pub ldap_home_directory_template: String,
+    #[builder(default = "crate::infra::ldap_handler::DEFAULT_POSIX_UID_OFFSET")]
+    pub ldap_posix_uid_offset: u32,
+    #[builder(default = "crate::infra::ldap_handler::DEFAULT_POSIX_GID_OFFSET")]
+    pub ldap_posix_gid_offset: u32,
+    #[builder(default = "None")]
+    pub ldap_cn_template: Option<String>,
+    #[builder(default = "true")]
+    pub ldap_ad_compatibility: bool,
+    #[builder(default = "UserRdnAttribute::Cn")]
+    pub ldap_user_rdn_attribute: UserRdnAttribute,
+    #[builder(default = r#"String::from("ou=people")"#)]
+    pub ldap_people_ou: String,
+    #[builder(default = r#"String::from("ou=groups")"#)]
+    pub ldap_groups_ou: String,
+    #[builder(default)]
+    pub ldap_user_ou_mapping: HashMap<String, String>,
+    #[builder(default = "false")]
+    pub ldap_allow_email_login: bool,
+    #[builder(default = "None")]
+    pub ldap_netbios_domain: Option<String>,
+    #[builder(default = "None")]
+    pub ldap_upn_suffix: Option<String>,
+    #[builder(default = "None")]
+    pub ldap_bind_dn_template: Option<String>,
     #[builder(default = "17170")]
     pub http_port: u16,
+    #[builder(default = "None")]
+    pub metrics_port: Option<u16>,
     #[builder(default = r#"SecUtf8::from("secretjwtsecret")"#)]
     pub jwt_secret: SecUtf8,
-    #[builder(default = r#"String::from("dc=example,dc=com")"#)]
-    pub ldap_base_dn: String,
+    /// The first base DN is where the entries are, the others are aliases of it.
+    #[builder(default = r#"vec![String::from("dc=example,dc=com")]"#)]
+    #[serde(deserialize_with = "deserialize_base_dns")]
+    pub ldap_base_dn: Vec<String>,
     #[builder(default = r#"UserId::new("admin")"#)]
     pub ldap_user_dn: UserId,
     #[builder(default = r#"SecUtf8::from("password")"#)]
     pub ldap_user_pass: SecUtf8,
+    #[builder(default = "None")]
+    pub ldap_readonly_dn: Option<String>,
+    #[builder(default = "None")]
+    pub ldap_readonly_pass: Option<SecUtf8>,
+    #[builder(default = "None")]
+    pub ldap_proxy_group: Option<String>,
+    #[builder(default = "false")]
+    pub ldap_allow_insecure_sasl_plain: bool,
+    #[builder(default = "None")]
+    pub ldap_admin_group_dn: Option<String>,
+    #[builder(default = "None")]
+    pub ldap_virtual_all_users_group: Option<String>,
+    #[builder(default = "None")]
+    pub ldap_group_cache_ttl_seconds: Option<u64>,
+    #[builder(default = "None")]
+    pub ldap_search_cache_ttl_seconds: Option<u64>,
+    #[builder(default = "0")]
+    pub ldap_backend_retry_attempts: u32,
+    #[builder(default = "50")]
+    pub ldap_backend_retry_initial_delay_ms: u64,
+    #[builder(default = "1000")]
+    pub ldap_backend_retry_max_delay_ms: u64,
     #[builder(default = r#"String::from("sqlite://users.db?mode=rwc")"#)]
     pub database_url: String,
+    #[builder(default = "None")]
+    pub database_replica_url: Option<String>,
     #[builder(default = "false")]
     pub verbose: bool,
+    #[builder(default = "LogFormat::Text")]
+    pub log_format: LogFormat,
+    #[builder(default = "false")]
+    pub tracing_enabled: bool,
+    #[builder(default = r#"String::from("http://localhost:4317")"#)]
+    pub otlp_endpoint: String,
     #[builder(default = r#"String::from("server_key")"#)]
     pub key_file: String,
     #[builder(default)]
@@ -71,6 +273,23 @@ pub struct Configuration {
     server_setup: Option<ServerSetup>,
 }
 
+/// Accepts either a single base DN or a list of them.
+fn deserialize_base_dns<'de, D>(deserializer: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(base_dn) => vec![base_dn],
+        OneOrMany::Many(base_dns) => base_dns,
+    })
+}
+
 impl std::default::Default for Configuration {
     fn default() -> Self {
         ConfigurationBuilder::default().build().unwrap()
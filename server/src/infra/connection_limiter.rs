@@ -0,0 +1,97 @@
+//! Per-user limit of the LDAP connections, so that an application opening many connections as
+//! the same account can't starve the others.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// The number of connections bound as each DN.
+#[derive(Debug)]
+pub struct UserConnectionLimiter {
+    max_per_user: usize,
+    connections: Mutex<HashMap<String, usize>>,
+}
+
+/// Counts a connection as bound as the DN until dropped.
+#[derive(Debug)]
+pub struct UserConnectionGuard {
+    limiter: Arc<UserConnectionLimiter>,
+    dn: String,
+}
+
+impl UserConnectionGuard {
+    pub fn dn(&self) -> &str {
+        &self.dn
+    }
+}
+
+impl Drop for UserConnectionGuard {
+    fn drop(&mut self) {
+        let mut connections = self.limiter.connections.lock().unwrap();
+        if let Some(count) = connections.get_mut(&self.dn) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.dn);
+            }
+        }
+    }
+}
+
+impl UserConnectionLimiter {
+    pub fn new(max_per_user: usize) -> Self {
+        Self {
+            max_per_user,
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts one more connection bound as the DN. None if the DN is already at the limit.
+    pub fn try_acquire(self: &Arc<Self>, dn: &str) -> Option<UserConnectionGuard> {
+        let mut connections = self.connections.lock().unwrap();
+        let count = connections.get(dn).copied().unwrap_or(0);
+        if count >= self.max_per_user {
+            return None;
+        }
+        connections.insert(dn.to_string(), count + 1);
+        Some(UserConnectionGuard {
+            limiter: self.clone(),
+            dn: dn.to_string(),
+        })
+    }
+
+    /// The number of connections bound as the DN.
+    pub fn connections(&self, dn: &str) -> usize {
+        self.connections
+            .lock()
+            .unwrap()
+            .get(dn)
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit_per_user() {
+        let limiter = Arc::new(UserConnectionLimiter::new(2));
+        let first = limiter.try_acquire("uid=bob").unwrap();
+        let _second = limiter.try_acquire("uid=bob").unwrap();
+        assert!(limiter.try_acquire("uid=bob").is_none());
+        // The other users have their own allowance.
+        let _other = limiter.try_acquire("uid=jim").unwrap();
+        assert_eq!(limiter.connections("uid=bob"), 2);
+        drop(first);
+        assert_eq!(limiter.connections("uid=bob"), 1);
+        assert!(limiter.try_acquire("uid=bob").is_some());
+    }
+
+    #[test]
+    fn test_released_users_are_forgotten() {
+        let limiter = Arc::new(UserConnectionLimiter::new(1));
+        drop(limiter.try_acquire("uid=bob").unwrap());
+        assert!(limiter.connections.lock().unwrap().is_empty());
+    }
+}
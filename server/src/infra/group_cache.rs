@@ -0,0 +1,182 @@
+//! Cache of the group queries of the LDAP searches, in front of the backend. Apps that expand the
+//! members of the same groups on every request then only hit the database once per TTL.
+use crate::{
+    domain::{
+        error::Result,
+        handler::{BackendHandler, Group, GroupRequestFilter},
+    },
+    infra::metrics::LdapMetrics,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Above this number of cached queries, the least recently used one is evicted.
+const MAX_ENTRIES: usize = 1024;
+
+#[derive(Debug)]
+struct CacheEntry {
+    groups: Vec<Group>,
+    inserted: Instant,
+    last_used: Instant,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<Option<GroupRequestFilter>, CacheEntry>,
+    /// Incremented on each invalidation, so that the results of the queries that were running
+    /// during an invalidation are not cached.
+    generation: u64,
+}
+
+/// The results of `list_groups`, by filter. The entries expire after the TTL, and they are all
+/// dropped when a group or a membership changes (see `invalidate`).
+pub struct GroupCache {
+    ttl: Duration,
+    state: Mutex<CacheState>,
+    metrics: Option<Arc<LdapMetrics>>,
+}
+
+impl std::fmt::Debug for GroupCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("GroupCache")
+            .field("ttl", &self.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl GroupCache {
+    pub fn new(ttl: Duration, metrics: Option<Arc<LdapMetrics>>) -> Self {
+        Self {
+            ttl,
+            state: Mutex::new(CacheState::default()),
+            metrics,
+        }
+    }
+
+    /// Same as `BackendHandler::list_groups`, from the cache if possible.
+    pub async fn list_groups<Backend: BackendHandler>(
+        &self,
+        backend_handler: &Backend,
+        filters: Option<GroupRequestFilter>,
+    ) -> Result<Vec<Group>> {
+        let (cached, generation) = self.get_at(&filters, Instant::now());
+        if let Some(metrics) = &self.metrics {
+            metrics.record_group_cache_lookup(cached.is_some());
+        }
+        if let Some(groups) = cached {
+            return Ok(groups);
+        }
+        let groups = backend_handler.list_groups(filters.clone()).await?;
+        self.insert_at(filters, &groups, generation, Instant::now());
+        Ok(groups)
+    }
+
+    /// Drops all the cached queries. Called whenever a group or a membership changes.
+    pub fn invalidate(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.clear();
+        state.generation += 1;
+    }
+
+    fn get_at(
+        &self,
+        filters: &Option<GroupRequestFilter>,
+        now: Instant,
+    ) -> (Option<Vec<Group>>, u64) {
+        let mut state = self.state.lock().unwrap();
+        let generation = state.generation;
+        let ttl = self.ttl;
+        let groups = match state.entries.get_mut(filters) {
+            Some(entry) if now.saturating_duration_since(entry.inserted) < ttl => {
+                entry.last_used = now;
+                Some(entry.groups.clone())
+            }
+            Some(_) => {
+                state.entries.remove(filters);
+                None
+            }
+            None => None,
+        };
+        (groups, generation)
+    }
+
+    fn insert_at(
+        &self,
+        filters: Option<GroupRequestFilter>,
+        groups: &[Group],
+        generation: u64,
+        now: Instant,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        if state.generation != generation {
+            return;
+        }
+        if state.entries.len() >= MAX_ENTRIES {
+            if let Some(oldest) = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(filters, _)| filters.clone())
+            {
+                state.entries.remove(&oldest);
+            }
+        }
+        state.entries.insert(
+            filters,
+            CacheEntry {
+                groups: groups.to_vec(),
+                inserted: now,
+                last_used: now,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::handler::{GroupId, UserId};
+
+    fn make_groups() -> Vec<Group> {
+        vec![Group {
+            id: GroupId(1),
+            display_name: "group_1".to_string(),
+            users: vec![UserId::new("bob")],
+        }]
+    }
+
+    #[test]
+    fn test_group_cache_expiry() {
+        let cache = GroupCache::new(Duration::from_secs(10), None);
+        let start = Instant::now();
+        let filters = Some(GroupRequestFilter::DisplayName("group_1".to_string()));
+        assert_eq!(cache.get_at(&filters, start), (None, 0));
+        cache.insert_at(filters.clone(), &make_groups(), 0, start);
+        assert_eq!(
+            cache.get_at(&filters, start + Duration::from_secs(5)),
+            (Some(make_groups()), 0)
+        );
+        assert_eq!(cache.get_at(&None, start), (None, 0));
+        assert_eq!(
+            cache.get_at(&filters, start + Duration::from_secs(10)),
+            (None, 0)
+        );
+    }
+
+    #[test]
+    fn test_group_cache_invalidation() {
+        let cache = GroupCache::new(Duration::from_secs(10), None);
+        let start = Instant::now();
+        cache.insert_at(None, &make_groups(), 0, start);
+        cache.invalidate();
+        assert_eq!(cache.get_at(&None, start), (None, 1));
+        // A query that started before the invalidation is not cached.
+        cache.insert_at(None, &make_groups(), 0, start);
+        assert_eq!(cache.get_at(&None, start), (None, 1));
+        cache.insert_at(None, &make_groups(), 1, start);
+        assert_eq!(cache.get_at(&None, start), (Some(make_groups()), 1));
+    }
+}
@@ -0,0 +1,1752 @@
+//! LDAP message framing, with support for the request and response controls.
+//!
+//! The operations themselves are (de)serialized by `ldap3_server`, but the controls are handled
+//! here: they are extracted from the incoming messages before decoding, and appended to the
+//! outgoing messages after encoding. SASL binds, compare, add, delete, modify and modify DN
+//! requests, that `ldap3_server` can't decode, are parsed here as well, and their responses encoded
+//! here. So are the abandon requests, only to be ignored without dropping the connection. The
+//! extensible match filters of the searches are rewritten as equality filters for `ldap3_server`
+//! (see `rewrite_extensible_matches`), and the search result entries with binary attributes are
+//! encoded here (see `BINARY_ATTRIBUTES`).
+use crate::infra::ber::{
+    context_constructed_tag, context_tag, parse_header, BerElement, TAG_BOOLEAN, TAG_ENUMERATED,
+    TAG_INTEGER, TAG_OCTET_STRING, TAG_SEQUENCE, TAG_SET,
+};
+use anyhow::{bail, Context, Result};
+use bytes::BytesMut;
+use ldap3_server::{
+    proto::{LdapFilter, LdapMsg, LdapOp, LdapResult, LdapSearchResultEntry, LdapSubstringFilter},
+    LdapCodec,
+};
+use log::warn;
+use std::io;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// A control attached to an LDAP message, with its value still BER-encoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawControl {
+    pub oid: String,
+    pub criticality: bool,
+    pub value: Option<Vec<u8>>,
+}
+
+/// A SASL bind request. They are parsed here since `ldap3_server` only supports simple binds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SaslBindRequest {
+    pub dn: String,
+    pub mechanism: String,
+    pub credentials: Option<Vec<u8>>,
+}
+
+/// A bind request for another protocol version than LDAPv3, the only one supported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsupportedBindRequest {
+    pub dn: String,
+    pub version: i64,
+}
+
+/// A compare request: does the entry have the attribute with the given value?
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompareRequest {
+    pub dn: String,
+    pub attribute: String,
+    pub value: Vec<u8>,
+}
+
+/// A request that is recognized, but not supported: the connection stays open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnsupportedOperation {
+    /// Never answered (RFC 4511), with the message ID of the operation to abandon.
+    Abandon(i32),
+}
+
+/// A delete request: removes the entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeleteRequest {
+    pub dn: String,
+}
+
+/// An add request: creates the entry, with the given attributes and their values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddRequest {
+    pub dn: String,
+    pub attributes: Vec<(String, Vec<Vec<u8>>)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModifyOperation {
+    Add,
+    Delete,
+    Replace,
+}
+
+/// A single change of a modify request, on one attribute.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Modification {
+    pub operation: ModifyOperation,
+    pub attribute: String,
+    pub values: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModifyRequest {
+    pub dn: String,
+    pub changes: Vec<Modification>,
+}
+
+/// A modify DN request: renames the entry, and moves it under `new_superior` if set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModifyDnRequest {
+    pub dn: String,
+    pub new_rdn: String,
+    pub delete_old_rdn: bool,
+    pub new_superior: Option<String>,
+}
+
+/// The operation of an incoming message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LdapRequest {
+    Op(LdapOp),
+    SaslBind(SaslBindRequest),
+    UnsupportedBind(UnsupportedBindRequest),
+    Compare(CompareRequest),
+    Add(AddRequest),
+    Delete(DeleteRequest),
+    Modify(ModifyRequest),
+    ModifyDn(ModifyDnRequest),
+    Unsupported(UnsupportedOperation),
+}
+
+impl LdapRequest {
+    /// Short name of the operation, for the logs.
+    pub fn op_type(&self) -> &'static str {
+        match self {
+            LdapRequest::Op(LdapOp::BindRequest(_))
+            | LdapRequest::SaslBind(_)
+            | LdapRequest::UnsupportedBind(_) => "bind",
+            LdapRequest::Op(LdapOp::SearchRequest(_)) => "search",
+            LdapRequest::Op(LdapOp::UnbindRequest) => "unbind",
+            LdapRequest::Op(LdapOp::ExtendedRequest(_)) => "extended",
+            LdapRequest::Op(_) => "other",
+            LdapRequest::Compare(_) => "compare",
+            LdapRequest::Add(_) => "add",
+            LdapRequest::Delete(_) => "delete",
+            LdapRequest::Modify(_) => "modify",
+            LdapRequest::ModifyDn(_) => "modifydn",
+            LdapRequest::Unsupported(UnsupportedOperation::Abandon(_)) => "abandon",
+        }
+    }
+}
+
+/// The operation of an outgoing message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LdapResponseOp {
+    Op(LdapOp),
+    CompareResponse(LdapResult),
+    ModifyResponse(LdapResult),
+    ModifyDnResponse(LdapResult),
+    AddResponse(LdapResult),
+    DeleteResponse(LdapResult),
+    /// A response (bind or search done) with the URLs of the server to ask instead, since
+    /// `ldap3_server` doesn't encode the referrals.
+    Referral(LdapOp, Vec<String>),
+    /// URLs of a subtree held by another server, returned along with the search results.
+    SearchResultReference(Vec<String>),
+    /// A message sent while an operation is still in progress (RFC 4511, section 4.13), before
+    /// its final response. Its meaning is defined by the request or control it answers.
+    IntermediateResponse {
+        name: Option<String>,
+        value: Option<Vec<u8>>,
+    },
+    /// A response with a result code that `ldap3_server` doesn't define (see e.g.
+    /// `ASSERTION_FAILED`): the result code of the inner response is replaced when encoding.
+    WithResultCode(Box<LdapResponseOp>, i64),
+}
+
+/// The assertion of the assertion control (RFC 4528) doesn't match the entry.
+pub const ASSERTION_FAILED: i64 = 122;
+/// The operation was canceled with the Cancel extended operation (RFC 3909).
+pub const CANCELED: i64 = 118;
+/// The operation to cancel with the Cancel extended operation (RFC 3909) is not in progress.
+pub const NO_SUCH_OPERATION: i64 = 119;
+/// The bound user can't act on behalf of the authorization ID of the proxied authorization
+/// control (RFC 4370).
+pub const AUTHORIZATION_DENIED: i64 = 123;
+
+impl LdapResponseOp {
+    /// The result of the operation, if this is the final response to a request.
+    pub fn result(&self) -> Option<&LdapResult> {
+        match self {
+            LdapResponseOp::Op(LdapOp::BindResponse(response)) => Some(&response.res),
+            LdapResponseOp::Op(LdapOp::SearchResultDone(result)) => Some(result),
+            LdapResponseOp::Op(LdapOp::ExtendedResponse(response)) => Some(&response.res),
+            LdapResponseOp::Op(_)
+            | LdapResponseOp::SearchResultReference(_)
+            | LdapResponseOp::IntermediateResponse { .. } => None,
+            LdapResponseOp::CompareResponse(result)
+            | LdapResponseOp::ModifyResponse(result)
+            | LdapResponseOp::ModifyDnResponse(result)
+            | LdapResponseOp::AddResponse(result)
+            | LdapResponseOp::DeleteResponse(result) => Some(result),
+            LdapResponseOp::Referral(op, _) => LdapResponseOp::Op(op.clone()).result(),
+            LdapResponseOp::WithResultCode(op, _) => op.result(),
+        }
+    }
+}
+
+impl From<LdapOp> for LdapResponseOp {
+    fn from(op: LdapOp) -> Self {
+        LdapResponseOp::Op(op)
+    }
+}
+
+/// An LDAP message, with its controls.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapFrame<Op = LdapResponseOp> {
+    pub msgid: i32,
+    pub op: Op,
+    pub controls: Vec<RawControl>,
+}
+
+const CONTROLS_TAG: u8 = context_constructed_tag(0);
+const BIND_REQUEST_TAG: u8 = 0x60;
+const SASL_CREDENTIALS_TAG: u8 = context_constructed_tag(3);
+const SEARCH_REQUEST_TAG: u8 = 0x63;
+const SEARCH_RESULT_ENTRY_TAG: u8 = 0x64;
+const SEARCH_RESULT_DONE_TAG: u8 = 0x65;
+const MODIFY_REQUEST_TAG: u8 = 0x66;
+const MODIFY_RESPONSE_TAG: u8 = 0x67;
+const ADD_REQUEST_TAG: u8 = 0x68;
+const ADD_RESPONSE_TAG: u8 = 0x69;
+const DELETE_REQUEST_TAG: u8 = 0x4A;
+const DELETE_RESPONSE_TAG: u8 = 0x6B;
+const MODIFY_DN_REQUEST_TAG: u8 = 0x6C;
+const MODIFY_DN_RESPONSE_TAG: u8 = 0x6D;
+const NEW_SUPERIOR_TAG: u8 = context_tag(0);
+const COMPARE_REQUEST_TAG: u8 = 0x6E;
+const COMPARE_RESPONSE_TAG: u8 = 0x6F;
+const ABANDON_REQUEST_TAG: u8 = 0x50;
+const SEARCH_RESULT_REFERENCE_TAG: u8 = 0x73;
+const INTERMEDIATE_RESPONSE_TAG: u8 = 0x79;
+const INTERMEDIATE_RESPONSE_NAME_TAG: u8 = context_tag(0);
+const INTERMEDIATE_RESPONSE_VALUE_TAG: u8 = context_tag(1);
+const REFERRAL_TAG: u8 = context_constructed_tag(3);
+const FILTER_AND_TAG: u8 = context_constructed_tag(0);
+const FILTER_OR_TAG: u8 = context_constructed_tag(1);
+const FILTER_NOT_TAG: u8 = context_constructed_tag(2);
+const FILTER_EQUALITY_TAG: u8 = context_constructed_tag(3);
+const FILTER_SUBSTRINGS_TAG: u8 = context_constructed_tag(4);
+const FILTER_PRESENT_TAG: u8 = context_tag(7);
+const FILTER_EXTENSIBLE_TAG: u8 = context_constructed_tag(9);
+const MATCHING_RULE_TAG: u8 = context_tag(1);
+const MATCHING_TYPE_TAG: u8 = context_tag(2);
+const MATCH_VALUE_TAG: u8 = context_tag(3);
+const DN_ATTRIBUTES_TAG: u8 = context_tag(4);
+/// The filters nested deeper than that are refused: they are parsed recursively, and a message
+/// can hold enough nesting levels to overflow the stack.
+const MAX_FILTER_DEPTH: usize = 64;
+const SUBSTRING_INITIAL_TAG: u8 = context_tag(0);
+const SUBSTRING_ANY_TAG: u8 = context_tag(1);
+const SUBSTRING_FINAL_TAG: u8 = context_tag(2);
+
+fn parse_control(element: BerElement) -> Result<RawControl> {
+    let mut fields = element
+        .expect_tag(TAG_SEQUENCE)
+        .context("while parsing a control")?
+        .children()?
+        .into_iter();
+    let oid = fields
+        .next()
+        .context("Missing control type")?
+        .expect_tag(TAG_OCTET_STRING)?
+        .as_string()?;
+    let mut control = RawControl {
+        oid,
+        criticality: false,
+        value: None,
+    };
+    for field in fields {
+        match field.tag {
+            TAG_BOOLEAN if control.value.is_none() => control.criticality = field.as_bool()?,
+            TAG_OCTET_STRING if control.value.is_none() => control.value = Some(field.value),
+            tag => bail!(
+                "Unexpected field in control `{}`: {:#04x}",
+                control.oid,
+                tag
+            ),
+        }
+    }
+    Ok(control)
+}
+
+fn encode_control(control: &RawControl) -> BerElement {
+    let mut fields = vec![BerElement::octet_string(control.oid.as_str())];
+    if control.criticality {
+        fields.push(BerElement::boolean(true));
+    }
+    if let Some(value) = &control.value {
+        fields.push(BerElement::octet_string(value.as_slice()));
+    }
+    BerElement::sequence(&fields)
+}
+
+fn invalid_data(error: anyhow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{:#}", error))
+}
+
+/// Parses the operation if it is a bind request with a protocol version other than 3.
+fn parse_unsupported_bind(op: &BerElement) -> Result<Option<UnsupportedBindRequest>> {
+    if op.tag != BIND_REQUEST_TAG {
+        return Ok(None);
+    }
+    let fields = op.children().context("while parsing a bind request")?;
+    let (version, name) = match fields.as_slice() {
+        [version, name, _authentication] => (version, name),
+        _ => bail!("Invalid bind request"),
+    };
+    let version = version.clone().expect_tag(TAG_INTEGER)?.as_integer()?;
+    if version == 3 {
+        return Ok(None);
+    }
+    Ok(Some(UnsupportedBindRequest {
+        dn: name.as_string()?,
+        version,
+    }))
+}
+
+/// Parses the operation if it is a SASL bind request.
+fn parse_sasl_bind(op: &BerElement) -> Result<Option<SaslBindRequest>> {
+    if op.tag != BIND_REQUEST_TAG {
+        return Ok(None);
+    }
+    let fields = op.children().context("while parsing a bind request")?;
+    let (name, authentication) = match fields.as_slice() {
+        [_version, name, authentication] => (name, authentication),
+        _ => bail!("Invalid bind request"),
+    };
+    if authentication.tag != SASL_CREDENTIALS_TAG {
+        return Ok(None);
+    }
+    let mut credentials = authentication.children()?.into_iter();
+    let mechanism = credentials
+        .next()
+        .context("Missing SASL mechanism")?
+        .expect_tag(TAG_OCTET_STRING)?
+        .as_string()?;
+    let credentials = credentials
+        .next()
+        .map(|c| c.expect_tag(TAG_OCTET_STRING))
+        .transpose()?
+        .map(|c| c.value);
+    Ok(Some(SaslBindRequest {
+        dn: name.as_string()?,
+        mechanism,
+        credentials,
+    }))
+}
+
+fn parse_compare(op: BerElement) -> Result<CompareRequest> {
+    let mut fields = op
+        .children()
+        .context("while parsing a compare request")?
+        .into_iter();
+    let dn = fields
+        .next()
+        .context("Missing compare entry")?
+        .expect_tag(TAG_OCTET_STRING)?
+        .as_string()?;
+    let mut assertion = fields
+        .next()
+        .context("Missing compare assertion")?
+        .expect_tag(TAG_SEQUENCE)?
+        .children()?
+        .into_iter();
+    let attribute = assertion
+        .next()
+        .context("Missing compare attribute")?
+        .expect_tag(TAG_OCTET_STRING)?
+        .as_string()?;
+    let value = assertion
+        .next()
+        .context("Missing compare value")?
+        .expect_tag(TAG_OCTET_STRING)?
+        .value;
+    Ok(CompareRequest {
+        dn,
+        attribute,
+        value,
+    })
+}
+
+fn parse_modification(change: BerElement) -> Result<Modification> {
+    let mut fields = change.expect_tag(TAG_SEQUENCE)?.children()?.into_iter();
+    let operation = match fields
+        .next()
+        .context("Missing modify operation")?
+        .expect_tag(TAG_ENUMERATED)?
+        .as_integer()?
+    {
+        0 => ModifyOperation::Add,
+        1 => ModifyOperation::Delete,
+        2 => ModifyOperation::Replace,
+        operation => bail!("Invalid modify operation: {}", operation),
+    };
+    let (name, values) = parse_attribute(fields.next().context("Missing modified attribute")?)?;
+    Ok(Modification {
+        operation,
+        attribute: name,
+        values,
+    })
+}
+
+/// Parses an attribute with its values: "SEQUENCE { type, SET OF value }".
+fn parse_attribute(attribute: BerElement) -> Result<(String, Vec<Vec<u8>>)> {
+    let mut attribute = attribute.expect_tag(TAG_SEQUENCE)?.children()?.into_iter();
+    let name = attribute
+        .next()
+        .context("Missing attribute type")?
+        .expect_tag(TAG_OCTET_STRING)?
+        .as_string()?;
+    let values = attribute
+        .next()
+        .context("Missing attribute values")?
+        .expect_tag(TAG_SET)?
+        .children()?
+        .into_iter()
+        .map(|v| Ok(v.expect_tag(TAG_OCTET_STRING)?.value))
+        .collect::<Result<Vec<_>>>()?;
+    Ok((name, values))
+}
+
+fn parse_add(op: BerElement) -> Result<AddRequest> {
+    let mut fields = op
+        .children()
+        .context("while parsing an add request")?
+        .into_iter();
+    let dn = fields
+        .next()
+        .context("Missing added entry")?
+        .expect_tag(TAG_OCTET_STRING)?
+        .as_string()?;
+    let attributes = fields
+        .next()
+        .context("Missing attributes")?
+        .expect_tag(TAG_SEQUENCE)?
+        .children()?
+        .into_iter()
+        .map(parse_attribute)
+        .collect::<Result<Vec<_>>>()?;
+    Ok(AddRequest { dn, attributes })
+}
+
+fn parse_substrings(element: BerElement) -> Result<LdapFilter> {
+    let mut fields = element.children()?.into_iter();
+    let attribute = fields
+        .next()
+        .context("Missing substring filter attribute")?
+        .expect_tag(TAG_OCTET_STRING)?
+        .as_string()?;
+    let mut substrings = LdapSubstringFilter::default();
+    for substring in fields
+        .next()
+        .context("Missing substrings")?
+        .expect_tag(TAG_SEQUENCE)?
+        .children()?
+    {
+        match substring.tag {
+            SUBSTRING_INITIAL_TAG => substrings.initial = Some(substring.as_string()?),
+            SUBSTRING_ANY_TAG => substrings.any.push(substring.as_string()?),
+            SUBSTRING_FINAL_TAG => substrings.final_ = Some(substring.as_string()?),
+            tag => bail!("Invalid substring tag: {:#04x}", tag),
+        }
+    }
+    Ok(LdapFilter::Substring(attribute, substrings))
+}
+
+/// Rewrites the extensible match filters, that `ldap3_server` can't decode, as equality filters
+/// on an attribute in the form of the string representation of the filter (RFC 4515): e.g.
+/// `(memberOf:1.2.840.113556.1.4.1941:=cn=admins,...)` becomes an equality filter on the
+/// attribute "memberOf:1.2.840.113556.1.4.1941:". The LDAP handler then interprets the matching
+/// rule. `depth` is the nesting level of the filter, see `MAX_FILTER_DEPTH`.
+fn rewrite_extensible_matches(filter: BerElement, depth: usize) -> Result<BerElement> {
+    if depth > MAX_FILTER_DEPTH {
+        bail!("The filter is nested more than {} levels", MAX_FILTER_DEPTH);
+    }
+    Ok(match filter.tag {
+        FILTER_AND_TAG | FILTER_OR_TAG | FILTER_NOT_TAG => {
+            let tag = filter.tag;
+            let children = filter.children()?;
+            // The children are a copy of the value: only one of them is kept at a time.
+            drop(filter);
+            BerElement::constructed(
+                tag,
+                &children
+                    .into_iter()
+                    .map(|child| rewrite_extensible_matches(child, depth + 1))
+                    .collect::<Result<Vec<_>>>()?,
+            )
+        }
+        FILTER_EXTENSIBLE_TAG => {
+            let mut rule = None;
+            let mut attribute = String::new();
+            let mut value = None;
+            let mut dn_attributes = false;
+            for field in filter.children()? {
+                match field.tag {
+                    MATCHING_RULE_TAG => rule = Some(field.as_string()?),
+                    MATCHING_TYPE_TAG => attribute = field.as_string()?,
+                    MATCH_VALUE_TAG => value = Some(field.value),
+                    DN_ATTRIBUTES_TAG => dn_attributes = field.as_bool()?,
+                    tag => bail!("Invalid extensible match tag: {:#04x}", tag),
+                }
+            }
+            let value = value.context("Missing extensible match value")?;
+            if dn_attributes {
+                attribute.push_str(":dn");
+            }
+            if let Some(rule) = rule {
+                attribute.push(':');
+                attribute.push_str(&rule);
+            }
+            attribute.push(':');
+            BerElement::constructed(
+                FILTER_EQUALITY_TAG,
+                &[
+                    BerElement::octet_string(attribute),
+                    BerElement::octet_string(value),
+                ],
+            )
+        }
+        _ => filter,
+    })
+}
+
+/// Rewrites the filter of a search request with `rewrite_extensible_matches`.
+fn rewrite_search_request(op: &BerElement) -> Result<BerElement> {
+    let mut fields = op.children().context("while parsing a search request")?;
+    if let Some(filter) = fields.get_mut(6) {
+        *filter = rewrite_extensible_matches(filter.clone(), 0)?;
+    }
+    Ok(BerElement::constructed(op.tag, &fields))
+}
+
+/// Parses a BER-encoded search filter, e.g. from a control. Only the filters supported in the
+/// searches are accepted.
+pub fn parse_filter(element: BerElement) -> Result<LdapFilter> {
+    parse_nested_filter(element, 0)
+}
+
+fn parse_nested_filter(element: BerElement, depth: usize) -> Result<LdapFilter> {
+    if depth > MAX_FILTER_DEPTH {
+        bail!("The filter is nested more than {} levels", MAX_FILTER_DEPTH);
+    }
+    // The children are a copy of the value: only one of them is kept at a time.
+    let parse_all = |element: BerElement| {
+        let children = element.children()?;
+        drop(element);
+        children
+            .into_iter()
+            .map(|child| parse_nested_filter(child, depth + 1))
+            .collect::<Result<Vec<_>>>()
+    };
+    Ok(match element.tag {
+        FILTER_AND_TAG => LdapFilter::And(parse_all(element)?),
+        FILTER_OR_TAG => LdapFilter::Or(parse_all(element)?),
+        FILTER_NOT_TAG => LdapFilter::Not(Box::new(
+            parse_all(element)?
+                .into_iter()
+                .next()
+                .context("Missing negated filter")?,
+        )),
+        FILTER_EQUALITY_TAG => {
+            let mut fields = element.children()?.into_iter();
+            let attribute = fields
+                .next()
+                .context("Missing equality filter attribute")?
+                .expect_tag(TAG_OCTET_STRING)?
+                .as_string()?;
+            let value = fields
+                .next()
+                .context("Missing equality filter value")?
+                .expect_tag(TAG_OCTET_STRING)?
+                .as_string()?;
+            LdapFilter::Equality(attribute, value)
+        }
+        FILTER_SUBSTRINGS_TAG => parse_substrings(element)?,
+        FILTER_PRESENT_TAG => LdapFilter::Present(element.as_string()?),
+        FILTER_EXTENSIBLE_TAG => {
+            parse_nested_filter(rewrite_extensible_matches(element, depth)?, depth)?
+        }
+        tag => bail!("Unsupported filter type: {:#04x}", tag),
+    })
+}
+
+fn parse_modify(op: BerElement) -> Result<ModifyRequest> {
+    let mut fields = op
+        .children()
+        .context("while parsing a modify request")?
+        .into_iter();
+    let dn = fields
+        .next()
+        .context("Missing modified entry")?
+        .expect_tag(TAG_OCTET_STRING)?
+        .as_string()?;
+    let changes = fields
+        .next()
+        .context("Missing modifications")?
+        .expect_tag(TAG_SEQUENCE)?
+        .children()?
+        .into_iter()
+        .map(parse_modification)
+        .collect::<Result<Vec<_>>>()?;
+    Ok(ModifyRequest { dn, changes })
+}
+
+fn parse_modify_dn(op: BerElement) -> Result<ModifyDnRequest> {
+    let mut fields = op
+        .children()
+        .context("while parsing a modify DN request")?
+        .into_iter();
+    let dn = fields
+        .next()
+        .context("Missing renamed entry")?
+        .expect_tag(TAG_OCTET_STRING)?
+        .as_string()?;
+    let new_rdn = fields
+        .next()
+        .context("Missing new RDN")?
+        .expect_tag(TAG_OCTET_STRING)?
+        .as_string()?;
+    let delete_old_rdn = fields
+        .next()
+        .context("Missing deleteoldrdn")?
+        .expect_tag(TAG_BOOLEAN)?
+        .as_bool()?;
+    let new_superior = fields
+        .next()
+        .map(|field| field.expect_tag(NEW_SUPERIOR_TAG)?.as_string())
+        .transpose()?;
+    Ok(ModifyDnRequest {
+        dn,
+        new_rdn,
+        delete_old_rdn,
+        new_superior,
+    })
+}
+
+/// Parses the operations that `ldap3_server` doesn't support.
+fn parse_custom_request(op: &BerElement) -> Result<Option<LdapRequest>> {
+    Ok(match op.tag {
+        COMPARE_REQUEST_TAG => Some(LdapRequest::Compare(parse_compare(op.clone())?)),
+        MODIFY_REQUEST_TAG => Some(LdapRequest::Modify(parse_modify(op.clone())?)),
+        MODIFY_DN_REQUEST_TAG => Some(LdapRequest::ModifyDn(parse_modify_dn(op.clone())?)),
+        ADD_REQUEST_TAG => Some(LdapRequest::Add(parse_add(op.clone())?)),
+        // The DN is the whole value of the request.
+        DELETE_REQUEST_TAG => Some(LdapRequest::Delete(DeleteRequest {
+            dn: op.as_string().context("while parsing a delete request")?,
+        })),
+        // The message ID is the whole value of the request.
+        ABANDON_REQUEST_TAG => Some(LdapRequest::Unsupported(UnsupportedOperation::Abandon(
+            i32::try_from(
+                op.as_integer()
+                    .context("while parsing an abandon request")?,
+            )
+            .context("Invalid message ID in an abandon request")?,
+        ))),
+        _ => match parse_unsupported_bind(op)? {
+            Some(request) => Some(LdapRequest::UnsupportedBind(request)),
+            None => parse_sasl_bind(op)?.map(LdapRequest::SaslBind),
+        },
+    })
+}
+
+/// Splits an encoded message into its fields, and its controls (if any).
+fn split_controls(message: BerElement) -> Result<(Vec<BerElement>, Vec<RawControl>)> {
+    let mut fields = message
+        .expect_tag(TAG_SEQUENCE)
+        .context("while parsing an LDAP message")?
+        .children()?;
+    if fields.last().map(|field| field.tag) != Some(CONTROLS_TAG) {
+        return Ok((fields, Vec::new()));
+    }
+    let controls = fields
+        .pop()
+        .unwrap()
+        .children()?
+        .into_iter()
+        .map(parse_control)
+        .collect::<Result<Vec<_>>>()?;
+    Ok((fields, controls))
+}
+
+/// Default limit of the size of the incoming messages.
+pub const DEFAULT_MAX_MESSAGE_BYTES: usize = 4 * 1024 * 1024;
+
+/// Codec for the LDAP messages, including their controls.
+#[derive(Debug, Clone, Copy)]
+pub struct LdapFrameCodec {
+    /// Larger incoming messages are refused before being buffered, and the connection dropped.
+    max_message_bytes: usize,
+}
+
+impl LdapFrameCodec {
+    pub fn new(max_message_bytes: usize) -> Self {
+        Self { max_message_bytes }
+    }
+}
+
+impl Default for LdapFrameCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_MESSAGE_BYTES)
+    }
+}
+
+impl Decoder for LdapFrameCodec {
+    type Item = LdapFrame<LdapRequest>;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<LdapFrame<LdapRequest>>> {
+        if let Some((_, header_length, length)) = parse_header(buf).map_err(invalid_data)? {
+            if header_length + length > self.max_message_bytes {
+                warn!(
+                    "Refusing an LDAP message of {} bytes, over the limit of {} bytes",
+                    header_length + length,
+                    self.max_message_bytes
+                );
+                return Err(invalid_data(anyhow::anyhow!("LDAP message too large")));
+            }
+        }
+        let (message, length) = match BerElement::parse(buf).map_err(invalid_data)? {
+            None => return Ok(None),
+            Some(parsed) => parsed,
+        };
+        let _ = buf.split_to(length);
+        let (mut fields, controls) = split_controls(message).map_err(invalid_data)?;
+        if let [msgid, op] = fields.as_slice() {
+            if let Some(request) = parse_custom_request(op).map_err(invalid_data)? {
+                let msgid = msgid
+                    .as_integer()
+                    .and_then(|id| i32::try_from(id).context("Invalid message id"))
+                    .map_err(invalid_data)?;
+                return Ok(Some(LdapFrame {
+                    msgid,
+                    op: request,
+                    controls,
+                }));
+            }
+        }
+        if let [_, op] = fields.as_mut_slice() {
+            if op.tag == SEARCH_REQUEST_TAG {
+                *op = rewrite_search_request(op).map_err(invalid_data)?;
+            }
+        }
+        let mut stripped = BytesMut::from(BerElement::sequence(&fields).encode().as_slice());
+        let msg = LdapCodec
+            .decode(&mut stripped)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+            .ok_or_else(|| invalid_data(anyhow::anyhow!("Incomplete LDAP message")))?;
+        Ok(Some(LdapFrame {
+            msgid: msg.msgid,
+            op: LdapRequest::Op(msg.op),
+            controls,
+        }))
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> io::Result<Option<LdapFrame<LdapRequest>>> {
+        match self.decode(buf)? {
+            Some(frame) => Ok(Some(frame)),
+            None if buf.is_empty() => Ok(None),
+            None => Err(invalid_data(anyhow::anyhow!("Truncated LDAP message"))),
+        }
+    }
+}
+
+/// The attributes with binary values, e.g. the photos. Since the values of `ldap3_server` are
+/// strings, they are base64-encoded in the search result entries, and decoded here to be sent as
+/// raw octet strings.
+const BINARY_ATTRIBUTES: &[&str] = &["jpegPhoto", "thumbnailPhoto"];
+
+pub fn is_binary_attribute(attribute: &str) -> bool {
+    BINARY_ATTRIBUTES
+        .iter()
+        .any(|a| a.eq_ignore_ascii_case(attribute))
+}
+
+pub fn encode_search_result_entry(entry: &LdapSearchResultEntry) -> Result<BerElement> {
+    let attributes = entry
+        .attributes
+        .iter()
+        .map(|attribute| {
+            let values = attribute
+                .vals
+                .iter()
+                .map(|value| {
+                    Ok(if is_binary_attribute(&attribute.atype) {
+                        BerElement::octet_string(base64::decode(value).with_context(|| {
+                            format!("Invalid binary value for {}", attribute.atype)
+                        })?)
+                    } else {
+                        BerElement::octet_string(value.as_str())
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            Ok(BerElement::sequence(&[
+                BerElement::octet_string(attribute.atype.as_str()),
+                BerElement::constructed(TAG_SET, &values),
+            ]))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(BerElement::constructed(
+        SEARCH_RESULT_ENTRY_TAG,
+        &[
+            BerElement::octet_string(entry.dn.as_str()),
+            BerElement::sequence(&attributes),
+        ],
+    ))
+}
+
+fn encode_controls(controls: &[RawControl]) -> BerElement {
+    BerElement::constructed(
+        CONTROLS_TAG,
+        &controls.iter().map(encode_control).collect::<Vec<_>>(),
+    )
+}
+
+fn encode_urls(tag: u8, urls: &[String]) -> BerElement {
+    BerElement::constructed(
+        tag,
+        &urls
+            .iter()
+            .map(|url| BerElement::octet_string(url.as_str()))
+            .collect::<Vec<_>>(),
+    )
+}
+
+fn encode_intermediate_response(name: Option<String>, value: Option<Vec<u8>>) -> BerElement {
+    let mut fields = vec![];
+    if let Some(name) = name {
+        fields.push(BerElement {
+            tag: INTERMEDIATE_RESPONSE_NAME_TAG,
+            value: name.into_bytes(),
+        });
+    }
+    if let Some(value) = value {
+        fields.push(BerElement {
+            tag: INTERMEDIATE_RESPONSE_VALUE_TAG,
+            value,
+        });
+    }
+    BerElement::constructed(INTERMEDIATE_RESPONSE_TAG, &fields)
+}
+
+impl Encoder<LdapFrame> for LdapFrameCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: LdapFrame, dst: &mut BytesMut) -> io::Result<()> {
+        let (response, result_code) = match frame.op {
+            LdapResponseOp::WithResultCode(response, code) => (*response, Some(code)),
+            response => (response, None),
+        };
+        if let LdapResponseOp::Op(LdapOp::SearchResultEntry(entry)) = &response {
+            if entry
+                .attributes
+                .iter()
+                .any(|a| is_binary_attribute(&a.atype))
+            {
+                let mut fields = vec![
+                    BerElement::integer(frame.msgid.into()),
+                    encode_search_result_entry(entry).map_err(invalid_data)?,
+                ];
+                if !frame.controls.is_empty() {
+                    fields.push(encode_controls(&frame.controls));
+                }
+                dst.extend_from_slice(&BerElement::sequence(&fields).encode());
+                return Ok(());
+            }
+        }
+        // The responses unknown to `ldap3_server` are only made of a result, like a
+        // SearchResultDone: they are encoded as such, and their tag is replaced.
+        let (op, custom_tag, referrals) = match response {
+            LdapResponseOp::Op(op) => (op, None, vec![]),
+            LdapResponseOp::CompareResponse(result) => (
+                LdapOp::SearchResultDone(result),
+                Some(COMPARE_RESPONSE_TAG),
+                vec![],
+            ),
+            LdapResponseOp::ModifyResponse(result) => (
+                LdapOp::SearchResultDone(result),
+                Some(MODIFY_RESPONSE_TAG),
+                vec![],
+            ),
+            LdapResponseOp::ModifyDnResponse(result) => (
+                LdapOp::SearchResultDone(result),
+                Some(MODIFY_DN_RESPONSE_TAG),
+                vec![],
+            ),
+            LdapResponseOp::AddResponse(result) => (
+                LdapOp::SearchResultDone(result),
+                Some(ADD_RESPONSE_TAG),
+                vec![],
+            ),
+            LdapResponseOp::DeleteResponse(result) => (
+                LdapOp::SearchResultDone(result),
+                Some(DELETE_RESPONSE_TAG),
+                vec![],
+            ),
+            LdapResponseOp::Referral(op, urls) => (op, None, urls),
+            LdapResponseOp::SearchResultReference(urls) => {
+                let mut fields = vec![
+                    BerElement::integer(frame.msgid.into()),
+                    encode_urls(SEARCH_RESULT_REFERENCE_TAG, &urls),
+                ];
+                if !frame.controls.is_empty() {
+                    fields.push(encode_controls(&frame.controls));
+                }
+                dst.extend_from_slice(&BerElement::sequence(&fields).encode());
+                return Ok(());
+            }
+            LdapResponseOp::IntermediateResponse { name, value } => {
+                let mut fields = vec![
+                    BerElement::integer(frame.msgid.into()),
+                    encode_intermediate_response(name, value),
+                ];
+                if !frame.controls.is_empty() {
+                    fields.push(encode_controls(&frame.controls));
+                }
+                dst.extend_from_slice(&BerElement::sequence(&fields).encode());
+                return Ok(());
+            }
+            LdapResponseOp::WithResultCode(..) => {
+                return Err(invalid_data(anyhow::anyhow!("Nested result code override")))
+            }
+        };
+        let msg = LdapMsg {
+            msgid: frame.msgid,
+            op,
+            ctrl: vec![],
+        };
+        if frame.controls.is_empty()
+            && custom_tag.is_none()
+            && referrals.is_empty()
+            && result_code.is_none()
+        {
+            return LdapCodec.encode(msg, dst);
+        }
+        let mut encoded = BytesMut::new();
+        LdapCodec.encode(msg, &mut encoded)?;
+        let message = BerElement::parse_complete(&encoded).map_err(invalid_data)?;
+        let (mut fields, _) = split_controls(message).map_err(invalid_data)?;
+        if let Some(tag) = custom_tag {
+            match fields.get_mut(1) {
+                Some(op) if op.tag == SEARCH_RESULT_DONE_TAG => op.tag = tag,
+                _ => return Err(invalid_data(anyhow::anyhow!("Unexpected encoded response"))),
+            }
+        }
+        // The result code is the first field of the result.
+        if let Some(code) = result_code {
+            let op = fields
+                .get_mut(1)
+                .ok_or_else(|| invalid_data(anyhow::anyhow!("Unexpected encoded response")))?;
+            let mut result = op.children().map_err(invalid_data)?;
+            match result.first_mut() {
+                Some(field) if field.tag == TAG_ENUMERATED => {
+                    *field = BerElement::integer_with_tag(TAG_ENUMERATED, code)
+                }
+                _ => return Err(invalid_data(anyhow::anyhow!("Unexpected encoded response"))),
+            }
+            *op = BerElement::constructed(op.tag, &result);
+        }
+        // The referrals come right after the result message, which is the last field of the
+        // responses without optional fields.
+        if !referrals.is_empty() {
+            match fields.get_mut(1) {
+                Some(op) => op
+                    .value
+                    .extend(encode_urls(REFERRAL_TAG, &referrals).encode()),
+                None => return Err(invalid_data(anyhow::anyhow!("Unexpected encoded response"))),
+            }
+        }
+        if frame.controls.is_empty() {
+            dst.extend_from_slice(&BerElement::sequence(&fields).encode());
+            return Ok(());
+        }
+        fields.push(encode_controls(&frame.controls));
+        dst.extend_from_slice(&BerElement::sequence(&fields).encode());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ldap3_server::proto::LdapResultCode;
+
+    #[test]
+    fn test_round_trip_with_controls() {
+        let op = LdapOp::SearchResultDone(LdapResult {
+            code: LdapResultCode::Success,
+            matcheddn: "".to_string(),
+            message: "".to_string(),
+            referral: vec![],
+        });
+        let frame = LdapFrame {
+            msgid: 3,
+            op: op.clone().into(),
+            controls: vec![
+                RawControl {
+                    oid: "1.2.840.113556.1.4.319".to_string(),
+                    criticality: true,
+                    value: Some(vec![0x30, 0x03, 0x02, 0x01, 0x05]),
+                },
+                RawControl {
+                    oid: "1.2.3".to_string(),
+                    criticality: false,
+                    value: None,
+                },
+            ],
+        };
+        let mut buf = BytesMut::new();
+        LdapFrameCodec::default()
+            .encode(frame.clone(), &mut buf)
+            .unwrap();
+        let decoded = LdapFrameCodec::default().decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded.op, LdapRequest::Op(op));
+        assert_eq!(decoded.controls, frame.controls);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_partial_message() {
+        let frame = LdapFrame {
+            msgid: 1,
+            op: LdapOp::UnbindRequest.into(),
+            controls: vec![],
+        };
+        let mut encoded = BytesMut::new();
+        LdapFrameCodec::default()
+            .encode(frame.clone(), &mut encoded)
+            .unwrap();
+        let mut buf = BytesMut::from(&encoded[..encoded.len() - 1]);
+        assert_eq!(LdapFrameCodec::default().decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(&encoded[encoded.len() - 1..]);
+        assert_eq!(
+            LdapFrameCodec::default().decode(&mut buf).unwrap(),
+            Some(LdapFrame {
+                msgid: 1,
+                op: LdapRequest::Op(LdapOp::UnbindRequest),
+                controls: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn test_decode_sasl_bind() {
+        let message = BerElement::sequence(&[
+            BerElement::integer(2),
+            BerElement::constructed(
+                BIND_REQUEST_TAG,
+                &[
+                    BerElement::integer(3),
+                    BerElement::octet_string(""),
+                    BerElement::constructed(
+                        SASL_CREDENTIALS_TAG,
+                        &[BerElement::octet_string("EXTERNAL")],
+                    ),
+                ],
+            ),
+        ]);
+        let mut buf = BytesMut::from(message.encode().as_slice());
+        assert_eq!(
+            LdapFrameCodec::default().decode(&mut buf).unwrap(),
+            Some(LdapFrame {
+                msgid: 2,
+                op: LdapRequest::SaslBind(SaslBindRequest {
+                    dn: "".to_string(),
+                    mechanism: "EXTERNAL".to_string(),
+                    credentials: None,
+                }),
+                controls: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn test_compare() {
+        let message = BerElement::sequence(&[
+            BerElement::integer(4),
+            BerElement::constructed(
+                COMPARE_REQUEST_TAG,
+                &[
+                    BerElement::octet_string("uid=bob,ou=people,dc=example,dc=com"),
+                    BerElement::sequence(&[
+                        BerElement::octet_string("mail"),
+                        BerElement::octet_string("bob@example.com"),
+                    ]),
+                ],
+            ),
+        ]);
+        let mut buf = BytesMut::from(message.encode().as_slice());
+        assert_eq!(
+            LdapFrameCodec::default().decode(&mut buf).unwrap(),
+            Some(LdapFrame {
+                msgid: 4,
+                op: LdapRequest::Compare(CompareRequest {
+                    dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                    attribute: "mail".to_string(),
+                    value: b"bob@example.com".to_vec(),
+                }),
+                controls: vec![],
+            })
+        );
+
+        let result = LdapResult {
+            code: LdapResultCode::CompareTrue,
+            matcheddn: "".to_string(),
+            message: "".to_string(),
+            referral: vec![],
+        };
+        let mut encoded = BytesMut::new();
+        LdapFrameCodec::default()
+            .encode(
+                LdapFrame {
+                    msgid: 4,
+                    op: LdapResponseOp::CompareResponse(result.clone()),
+                    controls: vec![],
+                },
+                &mut encoded,
+            )
+            .unwrap();
+        let fields = BerElement::parse_complete(&encoded)
+            .unwrap()
+            .children()
+            .unwrap();
+        assert_eq!(fields[1].tag, COMPARE_RESPONSE_TAG);
+        let mut expected = BytesMut::new();
+        LdapFrameCodec::default()
+            .encode(
+                LdapFrame {
+                    msgid: 4,
+                    op: LdapOp::SearchResultDone(result).into(),
+                    controls: vec![],
+                },
+                &mut expected,
+            )
+            .unwrap();
+        let expected_fields = BerElement::parse_complete(&expected)
+            .unwrap()
+            .children()
+            .unwrap();
+        assert_eq!(fields[1].value, expected_fields[1].value);
+    }
+
+    #[test]
+    fn test_decode_modify() {
+        let message = BerElement::sequence(&[
+            BerElement::integer(5),
+            BerElement::constructed(
+                MODIFY_REQUEST_TAG,
+                &[
+                    BerElement::octet_string("uid=bob,ou=people,dc=example,dc=com"),
+                    BerElement::sequence(&[BerElement::sequence(&[
+                        BerElement::integer_with_tag(TAG_ENUMERATED, 2),
+                        BerElement::sequence(&[
+                            BerElement::octet_string("userPassword"),
+                            BerElement::constructed(TAG_SET, &[BerElement::octet_string("secret")]),
+                        ]),
+                    ])]),
+                ],
+            ),
+        ]);
+        let mut buf = BytesMut::from(message.encode().as_slice());
+        assert_eq!(
+            LdapFrameCodec::default().decode(&mut buf).unwrap(),
+            Some(LdapFrame {
+                msgid: 5,
+                op: LdapRequest::Modify(ModifyRequest {
+                    dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                    changes: vec![Modification {
+                        operation: ModifyOperation::Replace,
+                        attribute: "userPassword".to_string(),
+                        values: vec![b"secret".to_vec()],
+                    }],
+                }),
+                controls: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn test_decode_add() {
+        let attribute = |name: &str, values: &[&str]| {
+            BerElement::sequence(&[
+                BerElement::octet_string(name),
+                BerElement::constructed(
+                    TAG_SET,
+                    &values
+                        .iter()
+                        .map(|v| BerElement::octet_string(*v))
+                        .collect::<Vec<_>>(),
+                ),
+            ])
+        };
+        let message = BerElement::sequence(&[
+            BerElement::integer(5),
+            BerElement::constructed(
+                ADD_REQUEST_TAG,
+                &[
+                    BerElement::octet_string("uid=bob,ou=people,dc=example,dc=com"),
+                    BerElement::sequence(&[
+                        attribute("objectClass", &["person", "inetOrgPerson"]),
+                        attribute("mail", &["bob@example.com"]),
+                    ]),
+                ],
+            ),
+        ]);
+        let mut buf = BytesMut::from(message.encode().as_slice());
+        assert_eq!(
+            LdapFrameCodec::default().decode(&mut buf).unwrap(),
+            Some(LdapFrame {
+                msgid: 5,
+                op: LdapRequest::Add(AddRequest {
+                    dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        (
+                            "objectClass".to_string(),
+                            vec![b"person".to_vec(), b"inetOrgPerson".to_vec()]
+                        ),
+                        ("mail".to_string(), vec![b"bob@example.com".to_vec()]),
+                    ],
+                }),
+                controls: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn test_encode_referrals() {
+        let url = "ldap://other.example.com/".to_string();
+        let done = LdapOp::SearchResultDone(LdapResult {
+            code: LdapResultCode::Referral,
+            matcheddn: "".to_string(),
+            message: "".to_string(),
+            referral: vec![],
+        });
+        let mut buf = BytesMut::new();
+        LdapFrameCodec::default()
+            .encode(
+                LdapFrame {
+                    msgid: 2,
+                    op: LdapResponseOp::Referral(done, vec![url.clone()]),
+                    controls: vec![],
+                },
+                &mut buf,
+            )
+            .unwrap();
+        let fields = BerElement::parse_complete(&buf)
+            .unwrap()
+            .children()
+            .unwrap();
+        assert_eq!(fields[1].tag, SEARCH_RESULT_DONE_TAG);
+        let result = fields[1].children().unwrap();
+        assert_eq!(result.len(), 4);
+        assert_eq!(result[3], encode_urls(REFERRAL_TAG, &[url.clone()]));
+
+        let mut buf = BytesMut::new();
+        LdapFrameCodec::default()
+            .encode(
+                LdapFrame {
+                    msgid: 2,
+                    op: LdapResponseOp::SearchResultReference(vec![url.clone()]),
+                    controls: vec![],
+                },
+                &mut buf,
+            )
+            .unwrap();
+        assert_eq!(
+            BerElement::parse_complete(&buf).unwrap(),
+            BerElement::sequence(&[
+                BerElement::integer(2),
+                encode_urls(SEARCH_RESULT_REFERENCE_TAG, &[url]),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_filter() {
+        let filter = BerElement::constructed(
+            FILTER_AND_TAG,
+            &[
+                BerElement {
+                    tag: FILTER_PRESENT_TAG,
+                    value: b"objectClass".to_vec(),
+                },
+                BerElement::constructed(
+                    FILTER_NOT_TAG,
+                    &[BerElement::constructed(
+                        FILTER_EQUALITY_TAG,
+                        &[
+                            BerElement::octet_string("uid"),
+                            BerElement::octet_string("bob"),
+                        ],
+                    )],
+                ),
+                BerElement::constructed(
+                    FILTER_SUBSTRINGS_TAG,
+                    &[
+                        BerElement::octet_string("mail"),
+                        BerElement::sequence(&[
+                            BerElement {
+                                tag: SUBSTRING_INITIAL_TAG,
+                                value: b"bob".to_vec(),
+                            },
+                            BerElement {
+                                tag: SUBSTRING_FINAL_TAG,
+                                value: b"@example.com".to_vec(),
+                            },
+                        ]),
+                    ],
+                ),
+            ],
+        );
+        assert_eq!(
+            parse_filter(filter).unwrap(),
+            LdapFilter::And(vec![
+                LdapFilter::Present("objectClass".to_string()),
+                LdapFilter::Not(Box::new(LdapFilter::Equality(
+                    "uid".to_string(),
+                    "bob".to_string()
+                ))),
+                LdapFilter::Substring(
+                    "mail".to_string(),
+                    LdapSubstringFilter {
+                        initial: Some("bob".to_string()),
+                        any: vec![],
+                        final_: Some("@example.com".to_string()),
+                    }
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_encode_result_code_override() {
+        let response = LdapResponseOp::ModifyResponse(LdapResult {
+            code: LdapResultCode::Other,
+            matcheddn: "".to_string(),
+            message: "".to_string(),
+            referral: vec![],
+        });
+        let mut buf = BytesMut::new();
+        LdapFrameCodec::default()
+            .encode(
+                LdapFrame {
+                    msgid: 4,
+                    op: LdapResponseOp::WithResultCode(Box::new(response), ASSERTION_FAILED),
+                    controls: vec![],
+                },
+                &mut buf,
+            )
+            .unwrap();
+        let fields = BerElement::parse_complete(&buf)
+            .unwrap()
+            .children()
+            .unwrap();
+        assert_eq!(fields[1].tag, MODIFY_RESPONSE_TAG);
+        assert_eq!(
+            fields[1].children().unwrap()[0],
+            BerElement::integer_with_tag(TAG_ENUMERATED, ASSERTION_FAILED)
+        );
+    }
+
+    #[test]
+    fn test_decode_message_too_large() {
+        // Only the header is needed to refuse the message.
+        let mut buf = BytesMut::from(&[0x30, 0x82, 0x10, 0x00][..]);
+        assert!(LdapFrameCodec::new(1024).decode(&mut buf).is_err());
+        assert!(LdapFrameCodec::default()
+            .decode(&mut buf)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_decode_malformed_messages() {
+        let mut codec = LdapFrameCodec::default();
+        let message =
+            BerElement::sequence(&[BerElement::integer(1), BerElement::constructed(0x42, &[])])
+                .encode();
+        // A truncated message is only an error once the stream is closed.
+        let mut buf = BytesMut::from(&message[..message.len() - 1]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        let error = codec.decode_eof(&mut buf).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        // Not a BER sequence.
+        let mut buf = BytesMut::from(&b"\x04\x02hi"[..]);
+        let error = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(codec.decode_eof(&mut BytesMut::new()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_decode_extensible_match() {
+        let filter = BerElement::constructed(
+            FILTER_AND_TAG,
+            &[BerElement::constructed(
+                FILTER_EXTENSIBLE_TAG,
+                &[
+                    BerElement {
+                        tag: MATCHING_RULE_TAG,
+                        value: b"1.2.840.113556.1.4.1941".to_vec(),
+                    },
+                    BerElement {
+                        tag: MATCHING_TYPE_TAG,
+                        value: b"memberOf".to_vec(),
+                    },
+                    BerElement {
+                        tag: MATCH_VALUE_TAG,
+                        value: b"cn=admins,ou=groups,dc=example,dc=com".to_vec(),
+                    },
+                ],
+            )],
+        );
+        let message = BerElement::sequence(&[
+            BerElement::integer(5),
+            BerElement::constructed(
+                SEARCH_REQUEST_TAG,
+                &[
+                    BerElement::octet_string("dc=example,dc=com"),
+                    BerElement::integer_with_tag(TAG_ENUMERATED, 2),
+                    BerElement::integer_with_tag(TAG_ENUMERATED, 0),
+                    BerElement::integer(0),
+                    BerElement::integer(0),
+                    BerElement::boolean(false),
+                    filter.clone(),
+                    BerElement::sequence(&[]),
+                ],
+            ),
+        ]);
+        let expected_filter = LdapFilter::And(vec![LdapFilter::Equality(
+            "memberOf:1.2.840.113556.1.4.1941:".to_string(),
+            "cn=admins,ou=groups,dc=example,dc=com".to_string(),
+        )]);
+        let mut buf = BytesMut::from(message.encode().as_slice());
+        match LdapFrameCodec::default()
+            .decode(&mut buf)
+            .unwrap()
+            .unwrap()
+            .op
+        {
+            LdapRequest::Op(LdapOp::SearchRequest(request)) => {
+                assert_eq!(request.base, "dc=example,dc=com");
+                assert_eq!(request.filter, expected_filter);
+            }
+            op => panic!("Unexpected operation: {:?}", op),
+        }
+        assert_eq!(parse_filter(filter).unwrap(), expected_filter);
+    }
+
+    #[test]
+    fn test_decode_deeply_nested_filter() {
+        let nested_filter = |depth| {
+            let mut filter = BerElement {
+                tag: FILTER_PRESENT_TAG,
+                value: b"objectClass".to_vec(),
+            };
+            for _ in 0..depth {
+                filter = BerElement::constructed(FILTER_NOT_TAG, &[filter]);
+            }
+            filter
+        };
+        let search_message = |filter| {
+            BerElement::sequence(&[
+                BerElement::integer(5),
+                BerElement::constructed(
+                    SEARCH_REQUEST_TAG,
+                    &[
+                        BerElement::octet_string("dc=example,dc=com"),
+                        BerElement::integer_with_tag(TAG_ENUMERATED, 2),
+                        BerElement::integer_with_tag(TAG_ENUMERATED, 0),
+                        BerElement::integer(0),
+                        BerElement::integer(0),
+                        BerElement::boolean(false),
+                        filter,
+                        BerElement::sequence(&[]),
+                    ],
+                ),
+            ])
+        };
+        assert!(parse_filter(nested_filter(MAX_FILTER_DEPTH)).is_ok());
+        let mut buf = BytesMut::from(
+            search_message(nested_filter(MAX_FILTER_DEPTH))
+                .encode()
+                .as_slice(),
+        );
+        assert!(LdapFrameCodec::default().decode(&mut buf).is_ok());
+        assert!(parse_filter(nested_filter(MAX_FILTER_DEPTH + 1)).is_err());
+        let mut buf = BytesMut::from(search_message(nested_filter(1000)).encode().as_slice());
+        let error = LdapFrameCodec::default().decode(&mut buf).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_decode_modify_dn() {
+        let message = BerElement::sequence(&[
+            BerElement::integer(6),
+            BerElement::constructed(
+                MODIFY_DN_REQUEST_TAG,
+                &[
+                    BerElement::octet_string("uid=bob,ou=people,dc=example,dc=com"),
+                    BerElement::octet_string("uid=robert"),
+                    BerElement::boolean(true),
+                    BerElement {
+                        tag: NEW_SUPERIOR_TAG,
+                        value: b"ou=people,dc=example,dc=com".to_vec(),
+                    },
+                ],
+            ),
+        ]);
+        let mut buf = BytesMut::from(message.encode().as_slice());
+        assert_eq!(
+            LdapFrameCodec::default().decode(&mut buf).unwrap(),
+            Some(LdapFrame {
+                msgid: 6,
+                op: LdapRequest::ModifyDn(ModifyDnRequest {
+                    dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                    new_rdn: "uid=robert".to_string(),
+                    delete_old_rdn: true,
+                    new_superior: Some("ou=people,dc=example,dc=com".to_string()),
+                }),
+                controls: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn test_encode_binary_attribute() {
+        use ldap3_server::proto::LdapPartialAttribute;
+        let photo = vec![0xFF, 0xD8, 0x00, 0xFF, 0xD9];
+        let entry = LdapSearchResultEntry {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            attributes: vec![
+                LdapPartialAttribute {
+                    atype: "uid".to_string(),
+                    vals: vec!["bob".to_string()],
+                },
+                LdapPartialAttribute {
+                    atype: "jpegPhoto".to_string(),
+                    vals: vec![base64::encode(&photo)],
+                },
+            ],
+        };
+        let mut buf = BytesMut::new();
+        LdapFrameCodec::default()
+            .encode(
+                LdapFrame {
+                    msgid: 2,
+                    op: LdapOp::SearchResultEntry(entry.clone()).into(),
+                    controls: vec![],
+                },
+                &mut buf,
+            )
+            .unwrap();
+        let fields = BerElement::parse_complete(&buf)
+            .unwrap()
+            .children()
+            .unwrap();
+        assert_eq!(fields[1].tag, SEARCH_RESULT_ENTRY_TAG);
+        let attributes = fields[1].children().unwrap()[1].children().unwrap();
+        let photo_attribute = attributes[1].children().unwrap();
+        assert_eq!(photo_attribute[0], BerElement::octet_string("jpegPhoto"));
+        assert_eq!(
+            photo_attribute[1].children().unwrap(),
+            vec![BerElement::octet_string(photo)]
+        );
+
+        // The other attributes are encoded like `ldap3_server` does.
+        let mut text_entry = entry;
+        text_entry.attributes.truncate(1);
+        let mut expected = BytesMut::new();
+        LdapCodec
+            .encode(
+                LdapMsg {
+                    msgid: 2,
+                    op: LdapOp::SearchResultEntry(text_entry.clone()),
+                    ctrl: vec![],
+                },
+                &mut expected,
+            )
+            .unwrap();
+        let expected = BerElement::parse_complete(&expected)
+            .unwrap()
+            .children()
+            .unwrap();
+        assert_eq!(
+            encode_search_result_entry(&text_entry).unwrap(),
+            expected[1]
+        );
+    }
+
+    #[test]
+    fn test_decode_bind_version() {
+        let make_message = |version| {
+            BerElement::sequence(&[
+                BerElement::integer(1),
+                BerElement::constructed(
+                    BIND_REQUEST_TAG,
+                    &[
+                        BerElement::integer(version),
+                        BerElement::octet_string("uid=bob,ou=people,dc=example,dc=com"),
+                        BerElement {
+                            tag: context_tag(0),
+                            value: b"pass".to_vec(),
+                        },
+                    ],
+                ),
+            ])
+        };
+        let mut buf = BytesMut::from(make_message(2).encode().as_slice());
+        assert_eq!(
+            LdapFrameCodec::default().decode(&mut buf).unwrap(),
+            Some(LdapFrame {
+                msgid: 1,
+                op: LdapRequest::UnsupportedBind(UnsupportedBindRequest {
+                    dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                    version: 2,
+                }),
+                controls: vec![],
+            })
+        );
+        let mut buf = BytesMut::from(make_message(3).encode().as_slice());
+        assert!(matches!(
+            LdapFrameCodec::default().decode(&mut buf).unwrap(),
+            Some(LdapFrame {
+                op: LdapRequest::Op(LdapOp::BindRequest(_)),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_decode_unsupported_operations() {
+        let message = BerElement::sequence(&[
+            BerElement::integer(4),
+            BerElement::integer_with_tag(ABANDON_REQUEST_TAG, 3),
+        ]);
+        let mut buf = BytesMut::from(message.encode().as_slice());
+        assert_eq!(
+            LdapFrameCodec::default().decode(&mut buf).unwrap(),
+            Some(LdapFrame {
+                msgid: 4,
+                op: LdapRequest::Unsupported(UnsupportedOperation::Abandon(3)),
+                controls: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn test_decode_delete() {
+        let message = BerElement::sequence(&[
+            BerElement::integer(4),
+            BerElement {
+                tag: DELETE_REQUEST_TAG,
+                value: b"uid=bob,ou=people,dc=example,dc=com".to_vec(),
+            },
+        ]);
+        let mut buf = BytesMut::from(message.encode().as_slice());
+        assert_eq!(
+            LdapFrameCodec::default().decode(&mut buf).unwrap(),
+            Some(LdapFrame {
+                msgid: 4,
+                op: LdapRequest::Delete(DeleteRequest {
+                    dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                }),
+                controls: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn test_encode_delete_response() {
+        let mut buf = BytesMut::new();
+        LdapFrameCodec::default()
+            .encode(
+                LdapFrame {
+                    msgid: 4,
+                    op: LdapResponseOp::DeleteResponse(LdapResult {
+                        code: LdapResultCode::UnwillingToPerform,
+                        matcheddn: "".to_string(),
+                        message: "The admin account cannot be deleted".to_string(),
+                        referral: vec![],
+                    }),
+                    controls: vec![],
+                },
+                &mut buf,
+            )
+            .unwrap();
+        let fields = BerElement::parse_complete(&buf)
+            .unwrap()
+            .children()
+            .unwrap();
+        assert_eq!(fields[1].tag, DELETE_RESPONSE_TAG);
+        assert_eq!(
+            fields[1].children().unwrap()[0],
+            BerElement::integer_with_tag(TAG_ENUMERATED, 53)
+        );
+    }
+
+    #[test]
+    fn test_encode_intermediate_response() {
+        let encode = |name: Option<&str>, value: Option<&[u8]>| {
+            let mut buf = BytesMut::new();
+            LdapFrameCodec::default()
+                .encode(
+                    LdapFrame {
+                        msgid: 3,
+                        op: LdapResponseOp::IntermediateResponse {
+                            name: name.map(str::to_string),
+                            value: value.map(<[u8]>::to_vec),
+                        },
+                        controls: vec![],
+                    },
+                    &mut buf,
+                )
+                .unwrap();
+            BerElement::parse_complete(&buf).unwrap()
+        };
+        assert_eq!(
+            encode(Some("1.3.6.1.4.1.4203.1.9.1.4"), Some(b"\x30\x00")),
+            BerElement::sequence(&[
+                BerElement::integer(3),
+                BerElement::constructed(
+                    INTERMEDIATE_RESPONSE_TAG,
+                    &[
+                        BerElement {
+                            tag: INTERMEDIATE_RESPONSE_NAME_TAG,
+                            value: b"1.3.6.1.4.1.4203.1.9.1.4".to_vec(),
+                        },
+                        BerElement {
+                            tag: INTERMEDIATE_RESPONSE_VALUE_TAG,
+                            value: b"\x30\x00".to_vec(),
+                        },
+                    ],
+                ),
+            ])
+        );
+        // Both fields are optional.
+        assert_eq!(
+            encode(None, None),
+            BerElement::sequence(&[
+                BerElement::integer(3),
+                BerElement::constructed(INTERMEDIATE_RESPONSE_TAG, &[]),
+            ])
+        );
+        assert_eq!(
+            LdapResponseOp::IntermediateResponse {
+                name: None,
+                value: None
+            }
+            .result(),
+            None
+        );
+    }
+}
@@ -1,21 +1,250 @@
 use crate::domain::{
+    error::DomainError,
     handler::{
-        BackendHandler, BindRequest, Group, GroupRequestFilter, LoginHandler, User, UserId,
-        UserRequestFilter,
+        make_entry_uuid, BackendHandler, BindRequest, ChangeReceiver, CreateUserRequest, Group,
+        GroupId, GroupIdAndName, GroupRequestFilter, LoginHandler, PasswordMetadata,
+        SubStringFilter, User, UserId, UserRequestFilter, ENTRY_UUID_NAMESPACE, POSIX_ID_RANGE,
     },
     opaque_handler::OpaqueHandler,
 };
+use crate::infra::{
+    ber::{
+        context_constructed_tag, context_tag, BerElement, TAG_BOOLEAN, TAG_ENUMERATED, TAG_INTEGER,
+        TAG_OCTET_STRING, TAG_SEQUENCE,
+    },
+    client_certificate::{parse_certificate_identity, CertificateIdentity},
+    configuration::UserRdnAttribute,
+    connection_limiter::{UserConnectionGuard, UserConnectionLimiter},
+    group_cache::GroupCache,
+    ldap_codec::{
+        encode_search_result_entry, is_binary_attribute, parse_filter, AddRequest, CompareRequest,
+        DeleteRequest, LdapRequest, LdapResponseOp, Modification, ModifyDnRequest, ModifyOperation,
+        ModifyRequest, RawControl, SaslBindRequest, UnsupportedBindRequest, UnsupportedOperation,
+        ASSERTION_FAILED, AUTHORIZATION_DENIED, CANCELED, NO_SUCH_OPERATION,
+    },
+    ldap_schema::{is_schema_dn, schema_response, to_ldif, SCHEMA_DN},
+    operation_log::{filter_to_string, OperationLog},
+    rate_limiter::{BindFailureTracker, BindRateLimiter},
+    search_cache::{SearchCache, SearchCacheKey},
+};
 use anyhow::{bail, Context, Result};
 use ldap3_server::proto::{
-    LdapBindCred, LdapBindRequest, LdapBindResponse, LdapExtendedRequest, LdapExtendedResponse,
-    LdapFilter, LdapOp, LdapPartialAttribute, LdapPasswordModifyRequest, LdapResult,
-    LdapResultCode, LdapSearchRequest, LdapSearchResultEntry, LdapSearchScope,
+    LdapBindCred, LdapBindRequest, LdapBindResponse, LdapDerefAliases, LdapExtendedRequest,
+    LdapExtendedResponse, LdapFilter, LdapOp, LdapPartialAttribute, LdapPasswordModifyRequest,
+    LdapResult, LdapResultCode, LdapSearchRequest, LdapSearchResultEntry, LdapSearchScope,
+    LdapSubstringFilter,
+};
+use log::{debug, info, warn};
+use secstr::SecUtf8;
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    future::Future,
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
 };
-use log::{debug, warn};
+use uuid::Uuid;
+
+const PASSWORD_MODIFY_OID: &str = "1.3.6.1.4.1.4203.1.11.1";
+const START_TLS_OID: &str = "1.3.6.1.4.1.1466.20037";
+const WHOAMI_OID: &str = "1.3.6.1.4.1.4203.1.11.3";
+const CANCEL_OID: &str = "1.3.6.1.1.8";
+/// The extended operation describing the features of the server. lldap has no OID arc of its
+/// own: this is a UUID-based OID (X.667).
+const CAPABILITIES_OID: &str = "2.25.323347533054621635317816244393214589870";
+/// The extended operation returning the subschema entry as LDIF, also a UUID-based OID.
+const SCHEMA_LDIF_OID: &str = "2.25.281361238156171529779261293673349677597";
+/// The extended operation returning the last operations of the operation log, for the admins.
+const OPERATION_LOG_OID: &str = "2.25.109627429863746967280163388015662528003";
+const PAGED_RESULTS_OID: &str = "1.2.840.113556.1.4.319";
+const MANAGE_DSA_IT_OID: &str = "2.16.840.1.113730.3.4.2";
+const ASSERTION_OID: &str = "1.3.6.1.1.12";
+const PROXIED_AUTHORIZATION_OID: &str = "2.16.840.1.113730.3.4.18";
+const DONT_USE_COPY_OID: &str = "1.3.6.1.1.22";
+const PRE_READ_OID: &str = "1.3.6.1.1.13.1";
+const POST_READ_OID: &str = "1.3.6.1.1.13.2";
+const SORT_REQUEST_OID: &str = "1.2.840.113556.1.4.473";
+const SORT_RESPONSE_OID: &str = "1.2.840.113556.1.4.474";
+const MATCHED_VALUES_OID: &str = "1.2.826.0.1.3344810.2.3";
+/// The content synchronization (RFC 4533): the request and the response controls, and the
+/// intermediate response.
+const SYNC_REQUEST_OID: &str = "1.3.6.1.4.1.4203.1.9.1.1";
+const SYNC_STATE_OID: &str = "1.3.6.1.4.1.4203.1.9.1.2";
+const SYNC_DONE_OID: &str = "1.3.6.1.4.1.4203.1.9.1.3";
+const SYNC_INFO_OID: &str = "1.3.6.1.4.1.4203.1.9.1.4";
+/// The states of the entries in the sync state control.
+const SYNC_STATE_ADD: i64 = 1;
+const SYNC_STATE_MODIFY: i64 = 2;
+const SYNC_STATE_DELETE: i64 = 3;
+/// The password policy control (draft-behera-ldap-password-policy), with the error codes of its
+/// response.
+const PASSWORD_POLICY_OID: &str = "1.3.6.1.4.1.42.2.27.8.5.1";
+const PASSWORD_POLICY_ACCOUNT_LOCKED: i64 = 1;
+/// The sortResult codes of the sort response control (the LDAP result codes).
+const SORT_SUCCESS: i64 = 0;
+const SORT_UNWILLING_TO_PERFORM: i64 = 53;
+/// The paged searches kept for their next pages, by connection. Over that, the oldest one is
+/// dropped.
+const MAX_PAGED_SEARCHES: usize = 16;
+/// Default limit of the size of the avatars returned in the searches.
+pub const DEFAULT_MAX_PHOTO_BYTES: usize = 512 * 1024;
+/// Default limit of the size of the attribute values sent in the adds and modifications.
+pub const DEFAULT_MAX_ATTRIBUTE_VALUE_BYTES: usize = 64 * 1024;
+/// The operations that can be disabled, by their `LdapRequest::op_type`. The unbinds and the
+/// abandons have no response, they can't be refused.
+pub const DISABLEABLE_OPERATIONS: &[&str] = &[
+    "bind", "search", "compare", "add", "delete", "modify", "modifydn", "extended",
+];
+/// The operational attributes returned with the ManageDsaIT control.
+const OPERATIONAL_ATTRIBUTES: &[&str] = &[
+    "createTimestamp",
+    "modifyTimestamp",
+    "entryUUID",
+    "entryDN",
+    "hasSubordinates",
+];
+/// The attributes of the users returned for "*".
+const USER_ATTRIBUTES: &[&str] = &[
+    "objectClass",
+    "uid",
+    "mail",
+    "givenName",
+    "sn",
+    "cn",
+    "displayName",
+    "uidNumber",
+    "gidNumber",
+    "homeDirectory",
+];
+/// The attributes of the groups returned for "*".
+const GROUP_ATTRIBUTES: &[&str] = &[
+    "objectClass",
+    "cn",
+    "uid",
+    "member",
+    "uniqueMember",
+    "memberUid",
+    "gidNumber",
+];
+const ADMIN_GROUP_NAME: &str = "lldap_admin";
+/// Both forms of groups are served, with the same members in `member` and `uniqueMember`, for
+/// the clients that only understand one of them. The groups are also POSIX groups.
+const GROUP_OBJECT_CLASSES: &[&str] = &["groupOfNames", "groupOfUniqueNames", "posixGroup"];
+/// The ID of the virtual group of all the users, never used by the database.
+const ALL_USERS_GROUP_ID: GroupId = GroupId(0);
+/// Default start of the POSIX IDs of the users, above the gidNumbers of the groups.
+pub const DEFAULT_POSIX_UID_OFFSET: u32 = 100_000;
+/// Default offset of the gidNumbers of the groups.
+pub const DEFAULT_POSIX_GID_OFFSET: u32 = 10_000;
+/// The group IDs whose gidNumbers are reserved, from 1: with the default offsets, they end right
+/// below the POSIX IDs of the users. The database would have to create more groups than that for
+/// a gidNumber to land outside.
+const MAX_POSIX_GROUP_ID: u32 = 89_999;
+/// The POSIX IDs below are those of the system accounts.
+const MIN_POSIX_ID: u32 = 1000;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 struct LdapDn(String);
 
+impl LdapDn {
+    /// The canonical form of a DN: lowercase, without spaces around the separators, so that
+    /// equivalent DNs compare equal.
+    fn normalized(dn: &str) -> Result<Self> {
+        Ok(LdapDn(
+            parse_distinguished_name(dn)?
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, escape_dn_value(&value.to_lowercase())))
+                .collect::<Vec<_>>()
+                .join(","),
+        ))
+    }
+}
+
+/// The DNs of the binds are logged up to this length.
+const LOGGED_DN_MAX_CHARS: usize = 256;
+
+/// A value sent by the client, as it can be logged: with the control characters and quotes
+/// escaped, and truncated to `LOGGED_DN_MAX_CHARS`.
+fn escape_for_log(value: &str) -> String {
+    let mut escaped = value
+        .chars()
+        .take(LOGGED_DN_MAX_CHARS)
+        .flat_map(char::escape_debug)
+        .collect::<String>();
+    if value.chars().nth(LOGGED_DN_MAX_CHARS).is_some() {
+        escaped.push_str("...");
+    }
+    escaped
+}
+
+/// Escapes the special characters of a DN attribute value (RFC 4514).
+fn escape_dn_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        let special = matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=')
+            || (i == 0 && (c == '#' || c == ' '))
+            || (i == last && c == ' ');
+        if c == '\0' {
+            escaped.push_str("\\00");
+            continue;
+        }
+        if special {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Reverses `escape_dn_value`, also decoding the hex-escaped bytes (e.g. "\2C").
+fn unescape_dn_value(value: &str) -> String {
+    let hex_digit = |b: &u8| (*b as char).to_digit(16).map(|d| d as u8);
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+        if b != b'\\' || rest.is_empty() {
+            bytes.push(b);
+            continue;
+        }
+        match (
+            rest.first().and_then(hex_digit),
+            rest.get(1).and_then(hex_digit),
+        ) {
+            (Some(high), Some(low)) => {
+                bytes.push((high << 4) | low);
+                rest = &rest[2..];
+            }
+            _ => {
+                bytes.push(rest[0]);
+                rest = &rest[1..];
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Splits on the separator, except where it is escaped with a backslash.
+fn split_unescaped(s: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == separator {
+            parts.push(&s[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
 fn make_dn_pair<I>(mut iter: I) -> Result<(String, String)>
 where
     I: Iterator<Item = String>,
@@ -37,84 +266,332 @@ where
     Ok(pair)
 }
 
+/// Removes the spaces around the value, except for an escaped trailing space.
+fn trim_unescaped(s: &str) -> &str {
+    let s = s.trim_start();
+    let trimmed = s.trim_end();
+    let backslashes = trimmed.len() - trimmed.trim_end_matches('\\').len();
+    if backslashes % 2 == 1 && trimmed.len() < s.len() {
+        &s[..trimmed.len() + 1]
+    } else {
+        trimmed
+    }
+}
+
+/// Splits a DN into its (attribute, value) pairs, most specific first. The attribute names are
+/// lowercased, the values are unescaped, and the spaces around the separators are ignored.
 fn parse_distinguished_name(dn: &str) -> Result<Vec<(String, String)>> {
-    dn.split(',')
-        .map(|s| make_dn_pair(s.split('=').map(str::trim).map(String::from)))
+    split_unescaped(dn, ',')
+        .into_iter()
+        .map(|s| {
+            let (name, value) = make_dn_pair(
+                split_unescaped(s, '=')
+                    .into_iter()
+                    .map(trim_unescaped)
+                    .map(String::from),
+            )?;
+            Ok((name.to_lowercase(), unescape_dn_value(&value)))
+        })
         .collect()
 }
 
+/// Checks that the DN can be used as a base DN: every component has a name and a value.
+pub fn check_base_dn(dn: &str) -> Result<()> {
+    let components = parse_distinguished_name(dn)?;
+    if components
+        .iter()
+        .any(|(name, value)| name.is_empty() || value.is_empty())
+    {
+        bail!("Empty DN component");
+    }
+    Ok(())
+}
+
+impl UserRdnAttribute {
+    fn name(self) -> &'static str {
+        match self {
+            UserRdnAttribute::Cn => "cn",
+            UserRdnAttribute::Uid => "uid",
+            UserRdnAttribute::Mail => "mail",
+        }
+    }
+}
+
+/// The DN of a user, from the value of its RDN attribute (e.g. its email for "mail"), and the
+/// OU under the people OU it is in, if any.
+fn make_user_dn(
+    rdn_attribute: UserRdnAttribute,
+    value: &str,
+    ou: Option<&str>,
+    people_dn_str: &str,
+) -> String {
+    format!(
+        "{}={},{}{}",
+        rdn_attribute.name(),
+        escape_dn_value(value),
+        ou.map(|ou| format!("ou={},", escape_dn_value(ou)))
+            .unwrap_or_default(),
+        people_dn_str
+    )
+}
+
+/// The DN of a group, from its name.
+fn make_group_dn(display_name: &str, groups_dn_str: &str) -> String {
+    format!("cn={},{}", escape_dn_value(display_name), groups_dn_str)
+}
+
+/// Whether the DN element is the given organizational unit, e.g. "ou=employees".
+fn is_ou(element: &(String, String), name: &str) -> bool {
+    element.0 == "ou" && element.1.eq_ignore_ascii_case(name)
+}
+
+/// Whether the DN element is the given RDN, e.g. the people OU.
+fn is_rdn(element: &(String, String), rdn: &(String, String)) -> bool {
+    element.0 == rdn.0 && element.1.eq_ignore_ascii_case(&rdn.1)
+}
+
+/// Parses the RDN of the people or groups OU, e.g. "ou=people".
+pub fn parse_ou_rdn(rdn: &str) -> Result<(String, String)> {
+    match parse_distinguished_name(rdn)?.as_slice() {
+        [(name, value)] if !name.is_empty() && !value.is_empty() => {
+            Ok((name.clone(), value.clone()))
+        }
+        _ => bail!(
+            r#"Expected a single RDN such as "ou=people", got "{}""#,
+            rdn
+        ),
+    }
+}
+
 fn get_group_id_from_distinguished_name(
     dn: &str,
     base_tree: &[(String, String)],
-    base_dn_str: &str,
+    groups_ou: &(String, String),
+    groups_dn_str: &str,
 ) -> Result<String> {
     let parts = parse_distinguished_name(dn).context("while parsing a group ID")?;
     if !is_subtree(&parts, base_tree) {
         bail!("Not a subtree of the base tree");
     }
     if parts.len() == base_tree.len() + 2 {
-        if parts[1].0 != "ou" || parts[1].1 != "groups" || parts[0].0 != "cn" {
+        if !is_rdn(&parts[1], groups_ou) || parts[0].0 != "cn" {
             bail!(
-                r#"Unexpected group DN format. Got "{}", expected: "cn=groupname,ou=groups,{}""#,
+                r#"Unexpected group DN format. Got "{}", expected: "cn=groupname,{}""#,
                 dn,
-                base_dn_str
+                groups_dn_str
             );
         }
         Ok(parts[0].1.to_string())
     } else {
         bail!(
-            r#"Unexpected group DN format. Got "{}", expected: "cn=groupname,ou=groups,{}""#,
+            r#"Unexpected group DN format. Got "{}", expected: "cn=groupname,{}""#,
             dn,
-            base_dn_str
+            groups_dn_str
         );
     }
 }
 
-fn get_user_id_from_distinguished_name(
+/// The name of the group of a DN, e.g. from the configuration, for the given base DN and groups OU.
+pub fn parse_group_dn(dn: &str, base_dn: &str, groups_ou: &str) -> Result<String> {
+    get_group_id_from_distinguished_name(
+        dn,
+        &parse_distinguished_name(base_dn)?,
+        &parse_ou_rdn(groups_ou)?,
+        &format!("{},{}", groups_ou, base_dn),
+    )
+}
+
+/// Returns the value of the RDN of a user DN: the user ID, or the email for the "mail" RDN.
+fn get_user_rdn_value_from_distinguished_name(
     dn: &str,
     base_tree: &[(String, String)],
-    base_dn_str: &str,
-) -> Result<UserId> {
+    people_ou: &(String, String),
+    people_dn_str: &str,
+    rdn_attribute: UserRdnAttribute,
+) -> Result<String> {
     let parts = parse_distinguished_name(dn).context("while parsing a user ID")?;
     if !is_subtree(&parts, base_tree) {
         bail!("Not a subtree of the base tree");
     }
-    if parts.len() == base_tree.len() + 2 {
-        if parts[1].0 != "ou"
-            || parts[1].1 != "people"
-            || (parts[0].0 != "cn" && parts[0].0 != "uid")
-        {
+    let valid_rdn = |name: &str| match rdn_attribute {
+        UserRdnAttribute::Cn => name == "cn" || name == "uid",
+        rdn_attribute => name == rdn_attribute.name(),
+    };
+    let expected = match rdn_attribute {
+        UserRdnAttribute::Mail => "mail=email",
+        _ => "uid=username",
+    };
+    // The users can also be in an OU under the people OU.
+    let is_in_people = match parts.len() - base_tree.len() {
+        2 => is_rdn(&parts[1], people_ou),
+        3 => parts[1].0 == "ou" && is_rdn(&parts[2], people_ou),
+        _ => false,
+    };
+    if parts.len() == base_tree.len() + 2 || parts.len() == base_tree.len() + 3 {
+        if !is_in_people || !valid_rdn(&parts[0].0) {
             bail!(
-                r#"Unexpected user DN format. Got "{}", expected: "uid=username,ou=people,{}""#,
+                r#"Unexpected user DN format. Got "{}", expected: "{},{}""#,
                 dn,
-                base_dn_str
+                expected,
+                people_dn_str
             );
         }
-        Ok(UserId::new(&parts[0].1))
+        Ok(parts[0].1.clone())
     } else {
         bail!(
-            r#"Unexpected user DN format. Got "{}", expected: "uid=username,ou=people,{}""#,
+            r#"Unexpected user DN format. Got "{}", expected: "{},{}""#,
             dn,
-            base_dn_str
+            expected,
+            people_dn_str
         );
     }
 }
 
-fn get_user_attribute(user: &User, attribute: &str, dn: &str) -> Result<Option<Vec<String>>> {
+/// The entryUUID of a user: the one kept by the backend, which doesn't change when the user is
+/// renamed, or else one derived from the user ID.
+fn get_user_entry_uuid(user: &User) -> String {
+    if user.uuid.is_empty() {
+        make_entry_uuid("user", user.user_id.as_str())
+    } else {
+        user.uuid.clone()
+    }
+}
+
+/// Where the POSIX IDs of the users and of the groups are, so that they don't collide with each
+/// other or with the system accounts. The IDs only depend on the entries, not on the order in
+/// which they are read, so they are the same from one restart to the next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PosixIdRanges {
+    /// The uidNumbers of the users are in [uid_offset, uid_offset + 2^30).
+    pub uid_offset: u32,
+    /// The gidNumber of a group is its ID plus this offset.
+    pub gid_offset: u32,
+}
+
+impl Default for PosixIdRanges {
+    fn default() -> Self {
+        Self {
+            uid_offset: DEFAULT_POSIX_UID_OFFSET,
+            gid_offset: DEFAULT_POSIX_GID_OFFSET,
+        }
+    }
+}
+
+impl PosixIdRanges {
+    /// The ranges must be above the system accounts, below 2^31 (the IDs are signed on some
+    /// systems), and apart: the gidNumber of the personal group of a user is its uidNumber.
+    pub fn check(&self) -> Result<()> {
+        let users =
+            u64::from(self.uid_offset)..u64::from(self.uid_offset) + u64::from(POSIX_ID_RANGE);
+        let groups = u64::from(self.gid_offset) + 1
+            ..u64::from(self.gid_offset) + u64::from(MAX_POSIX_GROUP_ID) + 1;
+        for (name, range) in [("uid", &users), ("gid", &groups)] {
+            if range.start < u64::from(MIN_POSIX_ID) {
+                bail!(
+                    "ldap_posix_{}_offset must be at least {}, above the IDs of the system accounts",
+                    name,
+                    MIN_POSIX_ID
+                );
+            }
+            if range.end > 1 << 31 {
+                bail!(
+                    "ldap_posix_{}_offset is too large: the IDs would go up to {}, above 2^31",
+                    name,
+                    range.end - 1
+                );
+            }
+        }
+        if users.start < groups.end && groups.start < users.end {
+            bail!(
+                "The uidNumbers of the users ({}-{}, from ldap_posix_uid_offset) overlap with the \
+                 gidNumbers of the groups ({}-{}, from ldap_posix_gid_offset)",
+                users.start,
+                users.end - 1,
+                groups.start,
+                groups.end - 1
+            );
+        }
+        Ok(())
+    }
+
+    /// The uidNumber of a user, from the POSIX ID allocated by the backend: it is unique, and
+    /// it doesn't change when the user is renamed.
+    fn uid_number(&self, user: &User) -> i64 {
+        i64::from(self.uid_offset) + i64::from(user.posix_id)
+    }
+
+    fn gid_number(&self, group_id: GroupId) -> i64 {
+        i64::from(self.gid_offset) + i64::from(group_id.0)
+    }
+
+    /// The inverse of `gid_number`, if the value is a valid group gidNumber.
+    fn group_id(&self, gid_number: &str) -> Option<GroupId> {
+        let group_id = gid_number.trim().parse::<i64>().ok()? - i64::from(self.gid_offset);
+        i32::try_from(group_id)
+            .ok()
+            .filter(|id| *id > 0)
+            .map(GroupId)
+    }
+}
+
+/// The POSIX attributes, computed rather than stored.
+fn is_posix_attribute(attribute: &str) -> bool {
+    matches!(
+        attribute.to_lowercase().as_str(),
+        "uidnumber" | "gidnumber" | "homedirectory"
+    )
+}
+
+/// The cn of a user: its display name, or else the template with "%display", "%first", "%last"
+/// and "%uid" replaced with the fields of the user, e.g. "%last, %first". Falls back to the
+/// display name when the result is empty.
+fn make_user_cn(user: &User, template: Option<&str>) -> String {
+    let cn = match template {
+        Some(template) => template
+            .replace("%display", &user.display_name)
+            .replace("%first", &user.first_name)
+            .replace("%last", &user.last_name)
+            .replace("%uid", user.user_id.as_str()),
+        None => return user.display_name.clone(),
+    };
+    match cn.trim() {
+        "" => user.display_name.clone(),
+        cn => cn.to_string(),
+    }
+}
+
+fn get_user_attribute(
+    user: &User,
+    attribute: &str,
+    dn: &str,
+    options: &LdapHandlerOptions,
+) -> Result<Option<Vec<String>>> {
     Ok(Some(match attribute.to_lowercase().as_str() {
-        "objectclass" => vec![
-            "inetOrgPerson".to_string(),
-            "posixAccount".to_string(),
-            "mailAccount".to_string(),
-            "person".to_string(),
-        ],
-        "dn" => vec![dn.to_string()],
+        "objectclass" => options.user_object_classes.clone(),
+        "dn" | "entrydn" => vec![dn.to_string()],
+        "subschemasubentry" => vec![SCHEMA_DN.to_string()],
+        // The users and groups are the leaves of the tree.
+        "hassubordinates" => vec!["FALSE".to_string()],
+        // The accounts can't be disabled: they are all active.
+        "nsaccountlock" => vec!["FALSE".to_string()],
+        // Only for the admins, see `make_password_metadata_attributes`.
+        "pwdchangedtime" | "pwdreset" => return Ok(None),
+        // Fetched separately, see `make_photo_attributes`.
+        "jpegphoto" | "thumbnailphoto" => return Ok(None),
+        "entryuuid" => vec![get_user_entry_uuid(user)],
         "uid" => vec![user.user_id.to_string()],
         "mail" => vec![user.email.clone()],
         "givenname" => vec![user.first_name.clone()],
         "sn" => vec![user.last_name.clone()],
-        "cn" | "displayname" => vec![user.display_name.clone()],
-        "createtimestamp" | "modifytimestamp" => vec![user.creation_date.to_rfc3339()],
+        "cn" => vec![make_user_cn(user, options.cn_template.as_deref())],
+        "displayname" => vec![user.display_name.clone()],
+        "createtimestamp" | "modifytimestamp" => vec![to_generalized_time(&user.creation_date)],
+        // Each user has its own group, with the same ID.
+        "uidnumber" | "gidnumber" => vec![options.posix_ids.uid_number(user).to_string()],
+        "homedirectory" => vec![options
+            .home_directory_template
+            .replace("{uid}", user.user_id.as_str())],
+        "useraccountcontrol" if options.ad_compatibility => vec![USER_ACCOUNT_CONTROL.to_string()],
         "1.1" => return Ok(None),
         _ => bail!("Unsupported user attribute: {}", attribute),
     }))
@@ -122,20 +599,24 @@ fn get_user_attribute(user: &User, attribute: &str, dn: &str) -> Result<Option<V
 
 fn make_ldap_search_user_result_entry(
     user: User,
-    base_dn_str: &str,
+    ou: Option<&str>,
+    people_dn_str: &str,
     attributes: &[String],
+    options: &LdapHandlerOptions,
 ) -> Result<LdapSearchResultEntry> {
-    let dn = format!(
-        "cn={},ou=people,{}",
-        user.display_name.as_str(),
-        base_dn_str
-    );
+    let rdn_value = match options.user_rdn_attribute {
+        UserRdnAttribute::Cn => &user.display_name,
+        UserRdnAttribute::Uid => user.user_id.as_str(),
+        UserRdnAttribute::Mail => &user.email,
+    };
+    let dn = make_user_dn(options.user_rdn_attribute, rdn_value, ou, people_dn_str);
     Ok(LdapSearchResultEntry {
         dn: dn.clone(),
         attributes: attributes
             .iter()
             .filter_map(|a| {
-                let values = match get_user_attribute(&user, a, &dn) {
+                let name = resolve_attribute_alias(a, options.ad_compatibility);
+                let values = match get_user_attribute(&user, name, &dn, options) {
                     Err(e) => return Some(Err(e)),
                     Ok(v) => v,
                 }?;
@@ -148,94 +629,363 @@ fn make_ldap_search_user_result_entry(
     })
 }
 
+/// Whether the attribute is one of the password policy attributes (draft-behera-ldap-password-policy)
+/// describing the state of the password, that only the admins can read.
+fn is_password_metadata_attribute(attribute: &str) -> bool {
+    matches!(
+        attribute.to_lowercase().as_str(),
+        "pwdchangedtime" | "pwdreset"
+    )
+}
+
+/// Formats a timestamp as an LDAP GeneralizedTime (RFC 4517), e.g. "20140708091011Z": in UTC, and
+/// without the fractions of seconds.
+fn to_generalized_time<Tz: chrono::TimeZone>(time: &chrono::DateTime<Tz>) -> String {
+    time.with_timezone(&chrono::Utc)
+        .format("%Y%m%d%H%M%SZ")
+        .to_string()
+}
+
+fn make_password_metadata_attributes(
+    metadata: &PasswordMetadata,
+    attributes: &[String],
+) -> Vec<LdapPartialAttribute> {
+    attributes
+        .iter()
+        .filter_map(|a| {
+            let vals = match a.to_lowercase().as_str() {
+                "pwdchangedtime" => {
+                    vec![to_generalized_time(&metadata.changed_time?)]
+                }
+                "pwdreset" => vec![if metadata.reset_pending {
+                    "TRUE".to_string()
+                } else {
+                    "FALSE".to_string()
+                }],
+                _ => return None,
+            };
+            Some(LdapPartialAttribute {
+                atype: a.to_string(),
+                vals,
+            })
+        })
+        .collect()
+}
+
+/// The photo attributes requested, with the avatar of the user. The value is base64-encoded, and
+/// sent as raw bytes by the codec.
+fn make_photo_attributes(avatar: &[u8], attributes: &[String]) -> Vec<LdapPartialAttribute> {
+    attributes
+        .iter()
+        .filter(|a| is_binary_attribute(a))
+        .map(|a| LdapPartialAttribute {
+            atype: a.to_string(),
+            vals: vec![base64::encode(avatar)],
+        })
+        .collect()
+}
+
 fn get_group_attribute(
     group: &Group,
-    base_dn_str: &str,
+    groups_dn_str: &str,
     attribute: &str,
     user_filter: &Option<&UserId>,
+    member_dn: &dyn Fn(&UserId) -> String,
+    posix_ids: PosixIdRanges,
 ) -> Result<Option<Vec<String>>> {
     Ok(Some(match attribute.to_lowercase().as_str() {
-        "objectclass" => vec!["groupOfUniqueNames".to_string()],
-        "dn" => vec![format!(
-            "cn={},ou=groups,{}",
-            group.display_name, base_dn_str
-        )],
+        "objectclass" => GROUP_OBJECT_CLASSES.iter().map(|c| c.to_string()).collect(),
+        "dn" | "entrydn" => vec![make_group_dn(&group.display_name, groups_dn_str)],
+        "entryuuid" => vec![make_entry_uuid("group", &group.id.0.to_string())],
+        "hassubordinates" => vec!["FALSE".to_string()],
+        "subschemasubentry" => vec![SCHEMA_DN.to_string()],
+        // The creation and modification times of the groups are not recorded.
+        "createtimestamp" | "modifytimestamp" => return Ok(None),
         "cn" | "uid" => vec![group.display_name.clone()],
+        "gidnumber" => vec![posix_ids.gid_number(group.id).to_string()],
+        "memberuid" => group
+            .users
+            .iter()
+            .filter(|u| user_filter.map(|f| *u == f).unwrap_or(true))
+            .map(|u| u.to_string())
+            .collect(),
         "member" | "uniquemember" => group
             .users
             .iter()
             .filter(|u| user_filter.map(|f| *u == f).unwrap_or(true))
-            .map(|u| format!("cn={},ou=people,{}", u, base_dn_str))
+            .map(member_dn)
             .collect(),
         "1.1" => return Ok(None),
         _ => bail!("Unsupported group attribute: {}", attribute),
     }))
 }
 
+/// Splits a ranged attribute ("member;range=100-199" or "member;range=100-*") into its name
+/// and range. None if the attribute has no range, or for the attributes that can't be ranged.
+fn parse_attribute_range(attribute: &str) -> Option<(&str, usize, Option<usize>)> {
+    let (name, option) = attribute.split_once(';')?;
+    if !is_member_attribute(name) || !option.get(..6)?.eq_ignore_ascii_case("range=") {
+        return None;
+    }
+    let (start, end) = option[6..].split_once('-')?;
+    let end = match end {
+        "*" => None,
+        end => Some(end.parse().ok()?),
+    };
+    Some((name, start.parse().ok()?, end))
+}
+
+/// Checks that the values of the string attributes sent by a client are valid UTF-8, as the
+/// strings of the directory must be. The binary attributes can hold any octets.
+fn check_value_syntax<'a, I>(attributes: I) -> std::result::Result<(), (LdapResultCode, String)>
+where
+    I: IntoIterator<Item = (&'a str, &'a [Vec<u8>])>,
+{
+    for (attribute, values) in attributes {
+        if is_binary_attribute(attribute) {
+            continue;
+        }
+        if values
+            .iter()
+            .any(|value| std::str::from_utf8(value).is_err())
+        {
+            return Err((
+                LdapResultCode::InvalidAttributeSyntax,
+                format!(r#"The value of "{}" is not valid UTF-8"#, attribute),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Checks the size of the attribute values sent by a client, with a separate limit for the
+/// binary attributes (e.g. jpegPhoto).
+fn check_value_lengths<'a, I>(
+    attributes: I,
+    options: &LdapHandlerOptions,
+) -> std::result::Result<(), (LdapResultCode, String)>
+where
+    I: IntoIterator<Item = (&'a str, &'a [Vec<u8>])>,
+{
+    for (attribute, values) in attributes {
+        let max_bytes = if is_binary_attribute(attribute) {
+            options.max_binary_attribute_value_bytes
+        } else {
+            options.max_attribute_value_bytes
+        };
+        if values.iter().any(|value| value.len() > max_bytes) {
+            return Err((
+                LdapResultCode::ConstraintViolation,
+                format!(
+                    r#"The value of "{}" is larger than {} bytes"#,
+                    attribute, max_bytes
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The attributes listing the members of the groups, which can be returned in ranges.
+fn is_member_attribute(attribute: &str) -> bool {
+    ["member", "uniquemember", "memberuid"]
+        .iter()
+        .any(|a| a.eq_ignore_ascii_case(attribute))
+}
+
+/// The part of the values in the range, capped at `threshold` values. The attribute is named
+/// with the range returned, which ends with "*" if it includes the last value.
+fn make_ranged_attribute(
+    name: &str,
+    values: Vec<String>,
+    start: usize,
+    end: Option<usize>,
+    threshold: Option<usize>,
+) -> LdapPartialAttribute {
+    let mut last = end.unwrap_or(usize::MAX);
+    if let Some(threshold) = threshold {
+        last = std::cmp::min(last, start.saturating_add(threshold.max(1) - 1));
+    }
+    if last.saturating_add(1) >= values.len() {
+        LdapPartialAttribute {
+            atype: format!("{};range={}-*", name, start),
+            vals: values.into_iter().skip(start).collect(),
+        }
+    } else {
+        LdapPartialAttribute {
+            atype: format!("{};range={}-{}", name, start, last),
+            vals: values
+                .into_iter()
+                .skip(start)
+                .take((last + 1).saturating_sub(start))
+                .collect(),
+        }
+    }
+}
+
 fn make_ldap_search_group_result_entry(
     group: Group,
-    base_dn_str: &str,
+    groups_dn_str: &str,
     attributes: &[String],
     user_filter: &Option<&UserId>,
+    member_dn: &dyn Fn(&UserId) -> String,
+    options: &LdapHandlerOptions,
 ) -> Result<LdapSearchResultEntry> {
     Ok(LdapSearchResultEntry {
-        dn: format!("cn={},ou=groups,{}", group.display_name, base_dn_str),
+        dn: make_group_dn(&group.display_name, groups_dn_str),
         attributes: attributes
             .iter()
             .filter_map(|a| {
-                let values = match get_group_attribute(&group, base_dn_str, a, user_filter) {
+                let range = parse_attribute_range(a);
+                let name = range.map(|(name, _, _)| name).unwrap_or(a);
+                let mut values = match get_group_attribute(
+                    &group,
+                    groups_dn_str,
+                    resolve_attribute_alias(name, options.ad_compatibility),
+                    user_filter,
+                    member_dn,
+                    options.posix_ids,
+                ) {
                     Err(e) => return Some(Err(e)),
                     Ok(v) => v,
                 }?;
-                Some(Ok(LdapPartialAttribute {
-                    atype: a.to_string(),
-                    vals: values,
+                // The backend lists the members in any order: sorted, the entries are the same
+                // from one search to the next, and so are the ranges.
+                if is_member_attribute(name) {
+                    values.sort();
+                }
+                let range_threshold = options.member_range_threshold;
+                Some(Ok(match (range, range_threshold) {
+                    (Some((name, start, end)), _) => {
+                        make_ranged_attribute(name, values, start, end, range_threshold)
+                    }
+                    // Only the first range is returned to the clients that didn't ask for one.
+                    (None, Some(threshold))
+                        if is_member_attribute(a) && values.len() > threshold =>
+                    {
+                        make_ranged_attribute(a, values, 0, None, Some(threshold))
+                    }
+                    (None, _) => LdapPartialAttribute {
+                        atype: a.to_string(),
+                        vals: values,
+                    },
                 }))
             })
             .collect::<Result<Vec<LdapPartialAttribute>>>()?,
     })
 }
 
+/// Whether the filter matches all the users, e.g. "(&(objectClass=*)(objectClass=person))". Such
+/// filters are sent to the backend as the empty "and", which it runs without any condition.
+fn is_match_all_user_filter(filter: &UserRequestFilter) -> bool {
+    match filter {
+        UserRequestFilter::And(filters) => filters.iter().all(is_match_all_user_filter),
+        UserRequestFilter::Or(filters) => filters.iter().any(is_match_all_user_filter),
+        _ => false,
+    }
+}
+
+/// Same as `is_match_all_user_filter`, for the groups.
+fn is_match_all_group_filter(filter: &GroupRequestFilter) -> bool {
+    match filter {
+        GroupRequestFilter::And(filters) => filters.iter().all(is_match_all_group_filter),
+        GroupRequestFilter::Or(filters) => filters.iter().any(is_match_all_group_filter),
+        _ => false,
+    }
+}
+
+/// Whether the user filter only selects the given user, e.g. "(&(objectClass=person)(uid=bob))":
+/// Some(true) if so, Some(false) if it selects everyone, None otherwise.
+fn selects_only_user(filter: &UserRequestFilter, user_id: &UserId) -> Option<bool> {
+    match filter {
+        UserRequestFilter::UserId(id) if id == user_id => Some(true),
+        UserRequestFilter::And(filters) => filters.iter().try_fold(false, |found, f| {
+            Some(found || selects_only_user(f, user_id)?)
+        }),
+        _ => None,
+    }
+}
+
+/// Evaluates the filter on a group that is not in the database.
+fn group_matches(filter: &GroupRequestFilter, group: &Group) -> bool {
+    match filter {
+        GroupRequestFilter::And(filters) => filters.iter().all(|f| group_matches(f, group)),
+        GroupRequestFilter::Or(filters) => filters.iter().any(|f| group_matches(f, group)),
+        GroupRequestFilter::Not(filter) => !group_matches(filter, group),
+        GroupRequestFilter::DisplayName(name) => name.eq_ignore_ascii_case(&group.display_name),
+        GroupRequestFilter::DisplayNameSubString(substring) => {
+            substring.matches(&group.display_name)
+        }
+        GroupRequestFilter::GroupId(id) => *id == group.id,
+        GroupRequestFilter::Member(user_id) => group.users.contains(user_id),
+    }
+}
+
 fn is_subtree(subtree: &[(String, String)], base_tree: &[(String, String)]) -> bool {
     if subtree.len() < base_tree.len() {
         return false;
     }
     let size_diff = subtree.len() - base_tree.len();
+    // The attribute values are compared case-insensitively.
     for i in 0..base_tree.len() {
-        if subtree[size_diff + i] != base_tree[i] {
+        let (name, value) = &subtree[size_diff + i];
+        if *name != base_tree[i].0 || value.to_lowercase() != base_tree[i].1.to_lowercase() {
             return false;
         }
     }
     true
 }
 
+/// The backend field of an attribute. The attribute names are case-insensitive.
 fn map_field(field: &str) -> Result<String> {
-    Ok(if field == "uid" {
-        "user_id".to_string()
-    } else if field == "mail" {
-        "email".to_string()
-    } else if field == "cn" || field.to_lowercase() == "displayname" {
-        "display_name".to_string()
-    } else if field.to_lowercase() == "givenname" {
-        "first_name".to_string()
-    } else if field == "sn" {
-        "last_name".to_string()
-    } else if field == "avatar" {
-        "avatar".to_string()
-    } else if field.to_lowercase() == "creationdate"
-        || field.to_lowercase() == "createtimestamp"
-        || field.to_lowercase() == "modifytimestamp"
-    {
-        "creation_date".to_string()
-    } else {
-        bail!("Unknown field: {}", field);
-    })
+    Ok(match field.to_lowercase().as_str() {
+        "uid" => "user_id",
+        "mail" => "email",
+        "cn" | "displayname" => "display_name",
+        "givenname" => "first_name",
+        "sn" => "last_name",
+        "avatar" => "avatar",
+        "creationdate" | "createtimestamp" | "modifytimestamp" => "creation_date",
+        _ => bail!("Unknown field: {}", field),
+    }
+    .to_string())
+}
+
+fn convert_substring_filter(filter: &LdapSubstringFilter) -> SubStringFilter {
+    SubStringFilter {
+        initial: filter.initial.clone(),
+        any: filter.any.clone(),
+        final_: filter.final_.clone(),
+    }
+}
+
+/// Evaluates an equality filter on objectClass: it is multivalued, any of the classes of the
+/// entries matches.
+fn has_object_class<S: AsRef<str>>(classes: &[S], value: &str) -> bool {
+    classes
+        .iter()
+        .any(|class| class.as_ref().eq_ignore_ascii_case(value))
+}
+
+/// Evaluates a substring filter on objectClass against the fixed list of classes.
+fn matches_object_class<S: AsRef<str>>(classes: &[S], filter: &SubStringFilter) -> bool {
+    classes.iter().any(|class| filter.matches(class.as_ref()))
 }
 
 fn make_search_success() -> LdapOp {
     make_search_error(LdapResultCode::Success, "".to_string())
 }
 
+/// The result code of a failed backend call: "unavailable" for the transient errors of the
+/// database, that are worth trying again later.
+fn get_backend_error_code(error: &DomainError) -> LdapResultCode {
+    if error.is_transient() {
+        LdapResultCode::Unavailable
+    } else {
+        LdapResultCode::Other
+    }
+}
+
 fn make_search_error(code: LdapResultCode, message: String) -> LdapOp {
     LdapOp::SearchResultDone(LdapResult {
         code,
@@ -245,6 +995,18 @@ fn make_search_error(code: LdapResultCode, message: String) -> LdapOp {
     })
 }
 
+fn make_bind_response(code: LdapResultCode, message: String) -> LdapOp {
+    LdapOp::BindResponse(LdapBindResponse {
+        res: LdapResult {
+            code,
+            matcheddn: "".to_string(),
+            message,
+            referral: vec![],
+        },
+        saslcreds: None,
+    })
+}
+
 fn make_extended_response(code: LdapResultCode, message: String) -> LdapOp {
     LdapOp::ExtendedResponse(LdapExtendedResponse {
         res: LdapResult {
@@ -258,920 +1020,12374 @@ fn make_extended_response(code: LdapResultCode, message: String) -> LdapOp {
     })
 }
 
-fn root_dse_response(base_dn: &str) -> LdapOp {
-    LdapOp::SearchResultEntry(LdapSearchResultEntry {
-        dn: "".to_string(),
-        attributes: vec![
-            LdapPartialAttribute {
-                atype: "objectClass".to_string(),
-                vals: vec!["top".to_string()],
-            },
-            LdapPartialAttribute {
-                atype: "vendorName".to_string(),
-                vals: vec!["LLDAP".to_string()],
-            },
-            LdapPartialAttribute {
-                atype: "vendorVersion".to_string(),
-                vals: vec!["lldap_0.2.0".to_string()],
-            },
-            LdapPartialAttribute {
-                atype: "supportedLDAPVersion".to_string(),
-                vals: vec!["3".to_string()],
-            },
-            LdapPartialAttribute {
-                atype: "supportedExtension".to_string(),
-                vals: vec!["1.3.6.1.4.1.4203.1.11.1".to_string()],
-            },
-            LdapPartialAttribute {
-                atype: "defaultnamingcontext".to_string(),
-                vals: vec![base_dn.to_string()],
-            },
-        ],
-    })
+/// The authorization ID, authentication ID and password of SASL PLAIN credentials (RFC 4616),
+/// separated by NUL characters. Only the authorization ID can be empty.
+fn parse_sasl_plain_credentials(credentials: &[u8]) -> Option<(&str, &str, &str)> {
+    let credentials = std::str::from_utf8(credentials).ok()?;
+    let mut parts = credentials.split('\0');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(authz_id), Some(authc_id), Some(password), None)
+            if !authc_id.is_empty() && !password.is_empty() =>
+        {
+            Some((authz_id, authc_id, password))
+        }
+        _ => None,
+    }
 }
 
-pub struct LdapHandler<Backend: BackendHandler + LoginHandler + OpaqueHandler> {
-    dn: LdapDn,
-    user_id: UserId,
-    backend_handler: Backend,
-    pub base_dn: Vec<(String, String)>,
-    base_dn_str: String,
-    ldap_user_dn: LdapDn,
+/// The attributes selected by a pre-read or post-read control (RFC 4527). None means all the
+/// user attributes, as in a search.
+fn parse_read_attributes(control: &RawControl) -> Result<Vec<String>> {
+    BerElement::parse_complete(control.value.as_deref().context("Missing value")?)?
+        .expect_tag(TAG_SEQUENCE)?
+        .children()?
+        .into_iter()
+        .map(|attribute| attribute.expect_tag(TAG_OCTET_STRING)?.as_string())
+        .collect()
 }
 
-impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend> {
-    pub fn new(backend_handler: Backend, ldap_base_dn: String, ldap_user_dn: UserId) -> Self {
-        Self {
-            dn: LdapDn("unauthenticated".to_string()),
-            user_id: UserId::new("unauthenticated"),
-            backend_handler,
-            base_dn: parse_distinguished_name(&ldap_base_dn).unwrap_or_else(|_| {
-                panic!(
-                    "Invalid value for ldap_base_dn in configuration: {}",
-                    ldap_base_dn
-                )
-            }),
-            ldap_user_dn: LdapDn(format!("cn={},ou=people,{}", ldap_user_dn, &ldap_base_dn)),
-            base_dn_str: ldap_base_dn,
+/// The binds set the identity, they can't be proxied.
+fn is_bind_or_unbind(request: &LdapRequest) -> bool {
+    matches!(
+        request,
+        LdapRequest::Op(LdapOp::BindRequest(_) | LdapOp::UnbindRequest)
+            | LdapRequest::SaslBind(_)
+            | LdapRequest::UnsupportedBind(_)
+    )
+}
+
+/// A failure response of the type expected for the request.
+fn make_error_response(
+    request: &LdapRequest,
+    code: LdapResultCode,
+    message: String,
+) -> LdapResponseOp {
+    match request {
+        LdapRequest::Op(LdapOp::BindRequest(_))
+        | LdapRequest::SaslBind(_)
+        | LdapRequest::UnsupportedBind(_) => make_bind_response(code, message).into(),
+        LdapRequest::Op(LdapOp::SearchRequest(_)) => make_search_error(code, message).into(),
+        LdapRequest::Compare(_) => make_compare_response(code, message),
+        LdapRequest::Modify(_) => make_modify_response(code, message),
+        LdapRequest::ModifyDn(_) => make_modify_dn_response(code, message),
+        LdapRequest::Add(_) => make_add_response(code, message),
+        LdapRequest::Delete(_) => make_delete_response(code, message),
+        LdapRequest::Op(_) | LdapRequest::Unsupported(UnsupportedOperation::Abandon(_)) => {
+            make_extended_response(code, message).into()
         }
     }
+}
 
-    pub async fn do_bind(&mut self, request: &LdapBindRequest) -> (LdapResultCode, String) {
-        debug!(r#"Received bind request for "{}""#, &request.dn);
-        let user_id = match get_user_id_from_distinguished_name(
-            &request.dn,
-            &self.base_dn,
-            &self.base_dn_str,
-        ) {
-            Ok(s) => s,
-            Err(e) => return (LdapResultCode::NamingViolation, e.to_string()),
-        };
-        let LdapBindCred::Simple(password) = &request.cred;
-        match self
-            .backend_handler
-            .bind(BindRequest {
-                name: user_id.clone(),
-                password: password.clone(),
-            })
-            .await
-        {
-            Ok(()) => {
-                self.dn = LdapDn(request.dn.clone());
-                self.user_id = user_id;
-                (LdapResultCode::Success, "".to_string())
-            }
-            Err(_) => (LdapResultCode::InvalidCredentials, "".to_string()),
+fn make_compare_response(code: LdapResultCode, message: String) -> LdapResponseOp {
+    LdapResponseOp::CompareResponse(LdapResult {
+        code,
+        matcheddn: "".to_string(),
+        message,
+        referral: vec![],
+    })
+}
+
+fn make_modify_response(code: LdapResultCode, message: String) -> LdapResponseOp {
+    LdapResponseOp::ModifyResponse(LdapResult {
+        code,
+        matcheddn: "".to_string(),
+        message,
+        referral: vec![],
+    })
+}
+
+fn make_modify_dn_response(code: LdapResultCode, message: String) -> LdapResponseOp {
+    LdapResponseOp::ModifyDnResponse(LdapResult {
+        code,
+        matcheddn: "".to_string(),
+        message,
+        referral: vec![],
+    })
+}
+
+fn make_add_response(code: LdapResultCode, message: String) -> LdapResponseOp {
+    LdapResponseOp::AddResponse(LdapResult {
+        code,
+        matcheddn: "".to_string(),
+        message,
+        referral: vec![],
+    })
+}
+
+fn make_delete_response(code: LdapResultCode, message: String) -> LdapResponseOp {
+    LdapResponseOp::DeleteResponse(LdapResult {
+        code,
+        matcheddn: "".to_string(),
+        message,
+        referral: vec![],
+    })
+}
+
+fn make_referral(op: LdapOp, url: &str) -> LdapResponse {
+    LdapResponse {
+        op: LdapResponseOp::Referral(op, vec![url.to_string()]),
+        controls: vec![],
+    }
+}
+
+/// Whether one of the values matches the asserted value. All the attributes we expose are
+/// case-insensitive.
+fn compare_values(values: Option<Vec<String>>, value: &str) -> (LdapResultCode, String) {
+    let value = value.to_lowercase();
+    match values {
+        Some(values) if values.iter().any(|v| v.to_lowercase() == value) => {
+            (LdapResultCode::CompareTrue, "".to_string())
         }
+        Some(_) => (LdapResultCode::CompareFalse, "".to_string()),
+        None => (LdapResultCode::NoSuchAttribute, "".to_string()),
     }
+}
 
-    async fn change_password(&mut self, user: &UserId, password: &str) -> Result<()> {
-        use lldap_auth::*;
-        let mut rng = rand::rngs::OsRng;
-        let registration_start_request =
-            opaque::client::registration::start_registration(password, &mut rng)?;
-        let req = registration::ClientRegistrationStartRequest {
-            username: user.to_string(),
-            registration_start_request: registration_start_request.message,
-        };
-        let registration_start_response = self.backend_handler.registration_start(req).await?;
-        let registration_finish = opaque::client::registration::finish_registration(
-            registration_start_request.state,
-            registration_start_response.registration_response,
-            &mut rng,
-        )?;
-        let req = registration::ClientRegistrationFinishRequest {
-            server_data: registration_start_response.server_data,
-            registration_upload: registration_finish.message,
-        };
-        self.backend_handler.registration_finish(req).await?;
-        Ok(())
+/// The controls that the server implements, in the order of the root DSE.
+const SUPPORTED_CONTROLS: &[&str] = &[
+    PAGED_RESULTS_OID,
+    MANAGE_DSA_IT_OID,
+    ASSERTION_OID,
+    DONT_USE_COPY_OID,
+    PRE_READ_OID,
+    POST_READ_OID,
+    SORT_REQUEST_OID,
+    MATCHED_VALUES_OID,
+    SYNC_REQUEST_OID,
+    PROXIED_AUTHORIZATION_OID,
+    PASSWORD_POLICY_OID,
+];
+
+/// Whether the control is enabled by the configuration: the root DSE only advertises those, and
+/// the others are treated as unknown.
+fn is_control_enabled(options: &LdapHandlerOptions, oid: &str) -> bool {
+    match oid {
+        PROXIED_AUTHORIZATION_OID => options.proxy_group.is_some(),
+        PASSWORD_POLICY_OID => options.report_account_lockout,
+        _ => SUPPORTED_CONTROLS.contains(&oid),
     }
+}
 
-    async fn do_password_modification(
-        &mut self,
-        request: &LdapPasswordModifyRequest,
-    ) -> Vec<LdapOp> {
-        match (&request.user_identity, &request.new_password) {
-            (Some(user), Some(password)) => {
-                match get_user_id_from_distinguished_name(user, &self.base_dn, &self.base_dn_str) {
-                    Ok(uid) => {
-                        if let Err(e) = self.change_password(&uid, password).await {
-                            vec![make_extended_response(
-                                LdapResultCode::Other,
-                                format!("Error while changing the password: {:#?}", e),
-                            )]
-                        } else {
-                            vec![make_extended_response(
-                                LdapResultCode::Success,
-                                "".to_string(),
-                            )]
-                        }
-                    }
-                    Err(e) => vec![make_extended_response(
-                        LdapResultCode::InvalidDNSyntax,
-                        format!("Invalid username: {:#?}", e),
-                    )],
+fn is_operation_disabled(options: &LdapHandlerOptions, op_type: &str) -> bool {
+    options
+        .disabled_operations
+        .iter()
+        .any(|disabled| disabled.eq_ignore_ascii_case(op_type))
+}
+
+fn root_dse_response(base_dn: &str, options: &LdapHandlerOptions) -> LdapOp {
+    let naming_contexts = std::iter::once(base_dn.to_string())
+        .chain(options.base_dn_aliases.iter().cloned())
+        .collect();
+    let mut supported_extensions = vec![
+        PASSWORD_MODIFY_OID.to_string(),
+        WHOAMI_OID.to_string(),
+        CANCEL_OID.to_string(),
+        CAPABILITIES_OID.to_string(),
+        SCHEMA_LDIF_OID.to_string(),
+    ];
+    if options.start_tls_available {
+        supported_extensions.push(START_TLS_OID.to_string());
+    }
+    if options.operation_log.is_some() {
+        supported_extensions.push(OPERATION_LOG_OID.to_string());
+    }
+    if is_operation_disabled(options, "extended") {
+        supported_extensions.clear();
+    }
+    let supported_controls = SUPPORTED_CONTROLS
+        .iter()
+        .filter(|oid| is_control_enabled(options, oid))
+        .map(|oid| oid.to_string())
+        .collect();
+    LdapOp::SearchResultEntry(LdapSearchResultEntry {
+        dn: "".to_string(),
+        attributes: vec![
+            LdapPartialAttribute {
+                atype: "objectClass".to_string(),
+                vals: vec!["top".to_string()],
+            },
+            LdapPartialAttribute {
+                atype: "vendorName".to_string(),
+                vals: vec!["LLDAP".to_string()],
+            },
+            LdapPartialAttribute {
+                atype: "supportedLDAPVersion".to_string(),
+                vals: vec!["3".to_string()],
+            },
+            LdapPartialAttribute {
+                atype: "supportedExtension".to_string(),
+                vals: supported_extensions,
+            },
+            LdapPartialAttribute {
+                atype: "supportedControl".to_string(),
+                vals: supported_controls,
+            },
+            LdapPartialAttribute {
+                atype: "supportedSASLMechanisms".to_string(),
+                vals: vec!["EXTERNAL".to_string(), "PLAIN".to_string()],
+            },
+            LdapPartialAttribute {
+                atype: "subschemaSubentry".to_string(),
+                vals: vec![SCHEMA_DN.to_string()],
+            },
+            LdapPartialAttribute {
+                atype: "namingContexts".to_string(),
+                vals: naming_contexts,
+            },
+            LdapPartialAttribute {
+                atype: "defaultnamingcontext".to_string(),
+                vals: vec![base_dn.to_string()],
+            },
+        ],
+    })
+}
+
+/// The version and uptime of the server, added to the root DSE for the admins and the read-only
+/// account.
+fn server_info_attributes(options: &LdapHandlerOptions) -> Vec<LdapPartialAttribute> {
+    let uptime = chrono::Utc::now() - options.start_time;
+    vec![
+        LdapPartialAttribute {
+            atype: "vendorVersion".to_string(),
+            vals: vec![format!("lldap_{}", env!("CARGO_PKG_VERSION"))],
+        },
+        LdapPartialAttribute {
+            atype: "startTime".to_string(),
+            vals: vec![to_generalized_time(&options.start_time)],
+        },
+        LdapPartialAttribute {
+            atype: "serverUptimeSeconds".to_string(),
+            vals: vec![uptime.num_seconds().max(0).to_string()],
+        },
+    ]
+}
+
+/// LDAP_MATCHING_RULE_IN_CHAIN, from Active Directory: matches the memberships through the
+/// nested groups.
+const IN_CHAIN_MATCHING_RULE: &str = "1.2.840.113556.1.4.1941";
+/// LDAP_MATCHING_RULE_BIT_AND and LDAP_MATCHING_RULE_BIT_OR, from Active Directory: all or any
+/// of the bits of the value are set in the attribute.
+const BIT_AND_MATCHING_RULE: &str = "1.2.840.113556.1.4.803";
+const BIT_OR_MATCHING_RULE: &str = "1.2.840.113556.1.4.804";
+/// The userAccountControl of the users, with the AD compatibility: NORMAL_ACCOUNT. The accounts
+/// can't be disabled, so ACCOUNTDISABLE (0x2) is never set.
+const USER_ACCOUNT_CONTROL: i64 = 0x200;
+
+/// Interprets the extensible match filters, that the codec gives as equality filters on
+/// "attribute[:dn][:rule]:". Returns an error for the unsupported matching rules.
+///
+/// The groups can't contain other groups, so the memberships "in chain" are the direct ones.
+///
+/// With the AD compatibility, the bitwise matching rules are accepted on userAccountControl:
+/// since all the users have the same value, they either match all the users ("present") or
+/// nothing.
+fn resolve_matching_rules(
+    filter: &LdapFilter,
+    ad_compatibility: bool,
+) -> std::result::Result<LdapFilter, String> {
+    let resolve_all = |filters: &[LdapFilter]| {
+        filters
+            .iter()
+            .map(|f| resolve_matching_rules(f, ad_compatibility))
+            .collect::<std::result::Result<Vec<_>, _>>()
+    };
+    Ok(match filter {
+        LdapFilter::And(filters) => LdapFilter::And(resolve_all(filters)?),
+        LdapFilter::Or(filters) => LdapFilter::Or(resolve_all(filters)?),
+        LdapFilter::Not(filter) => {
+            LdapFilter::Not(Box::new(resolve_matching_rules(filter, ad_compatibility)?))
+        }
+        LdapFilter::Equality(field, value) if field.ends_with(':') => {
+            let mut parts = field[..field.len() - 1].split(':');
+            let attribute = parts.next().unwrap_or_default();
+            let rule = parts.next();
+            match rule {
+                // Without a matching rule, this is the equality of the attribute.
+                None if !attribute.is_empty() => {}
+                Some(IN_CHAIN_MATCHING_RULE)
+                    if parts.next().is_none()
+                        && ["memberof", "member", "uniquemember"]
+                            .contains(&attribute.to_lowercase().as_str()) => {}
+                Some(rule @ (BIT_AND_MATCHING_RULE | BIT_OR_MATCHING_RULE))
+                    if ad_compatibility
+                        && parts.next().is_none()
+                        && attribute.eq_ignore_ascii_case("useraccountcontrol") =>
+                {
+                    let bits = value
+                        .parse::<i64>()
+                        .map_err(|_| format!(r#"Invalid bits: "{}""#, value))?;
+                    let matches = if rule == BIT_AND_MATCHING_RULE {
+                        USER_ACCOUNT_CONTROL & bits == bits
+                    } else {
+                        USER_ACCOUNT_CONTROL & bits != 0
+                    };
+                    return Ok(if matches {
+                        LdapFilter::Present(attribute.to_string())
+                    } else {
+                        LdapFilter::Not(Box::new(LdapFilter::Present("objectClass".to_string())))
+                    });
+                }
+                _ => {
+                    return Err(format!(
+                        r#"Unsupported extensible match: "{}={}""#,
+                        field, value
+                    ))
                 }
             }
-            _ => vec![make_extended_response(
-                LdapResultCode::ConstraintViolation,
-                "Missing either user_id or password".to_string(),
-            )],
+            LdapFilter::Equality(attribute.to_string(), value.clone())
         }
+        _ => filter.clone(),
+    })
+}
+
+/// The attribute read for a requested one: with the AD compatibility, sAMAccountName is the uid,
+/// as in Active Directory. The entries still name it as requested.
+fn resolve_attribute_alias(attribute: &str, ad_compatibility: bool) -> &str {
+    if ad_compatibility && attribute.eq_ignore_ascii_case("samaccountname") {
+        "uid"
+    } else {
+        attribute
     }
+}
 
-    async fn do_extended_request(&mut self, request: &LdapExtendedRequest) -> Vec<LdapOp> {
-        match LdapPasswordModifyRequest::try_from(request) {
-            Ok(password_request) => self.do_password_modification(&password_request).await,
-            Err(_) => vec![make_extended_response(
-                LdapResultCode::UnwillingToPerform,
-                format!("Unsupported extended operation: {}", &request.name),
-            )],
+/// The filter with the aliases of `resolve_attribute_alias` replaced with their attribute.
+fn resolve_filter_aliases(filter: &LdapFilter, ad_compatibility: bool) -> LdapFilter {
+    let resolve = |field: &str| resolve_attribute_alias(field, ad_compatibility).to_string();
+    match filter {
+        LdapFilter::And(filters) => LdapFilter::And(
+            filters
+                .iter()
+                .map(|f| resolve_filter_aliases(f, ad_compatibility))
+                .collect(),
+        ),
+        LdapFilter::Or(filters) => LdapFilter::Or(
+            filters
+                .iter()
+                .map(|f| resolve_filter_aliases(f, ad_compatibility))
+                .collect(),
+        ),
+        LdapFilter::Not(filter) => {
+            LdapFilter::Not(Box::new(resolve_filter_aliases(filter, ad_compatibility)))
+        }
+        LdapFilter::Equality(field, value) => LdapFilter::Equality(resolve(field), value.clone()),
+        LdapFilter::Substring(field, substring) => {
+            LdapFilter::Substring(resolve(field), substring.clone())
         }
+        LdapFilter::Present(field) => LdapFilter::Present(resolve(field)),
     }
+}
 
-    pub async fn do_search(&mut self, request: &LdapSearchRequest) -> Vec<LdapOp> {
-        let admin = self.dn == self.ldap_user_dn;
-        if request.base.is_empty()
-            && request.scope == LdapSearchScope::Base
-            && request.filter == LdapFilter::Present("objectClass".to_string())
-        {
-            debug!("Received rootDSE request");
-            return vec![root_dse_response(&self.base_dn_str), make_search_success()];
+/// The attributes the filter tests.
+fn get_filter_attributes(filter: &LdapFilter) -> Vec<&str> {
+    match filter {
+        LdapFilter::And(filters) | LdapFilter::Or(filters) => {
+            filters.iter().flat_map(get_filter_attributes).collect()
         }
-        debug!("Received search request: {:?}", &request);
-        let dn_parts = match parse_distinguished_name(&request.base) {
-            Ok(dn) => dn,
-            Err(_) => {
-                return vec![make_search_error(
-                    LdapResultCode::OperationsError,
-                    format!(r#"Could not parse base DN: "{}""#, request.base),
-                )]
+        LdapFilter::Not(filter) => get_filter_attributes(filter),
+        LdapFilter::Equality(field, _)
+        | LdapFilter::Substring(field, _)
+        | LdapFilter::Present(field) => vec![field.as_str()],
+    }
+}
+
+/// The cost of evaluating the filter: each term counts for 1 plus its nesting depth, and each
+/// part of a substring assertion for 1 more.
+fn get_filter_complexity(filter: &LdapFilter, depth: usize) -> usize {
+    1 + depth
+        + match filter {
+            LdapFilter::And(filters) | LdapFilter::Or(filters) => filters
+                .iter()
+                .map(|f| get_filter_complexity(f, depth + 1))
+                .sum(),
+            LdapFilter::Not(filter) => get_filter_complexity(filter, depth + 1),
+            LdapFilter::Substring(_, substring) => {
+                usize::from(substring.initial.is_some())
+                    + substring.any.len()
+                    + usize::from(substring.final_.is_some())
             }
-        };
-        if !is_subtree(&dn_parts, &self.base_dn) {
-            // Search path is not in our tree, just return an empty success.
-            warn!(
-                "The specified search tree {:?} is not under the common subtree {:?}",
-                &dn_parts, &self.base_dn
-            );
-            return vec![make_search_success()];
+            LdapFilter::Equality(_, _) | LdapFilter::Present(_) => 0,
         }
-        let mut results = Vec::new();
-        let mut got_match = false;
-        let user_filter = if admin { None } else { Some(&self.user_id) };
-        if dn_parts.len() == self.base_dn.len()
-            || (dn_parts.len() == self.base_dn.len() + 1
-                && dn_parts[0] == ("ou".to_string(), "people".to_string()))
-        {
-            got_match = true;
-            results.extend(self.get_user_list(request, &user_filter).await);
+}
+
+/// Removes the attributes that are not in the list from the entries.
+fn retain_attributes(mut results: Vec<LdapOp>, allowed: &[String]) -> Vec<LdapOp> {
+    for op in results.iter_mut() {
+        if let LdapOp::SearchResultEntry(entry) = op {
+            entry.attributes.retain(|a| {
+                allowed
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(&a.atype))
+            });
         }
-        if dn_parts.len() == self.base_dn.len()
-            || (dn_parts.len() == self.base_dn.len() + 1
-                && dn_parts[0] == ("ou".to_string(), "groups".to_string()))
-        {
-            got_match = true;
-            results.extend(self.get_groups_list(request, &user_filter).await);
+    }
+    results
+}
+
+/// The values of the equality filters on the attribute.
+fn get_filter_values<'a>(filter: &'a LdapFilter, attribute: &str) -> Vec<&'a str> {
+    match filter {
+        LdapFilter::And(filters) | LdapFilter::Or(filters) => filters
+            .iter()
+            .flat_map(|f| get_filter_values(f, attribute))
+            .collect(),
+        LdapFilter::Not(filter) => get_filter_values(filter, attribute),
+        LdapFilter::Equality(field, value) if field.eq_ignore_ascii_case(attribute) => {
+            vec![value.as_str()]
         }
-        if !got_match {
-            warn!(
-                r#"The requested search tree "{}" matches neither the user subtree "ou=people,{}" nor the group subtree "ou=groups,{}""#,
-                &request.base, &self.base_dn_str, &self.base_dn_str
-            );
+        _ => vec![],
+    }
+}
+
+/// The user DNs in the member filters, e.g. "(member=uid=bob,ou=people,dc=example,dc=com)".
+fn get_filter_member_dns(filter: &LdapFilter) -> Vec<&str> {
+    match filter {
+        LdapFilter::And(filters) | LdapFilter::Or(filters) => {
+            filters.iter().flat_map(get_filter_member_dns).collect()
         }
-        if results.is_empty() || matches!(results[results.len() - 1], LdapOp::SearchResultEntry(_))
+        LdapFilter::Not(filter) => get_filter_member_dns(filter),
+        LdapFilter::Equality(field, value)
+            if field.eq_ignore_ascii_case("member")
+                || field.eq_ignore_ascii_case("uniquemember") =>
         {
-            results.push(make_search_success());
+            vec![value.as_str()]
         }
-        results
+        _ => vec![],
     }
+}
 
-    async fn get_user_list(
-        &self,
-        request: &LdapSearchRequest,
-        user_filter: &Option<&UserId>,
-    ) -> Vec<LdapOp> {
-        let filters = match self.convert_user_filter(&request.filter) {
-            Ok(f) => f,
-            Err(e) => {
-                return vec![make_search_error(
-                    LdapResultCode::UnwillingToPerform,
-                    format!("Unsupported user filter: {:#}", e),
-                )]
-            }
-        };
-        let filters = match user_filter {
-            None => filters,
-            Some(u) => {
-                UserRequestFilter::And(vec![filters, UserRequestFilter::UserId((*u).clone())])
-            }
+/// An anonymous `(objectClass=*)` search of the root DSE is used as a health check, that also
+/// checks the backend.
+fn is_health_check_filter(filter: &LdapFilter) -> bool {
+    matches!(filter, LdapFilter::Present(field) if field.eq_ignore_ascii_case("objectclass"))
+}
+
+/// Replaces "*" with all the user attributes, and "+" with all the operational attributes
+/// (RFC 3673), without duplicates.
+fn expand_attributes(requested: &[String], all_user_attributes: &[&str]) -> Vec<String> {
+    let mut attributes: Vec<String> = Vec::with_capacity(requested.len());
+    for attribute in requested {
+        let expanded: Vec<&str> = match attribute.as_str() {
+            "*" => all_user_attributes.to_vec(),
+            "+" => OPERATIONAL_ATTRIBUTES
+                .iter()
+                .copied()
+                .chain(std::iter::once("subschemaSubentry"))
+                .collect(),
+            attribute => vec![attribute],
         };
-        let users = match self.backend_handler.list_users(Some(filters)).await {
-            Ok(users) => users,
-            Err(e) => {
-                return vec![make_search_error(
-                    LdapResultCode::Other,
-                    format!(r#"Error during searching user "{}": {:#}"#, request.base, e),
-                )]
+        for attribute in expanded {
+            if !attributes.iter().any(|a| a.eq_ignore_ascii_case(attribute)) {
+                attributes.push(attribute.to_string());
             }
-        };
+        }
+    }
+    attributes
+}
 
-        users
-            .into_iter()
-            .map(|u| make_ldap_search_user_result_entry(u, &self.base_dn_str, &request.attrs))
-            .map(|entry| Ok(LdapOp::SearchResultEntry(entry?)))
-            .collect::<Result<Vec<_>>>()
-            .unwrap_or_else(|e| {
-                vec![make_search_error(
-                    LdapResultCode::NoSuchAttribute,
-                    e.to_string(),
-                )]
-            })
+/// With the ManageDsaIT control, the operational attributes are returned even if they were not
+/// requested.
+fn add_operational_attributes(request: &mut LdapSearchRequest) {
+    for attribute in OPERATIONAL_ATTRIBUTES {
+        if !request
+            .attrs
+            .iter()
+            .any(|a| a.eq_ignore_ascii_case(attribute))
+        {
+            request.attrs.push(attribute.to_string());
+        }
     }
+}
 
-    async fn get_groups_list(
-        &self,
-        request: &LdapSearchRequest,
-        user_filter: &Option<&UserId>,
-    ) -> Vec<LdapOp> {
-        let filter = match self.convert_group_filter(&request.filter) {
-            Ok(f) => f,
-            Err(e) => {
-                return vec![make_search_error(
-                    LdapResultCode::UnwillingToPerform,
-                    format!("Unsupported group filter: {:#}", e),
-                )]
-            }
-        };
-        let filter = match user_filter {
-            None => filter,
-            Some(u) => {
-                GroupRequestFilter::And(vec![filter, GroupRequestFilter::Member((*u).clone())])
-            }
-        };
+/// Parses the value of a paged results control (RFC 2696): the page size and the cookie.
+fn parse_paged_results_control(control: &RawControl) -> Result<(usize, Vec<u8>)> {
+    let value = control.value.as_deref().context("Missing control value")?;
+    let mut fields = BerElement::parse_complete(value)?
+        .expect_tag(TAG_SEQUENCE)?
+        .children()?
+        .into_iter();
+    let size = fields
+        .next()
+        .context("Missing page size")?
+        .expect_tag(TAG_INTEGER)?
+        .as_integer()?;
+    let cookie = fields
+        .next()
+        .context("Missing cookie")?
+        .expect_tag(TAG_OCTET_STRING)?
+        .value;
+    Ok((usize::try_from(size).context("Invalid page size")?, cookie))
+}
 
-        let groups = match self.backend_handler.list_groups(Some(filter)).await {
-            Ok(groups) => groups,
-            Err(e) => {
-                return vec![make_search_error(
-                    LdapResultCode::Other,
-                    format!(r#"Error while listing groups "{}": {:#}"#, request.base, e),
-                )]
+fn make_paged_results_control(cookie: Vec<u8>) -> RawControl {
+    RawControl {
+        oid: PAGED_RESULTS_OID.to_string(),
+        criticality: false,
+        // We don't give an estimate of the total number of entries.
+        value: Some(
+            BerElement::sequence(&[BerElement::integer(0), BerElement::octet_string(cookie)])
+                .encode(),
+        ),
+    }
+}
+
+/// The password policy response control of a bind, with the error if any.
+fn make_password_policy_control(error: Option<i64>) -> RawControl {
+    let fields = error
+        .map(|error| BerElement::integer_with_tag(context_tag(1), error))
+        .into_iter()
+        .collect::<Vec<_>>();
+    RawControl {
+        oid: PASSWORD_POLICY_OID.to_string(),
+        criticality: false,
+        value: Some(BerElement::sequence(&fields).encode()),
+    }
+}
+
+/// A key of a server-side sort request (RFC 2891).
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SortKey {
+    attribute: String,
+    ordering_rule: Option<String>,
+    reverse: bool,
+}
+
+/// How the values of a sort key are compared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SortOrdering {
+    CaseIgnore,
+    Integer,
+}
+
+/// Parses the value of a sort request control: the sort keys, most significant first.
+fn parse_sort_control(control: &RawControl) -> Result<Vec<SortKey>> {
+    let value = control.value.as_deref().context("Missing control value")?;
+    let keys = BerElement::parse_complete(value)?
+        .expect_tag(TAG_SEQUENCE)?
+        .children()?;
+    if keys.is_empty() {
+        bail!("No sort key");
+    }
+    keys.into_iter()
+        .map(|key| {
+            let mut fields = key.expect_tag(TAG_SEQUENCE)?.children()?.into_iter();
+            let mut sort_key = SortKey {
+                attribute: fields
+                    .next()
+                    .context("Missing sort attribute")?
+                    .expect_tag(TAG_OCTET_STRING)?
+                    .as_string()?,
+                ordering_rule: None,
+                reverse: false,
+            };
+            for field in fields {
+                match field.tag {
+                    tag if tag == context_tag(0) => {
+                        sort_key.ordering_rule = Some(field.as_string()?)
+                    }
+                    tag if tag == context_tag(1) => sort_key.reverse = field.as_bool()?,
+                    tag => bail!("Unexpected sort key field: {:#04x}", tag),
+                }
             }
+            Ok(sort_key)
+        })
+        .collect()
+}
+
+/// The ordering of the sort key, or None if the entries can't be sorted by it: the binary
+/// attributes have no ordering, and only the ordering rules matching the attribute syntax are
+/// supported.
+fn get_sort_ordering(key: &SortKey) -> Option<SortOrdering> {
+    let attribute = key.attribute.to_lowercase();
+    if is_binary_attribute(&attribute) {
+        return None;
+    }
+    let is_integer = matches!(attribute.as_str(), "uidnumber" | "gidnumber");
+    let is_time = matches!(
+        attribute.as_str(),
+        "createtimestamp" | "modifytimestamp" | "pwdchangedtime"
+    );
+    let rule = match &key.ordering_rule {
+        None if is_integer => return Some(SortOrdering::Integer),
+        None => return Some(SortOrdering::CaseIgnore),
+        Some(rule) => rule.to_lowercase(),
+    };
+    match rule.as_str() {
+        "2.5.13.15" | "integerorderingmatch" if is_integer => Some(SortOrdering::Integer),
+        // The generalized times all have the same format, they sort as strings.
+        "2.5.13.28" | "generalizedtimeorderingmatch" if is_time => Some(SortOrdering::CaseIgnore),
+        "2.5.13.3" | "caseignoreorderingmatch" if !is_integer && !is_time => {
+            Some(SortOrdering::CaseIgnore)
+        }
+        _ => None,
+    }
+}
+
+fn compare_sort_values(ordering: SortOrdering, a: &str, b: &str) -> std::cmp::Ordering {
+    match (ordering, a.parse::<i64>(), b.parse::<i64>()) {
+        (SortOrdering::Integer, Ok(a), Ok(b)) => a.cmp(&b),
+        _ => a.to_lowercase().cmp(&b.to_lowercase()),
+    }
+}
+
+/// The value of the entry that it is sorted by: the smallest one, if the attribute has several.
+fn get_sort_value<'a>(
+    entry: &'a LdapSearchResultEntry,
+    attribute: &str,
+    ordering: SortOrdering,
+) -> Option<&'a str> {
+    entry
+        .attributes
+        .iter()
+        .filter(|a| a.atype.eq_ignore_ascii_case(attribute))
+        .flat_map(|a| a.vals.iter())
+        .map(String::as_str)
+        .min_by(|a, b| compare_sort_values(ordering, a, b))
+}
+
+/// Sorts the entries by the keys. The entries without a value for a key are greater than the
+/// others.
+fn sort_entries(entries: &mut [LdapOp], keys: &[(SortKey, SortOrdering)]) {
+    entries.sort_by(|a, b| {
+        let (a, b) = match (a, b) {
+            (LdapOp::SearchResultEntry(a), LdapOp::SearchResultEntry(b)) => (a, b),
+            _ => return std::cmp::Ordering::Equal,
         };
+        for (key, ordering) in keys {
+            let order = match (
+                get_sort_value(a, &key.attribute, *ordering),
+                get_sort_value(b, &key.attribute, *ordering),
+            ) {
+                (Some(a), Some(b)) => compare_sort_values(*ordering, a, b),
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (None, None) => std::cmp::Ordering::Equal,
+            };
+            let order = if key.reverse { order.reverse() } else { order };
+            if order != std::cmp::Ordering::Equal {
+                return order;
+            }
+        }
+        std::cmp::Ordering::Equal
+    });
+}
 
-        groups
-            .into_iter()
-            .map(|u| {
-                make_ldap_search_group_result_entry(
-                    u,
-                    &self.base_dn_str,
-                    &request.attrs,
-                    user_filter,
+/// Removes the attributes from the entries.
+fn remove_attributes(results: &mut [LdapOp], removed: &[String]) {
+    for op in results.iter_mut() {
+        if let LdapOp::SearchResultEntry(entry) = op {
+            entry.attributes.retain(|a| {
+                !removed
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(&a.atype))
+            });
+        }
+    }
+}
+
+fn make_sort_response_control(result: i64, attribute: Option<&str>) -> RawControl {
+    let mut fields = vec![BerElement::integer_with_tag(TAG_ENUMERATED, result)];
+    if let Some(attribute) = attribute {
+        fields.push(BerElement {
+            tag: context_tag(0),
+            value: attribute.as_bytes().to_vec(),
+        });
+    }
+    RawControl {
+        oid: SORT_RESPONSE_OID.to_string(),
+        criticality: false,
+        value: Some(BerElement::sequence(&fields).encode()),
+    }
+}
+
+/// The value of a sync request control (RFC 4533).
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SyncRequest {
+    /// refreshAndPersist: the changes are sent after the initial content, until the search is
+    /// abandoned. Otherwise refreshOnly.
+    persist: bool,
+    cookie: Option<Vec<u8>>,
+    reload_hint: bool,
+}
+
+fn parse_sync_request_control(control: &RawControl) -> Result<SyncRequest> {
+    let value = control.value.as_deref().context("Missing control value")?;
+    let mut fields = BerElement::parse_complete(value)?
+        .expect_tag(TAG_SEQUENCE)?
+        .children()?
+        .into_iter()
+        .peekable();
+    let persist = match fields
+        .next()
+        .context("Missing mode")?
+        .expect_tag(TAG_ENUMERATED)?
+        .as_integer()?
+    {
+        1 => false,
+        3 => true,
+        mode => bail!("Invalid mode: {}", mode),
+    };
+    let cookie = match fields.peek() {
+        Some(field) if field.tag == TAG_OCTET_STRING => fields.next().map(|field| field.value),
+        _ => None,
+    };
+    let reload_hint = match fields.next() {
+        Some(field) => field.expect_tag(TAG_BOOLEAN)?.as_bool()?,
+        None => false,
+    };
+    Ok(SyncRequest {
+        persist,
+        cookie,
+        reload_hint,
+    })
+}
+
+/// The cookie of the content synchronization, after the given change of the backend.
+fn make_sync_cookie(change_id: u64) -> Vec<u8> {
+    format!("lldap:{}", change_id).into_bytes()
+}
+
+fn make_sync_state_control(state: i64, uuid: &Uuid, cookie: Option<&[u8]>) -> RawControl {
+    let mut fields = vec![
+        BerElement::integer_with_tag(TAG_ENUMERATED, state),
+        BerElement::octet_string(uuid.as_bytes().to_vec()),
+    ];
+    fields.extend(cookie.map(BerElement::octet_string));
+    RawControl {
+        oid: SYNC_STATE_OID.to_string(),
+        criticality: false,
+        value: Some(BerElement::sequence(&fields).encode()),
+    }
+}
+
+/// With `refresh_deletes`, the entries left out were not deleted: only the deleted entries are
+/// sent. Otherwise, all the entries are sent and the others were deleted.
+fn make_sync_done_control(cookie: &[u8], refresh_deletes: bool) -> RawControl {
+    let mut fields = vec![BerElement::octet_string(cookie)];
+    if refresh_deletes {
+        fields.push(BerElement::boolean(true));
+    }
+    RawControl {
+        oid: SYNC_DONE_OID.to_string(),
+        criticality: false,
+        value: Some(BerElement::sequence(&fields).encode()),
+    }
+}
+
+/// The sync info message ending the refresh of a refreshAndPersist search, like the sync done
+/// control of a refreshOnly one: refreshDelete or refreshPresent.
+fn make_sync_refresh_done(cookie: &[u8], refresh_deletes: bool) -> LdapResponse {
+    let choice = if refresh_deletes { 1 } else { 2 };
+    LdapResponse {
+        op: LdapResponseOp::IntermediateResponse {
+            name: Some(SYNC_INFO_OID.to_string()),
+            value: Some(
+                BerElement::constructed(
+                    context_constructed_tag(choice),
+                    &[BerElement::octet_string(cookie)],
                 )
-            })
-            .map(|entry| Ok(LdapOp::SearchResultEntry(entry?)))
-            .collect::<Result<Vec<_>>>()
-            .unwrap_or_else(|e| {
-                vec![make_search_error(
-                    LdapResultCode::NoSuchAttribute,
-                    e.to_string(),
-                )]
-            })
+                .encode(),
+            ),
+        },
+        controls: vec![],
     }
+}
 
-    pub async fn handle_ldap_message(&mut self, ldap_op: LdapOp) -> Option<Vec<LdapOp>> {
-        Some(match ldap_op {
-            LdapOp::BindRequest(request) => {
-                let (code, message) = self.do_bind(&request).await;
-                vec![LdapOp::BindResponse(LdapBindResponse {
-                    res: LdapResult {
-                        code,
-                        matcheddn: "".to_string(),
-                        message,
-                        referral: vec![],
-                    },
-                    saslcreds: None,
-                })]
-            }
-            LdapOp::SearchRequest(request) => self.do_search(&request).await,
-            LdapOp::UnbindRequest => {
-                self.dn = LdapDn("unauthenticated".to_string());
-                self.user_id = UserId::new("unauthenticated");
-                // No need to notify on unbind (per rfc4511)
-                return None;
-            }
-            LdapOp::ExtendedRequest(request) => self.do_extended_request(&request).await,
-            op => vec![make_extended_response(
-                LdapResultCode::UnwillingToPerform,
-                format!("Unsupported operation: {:#?}", op),
-            )],
+/// The sync info message with the cookie after changes that left the content unchanged.
+fn make_sync_new_cookie(cookie: &[u8]) -> LdapResponse {
+    LdapResponse {
+        op: LdapResponseOp::IntermediateResponse {
+            name: Some(SYNC_INFO_OID.to_string()),
+            value: Some(
+                BerElement {
+                    tag: context_tag(0),
+                    value: cookie.to_vec(),
+                }
+                .encode(),
+            ),
+        },
+        controls: vec![],
+    }
+}
+
+/// An entry of a synchronized search, with its sync state control.
+fn make_sync_entry(
+    request: &LdapSearchRequest,
+    entry: LdapSearchResultEntry,
+    state: i64,
+    uuid: &Uuid,
+    cookie: Option<&[u8]>,
+) -> LdapResponse {
+    let mut ops = apply_types_only(request, vec![LdapOp::SearchResultEntry(entry)]);
+    LdapResponse {
+        op: ops.remove(0).into(),
+        controls: vec![make_sync_state_control(state, uuid, cookie)],
+    }
+}
+
+/// The entryUUID of an entry of a synchronized search. The entries that have none, like the
+/// containers, get one from their DN.
+fn get_sync_entry_uuid(entry: &LdapSearchResultEntry) -> Uuid {
+    entry
+        .attributes
+        .iter()
+        .find(|a| a.atype.eq_ignore_ascii_case("entryuuid"))
+        .and_then(|a| a.vals.first())
+        .and_then(|uuid| Uuid::parse_str(uuid).ok())
+        .unwrap_or_else(|| {
+            Uuid::new_v5(
+                &ENTRY_UUID_NAMESPACE,
+                format!("entry:{}", entry.dn.to_lowercase()).as_bytes(),
+            )
+        })
+}
+
+/// The content of a persistent search (refreshAndPersist), compared to the results of the
+/// search after every change of the backend.
+struct PersistentSearch {
+    msgid: i32,
+    request: LdapSearchRequest,
+    entries: HashMap<Uuid, LdapSearchResultEntry>,
+    changes: ChangeReceiver,
+}
+
+/// A subtree held by another directory server: the requests under it are referred there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LdapReferral {
+    dn: Vec<(String, String)>,
+    url: String,
+}
+
+impl LdapReferral {
+    pub fn new(dn: &str, url: String) -> Result<Self> {
+        Ok(Self {
+            dn: parse_distinguished_name(dn)
+                .with_context(|| format!(r#"Invalid referral DN: "{}""#, dn))?,
+            url,
         })
     }
+}
 
-    fn convert_group_filter(&self, filter: &LdapFilter) -> Result<GroupRequestFilter> {
-        match filter {
-            LdapFilter::Equality(field, value) => {
-                if field == "member" || field.to_lowercase() == "uniquemember" {
-                    let user_name = get_user_id_from_distinguished_name(
-                        value,
-                        &self.base_dn,
-                        &self.base_dn_str,
-                    )?;
-                    Ok(GroupRequestFilter::Member(user_name))
-                } else if field.to_lowercase() == "objectclass" {
-                    if value == "groupOfUniqueNames" || value == "groupOfNames" {
-                        Ok(GroupRequestFilter::And(vec![]))
-                    } else {
-                        Ok(GroupRequestFilter::Not(Box::new(GroupRequestFilter::And(
-                            vec![],
-                        ))))
-                    }
-                } else {
-                    let field = map_field(field)?;
-                    if field == "display_name" {
-                        Ok(GroupRequestFilter::DisplayName(value.clone()))
-                    } else {
-                        bail!("Unsupported group attribute: {:?}", field)
-                    }
+/// The only subtrees that an identity can search: a bind DN, or a group DN for all its members.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LdapSearchRestriction {
+    identity: LdapDn,
+    bases: Vec<Vec<(String, String)>>,
+}
+
+impl LdapSearchRestriction {
+    pub fn new(identity: &str, bases: &[String]) -> Result<Self> {
+        Ok(Self {
+            identity: LdapDn::normalized(identity)
+                .with_context(|| format!(r#"Invalid DN: "{}""#, identity))?,
+            bases: bases
+                .iter()
+                .map(|base| {
+                    parse_distinguished_name(base)
+                        .with_context(|| format!(r#"Invalid search base: "{}""#, base))
+                })
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+/// An account outside of the users, for the applications: it can read all the entries, but
+/// can't modify anything.
+#[derive(Debug, Clone)]
+pub struct LdapReadOnlyAccount {
+    dn: LdapDn,
+    password: SecUtf8,
+}
+
+impl LdapReadOnlyAccount {
+    pub fn new(dn: &str, password: SecUtf8) -> Result<Self> {
+        Ok(Self {
+            dn: LdapDn::normalized(dn)
+                .with_context(|| format!(r#"Invalid read-only account DN: "{}""#, dn))?,
+            password,
+        })
+    }
+}
+
+/// A response to an LDAP request, with its controls.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapResponse {
+    pub op: LdapResponseOp,
+    pub controls: Vec<RawControl>,
+}
+
+impl From<LdapOp> for LdapResponse {
+    fn from(op: LdapOp) -> Self {
+        Self {
+            op: op.into(),
+            controls: vec![],
+        }
+    }
+}
+
+/// The remaining results of a paged search, waiting for the client to ask for the next page.
+struct PagedSearch {
+    /// The identity and the request of the first page: the next pages must match them.
+    dn: LdapDn,
+    request: LdapSearchRequest,
+    entries: VecDeque<LdapOp>,
+    done: LdapOp,
+    /// The controls of the final result of every page, besides the paged results control.
+    done_controls: Vec<RawControl>,
+}
+
+/// Settings of the LDAP handler, shared by all the connections of a listener.
+#[derive(Debug, Clone)]
+pub struct LdapHandlerOptions {
+    /// Whether the connection can be upgraded to TLS with the StartTLS extended operation.
+    pub start_tls_available: bool,
+    /// Maximum number of entries returned by a search, whatever the client asks for.
+    pub max_size_limit: Option<usize>,
+    /// Maximum time to answer a search, whatever the client asks for.
+    pub search_timeout: Option<Duration>,
+    /// Whether clients can bind anonymously (empty DN and password), to read the root DSE.
+    pub allow_anonymous_bind: bool,
+    /// The only attributes the anonymous sessions can read and filter on, in all the entries.
+    /// By default, they read the whole root DSE and schema but no other entry.
+    pub anonymous_readable_attributes: Option<Vec<String>>,
+    /// Whether the anonymous sessions are refused all the searches but those of the root DSE and
+    /// the schema, even with `anonymous_readable_attributes`.
+    pub require_auth_for_search: bool,
+    /// The attributes that are never returned nor filtered on, whoever is bound, on top of
+    /// userPassword.
+    pub hidden_attributes: Vec<String>,
+    /// Limits the number of binds per client address, shared with all the listeners.
+    pub bind_rate_limiter: Option<Arc<BindRateLimiter>>,
+    /// Delays the answers to the repeated failed binds of a user, shared with all the listeners.
+    pub bind_failure_tracker: Option<Arc<BindFailureTracker>>,
+    /// Whether the failed binds of the users whose failures are delayed say that the account is
+    /// locked, instead of looking like wrong passwords.
+    pub report_account_lockout: bool,
+    /// Subtrees delegated to other servers.
+    pub referrals: Vec<LdapReferral>,
+    /// The identities that can only search some subtrees. The others can search everywhere.
+    pub search_restrictions: Vec<LdapSearchRestriction>,
+    /// Other suffixes under which the entries of the base DN can be found, e.g. after merging
+    /// directories. The entries are always returned under the base DN.
+    pub base_dn_aliases: Vec<String>,
+    /// The objectClass values of the user entries.
+    pub user_object_classes: Vec<String>,
+    /// The home directory of the users, where "{uid}" is replaced with the user ID.
+    pub home_directory_template: String,
+    /// The template of the cn of the users, see `make_user_cn`. By default, their display name.
+    pub cn_template: Option<String>,
+    /// Whether the Active Directory names of the attributes are understood, for the applications
+    /// expecting AD: see `resolve_attribute_alias`.
+    pub ad_compatibility: bool,
+    /// The attribute naming the users in their DN.
+    pub user_rdn_attribute: UserRdnAttribute,
+    /// Whether the users can bind with their email instead of their DN.
+    pub allow_email_login: bool,
+    /// The domain of the "DOMAIN\\user" logins, if they are accepted.
+    pub netbios_domain: Option<String>,
+    /// The suffix of the "user@suffix" logins, if they are accepted.
+    pub upn_suffix: Option<String>,
+    /// The DN of the binds with a bare user name, with "%u" standing for the name.
+    pub bind_dn_template: Option<String>,
+    /// The account of the applications that only read the directory.
+    pub readonly_account: Option<LdapReadOnlyAccount>,
+    /// The group of the users that can act on behalf of other users, with the proxied
+    /// authorization control or the authorization ID of SASL PLAIN.
+    pub proxy_group: Option<String>,
+    /// Whether SASL PLAIN is allowed on the connections without TLS.
+    pub allow_insecure_sasl_plain: bool,
+    /// The name of a virtual group containing all the users, if any.
+    pub all_users_group: Option<String>,
+    /// The name of a group whose members are admins, like those of "lldap_admin". The members
+    /// added to it through LDAP are also added to "lldap_admin", for the web UI, see
+    /// `grant_admin_role`.
+    pub admin_group: Option<String>,
+    /// The RDN of the entry containing the users, under the base DN.
+    pub people_ou: String,
+    /// The RDN of the entry containing the groups, under the base DN.
+    pub groups_ou: String,
+    /// The OUs under the people OU, with the group of their users. A user in several of these
+    /// groups is in the first OU.
+    pub user_ou_mapping: Vec<(String, String)>,
+    /// Caches the group queries of the searches, shared with all the listeners.
+    pub group_cache: Option<Arc<GroupCache>>,
+    /// Caches the results of the searches, shared with all the listeners. The entries of the
+    /// searches are then not streamed.
+    pub search_cache: Option<Arc<SearchCache>>,
+    /// The avatars larger than that are not returned as jpegPhoto.
+    pub max_photo_bytes: usize,
+    /// The adds and modifications with a larger value of a string attribute are refused.
+    pub max_attribute_value_bytes: usize,
+    /// Same, for the binary attributes.
+    pub max_binary_attribute_value_bytes: usize,
+    /// The groups with more members than that return them in ranges ("member;range=0-999").
+    pub member_range_threshold: Option<usize>,
+    /// The searches with a more complex filter are refused, see `get_filter_complexity`.
+    pub max_filter_complexity: Option<usize>,
+    /// The operations refused with unwillingToPerform, see `DISABLEABLE_OPERATIONS`.
+    pub disabled_operations: Vec<String>,
+    /// Limits the number of connections bound as the same DN, shared with all the listeners.
+    pub user_connection_limiter: Option<Arc<UserConnectionLimiter>>,
+    /// Whether the entry and the groups of the bound user are kept for the connection.
+    pub cache_bound_user: bool,
+    /// Whether the successful searches say how many entries they returned, in the message of
+    /// the result.
+    pub verbose_result_messages: bool,
+    /// Where the uidNumbers and the gidNumbers are.
+    pub posix_ids: PosixIdRanges,
+    /// When the server started, for its uptime.
+    pub start_time: chrono::DateTime<chrono::Utc>,
+    /// The last operations of all the connections, recorded by the server.
+    pub operation_log: Option<Arc<OperationLog>>,
+}
+
+impl Default for LdapHandlerOptions {
+    fn default() -> Self {
+        Self {
+            start_tls_available: false,
+            max_size_limit: None,
+            search_timeout: None,
+            allow_anonymous_bind: false,
+            anonymous_readable_attributes: None,
+            require_auth_for_search: false,
+            hidden_attributes: vec![],
+            bind_rate_limiter: None,
+            bind_failure_tracker: None,
+            report_account_lockout: false,
+            referrals: vec![],
+            search_restrictions: vec![],
+            base_dn_aliases: vec![],
+            user_object_classes: ["inetOrgPerson", "posixAccount", "mailAccount", "person"]
+                .iter()
+                .map(|c| c.to_string())
+                .collect(),
+            home_directory_template: "/home/{uid}".to_string(),
+            cn_template: None,
+            ad_compatibility: true,
+            user_rdn_attribute: UserRdnAttribute::Cn,
+            allow_email_login: false,
+            netbios_domain: None,
+            upn_suffix: None,
+            bind_dn_template: None,
+            readonly_account: None,
+            proxy_group: None,
+            allow_insecure_sasl_plain: false,
+            all_users_group: None,
+            admin_group: None,
+            people_ou: "ou=people".to_string(),
+            groups_ou: "ou=groups".to_string(),
+            user_ou_mapping: vec![],
+            group_cache: None,
+            search_cache: None,
+            max_photo_bytes: DEFAULT_MAX_PHOTO_BYTES,
+            max_attribute_value_bytes: DEFAULT_MAX_ATTRIBUTE_VALUE_BYTES,
+            max_binary_attribute_value_bytes: DEFAULT_MAX_PHOTO_BYTES,
+            member_range_threshold: None,
+            max_filter_complexity: None,
+            disabled_operations: vec![],
+            user_connection_limiter: None,
+            cache_bound_user: true,
+            verbose_result_messages: false,
+            posix_ids: PosixIdRanges::default(),
+            start_time: chrono::Utc::now(),
+            operation_log: None,
+        }
+    }
+}
+
+/// Runs the query until the deadline, if any. None if the deadline passed first.
+async fn run_until<F: Future>(
+    deadline: Option<tokio::time::Instant>,
+    query: F,
+) -> Option<F::Output> {
+    match deadline {
+        None => Some(query.await),
+        Some(deadline) => tokio::time::timeout_at(deadline, query).await.ok(),
+    }
+}
+
+/// Parses the filter of a matched values control (RFC 3876): a sequence of simple filter items,
+/// without "and", "or" nor "not".
+fn parse_matched_values_filter(
+    control: &RawControl,
+    ad_compatibility: bool,
+) -> Result<Vec<LdapFilter>> {
+    BerElement::parse_complete(control.value.as_deref().context("Missing values filter")?)?
+        .expect_tag(TAG_SEQUENCE)?
+        .children()?
+        .into_iter()
+        .map(|item| {
+            match resolve_matching_rules(&parse_filter(item)?, ad_compatibility)
+                .map_err(anyhow::Error::msg)?
+            {
+                LdapFilter::And(_) | LdapFilter::Or(_) | LdapFilter::Not(_) => {
+                    bail!("Only simple filter items are allowed in a values filter")
                 }
+                filter => Ok(filter),
             }
-            LdapFilter::And(filters) => Ok(GroupRequestFilter::And(
-                filters
-                    .iter()
-                    .map(|f| self.convert_group_filter(f))
-                    .collect::<Result<_>>()?,
-            )),
-            LdapFilter::Or(filters) => Ok(GroupRequestFilter::Or(
-                filters
-                    .iter()
-                    .map(|f| self.convert_group_filter(f))
-                    .collect::<Result<_>>()?,
-            )),
-            LdapFilter::Not(filter) => Ok(GroupRequestFilter::Not(Box::new(
-                self.convert_group_filter(&*filter)?,
-            ))),
-            _ => bail!("Unsupported group filter: {:?}", filter),
+        })
+        .collect()
+}
+
+/// Whether the value of the attribute matches the filter item of a matched values control.
+fn matches_value_filter(filter: &LdapFilter, attribute: &str, value: &str) -> bool {
+    match filter {
+        LdapFilter::Equality(a, v) => {
+            a.eq_ignore_ascii_case(attribute) && v.to_lowercase() == value.to_lowercase()
         }
+        LdapFilter::Substring(a, substring) => {
+            a.eq_ignore_ascii_case(attribute) && convert_substring_filter(substring).matches(value)
+        }
+        LdapFilter::Present(a) => a.eq_ignore_ascii_case(attribute),
+        _ => false,
     }
+}
 
-    fn convert_user_filter(&self, filter: &LdapFilter) -> Result<UserRequestFilter> {
-        match filter {
-            LdapFilter::And(filters) => Ok(UserRequestFilter::And(
-                filters
-                    .iter()
-                    .map(|f| self.convert_user_filter(f))
-                    .collect::<Result<_>>()?,
-            )),
-            LdapFilter::Or(filters) => Ok(UserRequestFilter::Or(
-                filters
-                    .iter()
-                    .map(|f| self.convert_user_filter(f))
-                    .collect::<Result<_>>()?,
-            )),
-            LdapFilter::Not(filter) => Ok(UserRequestFilter::Not(Box::new(
-                self.convert_user_filter(&*filter)?,
-            ))),
-            LdapFilter::Equality(field, value) => {
-                if field.to_lowercase() == "memberof" {
-                    let group_name = get_group_id_from_distinguished_name(
-                        value,
-                        &self.base_dn,
-                        &self.base_dn_str,
-                    )?;
-                    Ok(UserRequestFilter::MemberOf(group_name))
-                } else if field.to_lowercase() == "objectclass" {
-                    if value == "person"
-                        || value == "inetOrgPerson"
-                        || value == "posixAccount"
-                        || value == "mailAccount"
-                    {
-                        Ok(UserRequestFilter::And(vec![]))
-                    } else {
-                        Ok(UserRequestFilter::Not(Box::new(UserRequestFilter::And(
-                            vec![],
-                        ))))
-                    }
-                } else {
-                    let field = map_field(field)?;
-                    if field == "user_id" {
-                        Ok(UserRequestFilter::UserId(UserId::new(value)))
-                    } else {
-                        Ok(UserRequestFilter::Equality(field, value.clone()))
-                    }
-                }
+/// Whether the entry, with all its attributes, matches the filter. For the entries that are not
+/// in the backend, i.e. the containers.
+fn matches_entry_filter(filter: &LdapFilter, entry: &LdapSearchResultEntry) -> bool {
+    match filter {
+        LdapFilter::And(filters) => filters.iter().all(|f| matches_entry_filter(f, entry)),
+        LdapFilter::Or(filters) => filters.iter().any(|f| matches_entry_filter(f, entry)),
+        LdapFilter::Not(filter) => !matches_entry_filter(filter, entry),
+        filter => entry.attributes.iter().any(|attribute| {
+            attribute
+                .vals
+                .iter()
+                .any(|value| matches_value_filter(filter, &attribute.atype, value))
+        }),
+    }
+}
+
+/// The entry of a container (the base DN, the people or groups OU or a mapped OU), with all its
+/// attributes. Its object class depends on its RDN, e.g. "organizationalUnit" for "ou=people".
+fn make_container_entry(dn: &str, rdn: &(String, String)) -> LdapSearchResultEntry {
+    let object_class = match rdn.0.as_str() {
+        "dc" => "domain",
+        "o" => "organization",
+        "ou" => "organizationalUnit",
+        _ => "extensibleObject",
+    };
+    LdapSearchResultEntry {
+        dn: dn.to_string(),
+        attributes: vec![
+            LdapPartialAttribute {
+                atype: "objectClass".to_string(),
+                vals: vec!["top".to_string(), object_class.to_string()],
+            },
+            LdapPartialAttribute {
+                atype: rdn.0.clone(),
+                vals: vec![rdn.1.clone()],
+            },
+        ],
+    }
+}
+
+/// With a matched values control, the entries only return the values that match one of the
+/// filter items. The attributes are still listed when none of their values match, e.g. when no
+/// filter item is on them.
+fn apply_matched_values(responses: &mut [LdapResponse], filters: &[LdapFilter]) {
+    for response in responses {
+        if let LdapResponseOp::Op(LdapOp::SearchResultEntry(entry)) = &mut response.op {
+            for attribute in entry.attributes.iter_mut() {
+                let atype = &attribute.atype;
+                attribute.vals.retain(|value| {
+                    filters
+                        .iter()
+                        .any(|filter| matches_value_filter(filter, atype, value))
+                });
             }
-            LdapFilter::Present(field) => {
-                // Check that it's a field we support.
-                if field.to_lowercase() == "objectclass" || map_field(field).is_ok() {
-                    Ok(UserRequestFilter::And(vec![]))
-                } else {
-                    Ok(UserRequestFilter::Not(Box::new(UserRequestFilter::And(
-                        vec![],
-                    ))))
+        }
+    }
+}
+
+/// With typesOnly, the entries only list their attributes, without the values.
+fn apply_types_only(request: &LdapSearchRequest, mut results: Vec<LdapOp>) -> Vec<LdapOp> {
+    if request.typesonly {
+        for op in results.iter_mut() {
+            if let LdapOp::SearchResultEntry(entry) = op {
+                for attribute in entry.attributes.iter_mut() {
+                    attribute.vals.clear();
                 }
             }
-            _ => bail!("Unsupported user filter: {:?}", filter),
         }
     }
+    results
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::domain::{error::Result, handler::*, opaque_handler::*};
-    use async_trait::async_trait;
-    use ldap3_server::proto::{LdapDerefAliases, LdapSearchScope};
-    use mockall::predicate::eq;
-    use std::collections::HashSet;
-    use tokio;
+/// Receives the responses sent before the final ones: the user entries of the streamed searches
+/// and the intermediate responses, see `handle_ldap_request_streaming`.
+pub type ResponseSender = tokio::sync::mpsc::Sender<LdapResponse>;
 
-    mockall::mock! {
-        pub TestBackendHandler{}
-        impl Clone for TestBackendHandler {
-            fn clone(&self) -> Self;
+/// The number of users fetched at once from the backend by the streamed searches.
+const SEARCH_BATCH_SIZE: usize = 500;
+
+/// Where the user entries of the current search go, when they are streamed.
+struct EntryStream {
+    /// The number of entries sent so far, for the size limit.
+    sent: Cell<usize>,
+    /// Whether some entries were left out because of the size limit.
+    truncated: Cell<bool>,
+}
+
+/// Where the users of a search are under the people OU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UserSubtree<'a> {
+    /// Anywhere: directly in the people OU or in one of the mapped OUs.
+    All,
+    /// Directly in the people OU, i.e. in none of the mapped OUs.
+    People,
+    /// In one of the OUs of `user_ou_mapping`.
+    Ou(&'a str),
+}
+
+impl UserSubtree<'_> {
+    fn contains(&self, user_ou: Option<&str>) -> bool {
+        match self {
+            UserSubtree::All => true,
+            UserSubtree::People => user_ou.is_none(),
+            UserSubtree::Ou(ou) => user_ou == Some(*ou),
         }
-        #[async_trait]
+    }
+}
+
+/// The entries selected by a search, depending on its base and scope.
+#[derive(Debug)]
+struct SearchTargets<'a> {
+    /// The DNs and RDNs of the containers.
+    containers: Vec<(String, (String, String))>,
+    /// The users, and the user of the base if it is a user entry.
+    users: Option<(UserSubtree<'a>, Option<UserId>)>,
+    /// The groups, and the group of the base if it is a group entry.
+    groups: Option<Option<String>>,
+}
+
+/// The attributes requested for the users of a search.
+struct UserEntryAttributes {
+    names: Vec<String>,
+    with_password_metadata: bool,
+    with_photo: bool,
+}
+
+pub struct LdapHandler<Backend: BackendHandler + LoginHandler + OpaqueHandler> {
+    dn: LdapDn,
+    user_id: UserId,
+    backend_handler: Backend,
+    pub base_dn: Vec<(String, String)>,
+    base_dn_str: String,
+    people_ou: (String, String),
+    groups_ou: (String, String),
+    /// The DNs of the entries containing the users and the groups, e.g. "ou=people,dc=...".
+    people_dn_str: String,
+    groups_dn_str: String,
+    base_dn_aliases: Vec<Vec<(String, String)>>,
+    ldap_user_id: UserId,
+    options: LdapHandlerOptions,
+    /// With the "mail" RDN, the users of the emails found in the DNs of the current request.
+    resolved_emails: HashMap<String, UserId>,
+    /// The groups of the entryUUIDs in the current search filter, by UUID.
+    resolved_group_uuids: HashMap<String, GroupId>,
+    peer_addr: Option<SocketAddr>,
+    client_certificate: Option<Vec<u8>>,
+    /// The user that SASL EXTERNAL binds as without a certificate, on the Unix socket.
+    local_user: Option<UserId>,
+    tls_active: bool,
+    start_tls_pending: bool,
+    /// By cookie, at most `MAX_PAGED_SEARCHES`. Dropped on each bind and unbind.
+    paged_searches: BTreeMap<u64, PagedSearch>,
+    last_paged_search_cookie: u64,
+    /// Where the responses to the current request go while it is in progress, if they can be sent
+    /// before the final ones.
+    response_sender: Option<ResponseSender>,
+    entry_stream: Option<EntryStream>,
+    /// The message ID of the current request.
+    msgid: i32,
+    persistent_search: Option<PersistentSearch>,
+    /// The persistent search canceled by the current request, see `take_canceled_search`.
+    canceled_search: Option<i32>,
+    /// Counts the connection for the DN it is bound as.
+    user_connection: Option<UserConnectionGuard>,
+    close_pending: bool,
+    /// Whether the last bind failed because the account is temporarily locked out.
+    bind_locked_out: bool,
+    /// The entry and the groups of the bound user, read at most once per connection with
+    /// `cache_bound_user`. The changes made through the connection clear them.
+    bound_user_entry: RefCell<Option<User>>,
+    /// Also with the last change of the backend when they were read: they decide what the user
+    /// is allowed to do, so they are read again after any change.
+    bound_user_groups: RefCell<Option<(UserId, u64, HashSet<GroupIdAndName>)>>,
+    /// The entries read from the backend but left out of the results of the current search.
+    filtered_entries: Cell<usize>,
+}
+
+impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend> {
+    /// The handler of one connection, anonymous until a bind. It doesn't need a socket: the
+    /// tests can feed it the operations with `handle_ldap_message`, e.g. over the in-memory
+    /// backend of `domain::in_memory_backend_handler`.
+    pub fn new(
+        backend_handler: Backend,
+        ldap_base_dn: String,
+        ldap_user_id: UserId,
+        options: LdapHandlerOptions,
+        peer_addr: Option<SocketAddr>,
+    ) -> Self {
+        let parse_ou = |rdn: &str| {
+            parse_ou_rdn(rdn).unwrap_or_else(|_| {
+                panic!(
+                    "Invalid value for the people or groups OU in configuration: {}",
+                    rdn
+                )
+            })
+        };
+        Self {
+            dn: LdapDn("unauthenticated".to_string()),
+            user_id: UserId::new("unauthenticated"),
+            backend_handler,
+            base_dn: parse_distinguished_name(&ldap_base_dn).unwrap_or_else(|_| {
+                panic!(
+                    "Invalid value for ldap_base_dn in configuration: {}",
+                    ldap_base_dn
+                )
+            }),
+            ldap_user_id,
+            people_ou: parse_ou(&options.people_ou),
+            groups_ou: parse_ou(&options.groups_ou),
+            people_dn_str: format!("{},{}", options.people_ou, ldap_base_dn),
+            groups_dn_str: format!("{},{}", options.groups_ou, ldap_base_dn),
+            base_dn_str: ldap_base_dn,
+            base_dn_aliases: options
+                .base_dn_aliases
+                .iter()
+                .map(|alias| {
+                    parse_distinguished_name(alias).unwrap_or_else(|_| {
+                        panic!("Invalid alias of ldap_base_dn in configuration: {}", alias)
+                    })
+                })
+                .collect(),
+            options,
+            resolved_emails: HashMap::new(),
+            resolved_group_uuids: HashMap::new(),
+            peer_addr,
+            client_certificate: None,
+            local_user: None,
+            tls_active: false,
+            start_tls_pending: false,
+            paged_searches: BTreeMap::new(),
+            last_paged_search_cookie: 0,
+            response_sender: None,
+            entry_stream: None,
+            msgid: 0,
+            persistent_search: None,
+            canceled_search: None,
+            user_connection: None,
+            close_pending: false,
+            bind_locked_out: false,
+            bound_user_entry: RefCell::new(None),
+            bound_user_groups: RefCell::new(None),
+            filtered_entries: Cell::new(0),
+        }
+    }
+
+    /// Returns true (once) if the last message was an accepted StartTLS request: the caller
+    /// should then perform the TLS handshake on the connection before reading the next message.
+    pub fn take_start_tls_request(&mut self) -> bool {
+        std::mem::replace(&mut self.start_tls_pending, false)
+    }
+
+    /// Returns true if the connection should be closed after sending the responses to the last
+    /// message.
+    pub fn take_close_request(&mut self) -> bool {
+        std::mem::replace(&mut self.close_pending, false)
+    }
+
+    /// Sets the (DER-encoded) certificate presented by the client during the TLS handshake, if
+    /// any. It can then be used to bind with SASL EXTERNAL.
+    pub fn set_client_certificate(&mut self, certificate: Option<Vec<u8>>) {
+        self.client_certificate = certificate;
+    }
+
+    /// Sets the user of the local connections (on the Unix socket), which can bind as this user
+    /// with SASL EXTERNAL, without credentials.
+    pub fn set_local_user(&mut self, user_id: Option<UserId>) {
+        self.local_user = user_id;
+    }
+
+    /// Marks the connection as encrypted, after the TLS handshake of LDAPS.
+    pub fn set_tls_active(&mut self) {
+        self.tls_active = true;
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// Where the server records the operations of the session, if anywhere.
+    pub fn operation_log(&self) -> Option<&Arc<OperationLog>> {
+        self.options.operation_log.as_ref()
+    }
+
+    /// The DN the session is bound as, if any.
+    pub fn bound_dn(&self) -> Option<&str> {
+        if self.is_anonymous() {
+            None
+        } else {
+            Some(&self.dn.0)
+        }
+    }
+
+    /// Rewrites a DN under one of the aliases of the base DN to be under the base DN; the other
+    /// DNs are returned unchanged.
+    fn to_canonical_dn(&self, dn: &str) -> String {
+        let parts = match parse_distinguished_name(dn) {
+            Ok(parts) => parts,
+            Err(_) => return dn.to_string(),
+        };
+        match self
+            .base_dn_aliases
+            .iter()
+            .find(|alias| is_subtree(&parts, alias))
+        {
+            Some(alias) => parts[..parts.len() - alias.len()]
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, escape_dn_value(value)))
+                .chain(std::iter::once(self.base_dn_str.clone()))
+                .collect::<Vec<_>>()
+                .join(","),
+            None => dn.to_string(),
+        }
+    }
+
+    fn get_user_id_from_dn(&self, dn: &str) -> Result<UserId> {
+        let rdn_value = get_user_rdn_value_from_distinguished_name(
+            &self.to_canonical_dn(dn),
+            &self.base_dn,
+            &self.people_ou,
+            &self.people_dn_str,
+            self.options.user_rdn_attribute,
+        )?;
+        match self.options.user_rdn_attribute {
+            UserRdnAttribute::Mail => self
+                .resolved_emails
+                .get(&rdn_value.to_lowercase())
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!(r#"No user with the email "{}""#, rdn_value)),
+            _ => Ok(UserId::new(&rdn_value)),
+        }
+    }
+
+    /// With the "mail" RDN, looks up the users of the emails in the given DNs, so that
+    /// `get_user_id_from_dn` can find them.
+    async fn resolve_user_emails<'a, I>(&mut self, dns: I)
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        self.resolved_emails.clear();
+        if self.options.user_rdn_attribute != UserRdnAttribute::Mail {
+            return;
+        }
+        let emails = dns
+            .into_iter()
+            .filter_map(|dn| {
+                get_user_rdn_value_from_distinguished_name(
+                    &self.to_canonical_dn(dn),
+                    &self.base_dn,
+                    &self.people_ou,
+                    &self.people_dn_str,
+                    UserRdnAttribute::Mail,
+                )
+                .ok()
+            })
+            .map(|email| UserRequestFilter::Equality("email".to_string(), email))
+            .collect::<Vec<_>>();
+        if emails.is_empty() {
+            return;
+        }
+        match self
+            .backend_handler
+            .list_users(Some(UserRequestFilter::Or(emails)))
+            .await
+        {
+            Ok(users) => {
+                self.resolved_emails = users
+                    .into_iter()
+                    .map(|user| (user.email.to_lowercase(), user.user_id))
+                    .collect()
+            }
+            Err(e) => warn!("Could not look up the users by email: {:#}", e),
+        }
+    }
+
+    /// Looks up the groups of the entryUUIDs in the filter, so that `convert_group_filter` can
+    /// find them: the UUIDs are derived from the group IDs, and can't be reversed.
+    async fn resolve_group_uuids(&mut self, filter: &LdapFilter) {
+        self.resolved_group_uuids.clear();
+        let uuids = get_filter_values(filter, "entryUUID");
+        if uuids.is_empty() {
+            return;
+        }
+        let group_ids = match self.list_groups(None).await {
+            Ok(groups) => groups.into_iter().map(|group| group.id).collect::<Vec<_>>(),
+            Err(e) => {
+                warn!("Could not look up the groups by UUID: {:#}", e);
+                return;
+            }
+        };
+        self.resolved_group_uuids = group_ids
+            .into_iter()
+            .chain(
+                self.options
+                    .all_users_group
+                    .as_ref()
+                    .map(|_| ALL_USERS_GROUP_ID),
+            )
+            .map(|id| (make_entry_uuid("group", &id.0.to_string()), id))
+            .filter(|(uuid, _)| uuids.iter().any(|u| u.eq_ignore_ascii_case(uuid)))
+            .collect();
+    }
+
+    /// The users with the given email, ignoring the errors.
+    async fn find_users_by_email(&self, email: &str) -> Vec<UserId> {
+        match self
+            .backend_handler
+            .list_users(Some(UserRequestFilter::Equality(
+                "email".to_string(),
+                email.to_string(),
+            )))
+            .await
+        {
+            Ok(users) => users.into_iter().map(|user| user.user_id).collect(),
+            Err(e) => {
+                warn!(
+                    r#"Could not look up the users with the email "{}": {:#}"#,
+                    email, e
+                );
+                vec![]
+            }
+        }
+    }
+
+    /// With `netbios_domain` or `upn_suffix`, the user of an Active Directory-style login:
+    /// "DOMAIN\\user" or "user@suffix". The logins for other domains are refused like wrong
+    /// passwords; None if the name is not of this form.
+    fn get_windows_login_user_id(
+        &self,
+        name: &str,
+    ) -> Option<std::result::Result<UserId, (LdapResultCode, String)>> {
+        if name.contains('=') {
+            return None;
+        }
+        let refuse = |domain: &str| {
+            debug!(r#"Refused a bind for the unknown domain "{}""#, domain);
+            Some(Err((LdapResultCode::InvalidCredentials, "".to_string())))
+        };
+        if let Some(netbios_domain) = &self.options.netbios_domain {
+            if let Some((domain, user)) = name.split_once('\\') {
+                if !domain.eq_ignore_ascii_case(netbios_domain) || user.is_empty() {
+                    return refuse(domain);
+                }
+                return Some(Ok(UserId::new(user)));
+            }
+        }
+        if let Some(upn_suffix) = &self.options.upn_suffix {
+            if let Some((user, suffix)) = name.rsplit_once('@') {
+                if suffix.eq_ignore_ascii_case(upn_suffix) && !user.is_empty() {
+                    return Some(Ok(UserId::new(user)));
+                }
+                // Another domain could still be the one of an email.
+                if !self.options.allow_email_login {
+                    return refuse(suffix);
+                }
+            }
+        }
+        None
+    }
+
+    /// The user designated by the DN of a bind request. With `allow_email_login`, the DN can also
+    /// be a plain email, or have an email as its RDN value; with `netbios_domain` and
+    /// `upn_suffix`, it can be a Windows login; with `bind_dn_template`, a bare user name. Returns
+    /// whether the DN was something else than the DN of the user.
+    async fn get_bind_user_id(
+        &mut self,
+        dn: &str,
+    ) -> std::result::Result<(UserId, bool), (LdapResultCode, String)> {
+        if let Some(user_id) = self.get_windows_login_user_id(dn) {
+            return user_id.map(|user_id| (user_id, true));
+        }
+        let expanded_dn = match &self.options.bind_dn_template {
+            Some(template) if !dn.contains('=') => {
+                Some(template.replace("%u", &escape_dn_value(dn)))
+            }
+            _ => None,
+        };
+        let is_expanded = expanded_dn.is_some();
+        let dn = expanded_dn.as_deref().unwrap_or(dn);
+        let is_plain_email = !dn.contains('=') && dn.contains('@');
+        if self.options.allow_email_login
+            && self.options.user_rdn_attribute != UserRdnAttribute::Mail
+        {
+            let email = if is_plain_email {
+                Some(dn.to_string())
+            } else {
+                get_user_rdn_value_from_distinguished_name(
+                    &self.to_canonical_dn(dn),
+                    &self.base_dn,
+                    &self.people_ou,
+                    &self.people_dn_str,
+                    self.options.user_rdn_attribute,
+                )
+                .ok()
+                .filter(|value| value.contains('@'))
+            };
+            if let Some(email) = email {
+                match self.find_users_by_email(&email).await.as_slice() {
+                    [user_id] => return Ok((user_id.clone(), true)),
+                    // The user ID itself could look like an email.
+                    [] if !is_plain_email => (),
+                    // Unknown and ambiguous emails look like wrong passwords.
+                    _ => {
+                        debug!(r#"No single user with the email "{}""#, &email);
+                        return Err((LdapResultCode::InvalidCredentials, "".to_string()));
+                    }
+                }
+            }
+        }
+        self.resolve_user_emails([dn]).await;
+        match self.get_user_id_from_dn(dn) {
+            Ok(user_id) => Ok((user_id, is_expanded)),
+            Err(e) => Err((LdapResultCode::NamingViolation, e.to_string())),
+        }
+    }
+
+    /// The DN of a user, as it appears in the entries.
+    async fn get_user_dn(&self, user_id: &UserId) -> String {
+        let rdn_value = match self.options.user_rdn_attribute {
+            UserRdnAttribute::Mail => match self.backend_handler.get_user_details(user_id).await {
+                Ok(user) => user.email,
+                Err(e) => {
+                    warn!(r#"Could not get the email of "{}": {:#}"#, user_id, e);
+                    user_id.to_string()
+                }
+            },
+            _ => user_id.to_string(),
+        };
+        let ous = match self.get_user_ous().await {
+            Ok(ous) => ous,
+            Err(e) => {
+                warn!(r#"Could not get the OU of "{}": {:#}"#, user_id, e);
+                HashMap::new()
+            }
+        };
+        make_user_dn(
+            self.options.user_rdn_attribute,
+            &rdn_value,
+            ous.get(user_id).map(String::as_str),
+            &self.people_dn_str,
+        )
+    }
+
+    /// The OU of `user_ou_mapping` designated by the DN, e.g. "ou=employees,ou=people,...".
+    fn get_user_ou_of_dn(&self, dn_parts: &[(String, String)]) -> Option<&str> {
+        if dn_parts.len() != self.base_dn.len() + 2 || !is_rdn(&dn_parts[1], &self.people_ou) {
+            return None;
+        }
+        self.options
+            .user_ou_mapping
+            .iter()
+            .map(|(ou, _)| ou.as_str())
+            .find(|ou| is_ou(&dn_parts[0], ou))
+    }
+
+    /// Whether the DN, under the base DN, is one of the entries containing the users or the
+    /// groups: the base itself, the people and groups OUs or one of the mapped OUs.
+    fn is_container_dn(&self, dn_parts: &[(String, String)]) -> bool {
+        dn_parts.len() == self.base_dn.len()
+            || (dn_parts.len() == self.base_dn.len() + 1
+                && (is_rdn(&dn_parts[0], &self.people_ou) || is_rdn(&dn_parts[0], &self.groups_ou)))
+            || self.get_user_ou_of_dn(dn_parts).is_some()
+    }
+
+    /// For a DN under the base DN that can't exist, i.e. that is neither a container nor an
+    /// entry directly in one of the OUs, the DN of its deepest existing ancestor (the matchedDN
+    /// of a noSuchObject result).
+    fn get_missing_dn_ancestor(&self, dn_parts: &[(String, String)]) -> Option<String> {
+        if self.is_container_dn(dn_parts)
+            || (dn_parts.len() > self.base_dn.len() + 1 && self.is_container_dn(&dn_parts[1..]))
+        {
+            return None;
+        }
+        let depth = (1..dn_parts.len())
+            .find(|&i| self.is_container_dn(&dn_parts[i..]))
+            .unwrap();
+        Some(
+            dn_parts[depth..dn_parts.len() - self.base_dn.len()]
+                .iter()
+                .map(|(name, value)| format!("{}={},", name, escape_dn_value(value)))
+                .collect::<String>()
+                + &self.base_dn_str,
+        )
+    }
+
+    /// The OU of the users in the groups of `user_ou_mapping`. The other users are directly
+    /// under the people OU.
+    async fn get_user_ous(&self) -> Result<HashMap<UserId, String>> {
+        if self.options.user_ou_mapping.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let groups = self
+            .list_groups(Some(GroupRequestFilter::Or(
+                self.options
+                    .user_ou_mapping
+                    .iter()
+                    .map(|(_, group)| GroupRequestFilter::DisplayName(group.clone()))
+                    .collect(),
+            )))
+            .await?;
+        let mut ous = HashMap::new();
+        // In the order of the mapping, so that the users in several groups get the first OU.
+        for (ou, group_name) in &self.options.user_ou_mapping {
+            for group in groups.iter().filter(|g| &g.display_name == group_name) {
+                for user_id in &group.users {
+                    ous.entry(user_id.clone()).or_insert_with(|| ou.clone());
+                }
+            }
+        }
+        Ok(ous)
+    }
+
+    /// The emails of all the users, to build the DNs of the group members with the "mail" RDN.
+    /// Empty for the other RDNs.
+    async fn get_member_emails(&self) -> Result<HashMap<UserId, String>> {
+        if self.options.user_rdn_attribute != UserRdnAttribute::Mail {
+            return Ok(HashMap::new());
+        }
+        Ok(self
+            .backend_handler
+            .list_users(None)
+            .await?
+            .into_iter()
+            .map(|user| (user.user_id, user.email))
+            .collect())
+    }
+
+    /// The DN of a group member. For the "cn" RDN, the members are named after their user ID.
+    fn make_member_dn(
+        &self,
+        user_id: &UserId,
+        emails: &HashMap<UserId, String>,
+        ous: &HashMap<UserId, String>,
+    ) -> String {
+        let rdn_value = match self.options.user_rdn_attribute {
+            // The user could have been deleted in the meantime.
+            UserRdnAttribute::Mail => emails.get(user_id).map(String::as_str),
+            _ => None,
+        };
+        make_user_dn(
+            self.options.user_rdn_attribute,
+            rdn_value.unwrap_or_else(|| user_id.as_str()),
+            ous.get(user_id).map(String::as_str),
+            &self.people_dn_str,
+        )
+    }
+
+    /// Whether the bound user is the admin user of the configuration.
+    fn is_ldap_admin(&self) -> bool {
+        !self.is_anonymous() && self.user_id == self.ldap_user_id
+    }
+
+    /// Whether the session is bound to the read-only account.
+    fn is_readonly_account(&self) -> bool {
+        matches!(&self.options.readonly_account, Some(account) if account.dn == self.dn)
+    }
+
+    /// Whether the bound identity can read all the entries, rather than only its own.
+    fn can_read_all(&self) -> bool {
+        self.is_ldap_admin() || self.is_readonly_account()
+    }
+
+    fn get_group_id_from_dn(&self, dn: &str) -> Result<String> {
+        get_group_id_from_distinguished_name(
+            &self.to_canonical_dn(dn),
+            &self.base_dn,
+            &self.groups_ou,
+            &self.groups_dn_str,
+        )
+    }
+
+    /// Whether a user that is not an admin can read the entry of the DN: its own entry and those
+    /// of its groups, but not those of the other users and groups. The searches under the
+    /// entries it can't read at all are refused, whereas the searches of the containers only
+    /// return the entries it can read, and the attributes it can't read (hidden attributes,
+    /// password metadata) are silently left out of the entries.
+    async fn can_read_entry(&self, dn: &str) -> bool {
+        if let Ok(user_id) = self.get_user_id_from_dn(dn) {
+            return user_id == self.user_id;
+        }
+        if let Ok(group_name) = self.get_group_id_from_dn(dn) {
+            let all_users_group = self.options.all_users_group.as_deref();
+            if all_users_group.map_or(false, |g| g.eq_ignore_ascii_case(&group_name)) {
+                return true;
+            }
+            return match self.get_bound_user_groups().await {
+                Ok(groups) => groups
+                    .iter()
+                    .any(|group| group.1.eq_ignore_ascii_case(&group_name)),
+                Err(e) => {
+                    warn!(
+                        r#"Could not get the groups of "{}": {:#}"#,
+                        &self.user_id, e
+                    );
+                    false
+                }
+            };
+        }
+        true
+    }
+
+    /// The subtrees that the bound identity can search, if it is restricted: those of its DN and
+    /// of its groups.
+    async fn get_allowed_search_bases(&self) -> Option<Vec<&[(String, String)]>> {
+        if self.options.search_restrictions.is_empty() || self.is_anonymous() {
+            return None;
+        }
+        let is_user = !self.is_readonly_account();
+        let mut groups = None;
+        let mut bases = None;
+        for restriction in &self.options.search_restrictions {
+            let identity = restriction.identity.0.as_str();
+            let applies = if restriction.identity == self.dn {
+                true
+            } else if let Ok(user_id) = self.get_user_id_from_dn(identity) {
+                is_user && user_id == self.user_id
+            } else if let Ok(group_name) = self.get_group_id_from_dn(identity) {
+                if groups.is_none() && is_user {
+                    groups = Some(match self.get_bound_user_groups().await {
+                        Ok(groups) => groups,
+                        Err(e) => {
+                            warn!(
+                                r#"Could not get the groups of "{}": {:#}"#,
+                                &self.user_id, e
+                            );
+                            // Restricted to nothing rather than unrestricted.
+                            return Some(vec![]);
+                        }
+                    });
+                }
+                groups
+                    .iter()
+                    .flatten()
+                    .any(|group| group.1.eq_ignore_ascii_case(&group_name))
+            } else {
+                false
+            };
+            if applies {
+                bases
+                    .get_or_insert_with(Vec::new)
+                    .extend(restriction.bases.iter().map(Vec::as_slice));
+            }
+        }
+        bases
+    }
+
+    /// The client address, for the logs.
+    fn peer(&self) -> String {
+        self.peer_addr
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|| "an unknown address".to_string())
+    }
+
+    /// Logs every bind, successful or not, with the DN requested by the client.
+    fn log_bind(&self, dn: &str, mechanism: &str, code: LdapResultCode) {
+        info!(
+            r#"Bind for "{}" from {} with {}: {:?}"#,
+            escape_for_log(dn),
+            self.peer(),
+            mechanism,
+            code
+        );
+    }
+
+    fn is_anonymous(&self) -> bool {
+        self.dn == LdapDn("unauthenticated".to_string())
+    }
+
+    /// Whether the attribute is never readable. The passwords are stored as OPAQUE files, that
+    /// must not leave the server.
+    fn is_hidden_attribute(&self, attribute: &str) -> bool {
+        attribute.eq_ignore_ascii_case("userPassword")
+            || self
+                .options
+                .hidden_attributes
+                .iter()
+                .any(|a| a.eq_ignore_ascii_case(attribute))
+    }
+
+    /// Returns the error to send back if the client is over its bind rate limit.
+    fn check_bind_rate_limit(&self) -> Option<(LdapResultCode, String)> {
+        let (limiter, peer_addr) = match (&self.options.bind_rate_limiter, self.peer_addr) {
+            (Some(limiter), Some(peer_addr)) => (limiter, peer_addr),
+            _ => return None,
+        };
+        if limiter.try_acquire(peer_addr.ip()) {
+            return None;
+        }
+        warn!("Too many binds from {}, refusing the bind", peer_addr.ip());
+        Some((
+            LdapResultCode::Busy,
+            "Too many bind attempts, try again later".to_string(),
+        ))
+    }
+
+    fn do_anonymous_bind(&mut self, password: &str) -> (LdapResultCode, String) {
+        if !password.is_empty() {
+            return (LdapResultCode::InvalidCredentials, "".to_string());
+        }
+        if !self.options.allow_anonymous_bind {
+            return (
+                LdapResultCode::InappropriateAuthentication,
+                "Anonymous bind is not allowed".to_string(),
+            );
+        }
+        debug!("Anonymous bind from {}", self.peer());
+        self.reset_to_anonymous();
+        (LdapResultCode::Success, "".to_string())
+    }
+
+    /// Binds to the read-only account, if that's the DN of the request.
+    fn do_readonly_account_bind(
+        &mut self,
+        dn: &str,
+        password: &str,
+    ) -> Option<(LdapResultCode, String)> {
+        let account = self.options.readonly_account.as_ref()?;
+        match LdapDn::normalized(&self.to_canonical_dn(dn)) {
+            Ok(dn) if dn == account.dn => (),
+            _ => return None,
+        }
+        if account.password != SecUtf8::from(password) {
+            return Some((LdapResultCode::InvalidCredentials, "".to_string()));
+        }
+        self.reset_to_anonymous();
+        self.dn = account.dn.clone();
+        Some((LdapResultCode::Success, "".to_string()))
+    }
+
+    pub async fn do_bind(&mut self, request: &LdapBindRequest) -> (LdapResultCode, String) {
+        debug!(
+            r#"Received bind request for "{}" from {}"#,
+            &request.dn,
+            self.peer()
+        );
+        self.bind_locked_out = false;
+        if let Some(error) = self.check_bind_rate_limit() {
+            return error;
+        }
+        let LdapBindCred::Simple(password) = &request.cred;
+        if request.dn.is_empty() {
+            return self.do_anonymous_bind(password);
+        }
+        if let Some(result) = self.do_readonly_account_bind(&request.dn, password) {
+            return result;
+        }
+        let (user_id, is_rewritten) = match self.get_bind_user_id(&request.dn).await {
+            Ok(s) => s,
+            Err(e) => return e,
+        };
+        if let Err(e) = self.check_password(&user_id, password, &request.dn).await {
+            return e;
+        }
+        // The DN was already parsed to get the user ID.
+        let dn = if is_rewritten {
+            self.get_user_dn(&user_id).await
+        } else {
+            self.to_canonical_dn(&request.dn)
+        };
+        self.dn = LdapDn::normalized(&dn).unwrap_or(LdapDn(dn));
+        self.user_id = user_id;
+        (LdapResultCode::Success, "".to_string())
+    }
+
+    /// Checks the password of the user with the backend, and keeps track of the failures. `name`
+    /// is the name the client gave, for the logs.
+    async fn check_password(
+        &mut self,
+        user_id: &UserId,
+        password: &str,
+        name: &str,
+    ) -> std::result::Result<(), (LdapResultCode, String)> {
+        match self
+            .backend_handler
+            .bind(BindRequest {
+                name: user_id.clone(),
+                password: password.to_string(),
+            })
+            .await
+        {
+            Ok(()) => {
+                if let Some(tracker) = &self.options.bind_failure_tracker {
+                    tracker.reset(user_id);
+                }
+                Ok(())
+            }
+            // Wrong passwords and unknown users are indistinguishable, to avoid user enumeration.
+            Err(DomainError::AuthenticationError(_)) => {
+                if let Some(tracker) = &self.options.bind_failure_tracker {
+                    let delay = tracker.record_failure(user_id);
+                    if !delay.is_zero() {
+                        debug!(
+                            r#"Delaying the failed bind for "{}" by {:?}"#,
+                            user_id, delay
+                        );
+                        tokio::time::sleep(delay).await;
+                    }
+                    if self.options.report_account_lockout && tracker.is_throttled(user_id) {
+                        self.bind_locked_out = true;
+                        return Err((
+                            LdapResultCode::InvalidCredentials,
+                            "Too many failed binds, the account is temporarily locked".to_string(),
+                        ));
+                    }
+                }
+                Err((LdapResultCode::InvalidCredentials, "".to_string()))
+            }
+            Err(e @ DomainError::DatabaseError(_)) => {
+                warn!(r#"Could not bind "{}": {}"#, name, e);
+                Err((
+                    LdapResultCode::Unavailable,
+                    "The server is unavailable, try again later".to_string(),
+                ))
+            }
+            Err(e) => {
+                warn!(r#"Could not bind "{}": {}"#, name, e);
+                Err((
+                    LdapResultCode::Other,
+                    "Internal error during the bind".to_string(),
+                ))
+            }
+        }
+    }
+
+    /// Finds the user corresponding to a client certificate: the subject UID or CN is the user
+    /// ID, otherwise the email in the alternative names is looked up.
+    async fn find_certificate_user(&self, identity: &CertificateIdentity) -> Option<UserId> {
+        for attribute in ["UID", "CN"] {
+            if let Some(value) = identity.subject_attribute(attribute) {
+                let user_id = UserId::new(value);
+                if self
+                    .backend_handler
+                    .get_user_details(&user_id)
+                    .await
+                    .is_ok()
+                {
+                    return Some(user_id);
+                }
+            }
+        }
+        for email in &identity.emails {
+            let filter = UserRequestFilter::Equality("email".to_string(), email.clone());
+            if let Ok(users) = self.backend_handler.list_users(Some(filter)).await {
+                if let [user] = users.as_slice() {
+                    return Some(user.user_id.clone());
+                }
+            }
+        }
+        None
+    }
+
+    /// The user of the client certificate.
+    async fn get_certificate_user(
+        &self,
+        certificate: &[u8],
+    ) -> std::result::Result<UserId, (LdapResultCode, String)> {
+        let identity = match parse_certificate_identity(certificate) {
+            Ok(identity) => identity,
+            Err(e) => {
+                warn!("Could not read the client certificate: {:#}", e);
+                return Err((
+                    LdapResultCode::InvalidCredentials,
+                    "Invalid client certificate".to_string(),
+                ));
+            }
+        };
+        match self.find_certificate_user(&identity).await {
+            Some(user_id) => Ok(user_id),
+            None => {
+                debug!(
+                    r#"No user matching the certificate of "{}" from {}"#,
+                    identity.subject_dn(),
+                    self.peer()
+                );
+                Err((LdapResultCode::InvalidCredentials, "".to_string()))
+            }
+        }
+    }
+
+    /// Binds as the user of the client certificate, or as the local user on the Unix socket.
+    async fn do_external_bind(&mut self, request: &SaslBindRequest) -> (LdapResultCode, String) {
+        let user_id = match (&self.client_certificate, &self.local_user) {
+            (Some(certificate), _) => match self.get_certificate_user(certificate).await {
+                Ok(user_id) => user_id,
+                Err(e) => return e,
+            },
+            (None, Some(user_id)) => user_id.clone(),
+            (None, None) => {
+                return (
+                    LdapResultCode::InappropriateAuthentication,
+                    "No client certificate was presented".to_string(),
+                )
+            }
+        };
+        let dn = self.get_user_dn(&user_id).await;
+        // The client can ask for an authorization identity, but only its own.
+        if let Some(authz_id) = request.credentials.as_deref().filter(|c| !c.is_empty()) {
+            let authz_id = String::from_utf8_lossy(authz_id);
+            if authz_id != format!("dn:{}", dn) && authz_id != format!("u:{}", user_id) {
+                return (
+                    LdapResultCode::InsufficentAccessRights,
+                    format!("Not authorized to act as {}", authz_id),
+                );
+            }
+        }
+        debug!(r#"EXTERNAL bind as "{}""#, &dn);
+        self.dn = LdapDn::normalized(&dn).unwrap_or(LdapDn(dn));
+        self.user_id = user_id;
+        (LdapResultCode::Success, "".to_string())
+    }
+
+    /// SASL PLAIN (RFC 4616), with the credentials "authzid\0authcid\0password". The
+    /// authentication ID is a user ID, "u:<user ID>" or "dn:<user DN>". The authorization ID is
+    /// either empty or the same user, or another user to act as, for the members of the proxy
+    /// group.
+    async fn do_plain_bind(&mut self, request: &SaslBindRequest) -> (LdapResultCode, String) {
+        self.bind_locked_out = false;
+        // The password would be sent in the clear.
+        if !self.tls_active && !self.options.allow_insecure_sasl_plain {
+            return (
+                LdapResultCode::ConfidentialityRequired,
+                "SASL PLAIN is only allowed over TLS".to_string(),
+            );
+        }
+        let (authz_id, authc_id, password) = match parse_sasl_plain_credentials(
+            request.credentials.as_deref().unwrap_or_default(),
+        ) {
+            Some(credentials) => credentials,
+            None => {
+                return (
+                    LdapResultCode::ProtocolError,
+                    "Malformed SASL PLAIN credentials".to_string(),
+                )
+            }
+        };
+        let user_id = if let Some(dn) = authc_id.strip_prefix("dn:") {
+            match self.get_bind_user_id(dn).await {
+                Ok((user_id, _)) => user_id,
+                Err(e) => return e,
+            }
+        } else {
+            UserId::new(authc_id.strip_prefix("u:").unwrap_or(authc_id))
+        };
+        if let Err(e) = self.check_password(&user_id, password, authc_id).await {
+            return e;
+        }
+        let dn = self.get_user_dn(&user_id).await;
+        self.dn = LdapDn::normalized(&dn).unwrap_or(LdapDn(dn));
+        self.user_id = user_id;
+        if authz_id.is_empty()
+            || authz_id == format!("u:{}", self.user_id)
+            || authz_id == format!("dn:{}", self.dn.0)
+        {
+            return (LdapResultCode::Success, "".to_string());
+        }
+        match self.get_authz_identity(authz_id).await {
+            Ok((dn, user_id)) => {
+                debug!(
+                    r#"SASL PLAIN bind as "{}" acting as "{}""#,
+                    &self.dn.0, &dn.0
+                );
+                self.dn = dn;
+                self.user_id = user_id;
+                (LdapResultCode::Success, "".to_string())
+            }
+            Err(e) => {
+                self.reset_to_anonymous();
+                (
+                    LdapResultCode::InsufficentAccessRights,
+                    format!("Not authorized to act as {}: {:#}", authz_id, e),
+                )
+            }
+        }
+    }
+
+    /// The LDAPv2 clients would misread the LDAPv3 responses (e.g. the UTF-8 strings), so their
+    /// binds are refused. The session stays as it was.
+    pub fn do_unsupported_bind(
+        &self,
+        request: &UnsupportedBindRequest,
+    ) -> (LdapResultCode, String) {
+        (
+            LdapResultCode::ProtocolError,
+            format!(
+                "Unsupported LDAP protocol version {}, only version 3 is supported",
+                request.version
+            ),
+        )
+    }
+
+    pub async fn do_sasl_bind(&mut self, request: &SaslBindRequest) -> (LdapResultCode, String) {
+        debug!(
+            r#"Received SASL {} bind request for "{}" from {}"#,
+            &request.mechanism,
+            &request.dn,
+            self.peer()
+        );
+        if let Some(error) = self.check_bind_rate_limit() {
+            return error;
+        }
+        match request.mechanism.as_str() {
+            "EXTERNAL" => self.do_external_bind(request).await,
+            "PLAIN" => self.do_plain_bind(request).await,
+            mechanism => (
+                LdapResultCode::AuthMethodNotSupported,
+                format!("Unsupported SASL mechanism: {}", mechanism),
+            ),
+        }
+    }
+
+    async fn change_password(&mut self, user: &UserId, password: &str) -> Result<()> {
+        use lldap_auth::*;
+        let mut rng = rand::rngs::OsRng;
+        let registration_start_request =
+            opaque::client::registration::start_registration(password, &mut rng)?;
+        let req = registration::ClientRegistrationStartRequest {
+            username: user.to_string(),
+            registration_start_request: registration_start_request.message,
+        };
+        let registration_start_response = self.backend_handler.registration_start(req).await?;
+        let registration_finish = opaque::client::registration::finish_registration(
+            registration_start_request.state,
+            registration_start_response.registration_response,
+            &mut rng,
+        )?;
+        let req = registration::ClientRegistrationFinishRequest {
+            server_data: registration_start_response.server_data,
+            registration_upload: registration_finish.message,
+        };
+        self.backend_handler.registration_finish(req).await?;
+        Ok(())
+    }
+
+    /// The groups of the bound user (or of the proxied user), cached for the connection until the
+    /// backend changes.
+    async fn get_bound_user_groups(&self) -> Result<HashSet<GroupIdAndName>> {
+        if !self.options.cache_bound_user {
+            return Ok(self.backend_handler.get_user_groups(&self.user_id).await?);
+        }
+        // E.g. the user was removed from the admin group in the web UI.
+        let change_id = *self.backend_handler.subscribe_to_changes().borrow();
+        if let Some((user_id, cached_change_id, groups)) = &*self.bound_user_groups.borrow() {
+            if user_id == &self.user_id && *cached_change_id == change_id {
+                return Ok(groups.clone());
+            }
+        }
+        let groups = self.backend_handler.get_user_groups(&self.user_id).await?;
+        *self.bound_user_groups.borrow_mut() =
+            Some((self.user_id.clone(), change_id, groups.clone()));
+        Ok(groups)
+    }
+
+    /// Forgets the cached entry and groups of the bound user. The next requests read them again.
+    fn forget_bound_user(&self) {
+        self.bound_user_entry.replace(None);
+        self.bound_user_groups.replace(None);
+    }
+
+    /// Whether the members of the group are admins.
+    fn is_admin_group(&self, group_name: &str) -> bool {
+        group_name == ADMIN_GROUP_NAME
+            || matches!(
+                &self.options.admin_group,
+                Some(admin_group) if admin_group.eq_ignore_ascii_case(group_name)
+            )
+    }
+
+    /// Gives the admin role of lldap (the membership of "lldap_admin") to the users added to the
+    /// `admin_group` of the configuration. The role is never taken from the users removed from
+    /// it: they may have had it before, or been given it in the web UI, so it is up to an admin to
+    /// remove them from "lldap_admin" too. The changes made to the group in the web UI are not
+    /// synced either.
+    async fn grant_admin_role(
+        &self,
+        old_members: &[UserId],
+        new_members: &[UserId],
+    ) -> std::result::Result<(), DomainError> {
+        let admin_group = match self
+            .backend_handler
+            .list_groups(Some(GroupRequestFilter::DisplayName(
+                ADMIN_GROUP_NAME.to_string(),
+            )))
+            .await?
+            .into_iter()
+            .next()
+        {
+            Some(group) => group,
+            None => return Ok(()),
+        };
+        for user_id in new_members
+            .iter()
+            .filter(|u| !old_members.contains(u) && !admin_group.users.contains(u))
+        {
+            self.backend_handler
+                .add_user_to_group(user_id, admin_group.id)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Whether the bound user is an admin: either the configured admin user, or a member of the
+    /// admin group.
+    async fn is_admin(&self) -> bool {
+        if self.is_ldap_admin() {
+            return true;
+        }
+        match self.get_bound_user_groups().await {
+            Ok(groups) => groups.iter().any(|g| self.is_admin_group(&g.1)),
+            Err(e) => {
+                warn!(
+                    r#"Could not get the groups of "{}": {:#}"#,
+                    &self.user_id, e
+                );
+                false
+            }
+        }
+    }
+
+    /// Handles a modify request. For the users, only password changes are supported, as a replace
+    /// of the `userPassword` attribute: since passwords are stored with OPAQUE, they go through the
+    /// password registration rather than storing the value. Users can change their own password,
+    /// admins can change anybody's. For the groups, the members can be added and deleted, see
+    /// `modify_group_members`.
+    ///
+    /// Any other modification is unsupported, and refused with `unwillingToPerform`.
+    pub async fn do_modify(
+        &mut self,
+        request: &ModifyRequest,
+        controls: &[RawControl],
+    ) -> LdapResponseOp {
+        debug!(
+            r#"Received modify request for "{}" from {}"#,
+            &request.dn,
+            self.peer()
+        );
+        if self.is_anonymous() {
+            return make_modify_response(
+                LdapResultCode::InsufficentAccessRights,
+                "Anonymous sessions cannot modify entries".to_string(),
+            );
+        }
+        if self.is_readonly_account() {
+            return make_modify_response(
+                LdapResultCode::InsufficentAccessRights,
+                "The read-only account cannot modify entries".to_string(),
+            );
+        }
+        let changes = || {
+            request
+                .changes
+                .iter()
+                .map(|change| (change.attribute.as_str(), change.values.as_slice()))
+        };
+        if let Err((code, message)) = check_value_syntax(changes())
+            .and_then(|()| check_value_lengths(changes(), &self.options))
+        {
+            return make_modify_response(code, message);
+        }
+        if let Ok(group_name) = self.get_group_id_from_dn(&request.dn) {
+            return self.modify_group_members(group_name, request).await;
+        }
+        let password = match request.changes.as_slice() {
+            [Modification {
+                operation: ModifyOperation::Replace,
+                attribute,
+                values,
+            }] if attribute.eq_ignore_ascii_case("userPassword") => match values.as_slice() {
+                [password] => password,
+                _ => {
+                    return make_modify_response(
+                        LdapResultCode::ConstraintViolation,
+                        "Expected exactly one password".to_string(),
+                    )
+                }
+            },
+            _ => {
+                return make_modify_response(
+                    LdapResultCode::UnwillingToPerform,
+                    "Only replacing the userPassword attribute is supported".to_string(),
+                )
+            }
+        };
+        // Already checked to be valid UTF-8.
+        let password = String::from_utf8_lossy(password);
+        self.resolve_user_emails([request.dn.as_str()]).await;
+        let user_id = match self.get_user_id_from_dn(&request.dn) {
+            Ok(user_id) => user_id,
+            Err(e) => {
+                return make_modify_response(
+                    LdapResultCode::InvalidDNSyntax,
+                    format!("Invalid user DN: {:#}", e),
+                )
+            }
+        };
+        if user_id != self.user_id && !self.is_admin().await {
+            warn!(
+                r#""{}" is not allowed to change the password of "{}""#,
+                &self.dn.0, &request.dn
+            );
+            return make_modify_response(
+                LdapResultCode::InsufficentAccessRights,
+                "Only admins can change the password of other users".to_string(),
+            );
+        }
+        if let Some(control) = controls.iter().find(|c| c.oid == ASSERTION_OID) {
+            match self.check_assertion(&user_id, control).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    return LdapResponseOp::WithResultCode(
+                        Box::new(make_modify_response(
+                            LdapResultCode::Other,
+                            "The assertion does not match the entry".to_string(),
+                        )),
+                        ASSERTION_FAILED,
+                    )
+                }
+                Err(e) => {
+                    return make_modify_response(
+                        LdapResultCode::ProtocolError,
+                        format!("Invalid assertion control: {:#}", e),
+                    )
+                }
+            }
+        }
+        match self.change_password(&user_id, &password).await {
+            Ok(()) => {
+                info!(
+                    r#"Password changed for "{}" by "{}""#,
+                    &request.dn, &self.dn.0
+                );
+                make_modify_response(LdapResultCode::Success, "".to_string())
+            }
+            Err(e) => make_modify_response(
+                LdapResultCode::Other,
+                format!("Error while changing the password: {:#}", e),
+            ),
+        }
+    }
+
+    /// Adds and deletes the members of a group, as values of its member, uniqueMember or memberUid
+    /// attribute. Only the admins can change the memberships. All the modifications are checked
+    /// before any is applied: adding a member again is a no-op, but deleting a user that is not a
+    /// member fails with `noSuchAttribute`, and then nothing changes.
+    async fn modify_group_members(
+        &mut self,
+        group_name: String,
+        request: &ModifyRequest,
+    ) -> LdapResponseOp {
+        if !self.is_admin().await {
+            warn!(
+                r#""{}" is not allowed to change the members of "{}""#,
+                &self.dn.0, &request.dn
+            );
+            return make_modify_response(
+                LdapResultCode::InsufficentAccessRights,
+                "Only admins can change the members of the groups".to_string(),
+            );
+        }
+        let is_all_users_group = matches!(
+            &self.options.all_users_group,
+            Some(all_users) if all_users.eq_ignore_ascii_case(&group_name)
+        );
+        if is_all_users_group {
+            return make_modify_response(
+                LdapResultCode::UnwillingToPerform,
+                format!(r#"The members of "{}" are all the users"#, group_name),
+            );
+        }
+        let group = match self
+            .backend_handler
+            .list_groups(Some(GroupRequestFilter::DisplayName(group_name.clone())))
+            .await
+        {
+            Ok(groups) => match groups.into_iter().next() {
+                Some(group) => group,
+                None => {
+                    return make_modify_response(
+                        LdapResultCode::NoSuchObject,
+                        format!(r#"No such group: "{}""#, group_name),
+                    )
+                }
+            },
+            Err(e) => {
+                return make_modify_response(
+                    LdapResultCode::Other,
+                    format!(r#"Error while reading the group "{}": {:#}"#, group_name, e),
+                )
+            }
+        };
+        self.resolve_user_emails(
+            request
+                .changes
+                .iter()
+                .filter(|change| !change.attribute.eq_ignore_ascii_case("memberUid"))
+                .flat_map(|change| change.values.iter())
+                .filter_map(|value| std::str::from_utf8(value).ok()),
+        )
+        .await;
+        let mut members = group.users.clone();
+        for change in &request.changes {
+            let add = match change.operation {
+                ModifyOperation::Add => true,
+                ModifyOperation::Delete => false,
+                _ => {
+                    return make_modify_response(
+                        LdapResultCode::UnwillingToPerform,
+                        "Only adding and deleting the members of a group is supported".to_string(),
+                    )
+                }
+            };
+            if !is_member_attribute(&change.attribute) {
+                return make_modify_response(
+                    LdapResultCode::UnwillingToPerform,
+                    format!(
+                        r#"Cannot modify the attribute "{}" of a group"#,
+                        change.attribute
+                    ),
+                );
+            }
+            // Deleting the attribute itself removes all the members.
+            if !add && change.values.is_empty() {
+                members.clear();
+                continue;
+            }
+            for value in &change.values {
+                let user_id = match self.get_member_user_id(&change.attribute, value) {
+                    Ok(user_id) => user_id,
+                    Err(e) => {
+                        return make_modify_response(
+                            LdapResultCode::InvalidDNSyntax,
+                            format!("Invalid member: {:#}", e),
+                        )
+                    }
+                };
+                let is_member = members.contains(&user_id);
+                if add && !is_member {
+                    members.push(user_id);
+                } else if !add {
+                    if !is_member {
+                        return make_modify_response(
+                            LdapResultCode::NoSuchAttribute,
+                            format!(r#""{}" is not a member of "{}""#, user_id, group_name),
+                        );
+                    }
+                    members.retain(|member| member != &user_id);
+                }
+            }
+        }
+        let result = async {
+            for user_id in group.users.iter().filter(|u| !members.contains(u)) {
+                self.backend_handler
+                    .remove_user_from_group(user_id, group.id)
+                    .await?;
+            }
+            for user_id in members.iter().filter(|u| !group.users.contains(u)) {
+                self.backend_handler
+                    .add_user_to_group(user_id, group.id)
+                    .await?;
+            }
+            if group.display_name != ADMIN_GROUP_NAME && self.is_admin_group(&group.display_name) {
+                self.grant_admin_role(&group.users, &members).await?;
+            }
+            Ok::<_, DomainError>(())
+        }
+        .await;
+        if let Some(group_cache) = &self.options.group_cache {
+            group_cache.invalidate();
+        }
+        match result {
+            Ok(()) => {
+                info!(
+                    r#"Members of "{}" changed by "{}""#,
+                    &request.dn, &self.dn.0
+                );
+                make_modify_response(LdapResultCode::Success, "".to_string())
+            }
+            Err(e) => make_modify_response(
+                LdapResultCode::Other,
+                format!("Error while changing the members: {:#}", e),
+            ),
+        }
+    }
+
+    /// The user designated by a value of a member attribute: a DN, or a user ID for memberUid.
+    fn get_member_user_id(&self, attribute: &str, value: &[u8]) -> Result<UserId> {
+        let value = std::str::from_utf8(value).context("the value is not valid UTF-8")?;
+        if attribute.eq_ignore_ascii_case("memberUid") {
+            Ok(UserId::new(value))
+        } else {
+            self.get_user_id_from_dn(value)
+        }
+    }
+
+    /// Reads the entry for a pre-read or post-read control (RFC 4527): the response control
+    /// holds the entry, with the attributes selected by the request control. Only the user
+    /// entries can be modified, so only they can be read this way.
+    async fn read_entry_control(
+        &mut self,
+        dn: &str,
+        control: &RawControl,
+    ) -> std::result::Result<RawControl, (LdapResultCode, String)> {
+        let attributes = parse_read_attributes(control).map_err(|e| {
+            (
+                LdapResultCode::ProtocolError,
+                format!("Invalid attribute selection: {:#}", e),
+            )
+        })?;
+        let cannot_read = || {
+            (
+                LdapResultCode::InsufficentAccessRights,
+                format!(r#"Cannot read the entry "{}""#, dn),
+            )
+        };
+        self.resolve_user_emails([dn]).await;
+        let user_id = self.get_user_id_from_dn(dn).map_err(|_| cannot_read())?;
+        // Same as the searches: the users can only read their own entry.
+        if user_id != self.user_id && !self.can_read_all() {
+            return Err(cannot_read());
+        }
+        let request = LdapSearchRequest {
+            base: dn.to_string(),
+            scope: LdapSearchScope::Base,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter: LdapFilter::Present("objectClass".to_string()),
+            attrs: attributes,
+        };
+        let attributes = self.get_user_entry_attributes(&request).await;
+        let backend_error = |e: anyhow::Error| (LdapResultCode::Other, format!("{:#}", e));
+        let ous = self.get_user_ous().await.map_err(backend_error)?;
+        let users = self
+            .backend_handler
+            .list_users(Some(UserRequestFilter::UserId(user_id)))
+            .await
+            .map_err(|e| backend_error(e.into()))?;
+        let entry = self
+            .make_user_entries(users, UserSubtree::All, &ous, &attributes)
+            .await
+            .map_err(|_| cannot_read())?
+            .into_iter()
+            .find_map(|op| match op {
+                LdapOp::SearchResultEntry(entry) => Some(entry),
+                _ => None,
+            })
+            .ok_or_else(cannot_read)?;
+        let value = encode_search_result_entry(&entry)
+            .map_err(|e| (LdapResultCode::Other, format!("{:#}", e)))?
+            .encode();
+        Ok(RawControl {
+            oid: control.oid.clone(),
+            criticality: false,
+            value: Some(value),
+        })
+    }
+
+    /// With a pre-read control, reads the entry before it is changed. If the control is critical
+    /// and the entry can't be read, the operation must fail.
+    async fn do_pre_read(
+        &mut self,
+        dn: &str,
+        controls: &[RawControl],
+    ) -> std::result::Result<Option<RawControl>, (LdapResultCode, String)> {
+        let control = match controls.iter().find(|c| c.oid == PRE_READ_OID) {
+            Some(control) => control,
+            None => return Ok(None),
+        };
+        match self.read_entry_control(dn, control).await {
+            Ok(control) => Ok(Some(control)),
+            Err(error) if control.criticality => Err(error),
+            Err((_, message)) => {
+                debug!("Ignoring the pre-read control: {}", message);
+                Ok(None)
+            }
+        }
+    }
+
+    /// With a post-read control, reads the entry after a successful change. The change is done
+    /// anyway, so a failure only leaves the control out of the response.
+    async fn do_post_read(&mut self, dn: &str, controls: &[RawControl]) -> Option<RawControl> {
+        let control = controls.iter().find(|c| c.oid == POST_READ_OID)?;
+        match self.read_entry_control(dn, control).await {
+            Ok(control) => Some(control),
+            Err((_, message)) => {
+                warn!("Could not honor the post-read control: {}", message);
+                None
+            }
+        }
+    }
+
+    /// The DN of the entry after a successful modify DN request.
+    async fn get_renamed_dn(&self, request: &ModifyDnRequest) -> String {
+        match parse_distinguished_name(&request.new_rdn).as_deref() {
+            Ok([(_, value)]) => self.get_user_dn(&UserId::new(value)).await,
+            _ => request.dn.clone(),
+        }
+    }
+
+    /// Whether the user entry matches the filter of an assertion control (RFC 4528).
+    async fn check_assertion(&self, user_id: &UserId, control: &RawControl) -> Result<bool> {
+        let filter = parse_filter(BerElement::parse_complete(
+            control.value.as_deref().context("Missing assertion")?,
+        )?)?;
+        let filter = resolve_matching_rules(&filter, self.options.ad_compatibility)
+            .map_err(anyhow::Error::msg)?;
+        let filter = UserRequestFilter::And(vec![
+            self.convert_user_filter(&filter)?,
+            UserRequestFilter::UserId(user_id.clone()),
+        ]);
+        Ok(!self
+            .backend_handler
+            .list_users(Some(filter))
+            .await?
+            .is_empty())
+    }
+
+    /// Handles a modify DN request, to change the user ID of a user: the RDN must be the RDN
+    /// attribute of the users (or "uid"), and the entry stays where it is. Users can rename themselves, admins can rename anybody.
+    pub async fn do_modify_dn(&mut self, request: &ModifyDnRequest) -> (LdapResultCode, String) {
+        debug!(
+            r#"Received modify DN request for "{}" from {}"#,
+            &request.dn,
+            self.peer()
+        );
+        if self.is_anonymous() {
+            return (
+                LdapResultCode::InsufficentAccessRights,
+                "Anonymous sessions cannot rename entries".to_string(),
+            );
+        }
+        if self.is_readonly_account() {
+            return (
+                LdapResultCode::InsufficentAccessRights,
+                "The read-only account cannot rename entries".to_string(),
+            );
+        }
+        if self.options.user_rdn_attribute == UserRdnAttribute::Mail {
+            return (
+                LdapResultCode::UnwillingToPerform,
+                "The users are named after their email, which can't be changed this way"
+                    .to_string(),
+            );
+        }
+        self.resolve_user_emails([request.dn.as_str()]).await;
+        let user_id = match self.get_user_id_from_dn(&request.dn) {
+            Ok(user_id) => user_id,
+            Err(e) => {
+                return (
+                    LdapResultCode::UnwillingToPerform,
+                    format!("Only the users can be renamed: {:#}", e),
+                )
+            }
+        };
+        let rdn_attribute = self.options.user_rdn_attribute.name();
+        let new_user_id = match parse_distinguished_name(&request.new_rdn).as_deref() {
+            Ok([(name, value)])
+                if (name == rdn_attribute || name == "uid") && !value.is_empty() =>
+            {
+                UserId::new(value)
+            }
+            _ => {
+                return (
+                    LdapResultCode::NamingViolation,
+                    format!(
+                        r#"Invalid new RDN: "{}", expected: "{}=username""#,
+                        &request.new_rdn, rdn_attribute
+                    ),
+                )
+            }
+        };
+        if let Some(new_superior) = &request.new_superior {
+            let is_same_parent = match (
+                parse_distinguished_name(&self.to_canonical_dn(new_superior)),
+                parse_distinguished_name(&self.to_canonical_dn(&request.dn)),
+            ) {
+                (Ok(new_parent), Ok(dn)) => {
+                    new_parent.len() + 1 == dn.len() && is_subtree(&dn[1..], &new_parent)
+                }
+                _ => false,
+            };
+            if !is_same_parent {
+                return (
+                    LdapResultCode::UnwillingToPerform,
+                    "The users can't be moved".to_string(),
+                );
+            }
+        }
+        if user_id != self.user_id && !self.is_admin().await {
+            warn!(
+                r#""{}" is not allowed to rename "{}""#,
+                &self.dn.0, &request.dn
+            );
+            return (
+                LdapResultCode::InsufficentAccessRights,
+                "Only admins can rename other users".to_string(),
+            );
+        }
+        if user_id == self.ldap_user_id {
+            return (
+                LdapResultCode::UnwillingToPerform,
+                "The admin user of the configuration can't be renamed".to_string(),
+            );
+        }
+        if let Err(e) = self.backend_handler.get_user_details(&user_id).await {
+            debug!(r#"Could not get the user "{}": {:#}"#, &user_id, e);
+            return (LdapResultCode::NoSuchObject, "".to_string());
+        }
+        if new_user_id == user_id {
+            return (LdapResultCode::Success, "".to_string());
+        }
+        if self
+            .backend_handler
+            .get_user_details(&new_user_id)
+            .await
+            .is_ok()
+        {
+            return (
+                LdapResultCode::EntryAlreadyExists,
+                format!(r#"The user "{}" already exists"#, &new_user_id),
+            );
+        }
+        if let Err(e) = self
+            .backend_handler
+            .rename_user(&user_id, &new_user_id)
+            .await
+        {
+            return (
+                LdapResultCode::Other,
+                format!(r#"Error while renaming "{}": {:#}"#, &request.dn, e),
+            );
+        }
+        info!(
+            r#"User "{}" renamed to "{}" by "{}""#,
+            &user_id, &new_user_id, &self.dn.0
+        );
+        if user_id == self.user_id {
+            // The session follows the renamed user: the old DN doesn't exist anymore.
+            let dn = self.get_user_dn(&new_user_id).await;
+            self.dn = LdapDn::normalized(&dn).unwrap_or(LdapDn(dn));
+            self.user_id = new_user_id;
+        }
+        (LdapResultCode::Success, "".to_string())
+    }
+
+    /// Handles an add request: only the admins can create users, directly under "ou=people". The
+    /// user ID is the uid attribute, or the RDN. The password, if any, goes through the OPAQUE
+    /// registration, as for the password changes.
+    pub async fn do_add(&mut self, request: &AddRequest) -> (LdapResultCode, String) {
+        debug!(
+            r#"Received add request for "{}" from {}"#,
+            &request.dn,
+            self.peer()
+        );
+        if self.is_anonymous() {
+            return (
+                LdapResultCode::InsufficentAccessRights,
+                "Anonymous sessions cannot add entries".to_string(),
+            );
+        }
+        if self.is_readonly_account() {
+            return (
+                LdapResultCode::InsufficentAccessRights,
+                "The read-only account cannot add entries".to_string(),
+            );
+        }
+        if !self.is_admin().await {
+            warn!(
+                r#""{}" is not allowed to add "{}""#,
+                &self.dn.0, &request.dn
+            );
+            return (
+                LdapResultCode::InsufficentAccessRights,
+                "Only admins can add entries".to_string(),
+            );
+        }
+        let dn = self.to_canonical_dn(&request.dn);
+        let rdn_value = match parse_distinguished_name(&dn) {
+            Ok(parts)
+                if parts.len() == self.base_dn.len() + 2
+                    && is_subtree(&parts, &self.base_dn)
+                    && is_rdn(&parts[1], &self.people_ou) =>
+            {
+                match get_user_rdn_value_from_distinguished_name(
+                    &dn,
+                    &self.base_dn,
+                    &self.people_ou,
+                    &self.people_dn_str,
+                    self.options.user_rdn_attribute,
+                ) {
+                    Ok(value) => value,
+                    Err(e) => return (LdapResultCode::NamingViolation, format!("{:#}", e)),
+                }
+            }
+            Ok(_) => {
+                return (
+                    LdapResultCode::UnwillingToPerform,
+                    format!(
+                        r#"Only the users can be added, under "{}""#,
+                        &self.people_dn_str
+                    ),
+                )
+            }
+            Err(_) => {
+                return (
+                    LdapResultCode::InvalidDNSyntax,
+                    format!(r#"Could not parse DN: "{}""#, &request.dn),
+                )
+            }
+        };
+        let attributes = || {
+            request
+                .attributes
+                .iter()
+                .map(|(attribute, values)| (attribute.as_str(), values.as_slice()))
+        };
+        if let Err(error) = check_value_syntax(attributes())
+            .and_then(|()| check_value_lengths(attributes(), &self.options))
+        {
+            return error;
+        }
+        let mut user = CreateUserRequest::default();
+        let mut user_id = None;
+        let mut password = None;
+        for (attribute, values) in &request.attributes {
+            let name = attribute.to_lowercase();
+            // The object classes are the same for all the users.
+            if name == "objectclass" {
+                continue;
+            }
+            let value = match values.as_slice() {
+                // Already checked to be valid UTF-8.
+                [value] => String::from_utf8_lossy(value).into_owned(),
+                _ => {
+                    return (
+                        LdapResultCode::ConstraintViolation,
+                        format!(r#"Expected exactly one value for "{}""#, attribute),
+                    )
+                }
+            };
+            match name.as_str() {
+                "uid" => user_id = Some(UserId::new(&value)),
+                "mail" => user.email = value,
+                "cn" | "displayname" => user.display_name = Some(value),
+                "givenname" => user.first_name = Some(value),
+                "sn" => user.last_name = Some(value),
+                "userpassword" => password = Some(value),
+                _ => {
+                    return (
+                        LdapResultCode::UnwillingToPerform,
+                        format!(r#"The attribute "{}" can't be set"#, attribute),
+                    )
+                }
+            }
+        }
+        user.user_id = match user_id {
+            Some(user_id) => user_id,
+            None if self.options.user_rdn_attribute != UserRdnAttribute::Mail => {
+                UserId::new(&rdn_value)
+            }
+            None => {
+                return (
+                    LdapResultCode::ObjectClassViolation,
+                    "Missing uid attribute".to_string(),
+                )
+            }
+        };
+        if user.email.is_empty() {
+            return (
+                LdapResultCode::ObjectClassViolation,
+                "Missing mail attribute".to_string(),
+            );
+        }
+        if self
+            .backend_handler
+            .get_user_details(&user.user_id)
+            .await
+            .is_ok()
+        {
+            return (
+                LdapResultCode::EntryAlreadyExists,
+                format!(r#"The user "{}" already exists"#, &user.user_id),
+            );
+        }
+        let user_id = user.user_id.clone();
+        if let Err(e) = self.backend_handler.create_user(user).await {
+            return (
+                LdapResultCode::Other,
+                format!(r#"Error while creating "{}": {:#}"#, &request.dn, e),
+            );
+        }
+        info!(r#"User "{}" created by "{}""#, &user_id, &self.dn.0);
+        if let Some(password) = password {
+            if let Err(e) = self.change_password(&user_id, &password).await {
+                return (
+                    LdapResultCode::Other,
+                    format!(
+                        r#"The user "{}" was created, but not its password: {:#}"#,
+                        &user_id, e
+                    ),
+                );
+            }
+        }
+        (LdapResultCode::Success, "".to_string())
+    }
+
+    /// Deletes a user or a group. Only the admins can delete entries, and neither the containers
+    /// nor the admin account and group can be deleted. The memberships of a deleted user or
+    /// group are removed with it.
+    pub async fn do_delete(&mut self, request: &DeleteRequest) -> (LdapResultCode, String) {
+        debug!(
+            r#"Received delete request for "{}" from {}"#,
+            &request.dn,
+            self.peer()
+        );
+        if self.is_anonymous() {
+            return (
+                LdapResultCode::InsufficentAccessRights,
+                "Anonymous sessions cannot delete entries".to_string(),
+            );
+        }
+        if self.is_readonly_account() {
+            return (
+                LdapResultCode::InsufficentAccessRights,
+                "The read-only account cannot delete entries".to_string(),
+            );
+        }
+        if !self.is_admin().await {
+            warn!(
+                r#""{}" is not allowed to delete "{}""#,
+                &self.dn.0, &request.dn
+            );
+            return (
+                LdapResultCode::InsufficentAccessRights,
+                "Only admins can delete entries".to_string(),
+            );
+        }
+        let dn = self.to_canonical_dn(&request.dn);
+        match parse_distinguished_name(&dn) {
+            Ok(parts) if is_subtree(&parts, &self.base_dn) && self.is_container_dn(&parts) => {
+                return (
+                    LdapResultCode::NotAllowedOnNonLeaf,
+                    format!(r#"Cannot delete the container "{}""#, &request.dn),
+                )
+            }
+            Ok(_) => (),
+            Err(_) => {
+                return (
+                    LdapResultCode::InvalidDNSyntax,
+                    format!(r#"Could not parse DN: "{}""#, &request.dn),
+                )
+            }
+        }
+        self.resolve_user_emails(std::iter::once(request.dn.as_str()))
+            .await;
+        let result = if let Ok(user_id) = self.get_user_id_from_dn(&request.dn) {
+            self.delete_user_entry(&request.dn, user_id).await
+        } else if let Ok(group_name) = self.get_group_id_from_dn(&request.dn) {
+            self.delete_group_entry(&request.dn, group_name).await
+        } else {
+            Err((
+                LdapResultCode::NoSuchObject,
+                format!(r#"No such entry: "{}""#, &request.dn),
+            ))
+        };
+        match result {
+            Ok(()) => {
+                if let Some(group_cache) = &self.options.group_cache {
+                    group_cache.invalidate();
+                }
+                info!(r#""{}" deleted by "{}""#, &request.dn, &self.dn.0);
+                (LdapResultCode::Success, "".to_string())
+            }
+            Err(error) => error,
+        }
+    }
+
+    async fn delete_user_entry(
+        &self,
+        dn: &str,
+        user_id: UserId,
+    ) -> std::result::Result<(), (LdapResultCode, String)> {
+        if user_id == self.ldap_user_id {
+            return Err((
+                LdapResultCode::UnwillingToPerform,
+                "The admin account cannot be deleted".to_string(),
+            ));
+        }
+        if self
+            .backend_handler
+            .get_user_details(&user_id)
+            .await
+            .is_err()
+        {
+            return Err((
+                LdapResultCode::NoSuchObject,
+                format!(r#"No such user: "{}""#, dn),
+            ));
+        }
+        self.backend_handler
+            .delete_user(&user_id)
+            .await
+            .map_err(|e| {
+                (
+                    LdapResultCode::Other,
+                    format!(r#"Error while deleting "{}": {:#}"#, dn, e),
+                )
+            })
+    }
+
+    async fn delete_group_entry(
+        &self,
+        dn: &str,
+        group_name: String,
+    ) -> std::result::Result<(), (LdapResultCode, String)> {
+        let is_all_users_group = matches!(
+            &self.options.all_users_group,
+            Some(all_users) if all_users.eq_ignore_ascii_case(&group_name)
+        );
+        if is_all_users_group || group_name == ADMIN_GROUP_NAME {
+            return Err((
+                LdapResultCode::UnwillingToPerform,
+                format!(r#"The group "{}" cannot be deleted"#, group_name),
+            ));
+        }
+        let group = self
+            .backend_handler
+            .list_groups(Some(GroupRequestFilter::DisplayName(group_name.clone())))
+            .await
+            .map_err(|e| {
+                (
+                    LdapResultCode::Other,
+                    format!(r#"Error while reading the group "{}": {:#}"#, group_name, e),
+                )
+            })?
+            .into_iter()
+            .next()
+            .ok_or_else(|| {
+                (
+                    LdapResultCode::NoSuchObject,
+                    format!(r#"No such group: "{}""#, dn),
+                )
+            })?;
+        self.backend_handler
+            .delete_group(group.id)
+            .await
+            .map_err(|e| {
+                (
+                    LdapResultCode::Other,
+                    format!(r#"Error while deleting "{}": {:#}"#, dn, e),
+                )
+            })
+    }
+
+    async fn do_password_modification(
+        &mut self,
+        request: &LdapPasswordModifyRequest,
+    ) -> Vec<LdapOp> {
+        if self.is_anonymous() {
+            return vec![make_extended_response(
+                LdapResultCode::InsufficentAccessRights,
+                "Anonymous sessions cannot change passwords".to_string(),
+            )];
+        }
+        if self.is_readonly_account() {
+            return vec![make_extended_response(
+                LdapResultCode::InsufficentAccessRights,
+                "The read-only account cannot change passwords".to_string(),
+            )];
+        }
+        self.resolve_user_emails(request.user_identity.as_deref())
+            .await;
+        match (&request.user_identity, &request.new_password) {
+            (Some(user), Some(password)) => match self.get_user_id_from_dn(user) {
+                Ok(uid) if uid != self.user_id && !self.is_admin().await => {
+                    warn!(
+                        r#""{}" is not allowed to change the password of "{}""#,
+                        &self.dn.0, user
+                    );
+                    vec![make_extended_response(
+                        LdapResultCode::InsufficentAccessRights,
+                        "Only admins can change the password of other users".to_string(),
+                    )]
+                }
+                Ok(uid) => {
+                    if let Err(e) = self.change_password(&uid, password).await {
+                        vec![make_extended_response(
+                            LdapResultCode::Other,
+                            format!("Error while changing the password: {:#?}", e),
+                        )]
+                    } else {
+                        vec![make_extended_response(
+                            LdapResultCode::Success,
+                            "".to_string(),
+                        )]
+                    }
+                }
+                Err(e) => vec![make_extended_response(
+                    LdapResultCode::InvalidDNSyntax,
+                    format!("Invalid username: {:#?}", e),
+                )],
+            },
+            _ => vec![make_extended_response(
+                LdapResultCode::ConstraintViolation,
+                "Missing either user_id or password".to_string(),
+            )],
+        }
+    }
+
+    fn do_start_tls(&mut self) -> Vec<LdapOp> {
+        if self.tls_active {
+            return vec![make_extended_response(
+                LdapResultCode::OperationsError,
+                "TLS is already established on this connection".to_string(),
+            )];
+        }
+        if !self.options.start_tls_available {
+            return vec![make_extended_response(
+                LdapResultCode::Unavailable,
+                "StartTLS is not available on this server".to_string(),
+            )];
+        }
+        if !self.is_anonymous() {
+            return vec![make_extended_response(
+                LdapResultCode::OperationsError,
+                "StartTLS is not allowed after a bind".to_string(),
+            )];
+        }
+        debug!("Upgrading the connection to TLS");
+        self.tls_active = true;
+        self.start_tls_pending = true;
+        vec![LdapOp::ExtendedResponse(LdapExtendedResponse {
+            res: LdapResult {
+                code: LdapResultCode::Success,
+                matcheddn: "".to_string(),
+                message: "".to_string(),
+                referral: vec![],
+            },
+            name: Some(START_TLS_OID.to_string()),
+            value: None,
+        })]
+    }
+
+    fn do_whoami(&self) -> Vec<LdapOp> {
+        // The authorization identity is empty for anonymous sessions (RFC 4532).
+        let authz_id = if self.is_anonymous() {
+            "".to_string()
+        } else {
+            format!("dn:{}", self.dn.0)
+        };
+        vec![LdapOp::ExtendedResponse(LdapExtendedResponse {
+            res: LdapResult {
+                code: LdapResultCode::Success,
+                matcheddn: "".to_string(),
+                message: "".to_string(),
+                referral: vec![],
+            },
+            name: None,
+            value: Some(authz_id.into_bytes()),
+        })]
+    }
+
+    /// The subschema entry, with the object classes and the hidden attributes of the options.
+    fn schema_entry(&self, dn: &str) -> LdapOp {
+        schema_response(
+            dn,
+            &self.options.user_object_classes,
+            &self.options.hidden_attributes,
+        )
+    }
+
+    /// The effective schema as LDIF, to compare it with the expectations of a client. Readable
+    /// by everyone, like the subschema entry.
+    fn do_export_schema(&self) -> Vec<LdapOp> {
+        let ldif = match self.schema_entry(SCHEMA_DN) {
+            LdapOp::SearchResultEntry(entry) => to_ldif(&entry),
+            _ => unreachable!(),
+        };
+        vec![LdapOp::ExtendedResponse(LdapExtendedResponse {
+            res: LdapResult {
+                code: LdapResultCode::Success,
+                matcheddn: "".to_string(),
+                message: "".to_string(),
+                referral: vec![],
+            },
+            name: Some(SCHEMA_LDIF_OID.to_string()),
+            value: Some(ldif.into_bytes()),
+        })]
+    }
+
+    /// Describes the features of the server as a JSON object, for the tools setting up the
+    /// clients. Only for the bound sessions.
+    fn do_get_capabilities(&self) -> Vec<LdapOp> {
+        if self.is_anonymous() {
+            return vec![make_extended_response(
+                LdapResultCode::ProtocolError,
+                "The capabilities are only available to the bound sessions".to_string(),
+            )];
+        }
+        let capabilities = serde_json::json!({
+            "vendor": "lldap",
+            "version": env!("CARGO_PKG_VERSION"),
+            "pagedResults": true,
+            "sort": true,
+            "startTls": self.options.start_tls_available,
+            "passwordModify": true,
+            "memberOfFilter": true,
+            "proxiedAuthorization": self.options.proxy_group.is_some(),
+            "userRdnAttribute": self.options.user_rdn_attribute.name(),
+        });
+        vec![LdapOp::ExtendedResponse(LdapExtendedResponse {
+            res: LdapResult {
+                code: LdapResultCode::Success,
+                matcheddn: "".to_string(),
+                message: "".to_string(),
+                referral: vec![],
+            },
+            name: Some(CAPABILITIES_OID.to_string()),
+            value: Some(capabilities.to_string().into_bytes()),
+        })]
+    }
+
+    /// The last operations of the operation log as a JSON array, the oldest first. The value of
+    /// the request, if any, is the number of operations to return. Only for the admins: the
+    /// log shows the operations of all the users.
+    async fn do_get_operation_log(&self, request: &LdapExtendedRequest) -> Vec<LdapOp> {
+        let operation_log = match &self.options.operation_log {
+            Some(operation_log) => operation_log.clone(),
+            None => {
+                return vec![make_extended_response(
+                    LdapResultCode::UnwillingToPerform,
+                    "The operation log is disabled".to_string(),
+                )]
+            }
+        };
+        if self.is_anonymous() || !self.is_admin().await {
+            return vec![make_extended_response(
+                LdapResultCode::InsufficentAccessRights,
+                "Only the admins can read the operation log".to_string(),
+            )];
+        }
+        let limit = match request.value.as_deref().map(|value| {
+            std::str::from_utf8(value)
+                .ok()
+                .and_then(|value| value.trim().parse::<usize>().ok())
+        }) {
+            None => None,
+            Some(Some(limit)) => Some(limit),
+            Some(None) => {
+                return vec![make_extended_response(
+                    LdapResultCode::ProtocolError,
+                    "The value must be a number of operations".to_string(),
+                )]
+            }
+        };
+        let records = serde_json::to_string(&operation_log.recent(limit))
+            .expect("The operations can always be serialized");
+        vec![LdapOp::ExtendedResponse(LdapExtendedResponse {
+            res: LdapResult {
+                code: LdapResultCode::Success,
+                matcheddn: "".to_string(),
+                message: "".to_string(),
+                referral: vec![],
+            },
+            name: Some(OPERATION_LOG_OID.to_string()),
+            value: Some(records.into_bytes()),
+        })]
+    }
+
+    /// Handles the Cancel extended operation (RFC 3909). The operations of a connection are
+    /// handled one at a time, so the operation to cancel is never in progress when the request
+    /// is read: it is either already answered or unknown, unless it is the persistent search.
+    fn do_cancel(&mut self, request: &LdapExtendedRequest) -> LdapResponse {
+        let parse_cancel_id = |value: &[u8]| -> Result<i64> {
+            BerElement::parse_complete(value)?
+                .expect_tag(TAG_SEQUENCE)?
+                .children()?
+                .first()
+                .ok_or_else(|| anyhow::anyhow!("Missing cancelID"))?
+                .as_integer()
+        };
+        let cancel_id = match request.value.as_deref().map(parse_cancel_id) {
+            Some(Ok(cancel_id)) => cancel_id,
+            _ => {
+                return make_extended_response(
+                    LdapResultCode::ProtocolError,
+                    "Invalid cancel request".to_string(),
+                )
+                .into()
+            }
+        };
+        if let Some(msgid) = self
+            .persistent_search_id()
+            .filter(|msgid| i64::from(*msgid) == cancel_id)
+        {
+            debug!("Canceled the persistent search {}", msgid);
+            self.persistent_search = None;
+            self.canceled_search = Some(msgid);
+            return make_extended_response(LdapResultCode::Success, "".to_string()).into();
+        }
+        debug!("Nothing to cancel for the message {}", cancel_id);
+        LdapResponse {
+            op: LdapResponseOp::WithResultCode(
+                Box::new(
+                    make_extended_response(
+                        LdapResultCode::Other,
+                        format!("The operation {} is not in progress", cancel_id),
+                    )
+                    .into(),
+                ),
+                NO_SUCH_OPERATION,
+            ),
+            controls: vec![],
+        }
+    }
+
+    /// A search with the sync request control (RFC 4533): the entries come with their entryUUID
+    /// in the sync state control, and the final result with the cookie to resume from. There is
+    /// no history of the changes: unless the cookie is the current one, the whole content is sent
+    /// again. In refreshAndPersist mode, the search then goes on with the changes, see
+    /// `get_persistent_search_changes`.
+    async fn do_sync_search(
+        &mut self,
+        request: &LdapSearchRequest,
+        control: &RawControl,
+    ) -> Vec<LdapResponse> {
+        let sync_request = match parse_sync_request_control(control) {
+            Ok(sync_request) => sync_request,
+            Err(e) => {
+                return vec![make_search_error(
+                    LdapResultCode::ProtocolError,
+                    format!("Invalid sync request control: {:#}", e),
+                )
+                .into()]
+            }
+        };
+        if sync_request.persist && self.persistent_search.is_some() {
+            return vec![make_search_error(
+                LdapResultCode::Busy,
+                "There is already a persistent search on this connection".to_string(),
+            )
+            .into()];
+        }
+        let changes = self.backend_handler.subscribe_to_changes();
+        // Read before the search, so that the changes made during the search are not missed.
+        let cookie = make_sync_cookie(*changes.borrow());
+        let (entries, done) = self.get_sync_content(request).await;
+        if !matches!(
+            &done,
+            LdapOp::SearchResultDone(LdapResult {
+                code: LdapResultCode::Success,
+                ..
+            })
+        ) {
+            return apply_types_only(
+                request,
+                entries
+                    .into_iter()
+                    .map(|(_, entry)| LdapOp::SearchResultEntry(entry))
+                    .chain(std::iter::once(done))
+                    .collect(),
+            )
+            .into_iter()
+            .map(LdapResponse::from)
+            .collect();
+        }
+        // Nothing changed since the cookie: no entry is sent, and none is deleted.
+        let up_to_date =
+            !sync_request.reload_hint && sync_request.cookie.as_deref() == Some(cookie.as_slice());
+        let mut responses = Vec::new();
+        if !up_to_date {
+            responses.extend(entries.iter().map(|(uuid, entry)| {
+                make_sync_entry(request, entry.clone(), SYNC_STATE_ADD, uuid, None)
+            }));
+        }
+        if sync_request.persist {
+            debug!("Starting the persistent search {}", self.msgid);
+            responses.push(make_sync_refresh_done(&cookie, up_to_date));
+            self.persistent_search = Some(PersistentSearch {
+                msgid: self.msgid,
+                request: request.clone(),
+                entries: entries.into_iter().collect(),
+                changes,
+            });
+        } else {
+            responses.push(LdapResponse {
+                op: done.into(),
+                controls: vec![make_sync_done_control(&cookie, up_to_date)],
+            });
+        }
+        responses
+    }
+
+    /// The entries of a synchronized search by entryUUID, with all their values, and the final
+    /// result. The entryUUID is only returned if it was asked for.
+    async fn get_sync_content(
+        &mut self,
+        request: &LdapSearchRequest,
+    ) -> (Vec<(Uuid, LdapSearchResultEntry)>, LdapOp) {
+        let returns_uuid = request
+            .attrs
+            .iter()
+            .any(|a| a == "+" || a.eq_ignore_ascii_case("entryuuid"));
+        let mut attrs = if request.attrs.is_empty() {
+            vec!["*".to_string()]
+        } else {
+            request.attrs.clone()
+        };
+        attrs.push("entryUUID".to_string());
+        let mut results = self
+            .do_search(&LdapSearchRequest {
+                attrs,
+                typesonly: false,
+                ..request.clone()
+            })
+            .await;
+        let done = results.pop().unwrap_or_else(make_search_success);
+        let entries = results
+            .into_iter()
+            .filter_map(|op| match op {
+                LdapOp::SearchResultEntry(mut entry) => {
+                    let uuid = get_sync_entry_uuid(&entry);
+                    if !returns_uuid {
+                        entry
+                            .attributes
+                            .retain(|a| !a.atype.eq_ignore_ascii_case("entryuuid"));
+                    }
+                    Some((uuid, entry))
+                }
+                _ => None,
+            })
+            .collect();
+        (entries, done)
+    }
+
+    /// The message ID of the persistent search of the connection, if any.
+    pub fn persistent_search_id(&self) -> Option<i32> {
+        self.persistent_search.as_ref().map(|search| search.msgid)
+    }
+
+    /// Returns (once) the final response of the persistent search canceled by the last message,
+    /// with its message ID: it must be sent before the response to the cancel request.
+    pub fn take_canceled_search(&mut self) -> Option<(i32, LdapResponse)> {
+        self.canceled_search.take().map(|msgid| {
+            (
+                msgid,
+                LdapResponse {
+                    op: LdapResponseOp::WithResultCode(
+                        Box::new(
+                            make_search_error(LdapResultCode::Other, "Canceled".to_string()).into(),
+                        ),
+                        CANCELED,
+                    ),
+                    controls: vec![],
+                },
+            )
+        })
+    }
+
+    /// Resolves when the backend changes, if there is a persistent search. The changes are then
+    /// read with `get_persistent_search_changes`.
+    pub async fn wait_for_changes(&mut self) {
+        if let Some(search) = &mut self.persistent_search {
+            if search.changes.changed().await.is_ok() {
+                return;
+            }
+        }
+        // The backend doesn't notify its changes.
+        std::future::pending::<()>().await
+    }
+
+    /// Runs the persistent search again, and returns the entries that were added, modified or
+    /// deleted since its last results, with the new cookie. If the search fails, e.g. because
+    /// the bound user can't read its base anymore, it ends with the error.
+    pub async fn get_persistent_search_changes(&mut self) -> Vec<LdapResponse> {
+        let mut search = match self.persistent_search.take() {
+            Some(search) => search,
+            None => return vec![],
+        };
+        let cookie = make_sync_cookie(*search.changes.borrow());
+        let (entries, done) = self.get_sync_content(&search.request).await;
+        if !matches!(
+            &done,
+            LdapOp::SearchResultDone(LdapResult {
+                code: LdapResultCode::Success,
+                ..
+            })
+        ) {
+            debug!("The persistent search {} failed: {:?}", search.msgid, &done);
+            return vec![done.into()];
+        }
+        let mut responses = Vec::new();
+        let mut previous = std::mem::take(&mut search.entries);
+        for (uuid, entry) in &entries {
+            let state = match previous.remove(uuid) {
+                Some(previous_entry) if previous_entry == *entry => continue,
+                Some(_) => SYNC_STATE_MODIFY,
+                None => SYNC_STATE_ADD,
+            };
+            responses.push(make_sync_entry(
+                &search.request,
+                entry.clone(),
+                state,
+                uuid,
+                Some(&cookie),
+            ));
+        }
+        let mut deleted = previous.into_iter().collect::<Vec<_>>();
+        deleted.sort_by(|(_, a), (_, b)| a.dn.cmp(&b.dn));
+        for (uuid, entry) in deleted {
+            // The deleted entries only have their DN.
+            let entry = LdapSearchResultEntry {
+                dn: entry.dn,
+                attributes: vec![],
+            };
+            responses.push(make_sync_entry(
+                &search.request,
+                entry,
+                SYNC_STATE_DELETE,
+                &uuid,
+                Some(&cookie),
+            ));
+        }
+        if responses.is_empty() {
+            responses.push(make_sync_new_cookie(&cookie));
+        }
+        search.entries = entries.into_iter().collect();
+        self.persistent_search = Some(search);
+        responses
+    }
+
+    async fn do_extended_request(&mut self, request: &LdapExtendedRequest) -> Vec<LdapOp> {
+        if request.name == START_TLS_OID {
+            return self.do_start_tls();
+        }
+        if request.name == WHOAMI_OID {
+            return self.do_whoami();
+        }
+        if request.name == CAPABILITIES_OID {
+            return self.do_get_capabilities();
+        }
+        if request.name == SCHEMA_LDIF_OID {
+            return self.do_export_schema();
+        }
+        if request.name == OPERATION_LOG_OID {
+            return self.do_get_operation_log(request).await;
+        }
+        match LdapPasswordModifyRequest::try_from(request) {
+            Ok(password_request) => self.do_password_modification(&password_request).await,
+            Err(_) => vec![make_extended_response(
+                LdapResultCode::UnwillingToPerform,
+                format!("Unsupported extended operation: {}", &request.name),
+            )],
+        }
+    }
+
+    /// Same as `do_uncached_search`, with the results from the search cache if there is one. The
+    /// root DSE is never cached, for the health checks.
+    pub async fn do_search(&mut self, request: &LdapSearchRequest) -> Vec<LdapOp> {
+        let search_cache = match &self.options.search_cache {
+            Some(search_cache) if self.entry_stream.is_none() && !request.base.is_empty() => {
+                search_cache.clone()
+            }
+            _ => return self.do_uncached_search(request).await,
+        };
+        let key = SearchCacheKey {
+            bound_dn: self.dn.0.clone(),
+            base: request.base.to_ascii_lowercase(),
+            scope: format!("{:?}", request.scope),
+            filter: filter_to_string(&request.filter),
+            attributes: request.attrs.clone(),
+            types_only: request.typesonly,
+            size_limit: request.sizelimit,
+        };
+        // Read before the search: a change during the search makes its results stale.
+        let change_id = *self.backend_handler.subscribe_to_changes().borrow();
+        if let Some(results) = search_cache.get(&key, change_id) {
+            debug!("Search results found in the cache");
+            return results;
+        }
+        let results = self.do_uncached_search(request).await;
+        search_cache.insert(key, &results, change_id);
+        results
+    }
+
+    pub async fn do_uncached_search(&mut self, request: &LdapSearchRequest) -> Vec<LdapOp> {
+        let admin = self.can_read_all();
+        if request.base.is_empty() && request.scope == LdapSearchScope::Base {
+            debug!("Received rootDSE request");
+            if self.is_anonymous() && is_health_check_filter(&request.filter) {
+                if let Err(e) = self.check_backend().await {
+                    warn!("Health check failed: {:#}", e);
+                    return vec![make_search_error(
+                        LdapResultCode::Unavailable,
+                        "The server is unavailable, try again later".to_string(),
+                    )];
+                }
+            }
+            let mut root_dse = root_dse_response(&self.base_dn_str, &self.options);
+            if let LdapOp::SearchResultEntry(entry) = &mut root_dse {
+                if self.can_read_all() {
+                    entry
+                        .attributes
+                        .extend(server_info_attributes(&self.options));
+                }
+            }
+            return self.restrict_anonymous_results(apply_types_only(
+                request,
+                vec![root_dse, make_search_success()],
+            ));
+        }
+        if request.scope == LdapSearchScope::Base && is_schema_dn(&request.base, &self.base_dn_str)
+        {
+            debug!("Received schema request");
+            return self.restrict_anonymous_results(apply_types_only(
+                request,
+                vec![self.schema_entry(&request.base), make_search_success()],
+            ));
+        }
+        debug!(
+            "Received search request from {}: {:?}",
+            self.peer(),
+            &request
+        );
+        if self.is_anonymous()
+            && (self.options.require_auth_for_search
+                || self.options.anonymous_readable_attributes.is_none())
+        {
+            return vec![make_search_error(
+                LdapResultCode::InsufficentAccessRights,
+                "Anonymous sessions can only read the root DSE and the schema".to_string(),
+            )];
+        }
+        let filter = match resolve_matching_rules(&request.filter, self.options.ad_compatibility) {
+            Ok(filter) => resolve_filter_aliases(&filter, self.options.ad_compatibility),
+            Err(e) => return vec![make_search_error(LdapResultCode::InappropriateMatching, e)],
+        };
+        if let Some(max_complexity) = self.options.max_filter_complexity {
+            let complexity = get_filter_complexity(&filter, 0);
+            if complexity > max_complexity {
+                return vec![make_search_error(
+                    LdapResultCode::UnwillingToPerform,
+                    format!(
+                        "The filter is too complex: {} for at most {}",
+                        complexity, max_complexity
+                    ),
+                )];
+            }
+        }
+        // Filtering on the hidden attributes would reveal their values.
+        if let Some(attribute) = get_filter_attributes(&filter)
+            .into_iter()
+            .find(|a| self.is_hidden_attribute(a))
+        {
+            return vec![make_search_error(
+                LdapResultCode::InsufficentAccessRights,
+                format!(r#"Cannot filter on "{}""#, attribute),
+            )];
+        }
+        if let (true, Some(allowed)) = (
+            self.is_anonymous(),
+            &self.options.anonymous_readable_attributes,
+        ) {
+            // Filtering on the other attributes would reveal their values.
+            if let Some(attribute) = get_filter_attributes(&filter)
+                .into_iter()
+                .find(|a| !allowed.iter().any(|n| n.eq_ignore_ascii_case(a)))
+            {
+                return vec![make_search_error(
+                    LdapResultCode::InsufficentAccessRights,
+                    format!(r#"Anonymous sessions cannot filter on "{}""#, attribute),
+                )];
+            }
+        }
+        let request = &LdapSearchRequest {
+            base: self.to_canonical_dn(&request.base),
+            filter,
+            ..request.clone()
+        };
+        // The base can be a user entry too.
+        self.resolve_user_emails(
+            get_filter_member_dns(&request.filter)
+                .into_iter()
+                .chain(std::iter::once(request.base.as_str())),
+        )
+        .await;
+        self.resolve_group_uuids(&request.filter).await;
+        // There are no alias entries, so dereferencing the aliases never changes the results.
+        if !matches!(request.aliases, LdapDerefAliases::Never) {
+            debug!(
+                "Ignoring derefAliases {:?}: there are no aliases",
+                request.aliases
+            );
+        }
+        let dn_parts = match parse_distinguished_name(&request.base) {
+            Ok(dn) => dn,
+            Err(_) => {
+                return vec![make_search_error(
+                    LdapResultCode::OperationsError,
+                    format!(r#"Could not parse base DN: "{}""#, request.base),
+                )]
+            }
+        };
+        if !is_subtree(&dn_parts, &self.base_dn) {
+            // Search path is not in our tree, just return an empty success.
+            warn!(
+                "The specified search tree {:?} is not under the common subtree {:?}",
+                &dn_parts, &self.base_dn
+            );
+            return vec![make_search_success()];
+        }
+        if let Some(bases) = self.get_allowed_search_bases().await {
+            if !bases.iter().any(|base| is_subtree(&dn_parts, base)) {
+                warn!(
+                    r#""{}" is not allowed to search under "{}""#,
+                    &self.dn.0, &request.base
+                );
+                return vec![make_search_error(
+                    LdapResultCode::InsufficentAccessRights,
+                    format!(r#"Not allowed to search under "{}""#, &request.base),
+                )];
+            }
+        }
+        if !admin && !self.is_anonymous() && !self.can_read_entry(&request.base).await {
+            return vec![make_search_error(
+                LdapResultCode::InsufficentAccessRights,
+                format!(r#"Not allowed to read "{}""#, &request.base),
+            )];
+        }
+        if let Some(matched_dn) = self
+            .get_missing_dn_ancestor(&dn_parts)
+            .filter(|_| !is_schema_dn(&request.base, &self.base_dn_str))
+        {
+            debug!(
+                r#"The search base "{}" does not exist, the closest entry is "{}""#,
+                &request.base, &matched_dn
+            );
+            return vec![LdapOp::SearchResultDone(LdapResult {
+                code: LdapResultCode::NoSuchObject,
+                matcheddn: matched_dn,
+                message: format!(r#"No such entry: "{}""#, &request.base),
+                referral: vec![],
+            })];
+        }
+        self.filtered_entries.set(0);
+        let mut results = Vec::new();
+        let mut timed_out = false;
+        let time_limit = self.get_time_limit(request);
+        let deadline = time_limit.map(|limit| tokio::time::Instant::now() + limit);
+        // The anonymous sessions only get here with `anonymous_readable_attributes`.
+        let user_filter = if admin || self.is_anonymous() {
+            None
+        } else {
+            Some(&self.user_id)
+        };
+        let targets = self.get_search_targets(&request.base, &dn_parts, &request.scope);
+        results.extend(self.make_container_entries(request, &targets.containers));
+        if let Some((subtree, user_id)) = &targets.users {
+            // For a user entry, the users that are not admins can only get there for their own.
+            let user_filter = user_id.as_ref().or(user_filter);
+            match run_until(
+                deadline,
+                self.get_user_list(request, &user_filter, *subtree),
+            )
+            .await
+            {
+                Some(users) => results.extend(users),
+                None => timed_out = true,
+            }
+        }
+        if let (false, Some(group_name)) = (timed_out, &targets.groups) {
+            match run_until(
+                deadline,
+                self.get_groups_list(request, &user_filter, group_name.as_deref()),
+            )
+            .await
+            {
+                Some(groups) => results.extend(groups),
+                None => timed_out = true,
+            }
+        }
+        if timed_out {
+            // The entries found so far are still sent.
+            warn!(
+                r#"Search of "{}" timed out after {:?}"#,
+                &request.base,
+                time_limit.unwrap()
+            );
+            results.push(make_search_error(
+                LdapResultCode::TimeLimitExceeded,
+                format!(
+                    "Time limit exceeded: the search took more than {} seconds",
+                    time_limit.unwrap().as_secs()
+                ),
+            ));
+        } else if results.is_empty()
+            || matches!(results[results.len() - 1], LdapOp::SearchResultEntry(_))
+        {
+            results.push(make_search_success());
+        }
+        let results = self.restrict_anonymous_results(apply_types_only(
+            request,
+            self.apply_size_limit(request, results),
+        ));
+        if self.options.verbose_result_messages {
+            self.describe_search_results(results)
+        } else {
+            results
+        }
+    }
+
+    /// What the search selects with its scope: the base entry alone for a base search, its
+    /// children for a one-level search, and both the entry and everything under it for a
+    /// subtree search. E.g. a one-level search of the people OU returns the users directly in
+    /// it and the mapped OUs, but neither the people OU itself nor the users of the mapped OUs.
+    fn get_search_targets(
+        &self,
+        base: &str,
+        dn_parts: &[(String, String)],
+        scope: &LdapSearchScope,
+    ) -> SearchTargets<'_> {
+        let root = (self.base_dn_str.clone(), self.base_dn[0].clone());
+        let people = (self.people_dn_str.clone(), self.people_ou.clone());
+        let groups = (self.groups_dn_str.clone(), self.groups_ou.clone());
+        let mut ou_names: Vec<&str> = Vec::new();
+        for (ou, _) in &self.options.user_ou_mapping {
+            if !ou_names.iter().any(|name| name.eq_ignore_ascii_case(ou)) {
+                ou_names.push(ou);
+            }
+        }
+        let make_ou = |ou: &str| {
+            (
+                format!("ou={},{}", escape_dn_value(ou), self.people_dn_str),
+                ("ou".to_string(), ou.to_string()),
+            )
+        };
+        let ous: Vec<_> = ou_names.iter().copied().map(make_ou).collect();
+        let depth = dn_parts.len() - self.base_dn.len();
+        let (containers, users, groups) = if depth == 0 {
+            match scope {
+                LdapSearchScope::Base => (vec![root], None, None),
+                LdapSearchScope::OneLevel => (vec![people, groups], None, None),
+                LdapSearchScope::Subtree => (
+                    [vec![root, people], ous, vec![groups]].concat(),
+                    Some((UserSubtree::All, None)),
+                    Some(None),
+                ),
+            }
+        } else if depth == 1 && is_rdn(&dn_parts[0], &self.people_ou) {
+            match scope {
+                LdapSearchScope::Base => (vec![people], None, None),
+                LdapSearchScope::OneLevel => (ous, Some((UserSubtree::People, None)), None),
+                LdapSearchScope::Subtree => (
+                    [vec![people], ous].concat(),
+                    Some((UserSubtree::All, None)),
+                    None,
+                ),
+            }
+        } else if let Some(ou) = self.get_user_ou_of_dn(dn_parts) {
+            let users = Some((UserSubtree::Ou(ou), None));
+            match scope {
+                LdapSearchScope::Base => (vec![make_ou(ou)], None, None),
+                LdapSearchScope::OneLevel => (vec![], users, None),
+                LdapSearchScope::Subtree => (vec![make_ou(ou)], users, None),
+            }
+        } else if depth == 1 && is_rdn(&dn_parts[0], &self.groups_ou) {
+            match scope {
+                LdapSearchScope::Base => (vec![groups], None, None),
+                LdapSearchScope::OneLevel => (vec![], None, Some(None)),
+                LdapSearchScope::Subtree => (vec![groups], None, Some(None)),
+            }
+        } else if let Ok(user_id) = self.get_user_id_from_dn(base) {
+            // The users of the mapped OUs are only in the entries with their OU.
+            let subtree = match depth {
+                2 => Some(UserSubtree::People),
+                _ => self.get_user_ou_of_dn(&dn_parts[1..]).map(UserSubtree::Ou),
+            };
+            match (scope, subtree) {
+                (LdapSearchScope::OneLevel, _) | (_, None) => (vec![], None, None),
+                (_, Some(subtree)) => (vec![], Some((subtree, Some(user_id))), None),
+            }
+        } else if let Ok(group_name) = self.get_group_id_from_dn(base) {
+            match scope {
+                LdapSearchScope::OneLevel => (vec![], None, None),
+                _ => (vec![], None, Some(Some(group_name))),
+            }
+        } else {
+            warn!(
+                r#"The requested search tree "{}" matches neither the user subtree "{}" nor the group subtree "{}""#,
+                base, &self.people_dn_str, &self.groups_dn_str
+            );
+            (vec![], None, None)
+        };
+        SearchTargets {
+            containers,
+            users,
+            groups,
+        }
+    }
+
+    /// The entries of the containers that match the filter, with the requested attributes.
+    fn make_container_entries(
+        &self,
+        request: &LdapSearchRequest,
+        containers: &[(String, (String, String))],
+    ) -> Vec<LdapOp> {
+        containers
+            .iter()
+            .map(|(dn, rdn)| make_container_entry(dn, rdn))
+            .filter(|entry| matches_entry_filter(&request.filter, entry))
+            .map(|mut entry| {
+                let names: Vec<&str> = entry.attributes.iter().map(|a| a.atype.as_str()).collect();
+                let attributes: Vec<String> = expand_attributes(&request.attrs, &names)
+                    .into_iter()
+                    .filter(|a| !self.is_hidden_attribute(a))
+                    .collect();
+                entry
+                    .attributes
+                    .retain(|a| attributes.iter().any(|n| n.eq_ignore_ascii_case(&a.atype)));
+                LdapOp::SearchResultEntry(entry)
+            })
+            .collect()
+    }
+
+    /// Says in the message of a successful search how many entries were returned, and how many
+    /// were left out (e.g. the users of other OUs), for debugging.
+    fn describe_search_results(&self, mut results: Vec<LdapOp>) -> Vec<LdapOp> {
+        let returned = results
+            .iter()
+            .filter(|op| matches!(op, LdapOp::SearchResultEntry(_)))
+            .count()
+            + self.entry_stream.as_ref().map_or(0, |s| s.sent.get());
+        if let Some(LdapOp::SearchResultDone(result)) = results.last_mut() {
+            if result.code == LdapResultCode::Success {
+                result.message = format!(
+                    "returned {} entries ({} filtered)",
+                    returned,
+                    self.filtered_entries.get()
+                );
+            }
+        }
+        results
+    }
+
+    /// With `anonymous_readable_attributes`, removes the other attributes from the entries sent
+    /// to the anonymous sessions.
+    fn restrict_anonymous_results(&self, results: Vec<LdapOp>) -> Vec<LdapOp> {
+        match &self.options.anonymous_readable_attributes {
+            Some(allowed) if self.is_anonymous() => retain_attributes(results, allowed),
+            _ => results,
+        }
+    }
+
+    /// The time limit requested by the client (0 meaning unlimited), capped by the server's own
+    /// limit.
+    fn get_time_limit(&self, request: &LdapSearchRequest) -> Option<Duration> {
+        let requested_limit = u64::try_from(request.timelimit)
+            .ok()
+            .filter(|limit| *limit > 0)
+            .map(Duration::from_secs);
+        [requested_limit, self.options.search_timeout]
+            .iter()
+            .flatten()
+            .min()
+            .copied()
+    }
+
+    /// The size limit requested by the client (0 meaning unlimited), capped by the server's own
+    /// limit.
+    fn get_size_limit(&self, request: &LdapSearchRequest) -> Option<usize> {
+        let requested_limit = usize::try_from(request.sizelimit)
+            .ok()
+            .filter(|limit| *limit > 0);
+        [requested_limit, self.options.max_size_limit]
+            .iter()
+            .flatten()
+            .min()
+            .copied()
+    }
+
+    /// Truncates the search results to the size limit. The entries already streamed count
+    /// towards the limit.
+    fn apply_size_limit(
+        &self,
+        request: &LdapSearchRequest,
+        mut results: Vec<LdapOp>,
+    ) -> Vec<LdapOp> {
+        let limit = match self.get_size_limit(request) {
+            Some(limit) => limit,
+            None => return results,
+        };
+        let (streamed, truncated) = match &self.entry_stream {
+            Some(stream) => (stream.sent.get(), stream.truncated.get()),
+            None => (0, false),
+        };
+        let num_entries = results
+            .iter()
+            .filter(|op| matches!(op, LdapOp::SearchResultEntry(_)))
+            .count();
+        if streamed + num_entries <= limit && !truncated {
+            return results;
+        }
+        debug!("Search returned more than {} entries, truncating", limit);
+        let mut remaining = limit.saturating_sub(streamed);
+        results.retain(|op| {
+            if !matches!(op, LdapOp::SearchResultEntry(_)) {
+                return true;
+            }
+            if remaining == 0 {
+                return false;
+            }
+            remaining -= 1;
+            true
+        });
+        if results.last() == Some(&make_search_success()) {
+            results.pop();
+            results.push(make_search_error(
+                LdapResultCode::SizeLimitExceeded,
+                format!("Size limit exceeded: more than {} entries", limit),
+            ));
+        }
+        results
+    }
+
+    /// Returns the next page of a search. The full results are computed on the first page, and
+    /// the remaining entries are kept until the client asks for them with the returned cookie.
+    async fn do_paged_search(
+        &mut self,
+        request: &LdapSearchRequest,
+        control: &RawControl,
+        controls: &[RawControl],
+    ) -> Vec<LdapResponse> {
+        let (page_size, cookie) = match parse_paged_results_control(control) {
+            Ok(paging) => paging,
+            Err(e) => {
+                return vec![make_search_error(
+                    LdapResultCode::ProtocolError,
+                    format!("Invalid paged results control: {:#}", e),
+                )
+                .into()]
+            }
+        };
+        let mut search = if cookie.is_empty() {
+            let (mut results, done_controls) = self.do_sorted_search(request, controls).await;
+            let done = results.pop().unwrap_or_else(make_search_success);
+            PagedSearch {
+                dn: self.dn.clone(),
+                request: request.clone(),
+                entries: results.into(),
+                done,
+                done_controls,
+            }
+        } else {
+            let search = std::str::from_utf8(&cookie)
+                .ok()
+                .and_then(|cookie| cookie.parse::<u64>().ok())
+                .and_then(|cookie| self.paged_searches.remove(&cookie));
+            match search {
+                Some(search)
+                    if search.dn == self.dn
+                        && search.request.base == request.base
+                        && search.request.scope == request.scope
+                        && search.request.filter == request.filter
+                        && search.request.attrs == request.attrs =>
+                {
+                    search
+                }
+                Some(_) => {
+                    return vec![make_search_error(
+                        LdapResultCode::UnwillingToPerform,
+                        "The paged results cookie is for another search".to_string(),
+                    )
+                    .into()]
+                }
+                None => {
+                    return vec![make_search_error(
+                        LdapResultCode::UnwillingToPerform,
+                        "Unknown paged results cookie".to_string(),
+                    )
+                    .into()]
+                }
+            }
+        };
+        if page_size == 0 {
+            // The client abandoned the search.
+            debug!("Paged search abandoned");
+            return vec![LdapResponse {
+                op: make_search_success().into(),
+                controls: vec![make_paged_results_control(vec![])],
+            }];
+        }
+        let done_controls = |cookie| {
+            std::iter::once(make_paged_results_control(cookie))
+                .chain(search.done_controls.iter().cloned())
+                .collect()
+        };
+        let page_length = std::cmp::min(page_size, search.entries.len());
+        let mut responses: Vec<LdapResponse> = search
+            .entries
+            .drain(..page_length)
+            .map(LdapResponse::from)
+            .collect();
+        if search.entries.is_empty() {
+            responses.push(LdapResponse {
+                controls: done_controls(vec![]),
+                op: search.done.into(),
+            });
+        } else {
+            self.last_paged_search_cookie += 1;
+            let cookie = self.last_paged_search_cookie;
+            responses.push(LdapResponse {
+                op: search.done.clone().into(),
+                controls: done_controls(cookie.to_string().into_bytes()),
+            });
+            if self.paged_searches.len() >= MAX_PAGED_SEARCHES {
+                // The cookies are increasing: the first one is the oldest search.
+                if let Some(oldest) = self.paged_searches.keys().next().copied() {
+                    debug!("Dropping the oldest paged search");
+                    self.paged_searches.remove(&oldest);
+                }
+            }
+            self.paged_searches.insert(cookie, search);
+        }
+        responses
+    }
+
+    /// Runs the search, with the entries sorted as asked by the sort control (RFC 2891), if
+    /// any. Also returns the controls of the final result: the sort response. The entries are
+    /// left unsorted when a key is not supported, unless the control is critical.
+    async fn do_sorted_search(
+        &mut self,
+        request: &LdapSearchRequest,
+        controls: &[RawControl],
+    ) -> (Vec<LdapOp>, Vec<RawControl>) {
+        let control = match controls.iter().find(|c| c.oid == SORT_REQUEST_OID) {
+            Some(control) => control,
+            None => return (self.do_search(request).await, vec![]),
+        };
+        let keys = match parse_sort_control(control) {
+            Ok(keys) => keys,
+            Err(e) => {
+                return (
+                    vec![make_search_error(
+                        LdapResultCode::ProtocolError,
+                        format!("Invalid sort control: {:#}", e),
+                    )],
+                    vec![],
+                )
+            }
+        };
+        let mut orderings = Vec::with_capacity(keys.len());
+        for key in keys {
+            match get_sort_ordering(&key) {
+                Some(ordering) => orderings.push((key, ordering)),
+                None => {
+                    debug!("Unsupported sort key: {:?}", &key);
+                    let response =
+                        make_sort_response_control(SORT_UNWILLING_TO_PERFORM, Some(&key.attribute));
+                    if control.criticality {
+                        return (
+                            vec![make_search_error(
+                                LdapResultCode::UnavailableCriticalExtension,
+                                format!(r#"Cannot sort by "{}""#, &key.attribute),
+                            )],
+                            vec![response],
+                        );
+                    }
+                    return (self.do_search(request).await, vec![response]);
+                }
+            }
+        }
+        // The sort attributes are fetched even if they were not requested, then removed.
+        let missing_attributes: Vec<String> = orderings
+            .iter()
+            .map(|(key, _)| key.attribute.clone())
+            .filter(|a| {
+                !expand_attributes(&request.attrs, USER_ATTRIBUTES)
+                    .iter()
+                    .chain(expand_attributes(&request.attrs, GROUP_ATTRIBUTES).iter())
+                    .any(|requested| requested.eq_ignore_ascii_case(a))
+            })
+            .collect();
+        let sorted_request = LdapSearchRequest {
+            attrs: request
+                .attrs
+                .iter()
+                .chain(missing_attributes.iter())
+                .cloned()
+                .collect(),
+            ..request.clone()
+        };
+        let mut results = self.do_search(&sorted_request).await;
+        let entries_end = match results.last() {
+            Some(LdapOp::SearchResultDone(_)) => results.len() - 1,
+            _ => results.len(),
+        };
+        sort_entries(&mut results[..entries_end], &orderings);
+        remove_attributes(&mut results, &missing_attributes);
+        (
+            results,
+            vec![make_sort_response_control(SORT_SUCCESS, None)],
+        )
+    }
+
+    /// The filters of a user search, restricted to the given user if any.
+    fn get_user_search_filters(
+        &self,
+        request: &LdapSearchRequest,
+        user_filter: &Option<&UserId>,
+    ) -> std::result::Result<UserRequestFilter, LdapOp> {
+        let filters = self.convert_user_filter(&request.filter).map_err(|e| {
+            make_search_error(
+                LdapResultCode::UnwillingToPerform,
+                format!("Unsupported user filter: {:#}", e),
+            )
+        })?;
+        let filters = if is_match_all_user_filter(&filters) {
+            UserRequestFilter::And(vec![])
+        } else {
+            filters
+        };
+        Ok(match user_filter {
+            None => filters,
+            Some(u) => {
+                UserRequestFilter::And(vec![filters, UserRequestFilter::UserId((*u).clone())])
+            }
+        })
+    }
+
+    async fn get_user_entry_attributes(&self, request: &LdapSearchRequest) -> UserEntryAttributes {
+        let names: Vec<String> = expand_attributes(&request.attrs, USER_ATTRIBUTES)
+            .into_iter()
+            .filter(|a| !self.is_hidden_attribute(a))
+            .collect();
+        // The other sessions don't get the password metadata, even when they ask for it.
+        let with_password_metadata =
+            names.iter().any(|a| is_password_metadata_attribute(a)) && self.is_admin().await;
+        let with_photo = names.iter().any(|a| is_binary_attribute(a));
+        UserEntryAttributes {
+            names,
+            with_password_metadata,
+            with_photo,
+        }
+    }
+
+    /// The entries of the users, restricted to the given OU under "ou=people" if any.
+    async fn make_user_entries(
+        &self,
+        users: Vec<User>,
+        subtree: UserSubtree<'_>,
+        ous: &HashMap<UserId, String>,
+        attributes: &UserEntryAttributes,
+    ) -> std::result::Result<Vec<LdapOp>, LdapOp> {
+        let mut entries = Vec::new();
+        let num_users = users.len();
+        let users: Vec<User> = users
+            .into_iter()
+            .filter(|u| subtree.contains(ous.get(&u.user_id).map(String::as_str)))
+            .collect();
+        self.filtered_entries
+            .set(self.filtered_entries.get() + num_users - users.len());
+        for user in users {
+            let user_id = user.user_id.clone();
+            let user_ou = ous.get(&user_id).map(String::as_str);
+            let mut entry = make_ldap_search_user_result_entry(
+                user,
+                user_ou,
+                &self.people_dn_str,
+                &attributes.names,
+                &self.options,
+            )
+            .map_err(|e| make_search_error(LdapResultCode::NoSuchAttribute, e.to_string()))?;
+            if attributes.with_password_metadata {
+                match self.backend_handler.get_password_metadata(&user_id).await {
+                    Ok(metadata) => entry.attributes.extend(make_password_metadata_attributes(
+                        &metadata,
+                        &attributes.names,
+                    )),
+                    Err(e) => {
+                        return Err(make_search_error(
+                            LdapResultCode::Other,
+                            format!(
+                                r#"Error while getting the password metadata of "{}": {:#}"#,
+                                user_id, e
+                            ),
+                        ))
+                    }
+                }
+            }
+            if attributes.with_photo {
+                match self.backend_handler.get_user_avatar(&user_id).await {
+                    Ok(Some(avatar)) if avatar.len() <= self.options.max_photo_bytes => entry
+                        .attributes
+                        .extend(make_photo_attributes(&avatar, &attributes.names)),
+                    Ok(Some(avatar)) => debug!(
+                        r#"Not returning the avatar of "{}": {} bytes is over the limit"#,
+                        user_id,
+                        avatar.len()
+                    ),
+                    Ok(None) => (),
+                    Err(e) => {
+                        return Err(make_search_error(
+                            LdapResultCode::Other,
+                            format!(
+                                r#"Error while getting the avatar of "{}": {:#}"#,
+                                user_id, e
+                            ),
+                        ))
+                    }
+                }
+            }
+            entries.push(LdapOp::SearchResultEntry(entry));
+        }
+        Ok(entries)
+    }
+
+    /// The users matching the search, restricted to the given part of "ou=people". When the
+    /// entries are streamed, they are sent rather than returned.
+    async fn get_user_list(
+        &self,
+        request: &LdapSearchRequest,
+        user_filter: &Option<&UserId>,
+        subtree: UserSubtree<'_>,
+    ) -> Vec<LdapOp> {
+        let filters = match self.get_user_search_filters(request, user_filter) {
+            Ok(filters) => filters,
+            Err(error) => return vec![error],
+        };
+        let ous = match self.get_user_ous().await {
+            Ok(ous) => ous,
+            Err(e) => {
+                return vec![make_search_error(
+                    LdapResultCode::Other,
+                    format!(r#"Error during searching user "{}": {:#}"#, request.base, e),
+                )]
+            }
+        };
+        let attributes = self.get_user_entry_attributes(request).await;
+        // Typically, a user reading its own entry right after binding.
+        let is_self_search = self.options.cache_bound_user
+            && !self.is_anonymous()
+            && selects_only_user(&filters, &self.user_id) == Some(true);
+        let cached_user = self
+            .bound_user_entry
+            .borrow()
+            .as_ref()
+            .filter(|user| is_self_search && user.user_id == self.user_id)
+            .cloned();
+        if let Some(user) = cached_user {
+            debug!(r#"Using the cached entry of "{}""#, &self.user_id);
+            return self
+                .make_user_entries(vec![user], subtree, &ous, &attributes)
+                .await
+                .unwrap_or_else(|error| vec![error]);
+        }
+        if let Some(stream) = &self.entry_stream {
+            return self
+                .stream_user_list(stream, request, filters, subtree, &ous, &attributes)
+                .await;
+        }
+        let users = match self.backend_handler.list_users(Some(filters)).await {
+            Ok(users) => users,
+            Err(e) => {
+                return vec![make_search_error(
+                    get_backend_error_code(&e),
+                    format!(r#"Error during searching user "{}": {:#}"#, request.base, e),
+                )]
+            }
+        };
+        if let (true, [user]) = (is_self_search, users.as_slice()) {
+            self.bound_user_entry.replace(Some(user.clone()));
+        }
+        self.make_user_entries(users, subtree, &ous, &attributes)
+            .await
+            .unwrap_or_else(|error| vec![error])
+    }
+
+    /// Fetches the users in batches, and sends their entries as they come, up to the size limit.
+    async fn stream_user_list(
+        &self,
+        stream: &EntryStream,
+        request: &LdapSearchRequest,
+        filters: UserRequestFilter,
+        subtree: UserSubtree<'_>,
+        ous: &HashMap<UserId, String>,
+        attributes: &UserEntryAttributes,
+    ) -> Vec<LdapOp> {
+        let size_limit = self.get_size_limit(request);
+        let mut after = None;
+        loop {
+            let users = match self
+                .backend_handler
+                .list_users_batch(Some(filters.clone()), after, SEARCH_BATCH_SIZE)
+                .await
+            {
+                Ok(users) => users,
+                Err(e) => {
+                    return vec![make_search_error(
+                        get_backend_error_code(&e),
+                        format!(r#"Error during searching user "{}": {:#}"#, request.base, e),
+                    )]
+                }
+            };
+            let is_last_batch = users.len() < SEARCH_BATCH_SIZE;
+            after = users.last().map(|u| u.user_id.clone());
+            let entries = match self
+                .make_user_entries(users, subtree, ous, attributes)
+                .await
+            {
+                Ok(entries) => apply_types_only(request, entries),
+                Err(error) => return vec![error],
+            };
+            for entry in entries {
+                if matches!(size_limit, Some(limit) if stream.sent.get() >= limit) {
+                    stream.truncated.set(true);
+                    return vec![];
+                }
+                if !self.send_response(entry.into()).await {
+                    debug!("The client is gone, stopping the search");
+                    return vec![];
+                }
+                stream.sent.set(stream.sent.get() + 1);
+            }
+            if is_last_batch {
+                return vec![];
+            }
+        }
+    }
+
+    /// The groups matching the search, restricted to the given group if any.
+    async fn get_groups_list(
+        &self,
+        request: &LdapSearchRequest,
+        user_filter: &Option<&UserId>,
+        group_name: Option<&str>,
+    ) -> Vec<LdapOp> {
+        let filter = match self.convert_group_filter(&request.filter) {
+            Ok(f) => f,
+            Err(e) => {
+                return vec![make_search_error(
+                    LdapResultCode::UnwillingToPerform,
+                    format!("Unsupported group filter: {:#}", e),
+                )]
+            }
+        };
+        let filter = if is_match_all_group_filter(&filter) {
+            GroupRequestFilter::And(vec![])
+        } else {
+            filter
+        };
+        let filter = match group_name {
+            None => filter,
+            Some(name) => GroupRequestFilter::And(vec![
+                filter,
+                GroupRequestFilter::DisplayName(name.to_string()),
+            ]),
+        };
+        let filter = match user_filter {
+            None => filter,
+            Some(u) => {
+                GroupRequestFilter::And(vec![filter, GroupRequestFilter::Member((*u).clone())])
+            }
+        };
+
+        let groups = match self.list_ldap_groups(filter).await {
+            Ok(groups) => groups,
+            Err(e) => {
+                return vec![make_search_error(
+                    e.downcast_ref::<DomainError>()
+                        .map_or(LdapResultCode::Other, get_backend_error_code),
+                    format!(r#"Error while listing groups "{}": {:#}"#, request.base, e),
+                )]
+            }
+        };
+        let emails = match self.get_member_emails().await {
+            Ok(emails) => emails,
+            Err(e) => {
+                return vec![make_search_error(
+                    LdapResultCode::Other,
+                    format!(
+                        r#"Error while listing the group members "{}": {:#}"#,
+                        request.base, e
+                    ),
+                )]
+            }
+        };
+        let ous = match self.get_user_ous().await {
+            Ok(ous) => ous,
+            Err(e) => {
+                return vec![make_search_error(
+                    LdapResultCode::Other,
+                    format!(
+                        r#"Error while listing the group members "{}": {:#}"#,
+                        request.base, e
+                    ),
+                )]
+            }
+        };
+        let member_dn = |user_id: &UserId| self.make_member_dn(user_id, &emails, &ous);
+        let attributes: Vec<String> = expand_attributes(&request.attrs, GROUP_ATTRIBUTES)
+            .into_iter()
+            .filter(|a| !self.is_hidden_attribute(a))
+            .collect();
+
+        groups
+            .into_iter()
+            .map(|u| {
+                make_ldap_search_group_result_entry(
+                    u,
+                    &self.groups_dn_str,
+                    &attributes,
+                    user_filter,
+                    &member_dn,
+                    &self.options,
+                )
+            })
+            .map(|entry| Ok(LdapOp::SearchResultEntry(entry?)))
+            .collect::<Result<Vec<_>>>()
+            .unwrap_or_else(|e| {
+                vec![make_search_error(
+                    LdapResultCode::NoSuchAttribute,
+                    e.to_string(),
+                )]
+            })
+    }
+
+    async fn compare_user(
+        &self,
+        user_id: UserId,
+        request: &CompareRequest,
+        user_filter: &Option<&UserId>,
+    ) -> (LdapResultCode, String) {
+        let filter = match user_filter {
+            None => UserRequestFilter::UserId(user_id),
+            Some(u) => UserRequestFilter::And(vec![
+                UserRequestFilter::UserId(user_id),
+                UserRequestFilter::UserId((*u).clone()),
+            ]),
+        };
+        let user = match self.backend_handler.list_users(Some(filter)).await {
+            Ok(users) => match users.into_iter().next() {
+                Some(user) => user,
+                None => return (LdapResultCode::NoSuchObject, "".to_string()),
+            },
+            Err(e) => {
+                return (
+                    LdapResultCode::Other,
+                    format!(r#"Error while reading user "{}": {:#}"#, request.dn, e),
+                )
+            }
+        };
+        let attribute = resolve_attribute_alias(&request.attribute, self.options.ad_compatibility);
+        match get_user_attribute(&user, attribute, &request.dn, &self.options) {
+            Ok(values) => compare_values(values, &String::from_utf8_lossy(&request.value)),
+            Err(e) => (LdapResultCode::NoSuchAttribute, e.to_string()),
+        }
+    }
+
+    async fn compare_group(
+        &self,
+        group_name: String,
+        request: &CompareRequest,
+        user_filter: &Option<&UserId>,
+    ) -> (LdapResultCode, String) {
+        let filter = match user_filter {
+            None => GroupRequestFilter::DisplayName(group_name),
+            Some(u) => GroupRequestFilter::And(vec![
+                GroupRequestFilter::DisplayName(group_name),
+                GroupRequestFilter::Member((*u).clone()),
+            ]),
+        };
+        let group = match self.list_ldap_groups(filter).await {
+            Ok(groups) => match groups.into_iter().next() {
+                Some(group) => group,
+                None => return (LdapResultCode::NoSuchObject, "".to_string()),
+            },
+            Err(e) => {
+                return (
+                    LdapResultCode::Other,
+                    format!(r#"Error while reading group "{}": {:#}"#, request.dn, e),
+                )
+            }
+        };
+        let emails = match self.get_member_emails().await {
+            Ok(emails) => emails,
+            Err(e) => {
+                return (
+                    LdapResultCode::Other,
+                    format!(r#"Error while reading group "{}": {:#}"#, request.dn, e),
+                )
+            }
+        };
+        let ous = match self.get_user_ous().await {
+            Ok(ous) => ous,
+            Err(e) => {
+                return (
+                    LdapResultCode::Other,
+                    format!(r#"Error while reading group "{}": {:#}"#, request.dn, e),
+                )
+            }
+        };
+        match get_group_attribute(
+            &group,
+            &self.groups_dn_str,
+            resolve_attribute_alias(&request.attribute, self.options.ad_compatibility),
+            user_filter,
+            &|user_id| self.make_member_dn(user_id, &emails, &ous),
+            self.options.posix_ids,
+        ) {
+            Ok(values) => compare_values(values, &String::from_utf8_lossy(&request.value)),
+            Err(e) => (LdapResultCode::NoSuchAttribute, e.to_string()),
+        }
+    }
+
+    /// Compares an attribute of a user or a group. The entries that a search wouldn't return
+    /// (e.g. other users for a non-admin) don't exist as far as the client is concerned.
+    pub async fn do_compare(&mut self, request: &CompareRequest) -> (LdapResultCode, String) {
+        debug!(
+            r#"Received compare request for "{}" from {}"#,
+            &request.dn,
+            self.peer()
+        );
+        if self.is_anonymous() {
+            return (
+                LdapResultCode::InsufficentAccessRights,
+                "Anonymous sessions can only read the root DSE and the schema".to_string(),
+            );
+        }
+        if self.is_hidden_attribute(&request.attribute) {
+            return (
+                LdapResultCode::InsufficentAccessRights,
+                format!(r#"Cannot compare "{}""#, &request.attribute),
+            );
+        }
+        self.resolve_user_emails([request.dn.as_str()]).await;
+        let admin = self.can_read_all();
+        let user_filter = if admin { None } else { Some(&self.user_id) };
+        if let Ok(user_id) = self.get_user_id_from_dn(&request.dn) {
+            return self.compare_user(user_id, request, &user_filter).await;
+        }
+        if let Ok(group_name) = self.get_group_id_from_dn(&request.dn) {
+            return self.compare_group(group_name, request, &user_filter).await;
+        }
+        if parse_distinguished_name(&request.dn).is_err() {
+            return (
+                LdapResultCode::InvalidDNSyntax,
+                format!(r#"Could not parse DN: "{}""#, request.dn),
+            );
+        }
+        (LdapResultCode::NoSuchObject, "".to_string())
+    }
+
+    /// The referral URL if the DN is in a subtree held by another server.
+    fn find_referral(&self, dn: &str) -> Option<&str> {
+        let dn_parts = parse_distinguished_name(dn).ok()?;
+        self.options
+            .referrals
+            .iter()
+            .find(|referral| is_subtree(&dn_parts, &referral.dn))
+            .map(|referral| referral.url.as_str())
+    }
+
+    /// References to the delegated subtrees in the scope of the search, if any.
+    fn get_search_references(&self, request: &LdapSearchRequest) -> Vec<LdapResponse> {
+        let base = match parse_distinguished_name(&request.base) {
+            Ok(base) => base,
+            Err(_) => return vec![],
+        };
+        self.options
+            .referrals
+            .iter()
+            .filter(|referral| {
+                is_subtree(&referral.dn, &base)
+                    && match request.scope {
+                        LdapSearchScope::Base => false,
+                        LdapSearchScope::OneLevel => referral.dn.len() == base.len() + 1,
+                        LdapSearchScope::Subtree => referral.dn.len() > base.len(),
+                    }
+            })
+            .map(|referral| LdapResponse {
+                op: LdapResponseOp::SearchResultReference(vec![referral.url.clone()]),
+                controls: vec![],
+            })
+            .collect()
+    }
+
+    /// Lists the groups through the cache, if there is one.
+    async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>> {
+        Ok(match &self.options.group_cache {
+            Some(cache) => cache.list_groups(&self.backend_handler, filters).await?,
+            None => self.backend_handler.list_groups(filters).await?,
+        })
+    }
+
+    /// The groups of the LDAP entries: the groups of the backend, and the virtual group of all the
+    /// users if it matches the filter.
+    async fn list_ldap_groups(&self, filter: GroupRequestFilter) -> Result<Vec<Group>> {
+        let mut groups = self.list_groups(Some(filter.clone())).await?;
+        if let Some(display_name) = &self.options.all_users_group {
+            let all_users_group = Group {
+                id: ALL_USERS_GROUP_ID,
+                display_name: display_name.clone(),
+                users: self
+                    .backend_handler
+                    .list_users(None)
+                    .await?
+                    .into_iter()
+                    .map(|user| user.user_id)
+                    .collect(),
+            };
+            if group_matches(&filter, &all_users_group) {
+                groups.push(all_users_group);
+            }
+        }
+        Ok(groups)
+    }
+
+    /// Sends a response to the current request while it is still in progress. Returns false if the
+    /// client is gone, or if the request can't have such responses (see
+    /// `handle_ldap_request_streaming`).
+    async fn send_response(&self, response: LdapResponse) -> bool {
+        match &self.response_sender {
+            Some(sender) => sender.send(response).await.is_ok(),
+            None => false,
+        }
+    }
+
+    /// Issues a trivial query, to make sure that the backend is reachable.
+    async fn check_backend(&self) -> Result<()> {
+        self.backend_handler
+            .list_groups(Some(GroupRequestFilter::Not(Box::new(
+                GroupRequestFilter::And(vec![]),
+            ))))
+            .await?;
+        Ok(())
+    }
+
+    /// Forgets the bound identity: the session is anonymous again.
+    fn reset_to_anonymous(&mut self) {
+        self.dn = LdapDn("unauthenticated".to_string());
+        self.user_id = UserId::new("unauthenticated");
+        self.paged_searches.clear();
+    }
+
+    /// Handles a single LDAP operation with its controls, and returns the responses to send back.
+    /// Returns None if the connection should be closed, without any response (unbind).
+    pub async fn handle_ldap_message(
+        &mut self,
+        ldap_op: LdapOp,
+        controls: &[RawControl],
+    ) -> Option<Vec<LdapResponse>> {
+        let responses = match ldap_op {
+            LdapOp::BindRequest(request) => {
+                if let Some(url) = self.find_referral(&request.dn) {
+                    debug!(r#"Referring the bind for "{}" to {}"#, &request.dn, url);
+                    return Some(vec![make_referral(
+                        make_bind_response(LdapResultCode::Referral, "".to_string()),
+                        url,
+                    )]);
+                }
+                let (code, message) = self.do_bind(&request).await;
+                self.log_bind(&request.dn, "simple", code);
+                if self.options.report_account_lockout
+                    && controls.iter().any(|c| c.oid == PASSWORD_POLICY_OID)
+                {
+                    let error =
+                        Some(PASSWORD_POLICY_ACCOUNT_LOCKED).filter(|_| self.bind_locked_out);
+                    return Some(vec![LdapResponse {
+                        op: make_bind_response(code, message).into(),
+                        controls: vec![make_password_policy_control(error)],
+                    }]);
+                }
+                vec![make_bind_response(code, message)]
+            }
+            LdapOp::SearchRequest(mut request) => {
+                if let Some(url) = self.find_referral(&request.base) {
+                    debug!(r#"Referring the search of "{}" to {}"#, &request.base, url);
+                    return Some(vec![make_referral(
+                        make_search_error(LdapResultCode::Referral, "".to_string()),
+                        url,
+                    )]);
+                }
+                if controls.iter().any(|c| c.oid == MANAGE_DSA_IT_OID) {
+                    add_operational_attributes(&mut request);
+                }
+                let values_filter = match controls
+                    .iter()
+                    .find(|c| c.oid == MATCHED_VALUES_OID)
+                    .map(|c| parse_matched_values_filter(c, self.options.ad_compatibility))
+                    .transpose()
+                {
+                    Ok(filter) => filter,
+                    Err(e) => {
+                        return Some(vec![make_search_error(
+                            LdapResultCode::ProtocolError,
+                            format!("Invalid matched values control: {:#}", e),
+                        )
+                        .into()])
+                    }
+                };
+                if let Some(control) = controls.iter().find(|c| c.oid == SYNC_REQUEST_OID) {
+                    return Some(self.do_sync_search(&request, control).await);
+                }
+                if let Some(control) = controls.iter().find(|c| c.oid == PAGED_RESULTS_OID) {
+                    let mut responses = self.do_paged_search(&request, control, controls).await;
+                    if let Some(filter) = &values_filter {
+                        apply_matched_values(&mut responses, filter);
+                    }
+                    return Some(responses);
+                }
+                let (results, done_controls) = self.do_sorted_search(&request, controls).await;
+                let mut responses = results
+                    .into_iter()
+                    .map(LdapResponse::from)
+                    .collect::<Vec<_>>();
+                if let Some(filter) = &values_filter {
+                    apply_matched_values(&mut responses, filter);
+                }
+                // The references go with the entries, before the final result.
+                if let Some(LdapResponseOp::Op(LdapOp::SearchResultDone(LdapResult {
+                    code: LdapResultCode::Success,
+                    ..
+                }))) = responses.last().map(|r| &r.op)
+                {
+                    let done = responses.pop().unwrap();
+                    responses.extend(self.get_search_references(&request));
+                    responses.push(done);
+                }
+                if let Some(done) = responses.last_mut() {
+                    done.controls.extend(done_controls);
+                }
+                return Some(responses);
+            }
+            LdapOp::UnbindRequest => {
+                debug!(r#"Unbind request for "{}""#, &self.dn.0);
+                self.reset_to_anonymous();
+                // No need to notify on unbind (per rfc4511)
+                return None;
+            }
+            LdapOp::ExtendedRequest(request) if request.name == CANCEL_OID => {
+                return Some(vec![self.do_cancel(&request)]);
+            }
+            LdapOp::ExtendedRequest(request) => self.do_extended_request(&request).await,
+            op => vec![make_extended_response(
+                LdapResultCode::UnwillingToPerform,
+                format!("Unsupported operation: {:#?}", op),
+            )],
+        };
+        Some(responses.into_iter().map(LdapResponse::from).collect())
+    }
+
+    /// Handles an incoming request, whether or not it is supported by `ldap3_server`.
+    pub async fn handle_ldap_request(
+        &mut self,
+        request: LdapRequest,
+        controls: &[RawControl],
+    ) -> Option<Vec<LdapResponse>> {
+        if is_operation_disabled(&self.options, request.op_type()) {
+            debug!(
+                "Refusing the disabled {} operation from {}",
+                request.op_type(),
+                self.peer()
+            );
+            return Some(vec![make_error_response(
+                &request,
+                LdapResultCode::UnwillingToPerform,
+                format!(
+                    "The {} operations are disabled on this server",
+                    request.op_type()
+                ),
+            )
+            .into()]);
+        }
+        // There is no response to an unbind, it always succeeds.
+        if !matches!(request, LdapRequest::Op(LdapOp::UnbindRequest)) {
+            if let Some(control) = controls
+                .iter()
+                .find(|c| c.criticality && !self.is_supported_control(&request, &c.oid))
+            {
+                debug!("Refusing the unsupported critical control {}", &control.oid);
+                return Some(vec![LdapResponse {
+                    op: make_error_response(
+                        &request,
+                        LdapResultCode::UnavailableCriticalExtension,
+                        format!("Unsupported critical control: {}", &control.oid),
+                    ),
+                    controls: vec![],
+                }]);
+            }
+        }
+        match controls.iter().find(|c| c.oid == PROXIED_AUTHORIZATION_OID) {
+            Some(control) if !is_bind_or_unbind(&request) => {
+                self.do_proxied_request(request, controls, control).await
+            }
+            _ if is_bind_or_unbind(&request) => {
+                let responses = self.dispatch_ldap_request(request, controls).await;
+                match self.count_user_connection() {
+                    Some(busy) => Some(vec![busy]),
+                    None => responses,
+                }
+            }
+            _ => self.dispatch_ldap_request(request, controls).await,
+        }
+    }
+
+    /// After a bind, counts the connection for the DN it is now bound as. If there are already
+    /// too many connections bound as that DN, returns the busy response replacing the one of the
+    /// bind, and the connection is closed.
+    fn count_user_connection(&mut self) -> Option<LdapResponse> {
+        let limiter = self.options.user_connection_limiter.clone()?;
+        let dn = match self.bound_dn() {
+            Some(dn) => dn.to_string(),
+            None => {
+                self.user_connection = None;
+                return None;
+            }
+        };
+        if matches!(&self.user_connection, Some(guard) if guard.dn() == dn) {
+            return None;
+        }
+        self.user_connection = None;
+        if let Some(guard) = limiter.try_acquire(&dn) {
+            self.user_connection = Some(guard);
+            return None;
+        }
+        warn!(
+            r#"Refused the bind as "{}" from {}: too many connections for this DN"#,
+            &dn,
+            self.peer()
+        );
+        self.dn = LdapDn("unauthenticated".to_string());
+        self.user_id = UserId::new("unauthenticated");
+        self.close_pending = true;
+        Some(
+            make_bind_response(
+                LdapResultCode::Busy,
+                format!(r#"Too many connections bound as "{}""#, dn),
+            )
+            .into(),
+        )
+    }
+
+    /// Same as `handle_ldap_request`, except that the responses can be sent through `sender` while
+    /// the request is in progress: the intermediate responses, and the user entries of the
+    /// searches, which are fetched from the backend in batches and sent as they come, so that the
+    /// searches matching many users don't have to be held in memory. The other responses are
+    /// returned, to be sent after the streamed ones. The paged, the sorted, the matched values and
+    /// the synchronized searches are not streamed, nor any search when there is a search cache.
+    pub async fn handle_ldap_request_streaming(
+        &mut self,
+        msgid: i32,
+        request: LdapRequest,
+        controls: &[RawControl],
+        sender: ResponseSender,
+    ) -> Option<Vec<LdapResponse>> {
+        let streams_entries = matches!(request, LdapRequest::Op(LdapOp::SearchRequest(_)))
+            && self.options.search_cache.is_none()
+            && !controls.iter().any(|c| {
+                [
+                    PAGED_RESULTS_OID,
+                    SORT_REQUEST_OID,
+                    MATCHED_VALUES_OID,
+                    SYNC_REQUEST_OID,
+                ]
+                .contains(&c.oid.as_str())
+            });
+        self.msgid = msgid;
+        self.response_sender = Some(sender);
+        if streams_entries {
+            self.entry_stream = Some(EntryStream {
+                sent: Cell::new(0),
+                truncated: Cell::new(false),
+            });
+        }
+        let responses = self.handle_ldap_request(request, controls).await;
+        // Closes the stream: the caller stops forwarding once all the senders are dropped.
+        self.entry_stream = None;
+        self.response_sender = None;
+        responses
+    }
+
+    /// Whether the control is implemented for the request. The other controls are ignored,
+    /// unless they are critical (RFC 4511).
+    fn is_supported_control(&self, request: &LdapRequest, oid: &str) -> bool {
+        if !is_control_enabled(&self.options, oid) {
+            return false;
+        }
+        match oid {
+            MANAGE_DSA_IT_OID => true,
+            // The data is always authoritative: there are no copies to avoid.
+            DONT_USE_COPY_OID => matches!(
+                request,
+                LdapRequest::Op(LdapOp::SearchRequest(_)) | LdapRequest::Compare(_)
+            ),
+            PAGED_RESULTS_OID | SORT_REQUEST_OID | MATCHED_VALUES_OID | SYNC_REQUEST_OID => {
+                matches!(request, LdapRequest::Op(LdapOp::SearchRequest(_)))
+            }
+            ASSERTION_OID => matches!(request, LdapRequest::Modify(_)),
+            PASSWORD_POLICY_OID => matches!(request, LdapRequest::Op(LdapOp::BindRequest(_))),
+            PRE_READ_OID | POST_READ_OID => {
+                matches!(request, LdapRequest::Modify(_) | LdapRequest::ModifyDn(_))
+            }
+            PROXIED_AUTHORIZATION_OID => !is_bind_or_unbind(request),
+            _ => false,
+        }
+    }
+
+    /// Handles a request with the proxied authorization control (RFC 4370): the operation is
+    /// performed as the user of the authorization ID, then the bound identity is restored.
+    async fn do_proxied_request(
+        &mut self,
+        request: LdapRequest,
+        controls: &[RawControl],
+        control: &RawControl,
+    ) -> Option<Vec<LdapResponse>> {
+        let (dn, user_id) = match self.get_proxied_identity(control).await {
+            Ok(identity) => identity,
+            Err(e) => {
+                warn!(
+                    r#"Denied the proxied authorization of "{}" from {}: {:#}"#,
+                    &self.dn.0,
+                    self.peer(),
+                    e
+                );
+                return Some(vec![LdapResponse {
+                    op: LdapResponseOp::WithResultCode(
+                        Box::new(make_error_response(
+                            &request,
+                            LdapResultCode::Other,
+                            format!("Proxied authorization denied: {:#}", e),
+                        )),
+                        AUTHORIZATION_DENIED,
+                    ),
+                    controls: vec![],
+                }]);
+            }
+        };
+        debug!(r#""{}" is acting as "{}""#, &self.dn.0, &dn.0);
+        let bound_dn = std::mem::replace(&mut self.dn, dn);
+        let bound_user_id = std::mem::replace(&mut self.user_id, user_id);
+        let responses = self.dispatch_ldap_request(request, controls).await;
+        self.dn = bound_dn;
+        self.user_id = bound_user_id;
+        responses
+    }
+
+    /// The identity (DN and user ID) of the authorization ID of a proxied authorization
+    /// control, if the bound user is allowed to use it. The authorization ID is either
+    /// "dn:<user DN>", "u:<user ID>", or empty for the anonymous identity.
+    async fn get_proxied_identity(&mut self, control: &RawControl) -> Result<(LdapDn, UserId)> {
+        let authz_id = std::str::from_utf8(
+            control
+                .value
+                .as_deref()
+                .context("Missing authorization ID")?,
+        )
+        .context("Invalid authorization ID")?;
+        self.get_authz_identity(authz_id).await
+    }
+
+    /// The identity (DN and user ID) of an authorization ID, if the bound user is allowed to act
+    /// on behalf of other users.
+    async fn get_authz_identity(&mut self, authz_id: &str) -> Result<(LdapDn, UserId)> {
+        let proxy_group = self
+            .options
+            .proxy_group
+            .as_ref()
+            .context("The proxied authorization is not enabled")?;
+        if self.is_anonymous() || self.is_readonly_account() {
+            bail!("Only users can act on behalf of other users");
+        }
+        let groups = self.get_bound_user_groups().await?;
+        if !groups.iter().any(|g| g.1.eq_ignore_ascii_case(proxy_group)) {
+            bail!(r#"The user is not a member of "{}""#, proxy_group);
+        }
+        if authz_id.is_empty() {
+            return Ok((
+                LdapDn("unauthenticated".to_string()),
+                UserId::new("unauthenticated"),
+            ));
+        }
+        let user_id = if let Some(dn) = authz_id.strip_prefix("dn:") {
+            self.resolve_user_emails([dn]).await;
+            self.get_user_id_from_dn(dn)?
+        } else if let Some(user_id) = authz_id.strip_prefix("u:") {
+            UserId::new(user_id)
+        } else {
+            bail!(r#"Invalid authorization ID: "{}""#, authz_id);
+        };
+        self.backend_handler
+            .get_user_details(&user_id)
+            .await
+            .with_context(|| format!(r#"Unknown user "{}""#, &user_id))?;
+        // Otherwise, the members of the proxy group would get the rights of the admins.
+        if !self.is_admin().await
+            && (user_id == self.ldap_user_id
+                || self
+                    .backend_handler
+                    .get_user_groups(&user_id)
+                    .await?
+                    .iter()
+                    .any(|g| self.is_admin_group(&g.1)))
+        {
+            bail!(
+                r#"Only the admins can act on behalf of the admin "{}""#,
+                &user_id
+            );
+        }
+        let dn = self.get_user_dn(&user_id).await;
+        Ok((LdapDn::normalized(&dn).unwrap_or(LdapDn(dn)), user_id))
+    }
+
+    async fn dispatch_ldap_request(
+        &mut self,
+        request: LdapRequest,
+        controls: &[RawControl],
+    ) -> Option<Vec<LdapResponse>> {
+        // The entry or the groups of the bound user could change.
+        if is_bind_or_unbind(&request)
+            || matches!(
+                request,
+                LdapRequest::Modify(_)
+                    | LdapRequest::ModifyDn(_)
+                    | LdapRequest::Add(_)
+                    | LdapRequest::Delete(_)
+            )
+        {
+            self.forget_bound_user();
+        }
+        // A bind abandons the operations in progress (RFC 4511).
+        if is_bind_or_unbind(&request) {
+            if self.persistent_search.take().is_some() {
+                debug!("Abandoned the persistent search for the bind");
+            }
+            // Their entries were read with the rights of the previous identity.
+            self.paged_searches.clear();
+        }
+        match request {
+            LdapRequest::Op(op) => self.handle_ldap_message(op, controls).await,
+            LdapRequest::SaslBind(request) => {
+                let (code, message) = self.do_sasl_bind(&request).await;
+                self.log_bind(
+                    &request.dn,
+                    &format!("SASL {}", escape_for_log(&request.mechanism)),
+                    code,
+                );
+                Some(vec![make_bind_response(code, message).into()])
+            }
+            LdapRequest::UnsupportedBind(request) => {
+                let (code, message) = self.do_unsupported_bind(&request);
+                self.log_bind(&request.dn, &format!("LDAPv{}", request.version), code);
+                Some(vec![make_bind_response(code, message).into()])
+            }
+            LdapRequest::Compare(request) => {
+                let (code, message) = self.do_compare(&request).await;
+                Some(vec![LdapResponse {
+                    op: make_compare_response(code, message),
+                    controls: vec![],
+                }])
+            }
+            LdapRequest::Modify(request) => {
+                let pre_read = match self.do_pre_read(&request.dn, controls).await {
+                    Ok(control) => control,
+                    Err((code, message)) => {
+                        return Some(vec![LdapResponse {
+                            op: make_modify_response(code, message),
+                            controls: vec![],
+                        }])
+                    }
+                };
+                let op = self.do_modify(&request, controls).await;
+                let post_read = match op.result() {
+                    Some(result) if result.code == LdapResultCode::Success => {
+                        self.do_post_read(&request.dn, controls).await
+                    }
+                    _ => None,
+                };
+                Some(vec![LdapResponse {
+                    op,
+                    controls: pre_read.into_iter().chain(post_read).collect(),
+                }])
+            }
+            LdapRequest::ModifyDn(request) => {
+                let pre_read = match self.do_pre_read(&request.dn, controls).await {
+                    Ok(control) => control,
+                    Err((code, message)) => {
+                        return Some(vec![LdapResponse {
+                            op: make_modify_dn_response(code, message),
+                            controls: vec![],
+                        }])
+                    }
+                };
+                let (code, message) = self.do_modify_dn(&request).await;
+                let post_read = if code == LdapResultCode::Success {
+                    let new_dn = self.get_renamed_dn(&request).await;
+                    self.do_post_read(&new_dn, controls).await
+                } else {
+                    None
+                };
+                Some(vec![LdapResponse {
+                    op: make_modify_dn_response(code, message),
+                    controls: pre_read.into_iter().chain(post_read).collect(),
+                }])
+            }
+            LdapRequest::Add(request) => {
+                let (code, message) = self.do_add(&request).await;
+                Some(vec![LdapResponse {
+                    op: make_add_response(code, message),
+                    controls: vec![],
+                }])
+            }
+            LdapRequest::Delete(request) => {
+                let (code, message) = self.do_delete(&request).await;
+                Some(vec![LdapResponse {
+                    op: make_delete_response(code, message),
+                    controls: vec![],
+                }])
+            }
+            // Same as the Cancel operation: the operation to abandon is already answered, unless it
+            // is the persistent search. There is no response, but the connection stays open.
+            LdapRequest::Unsupported(UnsupportedOperation::Abandon(msgid)) => {
+                if self.persistent_search_id() == Some(msgid) {
+                    debug!("Abandoned the persistent search {}", msgid);
+                    self.persistent_search = None;
+                } else {
+                    debug!("Nothing to abandon for the message {}", msgid);
+                }
+                Some(vec![])
+            }
+        }
+    }
+
+    fn convert_group_filter(&self, filter: &LdapFilter) -> Result<GroupRequestFilter> {
+        match filter {
+            LdapFilter::Equality(field, value) => {
+                if field.to_lowercase() == "member" || field.to_lowercase() == "uniquemember" {
+                    let user_name = self.get_user_id_from_dn(value)?;
+                    Ok(GroupRequestFilter::Member(user_name))
+                } else if field.to_lowercase() == "memberuid" {
+                    Ok(GroupRequestFilter::Member(UserId::new(value)))
+                } else if field.to_lowercase() == "gidnumber" {
+                    match self.options.posix_ids.group_id(value) {
+                        Some(group_id) => Ok(GroupRequestFilter::GroupId(group_id)),
+                        None => Ok(GroupRequestFilter::Not(Box::new(GroupRequestFilter::And(
+                            vec![],
+                        )))),
+                    }
+                } else if field.eq_ignore_ascii_case("entryuuid") {
+                    // The UUIDs of the groups can't be reversed, see `resolve_group_uuids`.
+                    match self.resolved_group_uuids.get(&value.to_lowercase()) {
+                        Some(group_id) => Ok(GroupRequestFilter::GroupId(*group_id)),
+                        None => Ok(GroupRequestFilter::Not(Box::new(GroupRequestFilter::And(
+                            vec![],
+                        )))),
+                    }
+                } else if field.eq_ignore_ascii_case("useraccountcontrol") {
+                    // Only the users have one.
+                    Ok(GroupRequestFilter::Not(Box::new(GroupRequestFilter::And(
+                        vec![],
+                    ))))
+                } else if field.to_lowercase() == "objectclass" {
+                    if has_object_class(GROUP_OBJECT_CLASSES, value) {
+                        Ok(GroupRequestFilter::And(vec![]))
+                    } else {
+                        Ok(GroupRequestFilter::Not(Box::new(GroupRequestFilter::And(
+                            vec![],
+                        ))))
+                    }
+                } else {
+                    let field = map_field(field)?;
+                    if field == "display_name" {
+                        Ok(GroupRequestFilter::DisplayName(value.clone()))
+                    } else {
+                        bail!("Unsupported group attribute: {:?}", field)
+                    }
+                }
+            }
+            LdapFilter::And(filters) => Ok(GroupRequestFilter::And(
+                filters
+                    .iter()
+                    .map(|f| self.convert_group_filter(f))
+                    .collect::<Result<_>>()?,
+            )),
+            LdapFilter::Or(filters) => Ok(GroupRequestFilter::Or(
+                filters
+                    .iter()
+                    .map(|f| self.convert_group_filter(f))
+                    .collect::<Result<_>>()?,
+            )),
+            LdapFilter::Not(filter) => Ok(GroupRequestFilter::Not(Box::new(
+                self.convert_group_filter(&*filter)?,
+            ))),
+            LdapFilter::Substring(field, substring) => {
+                let substring = convert_substring_filter(substring);
+                if field.to_lowercase() == "objectclass" {
+                    if matches_object_class(GROUP_OBJECT_CLASSES, &substring) {
+                        Ok(GroupRequestFilter::And(vec![]))
+                    } else {
+                        Ok(GroupRequestFilter::Not(Box::new(GroupRequestFilter::And(
+                            vec![],
+                        ))))
+                    }
+                } else if map_field(field)? == "display_name" {
+                    Ok(GroupRequestFilter::DisplayNameSubString(substring))
+                } else {
+                    bail!(
+                        "Unsupported group attribute for substring filter: {:?}",
+                        field
+                    )
+                }
+            }
+            LdapFilter::Present(field) => {
+                // All the groups have these attributes, possibly empty.
+                if matches!(
+                    field.to_lowercase().as_str(),
+                    "objectclass"
+                        | "cn"
+                        | "uid"
+                        | "member"
+                        | "uniquemember"
+                        | "memberuid"
+                        | "gidnumber"
+                ) {
+                    Ok(GroupRequestFilter::And(vec![]))
+                } else {
+                    Ok(GroupRequestFilter::Not(Box::new(GroupRequestFilter::And(
+                        vec![],
+                    ))))
+                }
+            }
+        }
+    }
+
+    fn convert_user_filter(&self, filter: &LdapFilter) -> Result<UserRequestFilter> {
+        match filter {
+            LdapFilter::And(filters) => Ok(UserRequestFilter::And(
+                filters
+                    .iter()
+                    .map(|f| self.convert_user_filter(f))
+                    .collect::<Result<_>>()?,
+            )),
+            LdapFilter::Or(filters) => Ok(UserRequestFilter::Or(
+                filters
+                    .iter()
+                    .map(|f| self.convert_user_filter(f))
+                    .collect::<Result<_>>()?,
+            )),
+            LdapFilter::Not(filter) => Ok(UserRequestFilter::Not(Box::new(
+                self.convert_user_filter(&*filter)?,
+            ))),
+            LdapFilter::Equality(field, value) => {
+                if field.to_lowercase() == "memberof" {
+                    let group_name = self.get_group_id_from_dn(value)?;
+                    match &self.options.all_users_group {
+                        Some(all_users) if all_users.eq_ignore_ascii_case(&group_name) => {
+                            Ok(UserRequestFilter::And(vec![]))
+                        }
+                        _ => Ok(UserRequestFilter::MemberOf(group_name)),
+                    }
+                } else if field.eq_ignore_ascii_case("entryuuid") {
+                    Ok(UserRequestFilter::Equality(
+                        "uuid".to_string(),
+                        value.to_lowercase(),
+                    ))
+                } else if self.options.ad_compatibility
+                    && field.eq_ignore_ascii_case("useraccountcontrol")
+                {
+                    if value.parse::<i64>() == Ok(USER_ACCOUNT_CONTROL) {
+                        Ok(UserRequestFilter::And(vec![]))
+                    } else {
+                        Ok(UserRequestFilter::Not(Box::new(UserRequestFilter::And(
+                            vec![],
+                        ))))
+                    }
+                } else if field.to_lowercase() == "nsaccountlock" {
+                    if value.eq_ignore_ascii_case("false") {
+                        Ok(UserRequestFilter::And(vec![]))
+                    } else {
+                        Ok(UserRequestFilter::Not(Box::new(UserRequestFilter::And(
+                            vec![],
+                        ))))
+                    }
+                } else if field.to_lowercase() == "objectclass" {
+                    if has_object_class(&self.options.user_object_classes, value) {
+                        Ok(UserRequestFilter::And(vec![]))
+                    } else {
+                        Ok(UserRequestFilter::Not(Box::new(UserRequestFilter::And(
+                            vec![],
+                        ))))
+                    }
+                } else {
+                    let field = map_field(field)?;
+                    if field == "user_id" {
+                        Ok(UserRequestFilter::UserId(UserId::new(value)))
+                    } else {
+                        Ok(UserRequestFilter::Equality(field, value.clone()))
+                    }
+                }
+            }
+            LdapFilter::Present(field) => {
+                // Check that it's a field we support.
+                if field.to_lowercase() == "objectclass"
+                    || field.to_lowercase() == "nsaccountlock"
+                    || (self.options.ad_compatibility
+                        && field.eq_ignore_ascii_case("useraccountcontrol"))
+                    || is_posix_attribute(field)
+                    || map_field(field).is_ok()
+                {
+                    Ok(UserRequestFilter::And(vec![]))
+                } else {
+                    Ok(UserRequestFilter::Not(Box::new(UserRequestFilter::And(
+                        vec![],
+                    ))))
+                }
+            }
+            LdapFilter::Substring(field, substring) => {
+                let substring = convert_substring_filter(substring);
+                if field.to_lowercase() == "objectclass" {
+                    if matches_object_class(&self.options.user_object_classes, &substring) {
+                        Ok(UserRequestFilter::And(vec![]))
+                    } else {
+                        Ok(UserRequestFilter::Not(Box::new(UserRequestFilter::And(
+                            vec![],
+                        ))))
+                    }
+                } else {
+                    match map_field(field)?.as_str() {
+                        field @ ("user_id" | "email" | "display_name" | "first_name"
+                        | "last_name") => {
+                            Ok(UserRequestFilter::SubString(field.to_string(), substring))
+                        }
+                        _ => bail!(
+                            "Unsupported user attribute for substring filter: {:?}",
+                            field
+                        ),
+                    }
+                }
+            }
+            _ => bail!("Unsupported user filter: {:?}", filter),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{error::Result, handler::*, opaque_handler::*};
+    use async_trait::async_trait;
+    use ldap3_server::proto::{LdapDerefAliases, LdapSearchScope};
+    use mockall::predicate::{always, eq};
+    use std::collections::HashSet;
+    use tokio;
+
+    mockall::mock! {
+        pub TestBackendHandler{}
+        impl Clone for TestBackendHandler {
+            fn clone(&self) -> Self;
+        }
+        #[async_trait]
         impl LoginHandler for TestBackendHandler {
             async fn bind(&self, request: BindRequest) -> Result<()>;
         }
-        #[async_trait]
-        impl BackendHandler for TestBackendHandler {
-            async fn list_users(&self, filters: Option<UserRequestFilter>) -> Result<Vec<User>>;
-            async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>>;
-            async fn get_user_details(&self, user_id: &UserId) -> Result<User>;
-            async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName>;
-            async fn get_user_groups(&self, user: &UserId) -> Result<HashSet<GroupIdAndName>>;
-            async fn create_user(&self, request: CreateUserRequest) -> Result<()>;
-            async fn update_user(&self, request: UpdateUserRequest) -> Result<()>;
-            async fn update_group(&self, request: UpdateGroupRequest) -> Result<()>;
-            async fn delete_user(&self, user_id: &UserId) -> Result<()>;
-            async fn create_group(&self, group_name: &str) -> Result<GroupId>;
-            async fn delete_group(&self, group_id: GroupId) -> Result<()>;
-            async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
-            async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
+        #[async_trait]
+        impl BackendHandler for TestBackendHandler {
+            async fn list_users(&self, filters: Option<UserRequestFilter>) -> Result<Vec<User>>;
+            async fn list_users_batch(
+                &self,
+                filters: Option<UserRequestFilter>,
+                after: Option<UserId>,
+                limit: usize,
+            ) -> Result<Vec<User>>;
+            async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>>;
+            async fn get_user_details(&self, user_id: &UserId) -> Result<User>;
+            async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName>;
+            async fn get_user_groups(&self, user: &UserId) -> Result<HashSet<GroupIdAndName>>;
+            async fn get_password_metadata(&self, user_id: &UserId) -> Result<PasswordMetadata>;
+            async fn get_user_avatar(&self, user_id: &UserId) -> Result<Option<Vec<u8>>>;
+            async fn create_user(&self, request: CreateUserRequest) -> Result<()>;
+            async fn update_user(&self, request: UpdateUserRequest) -> Result<()>;
+            async fn rename_user(&self, user_id: &UserId, new_user_id: &UserId) -> Result<()>;
+            async fn update_group(&self, request: UpdateGroupRequest) -> Result<()>;
+            async fn delete_user(&self, user_id: &UserId) -> Result<()>;
+            async fn create_group(&self, group_name: &str) -> Result<GroupId>;
+            async fn delete_group(&self, group_id: GroupId) -> Result<()>;
+            async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
+            async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
+            fn subscribe_to_changes(&self) -> ChangeReceiver;
+        }
+        #[async_trait]
+        impl OpaqueHandler for TestBackendHandler {
+            async fn login_start(
+                &self,
+                request: login::ClientLoginStartRequest
+            ) -> Result<login::ServerLoginStartResponse>;
+            async fn login_finish(&self, request: login::ClientLoginFinishRequest) -> Result<UserId>;
+            async fn registration_start(
+                &self,
+                request: registration::ClientRegistrationStartRequest
+            ) -> Result<registration::ServerRegistrationStartResponse>;
+            async fn registration_finish(
+                &self,
+                request: registration::ClientRegistrationFinishRequest
+            ) -> Result<()>;
+        }
+    }
+
+    fn make_search_request<S: Into<String>>(
+        base: &str,
+        filter: LdapFilter,
+        attrs: Vec<S>,
+    ) -> LdapSearchRequest {
+        LdapSearchRequest {
+            base: base.to_string(),
+            scope: LdapSearchScope::Base,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter,
+            attrs: attrs.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    fn make_user_search_request<S: Into<String>>(
+        filter: LdapFilter,
+        attrs: Vec<S>,
+    ) -> LdapSearchRequest {
+        LdapSearchRequest {
+            scope: LdapSearchScope::OneLevel,
+            ..make_search_request::<S>("ou=people,dc=example,dc=com", filter, attrs)
+        }
+    }
+
+    fn make_group_search_request<S: Into<String>>(
+        filter: LdapFilter,
+        attrs: Vec<S>,
+    ) -> LdapSearchRequest {
+        LdapSearchRequest {
+            scope: LdapSearchScope::OneLevel,
+            ..make_search_request::<S>("ou=groups,dc=example,dc=com", filter, attrs)
+        }
+    }
+
+    /// The backend doesn't change during the test, see `get_bound_user_groups`.
+    fn expect_no_changes(mock: &mut MockTestBackendHandler) {
+        let (_, receiver) = tokio::sync::watch::channel(0);
+        mock.expect_subscribe_to_changes()
+            .returning(move || receiver.clone());
+    }
+
+    async fn setup_bound_handler(
+        mock: MockTestBackendHandler,
+    ) -> LdapHandler<MockTestBackendHandler> {
+        setup_bound_handler_with_options(mock, LdapHandlerOptions::default()).await
+    }
+
+    async fn setup_bound_handler_with_options(
+        mut mock: MockTestBackendHandler,
+        options: LdapHandlerOptions,
+    ) -> LdapHandler<MockTestBackendHandler> {
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: UserId::new("test"),
+                password: "pass".to_string(),
+            }))
+            .return_once(|_| Ok(()));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("test"),
+            options,
+            None,
+        );
+        let request = LdapBindRequest {
+            dn: "cn=test,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+        ldap_handler
+    }
+
+    #[tokio::test]
+    async fn test_bind() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_bind()
+            .with(eq(crate::domain::handler::BindRequest {
+                name: UserId::new("bob"),
+                password: "pass".to_string(),
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("test"),
+            LdapHandlerOptions::default(),
+            None,
+        );
+
+        let request = LdapOp::BindRequest(LdapBindRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        });
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request, &[]).await,
+            Some(vec![LdapOp::BindResponse(LdapBindResponse {
+                res: LdapResult {
+                    code: LdapResultCode::Success,
+                    matcheddn: "".to_string(),
+                    message: "".to_string(),
+                    referral: vec![],
+                },
+                saslcreds: None,
+            })
+            .into()]),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_bind() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_bind()
+            .with(eq(crate::domain::handler::BindRequest {
+                name: UserId::new("test"),
+                password: "pass".to_string(),
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("test"),
+            LdapHandlerOptions::default(),
+            None,
+        );
+
+        let request = LdapBindRequest {
+            dn: "cn=test,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_non_admin_user() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_bind()
+            .with(eq(crate::domain::handler::BindRequest {
+                name: UserId::new("test"),
+                password: "pass".to_string(),
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![
+                UserRequestFilter::And(vec![]),
+                UserRequestFilter::UserId(UserId::new("test")),
+            ]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![User {
+                    display_name: "test".to_string(),
+                    ..Default::default()
+                }])
+            });
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions::default(),
+            None,
+        );
+
+        let request = LdapBindRequest {
+            dn: "cn=test,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+
+        let request =
+            make_user_search_request::<String>(LdapFilter::And(vec![]), vec!["1.1".to_string()]);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=test,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![],
+                }),
+                make_search_success()
+            ],
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_invalid_dn() {
+        let mock = MockTestBackendHandler::new();
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions::default(),
+            None,
+        );
+
+        let request = LdapBindRequest {
+            dn: "cn=bob,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::NamingViolation,
+        );
+        let request = LdapBindRequest {
+            dn: "cn=bob,ou=groups,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::NamingViolation,
+        );
+        let request = LdapBindRequest {
+            dn: "cn=bob,ou=people,dc=example,dc=fr".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::NamingViolation,
+        );
+        let request = LdapBindRequest {
+            dn: "cn=bob=test,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::NamingViolation,
+        );
+    }
+
+    #[test]
+    fn test_is_subtree() {
+        let subtree1 = &[
+            ("ou".to_string(), "people".to_string()),
+            ("dc".to_string(), "example".to_string()),
+            ("dc".to_string(), "com".to_string()),
+        ];
+        let root = &[
+            ("dc".to_string(), "example".to_string()),
+            ("dc".to_string(), "com".to_string()),
+        ];
+        assert!(is_subtree(subtree1, root));
+        assert!(!is_subtree(&[], root));
+    }
+
+    #[test]
+    fn test_parse_distinguished_name() {
+        let parsed_dn = &[
+            ("ou".to_string(), "people".to_string()),
+            ("dc".to_string(), "example".to_string()),
+            ("dc".to_string(), "com".to_string()),
+        ];
+        assert_eq!(
+            parse_distinguished_name("ou=people,dc=example,dc=com").expect("parsing failed"),
+            parsed_dn
+        );
+        assert_eq!(
+            parse_distinguished_name(" ou  = people , dc = example , dc =  com ")
+                .expect("parsing failed"),
+            parsed_dn
+        );
+        assert_eq!(
+            LdapDn::normalized("UID=Bob , OU=People,DC=Example,DC=Com").unwrap(),
+            LdapDn("uid=bob,ou=people,dc=example,dc=com".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_and_search_unnormalized_dn() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: UserId::new("test"),
+                password: "pass".to_string(),
+            }))
+            .return_once(|_| Ok(()));
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![]))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("test"),
+            LdapHandlerOptions::default(),
+            None,
+        );
+        let request = LdapBindRequest {
+            dn: "CN=Test , OU=People,DC=Example,DC=Com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+        assert_eq!(
+            ldap_handler.dn,
+            LdapDn("cn=test,ou=people,dc=example,dc=com".to_string())
+        );
+        let request = make_search_request(
+            "OU=People, DC=Example,DC=Com",
+            LdapFilter::And(vec![]),
+            vec!["objectClass"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "objectClass".to_string(),
+                        vals: vec!["top".to_string(), "organizationalUnit".to_string()],
+                    }],
+                }),
+                make_search_success()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_users() {
+        use chrono::prelude::*;
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![
+                User {
+                    user_id: UserId::new("bob_1"),
+                    email: "bob@bobmail.bob".to_string(),
+                    display_name: "Bôb Böbberson".to_string(),
+                    first_name: "Bôb".to_string(),
+                    last_name: "Böbberson".to_string(),
+                    ..Default::default()
+                },
+                User {
+                    user_id: UserId::new("jim"),
+                    email: "jim@cricket.jim".to_string(),
+                    display_name: "Jimminy Cricket".to_string(),
+                    first_name: "Jim".to_string(),
+                    last_name: "Cricket".to_string(),
+                    creation_date: Utc.ymd(2014, 7, 8).and_hms(9, 10, 11),
+                    ..Default::default()
+                },
+            ])
+        });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(
+            LdapFilter::And(vec![]),
+            vec![
+                "objectClass",
+                "dn",
+                "uid",
+                "mail",
+                "givenName",
+                "sn",
+                "cn",
+                "createTimestamp",
+            ],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=Bôb Böbberson,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "objectClass".to_string(),
+                            vals: vec![
+                                "inetOrgPerson".to_string(),
+                                "posixAccount".to_string(),
+                                "mailAccount".to_string(),
+                                "person".to_string()
+                            ]
+                        },
+                        LdapPartialAttribute {
+                            atype: "dn".to_string(),
+                            vals: vec!["cn=Bôb Böbberson,ou=people,dc=example,dc=com".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "uid".to_string(),
+                            vals: vec!["bob_1".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "mail".to_string(),
+                            vals: vec!["bob@bobmail.bob".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "givenName".to_string(),
+                            vals: vec!["Bôb".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "sn".to_string(),
+                            vals: vec!["Böbberson".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec!["Bôb Böbberson".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "createTimestamp".to_string(),
+                            vals: vec!["19700101000000Z".to_string()]
+                        }
+                    ],
+                }),
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=Jimminy Cricket,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "objectClass".to_string(),
+                            vals: vec![
+                                "inetOrgPerson".to_string(),
+                                "posixAccount".to_string(),
+                                "mailAccount".to_string(),
+                                "person".to_string()
+                            ]
+                        },
+                        LdapPartialAttribute {
+                            atype: "dn".to_string(),
+                            vals: vec!["cn=Jimminy Cricket,ou=people,dc=example,dc=com".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "uid".to_string(),
+                            vals: vec!["jim".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "mail".to_string(),
+                            vals: vec!["jim@cricket.jim".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "givenName".to_string(),
+                            vals: vec!["Jim".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "sn".to_string(),
+                            vals: vec!["Cricket".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec!["Jimminy Cricket".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "createTimestamp".to_string(),
+                            vals: vec!["20140708091011Z".to_string()]
+                        }
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_groups() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::And(vec![]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![
+                    Group {
+                        id: GroupId(1),
+                        display_name: "group_1".to_string(),
+                        users: vec![UserId::new("bob"), UserId::new("john")],
+                    },
+                    Group {
+                        id: GroupId(3),
+                        display_name: "bestgroup".to_string(),
+                        users: vec![UserId::new("john")],
+                    },
+                ])
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_group_search_request(
+            LdapFilter::And(vec![]),
+            vec!["objectClass", "dn", "cn", "uniqueMember"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "objectClass".to_string(),
+                            vals: vec![
+                                "groupOfNames".to_string(),
+                                "groupOfUniqueNames".to_string(),
+                                "posixGroup".to_string()
+                            ]
+                        },
+                        LdapPartialAttribute {
+                            atype: "dn".to_string(),
+                            vals: vec!["cn=group_1,ou=groups,dc=example,dc=com".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec!["group_1".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "uniqueMember".to_string(),
+                            vals: vec![
+                                "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                                "cn=john,ou=people,dc=example,dc=com".to_string(),
+                            ]
+                        },
+                    ],
+                }),
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=bestgroup,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "objectClass".to_string(),
+                            vals: vec![
+                                "groupOfNames".to_string(),
+                                "groupOfUniqueNames".to_string(),
+                                "posixGroup".to_string()
+                            ]
+                        },
+                        LdapPartialAttribute {
+                            atype: "dn".to_string(),
+                            vals: vec!["cn=bestgroup,ou=groups,dc=example,dc=com".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec!["bestgroup".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "uniqueMember".to_string(),
+                            vals: vec!["cn=john,ou=people,dc=example,dc=com".to_string()]
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_groups_filter() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::And(vec![
+                GroupRequestFilter::DisplayName("group_1".to_string()),
+                GroupRequestFilter::Member(UserId::new("bob")),
+                GroupRequestFilter::And(vec![]),
+                GroupRequestFilter::And(vec![]),
+            ]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![Group {
+                    display_name: "group_1".to_string(),
+                    id: GroupId(1),
+                    users: vec![],
+                }])
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_group_search_request(
+            LdapFilter::And(vec![
+                LdapFilter::Equality("cn".to_string(), "group_1".to_string()),
+                LdapFilter::Equality(
+                    "uniqueMember".to_string(),
+                    "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                ),
+                LdapFilter::Equality("objectclass".to_string(), "groupOfUniqueNames".to_string()),
+                LdapFilter::Equality("objectclass".to_string(), "groupOfNames".to_string()),
+            ]),
+            vec!["1.1"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_groups_filter_2() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::Or(vec![
+                GroupRequestFilter::Not(Box::new(GroupRequestFilter::DisplayName(
+                    "group_2".to_string(),
+                ))),
+            ]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![Group {
+                    display_name: "group_1".to_string(),
+                    id: GroupId(1),
+                    users: vec![],
+                }])
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_group_search_request(
+            LdapFilter::Or(vec![LdapFilter::Not(Box::new(LdapFilter::Equality(
+                "displayname".to_string(),
+                "group_2".to_string(),
+            )))]),
+            vec!["cn"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "cn".to_string(),
+                        vals: vec!["group_1".to_string()]
+                    },],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_groups_error() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::Or(vec![
+                GroupRequestFilter::Not(Box::new(GroupRequestFilter::DisplayName(
+                    "group_2".to_string(),
+                ))),
+            ]))))
+            .times(1)
+            .return_once(|_| {
+                Err(crate::domain::error::DomainError::InternalError(
+                    "Error getting groups".to_string(),
+                ))
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_group_search_request(
+            LdapFilter::Or(vec![LdapFilter::Not(Box::new(LdapFilter::Equality(
+                "displayname".to_string(),
+                "group_2".to_string(),
+            )))]),
+            vec!["cn"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_error(
+                LdapResultCode::Other,
+                r#"Error while listing groups "ou=groups,dc=example,dc=com": Internal error: `Error getting groups`"#.to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_groups_filter_error() {
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
+        let request = make_group_search_request(
+            LdapFilter::And(vec![LdapFilter::Equality(
+                "whatever".to_string(),
+                "group_1".to_string(),
+            )]),
+            vec!["cn"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_error(
+                LdapResultCode::UnwillingToPerform,
+                "Unsupported group filter: Unknown field: whatever".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_filters() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![
+                UserRequestFilter::Or(vec![
+                    UserRequestFilter::Not(Box::new(UserRequestFilter::UserId(UserId::new("bob")))),
+                    UserRequestFilter::And(vec![]),
+                    UserRequestFilter::Not(Box::new(UserRequestFilter::And(vec![]))),
+                    UserRequestFilter::And(vec![]),
+                    UserRequestFilter::And(vec![]),
+                    UserRequestFilter::Not(Box::new(UserRequestFilter::And(vec![]))),
+                ]),
+            ]))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(
+            LdapFilter::And(vec![LdapFilter::Or(vec![
+                LdapFilter::Not(Box::new(LdapFilter::Equality(
+                    "uid".to_string(),
+                    "bob".to_string(),
+                ))),
+                LdapFilter::Equality("objectclass".to_string(), "person".to_string()),
+                LdapFilter::Equality("objectclass".to_string(), "other".to_string()),
+                LdapFilter::Present("objectClass".to_string()),
+                LdapFilter::Present("uid".to_string()),
+                LdapFilter::Present("unknown".to_string()),
+            ])]),
+            vec!["objectClass"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_success()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_substring_filters() {
+        let substring =
+            |initial: Option<&str>, any: &[&str], final_: Option<&str>| LdapSubstringFilter {
+                initial: initial.map(str::to_string),
+                any: any.iter().map(|s| s.to_string()).collect(),
+                final_: final_.map(str::to_string),
+            };
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::Or(vec![
+                UserRequestFilter::SubString(
+                    "user_id".to_string(),
+                    SubStringFilter {
+                        initial: Some("bo".to_string()),
+                        ..Default::default()
+                    },
+                ),
+                UserRequestFilter::SubString(
+                    "email".to_string(),
+                    SubStringFilter {
+                        any: vec!["bob".to_string()],
+                        final_: Some("@example.com".to_string()),
+                        ..Default::default()
+                    },
+                ),
+                UserRequestFilter::And(vec![]),
+                UserRequestFilter::Not(Box::new(UserRequestFilter::And(vec![]))),
+            ]))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(
+            LdapFilter::Or(vec![
+                LdapFilter::Substring("uid".to_string(), substring(Some("bo"), &[], None)),
+                LdapFilter::Substring(
+                    "mail".to_string(),
+                    substring(None, &["bob"], Some("@example.com")),
+                ),
+                LdapFilter::Substring("objectClass".to_string(), substring(None, &["ORG"], None)),
+                LdapFilter::Substring(
+                    "objectClass".to_string(),
+                    substring(Some("group"), &[], None),
+                ),
+            ]),
+            vec!["objectClass"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_success()]
+        );
+        let request = make_user_search_request(
+            LdapFilter::Substring("avatar".to_string(), substring(Some("a"), &[], None)),
+            vec!["objectClass"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_error(
+                LdapResultCode::UnwillingToPerform,
+                r#"Unsupported user filter: Unsupported user attribute for substring filter: "avatar""#
+                    .to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_member_of() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::MemberOf("group_1".to_string()))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(
+            LdapFilter::Equality(
+                "memberOf".to_string(),
+                "cn=group_1, ou=groups, dc=example,dc=com".to_string(),
+            ),
+            vec!["objectClass"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_success()]
+        );
+        let request = make_user_search_request(
+            LdapFilter::Equality("memberOf".to_string(), "group_1".to_string()),
+            vec!["objectClass"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_error(
+                LdapResultCode::UnwillingToPerform,
+                "Unsupported user filter: while parsing a group ID: Missing DN value".to_string()
+            )]
+        );
+        let request = make_user_search_request(
+            LdapFilter::Equality(
+                "memberOf".to_string(),
+                "cn=mygroup,dc=example,dc=com".to_string(),
+            ),
+            vec!["objectClass"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_error(
+                LdapResultCode::UnwillingToPerform,
+                "Unsupported user filter: Unexpected group DN format. Got \"cn=mygroup,dc=example,dc=com\", expected: \"cn=groupname,ou=groups,dc=example,dc=com\"".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_filters_lowercase() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![
+                UserRequestFilter::Or(vec![UserRequestFilter::Not(Box::new(
+                    UserRequestFilter::Equality("first_name".to_string(), "bob".to_string()),
+                ))]),
+            ]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![User {
+                    display_name: "bob_1".to_string(),
+                    ..Default::default()
+                }])
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(
+            LdapFilter::And(vec![LdapFilter::Or(vec![LdapFilter::Not(Box::new(
+                LdapFilter::Equality("givenname".to_string(), "bob".to_string()),
+            ))])]),
+            vec!["objectclass"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=bob_1,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "objectclass".to_string(),
+                        vals: vec![
+                            "inetOrgPerson".to_string(),
+                            "posixAccount".to_string(),
+                            "mailAccount".to_string(),
+                            "person".to_string()
+                        ]
+                    },]
+                }),
+                make_search_success()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_both() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![User {
+                user_id: UserId::new("bob_1"),
+                email: "bob@bobmail.bob".to_string(),
+                display_name: "Bôb Böbberson".to_string(),
+                first_name: "Bôb".to_string(),
+                last_name: "Böbberson".to_string(),
+                ..Default::default()
+            }])
+        });
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::And(vec![]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![Group {
+                    id: GroupId(1),
+                    display_name: "group_1".to_string(),
+                    users: vec![UserId::new("bob"), UserId::new("john")],
+                }])
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = LdapSearchRequest {
+            scope: LdapSearchScope::Subtree,
+            ..make_search_request(
+                "dc=example,dc=com",
+                LdapFilter::And(vec![]),
+                vec!["objectClass", "dn", "cn"],
+            )
+        };
+        let container = |dn: &str, object_class: &str| {
+            LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                dn: dn.to_string(),
+                attributes: vec![LdapPartialAttribute {
+                    atype: "objectClass".to_string(),
+                    vals: vec!["top".to_string(), object_class.to_string()],
+                }],
+            })
+        };
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                container("dc=example,dc=com", "domain"),
+                container("ou=people,dc=example,dc=com", "organizationalUnit"),
+                container("ou=groups,dc=example,dc=com", "organizationalUnit"),
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=Bôb Böbberson,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "objectClass".to_string(),
+                            vals: vec![
+                                "inetOrgPerson".to_string(),
+                                "posixAccount".to_string(),
+                                "mailAccount".to_string(),
+                                "person".to_string()
+                            ]
+                        },
+                        LdapPartialAttribute {
+                            atype: "dn".to_string(),
+                            vals: vec!["cn=Bôb Böbberson,ou=people,dc=example,dc=com".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec!["Bôb Böbberson".to_string()]
+                        },
+                    ],
+                }),
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "objectClass".to_string(),
+                            vals: vec![
+                                "groupOfNames".to_string(),
+                                "groupOfUniqueNames".to_string(),
+                                "posixGroup".to_string()
+                            ]
+                        },
+                        LdapPartialAttribute {
+                            atype: "dn".to_string(),
+                            vals: vec!["cn=group_1,ou=groups,dc=example,dc=com".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec!["group_1".to_string()]
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_wrong_base() {
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
+        let no_such_object = |base: &str, matched_dn: &str| {
+            vec![LdapOp::SearchResultDone(LdapResult {
+                code: LdapResultCode::NoSuchObject,
+                matcheddn: matched_dn.to_string(),
+                message: format!(r#"No such entry: "{}""#, base),
+                referral: vec![],
+            })]
+        };
+        for (base, matched_dn) in [
+            ("ou=users,dc=example,dc=com", "dc=example,dc=com"),
+            ("ou=a,ou=ppl,dc=example,dc=com", "dc=example,dc=com"),
+            (
+                "ou=x,cn=bob,ou=people,dc=example,dc=com",
+                "ou=people,dc=example,dc=com",
+            ),
+            (
+                "cn=a,ou=x,ou=Groups,dc=example,dc=com",
+                "ou=Groups,dc=example,dc=com",
+            ),
+        ] {
+            let request = make_search_request(base, LdapFilter::And(vec![]), vec!["objectClass"]);
+            assert_eq!(
+                ldap_handler.do_search(&request).await,
+                no_such_object(base, matched_dn),
+                "{}",
+                base
+            );
+        }
+        // Outside of the tree, and the children of the entries in the containers, are not
+        // checked.
+        for base in ["dc=other,dc=com", "cn=bob,ou=people,dc=example,dc=com"] {
+            let request = LdapSearchRequest {
+                scope: LdapSearchScope::OneLevel,
+                ..make_search_request(base, LdapFilter::And(vec![]), vec!["objectClass"])
+            };
+            assert_eq!(
+                ldap_handler.do_search(&request).await,
+                vec![make_search_success()],
+                "{}",
+                base
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_unsupported_filters() {
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
+        let request = make_user_search_request(
+            LdapFilter::Substring("unknown".to_string(), LdapSubstringFilter::default()),
+            vec!["objectClass"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_error(
+                LdapResultCode::UnwillingToPerform,
+                "Unsupported user filter: Unknown field: unknown".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_password_change() {
+        let mut mock = MockTestBackendHandler::new();
+        use lldap_auth::*;
+        let mut rng = rand::rngs::OsRng;
+        let registration_start_request =
+            opaque::client::registration::start_registration("password", &mut rng).unwrap();
+        let request = registration::ClientRegistrationStartRequest {
+            username: "bob".to_string(),
+            registration_start_request: registration_start_request.message,
+        };
+        let start_response = opaque::server::registration::start_registration(
+            &opaque::server::ServerSetup::new(&mut rng),
+            request.registration_start_request,
+            &request.username,
+        )
+        .unwrap();
+        mock.expect_registration_start().times(1).return_once(|_| {
+            Ok(registration::ServerRegistrationStartResponse {
+                server_data: "".to_string(),
+                registration_response: start_response.message,
+            })
+        });
+        mock.expect_registration_finish()
+            .times(1)
+            .return_once(|_| Ok(()));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = LdapOp::ExtendedRequest(
+            LdapPasswordModifyRequest {
+                user_identity: Some("cn=bob,ou=people,dc=example,dc=com".to_string()),
+                old_password: None,
+                new_password: Some("password".to_string()),
+            }
+            .into(),
+        );
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request, &[]).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::Success,
+                "".to_string(),
+            )
+            .into()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_password_change_errors() {
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
+        let request = LdapOp::ExtendedRequest(
+            LdapPasswordModifyRequest {
+                user_identity: None,
+                old_password: None,
+                new_password: None,
+            }
+            .into(),
+        );
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request, &[]).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::ConstraintViolation,
+                "Missing either user_id or password".to_string(),
+            )
+            .into()])
+        );
+        let request = LdapOp::ExtendedRequest(
+            LdapPasswordModifyRequest {
+                user_identity: Some("cn=bob,ou=groups,ou=people,dc=example,dc=com".to_string()),
+                old_password: None,
+                new_password: Some("password".to_string()),
+            }
+            .into(),
+        );
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request, &[]).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::InvalidDNSyntax,
+                r#"Invalid username: "Unexpected user DN format. Got \"cn=bob,ou=groups,ou=people,dc=example,dc=com\", expected: \"uid=username,ou=people,dc=example,dc=com\"""#.to_string(),
+            ).into()])
+        );
+        let request = LdapOp::ExtendedRequest(LdapExtendedRequest {
+            name: "test".to_string(),
+            value: None,
+        });
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request, &[]).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::UnwillingToPerform,
+                "Unsupported extended operation: test".to_string(),
+            )
+            .into()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_root_dse() {
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
+        let request = LdapSearchRequest {
+            base: "".to_string(),
+            scope: LdapSearchScope::Base,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter: LdapFilter::Present("objectClass".to_string()),
+            attrs: vec!["supportedExtension".to_string()],
+        };
+        let mut responses = ldap_handler.do_search(&request).await;
+        // The admins also get the information about the server.
+        match &mut responses[0] {
+            LdapOp::SearchResultEntry(entry) => {
+                let server_info = entry.attributes.split_off(entry.attributes.len() - 3);
+                assert_eq!(
+                    server_info[0],
+                    LdapPartialAttribute {
+                        atype: "vendorVersion".to_string(),
+                        vals: vec![format!("lldap_{}", env!("CARGO_PKG_VERSION"))],
+                    }
+                );
+                assert_eq!(server_info[1].atype, "startTime".to_string(),);
+                assert_eq!(server_info[2].atype, "serverUptimeSeconds".to_string());
+                assert!(server_info[2].vals[0].parse::<u64>().is_ok());
+            }
+            op => panic!("Unexpected root DSE: {:?}", op),
+        }
+        assert_eq!(
+            responses,
+            vec![
+                root_dse_response("dc=example,dc=com", &LdapHandlerOptions::default()),
+                make_search_success()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_start_tls() {
+        let mut ldap_handler = LdapHandler::new(
+            MockTestBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions {
+                start_tls_available: true,
+                ..Default::default()
+            },
+            None,
+        );
+        let request = LdapOp::ExtendedRequest(LdapExtendedRequest {
+            name: START_TLS_OID.to_string(),
+            value: None,
+        });
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request.clone(), &[]).await,
+            Some(vec![LdapOp::ExtendedResponse(LdapExtendedResponse {
+                res: LdapResult {
+                    code: LdapResultCode::Success,
+                    matcheddn: "".to_string(),
+                    message: "".to_string(),
+                    referral: vec![],
+                },
+                name: Some(START_TLS_OID.to_string()),
+                value: None,
+            })
+            .into()])
+        );
+        assert!(ldap_handler.take_start_tls_request());
+        assert!(!ldap_handler.take_start_tls_request());
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request, &[]).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::OperationsError,
+                "TLS is already established on this connection".to_string(),
+            )
+            .into()])
+        );
+        assert!(!ldap_handler.take_start_tls_request());
+    }
+
+    #[tokio::test]
+    async fn test_start_tls_errors() {
+        let request = LdapOp::ExtendedRequest(LdapExtendedRequest {
+            name: START_TLS_OID.to_string(),
+            value: None,
+        });
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request.clone(), &[]).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::Unavailable,
+                "StartTLS is not available on this server".to_string(),
+            )
+            .into()])
+        );
+        let mut ldap_handler = setup_bound_handler_with_options(
+            MockTestBackendHandler::new(),
+            LdapHandlerOptions {
+                start_tls_available: true,
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request, &[]).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::OperationsError,
+                "StartTLS is not allowed after a bind".to_string(),
+            )
+            .into()])
+        );
+        assert!(!ldap_handler.take_start_tls_request());
+    }
+
+    #[tokio::test]
+    async fn test_whoami() {
+        let make_whoami_response = |authz_id: &str| {
+            Some(vec![LdapOp::ExtendedResponse(LdapExtendedResponse {
+                res: LdapResult {
+                    code: LdapResultCode::Success,
+                    matcheddn: "".to_string(),
+                    message: "".to_string(),
+                    referral: vec![],
+                },
+                name: None,
+                value: Some(authz_id.as_bytes().to_vec()),
+            })
+            .into()])
+        };
+        let request = LdapOp::ExtendedRequest(LdapExtendedRequest {
+            name: WHOAMI_OID.to_string(),
+            value: None,
+        });
+        let mut ldap_handler = LdapHandler::new(
+            MockTestBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions::default(),
+            None,
+        );
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request.clone(), &[]).await,
+            make_whoami_response("")
+        );
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request, &[]).await,
+            make_whoami_response("dn:cn=test,ou=people,dc=example,dc=com")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unbind() {
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(LdapOp::UnbindRequest, &[])
+                .await,
+            None
+        );
+        // The session is anonymous again.
+        assert_eq!(ldap_handler.dn, LdapDn("unauthenticated".to_string()));
+        assert_eq!(ldap_handler.user_id, UserId::new("unauthenticated"));
+        let request = LdapOp::ExtendedRequest(LdapExtendedRequest {
+            name: WHOAMI_OID.to_string(),
+            value: None,
+        });
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request, &[]).await,
+            Some(vec![LdapOp::ExtendedResponse(LdapExtendedResponse {
+                res: LdapResult {
+                    code: LdapResultCode::Success,
+                    matcheddn: "".to_string(),
+                    message: "".to_string(),
+                    referral: vec![],
+                },
+                name: None,
+                value: Some(vec![]),
+            })
+            .into()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_paged_search() {
+        let make_paging_control = |size: i64, cookie: &[u8]| RawControl {
+            oid: PAGED_RESULTS_OID.to_string(),
+            criticality: true,
+            value: Some(
+                BerElement::sequence(&[
+                    BerElement::integer(size),
+                    BerElement::octet_string(cookie),
+                ])
+                .encode(),
+            ),
+        };
+        let make_entry = |name: &str| {
+            LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                dn: format!("cn={},ou=people,dc=example,dc=com", name),
+                attributes: vec![LdapPartialAttribute {
+                    atype: "uid".to_string(),
+                    vals: vec![name.to_string()],
+                }],
+            })
+        };
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(["bob", "jim", "tom"]
+                .iter()
+                .map(|name| User {
+                    user_id: UserId::new(name),
+                    display_name: name.to_string(),
+                    ..Default::default()
+                })
+                .collect())
+        });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = LdapOp::SearchRequest(make_user_search_request(
+            LdapFilter::And(vec![]),
+            vec!["uid"],
+        ));
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(request.clone(), &[make_paging_control(2, b"")])
+                .await,
+            Some(vec![
+                make_entry("bob").into(),
+                make_entry("jim").into(),
+                LdapResponse {
+                    op: make_search_success().into(),
+                    controls: vec![make_paged_results_control(b"1".to_vec())],
+                },
+            ])
+        );
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(request.clone(), &[make_paging_control(2, b"1")])
+                .await,
+            Some(vec![
+                make_entry("tom").into(),
+                LdapResponse {
+                    op: make_search_success().into(),
+                    controls: vec![make_paged_results_control(vec![])],
+                },
+            ])
+        );
+        // The cookie can't be reused once the search is over.
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(request, &[make_paging_control(2, b"1")])
+                .await,
+            Some(vec![make_search_error(
+                LdapResultCode::UnwillingToPerform,
+                "Unknown paged results cookie".to_string(),
+            )
+            .into()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_paged_search_state() {
+        let make_paging_control = |cookie: &[u8]| RawControl {
+            oid: PAGED_RESULTS_OID.to_string(),
+            criticality: true,
+            value: Some(
+                BerElement::sequence(&[BerElement::integer(1), BerElement::octet_string(cookie)])
+                    .encode(),
+            ),
+        };
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().returning(|_| {
+            Ok(["bob", "jim"]
+                .iter()
+                .map(|name| User {
+                    user_id: UserId::new(name),
+                    display_name: name.to_string(),
+                    ..Default::default()
+                })
+                .collect())
+        });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = LdapOp::SearchRequest(make_user_search_request(
+            LdapFilter::And(vec![]),
+            vec!["uid"],
+        ));
+        let unknown_cookie = Some(vec![make_search_error(
+            LdapResultCode::UnwillingToPerform,
+            "Unknown paged results cookie".to_string(),
+        )
+        .into()]);
+        ldap_handler
+            .handle_ldap_message(request.clone(), &[make_paging_control(b"")])
+            .await;
+        // The next pages must be for the same search.
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(
+                    LdapOp::SearchRequest(make_user_search_request(
+                        LdapFilter::Equality("uid".to_string(), "bob".to_string()),
+                        vec!["uid"],
+                    )),
+                    &[make_paging_control(b"1")]
+                )
+                .await,
+            Some(vec![make_search_error(
+                LdapResultCode::UnwillingToPerform,
+                "The paged results cookie is for another search".to_string(),
+            )
+            .into()])
+        );
+        // Only the last searches are kept.
+        for _ in 0..=MAX_PAGED_SEARCHES {
+            ldap_handler
+                .handle_ldap_message(request.clone(), &[make_paging_control(b"")])
+                .await;
+        }
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(request.clone(), &[make_paging_control(b"2")])
+                .await,
+            unknown_cookie
+        );
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(request.clone(), &[make_paging_control(b"3")])
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+        // The searches are dropped on unbind.
+        ldap_handler
+            .handle_ldap_request(LdapRequest::Op(LdapOp::UnbindRequest), &[])
+            .await;
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(request, &[make_paging_control(b"4")])
+                .await,
+            unknown_cookie
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_size_limit() {
+        let make_mock = || {
+            let mut mock = MockTestBackendHandler::new();
+            mock.expect_list_users().times(1).return_once(|_| {
+                Ok(vec![
+                    User {
+                        user_id: UserId::new("bob"),
+                        display_name: "bob".to_string(),
+                        ..Default::default()
+                    },
+                    User {
+                        user_id: UserId::new("jim"),
+                        display_name: "jim".to_string(),
+                        ..Default::default()
+                    },
+                ])
+            });
+            mock
+        };
+        let expected = vec![
+            LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                attributes: vec![LdapPartialAttribute {
+                    atype: "uid".to_string(),
+                    vals: vec!["bob".to_string()],
+                }],
+            }),
+            make_search_error(
+                LdapResultCode::SizeLimitExceeded,
+                "Size limit exceeded: more than 1 entries".to_string(),
+            ),
+        ];
+        let mut request = make_user_search_request(LdapFilter::And(vec![]), vec!["uid"]);
+        request.sizelimit = 1;
+        let mut ldap_handler = setup_bound_handler(make_mock()).await;
+        assert_eq!(ldap_handler.do_search(&request).await, expected);
+        // The server limit applies even if the client asks for an unlimited search.
+        request.sizelimit = 0;
+        let mut ldap_handler = setup_bound_handler_with_options(
+            make_mock(),
+            LdapHandlerOptions {
+                max_size_limit: Some(1),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(ldap_handler.do_search(&request).await, expected);
+        // No truncation when the limit isn't reached.
+        request.sizelimit = 5;
+        let mut ldap_handler = setup_bound_handler(make_mock()).await;
+        assert_eq!(ldap_handler.do_search(&request).await.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_bind_errors() {
+        let cases: Vec<(fn() -> DomainError, LdapResultCode)> = vec![
+            (
+                || DomainError::AuthenticationError("bob".to_string()),
+                LdapResultCode::InvalidCredentials,
+            ),
+            (
+                || DomainError::DatabaseError(sqlx::Error::PoolTimedOut),
+                LdapResultCode::Unavailable,
+            ),
+            (
+                || DomainError::InternalError("oops".to_string()),
+                LdapResultCode::Other,
+            ),
+        ];
+        for (make_error, expected_code) in cases {
+            let mut mock = MockTestBackendHandler::new();
+            mock.expect_bind()
+                .times(1)
+                .return_once(move |_| Err(make_error()));
+            let mut ldap_handler = LdapHandler::new(
+                mock,
+                "dc=example,dc=com".to_string(),
+                UserId::new("admin"),
+                LdapHandlerOptions::default(),
+                None,
+            );
+            let (code, _) = ldap_handler
+                .do_bind(&LdapBindRequest {
+                    dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                    cred: LdapBindCred::Simple("pass".to_string()),
+                })
+                .await;
+            assert_eq!(code, expected_code);
+            assert_eq!(ldap_handler.dn, LdapDn("unauthenticated".to_string()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_bind() {
+        let anonymous_bind = LdapBindRequest {
+            dn: "".to_string(),
+            cred: LdapBindCred::Simple("".to_string()),
+        };
+        let mut ldap_handler = LdapHandler::new(
+            MockTestBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions::default(),
+            None,
+        );
+        assert_eq!(
+            ldap_handler.do_bind(&anonymous_bind).await.0,
+            LdapResultCode::InappropriateAuthentication
+        );
+        let mut mock = MockTestBackendHandler::new();
+        // The root DSE search doubles as a health check.
+        mock.expect_list_groups()
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = setup_bound_handler_with_options(
+            mock,
+            LdapHandlerOptions {
+                allow_anonymous_bind: true,
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(
+            ldap_handler
+                .do_bind(&LdapBindRequest {
+                    dn: "".to_string(),
+                    cred: LdapBindCred::Simple("pass".to_string()),
+                })
+                .await
+                .0,
+            LdapResultCode::InvalidCredentials
+        );
+        assert_eq!(
+            ldap_handler.do_bind(&anonymous_bind).await.0,
+            LdapResultCode::Success
+        );
+        assert_eq!(ldap_handler.dn, LdapDn("unauthenticated".to_string()));
+        // Only the root DSE can be read.
+        let mut request = make_search_request(
+            "",
+            LdapFilter::Present("objectClass".to_string()),
+            vec!["supportedExtension"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                root_dse_response("dc=example,dc=com", &LdapHandlerOptions::default()),
+                make_search_success()
+            ]
+        );
+        request.base = "ou=people,dc=example,dc=com".to_string();
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_error(
+                LdapResultCode::InsufficentAccessRights,
+                "Anonymous sessions can only read the root DSE and the schema".to_string(),
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_root_dse_capabilities() {
+        let options = LdapHandlerOptions {
+            start_tls_available: true,
+            ..Default::default()
+        };
+        let get_values = |attribute: &str| match root_dse_response("dc=example,dc=com", &options) {
+            LdapOp::SearchResultEntry(entry) => entry
+                .attributes
+                .into_iter()
+                .find(|a| a.atype == attribute)
+                .map(|a| a.vals),
+            _ => panic!("Unexpected root DSE response"),
+        };
+        assert_eq!(
+            get_values("supportedExtension"),
+            Some(vec![
+                PASSWORD_MODIFY_OID.to_string(),
+                WHOAMI_OID.to_string(),
+                CANCEL_OID.to_string(),
+                CAPABILITIES_OID.to_string(),
+                SCHEMA_LDIF_OID.to_string(),
+                START_TLS_OID.to_string()
+            ])
+        );
+        assert_eq!(
+            get_values("supportedControl"),
+            Some(vec![
+                PAGED_RESULTS_OID.to_string(),
+                MANAGE_DSA_IT_OID.to_string(),
+                ASSERTION_OID.to_string(),
+                DONT_USE_COPY_OID.to_string(),
+                PRE_READ_OID.to_string(),
+                POST_READ_OID.to_string(),
+                SORT_REQUEST_OID.to_string(),
+                MATCHED_VALUES_OID.to_string(),
+                SYNC_REQUEST_OID.to_string(),
+            ])
+        );
+        assert_eq!(
+            get_values("namingContexts"),
+            Some(vec!["dc=example,dc=com".to_string()])
+        );
+        assert_eq!(get_values("vendorName"), Some(vec!["LLDAP".to_string()]));
+        assert_eq!(
+            get_values("supportedLDAPVersion"),
+            Some(vec!["3".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_schema() {
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
+        for base in ["cn=schema", "cn=Schema,dc=example,dc=com"] {
+            let request = make_search_request(
+                base,
+                LdapFilter::Present("objectClass".to_string()),
+                vec!["objectClasses", "attributeTypes"],
+            );
+            let results = ldap_handler.do_search(&request).await;
+            assert_eq!(
+                results,
+                vec![ldap_handler.schema_entry(base), make_search_success()]
+            );
+        }
+        match ldap_handler.schema_entry("cn=schema") {
+            LdapOp::SearchResultEntry(entry) => {
+                let object_classes = &entry
+                    .attributes
+                    .iter()
+                    .find(|a| a.atype == "objectClasses")
+                    .unwrap()
+                    .vals;
+                assert!(object_classes.iter().any(|c| c.contains("'inetOrgPerson'")));
+                assert!(object_classes.iter().any(|c| c.contains("'groupOfNames'")));
+                // Not a standard class, but one of the default user object classes.
+                assert!(object_classes
+                    .iter()
+                    .any(|c| c == "( mailAccount-oid NAME 'mailAccount' SUP top AUXILIARY )"));
+            }
+            _ => panic!("Unexpected schema response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_export_schema() {
+        let mut ldap_handler = LdapHandler::new(
+            MockTestBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions {
+                hidden_attributes: vec!["mail".to_string()],
+                ..Default::default()
+            },
+            None,
+        );
+        let request = LdapOp::ExtendedRequest(LdapExtendedRequest {
+            name: SCHEMA_LDIF_OID.to_string(),
+            value: None,
+        });
+        let response = ldap_handler
+            .handle_ldap_message(request, &[])
+            .await
+            .unwrap();
+        let ldif = match &response[0].op {
+            LdapResponseOp::Op(LdapOp::ExtendedResponse(response)) => {
+                assert_eq!(response.res.code, LdapResultCode::Success);
+                String::from_utf8(response.value.clone().unwrap()).unwrap()
+            }
+            op => panic!("Unexpected response: {:?}", op),
+        };
+        assert!(ldif.starts_with("dn: cn=schema\nobjectClass: top\n"));
+        assert!(ldif.contains("\nmatchingRules: ( 2.5.13.2 NAME 'caseIgnoreMatch' "));
+        assert!(
+            ldif.contains("\nattributeTypes: ( 2.5.4.3 NAME ( 'cn' 'commonName' ) SUP name )\n")
+        );
+        // The hidden attributes are not in the schema.
+        assert!(!ldif.contains("'mail'"));
+        assert!(ldif.lines().all(|line| line.len() <= 76));
+    }
+
+    #[tokio::test]
+    async fn test_sasl_external_bind() {
+        let request = SaslBindRequest {
+            dn: "".to_string(),
+            mechanism: "EXTERNAL".to_string(),
+            credentials: None,
+        };
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_user_details()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .return_once(|_| Ok(User::default()));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions::default(),
+            None,
+        );
+        assert_eq!(
+            ldap_handler.do_sasl_bind(&request).await.0,
+            LdapResultCode::InappropriateAuthentication
+        );
+        assert_eq!(
+            ldap_handler
+                .do_sasl_bind(&SaslBindRequest {
+                    mechanism: "GSSAPI".to_string(),
+                    ..request.clone()
+                })
+                .await
+                .0,
+            LdapResultCode::AuthMethodNotSupported
+        );
+        // A minimal certificate, with only the fields that are read.
+        let subject = BerElement::sequence(&[BerElement::constructed(
+            crate::infra::ber::TAG_SET,
+            &[BerElement::sequence(&[
+                BerElement {
+                    tag: crate::infra::ber::TAG_OID,
+                    value: vec![0x55, 0x04, 0x03],
+                },
+                BerElement::octet_string("bob"),
+            ])],
+        )]);
+        let empty = BerElement::sequence(&[]);
+        let certificate = BerElement::sequence(&[BerElement::sequence(&[
+            BerElement::integer(1),
+            empty.clone(),
+            empty.clone(),
+            empty.clone(),
+            subject,
+            empty,
+        ])]);
+        ldap_handler.set_client_certificate(Some(certificate.encode()));
+        assert_eq!(
+            ldap_handler.do_sasl_bind(&request).await,
+            (LdapResultCode::Success, "".to_string())
+        );
+        assert_eq!(
+            ldap_handler.dn,
+            LdapDn("cn=bob,ou=people,dc=example,dc=com".to_string())
+        );
+        assert_eq!(ldap_handler.user_id, UserId::new("bob"));
+    }
+
+    #[tokio::test]
+    async fn test_compare() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::UserId(UserId::new("bob")))))
+            .times(2)
+            .returning(|_| {
+                Ok(vec![User {
+                    user_id: UserId::new("bob"),
+                    email: "bob@example.com".to_string(),
+                    ..Default::default()
+                }])
+            });
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::UserId(UserId::new("john")))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::DisplayName(
+                "group_1".to_string(),
+            ))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![Group {
+                    id: GroupId(1),
+                    display_name: "group_1".to_string(),
+                    users: vec![UserId::new("bob")],
+                }])
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let compare = |dn: &str, attribute: &str, value: &str| {
+            LdapRequest::Compare(CompareRequest {
+                dn: dn.to_string(),
+                attribute: attribute.to_string(),
+                value: value.as_bytes().to_vec(),
+            })
+        };
+        let expect = |code| {
+            Some(vec![LdapResponse {
+                op: make_compare_response(code, "".to_string()),
+                controls: vec![],
+            }])
+        };
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_request(
+                    compare(
+                        "uid=bob,ou=people,dc=example,dc=com",
+                        "mail",
+                        "Bob@Example.com"
+                    ),
+                    &[]
+                )
+                .await,
+            expect(LdapResultCode::CompareTrue)
+        );
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_request(
+                    compare(
+                        "uid=bob,ou=people,dc=example,dc=com",
+                        "mail",
+                        "jim@example.com"
+                    ),
+                    &[]
+                )
+                .await,
+            expect(LdapResultCode::CompareFalse)
+        );
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_request(
+                    compare("uid=john,ou=people,dc=example,dc=com", "mail", "john"),
+                    &[]
+                )
+                .await,
+            expect(LdapResultCode::NoSuchObject)
+        );
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_request(
+                    compare(
+                        "cn=group_1,ou=groups,dc=example,dc=com",
+                        "member",
+                        "cn=bob,ou=people,dc=example,dc=com"
+                    ),
+                    &[]
+                )
+                .await,
+            expect(LdapResultCode::CompareTrue)
+        );
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_request(compare("ou=other,dc=example,dc=com", "cn", "x"), &[])
+                .await,
+            expect(LdapResultCode::NoSuchObject)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compare_non_admin() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_bind().return_once(|_| Ok(()));
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![
+                UserRequestFilter::UserId(UserId::new("bob")),
+                UserRequestFilter::UserId(UserId::new("test")),
+            ]))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions::default(),
+            None,
+        );
+        let request = CompareRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            attribute: "mail".to_string(),
+            value: b"bob@example.com".to_vec(),
+        };
+        assert_eq!(
+            ldap_handler.do_compare(&request).await.0,
+            LdapResultCode::InsufficentAccessRights
+        );
+        let bind = LdapBindRequest {
+            dn: "cn=test,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(ldap_handler.do_bind(&bind).await.0, LdapResultCode::Success);
+        // Other users are not visible to a non-admin.
+        assert_eq!(
+            ldap_handler.do_compare(&request).await.0,
+            LdapResultCode::NoSuchObject
+        );
+    }
+
+    fn make_password_replace(dn: &str, password: &str) -> ModifyRequest {
+        ModifyRequest {
+            dn: dn.to_string(),
+            changes: vec![Modification {
+                operation: ModifyOperation::Replace,
+                attribute: "userPassword".to_string(),
+                values: vec![password.as_bytes().to_vec()],
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_modify_password() {
+        let mut mock = MockTestBackendHandler::new();
+        expect_no_changes(&mut mock);
+        use lldap_auth::*;
+        let mut rng = rand::rngs::OsRng;
+        let registration_start_request =
+            opaque::client::registration::start_registration("password", &mut rng).unwrap();
+        let start_response = opaque::server::registration::start_registration(
+            &opaque::server::ServerSetup::new(&mut rng),
+            registration_start_request.message,
+            "test",
+        )
+        .unwrap();
+        mock.expect_registration_start()
+            .withf(|request| request.username == "test")
+            .times(1)
+            .return_once(|_| {
+                Ok(registration::ServerRegistrationStartResponse {
+                    server_data: "".to_string(),
+                    registration_response: start_response.message,
+                })
+            });
+        mock.expect_registration_finish()
+            .times(1)
+            .return_once(|_| Ok(()));
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("test")))
+            .times(1)
+            .return_once(|_| Ok(HashSet::new()));
+        mock.expect_bind().return_once(|_| Ok(()));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions::default(),
+            None,
+        );
+        let bind = LdapBindRequest {
+            dn: "cn=test,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(ldap_handler.do_bind(&bind).await.0, LdapResultCode::Success);
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_request(
+                    LdapRequest::Modify(make_password_replace(
+                        "cn=test,ou=people,dc=example,dc=com",
+                        "password"
+                    )),
+                    &[]
+                )
+                .await,
+            Some(vec![LdapResponse {
+                op: make_modify_response(LdapResultCode::Success, "".to_string()),
+                controls: vec![],
+            }])
+        );
+        // Non-admins can't change the password of other users.
+        assert_eq!(
+            ldap_handler
+                .do_modify(
+                    &make_password_replace("cn=bob,ou=people,dc=example,dc=com", "password"),
+                    &[]
+                )
+                .await
+                .result()
+                .unwrap()
+                .code,
+            LdapResultCode::InsufficentAccessRights
+        );
+    }
+
+    #[tokio::test]
+    async fn test_modify_unsupported() {
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
+        let request = ModifyRequest {
+            dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+            changes: vec![Modification {
+                operation: ModifyOperation::Replace,
+                attribute: "mail".to_string(),
+                values: vec![b"bob@example.com".to_vec()],
+            }],
+        };
+        assert_eq!(
+            ldap_handler
+                .do_modify(&request, &[])
+                .await
+                .result()
+                .unwrap()
+                .code,
+            LdapResultCode::UnwillingToPerform
+        );
+        let mut request = make_password_replace("cn=bob,ou=people,dc=example,dc=com", "pass");
+        request.changes[0].operation = ModifyOperation::Add;
+        assert_eq!(
+            ldap_handler
+                .do_modify(&request, &[])
+                .await
+                .result()
+                .unwrap()
+                .code,
+            LdapResultCode::UnwillingToPerform
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_rate_limit() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_bind().times(1).return_once(|_| Ok(()));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("test"),
+            LdapHandlerOptions {
+                bind_rate_limiter: Some(Arc::new(BindRateLimiter::new(1))),
+                ..Default::default()
+            },
+            Some("10.0.0.1:1234".parse().unwrap()),
+        );
+        let request = LdapBindRequest {
+            dn: "cn=test,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+        assert_eq!(ldap_handler.do_bind(&request).await.0, LdapResultCode::Busy);
+    }
+
+    #[tokio::test]
+    async fn test_bind_failure_delay_reset() {
+        let mut mock = MockTestBackendHandler::new();
+        let mut calls = 0;
+        mock.expect_bind().times(3).returning(move |_| {
+            calls += 1;
+            if calls == 2 {
+                Ok(())
+            } else {
+                Err(DomainError::AuthenticationError("test".to_string()))
+            }
+        });
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("test"),
+            LdapHandlerOptions {
+                bind_failure_tracker: Some(Arc::new(BindFailureTracker::new(
+                    1,
+                    std::time::Duration::from_secs(10),
+                ))),
+                ..Default::default()
+            },
+            None,
+        );
+        let request = LdapBindRequest {
+            dn: "cn=test,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        let start = std::time::Instant::now();
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::InvalidCredentials
+        );
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+        // The successful bind reset the count: this failure is not delayed.
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::InvalidCredentials
+        );
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_search_manage_dsa_it() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![User {
+                user_id: UserId::new("bob"),
+                display_name: "Bob".to_string(),
+                ..Default::default()
+            }])
+        });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["uid"]);
+        let control = RawControl {
+            oid: MANAGE_DSA_IT_OID.to_string(),
+            criticality: true,
+            value: None,
+        };
+        let creation_date = to_generalized_time(&User::default().creation_date);
+        let entry_uuid = make_entry_uuid("user", "bob");
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(LdapOp::SearchRequest(request), &[control])
+                .await,
+            Some(vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=Bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "uid".to_string(),
+                            vals: vec!["bob".to_string()],
+                        },
+                        LdapPartialAttribute {
+                            atype: "createTimestamp".to_string(),
+                            vals: vec![creation_date.clone()],
+                        },
+                        LdapPartialAttribute {
+                            atype: "modifyTimestamp".to_string(),
+                            vals: vec![creation_date],
+                        },
+                        LdapPartialAttribute {
+                            atype: "entryUUID".to_string(),
+                            vals: vec![entry_uuid.clone()],
+                        },
+                        LdapPartialAttribute {
+                            atype: "entryDN".to_string(),
+                            vals: vec!["cn=Bob,ou=people,dc=example,dc=com".to_string()],
+                        },
+                        LdapPartialAttribute {
+                            atype: "hasSubordinates".to_string(),
+                            vals: vec!["FALSE".to_string()],
+                        },
+                    ],
+                })
+                .into(),
+                make_search_success().into(),
+            ])
+        );
+        // The UUID only depends on the user ID.
+        assert_eq!(make_entry_uuid("user", "bob"), entry_uuid);
+        assert_ne!(make_entry_uuid("group", "bob"), entry_uuid);
+    }
+
+    #[tokio::test]
+    async fn test_search_not_member_of() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::Not(Box::new(
+                UserRequestFilter::MemberOf("group_1".to_string()),
+            )))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(
+            LdapFilter::Not(Box::new(LdapFilter::Equality(
+                "memberOf".to_string(),
+                "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+            ))),
+            vec!["objectClass"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_success()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_referrals() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        mock.expect_list_groups()
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let url = "ldap://other.example.com/ou=contractors,dc=example,dc=com";
+        let mut ldap_handler = setup_bound_handler_with_options(
+            mock,
+            LdapHandlerOptions {
+                referrals: vec![LdapReferral::new(
+                    "ou=contractors, dc=example,dc=com",
+                    url.to_string(),
+                )
+                .unwrap()],
+                ..Default::default()
+            },
+        )
+        .await;
+        let request = LdapOp::BindRequest(LdapBindRequest {
+            dn: "uid=bob,ou=contractors,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        });
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request, &[]).await,
+            Some(vec![make_referral(
+                make_bind_response(LdapResultCode::Referral, "".to_string()),
+                url
+            )])
+        );
+        let request = make_search_request(
+            "ou=contractors,dc=example,dc=com",
+            LdapFilter::And(vec![]),
+            vec!["objectClass"],
+        );
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(LdapOp::SearchRequest(request), &[])
+                .await,
+            Some(vec![make_referral(
+                make_search_error(LdapResultCode::Referral, "".to_string()),
+                url
+            )])
+        );
+        let request = LdapSearchRequest {
+            scope: LdapSearchScope::Subtree,
+            ..make_search_request(
+                "dc=example,dc=com",
+                LdapFilter::And(vec![]),
+                vec!["objectClass"],
+            )
+        };
+        let container = |dn: &str, object_class: &str| -> LdapResponse {
+            LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                dn: dn.to_string(),
+                attributes: vec![LdapPartialAttribute {
+                    atype: "objectClass".to_string(),
+                    vals: vec!["top".to_string(), object_class.to_string()],
+                }],
+            })
+            .into()
+        };
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(LdapOp::SearchRequest(request), &[])
+                .await,
+            Some(vec![
+                container("dc=example,dc=com", "domain"),
+                container("ou=people,dc=example,dc=com", "organizationalUnit"),
+                container("ou=groups,dc=example,dc=com", "organizationalUnit"),
+                LdapResponse {
+                    op: LdapResponseOp::SearchResultReference(vec![url.to_string()]),
+                    controls: vec![],
+                },
+                make_search_success().into(),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_health_check() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .times(1)
+            .return_once(|_| Err(DomainError::DatabaseError(sqlx::Error::PoolTimedOut)));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions::default(),
+            None,
+        );
+        let request = make_search_request(
+            "",
+            LdapFilter::Present("objectClass".to_string()),
+            vec!["supportedExtension"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_error(
+                LdapResultCode::Unavailable,
+                "The server is unavailable, try again later".to_string(),
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_posix_attributes() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![
+                UserRequestFilter::And(vec![]),
+                UserRequestFilter::Not(Box::new(UserRequestFilter::And(vec![]))),
+                UserRequestFilter::And(vec![]),
+            ]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![User {
+                    user_id: UserId::new("bob"),
+                    posix_id: 42,
+                    ..Default::default()
+                }])
+            });
+        let mut ldap_handler = setup_bound_handler_with_options(
+            mock,
+            LdapHandlerOptions {
+                user_object_classes: vec!["person".to_string(), "organizationalPerson".to_string()],
+                home_directory_template: "/users/{uid}".to_string(),
+                ..Default::default()
+            },
+        )
+        .await;
+        let request = make_user_search_request(
+            LdapFilter::And(vec![
+                LdapFilter::Equality(
+                    "objectClass".to_string(),
+                    "organizationalperson".to_string(),
+                ),
+                LdapFilter::Equality("objectClass".to_string(), "posixAccount".to_string()),
+                LdapFilter::Present("uidNumber".to_string()),
+            ]),
+            vec!["objectClass", "uidNumber", "gidNumber", "homeDirectory"],
+        );
+        // The POSIX ID allocated by the backend, in the range of the users.
+        let posix_id = DEFAULT_POSIX_UID_OFFSET + 42;
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=Bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "objectClass".to_string(),
+                            vals: vec!["person".to_string(), "organizationalPerson".to_string()],
+                        },
+                        LdapPartialAttribute {
+                            atype: "uidNumber".to_string(),
+                            vals: vec![posix_id.to_string()],
+                        },
+                        LdapPartialAttribute {
+                            atype: "gidNumber".to_string(),
+                            vals: vec![posix_id.to_string()],
+                        },
+                        LdapPartialAttribute {
+                            atype: "homeDirectory".to_string(),
+                            vals: vec!["/users/bob".to_string()],
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_groups_member_forms() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::Or(vec![
+                GroupRequestFilter::Member(UserId::new("bob")),
+                GroupRequestFilter::Member(UserId::new("bob")),
+            ]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![Group {
+                    display_name: "group_1".to_string(),
+                    id: GroupId(1),
+                    users: vec![UserId::new("bob")],
+                }])
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_group_search_request(
+            LdapFilter::Or(vec![
+                LdapFilter::Equality(
+                    "member".to_string(),
+                    "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                ),
+                LdapFilter::Equality(
+                    "uniqueMember".to_string(),
+                    "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                ),
+            ]),
+            vec!["member", "uniqueMember"],
+        );
+        let members = vec!["cn=bob,ou=people,dc=example,dc=com".to_string()];
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "member".to_string(),
+                            vals: members.clone(),
+                        },
+                        LdapPartialAttribute {
+                            atype: "uniqueMember".to_string(),
+                            vals: members,
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_modify_assertion() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![
+                UserRequestFilter::Equality("email".to_string(), "bob@example.com".to_string()),
+                UserRequestFilter::UserId(UserId::new("test")),
+            ]))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_password_replace("cn=test,ou=people,dc=example,dc=com", "password");
+        let assertion = |value: Option<Vec<u8>>| RawControl {
+            oid: ASSERTION_OID.to_string(),
+            criticality: true,
+            value,
+        };
+        let filter = BerElement::constructed(
+            0xA3,
+            &[
+                BerElement::octet_string("mail"),
+                BerElement::octet_string("bob@example.com"),
+            ],
+        );
+        assert_eq!(
+            ldap_handler
+                .do_modify(&request, &[assertion(Some(filter.encode()))])
+                .await,
+            LdapResponseOp::WithResultCode(
+                Box::new(make_modify_response(
+                    LdapResultCode::Other,
+                    "The assertion does not match the entry".to_string()
+                )),
+                ASSERTION_FAILED
+            )
+        );
+        assert_eq!(
+            ldap_handler
+                .do_modify(&request, &[assertion(None)])
+                .await
+                .result()
+                .unwrap()
+                .code,
+            LdapResultCode::ProtocolError
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_groups_by_gid_number() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::And(vec![
+                GroupRequestFilter::And(vec![]),
+                GroupRequestFilter::GroupId(GroupId(1)),
+            ]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![Group {
+                    display_name: "group_1".to_string(),
+                    id: GroupId(1),
+                    users: vec![UserId::new("bob"), UserId::new("john")],
+                }])
+            });
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::Or(vec![
+                GroupRequestFilter::Not(Box::new(GroupRequestFilter::And(vec![]))),
+                GroupRequestFilter::Member(UserId::new("bob")),
+            ]))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_group_search_request(
+            LdapFilter::And(vec![
+                LdapFilter::Equality("objectClass".to_string(), "posixGroup".to_string()),
+                LdapFilter::Equality("gidNumber".to_string(), "10001".to_string()),
+            ]),
+            vec!["cn", "gidNumber", "memberUid"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec!["group_1".to_string()],
+                        },
+                        LdapPartialAttribute {
+                            atype: "gidNumber".to_string(),
+                            vals: vec!["10001".to_string()],
+                        },
+                        LdapPartialAttribute {
+                            atype: "memberUid".to_string(),
+                            vals: vec!["bob".to_string(), "john".to_string()],
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+        // A gidNumber outside of the range of the groups matches nothing.
+        let request = make_group_search_request(
+            LdapFilter::Or(vec![
+                LdapFilter::Equality("gidNumber".to_string(), "12".to_string()),
+                LdapFilter::Equality("memberUid".to_string(), "bob".to_string()),
+            ]),
+            vec!["cn"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_success()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_deref_aliases() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(4).returning(|_| {
+            Ok(vec![User {
+                user_id: UserId::new("bob"),
+                ..Default::default()
+            }])
+        });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        for aliases in [
+            LdapDerefAliases::Never,
+            LdapDerefAliases::InSearching,
+            LdapDerefAliases::FindingBaseObj,
+            LdapDerefAliases::Always,
+        ] {
+            let request = LdapSearchRequest {
+                aliases,
+                ..make_user_search_request(LdapFilter::And(vec![]), vec!["uid"])
+            };
+            assert_eq!(
+                ldap_handler.do_search(&request).await,
+                vec![
+                    LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                        dn: "cn=Bob,ou=people,dc=example,dc=com".to_string(),
+                        attributes: vec![LdapPartialAttribute {
+                            atype: "uid".to_string(),
+                            vals: vec!["bob".to_string()],
+                        }],
+                    }),
+                    make_search_success(),
+                ]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_base_dn_aliases() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: UserId::new("test"),
+                password: "pass".to_string(),
+            }))
+            .return_once(|_| Ok(()));
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::Member(UserId::new("bob")))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![Group {
+                    display_name: "group_1".to_string(),
+                    id: GroupId(1),
+                    users: vec![UserId::new("bob")],
+                }])
+            });
+        let options = LdapHandlerOptions {
+            base_dn_aliases: vec!["dc=sales,dc=example,dc=com".to_string()],
+            ..Default::default()
+        };
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("test"),
+            options.clone(),
+            None,
+        );
+        let naming_contexts = match root_dse_response("dc=example,dc=com", &options) {
+            LdapOp::SearchResultEntry(entry) => {
+                entry
+                    .attributes
+                    .into_iter()
+                    .find(|a| a.atype == "namingContexts")
+                    .unwrap()
+                    .vals
+            }
+            op => panic!("Unexpected root DSE: {:?}", op),
+        };
+        assert_eq!(
+            naming_contexts,
+            vec![
+                "dc=example,dc=com".to_string(),
+                "dc=sales,dc=example,dc=com".to_string()
+            ]
+        );
+        let request = LdapBindRequest {
+            dn: "cn=test,ou=people,dc=sales,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+        assert_eq!(
+            ldap_handler.dn,
+            LdapDn("cn=test,ou=people,dc=example,dc=com".to_string())
+        );
+        let request = LdapSearchRequest {
+            scope: LdapSearchScope::OneLevel,
+            ..make_search_request(
+                "ou=groups,dc=sales,dc=example,dc=com",
+                LdapFilter::Equality(
+                    "member".to_string(),
+                    "cn=bob,ou=people,dc=sales,dc=example,dc=com".to_string(),
+                ),
+                vec!["cn"],
+            )
+        };
+        // The entries are returned under the canonical base DN.
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "cn".to_string(),
+                        vals: vec!["group_1".to_string()],
+                    }],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
+        let request = LdapOp::ExtendedRequest(LdapExtendedRequest {
+            name: CANCEL_OID.to_string(),
+            value: Some(BerElement::sequence(&[BerElement::integer(3)]).encode()),
+        });
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request, &[]).await,
+            Some(vec![LdapResponse {
+                op: LdapResponseOp::WithResultCode(
+                    Box::new(
+                        make_extended_response(
+                            LdapResultCode::Other,
+                            "The operation 3 is not in progress".to_string(),
+                        )
+                        .into()
+                    ),
+                    NO_SUCH_OPERATION
+                ),
+                controls: vec![],
+            }])
+        );
+        let request = LdapOp::ExtendedRequest(LdapExtendedRequest {
+            name: CANCEL_OID.to_string(),
+            value: None,
+        });
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request, &[]).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::ProtocolError,
+                "Invalid cancel request".to_string(),
+            )
+            .into()])
+        );
+    }
+
+    #[test]
+    fn test_dn_escaping() {
+        assert_eq!(
+            escape_dn_value("bob,smith@example.com"),
+            r"bob\,smith@example.com"
+        );
+        assert_eq!(escape_dn_value(" #a+b "), r"\ #a\+b\ ");
+        assert_eq!(unescape_dn_value(r"bob\,smith\2C\41"), "bob,smith,A");
+        assert_eq!(escape_dn_value("a\0b"), r"a\00b");
+        for value in ["Doe, John", r"O'Brien\", "#1 <a>; b=c ", "a\0b", ""] {
+            assert_eq!(unescape_dn_value(&escape_dn_value(value)), value);
+        }
+        let base_tree = parse_distinguished_name("dc=example,dc=com").unwrap();
+        let people_ou = ("ou".to_string(), "people".to_string());
+        let groups_ou = ("ou".to_string(), "groups".to_string());
+        let group_dn = make_group_dn("Doe, John", "ou=groups,dc=example,dc=com");
+        assert_eq!(group_dn, r"cn=Doe\, John,ou=groups,dc=example,dc=com");
+        assert_eq!(
+            get_group_id_from_distinguished_name(
+                &group_dn,
+                &base_tree,
+                &groups_ou,
+                "ou=groups,dc=example,dc=com"
+            )
+            .unwrap(),
+            "Doe, John"
+        );
+        let user_dn = make_user_dn(
+            UserRdnAttribute::Cn,
+            r"O'Brien\",
+            None,
+            "ou=people,dc=example,dc=com",
+        );
+        assert_eq!(user_dn, r"cn=O'Brien\\,ou=people,dc=example,dc=com");
+        let user_rdn_value = |dn: &str| {
+            get_user_rdn_value_from_distinguished_name(
+                dn,
+                &base_tree,
+                &people_ou,
+                "ou=people,dc=example,dc=com",
+                UserRdnAttribute::Cn,
+            )
+            .unwrap()
+        };
+        assert_eq!(user_rdn_value(&user_dn), r"O'Brien\");
+        // The escaped spaces at the ends of the values are kept.
+        assert_eq!(
+            user_rdn_value(r"cn=\ bob\ , ou=people, dc=example,dc=com"),
+            " bob "
+        );
+        assert_eq!(
+            parse_distinguished_name(r"mail=bob\,smith@example.com,ou=people,dc=example").unwrap(),
+            vec![
+                ("mail".to_string(), "bob,smith@example.com".to_string()),
+                ("ou".to_string(), "people".to_string()),
+                ("dc".to_string(), "example".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_uid_rdn() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: UserId::new("bob"),
+                password: "pass".to_string(),
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions {
+                user_rdn_attribute: UserRdnAttribute::Uid,
+                ..Default::default()
+            },
+            None,
+        );
+        let request = LdapBindRequest {
+            dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::NamingViolation
+        );
+        let request = LdapBindRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mail_rdn() {
+        let bob = || User {
+            user_id: UserId::new("bob"),
+            email: "bob,smith@example.com".to_string(),
+            ..Default::default()
+        };
+        let mut mock = MockTestBackendHandler::new();
+        let user = bob();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::Or(vec![
+                UserRequestFilter::Equality(
+                    "email".to_string(),
+                    "bob,smith@example.com".to_string(),
+                ),
+            ]))))
+            .times(1)
+            .return_once(move |_| Ok(vec![user]));
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: UserId::new("bob"),
+                password: "pass".to_string(),
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let user = bob();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![
+                UserRequestFilter::And(vec![]),
+                UserRequestFilter::UserId(UserId::new("bob")),
+            ]))))
+            .times(1)
+            .return_once(move |_| Ok(vec![user]));
+        mock.expect_list_groups().times(1).return_once(|_| {
+            Ok(vec![Group {
+                display_name: "group_1".to_string(),
+                id: GroupId(1),
+                users: vec![UserId::new("bob")],
+            }])
+        });
+        mock.expect_list_users()
+            .with(eq(None))
+            .times(1)
+            .return_once(move |_| Ok(vec![bob()]));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions {
+                user_rdn_attribute: UserRdnAttribute::Mail,
+                ..Default::default()
+            },
+            None,
+        );
+        let dn = r"mail=bob\,smith@example.com,ou=people,dc=example,dc=com";
+        let request = LdapBindRequest {
+            dn: dn.to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["uid"]);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: dn.to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "uid".to_string(),
+                        vals: vec!["bob".to_string()],
+                    }],
+                }),
+                make_search_success(),
+            ]
+        );
+        let request = make_group_search_request(LdapFilter::And(vec![]), vec!["member"]);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "member".to_string(),
+                        vals: vec![dn.to_string()],
+                    }],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_groups_tree_attributes() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups().times(1).return_once(|_| {
+            Ok(vec![Group {
+                display_name: "group_1".to_string(),
+                id: GroupId(1),
+                users: vec![],
+            }])
+        });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request =
+            make_group_search_request(LdapFilter::And(vec![]), vec!["entryDN", "hasSubordinates"]);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "entryDN".to_string(),
+                            vals: vec!["cn=group_1,ou=groups,dc=example,dc=com".to_string()],
+                        },
+                        LdapPartialAttribute {
+                            atype: "hasSubordinates".to_string(),
+                            vals: vec!["FALSE".to_string()],
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expand_attributes() {
+        let requested =
+            |attributes: &[&str]| attributes.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            expand_attributes(&requested(&["cn", "+"]), GROUP_ATTRIBUTES),
+            requested(&[
+                "cn",
+                "createTimestamp",
+                "modifyTimestamp",
+                "entryUUID",
+                "entryDN",
+                "hasSubordinates",
+                "subschemaSubentry"
+            ])
+        );
+        assert_eq!(
+            expand_attributes(&requested(&["*", "+", "CN", "entryuuid"]), GROUP_ATTRIBUTES),
+            requested(&[
+                "objectClass",
+                "cn",
+                "uid",
+                "member",
+                "uniqueMember",
+                "memberUid",
+                "gidNumber",
+                "createTimestamp",
+                "modifyTimestamp",
+                "entryUUID",
+                "entryDN",
+                "hasSubordinates",
+                "subschemaSubentry"
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_all_operational_attributes() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![User {
+                user_id: UserId::new("bob"),
+                display_name: "Bob".to_string(),
+                ..Default::default()
+            }])
+        });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["uid", "+"]);
+        let creation_date = to_generalized_time(&User::default().creation_date);
+        let dn = "cn=Bob,ou=people,dc=example,dc=com".to_string();
+        let attribute = |atype: &str, value: &str| LdapPartialAttribute {
+            atype: atype.to_string(),
+            vals: vec![value.to_string()],
+        };
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: dn.clone(),
+                    attributes: vec![
+                        attribute("uid", "bob"),
+                        attribute("createTimestamp", &creation_date),
+                        attribute("modifyTimestamp", &creation_date),
+                        attribute("entryUUID", &make_entry_uuid("user", "bob")),
+                        attribute("entryDN", &dn),
+                        attribute("hasSubordinates", "FALSE"),
+                        attribute("subschemaSubentry", SCHEMA_DN),
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_with_email() {
+        let email_filter = || {
+            Some(UserRequestFilter::Equality(
+                "email".to_string(),
+                "bob@example.com".to_string(),
+            ))
+        };
+        let bob = || User {
+            user_id: UserId::new("bob"),
+            email: "bob@example.com".to_string(),
+            ..Default::default()
+        };
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(email_filter()))
+            .times(2)
+            .returning(move |_| Ok(vec![bob()]));
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: UserId::new("bob"),
+                password: "pass".to_string(),
+            }))
+            .times(2)
+            .returning(|_| Ok(()));
+        mock.expect_list_users()
+            .with(eq(email_filter()))
+            .times(1)
+            .return_once(move |_| Ok(vec![bob(), bob()]));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions {
+                allow_email_login: true,
+                ..Default::default()
+            },
+            None,
+        );
+        let make_request = |dn: &str| LdapBindRequest {
+            dn: dn.to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler
+                .do_bind(&make_request("bob@example.com"))
+                .await
+                .0,
+            LdapResultCode::Success
+        );
+        assert_eq!(
+            ldap_handler.dn,
+            LdapDn("cn=bob,ou=people,dc=example,dc=com".to_string())
+        );
+        assert_eq!(
+            ldap_handler
+                .do_bind(&make_request(
+                    "uid=bob@example.com,ou=people,dc=example,dc=com"
+                ))
+                .await
+                .0,
+            LdapResultCode::Success
+        );
+        // Two users with the same email.
+        assert_eq!(
+            ldap_handler.do_bind(&make_request("bob@example.com")).await,
+            (LdapResultCode::InvalidCredentials, "".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_with_email_disabled() {
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
+        let request = LdapBindRequest {
+            dn: "bob@example.com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::NamingViolation
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_types_only() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![User {
+                user_id: UserId::new("bob"),
+                email: "bob@example.com".to_string(),
+                display_name: "Bob".to_string(),
+                ..Default::default()
+            }])
+        });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = LdapSearchRequest {
+            typesonly: true,
+            ..make_user_search_request(LdapFilter::And(vec![]), vec!["uid", "mail"])
+        };
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=Bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "uid".to_string(),
+                            vals: vec![],
+                        },
+                        LdapPartialAttribute {
+                            atype: "mail".to_string(),
+                            vals: vec![],
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_readonly_account() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![]))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions {
+                readonly_account: Some(
+                    LdapReadOnlyAccount::new(
+                        "cn=readonly,ou=services,dc=example,dc=com",
+                        SecUtf8::from("secret"),
+                    )
+                    .unwrap(),
+                ),
+                ..Default::default()
+            },
+            None,
+        );
+        let make_request = |password: &str| LdapBindRequest {
+            dn: "CN=ReadOnly, ou=services, dc=example, dc=com".to_string(),
+            cred: LdapBindCred::Simple(password.to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&make_request("wrong")).await.0,
+            LdapResultCode::InvalidCredentials
+        );
+        assert_eq!(
+            ldap_handler.do_bind(&make_request("secret")).await.0,
+            LdapResultCode::Success
+        );
+        // All the users are visible.
+        let request = make_user_search_request::<String>(LdapFilter::And(vec![]), vec![]);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_success()]
+        );
+        assert_eq!(
+            ldap_handler
+                .do_modify(
+                    &make_password_replace("cn=bob,ou=people,dc=example,dc=com", "password"),
+                    &[]
+                )
+                .await
+                .result()
+                .unwrap()
+                .code,
+            LdapResultCode::InsufficentAccessRights
+        );
+        let request = LdapPasswordModifyRequest {
+            user_identity: Some("cn=bob,ou=people,dc=example,dc=com".to_string()),
+            old_password: None,
+            new_password: Some("password".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_password_modification(&request).await,
+            vec![make_extended_response(
+                LdapResultCode::InsufficentAccessRights,
+                "The read-only account cannot change passwords".to_string(),
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_in_chain_membership() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::MemberOf("admins".to_string()))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(
+            LdapFilter::Equality(
+                "memberOf:1.2.840.113556.1.4.1941:".to_string(),
+                "cn=admins,ou=groups,dc=example,dc=com".to_string(),
+            ),
+            vec!["uid"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_success()]
+        );
+        let request = make_user_search_request(
+            LdapFilter::Equality("cn:1.2.3.4:".to_string(), "bob".to_string()),
+            vec!["uid"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_error(
+                LdapResultCode::InappropriateMatching,
+                r#"Unsupported extensible match: "cn:1.2.3.4:=bob""#.to_string(),
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_proxied_authorization() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_bind().times(1).return_once(|_| Ok(()));
+        let (sender, receiver) = tokio::sync::watch::channel(0);
+        mock.expect_subscribe_to_changes()
+            .returning(move || receiver.clone());
+        let mut groups = HashSet::new();
+        groups.insert(GroupIdAndName(GroupId(3), "proxies".to_string()));
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .return_once(|_| Ok(groups));
+        mock.expect_get_user_details()
+            .with(eq(UserId::new("alice")))
+            .times(1)
+            .return_once(|_| {
+                Ok(User {
+                    user_id: UserId::new("alice"),
+                    ..Default::default()
+                })
+            });
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![
+                UserRequestFilter::And(vec![]),
+                UserRequestFilter::UserId(UserId::new("alice")),
+            ]))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        // Alice is not an admin.
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("alice")))
+            .times(1)
+            .return_once(|_| Ok(HashSet::new()));
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .return_once(|_| Ok(HashSet::new()));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions {
+                proxy_group: Some("proxies".to_string()),
+                ..Default::default()
+            },
+            None,
+        );
+        let request = LdapBindRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+        let control = RawControl {
+            oid: PROXIED_AUTHORIZATION_OID.to_string(),
+            criticality: true,
+            value: Some(b"u:alice".to_vec()),
+        };
+        let request = || {
+            LdapRequest::Op(LdapOp::SearchRequest(make_user_search_request(
+                LdapFilter::And(vec![]),
+                vec!["uid"],
+            )))
+        };
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_request(request(), &[control.clone()])
+                .await,
+            Some(vec![make_search_success().into()])
+        );
+        // The bound identity is restored after the operation.
+        assert_eq!(ldap_handler.user_id, UserId::new("bob"));
+        // Bob is no longer a proxy: the groups are read again after the change.
+        sender.send(1).unwrap();
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_request(request(), &[control])
+                .await,
+            Some(vec![LdapResponse {
+                op: LdapResponseOp::WithResultCode(
+                    Box::new(
+                        make_search_error(
+                            LdapResultCode::Other,
+                            r#"Proxied authorization denied: The user is not a member of "proxies""#
+                                .to_string(),
+                        )
+                        .into()
+                    ),
+                    AUTHORIZATION_DENIED,
+                ),
+                controls: vec![],
+            }])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_control_criticality() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(2).returning(|_| Ok(vec![]));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = || {
+            LdapRequest::Op(LdapOp::SearchRequest(make_user_search_request(
+                LdapFilter::And(vec![]),
+                vec!["uid"],
+            )))
+        };
+        let make_control = |oid: &str, criticality: bool| RawControl {
+            oid: oid.to_string(),
+            criticality,
+            value: None,
+        };
+        // Recognized, and ignored.
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_request(request(), &[make_control(DONT_USE_COPY_OID, true)])
+                .await,
+            Some(vec![make_search_success().into()])
+        );
+        // Unknown, but not critical.
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_request(request(), &[make_control("1.2.3.4", false)])
+                .await,
+            Some(vec![make_search_success().into()])
+        );
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_request(request(), &[make_control("1.2.3.4", true)])
+                .await,
+            Some(vec![make_search_error(
+                LdapResultCode::UnavailableCriticalExtension,
+                "Unsupported critical control: 1.2.3.4".to_string(),
+            )
+            .into()])
+        );
+        // The critical paged results control is only supported in searches.
+        let request = LdapRequest::Compare(CompareRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            attribute: "uid".to_string(),
+            value: b"bob".to_vec(),
+        });
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_request(request, &[make_control(PAGED_RESULTS_OID, true)])
+                .await,
+            Some(vec![LdapResponse {
+                op: make_compare_response(
+                    LdapResultCode::UnavailableCriticalExtension,
+                    format!("Unsupported critical control: {}", PAGED_RESULTS_OID),
+                ),
+                controls: vec![],
+            }])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_groups_cached() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::And(vec![]))))
+            .times(2)
+            .returning(|_| {
+                Ok(vec![Group {
+                    id: GroupId(1),
+                    display_name: "group_1".to_string(),
+                    users: vec![UserId::new("bob")],
+                }])
+            });
+        let group_cache = Arc::new(GroupCache::new(Duration::from_secs(60), None));
+        let mut ldap_handler = setup_bound_handler_with_options(
+            mock,
+            LdapHandlerOptions {
+                group_cache: Some(group_cache.clone()),
+                ..Default::default()
+            },
+        )
+        .await;
+        let request = make_group_search_request(LdapFilter::And(vec![]), vec!["cn"]);
+        let expected = vec![
+            LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                attributes: vec![LdapPartialAttribute {
+                    atype: "cn".to_string(),
+                    vals: vec!["group_1".to_string()],
+                }],
+            }),
+            make_search_success(),
+        ];
+        assert_eq!(ldap_handler.do_search(&request).await, expected);
+        // From the cache.
+        assert_eq!(ldap_handler.do_search(&request).await, expected);
+        group_cache.invalidate();
+        assert_eq!(ldap_handler.do_search(&request).await, expected);
+    }
+
+    #[tokio::test]
+    async fn test_search_cached() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups().times(2).returning(|_| {
+            Ok(vec![Group {
+                id: GroupId(1),
+                display_name: "group_1".to_string(),
+                users: vec![UserId::new("bob")],
+            }])
+        });
+        let (sender, receiver) = tokio::sync::watch::channel(42);
+        mock.expect_subscribe_to_changes()
+            .returning(move || receiver.clone());
+        let mut ldap_handler = setup_bound_handler_with_options(
+            mock,
+            LdapHandlerOptions {
+                search_cache: Some(Arc::new(SearchCache::new(Duration::from_secs(60), None))),
+                ..Default::default()
+            },
+        )
+        .await;
+        let request = make_group_search_request(LdapFilter::And(vec![]), vec!["cn"]);
+        let expected = vec![
+            LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                attributes: vec![LdapPartialAttribute {
+                    atype: "cn".to_string(),
+                    vals: vec!["group_1".to_string()],
+                }],
+            }),
+            make_search_success(),
+        ];
+        assert_eq!(ldap_handler.do_search(&request).await, expected);
+        // From the cache.
+        assert_eq!(ldap_handler.do_search(&request).await, expected);
+        // A change, e.g. from the web UI.
+        sender.send(43).unwrap();
+        assert_eq!(ldap_handler.do_search(&request).await, expected);
+    }
+
+    #[tokio::test]
+    async fn test_search_time_limit() {
+        let mut ldap_handler = setup_bound_handler_with_options(
+            MockTestBackendHandler::new(),
+            LdapHandlerOptions {
+                search_timeout: Some(Duration::from_secs(30)),
+                ..Default::default()
+            },
+        )
+        .await;
+        let mut request = make_user_search_request(LdapFilter::And(vec![]), vec!["cn"]);
+        assert_eq!(
+            ldap_handler.get_time_limit(&request),
+            Some(Duration::from_secs(30))
+        );
+        request.timelimit = 5;
+        assert_eq!(
+            ldap_handler.get_time_limit(&request),
+            Some(Duration::from_secs(5))
+        );
+        request.timelimit = 60;
+        assert_eq!(
+            ldap_handler.get_time_limit(&request),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            run_until(
+                Some(tokio::time::Instant::now()),
+                std::future::pending::<()>()
+            )
+            .await,
+            None
+        );
+        assert_eq!(run_until(None, async { 1 }).await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_search_users_in_ou() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![]))))
+            .times(3)
+            .returning(|_| {
+                Ok(vec![
+                    User {
+                        user_id: UserId::new("bob"),
+                        display_name: "bob".to_string(),
+                        ..Default::default()
+                    },
+                    User {
+                        user_id: UserId::new("john"),
+                        display_name: "john".to_string(),
+                        ..Default::default()
+                    },
+                ])
+            });
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::Or(vec![
+                GroupRequestFilter::DisplayName("lldap_employees".to_string()),
+            ]))))
+            .times(3)
+            .returning(|_| {
+                Ok(vec![Group {
+                    id: GroupId(1),
+                    display_name: "lldap_employees".to_string(),
+                    users: vec![UserId::new("bob")],
+                }])
+            });
+        let mut ldap_handler = setup_bound_handler_with_options(
+            mock,
+            LdapHandlerOptions {
+                user_ou_mapping: vec![("employees".to_string(), "lldap_employees".to_string())],
+                ..Default::default()
+            },
+        )
+        .await;
+        let make_entry = |dn: &str| {
+            LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                dn: dn.to_string(),
+                attributes: vec![],
+            })
+        };
+        let bob = make_entry("cn=bob,ou=employees,ou=people,dc=example,dc=com");
+        let john = make_entry("cn=john,ou=people,dc=example,dc=com");
+        let people = make_entry("ou=people,dc=example,dc=com");
+        let employees = make_entry("ou=employees,ou=people,dc=example,dc=com");
+        let request = LdapSearchRequest {
+            scope: LdapSearchScope::OneLevel,
+            ..make_search_request(
+                "ou=employees,ou=people,dc=example,dc=com",
+                LdapFilter::And(vec![]),
+                vec!["1.1"],
+            )
+        };
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![bob.clone(), make_search_success()]
+        );
+        // The users of the OU are not directly in the people OU.
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["1.1"]);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![employees.clone(), john.clone(), make_search_success()]
+        );
+        let request = LdapSearchRequest {
+            scope: LdapSearchScope::Subtree,
+            ..make_user_search_request(LdapFilter::And(vec![]), vec!["1.1"])
+        };
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![people, employees, bob, john, make_search_success()]
+        );
+        assert_eq!(
+            ldap_handler
+                .get_user_id_from_dn("cn=bob,ou=employees,ou=people,dc=example,dc=com")
+                .unwrap(),
+            UserId::new("bob")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_all_users_group() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::And(vec![]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![Group {
+                    id: GroupId(1),
+                    display_name: "group_1".to_string(),
+                    users: vec![UserId::new("bob")],
+                }])
+            });
+        mock.expect_list_users()
+            .with(eq(None))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![
+                    User {
+                        user_id: UserId::new("bob"),
+                        ..Default::default()
+                    },
+                    User {
+                        user_id: UserId::new("john"),
+                        ..Default::default()
+                    },
+                ])
+            });
+        let mut ldap_handler = setup_bound_handler_with_options(
+            mock,
+            LdapHandlerOptions {
+                all_users_group: Some("all_users".to_string()),
+                ..Default::default()
+            },
+        )
+        .await;
+        let request = make_group_search_request(LdapFilter::And(vec![]), vec!["cn", "memberUid"]);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec!["group_1".to_string()],
+                        },
+                        LdapPartialAttribute {
+                            atype: "memberUid".to_string(),
+                            vals: vec!["bob".to_string()],
+                        },
+                    ],
+                }),
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=all_users,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec!["all_users".to_string()],
+                        },
+                        LdapPartialAttribute {
+                            atype: "memberUid".to_string(),
+                            vals: vec!["bob".to_string(), "john".to_string()],
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+        assert_eq!(
+            ldap_handler
+                .convert_user_filter(&LdapFilter::Equality(
+                    "memberOf".to_string(),
+                    "cn=all_users,ou=groups,dc=example,dc=com".to_string()
+                ))
+                .unwrap(),
+            UserRequestFilter::And(vec![])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_modify_dn() {
+        let mut mock = MockTestBackendHandler::new();
+        expect_no_changes(&mut mock);
+        mock.expect_bind().return_once(|_| Ok(()));
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("test")))
+            .times(1)
+            .return_once(|_| Ok(HashSet::new()));
+        mock.expect_get_user_details()
+            .with(eq(UserId::new("test")))
+            .times(2)
+            .returning(|_| {
+                Ok(User {
+                    user_id: UserId::new("test"),
+                    ..Default::default()
+                })
+            });
+        mock.expect_get_user_details()
+            .with(eq(UserId::new("john")))
+            .times(1)
+            .return_once(|_| {
+                Ok(User {
+                    user_id: UserId::new("john"),
+                    ..Default::default()
+                })
+            });
+        mock.expect_get_user_details()
+            .with(eq(UserId::new("tester")))
+            .times(1)
+            .return_once(|_| {
+                Err(crate::domain::error::DomainError::InternalError(
+                    "No such user".to_string(),
+                ))
+            });
+        mock.expect_rename_user()
+            .with(eq(UserId::new("test")), eq(UserId::new("tester")))
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions::default(),
+            None,
+        );
+        let bind = LdapBindRequest {
+            dn: "cn=test,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(ldap_handler.do_bind(&bind).await.0, LdapResultCode::Success);
+        let rename = |dn: &str, new_rdn: &str| ModifyDnRequest {
+            dn: dn.to_string(),
+            new_rdn: new_rdn.to_string(),
+            delete_old_rdn: true,
+            new_superior: None,
+        };
+        assert_eq!(
+            ldap_handler
+                .do_modify_dn(&rename("cn=bob,ou=people,dc=example,dc=com", "uid=robert"))
+                .await
+                .0,
+            LdapResultCode::InsufficentAccessRights
+        );
+        assert_eq!(
+            ldap_handler
+                .do_modify_dn(&rename(
+                    "cn=test,ou=people,dc=example,dc=com",
+                    "mail=tester"
+                ))
+                .await,
+            (
+                LdapResultCode::NamingViolation,
+                r#"Invalid new RDN: "mail=tester", expected: "cn=username""#.to_string()
+            )
+        );
+        assert_eq!(
+            ldap_handler
+                .do_modify_dn(&rename("cn=test,ou=people,dc=example,dc=com", "uid=john"))
+                .await
+                .0,
+            LdapResultCode::EntryAlreadyExists
+        );
+        assert_eq!(
+            ldap_handler
+                .do_modify_dn(&ModifyDnRequest {
+                    new_superior: Some("ou=groups,dc=example,dc=com".to_string()),
+                    ..rename("cn=test,ou=people,dc=example,dc=com", "uid=tester")
+                })
+                .await
+                .0,
+            LdapResultCode::UnwillingToPerform
+        );
+        // The RDN attribute of the users, "cn" by default.
+        assert_eq!(
+            ldap_handler
+                .do_modify_dn(&rename("cn=test,ou=people,dc=example,dc=com", "cn=tester"))
+                .await,
+            (LdapResultCode::Success, "".to_string())
+        );
+        assert_eq!(ldap_handler.user_id, UserId::new("tester"));
+    }
+
+    #[tokio::test]
+    async fn test_search_account_lock() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![
+                UserRequestFilter::Not(Box::new(UserRequestFilter::Not(Box::new(
+                    UserRequestFilter::And(vec![]),
+                )))),
+                UserRequestFilter::And(vec![]),
+            ]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![User {
+                    user_id: UserId::new("bob"),
+                    display_name: "bob".to_string(),
+                    ..Default::default()
+                }])
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(
+            LdapFilter::And(vec![
+                LdapFilter::Not(Box::new(LdapFilter::Equality(
+                    "nsAccountLock".to_string(),
+                    "TRUE".to_string(),
+                ))),
+                LdapFilter::Present("nsAccountLock".to_string()),
+            ]),
+            vec!["nsAccountLock"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "nsAccountLock".to_string(),
+                        vals: vec!["FALSE".to_string()],
+                    }],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_password_metadata() {
+        use chrono::TimeZone;
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![User {
+                user_id: UserId::new("bob"),
+                display_name: "bob".to_string(),
+                ..Default::default()
+            }])
+        });
+        mock.expect_get_password_metadata()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .return_once(|_| {
+                Ok(PasswordMetadata {
+                    changed_time: Some(chrono::Utc.timestamp(1_600_000_000, 0)),
+                    reset_pending: true,
+                })
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(
+            LdapFilter::And(vec![]),
+            vec!["uid", "pwdChangedTime", "pwdReset"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "uid".to_string(),
+                            vals: vec!["bob".to_string()],
+                        },
+                        LdapPartialAttribute {
+                            atype: "pwdChangedTime".to_string(),
+                            vals: vec!["20200913122640Z".to_string()],
+                        },
+                        LdapPartialAttribute {
+                            atype: "pwdReset".to_string(),
+                            vals: vec!["TRUE".to_string()],
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    async fn setup_bound_user_handler(
+        mut mock: MockTestBackendHandler,
+        groups: &[&str],
+    ) -> LdapHandler<MockTestBackendHandler> {
+        mock.expect_bind().return_once(|_| Ok(()));
+        expect_no_changes(&mut mock);
+        let groups = groups
+            .iter()
+            .enumerate()
+            .map(|(i, g)| GroupIdAndName(GroupId(i as i32 + 1), g.to_string()))
+            .collect::<HashSet<_>>();
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("bob")))
+            .return_once(|_| Ok(groups));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions::default(),
+            None,
+        );
+        let request = LdapBindRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+        ldap_handler
+    }
+
+    #[tokio::test]
+    async fn test_search_password_metadata_admin_group() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![User {
+                user_id: UserId::new("bob"),
+                display_name: "bob".to_string(),
+                ..Default::default()
+            }])
+        });
+        mock.expect_get_password_metadata()
+            .times(1)
+            .return_once(|_| Ok(PasswordMetadata::default()));
+        let mut ldap_handler = setup_bound_user_handler(mock, &["lldap_admin"]).await;
+        let request =
+            make_user_search_request(LdapFilter::And(vec![]), vec!["pwdChangedTime", "pwdReset"]);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "pwdReset".to_string(),
+                        vals: vec!["FALSE".to_string()],
+                    }],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_password_metadata_regular_user() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![
+                UserRequestFilter::And(vec![]),
+                UserRequestFilter::UserId(UserId::new("bob")),
+            ]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![User {
+                    user_id: UserId::new("bob"),
+                    display_name: "bob".to_string(),
+                    ..Default::default()
+                }])
+            });
+        mock.expect_get_password_metadata().never();
+        let mut ldap_handler = setup_bound_user_handler(mock, &["lldap_users"]).await;
+        let request = make_user_search_request(
+            LdapFilter::And(vec![]),
+            vec!["uid", "pwdChangedTime", "pwdReset"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "uid".to_string(),
+                        vals: vec!["bob".to_string()],
+                    }],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_photo() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![
+                User {
+                    user_id: UserId::new("bob"),
+                    display_name: "bob".to_string(),
+                    ..Default::default()
+                },
+                User {
+                    user_id: UserId::new("jim"),
+                    display_name: "jim".to_string(),
+                    ..Default::default()
+                },
+                User {
+                    user_id: UserId::new("tim"),
+                    display_name: "tim".to_string(),
+                    ..Default::default()
+                },
+            ])
+        });
+        mock.expect_get_user_avatar()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .return_once(|_| Ok(Some(vec![0xFF, 0xD8, 0xFF, 0xD9])));
+        mock.expect_get_user_avatar()
+            .with(eq(UserId::new("jim")))
+            .times(1)
+            .return_once(|_| Ok(None));
+        mock.expect_get_user_avatar()
+            .with(eq(UserId::new("tim")))
+            .times(1)
+            .return_once(|_| Ok(Some(vec![0; 5])));
+        let mut ldap_handler = setup_bound_handler_with_options(
+            mock,
+            LdapHandlerOptions {
+                max_photo_bytes: 4,
+                ..Default::default()
+            },
+        )
+        .await;
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["uid", "jpegPhoto"]);
+        let uid = |uid: &str| LdapPartialAttribute {
+            atype: "uid".to_string(),
+            vals: vec![uid.to_string()],
+        };
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        uid("bob"),
+                        LdapPartialAttribute {
+                            atype: "jpegPhoto".to_string(),
+                            vals: vec![base64::encode(&[0xFF, 0xD8, 0xFF, 0xD9])],
+                        },
+                    ],
+                }),
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=jim,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![uid("jim")],
+                }),
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=tim,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![uid("tim")],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_base_dn() {
+        check_base_dn("dc=example,dc=com").unwrap();
+        check_base_dn("ou=eng, o=Example\\, Inc.").unwrap();
+        check_base_dn("example.com").unwrap_err();
+        check_base_dn("dc=example,,dc=com").unwrap_err();
+        check_base_dn("dc=,dc=com").unwrap_err();
+        check_base_dn("").unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_search_streaming() {
+        let make_users = |range: std::ops::Range<usize>| {
+            range
+                .map(|i| User {
+                    user_id: UserId::new(&format!("user{:04}", i)),
+                    ..Default::default()
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut mock = MockTestBackendHandler::new();
+        let first_batch = make_users(0..SEARCH_BATCH_SIZE);
+        mock.expect_list_users_batch()
+            .with(always(), eq(None), eq(SEARCH_BATCH_SIZE))
+            .times(1)
+            .return_once(|_, _, _| Ok(first_batch));
+        let second_batch = make_users(SEARCH_BATCH_SIZE..SEARCH_BATCH_SIZE + 2);
+        mock.expect_list_users_batch()
+            .with(
+                always(),
+                eq(Some(UserId::new(&format!(
+                    "user{:04}",
+                    SEARCH_BATCH_SIZE - 1
+                )))),
+                eq(SEARCH_BATCH_SIZE),
+            )
+            .times(1)
+            .return_once(|_, _, _| Ok(second_batch));
+        mock.expect_list_users().never();
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["uid"]);
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+        let (responses, streamed) = futures_util::join!(
+            ldap_handler.handle_ldap_request_streaming(
+                1,
+                LdapRequest::Op(LdapOp::SearchRequest(request)),
+                &[],
+                sender
+            ),
+            async {
+                let mut streamed = vec![];
+                while let Some(response) = receiver.recv().await {
+                    streamed.push(response);
+                }
+                streamed
+            }
+        );
+        assert_eq!(streamed.len(), SEARCH_BATCH_SIZE + 2);
+        assert_eq!(
+            streamed[0],
+            LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                dn: "cn=user0000,ou=people,dc=example,dc=com".to_string(),
+                attributes: vec![LdapPartialAttribute {
+                    atype: "uid".to_string(),
+                    vals: vec!["user0000".to_string()],
+                }],
+            })
+            .into()
+        );
+        assert_eq!(responses, Some(vec![make_search_success().into()]));
+    }
+
+    #[tokio::test]
+    async fn test_search_streaming_size_limit() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users_batch()
+            .times(1)
+            .return_once(|_, _, _| {
+                Ok(vec![
+                    User {
+                        user_id: UserId::new("bob"),
+                        ..Default::default()
+                    },
+                    User {
+                        user_id: UserId::new("jim"),
+                        ..Default::default()
+                    },
+                ])
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let mut request = make_user_search_request(LdapFilter::And(vec![]), vec!["uid"]);
+        request.sizelimit = 1;
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(4);
+        let responses = ldap_handler
+            .handle_ldap_request_streaming(
+                1,
+                LdapRequest::Op(LdapOp::SearchRequest(request)),
+                &[],
+                sender,
+            )
+            .await;
+        assert!(receiver.recv().await.is_some());
+        assert!(receiver.recv().await.is_none());
+        assert_eq!(
+            responses,
+            Some(vec![make_search_error(
+                LdapResultCode::SizeLimitExceeded,
+                "Size limit exceeded: more than 1 entries".to_string(),
+            )
+            .into()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_bind_version() {
+        let mut ldap_handler = LdapHandler::new(
+            MockTestBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions::default(),
+            None,
+        );
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_request(
+                    LdapRequest::UnsupportedBind(UnsupportedBindRequest {
+                        dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                        version: 2,
+                    }),
+                    &[],
+                )
+                .await,
+            Some(vec![make_bind_response(
+                LdapResultCode::ProtocolError,
+                "Unsupported LDAP protocol version 2, only version 3 is supported".to_string(),
+            )
+            .into()])
+        );
+        assert_eq!(ldap_handler.bound_dn(), None);
+    }
+
+    #[tokio::test]
+    async fn test_max_connections_per_user() {
+        let limiter = Arc::new(UserConnectionLimiter::new(1));
+        let make_handler = || {
+            let mut mock = MockTestBackendHandler::new();
+            mock.expect_bind().returning(|_| Ok(()));
+            LdapHandler::new(
+                mock,
+                "dc=example,dc=com".to_string(),
+                UserId::new("test"),
+                LdapHandlerOptions {
+                    user_connection_limiter: Some(limiter.clone()),
+                    ..Default::default()
+                },
+                None,
+            )
+        };
+        let bind = || {
+            LdapRequest::Op(LdapOp::BindRequest(LdapBindRequest {
+                dn: "cn=test,ou=people,dc=example,dc=com".to_string(),
+                cred: LdapBindCred::Simple("pass".to_string()),
+            }))
+        };
+        let mut first = make_handler();
+        assert_eq!(
+            first.handle_ldap_request(bind(), &[]).await,
+            Some(vec![make_bind_response(
+                LdapResultCode::Success,
+                "".to_string()
+            )
+            .into()])
+        );
+        // Binding again as the same DN doesn't count the connection twice.
+        first.handle_ldap_request(bind(), &[]).await;
+        assert!(!first.take_close_request());
+        let mut second = make_handler();
+        assert_eq!(
+            second.handle_ldap_request(bind(), &[]).await,
+            Some(vec![make_bind_response(
+                LdapResultCode::Busy,
+                r#"Too many connections bound as "cn=test,ou=people,dc=example,dc=com""#
+                    .to_string(),
+            )
+            .into()])
+        );
+        assert_eq!(second.bound_dn(), None);
+        assert!(second.take_close_request());
+        // The connection is released when its handler is dropped.
+        drop(first);
+        drop(second);
+        let mut third = make_handler();
+        third.handle_ldap_request(bind(), &[]).await;
+        assert!(!third.take_close_request());
+        assert_eq!(
+            limiter.connections("cn=test,ou=people,dc=example,dc=com"),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_modify_dn_read_controls() {
+        let mut mock = MockTestBackendHandler::new();
+        expect_no_changes(&mut mock);
+        mock.expect_get_user_details()
+            .with(eq(UserId::new("robert")))
+            .times(1)
+            .return_once(|_| {
+                Err(crate::domain::error::DomainError::InternalError(
+                    "No such user".to_string(),
+                ))
+            });
+        mock.expect_get_user_details().returning(|user_id| {
+            Ok(User {
+                user_id: user_id.clone(),
+                ..Default::default()
+            })
+        });
+        mock.expect_get_user_groups()
+            .returning(|_| Ok(HashSet::new()));
+        mock.expect_rename_user()
+            .with(eq(UserId::new("bob")), eq(UserId::new("robert")))
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        for name in ["bob", "robert"] {
+            mock.expect_list_users()
+                .withf(move |filter| format!("{:?}", filter).contains(&format!("{:?}", name)))
+                .times(1)
+                .return_once(move |_| {
+                    Ok(vec![User {
+                        user_id: UserId::new(name),
+                        ..Default::default()
+                    }])
+                });
+        }
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let make_control = |oid: &str| RawControl {
+            oid: oid.to_string(),
+            criticality: true,
+            value: Some(BerElement::sequence(&[BerElement::octet_string("uid")]).encode()),
+        };
+        let make_entry = |name: &str| {
+            encode_search_result_entry(&LdapSearchResultEntry {
+                dn: format!("cn={},ou=people,dc=example,dc=com", name),
+                attributes: vec![LdapPartialAttribute {
+                    atype: "uid".to_string(),
+                    vals: vec![name.to_string()],
+                }],
+            })
+            .unwrap()
+            .encode()
+        };
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_request(
+                    LdapRequest::ModifyDn(ModifyDnRequest {
+                        dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                        new_rdn: "uid=robert".to_string(),
+                        delete_old_rdn: true,
+                        new_superior: None,
+                    }),
+                    &[make_control(PRE_READ_OID), make_control(POST_READ_OID)],
+                )
+                .await,
+            Some(vec![LdapResponse {
+                op: make_modify_dn_response(LdapResultCode::Success, "".to_string()),
+                controls: vec![
+                    RawControl {
+                        oid: PRE_READ_OID.to_string(),
+                        criticality: false,
+                        value: Some(make_entry("bob")),
+                    },
+                    RawControl {
+                        oid: POST_READ_OID.to_string(),
+                        criticality: false,
+                        value: Some(make_entry("robert")),
+                    },
+                ],
+            }])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_modify_pre_read_invalid() {
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
+        let response = ldap_handler
+            .handle_ldap_request(
+                LdapRequest::Modify(ModifyRequest {
+                    dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                    changes: vec![],
+                }),
+                &[RawControl {
+                    oid: PRE_READ_OID.to_string(),
+                    criticality: true,
+                    value: Some(b"garbage".to_vec()),
+                }],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            response[0].op.result().unwrap().code,
+            LdapResultCode::ProtocolError
+        );
+    }
+
+    #[tokio::test]
+    async fn test_object_class_filters() {
+        let match_all = UserRequestFilter::And(vec![]);
+        let match_none = UserRequestFilter::Not(Box::new(UserRequestFilter::And(vec![])));
+        let equality =
+            |class: &str| LdapFilter::Equality("objectClass".to_string(), class.to_string());
+        for options in [
+            LdapHandlerOptions::default(),
+            LdapHandlerOptions {
+                user_object_classes: vec!["inetOrgPerson".to_string(), "shadowAccount".to_string()],
+                ..Default::default()
+            },
+        ] {
+            let ldap_handler =
+                setup_bound_handler_with_options(MockTestBackendHandler::new(), options).await;
+            let user_classes = get_user_attribute(
+                &User::default(),
+                "objectClass",
+                "cn=bob,ou=people,dc=example,dc=com",
+                &ldap_handler.options,
+            )
+            .unwrap()
+            .unwrap();
+            assert!(!user_classes.is_empty());
+            for class in &user_classes {
+                // The classes are matched like the other attributes, ignoring the case.
+                for value in [class.clone(), class.to_uppercase()] {
+                    assert_eq!(
+                        ldap_handler.convert_user_filter(&equality(&value)).unwrap(),
+                        match_all,
+                        "{}",
+                        value
+                    );
+                    assert_eq!(
+                        ldap_handler
+                            .convert_group_filter(&equality(&value))
+                            .unwrap(),
+                        GroupRequestFilter::Not(Box::new(GroupRequestFilter::And(vec![]))),
+                        "{}",
+                        value
+                    );
+                }
+            }
+            let group_classes = get_group_attribute(
+                &Group {
+                    id: GroupId(1),
+                    display_name: "group".to_string(),
+                    users: vec![],
+                },
+                "ou=groups,dc=example,dc=com",
+                "objectClass",
+                &None,
+                &|user_id| format!("cn={},ou=people,dc=example,dc=com", user_id),
+                PosixIdRanges::default(),
+            )
+            .unwrap()
+            .unwrap();
+            assert_eq!(group_classes.len(), GROUP_OBJECT_CLASSES.len());
+            for class in &group_classes {
+                assert_eq!(
+                    ldap_handler.convert_group_filter(&equality(class)).unwrap(),
+                    GroupRequestFilter::And(vec![]),
+                    "{}",
+                    class
+                );
+                assert_eq!(
+                    ldap_handler.convert_user_filter(&equality(class)).unwrap(),
+                    match_none,
+                    "{}",
+                    class
+                );
+            }
+            assert_eq!(
+                ldap_handler
+                    .convert_user_filter(&equality("organizationalUnit"))
+                    .unwrap(),
+                match_none
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_groups_of_user() {
+        for count in [0, 1, 3] {
+            let groups = (1..=count)
+                .map(|i| Group {
+                    id: GroupId(i),
+                    display_name: format!("group_{}", i),
+                    users: vec![UserId::new("bob")],
+                })
+                .collect::<Vec<_>>();
+            let mut mock = MockTestBackendHandler::new();
+            mock.expect_list_groups()
+                .with(eq(Some(GroupRequestFilter::Or(vec![
+                    GroupRequestFilter::Member(UserId::new("bob")),
+                    GroupRequestFilter::Member(UserId::new("bob")),
+                ]))))
+                .times(1)
+                .return_once(move |_| Ok(groups));
+            let mut ldap_handler = setup_bound_handler(mock).await;
+            // The DNs are normalized, whatever their case and spacing.
+            let request = LdapSearchRequest {
+                scope: LdapSearchScope::Subtree,
+                ..make_group_search_request(
+                    LdapFilter::Or(vec![
+                        LdapFilter::Equality(
+                            "member".to_string(),
+                            "UID=Bob,OU=People,DC=Example,DC=Com".to_string(),
+                        ),
+                        LdapFilter::Equality(
+                            "uniqueMember".to_string(),
+                            "cn=bob, ou=people, dc=example, dc=com".to_string(),
+                        ),
+                    ]),
+                    vec!["cn"],
+                )
+            };
+            let mut expected = (1..=count)
+                .map(|i| {
+                    LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                        dn: format!("cn=group_{},ou=groups,dc=example,dc=com", i),
+                        attributes: vec![LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec![format!("group_{}", i)],
+                        }],
+                    })
+                })
+                .collect::<Vec<_>>();
+            expected.push(make_search_success());
+            assert_eq!(ldap_handler.do_search(&request).await, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind_windows_logins() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: UserId::new("bob"),
+                password: "pass".to_string(),
+            }))
+            .times(2)
+            .returning(|_| Ok(()));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions {
+                netbios_domain: Some("EXAMPLE".to_string()),
+                upn_suffix: Some("example.com".to_string()),
+                ..Default::default()
+            },
+            None,
+        );
+        let make_request = |dn: &str| LdapBindRequest {
+            dn: dn.to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        for login in ["example\\Bob", "Bob@EXAMPLE.com"] {
+            assert_eq!(
+                ldap_handler.do_bind(&make_request(login)).await,
+                (LdapResultCode::Success, "".to_string()),
+                "{}",
+                login
+            );
+            assert_eq!(
+                ldap_handler.dn,
+                LdapDn("cn=bob,ou=people,dc=example,dc=com".to_string())
+            );
+        }
+        for login in ["OTHER\\bob", "bob@other.com", "EXAMPLE\\"] {
+            assert_eq!(
+                ldap_handler.do_bind(&make_request(login)).await,
+                (LdapResultCode::InvalidCredentials, "".to_string()),
+                "{}",
+                login
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_cn_in_both_subtrees() {
+        let make_mock = |with_users: bool, with_groups: bool| {
+            let mut mock = MockTestBackendHandler::new();
+            if with_users {
+                mock.expect_list_users()
+                    .with(eq(Some(UserRequestFilter::Equality(
+                        "display_name".to_string(),
+                        "smith".to_string(),
+                    ))))
+                    .times(1)
+                    .return_once(|_| {
+                        Ok(vec![User {
+                            user_id: UserId::new("john"),
+                            display_name: "Smith".to_string(),
+                            ..Default::default()
+                        }])
+                    });
+            }
+            if with_groups {
+                mock.expect_list_groups()
+                    .with(eq(Some(GroupRequestFilter::DisplayName(
+                        "smith".to_string(),
+                    ))))
+                    .times(1)
+                    .return_once(|_| {
+                        Ok(vec![Group {
+                            id: GroupId(1),
+                            display_name: "SMITH".to_string(),
+                            users: vec![],
+                        }])
+                    });
+            }
+            mock
+        };
+        let user_entry = LdapOp::SearchResultEntry(LdapSearchResultEntry {
+            dn: "cn=john,ou=people,dc=example,dc=com".to_string(),
+            attributes: vec![LdapPartialAttribute {
+                atype: "cn".to_string(),
+                vals: vec!["Smith".to_string()],
+            }],
+        });
+        let group_entry = LdapOp::SearchResultEntry(LdapSearchResultEntry {
+            dn: "cn=SMITH,ou=groups,dc=example,dc=com".to_string(),
+            attributes: vec![LdapPartialAttribute {
+                atype: "cn".to_string(),
+                vals: vec!["SMITH".to_string()],
+            }],
+        });
+        let make_request = |base: &str| LdapSearchRequest {
+            scope: LdapSearchScope::Subtree,
+            ..make_search_request(
+                base,
+                // The attribute names are case-insensitive too.
+                LdapFilter::Equality("CN".to_string(), "smith".to_string()),
+                vec!["cn"],
+            )
+        };
+        let cases = [
+            (
+                "dc=example,dc=com",
+                (true, true),
+                vec![user_entry.clone(), group_entry.clone()],
+            ),
+            (
+                "ou=people,dc=example,dc=com",
+                (true, false),
+                vec![user_entry],
+            ),
+            (
+                "ou=groups,dc=example,dc=com",
+                (false, true),
+                vec![group_entry],
+            ),
+        ];
+        for (base, (with_users, with_groups), mut expected) in cases {
+            let mut ldap_handler = setup_bound_handler(make_mock(with_users, with_groups)).await;
+            expected.push(make_search_success());
+            assert_eq!(
+                ldap_handler.do_search(&make_request(base)).await,
+                expected,
+                "{}",
+                base
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_readable_attributes() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![User {
+                user_id: UserId::new("bob"),
+                email: "bob@example.com".to_string(),
+                display_name: "Bob".to_string(),
+                ..Default::default()
+            }])
+        });
+        let mut ldap_handler = setup_bound_handler_with_options(
+            mock,
+            LdapHandlerOptions {
+                allow_anonymous_bind: true,
+                anonymous_readable_attributes: Some(vec![
+                    "objectClass".to_string(),
+                    "namingContexts".to_string(),
+                    "uid".to_string(),
+                ]),
+                ..Default::default()
+            },
+        )
+        .await;
+        let anonymous_bind = LdapBindRequest {
+            dn: "".to_string(),
+            cred: LdapBindCred::Simple("".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&anonymous_bind).await.0,
+            LdapResultCode::Success
+        );
+        let request = make_search_request("", LdapFilter::And(vec![]), vec!["*"]);
+        let root_dse_attributes = match &ldap_handler.do_search(&request).await[0] {
+            LdapOp::SearchResultEntry(entry) => entry
+                .attributes
+                .iter()
+                .map(|a| a.atype.clone())
+                .collect::<Vec<_>>(),
+            op => panic!("Unexpected response: {:?}", op),
+        };
+        assert_eq!(root_dse_attributes, vec!["objectClass", "namingContexts"]);
+        // The other attributes are removed from the entries.
+        let request = make_user_search_request(
+            LdapFilter::Equality("uid".to_string(), "bob".to_string()),
+            vec!["uid", "mail"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=Bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "uid".to_string(),
+                        vals: vec!["bob".to_string()],
+                    }],
+                }),
+                make_search_success(),
+            ]
+        );
+        // And they can't be used in the filters.
+        let request = make_user_search_request(
+            LdapFilter::Substring(
+                "mail".to_string(),
+                LdapSubstringFilter {
+                    initial: Some("bob".to_string()),
+                    ..Default::default()
+                },
+            ),
+            vec!["uid"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_error(
+                LdapResultCode::InsufficentAccessRights,
+                r#"Anonymous sessions cannot filter on "mail""#.to_string(),
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_sorted() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(3).returning(|_| {
+            Ok([("bob", "Smith"), ("jim", "adams"), ("ann", "Brown")]
+                .iter()
+                .map(|(user_id, last_name)| User {
+                    user_id: UserId::new(user_id),
+                    display_name: user_id.to_string(),
+                    last_name: last_name.to_string(),
+                    ..Default::default()
+                })
+                .collect())
+        });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let make_sort_control = |attribute: &str, criticality: bool| RawControl {
+            oid: SORT_REQUEST_OID.to_string(),
+            criticality,
+            value: Some(
+                BerElement::sequence(&[BerElement::sequence(&[
+                    BerElement::octet_string(attribute),
+                    BerElement {
+                        tag: context_tag(1),
+                        value: vec![0xFF],
+                    },
+                ])])
+                .encode(),
+            ),
+        };
+        let request = || {
+            LdapRequest::Op(LdapOp::SearchRequest(make_user_search_request(
+                LdapFilter::And(vec![]),
+                vec!["uid"],
+            )))
+        };
+        let make_entry = |user_id: &str| {
+            LdapResponse::from(LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                dn: format!("cn={},ou=people,dc=example,dc=com", user_id),
+                attributes: vec![LdapPartialAttribute {
+                    atype: "uid".to_string(),
+                    vals: vec![user_id.to_string()],
+                }],
+            }))
+        };
+        // Sorted by decreasing last name, ignoring the case. The last name is not returned.
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_request(request(), &[make_sort_control("sn", false)])
+                .await,
+            Some(vec![
+                make_entry("bob"),
+                make_entry("ann"),
+                make_entry("jim"),
+                LdapResponse {
+                    op: make_search_success().into(),
+                    controls: vec![make_sort_response_control(SORT_SUCCESS, None)],
+                },
+            ])
+        );
+        // The photos can't be sorted: the entries are not sorted, unless the control is
+        // critical.
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_request(request(), &[make_sort_control("jpegPhoto", false)])
+                .await,
+            Some(vec![
+                make_entry("bob"),
+                make_entry("jim"),
+                make_entry("ann"),
+                LdapResponse {
+                    op: make_search_success().into(),
+                    controls: vec![make_sort_response_control(
+                        SORT_UNWILLING_TO_PERFORM,
+                        Some("jpegPhoto")
+                    )],
+                },
+            ])
+        );
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_request(request(), &[make_sort_control("jpegPhoto", true)])
+                .await,
+            Some(vec![LdapResponse {
+                op: make_search_error(
+                    LdapResultCode::UnavailableCriticalExtension,
+                    r#"Cannot sort by "jpegPhoto""#.to_string()
+                )
+                .into(),
+                controls: vec![make_sort_response_control(
+                    SORT_UNWILLING_TO_PERFORM,
+                    Some("jpegPhoto")
+                )],
+            }])
+        );
+        // Sorted pages.
+        let paged_control = RawControl {
+            oid: PAGED_RESULTS_OID.to_string(),
+            criticality: false,
+            value: Some(
+                BerElement::sequence(&[BerElement::integer(5), BerElement::octet_string("")])
+                    .encode(),
+            ),
+        };
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_request(request(), &[make_sort_control("uid", false), paged_control])
+                .await,
+            Some(vec![
+                make_entry("jim"),
+                make_entry("bob"),
+                make_entry("ann"),
+                LdapResponse {
+                    op: make_search_success().into(),
+                    controls: vec![
+                        make_paged_results_control(vec![]),
+                        make_sort_response_control(SORT_SUCCESS, None)
+                    ],
+                },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_capabilities() {
+        let request = LdapOp::ExtendedRequest(LdapExtendedRequest {
+            name: CAPABILITIES_OID.to_string(),
+            value: None,
+        });
+        let mut ldap_handler = LdapHandler::new(
+            MockTestBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions::default(),
+            None,
+        );
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request.clone(), &[]).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::ProtocolError,
+                "The capabilities are only available to the bound sessions".to_string(),
+            )
+            .into()])
+        );
+        let mut ldap_handler = setup_bound_handler_with_options(
+            MockTestBackendHandler::new(),
+            LdapHandlerOptions {
+                start_tls_available: true,
+                ..Default::default()
+            },
+        )
+        .await;
+        let response = ldap_handler
+            .handle_ldap_message(request, &[])
+            .await
+            .unwrap();
+        let value = match &response[0].op {
+            LdapResponseOp::Op(LdapOp::ExtendedResponse(response)) => {
+                assert_eq!(response.res.code, LdapResultCode::Success);
+                assert_eq!(response.name.as_deref(), Some(CAPABILITIES_OID));
+                response.value.clone().unwrap()
+            }
+            op => panic!("Unexpected response: {:?}", op),
+        };
+        let capabilities: serde_json::Value = serde_json::from_slice(&value).unwrap();
+        assert_eq!(capabilities["vendor"], "lldap");
+        assert_eq!(capabilities["startTls"], true);
+        assert_eq!(capabilities["pagedResults"], true);
+        assert_eq!(capabilities["proxiedAuthorization"], false);
+        assert_eq!(capabilities["userRdnAttribute"], "cn");
+    }
+
+    #[test]
+    fn test_parse_attribute_range() {
+        assert_eq!(
+            parse_attribute_range("member;range=0-1499"),
+            Some(("member", 0, Some(1499)))
+        );
+        assert_eq!(
+            parse_attribute_range("memberUid;Range=1500-*"),
+            Some(("memberUid", 1500, None))
+        );
+        assert_eq!(parse_attribute_range("member"), None);
+        assert_eq!(parse_attribute_range("cn;range=0-1"), None);
+        assert_eq!(parse_attribute_range("member;range=a-1"), None);
+        assert_eq!(parse_attribute_range("member;binary"), None);
+    }
+
+    #[tokio::test]
+    async fn test_search_group_member_ranges() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups().times(1).return_once(|_| {
+            Ok(vec![Group {
+                id: GroupId(1),
+                display_name: "group_1".to_string(),
+                users: ["a", "b", "c", "d", "e"]
+                    .iter()
+                    .map(|u| UserId::new(u))
+                    .collect(),
+            }])
+        });
+        let mut ldap_handler = setup_bound_handler_with_options(
+            mock,
+            LdapHandlerOptions {
+                member_range_threshold: Some(2),
+                ..Default::default()
+            },
+        )
+        .await;
+        let request = make_group_search_request(
+            LdapFilter::And(vec![]),
+            vec![
+                "cn",
+                "memberUid",
+                "member;range=2-*",
+                "uniqueMember;range=4-10",
+            ],
+        );
+        let member_dn = |u: &str| format!("cn={},ou=people,dc=example,dc=com", u);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec!["group_1".to_string()]
+                        },
+                        // The clients that don't know about the ranges get the first one.
+                        LdapPartialAttribute {
+                            atype: "memberUid;range=0-1".to_string(),
+                            vals: vec!["a".to_string(), "b".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "member;range=2-3".to_string(),
+                            vals: vec![member_dn("c"), member_dn("d")]
+                        },
+                        LdapPartialAttribute {
+                            atype: "uniqueMember;range=4-*".to_string(),
+                            vals: vec![member_dn("e")]
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_entry_uuid() {
+        let uuid = "4a6a2c3e-8d2b-4c5f-9e0a-1b2c3d4e5f60";
+        let mut mock = MockTestBackendHandler::new();
+        // The user was renamed from "bob": the DN changed, the entryUUID didn't.
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![
+                UserRequestFilter::Equality("uuid".to_string(), uuid.to_string()),
+            ]))))
+            .times(1)
+            .return_once(move |_| {
+                Ok(vec![User {
+                    user_id: UserId::new("robert"),
+                    display_name: "robert".to_string(),
+                    uuid: uuid.to_string(),
+                    ..Default::default()
+                }])
+            });
+        let group = Group {
+            id: GroupId(3),
+            display_name: "group_3".to_string(),
+            users: vec![],
+        };
+        let groups = vec![group.clone()];
+        // Listed to look up the entryUUID, once per search.
+        mock.expect_list_groups()
+            .with(eq(None))
+            .times(2)
+            .returning(move |_| Ok(groups.clone()));
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::And(vec![
+                GroupRequestFilter::GroupId(GroupId(3)),
+            ]))))
+            .times(1)
+            .return_once(move |_| Ok(vec![group]));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(
+            LdapFilter::And(vec![LdapFilter::Equality(
+                "entryUUID".to_string(),
+                uuid.to_uppercase(),
+            )]),
+            vec!["entryUUID"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=robert,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "entryUUID".to_string(),
+                        vals: vec![uuid.to_string()],
+                    }],
+                }),
+                make_search_success(),
+            ]
+        );
+        // The UUIDs of the groups are derived from their IDs.
+        let group_uuid = make_entry_uuid("group", "3");
+        let request = make_group_search_request(
+            LdapFilter::And(vec![LdapFilter::Equality(
+                "entryUUID".to_string(),
+                group_uuid.clone(),
+            )]),
+            vec!["entryUUID"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=group_3,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "entryUUID".to_string(),
+                        vals: vec![group_uuid],
+                    }],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_attribute_names_case_insensitive() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![
+                UserRequestFilter::UserId(UserId::new("bob")),
+                UserRequestFilter::Equality("email".to_string(), "bob@bobmail.bob".to_string()),
+                UserRequestFilter::And(vec![]),
+            ]))))
+            .times(2)
+            .returning(|_| {
+                Ok(vec![User {
+                    user_id: UserId::new("bob"),
+                    email: "bob@bobmail.bob".to_string(),
+                    display_name: "Bob".to_string(),
+                    first_name: "Bob".to_string(),
+                    last_name: "Bobberson".to_string(),
+                    ..Default::default()
+                }])
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let search = |names: [&str; 3], attributes: Vec<&str>| {
+            make_user_search_request(
+                LdapFilter::And(vec![
+                    LdapFilter::Equality(names[0].to_string(), "bob".to_string()),
+                    LdapFilter::Equality(names[1].to_string(), "bob@bobmail.bob".to_string()),
+                    LdapFilter::Present(names[2].to_string()),
+                ]),
+                attributes,
+            )
+        };
+        let canonical = ldap_handler
+            .do_search(&search(
+                ["uid", "mail", "objectClass"],
+                vec!["uid", "mail", "givenName", "sn", "entryDN"],
+            ))
+            .await;
+        let mixed_case = ldap_handler
+            .do_search(&search(
+                ["UID", "Mail", "OBJECTCLASS"],
+                vec!["UID", "MAIL", "givenname", "SN", "EntryDn", "mail"],
+            ))
+            .await;
+        let values = |results: &[LdapOp]| match &results[0] {
+            LdapOp::SearchResultEntry(entry) => entry
+                .attributes
+                .iter()
+                .map(|a| (a.atype.to_lowercase(), a.vals.clone()))
+                .collect::<Vec<_>>(),
+            op => panic!("Expected an entry, got {:?}", op),
+        };
+        assert_eq!(canonical.len(), 2);
+        // The same values, once each, named as requested.
+        assert_eq!(values(&mixed_case), values(&canonical));
+        assert_eq!(
+            values(&canonical)[1],
+            ("mail".to_string(), vec!["bob@bobmail.bob".to_string()])
+        );
+        match &mixed_case[0] {
+            LdapOp::SearchResultEntry(entry) => assert_eq!(entry.attributes[1].atype, "MAIL"),
+            op => panic!("Expected an entry, got {:?}", op),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_filter_complexity() {
+        let filter = LdapFilter::And(vec![LdapFilter::Or(vec![
+            LdapFilter::Equality("uid".to_string(), "bob".to_string()),
+            LdapFilter::Substring(
+                "cn".to_string(),
+                LdapSubstringFilter {
+                    initial: Some("b".to_string()),
+                    any: vec!["o".to_string()],
+                    final_: None,
+                },
+            ),
+        ])]);
+        // 1 for the And, 2 for the Or, 3 for the equality and 3 + 2 for the substring.
+        assert_eq!(get_filter_complexity(&filter, 0), 11);
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let options = |max_filter_complexity| LdapHandlerOptions {
+            max_filter_complexity: Some(max_filter_complexity),
+            ..Default::default()
+        };
+        let mut ldap_handler = setup_bound_handler_with_options(mock, options(11)).await;
+        let request = make_user_search_request(filter, vec!["uid"]);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_success()]
+        );
+        let mut ldap_handler =
+            setup_bound_handler_with_options(MockTestBackendHandler::new(), options(10)).await;
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_error(
+                LdapResultCode::UnwillingToPerform,
+                "The filter is too complex: 11 for at most 10".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_modify_group_members() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::DisplayName(
+                "group_1".to_string(),
+            ))))
+            .times(2)
+            .returning(|_| {
+                Ok(vec![Group {
+                    id: GroupId(1),
+                    display_name: "group_1".to_string(),
+                    users: vec![UserId::new("bob"), UserId::new("jim")],
+                }])
+            });
+        mock.expect_add_user_to_group()
+            .with(eq(UserId::new("john")), eq(GroupId(1)))
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        mock.expect_remove_user_from_group()
+            .with(eq(UserId::new("jim")), eq(GroupId(1)))
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let modification = |operation, attribute: &str, values: &[&str]| Modification {
+            operation,
+            attribute: attribute.to_string(),
+            values: values.iter().map(|v| v.as_bytes().to_vec()).collect(),
+        };
+        // Bob is already a member.
+        let request = ModifyRequest {
+            dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+            changes: vec![
+                modification(
+                    ModifyOperation::Add,
+                    "member",
+                    &[
+                        "cn=bob,ou=people,dc=example,dc=com",
+                        "cn=john,ou=people,dc=example,dc=com",
+                    ],
+                ),
+                modification(ModifyOperation::Delete, "memberUid", &["jim"]),
+            ],
+        };
+        assert_eq!(
+            ldap_handler.do_modify(&request, &[]).await,
+            make_modify_response(LdapResultCode::Success, "".to_string())
+        );
+        // Nothing changes when one of the deleted users is not a member.
+        let request = ModifyRequest {
+            dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+            changes: vec![
+                modification(ModifyOperation::Add, "memberUid", &["john"]),
+                modification(
+                    ModifyOperation::Delete,
+                    "uniqueMember",
+                    &["cn=jane,ou=people,dc=example,dc=com"],
+                ),
+            ],
+        };
+        assert_eq!(
+            ldap_handler.do_modify(&request, &[]).await,
+            make_modify_response(
+                LdapResultCode::NoSuchAttribute,
+                r#""jane" is not a member of "group_1""#.to_string()
+            )
+        );
+        let request = ModifyRequest {
+            dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+            changes: vec![modification(ModifyOperation::Replace, "member", &[])],
+        };
+        assert_eq!(
+            ldap_handler.do_modify(&request, &[]).await,
+            make_modify_response(
+                LdapResultCode::UnwillingToPerform,
+                "Only adding and deleting the members of a group is supported".to_string()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_modify_group_members_not_admin() {
+        let mut ldap_handler = setup_bound_user_handler(MockTestBackendHandler::new(), &[]).await;
+        let request = ModifyRequest {
+            dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+            changes: vec![Modification {
+                operation: ModifyOperation::Add,
+                attribute: "memberUid".to_string(),
+                values: vec![b"bob".to_vec()],
+            }],
+        };
+        assert_eq!(
+            ldap_handler.do_modify(&request, &[]).await,
+            make_modify_response(
+                LdapResultCode::InsufficentAccessRights,
+                "Only admins can change the members of the groups".to_string()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_restrictions() {
+        let restriction = |identity: &str, base: &str| {
+            LdapSearchRestriction::new(identity, &[base.to_string()]).unwrap()
+        };
+        let refused = |base: &str| {
+            vec![make_search_error(
+                LdapResultCode::InsufficentAccessRights,
+                format!(r#"Not allowed to search under "{}""#, base),
+            )]
+        };
+        let mut mock = MockTestBackendHandler::new();
+        expect_no_changes(&mut mock);
+        mock.expect_list_users()
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = setup_bound_handler_with_options(
+            mock,
+            LdapHandlerOptions {
+                search_restrictions: vec![restriction(
+                    "UID=Test,ou=people,dc=example,dc=com",
+                    "ou=people,dc=example,dc=com",
+                )],
+                ..Default::default()
+            },
+        )
+        .await;
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["uid"]);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_success()]
+        );
+        for base in ["ou=groups,dc=example,dc=com", "dc=example,dc=com"] {
+            let request = make_search_request(base, LdapFilter::And(vec![]), vec!["cn"]);
+            assert_eq!(ldap_handler.do_search(&request).await, refused(base));
+        }
+        // Restricted through a group.
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("test")))
+            .times(1)
+            .return_once(|_| {
+                Ok(HashSet::from([GroupIdAndName(
+                    GroupId(1),
+                    "group_sync".to_string(),
+                )]))
+            });
+        let mut ldap_handler = setup_bound_handler_with_options(
+            mock,
+            LdapHandlerOptions {
+                search_restrictions: vec![restriction(
+                    "cn=group_sync,ou=groups,dc=example,dc=com",
+                    "ou=groups,dc=example,dc=com",
+                )],
+                ..Default::default()
+            },
+        )
+        .await;
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["uid"]);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            refused("ou=people,dc=example,dc=com")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_operations() {
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_request(
+                    LdapRequest::Unsupported(UnsupportedOperation::Abandon(2)),
+                    &[]
+                )
+                .await,
+            Some(vec![])
+        );
+        // The session is still bound.
+        assert!(ldap_handler.bound_dn().is_some());
+    }
+
+    fn make_add_request(dn: &str, attributes: &[(&str, &str)]) -> AddRequest {
+        AddRequest {
+            dn: dn.to_string(),
+            attributes: attributes
+                .iter()
+                .map(|(name, value)| (name.to_string(), vec![value.as_bytes().to_vec()]))
+                .collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_add_user() {
+        let mut mock = MockTestBackendHandler::new();
+        use lldap_auth::*;
+        let mut rng = rand::rngs::OsRng;
+        let registration_start_request =
+            opaque::client::registration::start_registration("password", &mut rng).unwrap();
+        let start_response = opaque::server::registration::start_registration(
+            &opaque::server::ServerSetup::new(&mut rng),
+            registration_start_request.message,
+            "bob",
+        )
+        .unwrap();
+        mock.expect_get_user_details()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .return_once(|_| Err(DomainError::InternalError("Not found".to_string())));
+        mock.expect_create_user()
+            .with(eq(CreateUserRequest {
+                user_id: UserId::new("bob"),
+                email: "bob@example.com".to_string(),
+                display_name: Some("Bob Bobberson".to_string()),
+                first_name: Some("Bob".to_string()),
+                last_name: Some("Bobberson".to_string()),
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        mock.expect_registration_start()
+            .withf(|request| request.username == "bob")
+            .times(1)
+            .return_once(|_| {
+                Ok(registration::ServerRegistrationStartResponse {
+                    server_data: "".to_string(),
+                    registration_response: start_response.message,
+                })
+            });
+        mock.expect_registration_finish()
+            .times(1)
+            .return_once(|_| Ok(()));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_add_request(
+            "uid=bob,ou=people,dc=example,dc=com",
+            &[
+                ("objectClass", "inetOrgPerson"),
+                ("mail", "bob@example.com"),
+                ("cn", "Bob Bobberson"),
+                ("givenName", "Bob"),
+                ("SN", "Bobberson"),
+                ("userPassword", "password"),
+            ],
+        );
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_request(LdapRequest::Add(request), &[])
+                .await,
+            Some(vec![LdapResponse {
+                op: make_add_response(LdapResultCode::Success, "".to_string()),
+                controls: vec![],
+            }])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_user_errors() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_user_details()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .return_once(|_| Ok(User::default()));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let bob = [("uid", "bob"), ("mail", "bob@example.com")];
+        assert_eq!(
+            ldap_handler
+                .do_add(&make_add_request(
+                    "cn=Bob,ou=people,dc=example,dc=com",
+                    &bob
+                ))
+                .await,
+            (
+                LdapResultCode::EntryAlreadyExists,
+                r#"The user "bob" already exists"#.to_string()
+            )
+        );
+        assert_eq!(
+            ldap_handler
+                .do_add(&make_add_request(
+                    "uid=bob,ou=people,dc=example,dc=com",
+                    &[("uid", "bob")]
+                ))
+                .await,
+            (
+                LdapResultCode::ObjectClassViolation,
+                "Missing mail attribute".to_string()
+            )
+        );
+        assert_eq!(
+            ldap_handler
+                .do_add(&make_add_request(
+                    "cn=group_1,ou=groups,dc=example,dc=com",
+                    &bob
+                ))
+                .await
+                .0,
+            LdapResultCode::UnwillingToPerform
+        );
+        let mut ldap_handler = setup_bound_user_handler(MockTestBackendHandler::new(), &[]).await;
+        assert_eq!(
+            ldap_handler
+                .do_add(&make_add_request(
+                    "uid=jim,ou=people,dc=example,dc=com",
+                    &bob
+                ))
+                .await,
+            (
+                LdapResultCode::InsufficentAccessRights,
+                "Only admins can add entries".to_string()
+            )
+        );
+    }
+
+    fn make_delete_request(dn: &str) -> DeleteRequest {
+        DeleteRequest { dn: dn.to_string() }
+    }
+
+    #[tokio::test]
+    async fn test_delete_user() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_user_details()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .return_once(|_| {
+                Ok(User {
+                    user_id: UserId::new("bob"),
+                    ..Default::default()
+                })
+            });
+        mock.expect_delete_user()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .return_once(|_| Ok(()));
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::DisplayName(
+                "group_1".to_string(),
+            ))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![Group {
+                    id: GroupId(1),
+                    display_name: "group_1".to_string(),
+                    users: vec![UserId::new("bob")],
+                }])
+            });
+        mock.expect_delete_group()
+            .with(eq(GroupId(1)))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_request(
+                    LdapRequest::Delete(make_delete_request("uid=bob,ou=people,dc=example,dc=com")),
+                    &[]
+                )
+                .await,
+            Some(vec![LdapResponse {
+                op: make_delete_response(LdapResultCode::Success, "".to_string()),
+                controls: vec![],
+            }])
+        );
+        assert_eq!(
+            ldap_handler
+                .do_delete(&make_delete_request(
+                    "cn=group_1,ou=groups,dc=example,dc=com"
+                ))
+                .await
+                .0,
+            LdapResultCode::Success
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_errors() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_user_details()
+            .with(eq(UserId::new("jim")))
+            .times(1)
+            .return_once(|_| Err(DomainError::InternalError("Not found".to_string())));
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::DisplayName(
+                "missing".to_string(),
+            ))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        for (dn, code) in [
+            (
+                "uid=jim,ou=people,dc=example,dc=com",
+                LdapResultCode::NoSuchObject,
+            ),
+            (
+                "cn=missing,ou=groups,dc=example,dc=com",
+                LdapResultCode::NoSuchObject,
+            ),
+            (
+                "cn=whatever,dc=example,dc=com",
+                LdapResultCode::NoSuchObject,
+            ),
+            (
+                "ou=people,dc=example,dc=com",
+                LdapResultCode::NotAllowedOnNonLeaf,
+            ),
+            ("dc=example,dc=com", LdapResultCode::NotAllowedOnNonLeaf),
+            (
+                "uid=test,ou=people,dc=example,dc=com",
+                LdapResultCode::UnwillingToPerform,
+            ),
+            (
+                "cn=lldap_admin,ou=groups,dc=example,dc=com",
+                LdapResultCode::UnwillingToPerform,
+            ),
+            ("not a dn", LdapResultCode::InvalidDNSyntax),
+        ] {
+            assert_eq!(
+                ldap_handler.do_delete(&make_delete_request(dn)).await.0,
+                code,
+                "{}",
+                dn
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delete_not_admin() {
+        let mut ldap_handler = setup_bound_user_handler(MockTestBackendHandler::new(), &[]).await;
+        assert_eq!(
+            ldap_handler
+                .do_delete(&make_delete_request("uid=jim,ou=people,dc=example,dc=com"))
+                .await,
+            (
+                LdapResultCode::InsufficentAccessRights,
+                "Only admins can delete entries".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_escape_for_log() {
+        assert_eq!(
+            escape_for_log("uid=bob,ou=people,dc=example,dc=com"),
+            "uid=bob,ou=people,dc=example,dc=com"
+        );
+        assert_eq!(
+            escape_for_log("uid=bob\n\"x\",dc=com"),
+            "uid=bob\\n\\\"x\\\",dc=com"
+        );
+        let long_dn = "a".repeat(LOGGED_DN_MAX_CHARS + 1);
+        assert_eq!(
+            escape_for_log(&long_dn),
+            format!("{}...", "a".repeat(LOGGED_DN_MAX_CHARS))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_attribute_value_syntax() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_user_details()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .return_once(|_| Err(DomainError::InternalError("Not found".to_string())));
+        mock.expect_create_user()
+            .with(eq(CreateUserRequest {
+                user_id: UserId::new("bob"),
+                email: "bob@example.com".to_string(),
+                display_name: Some("Bób Bobbersön".to_string()),
+                ..Default::default()
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let invalid_utf8 = vec![0x42, 0xff, 0xfe];
+        let add_request = |attribute: &str, value: Vec<u8>| AddRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            attributes: vec![
+                ("mail".to_string(), vec![b"bob@example.com".to_vec()]),
+                (attribute.to_string(), vec![value]),
+            ],
+        };
+        assert_eq!(
+            ldap_handler
+                .do_add(&add_request("displayName", invalid_utf8.clone()))
+                .await,
+            (
+                LdapResultCode::InvalidAttributeSyntax,
+                r#"The value of "displayName" is not valid UTF-8"#.to_string()
+            )
+        );
+        // The binary attributes are not checked, but can't be set.
+        assert_eq!(
+            ldap_handler
+                .do_add(&add_request("jpegPhoto", invalid_utf8.clone()))
+                .await
+                .0,
+            LdapResultCode::UnwillingToPerform
+        );
+        assert_eq!(
+            ldap_handler
+                .do_add(&add_request(
+                    "displayName",
+                    "Bób Bobbersön".as_bytes().to_vec()
+                ))
+                .await
+                .0,
+            LdapResultCode::Success
+        );
+        for (dn, attribute) in [
+            ("uid=bob,ou=people,dc=example,dc=com", "userPassword"),
+            ("cn=group_1,ou=groups,dc=example,dc=com", "memberUid"),
+        ] {
+            let request = ModifyRequest {
+                dn: dn.to_string(),
+                changes: vec![Modification {
+                    operation: ModifyOperation::Replace,
+                    attribute: attribute.to_string(),
+                    values: vec![invalid_utf8.clone()],
+                }],
+            };
+            assert_eq!(
+                ldap_handler.do_modify(&request, &[]).await,
+                make_modify_response(
+                    LdapResultCode::InvalidAttributeSyntax,
+                    format!(r#"The value of "{}" is not valid UTF-8"#, attribute)
+                )
+            );
         }
-        #[async_trait]
-        impl OpaqueHandler for TestBackendHandler {
-            async fn login_start(
-                &self,
-                request: login::ClientLoginStartRequest
-            ) -> Result<login::ServerLoginStartResponse>;
-            async fn login_finish(&self, request: login::ClientLoginFinishRequest) -> Result<UserId>;
-            async fn registration_start(
-                &self,
-                request: registration::ClientRegistrationStartRequest
-            ) -> Result<registration::ServerRegistrationStartResponse>;
-            async fn registration_finish(
-                &self,
-                request: registration::ClientRegistrationFinishRequest
-            ) -> Result<()>;
+    }
+
+    #[tokio::test]
+    async fn test_bind_dn_template() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: UserId::new("bob"),
+                password: "pass".to_string(),
+            }))
+            .times(2)
+            .returning(|_| Ok(()));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions {
+                bind_dn_template: Some("uid=%u,ou=people,dc=example,dc=com".to_string()),
+                ..Default::default()
+            },
+            None,
+        );
+        let make_request = |dn: &str| LdapBindRequest {
+            dn: dn.to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        for dn in ["bob", "cn=bob,ou=people,dc=example,dc=com"] {
+            assert_eq!(
+                ldap_handler.do_bind(&make_request(dn)).await,
+                (LdapResultCode::Success, "".to_string()),
+                "{}",
+                dn
+            );
+            assert_eq!(
+                ldap_handler.dn,
+                LdapDn("cn=bob,ou=people,dc=example,dc=com".to_string())
+            );
         }
     }
 
-    fn make_search_request<S: Into<String>>(
-        base: &str,
-        filter: LdapFilter,
-        attrs: Vec<S>,
-    ) -> LdapSearchRequest {
-        LdapSearchRequest {
-            base: base.to_string(),
-            scope: LdapSearchScope::Base,
-            aliases: LdapDerefAliases::Never,
-            sizelimit: 0,
-            timelimit: 0,
-            typesonly: false,
-            filter,
-            attrs: attrs.into_iter().map(Into::into).collect(),
+    #[tokio::test]
+    async fn test_search_hidden_attributes() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![User {
+                    user_id: UserId::new("bob"),
+                    email: "bob@bobmail.bob".to_string(),
+                    display_name: "bob".to_string(),
+                    ..Default::default()
+                }])
+            });
+        let mut ldap_handler = setup_bound_handler_with_options(
+            mock,
+            LdapHandlerOptions {
+                hidden_attributes: vec!["mail".to_string()],
+                ..Default::default()
+            },
+        )
+        .await;
+        let request = make_user_search_request(
+            LdapFilter::And(vec![]),
+            vec!["uid", "userPassword", "MAIL", "UserPassword"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "uid".to_string(),
+                        vals: vec!["bob".to_string()],
+                    }],
+                }),
+                make_search_success(),
+            ]
+        );
+        for attribute in ["userPassword", "mail"] {
+            let request =
+                make_user_search_request(LdapFilter::Present(attribute.to_string()), vec!["uid"]);
+            assert_eq!(
+                ldap_handler.do_search(&request).await,
+                vec![make_search_error(
+                    LdapResultCode::InsufficentAccessRights,
+                    format!(r#"Cannot filter on "{}""#, attribute),
+                )]
+            );
         }
     }
 
-    fn make_user_search_request<S: Into<String>>(
-        filter: LdapFilter,
-        attrs: Vec<S>,
-    ) -> LdapSearchRequest {
-        make_search_request::<S>("ou=people,dc=example,dc=com", filter, attrs)
+    #[tokio::test]
+    async fn test_search_matched_values() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![User {
+                    user_id: UserId::new("bob"),
+                    email: "bob@bobmail.bob".to_string(),
+                    display_name: "bob".to_string(),
+                    ..Default::default()
+                }])
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request =
+            make_user_search_request(LdapFilter::And(vec![]), vec!["objectClass", "uid", "mail"]);
+        let make_control = |items: &[BerElement]| RawControl {
+            oid: MATCHED_VALUES_OID.to_string(),
+            criticality: true,
+            value: Some(BerElement::sequence(items).encode()),
+        };
+        let control = make_control(&[
+            BerElement::constructed(
+                0xA3,
+                &[
+                    BerElement::octet_string("objectclass"),
+                    BerElement::octet_string("PERSON"),
+                ],
+            ),
+            BerElement {
+                tag: context_tag(7),
+                value: b"mail".to_vec(),
+            },
+        ]);
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(LdapOp::SearchRequest(request.clone()), &[control])
+                .await,
+            Some(vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "objectClass".to_string(),
+                            vals: vec!["person".to_string()],
+                        },
+                        LdapPartialAttribute {
+                            atype: "uid".to_string(),
+                            vals: vec![],
+                        },
+                        LdapPartialAttribute {
+                            atype: "mail".to_string(),
+                            vals: vec!["bob@bobmail.bob".to_string()],
+                        },
+                    ],
+                })
+                .into(),
+                make_search_success().into(),
+            ])
+        );
+        // Only the simple filter items are allowed.
+        let control = make_control(&[BerElement::constructed(0xA0, &[])]);
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(LdapOp::SearchRequest(request), &[control])
+                .await,
+            Some(vec![make_search_error(
+                LdapResultCode::ProtocolError,
+                "Invalid matched values control: Only simple filter items are allowed in a values filter"
+                    .to_string(),
+            )
+            .into()])
+        );
     }
 
-    async fn setup_bound_handler(
-        mut mock: MockTestBackendHandler,
-    ) -> LdapHandler<MockTestBackendHandler> {
+    #[tokio::test]
+    async fn test_search_renamed_ous() {
+        let mut mock = MockTestBackendHandler::new();
         mock.expect_bind()
             .with(eq(BindRequest {
                 name: UserId::new("test"),
                 password: "pass".to_string(),
             }))
             .return_once(|_| Ok(()));
-        let mut ldap_handler =
-            LdapHandler::new(mock, "dc=example,dc=com".to_string(), UserId::new("test"));
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![User {
+                user_id: UserId::new("bob"),
+                display_name: "bob".to_string(),
+                ..Default::default()
+            }])
+        });
+        mock.expect_list_groups().times(1).return_once(|_| {
+            Ok(vec![Group {
+                id: GroupId(1),
+                display_name: "dev".to_string(),
+                users: vec![UserId::new("bob")],
+            }])
+        });
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("test"),
+            LdapHandlerOptions {
+                people_ou: "cn=Users".to_string(),
+                groups_ou: "ou=teams".to_string(),
+                ..Default::default()
+            },
+            None,
+        );
         let request = LdapBindRequest {
-            dn: "cn=test,ou=people,dc=example,dc=com".to_string(),
+            dn: "cn=test,cn=users,dc=example,dc=com".to_string(),
             cred: LdapBindCred::Simple("pass".to_string()),
         };
         assert_eq!(
             ldap_handler.do_bind(&request).await.0,
             LdapResultCode::Success
         );
-        ldap_handler
+        let request = LdapSearchRequest {
+            scope: LdapSearchScope::OneLevel,
+            ..make_search_request(
+                "cn=Users,dc=example,dc=com",
+                LdapFilter::And(vec![]),
+                vec!["dn"],
+            )
+        };
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=bob,cn=Users,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "dn".to_string(),
+                        vals: vec!["cn=bob,cn=Users,dc=example,dc=com".to_string()]
+                    }],
+                }),
+                make_search_success(),
+            ]
+        );
+        let request = LdapSearchRequest {
+            scope: LdapSearchScope::OneLevel,
+            ..make_search_request(
+                "ou=teams,dc=example,dc=com",
+                LdapFilter::And(vec![]),
+                vec!["member"],
+            )
+        };
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=dev,ou=teams,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "member".to_string(),
+                        vals: vec!["cn=bob,cn=Users,dc=example,dc=com".to_string()]
+                    }],
+                }),
+                make_search_success(),
+            ]
+        );
+        // The default OUs no longer exist.
+        let request = make_search_request(
+            "ou=people,dc=example,dc=com",
+            LdapFilter::And(vec![]),
+            vec!["dn"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![LdapOp::SearchResultDone(LdapResult {
+                code: LdapResultCode::NoSuchObject,
+                matcheddn: "dc=example,dc=com".to_string(),
+                message: r#"No such entry: "ou=people,dc=example,dc=com""#.to_string(),
+                referral: vec![],
+            })]
+        );
     }
 
     #[tokio::test]
-    async fn test_bind() {
+    async fn test_bind_account_locked() {
         let mut mock = MockTestBackendHandler::new();
         mock.expect_bind()
-            .with(eq(crate::domain::handler::BindRequest {
-                name: UserId::new("bob"),
-                password: "pass".to_string(),
-            }))
-            .times(1)
-            .return_once(|_| Ok(()));
-        let mut ldap_handler =
-            LdapHandler::new(mock, "dc=example,dc=com".to_string(), UserId::new("test"));
-
+            .times(2)
+            .returning(|_| Err(DomainError::AuthenticationError("test".to_string())));
+        let make_handler = |mock, report_account_lockout| {
+            LdapHandler::new(
+                mock,
+                "dc=example,dc=com".to_string(),
+                UserId::new("test"),
+                LdapHandlerOptions {
+                    bind_failure_tracker: Some(Arc::new(BindFailureTracker::new(
+                        1,
+                        std::time::Duration::ZERO,
+                    ))),
+                    report_account_lockout,
+                    ..Default::default()
+                },
+                None,
+            )
+        };
+        let mut ldap_handler = make_handler(mock, true);
         let request = LdapOp::BindRequest(LdapBindRequest {
-            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
-            cred: LdapBindCred::Simple("pass".to_string()),
+            dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("wrong".to_string()),
         });
+        let control = RawControl {
+            oid: PASSWORD_POLICY_OID.to_string(),
+            criticality: false,
+            value: None,
+        };
+        // The first failure is a plain wrong password.
         assert_eq!(
-            ldap_handler.handle_ldap_message(request).await,
-            Some(vec![LdapOp::BindResponse(LdapBindResponse {
-                res: LdapResult {
-                    code: LdapResultCode::Success,
-                    matcheddn: "".to_string(),
-                    message: "".to_string(),
-                    referral: vec![],
-                },
-                saslcreds: None,
-            })]),
+            ldap_handler
+                .handle_ldap_message(request.clone(), &[control.clone()])
+                .await,
+            Some(vec![LdapResponse {
+                op: make_bind_response(LdapResultCode::InvalidCredentials, "".to_string()).into(),
+                controls: vec![make_password_policy_control(None)],
+            }])
+        );
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(request.clone(), &[control.clone()])
+                .await,
+            Some(vec![LdapResponse {
+                op: make_bind_response(
+                    LdapResultCode::InvalidCredentials,
+                    "Too many failed binds, the account is temporarily locked".to_string()
+                )
+                .into(),
+                controls: vec![RawControl {
+                    oid: PASSWORD_POLICY_OID.to_string(),
+                    criticality: false,
+                    value: Some(vec![0x30, 0x03, 0x81, 0x01, 0x01]),
+                }],
+            }])
+        );
+        // By default, the lockout is not revealed.
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_bind()
+            .times(2)
+            .returning(|_| Err(DomainError::AuthenticationError("test".to_string())));
+        let mut ldap_handler = make_handler(mock, false);
+        ldap_handler.handle_ldap_message(request.clone(), &[]).await;
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request, &[control]).await,
+            Some(vec![make_bind_response(
+                LdapResultCode::InvalidCredentials,
+                "".to_string()
+            )
+            .into()])
         );
     }
 
     #[tokio::test]
-    async fn test_admin_bind() {
+    async fn test_search_cached_bound_user() {
         let mut mock = MockTestBackendHandler::new();
-        mock.expect_bind()
-            .with(eq(crate::domain::handler::BindRequest {
-                name: UserId::new("test"),
-                password: "pass".to_string(),
-            }))
-            .times(1)
-            .return_once(|_| Ok(()));
-        let mut ldap_handler =
-            LdapHandler::new(mock, "dc=example,dc=com".to_string(), UserId::new("test"));
-
+        expect_no_changes(&mut mock);
+        mock.expect_list_users().times(3).returning(|_| {
+            Ok(vec![User {
+                user_id: UserId::new("bob"),
+                display_name: "bob".to_string(),
+                ..Default::default()
+            }])
+        });
+        // The groups are read again after the cache is cleared.
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("bob")))
+            .times(2)
+            .returning(|_| Ok(HashSet::new()));
+        mock.expect_bind().return_once(|_| Ok(()));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions::default(),
+            None,
+        );
         let request = LdapBindRequest {
-            dn: "cn=test,ou=people,dc=example,dc=com".to_string(),
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
             cred: LdapBindCred::Simple("pass".to_string()),
         };
         assert_eq!(
-            ldap_handler.do_bind(&request).await.0,
-            LdapResultCode::Success
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+        let search = || {
+            LdapRequest::Op(LdapOp::SearchRequest(make_user_search_request(
+                LdapFilter::And(vec![
+                    LdapFilter::Equality("objectClass".to_string(), "person".to_string()),
+                    LdapFilter::Equality("uid".to_string(), "bob".to_string()),
+                ]),
+                vec!["uid"],
+            )))
+        };
+        let expected = Some(vec![
+            LdapResponse::from(LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                attributes: vec![LdapPartialAttribute {
+                    atype: "uid".to_string(),
+                    vals: vec!["bob".to_string()],
+                }],
+            })),
+            make_search_success().into(),
+        ]);
+        // Read once, then from the cache.
+        assert_eq!(
+            ldap_handler.handle_ldap_request(search(), &[]).await,
+            expected
+        );
+        assert_eq!(
+            ldap_handler.handle_ldap_request(search(), &[]).await,
+            expected
+        );
+        // Any change through the connection clears the cache, even when it fails.
+        ldap_handler
+            .handle_ldap_request(
+                LdapRequest::Delete(DeleteRequest {
+                    dn: "cn=jim,ou=people,dc=example,dc=com".to_string(),
+                }),
+                &[],
+            )
+            .await;
+        assert_eq!(
+            ldap_handler.handle_ldap_request(search(), &[]).await,
+            expected
+        );
+        ldap_handler.options.cache_bound_user = false;
+        assert_eq!(
+            ldap_handler.handle_ldap_request(search(), &[]).await,
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_match_all_filter() {
+        let mut mock = MockTestBackendHandler::new();
+        // No condition for the backend to evaluate on each entry.
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![]))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::And(vec![]))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = LdapSearchRequest {
+            scope: LdapSearchScope::Subtree,
+            ..make_search_request(
+                "dc=example,dc=com",
+                LdapFilter::And(vec![
+                    LdapFilter::Present("objectClass".to_string()),
+                    LdapFilter::Or(vec![
+                        LdapFilter::Equality("uid".to_string(), "bob".to_string()),
+                        LdapFilter::Present("cn".to_string()),
+                    ]),
+                ]),
+                vec!["cn"],
+            )
+        };
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_success()]
         );
     }
 
     #[tokio::test]
-    async fn test_search_non_admin_user() {
+    async fn test_admin_group_of_configuration() {
         let mut mock = MockTestBackendHandler::new();
-        mock.expect_bind()
-            .with(eq(crate::domain::handler::BindRequest {
-                name: UserId::new("test"),
-                password: "pass".to_string(),
-            }))
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::DisplayName(
+                "Domain Admins".to_string(),
+            ))))
             .times(1)
-            .return_once(|_| Ok(()));
-        mock.expect_list_users()
-            .with(eq(Some(UserRequestFilter::And(vec![
-                UserRequestFilter::And(vec![]),
-                UserRequestFilter::UserId(UserId::new("test")),
-            ]))))
+            .return_once(|_| {
+                Ok(vec![Group {
+                    id: GroupId(5),
+                    display_name: "Domain Admins".to_string(),
+                    users: vec![UserId::new("bob"), UserId::new("ann")],
+                }])
+            });
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::DisplayName(
+                "lldap_admin".to_string(),
+            ))))
             .times(1)
             .return_once(|_| {
-                Ok(vec![User {
-                    display_name: "test".to_string(),
-                    ..Default::default()
+                Ok(vec![Group {
+                    id: GroupId(1),
+                    display_name: "lldap_admin".to_string(),
+                    users: vec![UserId::new("ann")],
                 }])
             });
-        let mut ldap_handler =
-            LdapHandler::new(mock, "dc=example,dc=com".to_string(), UserId::new("admin"));
-
-        let request = LdapBindRequest {
-            dn: "cn=test,ou=people,dc=example,dc=com".to_string(),
-            cred: LdapBindCred::Simple("pass".to_string()),
+        mock.expect_add_user_to_group()
+            .with(eq(UserId::new("jim")), eq(GroupId(5)))
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        mock.expect_remove_user_from_group()
+            .with(eq(UserId::new("ann")), eq(GroupId(5)))
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        // The role of lldap is given to the new members, but not taken from the old ones.
+        mock.expect_add_user_to_group()
+            .with(eq(UserId::new("jim")), eq(GroupId(1)))
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        // Bob is an admin through the group of the configuration.
+        let mut ldap_handler = setup_bound_user_handler(mock, &["Domain Admins"]).await;
+        ldap_handler.options.admin_group = Some("domain admins".to_string());
+        let request = ModifyRequest {
+            dn: "cn=Domain Admins,ou=groups,dc=example,dc=com".to_string(),
+            changes: vec![
+                Modification {
+                    operation: ModifyOperation::Add,
+                    attribute: "memberUid".to_string(),
+                    values: vec![b"jim".to_vec()],
+                },
+                Modification {
+                    operation: ModifyOperation::Delete,
+                    attribute: "memberUid".to_string(),
+                    values: vec![b"ann".to_vec()],
+                },
+            ],
         };
         assert_eq!(
-            ldap_handler.do_bind(&request).await.0,
-            LdapResultCode::Success
+            ldap_handler.do_modify(&request, &[]).await,
+            make_modify_response(LdapResultCode::Success, "".to_string())
         );
+    }
 
-        let request =
-            make_user_search_request::<String>(LdapFilter::And(vec![]), vec!["1.1".to_string()]);
+    #[test]
+    fn test_parse_sasl_plain_credentials() {
         assert_eq!(
-            ldap_handler.do_search(&request).await,
-            vec![
-                LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: "cn=test,ou=people,dc=example,dc=com".to_string(),
-                    attributes: vec![],
-                }),
-                make_search_success()
-            ],
+            parse_sasl_plain_credentials(b"\0bob\0pass"),
+            Some(("", "bob", "pass"))
         );
+        assert_eq!(
+            parse_sasl_plain_credentials(b"u:alice\0dn:uid=bob,ou=people,dc=example,dc=com\0pass"),
+            Some(("u:alice", "dn:uid=bob,ou=people,dc=example,dc=com", "pass"))
+        );
+        assert_eq!(parse_sasl_plain_credentials(b""), None);
+        assert_eq!(parse_sasl_plain_credentials(b"bob\0pass"), None);
+        assert_eq!(parse_sasl_plain_credentials(b"\0\0pass"), None);
+        assert_eq!(parse_sasl_plain_credentials(b"\0bob\0"), None);
+        assert_eq!(parse_sasl_plain_credentials(b"\0bob\0pass\0more"), None);
+        assert_eq!(parse_sasl_plain_credentials(b"\0bob\0\xff"), None);
     }
 
     #[tokio::test]
-    async fn test_bind_invalid_dn() {
-        let mock = MockTestBackendHandler::new();
-        let mut ldap_handler =
-            LdapHandler::new(mock, "dc=example,dc=com".to_string(), UserId::new("admin"));
-
-        let request = LdapBindRequest {
-            dn: "cn=bob,dc=example,dc=com".to_string(),
-            cred: LdapBindCred::Simple("pass".to_string()),
+    async fn test_sasl_plain_bind() {
+        let plain = |credentials: &[u8]| SaslBindRequest {
+            dn: "".to_string(),
+            mechanism: "PLAIN".to_string(),
+            credentials: Some(credentials.to_vec()),
         };
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: UserId::new("bob"),
+                password: "pass".to_string(),
+            }))
+            .times(2)
+            .returning(|_| Ok(()));
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: UserId::new("bob"),
+                password: "wrong".to_string(),
+            }))
+            .times(1)
+            .return_once(|_| Err(DomainError::AuthenticationError("wrong".to_string())));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions::default(),
+            None,
+        );
+        // Not over TLS.
         assert_eq!(
-            ldap_handler.do_bind(&request).await.0,
-            LdapResultCode::NamingViolation,
+            ldap_handler.do_sasl_bind(&plain(b"\0bob\0pass")).await.0,
+            LdapResultCode::ConfidentialityRequired
         );
-        let request = LdapBindRequest {
-            dn: "cn=bob,ou=groups,dc=example,dc=com".to_string(),
-            cred: LdapBindCred::Simple("pass".to_string()),
-        };
+        ldap_handler.set_tls_active();
         assert_eq!(
-            ldap_handler.do_bind(&request).await.0,
-            LdapResultCode::NamingViolation,
+            ldap_handler.do_sasl_bind(&plain(b"bob\0pass")).await.0,
+            LdapResultCode::ProtocolError
         );
-        let request = LdapBindRequest {
-            dn: "cn=bob,ou=people,dc=example,dc=fr".to_string(),
-            cred: LdapBindCred::Simple("pass".to_string()),
-        };
         assert_eq!(
-            ldap_handler.do_bind(&request).await.0,
-            LdapResultCode::NamingViolation,
+            ldap_handler.do_sasl_bind(&plain(b"\0bob\0wrong")).await,
+            (LdapResultCode::InvalidCredentials, "".to_string())
         );
-        let request = LdapBindRequest {
-            dn: "cn=bob=test,ou=people,dc=example,dc=com".to_string(),
-            cred: LdapBindCred::Simple("pass".to_string()),
-        };
+        assert!(ldap_handler.is_anonymous());
         assert_eq!(
-            ldap_handler.do_bind(&request).await.0,
-            LdapResultCode::NamingViolation,
+            ldap_handler.do_sasl_bind(&plain(b"\0u:bob\0pass")).await,
+            (LdapResultCode::Success, "".to_string())
+        );
+        assert_eq!(
+            ldap_handler.dn,
+            LdapDn("cn=bob,ou=people,dc=example,dc=com".to_string())
+        );
+        assert_eq!(ldap_handler.user_id, UserId::new("bob"));
+        // The authorization ID can be the user itself.
+        assert_eq!(
+            ldap_handler
+                .do_sasl_bind(&plain(
+                    b"dn:cn=bob,ou=people,dc=example,dc=com\0dn:uid=bob,ou=people,dc=example,dc=com\0pass"
+                ))
+                .await,
+            (LdapResultCode::Success, "".to_string())
         );
+        assert_eq!(ldap_handler.user_id, UserId::new("bob"));
     }
 
-    #[test]
-    fn test_is_subtree() {
-        let subtree1 = &[
-            ("ou".to_string(), "people".to_string()),
-            ("dc".to_string(), "example".to_string()),
-            ("dc".to_string(), "com".to_string()),
-        ];
-        let root = &[
-            ("dc".to_string(), "example".to_string()),
-            ("dc".to_string(), "com".to_string()),
-        ];
-        assert!(is_subtree(subtree1, root));
-        assert!(!is_subtree(&[], root));
+    #[tokio::test]
+    async fn test_sasl_plain_bind_with_authz_id() {
+        let plain = |credentials: &[u8]| SaslBindRequest {
+            dn: "".to_string(),
+            mechanism: "PLAIN".to_string(),
+            credentials: Some(credentials.to_vec()),
+        };
+        let mut mock = MockTestBackendHandler::new();
+        expect_no_changes(&mut mock);
+        mock.expect_bind().times(2).returning(|_| Ok(()));
+        let mut groups = HashSet::new();
+        groups.insert(GroupIdAndName(GroupId(3), "proxies".to_string()));
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .return_once(|_| Ok(groups));
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("jim")))
+            .times(1)
+            .return_once(|_| Ok(HashSet::new()));
+        mock.expect_get_user_details()
+            .with(eq(UserId::new("alice")))
+            .times(1)
+            .return_once(|_| Ok(User::default()));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions {
+                proxy_group: Some("proxies".to_string()),
+                allow_insecure_sasl_plain: true,
+                ..Default::default()
+            },
+            None,
+        );
+        assert_eq!(
+            ldap_handler
+                .do_sasl_bind(&plain(b"u:alice\0bob\0pass"))
+                .await,
+            (LdapResultCode::Success, "".to_string())
+        );
+        assert_eq!(
+            ldap_handler.dn,
+            LdapDn("cn=alice,ou=people,dc=example,dc=com".to_string())
+        );
+        assert_eq!(ldap_handler.user_id, UserId::new("alice"));
+        // Jim is not a proxy.
+        assert_eq!(
+            ldap_handler
+                .do_sasl_bind(&plain(b"u:alice\0jim\0pass"))
+                .await
+                .0,
+            LdapResultCode::InsufficentAccessRights
+        );
+        assert!(ldap_handler.is_anonymous());
     }
 
-    #[test]
-    fn test_parse_distinguished_name() {
-        let parsed_dn = &[
-            ("ou".to_string(), "people".to_string()),
-            ("dc".to_string(), "example".to_string()),
-            ("dc".to_string(), "com".to_string()),
-        ];
+    #[tokio::test]
+    async fn test_search_verbose_result_messages() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![]))))
+            .times(2)
+            .returning(|_| {
+                Ok(vec![
+                    User {
+                        user_id: UserId::new("bob"),
+                        ..Default::default()
+                    },
+                    User {
+                        user_id: UserId::new("john"),
+                        ..Default::default()
+                    },
+                ])
+            });
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::Or(vec![
+                GroupRequestFilter::DisplayName("lldap_employees".to_string()),
+            ]))))
+            .times(2)
+            .returning(|_| {
+                Ok(vec![Group {
+                    id: GroupId(1),
+                    display_name: "lldap_employees".to_string(),
+                    users: vec![UserId::new("bob")],
+                }])
+            });
+        let mut ldap_handler = setup_bound_handler_with_options(
+            mock,
+            LdapHandlerOptions {
+                user_ou_mapping: vec![("employees".to_string(), "lldap_employees".to_string())],
+                verbose_result_messages: true,
+                ..Default::default()
+            },
+        )
+        .await;
+        let done = |message: &str| {
+            LdapOp::SearchResultDone(LdapResult {
+                code: LdapResultCode::Success,
+                matcheddn: "".to_string(),
+                message: message.to_string(),
+                referral: vec![],
+            })
+        };
+        // John is not in the OU.
+        let request = LdapSearchRequest {
+            scope: LdapSearchScope::OneLevel,
+            ..make_search_request(
+                "ou=employees,ou=people,dc=example,dc=com",
+                LdapFilter::And(vec![]),
+                vec!["1.1"],
+            )
+        };
         assert_eq!(
-            parse_distinguished_name("ou=people,dc=example,dc=com").expect("parsing failed"),
-            parsed_dn
+            ldap_handler.do_search(&request).await.pop(),
+            Some(done("returned 1 entries (1 filtered)"))
         );
+        // Bob is not directly in the people OU, but the OU is.
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["1.1"]);
         assert_eq!(
-            parse_distinguished_name(" ou  = people , dc = example , dc =  com ")
-                .expect("parsing failed"),
-            parsed_dn
+            ldap_handler.do_search(&request).await.pop(),
+            Some(done("returned 2 entries (1 filtered)"))
         );
     }
 
     #[tokio::test]
-    async fn test_search_users() {
-        use chrono::prelude::*;
+    async fn test_search_unreadable_entries() {
         let mut mock = MockTestBackendHandler::new();
-        mock.expect_list_users().times(1).return_once(|_| {
-            Ok(vec![
-                User {
-                    user_id: UserId::new("bob_1"),
-                    email: "bob@bobmail.bob".to_string(),
-                    display_name: "Bôb Böbberson".to_string(),
-                    first_name: "Bôb".to_string(),
-                    last_name: "Böbberson".to_string(),
-                    ..Default::default()
-                },
-                User {
-                    user_id: UserId::new("jim"),
-                    email: "jim@cricket.jim".to_string(),
-                    display_name: "Jimminy Cricket".to_string(),
-                    first_name: "Jim".to_string(),
-                    last_name: "Cricket".to_string(),
-                    creation_date: Utc.ymd(2014, 7, 8).and_hms(9, 10, 11),
-                },
-            ])
+        mock.expect_list_users().return_once(|_| {
+            Ok(vec![User {
+                user_id: UserId::new("bob"),
+                display_name: "bob".to_string(),
+                ..Default::default()
+            }])
         });
-        let mut ldap_handler = setup_bound_handler(mock).await;
-        let request = make_user_search_request(
-            LdapFilter::And(vec![]),
-            vec![
-                "objectClass",
-                "dn",
-                "uid",
-                "mail",
-                "givenName",
-                "sn",
-                "cn",
-                "createTimestamp",
-            ],
-        );
-        assert_eq!(
-            ldap_handler.do_search(&request).await,
-            vec![
-                LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: "cn=Bôb Böbberson,ou=people,dc=example,dc=com".to_string(),
-                    attributes: vec![
-                        LdapPartialAttribute {
-                            atype: "objectClass".to_string(),
-                            vals: vec![
-                                "inetOrgPerson".to_string(),
-                                "posixAccount".to_string(),
-                                "mailAccount".to_string(),
-                                "person".to_string()
-                            ]
-                        },
-                        LdapPartialAttribute {
-                            atype: "dn".to_string(),
-                            vals: vec!["cn=Bôb Böbberson,ou=people,dc=example,dc=com".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "uid".to_string(),
-                            vals: vec!["bob_1".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "mail".to_string(),
-                            vals: vec!["bob@bobmail.bob".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "givenName".to_string(),
-                            vals: vec!["Bôb".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "sn".to_string(),
-                            vals: vec!["Böbberson".to_string()]
-                        },
-                        LdapPartialAttribute {
+        mock.expect_list_groups().return_once(|_| {
+            Ok(vec![Group {
+                id: GroupId(1),
+                display_name: "lldap_users".to_string(),
+                users: vec![UserId::new("bob")],
+            }])
+        });
+        let mut ldap_handler = setup_bound_user_handler(mock, &["lldap_users"]).await;
+        let refused = |dn: &str| {
+            vec![make_search_error(
+                LdapResultCode::InsufficentAccessRights,
+                format!(r#"Not allowed to read "{}""#, dn),
+            )]
+        };
+        for dn in [
+            "cn=alice,ou=people,dc=example,dc=com",
+            "cn=lldap_admin,ou=groups,dc=example,dc=com",
+        ] {
+            let request = make_search_request(dn, LdapFilter::And(vec![]), vec!["cn"]);
+            assert_eq!(ldap_handler.do_search(&request).await, refused(dn));
+        }
+        // Its own entry and those of its groups are readable.
+        for (dn, cn) in [
+            ("cn=bob,ou=people,dc=example,dc=com", "bob"),
+            ("cn=lldap_users,ou=groups,dc=example,dc=com", "lldap_users"),
+        ] {
+            let request = make_search_request(dn, LdapFilter::And(vec![]), vec!["cn"]);
+            assert_eq!(
+                ldap_handler.do_search(&request).await,
+                vec![
+                    LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                        dn: dn.to_string(),
+                        attributes: vec![LdapPartialAttribute {
                             atype: "cn".to_string(),
-                            vals: vec!["Bôb Böbberson".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "createTimestamp".to_string(),
-                            vals: vec!["1970-01-01T00:00:00+00:00".to_string()]
-                        }
-                    ],
-                }),
+                            vals: vec![cn.to_string()],
+                        }],
+                    }),
+                    make_search_success()
+                ]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_scopes() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(5).returning(|_| {
+            Ok(vec![User {
+                user_id: UserId::new("bob"),
+                display_name: "bob".to_string(),
+                ..Default::default()
+            }])
+        });
+        mock.expect_list_groups().times(5).returning(|_| {
+            Ok(vec![Group {
+                id: GroupId(1),
+                display_name: "group_1".to_string(),
+                users: vec![UserId::new("bob")],
+            }])
+        });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let root = "dc=example,dc=com";
+        let people = "ou=people,dc=example,dc=com";
+        let groups = "ou=groups,dc=example,dc=com";
+        let bob = "cn=bob,ou=people,dc=example,dc=com";
+        let group = "cn=group_1,ou=groups,dc=example,dc=com";
+        let cases = [
+            (root, LdapSearchScope::Base, vec![root]),
+            (root, LdapSearchScope::OneLevel, vec![people, groups]),
+            (
+                root,
+                LdapSearchScope::Subtree,
+                vec![root, people, groups, bob, group],
+            ),
+            (people, LdapSearchScope::Base, vec![people]),
+            (people, LdapSearchScope::OneLevel, vec![bob]),
+            (people, LdapSearchScope::Subtree, vec![people, bob]),
+            (groups, LdapSearchScope::Base, vec![groups]),
+            (groups, LdapSearchScope::OneLevel, vec![group]),
+            (groups, LdapSearchScope::Subtree, vec![groups, group]),
+            (bob, LdapSearchScope::Base, vec![bob]),
+            (bob, LdapSearchScope::OneLevel, vec![]),
+            (bob, LdapSearchScope::Subtree, vec![bob]),
+            (group, LdapSearchScope::Base, vec![group]),
+            (group, LdapSearchScope::OneLevel, vec![]),
+            (group, LdapSearchScope::Subtree, vec![group]),
+        ];
+        for (base, scope, dns) in cases {
+            let request = LdapSearchRequest {
+                scope: scope.clone(),
+                ..make_search_request(base, LdapFilter::And(vec![]), vec!["1.1"])
+            };
+            let mut expected: Vec<LdapOp> = dns
+                .into_iter()
+                .map(|dn| {
+                    LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                        dn: dn.to_string(),
+                        attributes: vec![],
+                    })
+                })
+                .collect();
+            expected.push(make_search_success());
+            assert_eq!(
+                ldap_handler.do_search(&request).await,
+                expected,
+                "{} {:?}",
+                base,
+                scope
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_container_entries() {
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
+        let request = make_search_request(
+            "dc=example,dc=com",
+            LdapFilter::Equality("objectClass".to_string(), "Domain".to_string()),
+            vec!["*"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
                 LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: "cn=Jimminy Cricket,ou=people,dc=example,dc=com".to_string(),
+                    dn: "dc=example,dc=com".to_string(),
                     attributes: vec![
                         LdapPartialAttribute {
                             atype: "objectClass".to_string(),
-                            vals: vec![
-                                "inetOrgPerson".to_string(),
-                                "posixAccount".to_string(),
-                                "mailAccount".to_string(),
-                                "person".to_string()
-                            ]
-                        },
-                        LdapPartialAttribute {
-                            atype: "dn".to_string(),
-                            vals: vec!["cn=Jimminy Cricket,ou=people,dc=example,dc=com".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "uid".to_string(),
-                            vals: vec!["jim".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "mail".to_string(),
-                            vals: vec!["jim@cricket.jim".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "givenName".to_string(),
-                            vals: vec!["Jim".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "sn".to_string(),
-                            vals: vec!["Cricket".to_string()]
+                            vals: vec!["top".to_string(), "domain".to_string()],
                         },
                         LdapPartialAttribute {
-                            atype: "cn".to_string(),
-                            vals: vec!["Jimminy Cricket".to_string()]
+                            atype: "dc".to_string(),
+                            vals: vec!["example".to_string()],
                         },
-                        LdapPartialAttribute {
-                            atype: "createTimestamp".to_string(),
-                            vals: vec!["2014-07-08T09:10:11+00:00".to_string()]
-                        }
                     ],
                 }),
                 make_search_success(),
             ]
         );
+        // The OUs are only returned when they match the filter.
+        let request = LdapSearchRequest {
+            scope: LdapSearchScope::OneLevel,
+            ..make_search_request(
+                "dc=example,dc=com",
+                LdapFilter::Equality("ou".to_string(), "groups".to_string()),
+                vec!["ou"],
+            )
+        };
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "ou".to_string(),
+                        vals: vec!["groups".to_string()],
+                    }],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_to_generalized_time() {
+        use chrono::{FixedOffset, NaiveDateTime, TimeZone, Timelike, Utc};
+        let time = Utc.ymd(2014, 7, 8).and_hms_milli(9, 10, 11, 999);
+        // The fractions of seconds are truncated, not rounded.
+        assert_eq!(to_generalized_time(&time), "20140708091011Z");
+        // Normalized to UTC.
+        let local = FixedOffset::east(2 * 3600)
+            .ymd(2014, 7, 8)
+            .and_hms(11, 10, 11);
+        assert_eq!(to_generalized_time(&local), "20140708091011Z");
+        for time in [
+            Utc.timestamp(0, 0),
+            Utc.ymd(1999, 12, 31).and_hms(23, 59, 59),
+            Utc.ymd(2024, 2, 29).and_hms_nano(12, 0, 0, 123_456_789),
+        ] {
+            let parsed =
+                NaiveDateTime::parse_from_str(&to_generalized_time(&time), "%Y%m%d%H%M%SZ")
+                    .unwrap();
+            assert_eq!(
+                Utc.from_utc_datetime(&parsed),
+                time.with_nanosecond(0).unwrap()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_require_auth_for_search() {
+        let mut ldap_handler = setup_bound_handler_with_options(
+            MockTestBackendHandler::new(),
+            LdapHandlerOptions {
+                allow_anonymous_bind: true,
+                anonymous_readable_attributes: Some(vec!["uid".to_string()]),
+                require_auth_for_search: true,
+                ..Default::default()
+            },
+        )
+        .await;
+        let anonymous_bind = LdapBindRequest {
+            dn: "".to_string(),
+            cred: LdapBindCred::Simple("".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&anonymous_bind).await.0,
+            LdapResultCode::Success
+        );
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["uid"]);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_error(
+                LdapResultCode::InsufficentAccessRights,
+                "Anonymous sessions can only read the root DSE and the schema".to_string(),
+            )]
+        );
+        // The root DSE is still readable.
+        let request = make_search_request("", LdapFilter::And(vec![]), vec!["uid"]);
+        assert!(matches!(
+            ldap_handler.do_search(&request).await.as_slice(),
+            [
+                LdapOp::SearchResultEntry(_),
+                LdapOp::SearchResultDone(LdapResult {
+                    code: LdapResultCode::Success,
+                    ..
+                })
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_make_user_cn() {
+        let user = User {
+            user_id: UserId::new("bob"),
+            display_name: "Bobby".to_string(),
+            first_name: "Bob".to_string(),
+            last_name: "Smith".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(make_user_cn(&user, None), "Bobby");
+        assert_eq!(make_user_cn(&user, Some("%first %last")), "Bob Smith");
+        assert_eq!(make_user_cn(&user, Some("%last, %first")), "Smith, Bob");
+        assert_eq!(make_user_cn(&user, Some("%display (%uid)")), "Bobby (bob)");
+        let user = User {
+            first_name: "Bob".to_string(),
+            display_name: "Bobby".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(make_user_cn(&user, Some("%first %last")), "Bob");
+        assert_eq!(make_user_cn(&user, Some("%last")), "Bobby");
     }
 
     #[tokio::test]
-    async fn test_search_groups() {
+    async fn test_search_cn_template() {
         let mut mock = MockTestBackendHandler::new();
-        mock.expect_list_groups()
-            .with(eq(Some(GroupRequestFilter::And(vec![]))))
-            .times(1)
-            .return_once(|_| {
-                Ok(vec![
-                    Group {
-                        id: GroupId(1),
-                        display_name: "group_1".to_string(),
-                        users: vec![UserId::new("bob"), UserId::new("john")],
-                    },
-                    Group {
-                        id: GroupId(3),
-                        display_name: "bestgroup".to_string(),
-                        users: vec![UserId::new("john")],
-                    },
-                ])
-            });
-        let mut ldap_handler = setup_bound_handler(mock).await;
-        let request = make_search_request(
-            "ou=groups,dc=example,dc=com",
-            LdapFilter::And(vec![]),
-            vec!["objectClass", "dn", "cn", "uniqueMember"],
-        );
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![User {
+                user_id: UserId::new("bob"),
+                display_name: "Bobby".to_string(),
+                first_name: "Bob".to_string(),
+                last_name: "Smith".to_string(),
+                ..Default::default()
+            }])
+        });
+        let mut ldap_handler = setup_bound_handler_with_options(
+            mock,
+            LdapHandlerOptions {
+                cn_template: Some("%first %last".to_string()),
+                ..Default::default()
+            },
+        )
+        .await;
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["cn", "displayName"]);
         assert_eq!(
             ldap_handler.do_search(&request).await,
             vec![
                 LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                    dn: "cn=Bobby,ou=people,dc=example,dc=com".to_string(),
                     attributes: vec![
-                        LdapPartialAttribute {
-                            atype: "objectClass".to_string(),
-                            vals: vec!["groupOfUniqueNames".to_string(),]
-                        },
-                        LdapPartialAttribute {
-                            atype: "dn".to_string(),
-                            vals: vec!["cn=group_1,ou=groups,dc=example,dc=com".to_string()]
-                        },
                         LdapPartialAttribute {
                             atype: "cn".to_string(),
-                            vals: vec!["group_1".to_string()]
+                            vals: vec!["Bob Smith".to_string()],
                         },
                         LdapPartialAttribute {
-                            atype: "uniqueMember".to_string(),
-                            vals: vec![
-                                "cn=bob,ou=people,dc=example,dc=com".to_string(),
-                                "cn=john,ou=people,dc=example,dc=com".to_string(),
-                            ]
+                            atype: "displayName".to_string(),
+                            vals: vec!["Bobby".to_string()],
                         },
                     ],
                 }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_stable_member_order() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let reversed = AtomicBool::new(false);
+        let mut mock = MockTestBackendHandler::new();
+        // The backend returns the members in a different order each time.
+        mock.expect_list_groups().times(2).returning(move |_| {
+            let mut users = vec![
+                UserId::new("john"),
+                UserId::new("alice"),
+                UserId::new("bob"),
+            ];
+            if reversed.fetch_xor(true, Ordering::Relaxed) {
+                users.reverse();
+            }
+            Ok(vec![Group {
+                id: GroupId(1),
+                display_name: "group_1".to_string(),
+                users,
+            }])
+        });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request =
+            make_group_search_request(LdapFilter::And(vec![]), vec!["memberUid", "member"]);
+        let first = ldap_handler.do_search(&request).await;
+        assert_eq!(ldap_handler.do_search(&request).await, first);
+        let member_dn = |u: &str| format!("cn={},ou=people,dc=example,dc=com", u);
+        assert_eq!(
+            first,
+            vec![
                 LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: "cn=bestgroup,ou=groups,dc=example,dc=com".to_string(),
+                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
                     attributes: vec![
                         LdapPartialAttribute {
-                            atype: "objectClass".to_string(),
-                            vals: vec!["groupOfUniqueNames".to_string(),]
-                        },
-                        LdapPartialAttribute {
-                            atype: "dn".to_string(),
-                            vals: vec!["cn=bestgroup,ou=groups,dc=example,dc=com".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "cn".to_string(),
-                            vals: vec!["bestgroup".to_string()]
+                            atype: "memberUid".to_string(),
+                            vals: vec!["alice".to_string(), "bob".to_string(), "john".to_string()],
                         },
                         LdapPartialAttribute {
-                            atype: "uniqueMember".to_string(),
-                            vals: vec!["cn=john,ou=people,dc=example,dc=com".to_string()]
+                            atype: "member".to_string(),
+                            vals: vec![member_dn("alice"), member_dn("bob"), member_dn("john")],
                         },
                     ],
                 }),
@@ -1181,487 +13397,1070 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_search_groups_filter() {
+    async fn test_streaming_sender_is_closed_after_request() {
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+        let responses = ldap_handler
+            .handle_ldap_request_streaming(
+                1,
+                LdapRequest::Op(LdapOp::ExtendedRequest(LdapExtendedRequest {
+                    name: WHOAMI_OID.to_string(),
+                    value: None,
+                })),
+                &[],
+                sender,
+            )
+            .await;
+        assert!(responses.is_some());
+        assert!(ldap_handler.response_sender.is_none());
+        assert!(receiver.recv().await.is_none());
+    }
+
+    fn make_sync_request_control(mode: i64, cookie: Option<&[u8]>) -> RawControl {
+        let mut fields = vec![BerElement::integer_with_tag(TAG_ENUMERATED, mode)];
+        fields.extend(cookie.map(BerElement::octet_string));
+        RawControl {
+            oid: SYNC_REQUEST_OID.to_string(),
+            criticality: true,
+            value: Some(BerElement::sequence(&fields).encode()),
+        }
+    }
+
+    fn make_sync_user(user_id: &str, email: &str) -> User {
+        User {
+            user_id: UserId::new(user_id),
+            email: email.to_string(),
+            display_name: user_id.to_string(),
+            uuid: make_entry_uuid("user", user_id),
+            ..Default::default()
+        }
+    }
+
+    fn make_sync_user_entry(
+        user_id: &str,
+        email: &str,
+        state: i64,
+        cookie: Option<&[u8]>,
+    ) -> LdapResponse {
+        let uuid = Uuid::parse_str(&make_entry_uuid("user", user_id)).unwrap();
+        LdapResponse {
+            op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                dn: format!("cn={},ou=people,dc=example,dc=com", user_id),
+                attributes: vec![
+                    LdapPartialAttribute {
+                        atype: "uid".to_string(),
+                        vals: vec![user_id.to_string()],
+                    },
+                    LdapPartialAttribute {
+                        atype: "mail".to_string(),
+                        vals: vec![email.to_string()],
+                    },
+                ],
+            })
+            .into(),
+            controls: vec![make_sync_state_control(state, &uuid, cookie)],
+        }
+    }
+
+    #[test]
+    fn test_parse_sync_request_control() {
+        assert_eq!(
+            parse_sync_request_control(&make_sync_request_control(3, Some(&b"lldap:1"[..])))
+                .unwrap(),
+            SyncRequest {
+                persist: true,
+                cookie: Some(b"lldap:1".to_vec()),
+                reload_hint: false,
+            }
+        );
+        let control = RawControl {
+            value: Some(
+                BerElement::sequence(&[
+                    BerElement::integer_with_tag(TAG_ENUMERATED, 1),
+                    BerElement::boolean(true),
+                ])
+                .encode(),
+            ),
+            ..make_sync_request_control(1, None)
+        };
+        assert_eq!(
+            parse_sync_request_control(&control).unwrap(),
+            SyncRequest {
+                persist: false,
+                cookie: None,
+                reload_hint: true,
+            }
+        );
+        assert!(parse_sync_request_control(&make_sync_request_control(2, None)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sync_search_refresh_only() {
         let mut mock = MockTestBackendHandler::new();
-        mock.expect_list_groups()
-            .with(eq(Some(GroupRequestFilter::And(vec![
-                GroupRequestFilter::DisplayName("group_1".to_string()),
-                GroupRequestFilter::Member(UserId::new("bob")),
-                GroupRequestFilter::And(vec![]),
-                GroupRequestFilter::And(vec![]),
-            ]))))
+        let (_sender, receiver) = tokio::sync::watch::channel(42);
+        mock.expect_subscribe_to_changes()
+            .times(2)
+            .returning(move || receiver.clone());
+        mock.expect_list_users()
+            .times(2)
+            .returning(|_| Ok(vec![make_sync_user("bob", "bob@bob.bob")]));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["uid", "mail"]);
+        let cookie: &[u8] = b"lldap:42";
+        // The entryUUID is not returned, since it was not asked for.
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(
+                    LdapOp::SearchRequest(request.clone()),
+                    &[make_sync_request_control(1, None)]
+                )
+                .await,
+            Some(vec![
+                make_sync_user_entry("bob", "bob@bob.bob", SYNC_STATE_ADD, None),
+                LdapResponse {
+                    op: make_search_success().into(),
+                    controls: vec![make_sync_done_control(cookie, false)],
+                },
+            ])
+        );
+        // Nothing changed since the cookie.
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(
+                    LdapOp::SearchRequest(request),
+                    &[make_sync_request_control(1, Some(cookie))]
+                )
+                .await,
+            Some(vec![LdapResponse {
+                op: make_search_success().into(),
+                controls: vec![make_sync_done_control(cookie, true)],
+            }])
+        );
+        assert_eq!(ldap_handler.persistent_search_id(), None);
+    }
+
+    #[tokio::test]
+    async fn test_sync_search_refresh_and_persist() {
+        let mut mock = MockTestBackendHandler::new();
+        let (sender, receiver) = tokio::sync::watch::channel(1);
+        mock.expect_subscribe_to_changes()
+            .times(1)
+            .return_once(move || receiver);
+        // The users after each change.
+        let mut contents = vec![
+            vec![make_sync_user("bob", "bob@bob.bob")],
+            vec![
+                make_sync_user("bob", "bob@example.com"),
+                make_sync_user("jim", "jim@jim.jim"),
+            ],
+            vec![make_sync_user("bob", "bob@example.com")],
+            vec![make_sync_user("bob", "bob@example.com")],
+        ]
+        .into_iter();
+        mock.expect_list_users()
+            .times(4)
+            .returning(move |_| Ok(contents.next().unwrap()));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        ldap_handler.msgid = 5;
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["uid", "mail"]);
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(
+                    LdapOp::SearchRequest(request.clone()),
+                    &[make_sync_request_control(3, None)]
+                )
+                .await,
+            Some(vec![
+                make_sync_user_entry("bob", "bob@bob.bob", SYNC_STATE_ADD, None),
+                make_sync_refresh_done(b"lldap:1", false),
+            ])
+        );
+        assert_eq!(ldap_handler.persistent_search_id(), Some(5));
+        // Only one persistent search per connection.
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(
+                    LdapOp::SearchRequest(request),
+                    &[make_sync_request_control(3, None)]
+                )
+                .await,
+            Some(vec![make_search_error(
+                LdapResultCode::Busy,
+                "There is already a persistent search on this connection".to_string(),
+            )
+            .into()])
+        );
+
+        sender.send(2).unwrap();
+        ldap_handler.wait_for_changes().await;
+        assert_eq!(
+            ldap_handler.get_persistent_search_changes().await,
+            vec![
+                make_sync_user_entry(
+                    "bob",
+                    "bob@example.com",
+                    SYNC_STATE_MODIFY,
+                    Some(&b"lldap:2"[..])
+                ),
+                make_sync_user_entry("jim", "jim@jim.jim", SYNC_STATE_ADD, Some(&b"lldap:2"[..])),
+            ]
+        );
+        sender.send(3).unwrap();
+        ldap_handler.wait_for_changes().await;
+        assert_eq!(
+            ldap_handler.get_persistent_search_changes().await,
+            vec![LdapResponse {
+                op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=jim,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![],
+                })
+                .into(),
+                controls: vec![make_sync_state_control(
+                    SYNC_STATE_DELETE,
+                    &Uuid::parse_str(&make_entry_uuid("user", "jim")).unwrap(),
+                    Some(&b"lldap:3"[..]),
+                )],
+            }]
+        );
+        // The changes outside of the content only update the cookie.
+        sender.send(4).unwrap();
+        ldap_handler.wait_for_changes().await;
+        assert_eq!(
+            ldap_handler.get_persistent_search_changes().await,
+            vec![make_sync_new_cookie(b"lldap:4")]
+        );
+
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_request(
+                    LdapRequest::Unsupported(UnsupportedOperation::Abandon(5)),
+                    &[]
+                )
+                .await,
+            Some(vec![])
+        );
+        assert_eq!(ldap_handler.persistent_search_id(), None);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_persistent_search() {
+        let mut mock = MockTestBackendHandler::new();
+        let (_sender, receiver) = tokio::sync::watch::channel(1);
+        mock.expect_subscribe_to_changes()
+            .times(1)
+            .return_once(move || receiver);
+        mock.expect_list_users()
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        ldap_handler.msgid = 2;
+        ldap_handler
+            .handle_ldap_message(
+                LdapOp::SearchRequest(make_user_search_request(
+                    LdapFilter::And(vec![]),
+                    vec!["uid"],
+                )),
+                &[make_sync_request_control(3, None)],
+            )
+            .await;
+        assert_eq!(ldap_handler.take_canceled_search(), None);
+        let cancel = LdapExtendedRequest {
+            name: CANCEL_OID.to_string(),
+            value: Some(BerElement::sequence(&[BerElement::integer(2)]).encode()),
+        };
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(LdapOp::ExtendedRequest(cancel), &[])
+                .await,
+            Some(vec![make_extended_response(
+                LdapResultCode::Success,
+                "".to_string()
+            )
+            .into()])
+        );
+        assert_eq!(ldap_handler.persistent_search_id(), None);
+        let (msgid, response) = ldap_handler.take_canceled_search().unwrap();
+        assert_eq!(msgid, 2);
+        assert!(matches!(
+            response.op,
+            LdapResponseOp::WithResultCode(_, CANCELED)
+        ));
+        assert_eq!(ldap_handler.take_canceled_search(), None);
+    }
+
+    #[tokio::test]
+    async fn test_search_ad_compatibility() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::UserId(UserId::new("bob")))))
             .times(1)
             .return_once(|_| {
-                Ok(vec![Group {
-                    display_name: "group_1".to_string(),
-                    id: GroupId(1),
-                    users: vec![],
+                Ok(vec![User {
+                    user_id: UserId::new("bob"),
+                    display_name: "Bob".to_string(),
+                    ..Default::default()
                 }])
             });
         let mut ldap_handler = setup_bound_handler(mock).await;
-        let request = make_search_request(
-            "ou=groups,dc=example,dc=com",
-            LdapFilter::And(vec![
-                LdapFilter::Equality("cn".to_string(), "group_1".to_string()),
-                LdapFilter::Equality(
-                    "uniqueMember".to_string(),
-                    "cn=bob,ou=people,dc=example,dc=com".to_string(),
-                ),
-                LdapFilter::Equality("objectclass".to_string(), "groupOfUniqueNames".to_string()),
-                LdapFilter::Equality("objectclass".to_string(), "groupOfNames".to_string()),
-            ]),
-            vec!["1.1"],
+        let request = make_user_search_request(
+            LdapFilter::Equality("sAMAccountName".to_string(), "bob".to_string()),
+            vec!["sAMAccountName", "uid"],
         );
         assert_eq!(
             ldap_handler.do_search(&request).await,
             vec![
                 LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
-                    attributes: vec![],
+                    dn: "cn=Bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "sAMAccountName".to_string(),
+                            vals: vec!["bob".to_string()],
+                        },
+                        LdapPartialAttribute {
+                            atype: "uid".to_string(),
+                            vals: vec!["bob".to_string()],
+                        },
+                    ],
                 }),
                 make_search_success(),
             ]
         );
     }
 
+    #[test]
+    fn test_resolve_filter_aliases() {
+        let filter = LdapFilter::And(vec![
+            LdapFilter::Present("SAMACCOUNTNAME".to_string()),
+            LdapFilter::Not(Box::new(LdapFilter::Substring(
+                "sAMAccountName".to_string(),
+                LdapSubstringFilter::default(),
+            ))),
+            LdapFilter::Equality("mail".to_string(), "bob@bob.bob".to_string()),
+        ]);
+        assert_eq!(
+            resolve_filter_aliases(&filter, true),
+            LdapFilter::And(vec![
+                LdapFilter::Present("uid".to_string()),
+                LdapFilter::Not(Box::new(LdapFilter::Substring(
+                    "uid".to_string(),
+                    LdapSubstringFilter::default(),
+                ))),
+                LdapFilter::Equality("mail".to_string(), "bob@bob.bob".to_string()),
+            ])
+        );
+        // Without the AD compatibility, the attribute is unknown.
+        assert_eq!(resolve_filter_aliases(&filter, false), filter);
+        assert_eq!(
+            resolve_attribute_alias("sAMAccountName", false),
+            "sAMAccountName"
+        );
+    }
+
     #[tokio::test]
-    async fn test_search_groups_filter_2() {
+    async fn test_search_user_account_control() {
         let mut mock = MockTestBackendHandler::new();
-        mock.expect_list_groups()
-            .with(eq(Some(GroupRequestFilter::Or(vec![
-                GroupRequestFilter::Not(Box::new(GroupRequestFilter::DisplayName(
-                    "group_2".to_string(),
-                ))),
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![
+                UserRequestFilter::UserId(UserId::new("bob")),
+                UserRequestFilter::Not(Box::new(UserRequestFilter::Not(Box::new(
+                    UserRequestFilter::And(vec![]),
+                )))),
             ]))))
             .times(1)
             .return_once(|_| {
-                Ok(vec![Group {
-                    display_name: "group_1".to_string(),
-                    id: GroupId(1),
-                    users: vec![],
+                Ok(vec![User {
+                    user_id: UserId::new("bob"),
+                    display_name: "Bob".to_string(),
+                    ..Default::default()
                 }])
             });
         let mut ldap_handler = setup_bound_handler(mock).await;
-        let request = make_search_request(
-            "ou=groups,dc=example,dc=com",
-            LdapFilter::Or(vec![LdapFilter::Not(Box::new(LdapFilter::Equality(
-                "displayname".to_string(),
-                "group_2".to_string(),
-            )))]),
-            vec!["cn"],
+        // The enabled accounts: ACCOUNTDISABLE is not set.
+        let request = make_user_search_request(
+            LdapFilter::And(vec![
+                LdapFilter::Equality("uid".to_string(), "bob".to_string()),
+                LdapFilter::Not(Box::new(LdapFilter::Equality(
+                    "userAccountControl:1.2.840.113556.1.4.803:".to_string(),
+                    "2".to_string(),
+                ))),
+            ]),
+            vec!["userAccountControl"],
         );
         assert_eq!(
             ldap_handler.do_search(&request).await,
             vec![
                 LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                    dn: "cn=Bob,ou=people,dc=example,dc=com".to_string(),
                     attributes: vec![LdapPartialAttribute {
-                        atype: "cn".to_string(),
-                        vals: vec!["group_1".to_string()]
-                    },],
+                        atype: "userAccountControl".to_string(),
+                        vals: vec!["512".to_string()],
+                    }],
                 }),
                 make_search_success(),
             ]
         );
     }
 
-    #[tokio::test]
-    async fn test_search_groups_error() {
-        let mut mock = MockTestBackendHandler::new();
-        mock.expect_list_groups()
-            .with(eq(Some(GroupRequestFilter::Or(vec![
-                GroupRequestFilter::Not(Box::new(GroupRequestFilter::DisplayName(
-                    "group_2".to_string(),
-                ))),
-            ]))))
-            .times(1)
-            .return_once(|_| {
-                Err(crate::domain::error::DomainError::InternalError(
-                    "Error getting groups".to_string(),
-                ))
-            });
-        let mut ldap_handler = setup_bound_handler(mock).await;
-        let request = make_search_request(
-            "ou=groups,dc=example,dc=com",
-            LdapFilter::Or(vec![LdapFilter::Not(Box::new(LdapFilter::Equality(
-                "displayname".to_string(),
-                "group_2".to_string(),
-            )))]),
-            vec!["cn"],
+    #[test]
+    fn test_resolve_user_account_control_matching_rules() {
+        let bits = |rule: &str, value: &str| {
+            LdapFilter::Equality(format!("userAccountControl:{}:", rule), value.to_string())
+        };
+        let nothing = LdapFilter::Not(Box::new(LdapFilter::Present("objectClass".to_string())));
+        let present = LdapFilter::Present("userAccountControl".to_string());
+        assert_eq!(
+            resolve_matching_rules(&bits(BIT_AND_MATCHING_RULE, "2"), true),
+            Ok(nothing.clone())
         );
         assert_eq!(
-            ldap_handler.do_search(&request).await,
-            vec![make_search_error(
-                LdapResultCode::Other,
-                r#"Error while listing groups "ou=groups,dc=example,dc=com": Internal error: `Error getting groups`"#.to_string()
-            )]
+            resolve_matching_rules(&bits(BIT_AND_MATCHING_RULE, "512"), true),
+            Ok(present.clone())
         );
-    }
-
-    #[tokio::test]
-    async fn test_search_groups_filter_error() {
-        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
-        let request = make_search_request(
-            "ou=groups,dc=example,dc=com",
-            LdapFilter::And(vec![LdapFilter::Equality(
-                "whatever".to_string(),
-                "group_1".to_string(),
-            )]),
-            vec!["cn"],
+        assert_eq!(
+            resolve_matching_rules(&bits(BIT_OR_MATCHING_RULE, "514"), true),
+            Ok(present)
         );
         assert_eq!(
-            ldap_handler.do_search(&request).await,
-            vec![make_search_error(
-                LdapResultCode::UnwillingToPerform,
-                "Unsupported group filter: Unknown field: whatever".to_string()
-            )]
+            resolve_matching_rules(&bits(BIT_OR_MATCHING_RULE, "2"), true),
+            Ok(nothing)
         );
+        assert!(resolve_matching_rules(&bits(BIT_AND_MATCHING_RULE, "two"), true).is_err());
+        // Without the AD compatibility, the rules are not supported.
+        assert!(resolve_matching_rules(&bits(BIT_AND_MATCHING_RULE, "2"), false).is_err());
     }
 
     #[tokio::test]
-    async fn test_search_filters() {
+    async fn test_search_transient_backend_error() {
         let mut mock = MockTestBackendHandler::new();
         mock.expect_list_users()
-            .with(eq(Some(UserRequestFilter::And(vec![
-                UserRequestFilter::Or(vec![
-                    UserRequestFilter::Not(Box::new(UserRequestFilter::UserId(UserId::new("bob")))),
-                    UserRequestFilter::And(vec![]),
-                    UserRequestFilter::Not(Box::new(UserRequestFilter::And(vec![]))),
-                    UserRequestFilter::And(vec![]),
-                    UserRequestFilter::And(vec![]),
-                    UserRequestFilter::Not(Box::new(UserRequestFilter::And(vec![]))),
-                ]),
-            ]))))
             .times(1)
-            .return_once(|_| Ok(vec![]));
+            .return_once(|_| Err(DomainError::DatabaseError(sqlx::Error::PoolTimedOut)));
+        mock.expect_list_groups()
+            .times(1)
+            .return_once(|_| Err(DomainError::InternalError("oops".to_string())));
         let mut ldap_handler = setup_bound_handler(mock).await;
-        let request = make_user_search_request(
-            LdapFilter::And(vec![LdapFilter::Or(vec![
-                LdapFilter::Not(Box::new(LdapFilter::Equality(
-                    "uid".to_string(),
-                    "bob".to_string(),
-                ))),
-                LdapFilter::Equality("objectclass".to_string(), "person".to_string()),
-                LdapFilter::Equality("objectclass".to_string(), "other".to_string()),
-                LdapFilter::Present("objectClass".to_string()),
-                LdapFilter::Present("uid".to_string()),
-                LdapFilter::Present("unknown".to_string()),
-            ])]),
-            vec!["objectClass"],
-        );
-        assert_eq!(
-            ldap_handler.do_search(&request).await,
-            vec![make_search_success()]
-        );
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["uid"]);
+        assert!(matches!(
+            ldap_handler.do_search(&request).await.as_slice(),
+            [LdapOp::SearchResultDone(LdapResult {
+                code: LdapResultCode::Unavailable,
+                ..
+            })]
+        ));
+        // The other errors are not worth trying again.
+        let request = make_group_search_request(LdapFilter::And(vec![]), vec!["cn"]);
+        assert!(matches!(
+            ldap_handler.do_search(&request).await.as_slice(),
+            [LdapOp::SearchResultDone(LdapResult {
+                code: LdapResultCode::Other,
+                ..
+            })]
+        ));
     }
 
     #[tokio::test]
-    async fn test_search_member_of() {
-        let mut mock = MockTestBackendHandler::new();
-        mock.expect_list_users()
-            .with(eq(Some(UserRequestFilter::MemberOf("group_1".to_string()))))
-            .times(1)
-            .return_once(|_| Ok(vec![]));
-        let mut ldap_handler = setup_bound_handler(mock).await;
-        let request = make_user_search_request(
-            LdapFilter::Equality(
-                "memberOf".to_string(),
-                "cn=group_1, ou=groups, dc=example,dc=com".to_string(),
-            ),
-            vec!["objectClass"],
+    async fn test_in_memory_backend() {
+        use crate::domain::in_memory_backend_handler::InMemoryBackendHandler;
+        let backend = InMemoryBackendHandler::new();
+        for (user_id, display_name) in [("admin", "Administrator"), ("bob", "Bob")] {
+            backend
+                .create_user(CreateUserRequest {
+                    user_id: UserId::new(user_id),
+                    display_name: Some(display_name.to_string()),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+        }
+        backend.set_password(&UserId::new("admin"), "pass");
+        let editors = backend.create_group("editors").await.unwrap();
+        backend
+            .add_user_to_group(&UserId::new("bob"), editors)
+            .await
+            .unwrap();
+        let mut ldap_handler = LdapHandler::new(
+            backend,
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions::default(),
+            None,
         );
+        let bind = |password: &str| {
+            LdapOp::BindRequest(LdapBindRequest {
+                dn: "uid=admin,ou=people,dc=example,dc=com".to_string(),
+                cred: LdapBindCred::Simple(password.to_string()),
+            })
+        };
         assert_eq!(
-            ldap_handler.do_search(&request).await,
-            vec![make_search_success()]
-        );
-        let request = make_user_search_request(
-            LdapFilter::Equality("memberOf".to_string(), "group_1".to_string()),
-            vec!["objectClass"],
+            ldap_handler.handle_ldap_message(bind("wrong"), &[]).await,
+            Some(vec![make_bind_response(
+                LdapResultCode::InvalidCredentials,
+                "".to_string()
+            )
+            .into()])
         );
         assert_eq!(
-            ldap_handler.do_search(&request).await,
-            vec![make_search_error(
-                LdapResultCode::UnwillingToPerform,
-                "Unsupported user filter: while parsing a group ID: Missing DN value".to_string()
-            )]
+            ldap_handler.handle_ldap_message(bind("pass"), &[]).await,
+            Some(vec![make_bind_response(
+                LdapResultCode::Success,
+                "".to_string()
+            )
+            .into()])
         );
         let request = make_user_search_request(
             LdapFilter::Equality(
                 "memberOf".to_string(),
-                "cn=mygroup,dc=example,dc=com".to_string(),
+                "cn=editors,ou=groups,dc=example,dc=com".to_string(),
             ),
-            vec!["objectClass"],
+            vec!["uid"],
         );
         assert_eq!(
-            ldap_handler.do_search(&request).await,
-            vec![make_search_error(
-                LdapResultCode::UnwillingToPerform,
-                "Unsupported user filter: Unexpected group DN format. Got \"cn=mygroup,dc=example,dc=com\", expected: \"cn=groupname,ou=groups,dc=example,dc=com\"".to_string()
-            )]
+            ldap_handler
+                .handle_ldap_message(LdapOp::SearchRequest(request), &[])
+                .await,
+            Some(vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=Bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "uid".to_string(),
+                        vals: vec!["bob".to_string()],
+                    }],
+                })
+                .into(),
+                make_search_success().into(),
+            ])
         );
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(LdapOp::UnbindRequest, &[])
+                .await,
+            None
+        );
+    }
+
+    #[test]
+    fn test_root_dse_controls_follow_the_options() {
+        let get_controls =
+            |options: &LdapHandlerOptions| match root_dse_response("dc=example,dc=com", options) {
+                LdapOp::SearchResultEntry(entry) => {
+                    entry
+                        .attributes
+                        .into_iter()
+                        .find(|a| a.atype == "supportedControl")
+                        .unwrap()
+                        .vals
+                }
+                _ => panic!("Unexpected root DSE response"),
+            };
+        let disabled = get_controls(&LdapHandlerOptions::default());
+        assert!(!disabled.contains(&PROXIED_AUTHORIZATION_OID.to_string()));
+        assert!(!disabled.contains(&PASSWORD_POLICY_OID.to_string()));
+        let enabled = get_controls(&LdapHandlerOptions {
+            proxy_group: Some("proxies".to_string()),
+            report_account_lockout: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            enabled,
+            disabled
+                .iter()
+                .cloned()
+                .chain([
+                    PROXIED_AUTHORIZATION_OID.to_string(),
+                    PASSWORD_POLICY_OID.to_string()
+                ])
+                .collect::<Vec<_>>()
+        );
+        // The disabled controls are not accepted either.
+        let ldap_handler = LdapHandler::new(
+            MockTestBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions::default(),
+            None,
+        );
+        let search = LdapRequest::Op(LdapOp::SearchRequest(make_user_search_request(
+            LdapFilter::And(vec![]),
+            vec!["uid"],
+        )));
+        assert!(ldap_handler.is_supported_control(&search, PAGED_RESULTS_OID));
+        assert!(!ldap_handler.is_supported_control(&search, PROXIED_AUTHORIZATION_OID));
     }
 
     #[tokio::test]
-    async fn test_search_filters_lowercase() {
+    async fn test_attribute_value_lengths() {
         let mut mock = MockTestBackendHandler::new();
-        mock.expect_list_users()
-            .with(eq(Some(UserRequestFilter::And(vec![
-                UserRequestFilter::Or(vec![UserRequestFilter::Not(Box::new(
-                    UserRequestFilter::Equality("first_name".to_string(), "bob".to_string()),
-                ))]),
-            ]))))
+        mock.expect_get_user_details()
+            .with(eq(UserId::new("bob")))
             .times(1)
-            .return_once(|_| {
-                Ok(vec![User {
-                    display_name: "bob_1".to_string(),
-                    ..Default::default()
-                }])
-            });
-        let mut ldap_handler = setup_bound_handler(mock).await;
-        let request = make_user_search_request(
-            LdapFilter::And(vec![LdapFilter::Or(vec![LdapFilter::Not(Box::new(
-                LdapFilter::Equality("givenname".to_string(), "bob".to_string()),
-            ))])]),
-            vec!["objectclass"],
+            .return_once(|_| Err(DomainError::InternalError("Not found".to_string())));
+        mock.expect_create_user()
+            .with(eq(CreateUserRequest {
+                user_id: UserId::new("bob"),
+                email: "bob@example.com".to_string(),
+                display_name: Some("Bob Bobberson Jr".to_string()),
+                ..Default::default()
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let mut ldap_handler = setup_bound_handler_with_options(
+            mock,
+            LdapHandlerOptions {
+                max_attribute_value_bytes: 16,
+                max_binary_attribute_value_bytes: 32,
+                ..Default::default()
+            },
+        )
+        .await;
+        let add_request = |attribute: &str, value: Vec<u8>| AddRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            attributes: vec![
+                ("mail".to_string(), vec![b"bob@example.com".to_vec()]),
+                (attribute.to_string(), vec![value]),
+            ],
+        };
+        assert_eq!(
+            ldap_handler
+                .do_add(&add_request("displayName", b"Bob Bobberson Jr.".to_vec()))
+                .await,
+            (
+                LdapResultCode::ConstraintViolation,
+                r#"The value of "displayName" is larger than 16 bytes"#.to_string()
+            )
         );
         assert_eq!(
-            ldap_handler.do_search(&request).await,
-            vec![
-                LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: "cn=bob_1,ou=people,dc=example,dc=com".to_string(),
-                    attributes: vec![LdapPartialAttribute {
-                        atype: "objectclass".to_string(),
-                        vals: vec![
-                            "inetOrgPerson".to_string(),
-                            "posixAccount".to_string(),
-                            "mailAccount".to_string(),
-                            "person".to_string()
-                        ]
-                    },]
-                }),
-                make_search_success()
-            ]
+            ldap_handler
+                .do_add(&add_request("jpegPhoto", vec![0xff; 33]))
+                .await,
+            (
+                LdapResultCode::ConstraintViolation,
+                r#"The value of "jpegPhoto" is larger than 32 bytes"#.to_string()
+            )
+        );
+        // At the limit, the binary attributes get further, but can't be set.
+        assert_eq!(
+            ldap_handler
+                .do_add(&add_request("jpegPhoto", vec![0xff; 32]))
+                .await
+                .0,
+            LdapResultCode::UnwillingToPerform
+        );
+        assert_eq!(
+            ldap_handler
+                .do_add(&add_request("displayName", b"Bob Bobberson Jr".to_vec()))
+                .await
+                .0,
+            LdapResultCode::Success
+        );
+        let request = ModifyRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            changes: vec![Modification {
+                operation: ModifyOperation::Replace,
+                attribute: "userPassword".to_string(),
+                values: vec![vec![b'a'; 17]],
+            }],
+        };
+        assert_eq!(
+            ldap_handler.do_modify(&request, &[]).await,
+            make_modify_response(
+                LdapResultCode::ConstraintViolation,
+                r#"The value of "userPassword" is larger than 16 bytes"#.to_string()
+            )
         );
     }
 
     #[tokio::test]
-    async fn test_search_both() {
-        let mut mock = MockTestBackendHandler::new();
-        mock.expect_list_users().times(1).return_once(|_| {
-            Ok(vec![User {
-                user_id: UserId::new("bob_1"),
-                email: "bob@bobmail.bob".to_string(),
-                display_name: "Bôb Böbberson".to_string(),
-                first_name: "Bôb".to_string(),
-                last_name: "Böbberson".to_string(),
-                ..Default::default()
-            }])
-        });
-        mock.expect_list_groups()
-            .with(eq(Some(GroupRequestFilter::And(vec![]))))
-            .times(1)
-            .return_once(|_| {
-                Ok(vec![Group {
-                    id: GroupId(1),
-                    display_name: "group_1".to_string(),
-                    users: vec![UserId::new("bob"), UserId::new("john")],
-                }])
+    async fn test_operation_log() {
+        let request = |value: Option<&str>| LdapExtendedRequest {
+            name: OPERATION_LOG_OID.to_string(),
+            value: value.map(|v| v.as_bytes().to_vec()),
+        };
+        let operation_log = Arc::new(OperationLog::new(10));
+        for op_type in ["bind", "search"] {
+            operation_log.record(crate::infra::operation_log::OperationRecord {
+                time: chrono::Utc::now(),
+                op_type: op_type.to_string(),
+                bind_dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                peer_ip: "127.0.0.1".to_string(),
+                filter: None,
+                result_code: "Success".to_string(),
+                duration_ms: 1.0,
             });
-        let mut ldap_handler = setup_bound_handler(mock).await;
-        let request = make_search_request(
-            "dc=example,dc=com",
-            LdapFilter::And(vec![]),
-            vec!["objectClass", "dn", "cn"],
+        }
+        let options = LdapHandlerOptions {
+            operation_log: Some(operation_log),
+            ..Default::default()
+        };
+        let mut ldap_handler = LdapHandler::new(
+            MockTestBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            UserId::new("test"),
+            options.clone(),
+            None,
         );
         assert_eq!(
-            ldap_handler.do_search(&request).await,
-            vec![
-                LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: "cn=Bôb Böbberson,ou=people,dc=example,dc=com".to_string(),
-                    attributes: vec![
-                        LdapPartialAttribute {
-                            atype: "objectClass".to_string(),
-                            vals: vec![
-                                "inetOrgPerson".to_string(),
-                                "posixAccount".to_string(),
-                                "mailAccount".to_string(),
-                                "person".to_string()
-                            ]
-                        },
-                        LdapPartialAttribute {
-                            atype: "dn".to_string(),
-                            vals: vec!["cn=Bôb Böbberson,ou=people,dc=example,dc=com".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "cn".to_string(),
-                            vals: vec!["Bôb Böbberson".to_string()]
-                        },
-                    ],
-                }),
-                LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
-                    attributes: vec![
-                        LdapPartialAttribute {
-                            atype: "objectClass".to_string(),
-                            vals: vec!["groupOfUniqueNames".to_string(),]
-                        },
-                        LdapPartialAttribute {
-                            atype: "dn".to_string(),
-                            vals: vec!["cn=group_1,ou=groups,dc=example,dc=com".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "cn".to_string(),
-                            vals: vec!["group_1".to_string()]
-                        },
-                    ],
-                }),
-                make_search_success(),
-            ]
+            ldap_handler.do_extended_request(&request(None)).await,
+            vec![make_extended_response(
+                LdapResultCode::InsufficentAccessRights,
+                "Only the admins can read the operation log".to_string(),
+            )]
+        );
+        let mut ldap_handler =
+            setup_bound_handler_with_options(MockTestBackendHandler::new(), options).await;
+        let read_log = |responses: Vec<LdapOp>| match &responses[..] {
+            [LdapOp::ExtendedResponse(response)] => {
+                assert_eq!(response.res.code, LdapResultCode::Success);
+                assert_eq!(response.name.as_deref(), Some(OPERATION_LOG_OID));
+                serde_json::from_slice::<serde_json::Value>(response.value.as_ref().unwrap())
+                    .unwrap()
+            }
+            responses => panic!("Unexpected responses: {:?}", responses),
+        };
+        let records = read_log(ldap_handler.do_extended_request(&request(None)).await);
+        assert_eq!(records.as_array().unwrap().len(), 2);
+        assert_eq!(records[0]["op_type"], "bind");
+        let records = read_log(ldap_handler.do_extended_request(&request(Some("1"))).await);
+        assert_eq!(records.as_array().unwrap().len(), 1);
+        assert_eq!(records[0]["op_type"], "search");
+        assert_eq!(
+            ldap_handler
+                .do_extended_request(&request(Some("all")))
+                .await,
+            vec![make_extended_response(
+                LdapResultCode::ProtocolError,
+                "The value must be a number of operations".to_string(),
+            )]
+        );
+        // Without the log, the operation is refused.
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
+        assert_eq!(
+            ldap_handler.do_extended_request(&request(None)).await,
+            vec![make_extended_response(
+                LdapResultCode::UnwillingToPerform,
+                "The operation log is disabled".to_string(),
+            )]
         );
     }
 
     #[tokio::test]
-    async fn test_search_wrong_base() {
-        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
-        let request = make_search_request(
-            "ou=users,dc=example,dc=com",
-            LdapFilter::And(vec![]),
-            vec!["objectClass"],
+    async fn test_sasl_external_bind_as_local_user() {
+        let request = SaslBindRequest {
+            dn: "".to_string(),
+            mechanism: "EXTERNAL".to_string(),
+            credentials: None,
+        };
+        let mut ldap_handler = LdapHandler::new(
+            MockTestBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions::default(),
+            None,
         );
+        ldap_handler.set_local_user(Some(UserId::new("dovecot")));
         assert_eq!(
-            ldap_handler.do_search(&request).await,
-            vec![make_search_success()]
+            ldap_handler.do_sasl_bind(&request).await,
+            (LdapResultCode::Success, "".to_string())
+        );
+        assert_eq!(
+            ldap_handler.dn,
+            LdapDn("cn=dovecot,ou=people,dc=example,dc=com".to_string())
+        );
+        assert_eq!(ldap_handler.user_id, UserId::new("dovecot"));
+        // Only as itself.
+        assert_eq!(
+            ldap_handler
+                .do_sasl_bind(&SaslBindRequest {
+                    credentials: Some(b"u:admin".to_vec()),
+                    ..request
+                })
+                .await
+                .0,
+            LdapResultCode::InsufficentAccessRights
         );
     }
 
-    #[tokio::test]
-    async fn test_search_unsupported_filters() {
-        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
-        let request = make_user_search_request(
-            LdapFilter::Substring(
-                "uid".to_string(),
-                ldap3_server::proto::LdapSubstringFilter::default(),
-            ),
-            vec!["objectClass"],
-        );
+    #[test]
+    fn test_posix_id_ranges() {
+        let ranges = |uid_offset, gid_offset| PosixIdRanges {
+            uid_offset,
+            gid_offset,
+        };
+        PosixIdRanges::default().check().unwrap();
+        ranges(10_000, 2_000_000_000).check().unwrap();
+        // The personal groups of the users would take the gidNumbers of the groups.
+        assert!(ranges(100_000, 50_000).check().is_err());
+        assert!(ranges(10_000, 100_000).check().is_err());
+        assert!(ranges(500, 10_000).check().is_err());
+        assert!(ranges(100_000, 0).check().is_err());
+        assert!(ranges(1 << 30, 10_000).check().is_ok());
+        assert!(ranges((1 << 30) + 1, 10_000).check().is_err());
+        let posix_ids = ranges(200_000, 20_000);
+        assert_eq!(posix_ids.gid_number(GroupId(3)), 20_003);
+        assert_eq!(posix_ids.group_id("20003"), Some(GroupId(3)));
+        assert_eq!(posix_ids.group_id("10003"), None);
+        let bob = User {
+            user_id: UserId::new("bob"),
+            posix_id: 42,
+            ..Default::default()
+        };
+        assert_eq!(posix_ids.uid_number(&bob), 200_042);
         assert_eq!(
-            ldap_handler.do_search(&request).await,
-            vec![make_search_error(
-                LdapResultCode::UnwillingToPerform,
-                "Unsupported user filter: Unsupported user filter: Substring(\"uid\", LdapSubstringFilter { initial: None, any: [], final_: None })".to_string()
-            )]
+            posix_ids.uid_number(&User {
+                posix_id: POSIX_ID_RANGE as i32 - 1,
+                ..bob
+            }),
+            200_000 + i64::from(POSIX_ID_RANGE) - 1
         );
     }
 
     #[tokio::test]
-    async fn test_password_change() {
+    async fn test_disabled_operations() {
         let mut mock = MockTestBackendHandler::new();
-        use lldap_auth::*;
-        let mut rng = rand::rngs::OsRng;
-        let registration_start_request =
-            opaque::client::registration::start_registration("password", &mut rng).unwrap();
-        let request = registration::ClientRegistrationStartRequest {
-            username: "bob".to_string(),
-            registration_start_request: registration_start_request.message,
-        };
-        let start_response = opaque::server::registration::start_registration(
-            &opaque::server::ServerSetup::new(&mut rng),
-            request.registration_start_request,
-            &request.username,
-        )
-        .unwrap();
-        mock.expect_registration_start().times(1).return_once(|_| {
-            Ok(registration::ServerRegistrationStartResponse {
-                server_data: "".to_string(),
-                registration_response: start_response.message,
-            })
-        });
-        mock.expect_registration_finish()
+        // The disabled operations never reach the backend.
+        mock.expect_list_groups()
             .times(1)
-            .return_once(|_| Ok(()));
-        let mut ldap_handler = setup_bound_handler(mock).await;
-        let request = LdapOp::ExtendedRequest(
-            LdapPasswordModifyRequest {
-                user_identity: Some("cn=bob,ou=people,dc=example,dc=com".to_string()),
-                old_password: None,
-                new_password: Some("password".to_string()),
-            }
-            .into(),
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = setup_bound_handler_with_options(
+            mock,
+            LdapHandlerOptions {
+                disabled_operations: ["add", "Delete", "modify", "extended"]
+                    .iter()
+                    .map(|op| op.to_string())
+                    .collect(),
+                ..Default::default()
+            },
+        )
+        .await;
+        let refused = |op_type: &str, response: fn(LdapResultCode, String) -> LdapResponseOp| {
+            Some(vec![LdapResponse {
+                op: response(
+                    LdapResultCode::UnwillingToPerform,
+                    format!("The {} operations are disabled on this server", op_type),
+                ),
+                controls: vec![],
+            }])
+        };
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_request(
+                    LdapRequest::Delete(DeleteRequest {
+                        dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                    }),
+                    &[]
+                )
+                .await,
+            refused("delete", make_delete_response)
         );
         assert_eq!(
-            ldap_handler.handle_ldap_message(request).await,
-            Some(vec![make_extended_response(
-                LdapResultCode::Success,
-                "".to_string(),
-            )])
+            ldap_handler
+                .handle_ldap_request(
+                    LdapRequest::Add(AddRequest {
+                        dn: "uid=jim,ou=people,dc=example,dc=com".to_string(),
+                        attributes: vec![("uid".to_string(), vec![b"jim".to_vec()])],
+                    }),
+                    &[]
+                )
+                .await,
+            refused("add", make_add_response)
+        );
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_request(
+                    LdapRequest::Modify(ModifyRequest {
+                        dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                        changes: vec![Modification {
+                            operation: ModifyOperation::Replace,
+                            attribute: "mail".to_string(),
+                            values: vec![b"bob@example.com".to_vec()],
+                        }],
+                    }),
+                    &[]
+                )
+                .await,
+            refused("modify", make_modify_response)
+        );
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_request(
+                    LdapRequest::Op(LdapOp::ExtendedRequest(LdapExtendedRequest {
+                        name: WHOAMI_OID.to_string(),
+                        value: None,
+                    })),
+                    &[]
+                )
+                .await,
+            refused("extended", |code, message| make_extended_response(
+                code, message
+            )
+            .into())
+        );
+        // The other operations still work.
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_request(
+                    LdapRequest::Op(LdapOp::SearchRequest(make_group_search_request(
+                        LdapFilter::And(vec![]),
+                        vec!["cn"],
+                    ))),
+                    &[]
+                )
+                .await,
+            Some(vec![make_search_success().into()])
         );
     }
 
     #[tokio::test]
-    async fn test_password_change_errors() {
-        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
-        let request = LdapOp::ExtendedRequest(
-            LdapPasswordModifyRequest {
-                user_identity: None,
-                old_password: None,
-                new_password: None,
-            }
-            .into(),
+    async fn test_password_modification_of_other_users() {
+        let request = LdapPasswordModifyRequest {
+            user_identity: Some("uid=admin,ou=people,dc=example,dc=com".to_string()),
+            old_password: None,
+            new_password: Some("password".to_string()),
+        };
+        let mut ldap_handler = LdapHandler::new(
+            MockTestBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions::default(),
+            None,
         );
         assert_eq!(
-            ldap_handler.handle_ldap_message(request).await,
-            Some(vec![make_extended_response(
-                LdapResultCode::ConstraintViolation,
-                "Missing either user_id or password".to_string(),
-            )])
-        );
-        let request = LdapOp::ExtendedRequest(
-            LdapPasswordModifyRequest {
-                user_identity: Some("cn=bob,ou=groups,ou=people,dc=example,dc=com".to_string()),
-                old_password: None,
-                new_password: Some("password".to_string()),
-            }
-            .into(),
+            ldap_handler.do_password_modification(&request).await,
+            vec![make_extended_response(
+                LdapResultCode::InsufficentAccessRights,
+                "Anonymous sessions cannot change passwords".to_string(),
+            )]
         );
+        let mut ldap_handler = setup_bound_user_handler(MockTestBackendHandler::new(), &[]).await;
         assert_eq!(
-            ldap_handler.handle_ldap_message(request).await,
-            Some(vec![make_extended_response(
-                LdapResultCode::InvalidDNSyntax,
-                r#"Invalid username: "Unexpected user DN format. Got \"cn=bob,ou=groups,ou=people,dc=example,dc=com\", expected: \"uid=username,ou=people,dc=example,dc=com\"""#.to_string(),
-            )])
+            ldap_handler.do_password_modification(&request).await,
+            vec![make_extended_response(
+                LdapResultCode::InsufficentAccessRights,
+                "Only admins can change the password of other users".to_string(),
+            )]
         );
-        let request = LdapOp::ExtendedRequest(LdapExtendedRequest {
-            name: "test".to_string(),
-            value: None,
-        });
+    }
+
+    #[tokio::test]
+    async fn test_proxied_authorization_of_admin() {
+        let mut mock = MockTestBackendHandler::new();
+        expect_no_changes(&mut mock);
+        mock.expect_bind().times(1).return_once(|_| Ok(()));
+        let mut groups = HashSet::new();
+        groups.insert(GroupIdAndName(GroupId(3), "proxies".to_string()));
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .return_once(|_| Ok(groups));
+        mock.expect_get_user_details()
+            .times(2)
+            .returning(|user_id| {
+                Ok(User {
+                    user_id: user_id.clone(),
+                    ..Default::default()
+                })
+            });
+        let mut admin_groups = HashSet::new();
+        admin_groups.insert(GroupIdAndName(GroupId(1), "lldap_admin".to_string()));
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("alice")))
+            .times(1)
+            .return_once(|_| Ok(admin_groups));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions {
+                proxy_group: Some("Proxies".to_string()),
+                ..Default::default()
+            },
+            None,
+        );
+        let request = LdapBindRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
         assert_eq!(
-            ldap_handler.handle_ldap_message(request).await,
-            Some(vec![make_extended_response(
-                LdapResultCode::UnwillingToPerform,
-                "Unsupported extended operation: test".to_string(),
-            )])
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
         );
+        let request = || {
+            LdapRequest::Op(LdapOp::SearchRequest(make_user_search_request(
+                LdapFilter::And(vec![]),
+                vec!["uid"],
+            )))
+        };
+        let denied = |user_id: &str| {
+            Some(vec![LdapResponse {
+                op: LdapResponseOp::WithResultCode(
+                    Box::new(
+                        make_search_error(
+                            LdapResultCode::Other,
+                            format!(
+                                r#"Proxied authorization denied: Only the admins can act on behalf of the admin "{}""#,
+                                user_id
+                            ),
+                        )
+                        .into(),
+                    ),
+                    AUTHORIZATION_DENIED,
+                ),
+                controls: vec![],
+            }])
+        };
+        // Neither the LDAP admin, nor the members of the admin group.
+        for user_id in ["admin", "alice"] {
+            let control = RawControl {
+                oid: PROXIED_AUTHORIZATION_OID.to_string(),
+                criticality: true,
+                value: Some(format!("u:{}", user_id).into_bytes()),
+            };
+            assert_eq!(
+                ldap_handler
+                    .handle_ldap_request(request(), &[control])
+                    .await,
+                denied(user_id)
+            );
+        }
+        assert_eq!(ldap_handler.user_id, UserId::new("bob"));
     }
 
     #[tokio::test]
-    async fn test_search_root_dse() {
-        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
-        let request = LdapSearchRequest {
-            base: "".to_string(),
-            scope: LdapSearchScope::Base,
-            aliases: LdapDerefAliases::Never,
-            sizelimit: 0,
-            timelimit: 0,
-            typesonly: false,
-            filter: LdapFilter::Present("objectClass".to_string()),
-            attrs: vec!["supportedExtension".to_string()],
+    async fn test_bound_user_groups_after_change() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_bind().return_once(|_| Ok(()));
+        let (sender, receiver) = tokio::sync::watch::channel(0);
+        mock.expect_subscribe_to_changes()
+            .returning(move || receiver.clone());
+        let mut admin_groups = HashSet::new();
+        admin_groups.insert(GroupIdAndName(GroupId(1), "lldap_admin".to_string()));
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .return_once(|_| Ok(admin_groups));
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .return_once(|_| Ok(HashSet::new()));
+        let mut ldap_handler = LdapHandler::new(
+            mock,
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions::default(),
+            None,
+        );
+        let request = LdapBindRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
         };
         assert_eq!(
-            ldap_handler.do_search(&request).await,
-            vec![
-                root_dse_response("dc=example,dc=com"),
-                make_search_success()
-            ]
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
         );
+        // Read once, then from the cache.
+        assert!(ldap_handler.is_admin().await);
+        assert!(ldap_handler.is_admin().await);
+        // E.g. bob was removed from the admin group in the web UI.
+        sender.send(1).unwrap();
+        assert!(!ldap_handler.is_admin().await);
     }
 }
@@ -0,0 +1,259 @@
+//! The subschema entry, describing the object classes and attributes served over LDAP.
+use ldap3_server::proto::{LdapOp, LdapPartialAttribute, LdapSearchResultEntry};
+
+/// DN of the subschema entry, as advertised in the root DSE.
+pub const SCHEMA_DN: &str = "cn=schema";
+
+const OBJECT_CLASSES: &[&str] = &[
+    "( 2.5.6.0 NAME 'top' ABSTRACT MUST objectClass )",
+    "( 2.5.6.6 NAME 'person' SUP top STRUCTURAL MUST ( sn $ cn ) )",
+    "( 2.5.6.7 NAME 'organizationalPerson' SUP person STRUCTURAL )",
+    "( 2.16.840.1.113730.3.2.2 NAME 'inetOrgPerson' SUP organizationalPerson STRUCTURAL \
+     MAY ( displayName $ givenName $ jpegPhoto $ mail $ uid ) )",
+    "( 1.3.6.1.1.1.2.0 NAME 'posixAccount' SUP top AUXILIARY \
+     MUST ( cn $ uid $ uidNumber $ gidNumber $ homeDirectory ) )",
+    "( 2.5.6.9 NAME 'groupOfNames' SUP top STRUCTURAL MUST ( member $ cn ) )",
+    "( 2.5.6.17 NAME 'groupOfUniqueNames' SUP top STRUCTURAL MUST ( uniqueMember $ cn ) )",
+    "( 1.3.6.1.1.1.2.2 NAME 'posixGroup' SUP top AUXILIARY MUST ( cn $ gidNumber ) \
+     MAY memberUid )",
+    "( 0.9.2342.19200300.100.4.13 NAME 'domain' SUP top STRUCTURAL MUST dc )",
+    "( 2.5.6.4 NAME 'organization' SUP top STRUCTURAL MUST o )",
+    "( 2.5.6.5 NAME 'organizationalUnit' SUP top STRUCTURAL MUST ou )",
+    "( 1.3.6.1.4.1.1466.101.120.111 NAME 'extensibleObject' SUP top AUXILIARY )",
+    "( 2.5.20.1 NAME 'subschema' AUXILIARY \
+     MAY ( objectClasses $ attributeTypes $ matchingRules ) )",
+];
+
+const ATTRIBUTE_TYPES: &[&str] = &[
+    "( 2.5.4.0 NAME 'objectClass' EQUALITY objectIdentifierMatch \
+     SYNTAX 1.3.6.1.4.1.1466.115.121.1.38 )",
+    "( 2.5.4.41 NAME 'name' EQUALITY caseIgnoreMatch SUBSTR caseIgnoreSubstringsMatch \
+     SYNTAX 1.3.6.1.4.1.1466.115.121.1.15{32768} )",
+    "( 2.5.4.3 NAME ( 'cn' 'commonName' ) SUP name )",
+    "( 2.5.4.4 NAME ( 'sn' 'surname' ) SUP name )",
+    "( 2.5.4.42 NAME ( 'givenName' 'gn' ) SUP name )",
+    "( 2.16.840.1.113730.3.1.241 NAME 'displayName' EQUALITY caseIgnoreMatch \
+     SUBSTR caseIgnoreSubstringsMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.15 SINGLE-VALUE )",
+    "( 0.9.2342.19200300.100.1.1 NAME ( 'uid' 'userid' ) EQUALITY caseIgnoreMatch \
+     SUBSTR caseIgnoreSubstringsMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.15{256} )",
+    "( 0.9.2342.19200300.100.1.3 NAME ( 'mail' 'rfc822Mailbox' ) EQUALITY caseIgnoreIA5Match \
+     SUBSTR caseIgnoreIA5SubstringsMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.26{256} )",
+    "( 0.9.2342.19200300.100.1.60 NAME 'jpegPhoto' SYNTAX 1.3.6.1.4.1.1466.115.121.1.28 )",
+    "( 2.16.840.1.113730.3.1.35 NAME 'thumbnailPhoto' \
+     SYNTAX 1.3.6.1.4.1.1466.115.121.1.40 SINGLE-VALUE )",
+    "( 1.3.6.1.1.1.1.0 NAME 'uidNumber' EQUALITY integerMatch \
+     SYNTAX 1.3.6.1.4.1.1466.115.121.1.27 SINGLE-VALUE )",
+    "( 1.3.6.1.1.1.1.1 NAME 'gidNumber' EQUALITY integerMatch \
+     SYNTAX 1.3.6.1.4.1.1466.115.121.1.27 SINGLE-VALUE )",
+    "( 1.3.6.1.1.1.1.3 NAME 'homeDirectory' EQUALITY caseExactIA5Match \
+     SYNTAX 1.3.6.1.4.1.1466.115.121.1.26 SINGLE-VALUE )",
+    "( 1.3.6.1.1.1.1.12 NAME 'memberUid' EQUALITY caseExactIA5Match \
+     SYNTAX 1.3.6.1.4.1.1466.115.121.1.26 )",
+    "( 2.5.4.49 NAME 'distinguishedName' EQUALITY distinguishedNameMatch \
+     SYNTAX 1.3.6.1.4.1.1466.115.121.1.12 )",
+    "( 2.5.4.31 NAME 'member' SUP distinguishedName )",
+    "( 2.5.4.50 NAME 'uniqueMember' EQUALITY uniqueMemberMatch \
+     SYNTAX 1.3.6.1.4.1.1466.115.121.1.34 )",
+    "( 2.5.18.1 NAME 'createTimestamp' EQUALITY generalizedTimeMatch \
+     ORDERING generalizedTimeOrderingMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.24 \
+     SINGLE-VALUE NO-USER-MODIFICATION USAGE directoryOperation )",
+    "( 2.5.18.2 NAME 'modifyTimestamp' EQUALITY generalizedTimeMatch \
+     ORDERING generalizedTimeOrderingMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.24 \
+     SINGLE-VALUE NO-USER-MODIFICATION USAGE directoryOperation )",
+    "( 1.3.6.1.1.16.4 NAME 'entryUUID' EQUALITY UUIDMatch ORDERING UUIDOrderingMatch \
+     SYNTAX 1.3.6.1.1.16.1 SINGLE-VALUE NO-USER-MODIFICATION USAGE directoryOperation )",
+    "( 2.5.18.9 NAME 'hasSubordinates' EQUALITY booleanMatch \
+     SYNTAX 1.3.6.1.4.1.1466.115.121.1.7 SINGLE-VALUE NO-USER-MODIFICATION \
+     USAGE directoryOperation )",
+    "( 1.3.6.1.1.20 NAME 'entryDN' EQUALITY distinguishedNameMatch \
+     SYNTAX 1.3.6.1.4.1.1466.115.121.1.12 SINGLE-VALUE NO-USER-MODIFICATION \
+     USAGE directoryOperation )",
+    "( 2.16.840.1.113730.3.1.610 NAME 'nsAccountLock' EQUALITY booleanMatch \
+     SYNTAX 1.3.6.1.4.1.1466.115.121.1.7 SINGLE-VALUE NO-USER-MODIFICATION \
+     USAGE directoryOperation )",
+    "( 1.3.6.1.4.1.42.2.27.8.1.16 NAME 'pwdChangedTime' EQUALITY generalizedTimeMatch \
+     ORDERING generalizedTimeOrderingMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.24 \
+     SINGLE-VALUE NO-USER-MODIFICATION USAGE directoryOperation )",
+    "( 1.3.6.1.4.1.42.2.27.8.1.22 NAME 'pwdReset' EQUALITY booleanMatch \
+     SYNTAX 1.3.6.1.4.1.1466.115.121.1.7 SINGLE-VALUE NO-USER-MODIFICATION \
+     USAGE directoryOperation )",
+    "( 0.9.2342.19200300.100.1.25 NAME ( 'dc' 'domainComponent' ) EQUALITY caseIgnoreIA5Match \
+     SUBSTR caseIgnoreIA5SubstringsMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.26 SINGLE-VALUE )",
+    "( 2.5.4.10 NAME ( 'o' 'organizationName' ) SUP name )",
+    "( 2.5.4.11 NAME ( 'ou' 'organizationalUnitName' ) SUP name )",
+];
+
+const MATCHING_RULES: &[&str] = &[
+    "( 2.5.13.0 NAME 'objectIdentifierMatch' SYNTAX 1.3.6.1.4.1.1466.115.121.1.38 )",
+    "( 2.5.13.1 NAME 'distinguishedNameMatch' SYNTAX 1.3.6.1.4.1.1466.115.121.1.12 )",
+    "( 2.5.13.2 NAME 'caseIgnoreMatch' SYNTAX 1.3.6.1.4.1.1466.115.121.1.15 )",
+    "( 2.5.13.4 NAME 'caseIgnoreSubstringsMatch' SYNTAX 1.3.6.1.4.1.1466.115.121.1.58 )",
+    "( 2.5.13.13 NAME 'booleanMatch' SYNTAX 1.3.6.1.4.1.1466.115.121.1.7 )",
+    "( 2.5.13.14 NAME 'integerMatch' SYNTAX 1.3.6.1.4.1.1466.115.121.1.27 )",
+    "( 2.5.13.23 NAME 'uniqueMemberMatch' SYNTAX 1.3.6.1.4.1.1466.115.121.1.34 )",
+    "( 2.5.13.27 NAME 'generalizedTimeMatch' SYNTAX 1.3.6.1.4.1.1466.115.121.1.24 )",
+    "( 2.5.13.28 NAME 'generalizedTimeOrderingMatch' SYNTAX 1.3.6.1.4.1.1466.115.121.1.24 )",
+    "( 1.3.6.1.4.1.1466.109.114.1 NAME 'caseExactIA5Match' \
+     SYNTAX 1.3.6.1.4.1.1466.115.121.1.26 )",
+    "( 1.3.6.1.4.1.1466.109.114.2 NAME 'caseIgnoreIA5Match' \
+     SYNTAX 1.3.6.1.4.1.1466.115.121.1.26 )",
+    "( 1.3.6.1.4.1.1466.109.114.3 NAME 'caseIgnoreIA5SubstringsMatch' \
+     SYNTAX 1.3.6.1.4.1.1466.115.121.1.58 )",
+    "( 1.3.6.1.1.16.2 NAME 'UUIDMatch' SYNTAX 1.3.6.1.1.16.1 )",
+    "( 1.3.6.1.1.16.3 NAME 'UUIDOrderingMatch' SYNTAX 1.3.6.1.1.16.1 )",
+];
+
+/// The names of a definition: the quoted values after NAME, alone or in parentheses.
+fn definition_names(definition: &str) -> Vec<&str> {
+    let rest = match definition.split_once(" NAME ") {
+        Some((_, rest)) => rest.trim_start(),
+        None => return vec![],
+    };
+    let names = match rest.strip_prefix('(') {
+        Some(list) => list.split(')').next().unwrap_or_default(),
+        None => rest.split(' ').next().unwrap_or_default(),
+    };
+    names
+        .split_whitespace()
+        .map(|name| name.trim_matches('\''))
+        .collect()
+}
+
+fn has_name(definition: &str, name: &str) -> bool {
+    definition_names(definition)
+        .iter()
+        .any(|n| n.eq_ignore_ascii_case(name))
+}
+
+/// Whether the DN designates the subschema entry, either at the root or under the base DN.
+pub fn is_schema_dn(dn: &str, base_dn: &str) -> bool {
+    dn.eq_ignore_ascii_case(SCHEMA_DN)
+        || dn.eq_ignore_ascii_case(&format!("{},{}", SCHEMA_DN, base_dn))
+}
+
+fn to_strings(values: &[&str]) -> Vec<String> {
+    values.iter().map(|v| v.to_string()).collect()
+}
+
+/// The subschema entry, as served with the options: the user object classes that the schema
+/// doesn't define are declared as auxiliary classes without attributes (with a "<name>-oid"
+/// OID, as there is no real one), and the hidden attributes are left out.
+pub fn schema_response(
+    dn: &str,
+    user_object_classes: &[String],
+    hidden_attributes: &[String],
+) -> LdapOp {
+    let object_classes = to_strings(OBJECT_CLASSES)
+        .into_iter()
+        .chain(
+            user_object_classes
+                .iter()
+                .filter(|class| !OBJECT_CLASSES.iter().any(|d| has_name(d, class)))
+                .map(|class| format!("( {}-oid NAME '{}' SUP top AUXILIARY )", class, class)),
+        )
+        .collect();
+    let attribute_types = ATTRIBUTE_TYPES
+        .iter()
+        .filter(|d| !hidden_attributes.iter().any(|a| has_name(d, a)))
+        .map(|d| d.to_string())
+        .collect();
+    LdapOp::SearchResultEntry(LdapSearchResultEntry {
+        dn: dn.to_string(),
+        attributes: vec![
+            LdapPartialAttribute {
+                atype: "objectClass".to_string(),
+                vals: to_strings(&["top", "subschema"]),
+            },
+            LdapPartialAttribute {
+                atype: "cn".to_string(),
+                vals: vec!["schema".to_string()],
+            },
+            LdapPartialAttribute {
+                atype: "objectClasses".to_string(),
+                vals: object_classes,
+            },
+            LdapPartialAttribute {
+                atype: "attributeTypes".to_string(),
+                vals: attribute_types,
+            },
+            LdapPartialAttribute {
+                atype: "matchingRules".to_string(),
+                vals: to_strings(MATCHING_RULES),
+            },
+        ],
+    })
+}
+
+/// Writes an entry as LDIF (RFC 2849), folding the lines at 76 characters. The values that
+/// are not safe strings are base64-encoded.
+pub fn to_ldif(entry: &LdapSearchResultEntry) -> String {
+    let mut ldif = String::new();
+    let mut push_line = |name: &str, value: &str| {
+        let is_safe = !value.starts_with(&[' ', ':', '<'][..])
+            && !value.ends_with(' ')
+            && value.bytes().all(|b| (0x20..0x7f).contains(&b));
+        let line = if is_safe {
+            format!("{}: {}", name, value)
+        } else {
+            format!("{}:: {}", name, base64::encode(value))
+        };
+        // The lines are ASCII: they can be split anywhere.
+        let mut rest = line.as_str();
+        let mut width = 76;
+        while rest.len() > width {
+            let (chunk, tail) = rest.split_at(width);
+            ldif.push_str(chunk);
+            ldif.push_str("\n ");
+            rest = tail;
+            width = 75;
+        }
+        ldif.push_str(rest);
+        ldif.push('\n');
+    };
+    push_line("dn", &entry.dn);
+    for attribute in &entry.attributes {
+        for value in &attribute.vals {
+            push_line(&attribute.atype, value);
+        }
+    }
+    ldif
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_definition_names() {
+        assert_eq!(
+            definition_names(ATTRIBUTE_TYPES[2]),
+            vec!["cn", "commonName"]
+        );
+        assert_eq!(definition_names(OBJECT_CLASSES[0]), vec!["top"]);
+        assert!(definition_names("( 1.2.3 )").is_empty());
+    }
+
+    #[test]
+    fn test_to_ldif() {
+        let entry = LdapSearchResultEntry {
+            dn: "cn=schema".to_string(),
+            attributes: vec![LdapPartialAttribute {
+                atype: "description".to_string(),
+                vals: vec![
+                    "short".to_string(),
+                    "x".repeat(100),
+                    " leading space".to_string(),
+                ],
+            }],
+        };
+        assert_eq!(
+            to_ldif(&entry),
+            format!(
+                "dn: cn=schema\ndescription: short\ndescription: {}\n {}\ndescription:: {}\n",
+                "x".repeat(63),
+                "x".repeat(37),
+                base64::encode(" leading space")
+            )
+        );
+    }
+}
@@ -1,96 +1,1641 @@
 use crate::{
     domain::{
-        handler::{BackendHandler, LoginHandler},
+        handler::{BackendHandler, LoginHandler, UserId},
         opaque_handler::OpaqueHandler,
     },
-    infra::{configuration::Configuration, ldap_handler::LdapHandler},
+    infra::{
+        configuration::{Configuration, TlsVersion},
+        connection_limiter::UserConnectionLimiter,
+        group_cache::GroupCache,
+        ldap_codec::{LdapFrame, LdapFrameCodec, LdapRequest},
+        ldap_handler::{
+            check_base_dn, parse_group_dn, parse_ou_rdn, LdapHandler, LdapHandlerOptions,
+            LdapReadOnlyAccount, LdapReferral, LdapResponse, LdapSearchRestriction, PosixIdRanges,
+            DISABLEABLE_OPERATIONS,
+        },
+        logging::ACCESS_LOG_TARGET,
+        metrics::LdapMetrics,
+        operation_log::{filter_to_string, OperationLog, OperationRecord},
+        proxy_protocol::read_proxy_header,
+        rate_limiter::{BindFailureTracker, BindRateLimiter},
+        search_cache::SearchCache,
+    },
 };
-use actix_rt::net::TcpStream;
+use actix_rt::net::{TcpStream, UnixStream};
 use actix_server::ServerBuilder;
 use actix_service::{fn_service, ServiceFactoryExt};
-use anyhow::{Context, Result};
-use futures_util::future::ok;
-use ldap3_server::{proto::LdapMsg, LdapCodec};
+use anyhow::{anyhow, bail, Context, Result};
+use arc_swap::ArcSwap;
+use futures_util::{future::ok, FutureExt};
+use ipnet::IpNet;
+use ldap3_server::proto::{LdapExtendedResponse, LdapOp, LdapResult, LdapResultCode};
 use log::*;
-use tokio::net::tcp::WriteHalf;
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    panic::AssertUnwindSafe,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, WriteHalf},
+    signal::unix::{signal, Signal, SignalKind},
+    sync::{mpsc, watch, OwnedSemaphorePermit, Semaphore},
+};
+use tokio_rustls::{
+    rustls::{
+        server::{
+            AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello,
+            NoServerSessionStorage, ProducesTickets, ResolvesServerCert, ServerSessionMemoryCache,
+            StoresServerSessions,
+        },
+        sign::{any_supported_type, CertifiedKey},
+        version::{TLS12, TLS13},
+        Certificate, PrivateKey, RootCertStore, ServerConfig, SignatureScheme,
+        SupportedCipherSuite, SupportedProtocolVersion, Ticketer, DEFAULT_CIPHER_SUITES,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
 use tokio_util::codec::{FramedRead, FramedWrite};
+use tracing::Instrument;
 
-async fn handle_incoming_message<Backend>(
-    msg: Result<LdapMsg, std::io::Error>,
-    resp: &mut FramedWrite<WriteHalf<'_>, LdapCodec>,
-    session: &mut LdapHandler<Backend>,
+/// The unsolicited notification sent before the server closes a connection (RFC 4511).
+const NOTICE_OF_DISCONNECTION_OID: &str = "1.3.6.1.4.1.1466.20036";
+
+/// How long the client has to read the notice of disconnection, at most.
+const NOTICE_OF_DISCONNECTION_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The number of streamed search entries waiting to be written to the client, at most.
+const STREAMED_RESPONSES_BUFFER: usize = 64;
+
+/// How long a load balancer has to send the PROXY protocol header, once connected.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What to do with the connection after handling a message.
+enum ConnectionAction {
+    Continue,
+    Close,
+    StartTls,
+}
+
+/// Sends the responses to a message, and flushes them. Returns false if the client didn't read
+/// them within the write timeout.
+async fn write_responses<Stream>(
+    resp: &mut FramedWrite<WriteHalf<Stream>, LdapFrameCodec>,
+    msgid: i32,
+    responses: Vec<LdapResponse>,
+    write_timeout: Option<Duration>,
+    peer_ip: &str,
 ) -> Result<bool>
 where
-    Backend: BackendHandler + LoginHandler + OpaqueHandler,
+    Stream: AsyncWrite,
 {
     use futures_util::SinkExt;
+    // The frames are buffered up to the backpressure boundary of the sink: past that, sending
+    // waits for the client to read.
+    let write = async {
+        for response in responses.into_iter() {
+            debug!("Replying with LDAP op: {:?}", &response);
+            resp.send(LdapFrame {
+                msgid,
+                op: response.op,
+                controls: response.controls,
+            })
+            .await
+            .context("while sending a response: {:#}")?
+        }
+
+        resp.flush().await.context("while flushing responses: {:#}")
+    };
+    match write_timeout {
+        None => write.await?,
+        Some(write_timeout) => match tokio::time::timeout(write_timeout, write).await {
+            Ok(result) => result?,
+            Err(_) => {
+                warn!(
+                    "Closing the LDAP connection from {}: the responses were not read within {:?}",
+                    if peer_ip.is_empty() {
+                        "an unknown address"
+                    } else {
+                        peer_ip
+                    },
+                    write_timeout
+                );
+                return Ok(false);
+            }
+        },
+    }
+    Ok(true)
+}
+
+/// Runs the future, turning a panic into an error with the panic message, so that a bug in the
+/// handling of a request only closes its connection. The panic hook still logs where the panic
+/// happened (and the backtrace, with `RUST_BACKTRACE=1`).
+async fn catch_panic<F: Future>(future: F) -> std::result::Result<F::Output, String> {
+    AssertUnwindSafe(future)
+        .catch_unwind()
+        .await
+        .map_err(|panic| {
+            panic
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string())
+        })
+}
+
+async fn handle_incoming_message<Stream, Backend>(
+    msg: Result<LdapFrame<LdapRequest>, std::io::Error>,
+    resp: &mut FramedWrite<WriteHalf<Stream>, LdapFrameCodec>,
+    session: &mut LdapHandler<Backend>,
+    metrics: Option<&LdapMetrics>,
+    write_timeout: Option<Duration>,
+) -> Result<ConnectionAction>
+where
+    Stream: AsyncWrite,
+    Backend: BackendHandler + LoginHandler + OpaqueHandler,
+{
     let msg = msg.context("while receiving LDAP op")?;
     debug!("Received LDAP message: {:?}", &msg);
-    match session.handle_ldap_message(msg.op).await {
-        None => return Ok(false),
+    let op_type = msg.op.op_type();
+    let bind_dn = match &msg.op {
+        LdapRequest::Op(LdapOp::BindRequest(request)) => request.dn.clone(),
+        LdapRequest::SaslBind(request) => request.dn.clone(),
+        LdapRequest::UnsupportedBind(request) => request.dn.clone(),
+        _ => session.bound_dn().unwrap_or_default().to_string(),
+    };
+    let operation_log = session.operation_log().cloned();
+    let filter = match (&operation_log, &msg.op) {
+        (Some(_), LdapRequest::Op(LdapOp::SearchRequest(request))) => {
+            Some(filter_to_string(&request.filter))
+        }
+        _ => None,
+    };
+    let peer_ip = session
+        .peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_default();
+    // The backend calls are nested in this span, e.g. to see which query made a bind slow.
+    let span = tracing::info_span!(
+        "ldap_operation",
+        msgid = msg.msgid,
+        op_type,
+        peer_ip = peer_ip.as_str()
+    );
+    let start = Instant::now();
+    // The entries of the searches are sent while the search is still running, as they are
+    // found: the channel is bounded, so a slow client slows the search down instead of the
+    // entries piling up in memory.
+    let msgid = msg.msgid;
+    let (sender, receiver) = mpsc::channel(STREAMED_RESPONSES_BUFFER);
+    let forward = async {
+        let mut receiver = receiver;
+        while let Some(response) = receiver.recv().await {
+            if !write_responses(resp, msgid, vec![response], write_timeout, &peer_ip).await? {
+                return Ok(false);
+            }
+        }
+        Ok::<_, anyhow::Error>(true)
+    };
+    let (responses, forwarded) = futures_util::join!(
+        catch_panic(
+            session
+                .handle_ldap_request_streaming(msgid, msg.op, &msg.controls, sender)
+                .instrument(span)
+        ),
+        forward
+    );
+    let (responses, panic) = match responses {
+        Ok(responses) => (responses, None),
+        Err(message) => (None, Some(message)),
+    };
+    let result_code = match &panic {
+        Some(_) => "panic".to_string(),
+        None => responses
+            .as_ref()
+            .and_then(|responses| responses.last())
+            .and_then(|response| response.op.result())
+            .map(|result| format!("{:?}", result.code))
+            .unwrap_or_else(|| "none".to_string()),
+    };
+    let duration = start.elapsed();
+    if let Some(metrics) = metrics {
+        metrics.record_operation(op_type, &result_code, duration);
+    }
+    if let Some(operation_log) = operation_log {
+        operation_log.record(OperationRecord {
+            time: chrono::Utc::now(),
+            op_type: op_type.to_string(),
+            bind_dn: bind_dn.clone(),
+            peer_ip: peer_ip.clone(),
+            filter,
+            result_code: result_code.clone(),
+            duration_ms: duration.as_secs_f64() * 1000.0,
+        });
+    }
+    tracing::info!(
+        target: ACCESS_LOG_TARGET,
+        op_type,
+        msgid,
+        bind_dn = bind_dn.as_str(),
+        source_ip = peer_ip.as_str(),
+        result_code = result_code.as_str(),
+        duration_ms = duration.as_secs_f64() * 1000.0,
+        "LDAP operation"
+    );
+    if let Some(message) = panic {
+        // The state of the session is unknown: the connection can't go on.
+        error!(
+            "Panic while handling a {} request from {}, closing the connection: {}",
+            op_type,
+            if peer_ip.is_empty() {
+                "an unknown address"
+            } else {
+                peer_ip.as_str()
+            },
+            message
+        );
+        send_notice_of_disconnection(resp, LdapResultCode::Other, "Internal server error").await;
+        return Ok(ConnectionAction::Close);
+    }
+    if !forwarded? {
+        return Ok(ConnectionAction::Close);
+    }
+    match responses {
+        None => {
+            // Unbind: there is no response, the connection is simply closed.
+            debug!("Closing the connection");
+            return Ok(ConnectionAction::Close);
+        }
         Some(result) => {
             if result.is_empty() {
                 debug!("No response");
             }
-            for result_op in result.into_iter() {
-                debug!("Replying with LDAP op: {:?}", &result_op);
-                resp.send(LdapMsg {
-                    msgid: msg.msgid,
-                    op: result_op,
-                    ctrl: vec![],
-                })
-                .await
-                .context("while sending a response: {:#}")?
+            // The canceled operation is answered before the cancel request (RFC 3909).
+            if let Some((canceled_msgid, response)) = session.take_canceled_search() {
+                if !write_responses(
+                    resp,
+                    canceled_msgid,
+                    vec![response],
+                    write_timeout,
+                    &peer_ip,
+                )
+                .await?
+                {
+                    return Ok(ConnectionAction::Close);
+                }
             }
+            if !write_responses(resp, msgid, result, write_timeout, &peer_ip).await? {
+                return Ok(ConnectionAction::Close);
+            }
+        }
+    }
+    if session.take_close_request() {
+        debug!("Closing the connection");
+        return Ok(ConnectionAction::Close);
+    }
+    if session.take_start_tls_request() {
+        Ok(ConnectionAction::StartTls)
+    } else {
+        Ok(ConnectionAction::Continue)
+    }
+}
+
+/// Tells the client why the connection is about to be closed. The connection is closed anyway,
+/// so the errors are ignored, and a client that doesn't read doesn't hold it open.
+async fn send_notice_of_disconnection<Stream>(
+    resp: &mut FramedWrite<WriteHalf<Stream>, LdapFrameCodec>,
+    code: LdapResultCode,
+    message: &str,
+) where
+    Stream: AsyncWrite,
+{
+    use futures_util::SinkExt;
+    let notice = LdapFrame {
+        msgid: 0,
+        op: LdapOp::ExtendedResponse(LdapExtendedResponse {
+            res: LdapResult {
+                code,
+                matcheddn: "".to_string(),
+                message: message.to_string(),
+                referral: vec![],
+            },
+            name: Some(NOTICE_OF_DISCONNECTION_OID.to_string()),
+            value: None,
+        })
+        .into(),
+        controls: vec![],
+    };
+    match tokio::time::timeout(NOTICE_OF_DISCONNECTION_TIMEOUT, resp.send(notice)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => debug!("Could not send the notice of disconnection: {:#}", e),
+        Err(_) => debug!("Timed out sending the notice of disconnection"),
+    }
+}
+
+/// Tells the connections that the server is shutting down, and what to tell their clients.
+#[derive(Clone)]
+struct ShutdownSignal {
+    receiver: watch::Receiver<bool>,
+    /// The message of the notice of disconnection sent to the clients.
+    message: Arc<str>,
+}
+
+/// Resolves once the server starts shutting down.
+async fn wait_for_shutdown(shutdown: &mut watch::Receiver<bool>) {
+    while !*shutdown.borrow() {
+        if shutdown.changed().await.is_err() {
+            // Nobody can notify us anymore.
+            futures_util::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Handles the messages on the stream until the connection is closed, or a StartTLS request is
+/// accepted. Returns the stream so that it can be upgraded in the latter case.
+///
+/// When the server shuts down, the operation in progress is completed and its responses are
+/// flushed before the connection is closed, with a notice of disconnection so that the client
+/// knows to reconnect. Same when the connection fails.
+///
+/// Between the messages, the changes of the persistent search (RFC 4533) of the connection, if
+/// any, are sent as they happen. Such a connection is never idle.
+async fn handle_ldap_messages<Stream, Backend>(
+    stream: Stream,
+    codec: LdapFrameCodec,
+    session: &mut LdapHandler<Backend>,
+    idle_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    metrics: Option<&LdapMetrics>,
+    shutdown: &mut ShutdownSignal,
+) -> Result<(Stream, ConnectionAction)>
+where
+    Stream: AsyncRead + AsyncWrite + Unpin,
+    Backend: BackendHandler + LoginHandler + OpaqueHandler,
+{
+    use futures_util::StreamExt;
+
+    // Configure the codec etc.
+    let (r, w) = tokio::io::split(stream);
+    let mut requests = FramedRead::new(r, codec);
+    let mut resp = FramedWrite::new(w, codec);
+
+    let mut action = ConnectionAction::Close;
+    loop {
+        let persistent_search_id = session.persistent_search_id();
+        let idle_timeout = idle_timeout.filter(|_| persistent_search_id.is_none());
+        let next_message = async {
+            match idle_timeout {
+                None => Some(requests.next().await),
+                Some(idle_timeout) => tokio::time::timeout(idle_timeout, requests.next())
+                    .await
+                    .ok(),
+            }
+        };
+        // None when the backend changed.
+        let msg = tokio::select! {
+            msg = next_message => Some(msg),
+            _ = session.wait_for_changes(), if persistent_search_id.is_some() => None,
+            _ = wait_for_shutdown(&mut shutdown.receiver) => {
+                info!("Closing the LDAP connection for the server shutdown");
+                send_notice_of_disconnection(
+                    &mut resp,
+                    LdapResultCode::Unavailable,
+                    &shutdown.message,
+                )
+                .await;
+                break;
+            }
+        };
+        let msg = match (msg, persistent_search_id) {
+            (Some(msg), _) => msg,
+            (None, Some(msgid)) => {
+                let responses = session.get_persistent_search_changes().await;
+                let peer_ip = session
+                    .peer_addr()
+                    .map(|addr| addr.ip().to_string())
+                    .unwrap_or_default();
+                if !write_responses(&mut resp, msgid, responses, write_timeout, &peer_ip).await? {
+                    break;
+                }
+                continue;
+            }
+            (None, None) => continue,
+        };
+        let msg = match msg {
+            // A client sending garbage is not a server error: it is only logged.
+            Some(Some(Err(e))) if e.kind() == std::io::ErrorKind::InvalidData => {
+                warn!(
+                    "Closing the LDAP connection from {}: malformed message: {}",
+                    session
+                        .peer_addr()
+                        .map(|addr| addr.ip().to_string())
+                        .unwrap_or_else(|| "an unknown address".to_string()),
+                    e
+                );
+                send_notice_of_disconnection(
+                    &mut resp,
+                    LdapResultCode::ProtocolError,
+                    "Malformed LDAP message",
+                )
+                .await;
+                break;
+            }
+            Some(Some(msg)) => msg,
+            Some(None) => break,
+            None => {
+                info!(
+                    "Closing the LDAP connection after being idle for {:?}",
+                    idle_timeout.unwrap_or_default()
+                );
+                break;
+            }
+        };
+        match handle_incoming_message(msg, &mut resp, session, metrics, write_timeout).await {
+            Ok(ConnectionAction::Continue) => continue,
+            Ok(stop) => {
+                action = stop;
+                break;
+            }
+            Err(e) => {
+                send_notice_of_disconnection(
+                    &mut resp,
+                    LdapResultCode::Unavailable,
+                    "The connection failed, reconnect to go on",
+                )
+                .await;
+                return Err(e.context("while handling incoming messages"));
+            }
+        }
+    }
+
+    Ok((requests.into_inner().unsplit(resp.into_inner()), action))
+}
+
+/// The current TLS configuration, replaced when the certificate is reloaded.
+type TlsConfig = Arc<ArcSwap<ServerConfig>>;
 
-            resp.flush()
+/// How TLS is set up on a listener.
+#[derive(Clone)]
+enum ListenerTls {
+    /// Plaintext connections, that can be upgraded with StartTLS if TLS is configured.
+    StartTls(Option<TlsConfig>),
+    /// LDAPS: the TLS handshake happens as soon as the connection is accepted.
+    Implicit(TlsConfig),
+}
+
+/// The client address, for the logs.
+fn describe_peer(peer_addr: Option<SocketAddr>) -> String {
+    peer_addr
+        .map(|addr| addr.to_string())
+        .unwrap_or_else(|| "an unknown address".to_string())
+}
+
+/// Logs and counts a connection that is closed before any operation, so that the clients that
+/// can't connect can be told apart from the operations that fail.
+fn reject_connection(metrics: Option<&LdapMetrics>, reason: &str, error: anyhow::Error) {
+    warn!("{:#}", error);
+    if let Some(metrics) = metrics {
+        metrics.record_connection_failure(reason);
+    }
+}
+
+async fn accept_tls<Backend>(
+    tls_config: &TlsConfig,
+    stream: TcpStream,
+    session: &mut LdapHandler<Backend>,
+) -> Result<TlsStream<TcpStream>>
+where
+    Backend: BackendHandler + LoginHandler + OpaqueHandler,
+{
+    let peer = describe_peer(session.peer_addr());
+    // The connection keeps the configuration of its handshake, even after a reload.
+    let tls_stream = TlsAcceptor::from(tls_config.load_full())
+        .accept(stream)
+        .await
+        .with_context(|| format!("TLS handshake with {} failed", peer))?;
+    let connection = tls_stream.get_ref().1;
+    info!(
+        "TLS established with {}: {:?}, {:?}",
+        peer,
+        connection.protocol_version(),
+        connection
+            .negotiated_cipher_suite()
+            .map(|suite| suite.suite())
+    );
+    session.set_tls_active();
+    session.set_client_certificate(
+        tls_stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certificates| certificates.first())
+            .map(|certificate| certificate.0.clone()),
+    );
+    Ok(tls_stream)
+}
+
+/// State shared by all the LDAP connections, of both listeners.
+#[derive(Clone)]
+struct SharedState {
+    config: Arc<Configuration>,
+    bind_rate_limiter: Option<Arc<BindRateLimiter>>,
+    bind_failure_tracker: Option<Arc<BindFailureTracker>>,
+    metrics: Option<Arc<LdapMetrics>>,
+    referrals: Vec<LdapReferral>,
+    search_restrictions: Vec<LdapSearchRestriction>,
+    readonly_account: Option<LdapReadOnlyAccount>,
+    admin_group: Option<String>,
+    user_ou_mapping: Vec<(String, String)>,
+    group_cache: Option<Arc<GroupCache>>,
+    search_cache: Option<Arc<SearchCache>>,
+    /// One permit per allowed concurrent connection, if they are limited.
+    connection_limit: Option<Arc<Semaphore>>,
+    user_connection_limiter: Option<Arc<UserConnectionLimiter>>,
+    operation_log: Option<Arc<OperationLog>>,
+    start_time: chrono::DateTime<chrono::Utc>,
+    shutdown: ShutdownSignal,
+}
+
+/// One of the permits of the connection limit, if the connections are limited.
+fn acquire_connection_permit(state: &SharedState) -> Result<Option<OwnedSemaphorePermit>> {
+    match &state.connection_limit {
+        None => Ok(None),
+        Some(limit) => match limit.clone().try_acquire_owned() {
+            Ok(permit) => Ok(Some(permit)),
+            Err(_) => bail!("too many open connections"),
+        },
+    }
+}
+
+/// A session with the settings of the server, for a new connection.
+fn new_session<Backend>(
+    backend_handler: Backend,
+    state: &SharedState,
+    start_tls_available: bool,
+    peer_addr: Option<SocketAddr>,
+) -> LdapHandler<Backend>
+where
+    Backend: BackendHandler + LoginHandler + OpaqueHandler,
+{
+    let config = &state.config;
+    LdapHandler::new(
+        backend_handler,
+        config.ldap_base_dn[0].clone(),
+        config.ldap_user_dn.clone(),
+        LdapHandlerOptions {
+            start_tls_available,
+            max_size_limit: config.ldap_max_size_limit,
+            search_timeout: config.ldap_search_timeout_seconds.map(Duration::from_secs),
+            allow_anonymous_bind: config.ldap_allow_anonymous_bind,
+            anonymous_readable_attributes: config.ldap_anonymous_readable_attributes.clone(),
+            require_auth_for_search: config.ldap_require_auth_for_search,
+            hidden_attributes: config.ldap_hidden_attributes.clone(),
+            bind_rate_limiter: state.bind_rate_limiter.clone(),
+            bind_failure_tracker: state.bind_failure_tracker.clone(),
+            report_account_lockout: config.ldap_report_account_lockout,
+            referrals: state.referrals.clone(),
+            search_restrictions: state.search_restrictions.clone(),
+            base_dn_aliases: config.ldap_base_dn[1..].to_vec(),
+            user_object_classes: config.ldap_user_object_classes.clone(),
+            home_directory_template: config.ldap_home_directory_template.clone(),
+            cn_template: config.ldap_cn_template.clone(),
+            ad_compatibility: config.ldap_ad_compatibility,
+            people_ou: config.ldap_people_ou.clone(),
+            groups_ou: config.ldap_groups_ou.clone(),
+            user_rdn_attribute: config.ldap_user_rdn_attribute,
+            allow_email_login: config.ldap_allow_email_login,
+            netbios_domain: config.ldap_netbios_domain.clone(),
+            upn_suffix: config.ldap_upn_suffix.clone(),
+            bind_dn_template: config.ldap_bind_dn_template.clone(),
+            readonly_account: state.readonly_account.clone(),
+            proxy_group: config.ldap_proxy_group.clone(),
+            allow_insecure_sasl_plain: config.ldap_allow_insecure_sasl_plain,
+            all_users_group: config.ldap_virtual_all_users_group.clone(),
+            admin_group: state.admin_group.clone(),
+            user_ou_mapping: state.user_ou_mapping.clone(),
+            group_cache: state.group_cache.clone(),
+            search_cache: state.search_cache.clone(),
+            max_photo_bytes: config.ldap_max_photo_bytes,
+            max_attribute_value_bytes: config.ldap_max_attribute_value_bytes,
+            max_binary_attribute_value_bytes: config.ldap_max_binary_attribute_value_bytes,
+            member_range_threshold: config.ldap_member_range_threshold,
+            max_filter_complexity: config.ldap_max_filter_complexity,
+            disabled_operations: config.ldap_disabled_operations.clone(),
+            user_connection_limiter: state.user_connection_limiter.clone(),
+            cache_bound_user: config.ldap_cache_bound_user,
+            verbose_result_messages: config.ldap_verbose_result_messages,
+            posix_ids: get_posix_id_ranges(config),
+            start_time: state.start_time,
+            operation_log: state.operation_log.clone(),
+        },
+        peer_addr,
+    )
+}
+
+async fn handle_ldap_stream<Backend>(
+    mut stream: TcpStream,
+    backend_handler: Backend,
+    tls: ListenerTls,
+    mut state: SharedState,
+) -> Result<()>
+where
+    Backend: BackendHandler + LoginHandler + OpaqueHandler,
+{
+    let metrics = state.metrics.clone();
+    let metrics = metrics.as_deref();
+    // Held until the connection is closed.
+    let _permit = match acquire_connection_permit(&state) {
+        Ok(permit) => permit,
+        Err(e) => {
+            reject_connection(
+                metrics,
+                "connection_limit",
+                e.context(format!(
+                    "Rejected a connection from {}",
+                    describe_peer(stream.peer_addr().ok())
+                )),
+            );
+            return Ok(());
+        }
+    };
+    let _connection = metrics.map(|m| m.connection_opened());
+    let config = &state.config;
+    configure_socket(
+        &stream,
+        config.tcp_keepalive_seconds.map(Duration::from_secs),
+    );
+    let peer_addr = if config.ldap_proxy_protocol {
+        // Anyone else could pretend to connect from any address.
+        if let Err(e) = check_trusted_proxy(
+            stream.peer_addr().ok(),
+            &config.ldap_proxy_protocol_trusted_cidrs,
+        ) {
+            reject_connection(metrics, "proxy_protocol", e);
+            return Ok(());
+        }
+        let proxied_addr =
+            match tokio::time::timeout(PROXY_HEADER_TIMEOUT, read_proxy_header(&mut stream))
                 .await
-                .context("while flushing responses: {:#}")?
+                .context("Timed out waiting for the PROXY protocol header")
+                .and_then(|header| header)
+            {
+                Ok(proxied_addr) => proxied_addr,
+                Err(e) => {
+                    reject_connection(
+                        metrics,
+                        "proxy_protocol",
+                        e.context(format!(
+                            "Rejected a connection from {}",
+                            describe_peer(stream.peer_addr().ok())
+                        )),
+                    );
+                    return Ok(());
+                }
+            };
+        // Without a source address (e.g. the health checks of the load balancer), the connection
+        // is from the proxy itself.
+        proxied_addr.or_else(|| stream.peer_addr().ok())
+    } else {
+        stream.peer_addr().ok()
+    };
+    let peer_addr = match check_peer_address(peer_addr, &config.ldap_allowed_cidrs) {
+        Ok(peer_addr) => peer_addr,
+        Err(e) => {
+            reject_connection(metrics, "not_allowed", e);
+            return Ok(());
+        }
+    };
+    info!("New LDAP connection from {}", describe_peer(peer_addr));
+    let mut session = new_session(
+        backend_handler,
+        &state,
+        matches!(tls, ListenerTls::StartTls(Some(_))),
+        peer_addr,
+    );
+    let idle_timeout = config.ldap_idle_timeout_seconds.map(Duration::from_secs);
+    let write_timeout = config.ldap_write_timeout_seconds.map(Duration::from_secs);
+    let codec = LdapFrameCodec::new(config.ldap_max_message_bytes);
+    let shutdown = &mut state.shutdown;
+    match tls {
+        ListenerTls::Implicit(tls_config) => {
+            let tls_stream = match accept_tls(&tls_config, stream, &mut session).await {
+                Ok(tls_stream) => tls_stream,
+                Err(e) => {
+                    reject_connection(metrics, "tls_handshake", e);
+                    return Ok(());
+                }
+            };
+            handle_ldap_messages(
+                tls_stream,
+                codec,
+                &mut session,
+                idle_timeout,
+                write_timeout,
+                metrics,
+                shutdown,
+            )
+            .await?;
+        }
+        ListenerTls::StartTls(start_tls_config) => {
+            if let (stream, ConnectionAction::StartTls) = handle_ldap_messages(
+                stream,
+                codec,
+                &mut session,
+                idle_timeout,
+                write_timeout,
+                metrics,
+                shutdown,
+            )
+            .await?
+            {
+                let start_tls_config = start_tls_config
+                    .context("StartTLS was accepted without a TLS configuration")?;
+                let tls_stream = match accept_tls(&start_tls_config, stream, &mut session).await {
+                    Ok(tls_stream) => tls_stream,
+                    Err(e) => {
+                        reject_connection(
+                            metrics,
+                            "tls_handshake",
+                            e.context("while upgrading the connection to TLS"),
+                        );
+                        return Ok(());
+                    }
+                };
+                handle_ldap_messages(
+                    tls_stream,
+                    codec,
+                    &mut session,
+                    idle_timeout,
+                    write_timeout,
+                    metrics,
+                    shutdown,
+                )
+                .await?;
+            }
         }
     }
-    Ok(true)
+    Ok(())
+}
+
+/// Serves a connection on the Unix socket: without TLS, the PROXY protocol or the allowed
+/// ranges, which are about the network clients. The permissions of the socket file decide who
+/// can connect.
+async fn handle_unix_stream<Backend>(
+    stream: UnixStream,
+    backend_handler: Backend,
+    mut state: SharedState,
+) -> Result<()>
+where
+    Backend: BackendHandler + LoginHandler + OpaqueHandler,
+{
+    let metrics = state.metrics.clone();
+    let metrics = metrics.as_deref();
+    // Held until the connection is closed.
+    let _permit = match acquire_connection_permit(&state) {
+        Ok(permit) => permit,
+        Err(e) => {
+            reject_connection(
+                metrics,
+                "connection_limit",
+                e.context("Rejected a connection on the Unix socket"),
+            );
+            return Ok(());
+        }
+    };
+    let _connection = metrics.map(|m| m.connection_opened());
+    info!("New LDAP connection on the Unix socket");
+    let mut session = new_session(backend_handler, &state, false, None);
+    session.set_local_user(state.config.ldap_unix_socket_user.clone());
+    let config = &state.config;
+    handle_ldap_messages(
+        stream,
+        LdapFrameCodec::new(config.ldap_max_message_bytes),
+        &mut session,
+        config.ldap_idle_timeout_seconds.map(Duration::from_secs),
+        config.ldap_write_timeout_seconds.map(Duration::from_secs),
+        metrics,
+        &mut state.shutdown,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Disables Nagle's algorithm, since the LDAP responses are small, and enables the TCP
+/// keepalives if configured, to detect the half-open connections.
+fn configure_socket(stream: &TcpStream, keepalive: Option<Duration>) {
+    use socket2::{SockRef, TcpKeepalive};
+    if let Err(e) = stream.set_nodelay(true) {
+        warn!("Could not set TCP_NODELAY on the LDAP connection: {:#}", e);
+    }
+    if let Some(keepalive) = keepalive {
+        let keepalive = TcpKeepalive::new()
+            .with_time(keepalive)
+            .with_interval(keepalive);
+        if let Err(e) = SockRef::from(stream).set_tcp_keepalive(&keepalive) {
+            warn!(
+                "Could not enable the TCP keepalives on the LDAP connection: {:#}",
+                e
+            );
+        }
+    }
+}
+
+/// The IPv4 form of the IPv4-mapped IPv6 addresses (`::ffff:1.2.3.4`), with which the IPv4
+/// clients appear on a dual-stack socket.
+fn normalize_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.octets() {
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => {
+                IpAddr::V4(std::net::Ipv4Addr::new(a, b, c, d))
+            }
+            _ => ip,
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Returns the address of the client, or an error if it is not allowed to connect. The
+/// IPv4-mapped addresses are returned in their IPv4 form, and matched against the IPv4 ranges.
+fn check_peer_address(
+    peer_addr: Option<SocketAddr>,
+    allowed_cidrs: &[IpNet],
+) -> Result<Option<SocketAddr>> {
+    let peer_addr = peer_addr.map(|addr| SocketAddr::new(normalize_ip(addr.ip()), addr.port()));
+    if allowed_cidrs.is_empty() {
+        return Ok(peer_addr);
+    }
+    match peer_addr {
+        Some(addr) if allowed_cidrs.iter().any(|cidr| cidr.contains(&addr.ip())) => Ok(peer_addr),
+        Some(addr) => bail!(
+            "Rejected a connection from {}: not in the allowed ranges",
+            addr
+        ),
+        None => bail!("Rejected a connection from an unknown address"),
+    }
+}
+
+/// Returns an error unless the peer is one of the load balancers allowed to send the PROXY
+/// protocol header.
+fn check_trusted_proxy(peer_addr: Option<SocketAddr>, trusted_cidrs: &[IpNet]) -> Result<()> {
+    match peer_addr.map(|addr| normalize_ip(addr.ip())) {
+        Some(ip) if trusted_cidrs.iter().any(|cidr| cidr.contains(&ip)) => Ok(()),
+        Some(ip) => bail!(
+            "Rejected a connection from {}: not in ldap_proxy_protocol_trusted_cidrs",
+            ip
+        ),
+        None => bail!("Rejected a connection from an unknown address"),
+    }
+}
+
+fn read_certificates(cert_file: &str) -> Result<Vec<Certificate>> {
+    use std::{fs::File, io::BufReader};
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(cert_file)
+            .with_context(|| format!("Could not open the certificate file `{}`", cert_file))?,
+    ))
+    .with_context(|| format!("Could not parse the certificate file `{}`", cert_file))?;
+    if certs.is_empty() {
+        bail!("No certificate found in `{}`", cert_file);
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_root_certificates(ca_file: &str) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    let certificates = read_certificates(ca_file)?
+        .into_iter()
+        .map(|certificate| certificate.0)
+        .collect::<Vec<_>>();
+    let (valid, invalid) = roots.add_parsable_certificates(&certificates);
+    if invalid > 0 {
+        warn!("Ignored {} invalid certificates in `{}`", invalid, ca_file);
+    }
+    if valid == 0 {
+        bail!("No valid CA certificate found in `{}`", ca_file);
+    }
+    Ok(roots)
+}
+
+fn read_private_key(key_file: &str) -> Result<PrivateKey> {
+    use rustls_pemfile::Item;
+    use std::{fs::File, io::BufReader};
+    let mut reader = BufReader::new(
+        File::open(key_file)
+            .with_context(|| format!("Could not open the key file `{}`", key_file))?,
+    );
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)
+        .with_context(|| format!("Could not parse the key file `{}`", key_file))?
+    {
+        match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => {
+                return Ok(PrivateKey(key))
+            }
+            _ => continue,
+        }
+    }
+    bail!("No private key found in `{}`", key_file)
+}
+
+/// Checks that the private key matches the certificate, by signing a message with the key and
+/// verifying the signature with the certificate.
+fn check_key_matches_certificate(certificate: &Certificate, key: &PrivateKey) -> Result<()> {
+    fn webpki_algorithm(scheme: SignatureScheme) -> Option<&'static webpki::SignatureAlgorithm> {
+        Some(match scheme {
+            SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+            SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+            SignatureScheme::ED25519 => &webpki::ED25519,
+            SignatureScheme::RSA_PKCS1_SHA256 => &webpki::RSA_PKCS1_2048_8192_SHA256,
+            _ => return None,
+        })
+    }
+    let signer = any_supported_type(key)
+        .map_err(|_| anyhow!("Unsupported private key type"))?
+        .choose_scheme(&[
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            SignatureScheme::ED25519,
+            SignatureScheme::RSA_PKCS1_SHA256,
+        ])
+        .context("Unsupported private key type")?;
+    let algorithm = webpki_algorithm(signer.scheme()).context("Unsupported private key type")?;
+    let message = b"lldap certificate check";
+    let signature = signer
+        .sign(message)
+        .context("Could not sign with the private key")?;
+    webpki::EndEntityCert::try_from(certificate.0.as_slice())
+        .map_err(|e| anyhow!("Invalid certificate: {:?}", e))?
+        .verify_signature(algorithm, message, &signature)
+        .map_err(|_| anyhow!("The private key doesn't match the certificate"))
+}
+
+/// The allowed cipher suites, by their IANA names, or the safe defaults of rustls.
+fn get_cipher_suites(names: Option<&[String]>) -> Result<Vec<SupportedCipherSuite>> {
+    let names = match names {
+        None => return Ok(DEFAULT_CIPHER_SUITES.to_vec()),
+        Some(names) => names,
+    };
+    if names.is_empty() {
+        bail!("ldaps_cipher_suites can't be an empty list");
+    }
+    names
+        .iter()
+        .map(|name| {
+            DEFAULT_CIPHER_SUITES
+                .iter()
+                .find(|suite| format!("{:?}", suite.suite()).eq_ignore_ascii_case(name))
+                .copied()
+                .with_context(|| {
+                    format!(
+                        "Unknown or unsafe cipher suite `{}` in ldaps_cipher_suites, expected one of: {}",
+                        name,
+                        DEFAULT_CIPHER_SUITES
+                            .iter()
+                            .map(|suite| format!("{:?}", suite.suite()))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )
+                })
+        })
+        .collect()
+}
+
+/// Reads a certificate chain and its private key, and checks that they match.
+fn read_certified_key(cert_file: &str, key_file: &str) -> Result<CertifiedKey> {
+    let certificates = read_certificates(cert_file)?;
+    let key = read_private_key(key_file)?;
+    check_key_matches_certificate(&certificates[0], &key).with_context(|| {
+        format!(
+            "while checking the certificate `{}` and the key `{}`",
+            cert_file, key_file
+        )
+    })?;
+    let key = any_supported_type(&key).map_err(|_| anyhow!("Unsupported private key type"))?;
+    Ok(CertifiedKey::new(certificates, key))
+}
+
+/// Picks the certificate by the hostname that the client asked for with SNI, or the default one
+/// (ldaps_cert_file) for the other hostnames and the clients without SNI.
+struct SniCertificateResolver {
+    certificates: HashMap<String, Arc<CertifiedKey>>,
+    default: Arc<CertifiedKey>,
+}
+
+impl SniCertificateResolver {
+    fn select(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        server_name
+            .and_then(|name| {
+                self.certificates
+                    .get(name.trim_end_matches('.').to_lowercase().as_str())
+            })
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+impl ResolvesServerCert for SniCertificateResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.select(client_hello.server_name()))
+    }
+}
+
+/// Checks the hostnames of ldaps_sni_certificates, before reading any file.
+fn check_sni_hostnames(config: &Configuration) -> Result<()> {
+    let mut hostnames = HashSet::new();
+    for sni_certificate in &config.ldaps_sni_certificates {
+        let hostname = sni_certificate.hostname.to_lowercase();
+        if hostname.is_empty() {
+            bail!("Empty hostname in ldaps_sni_certificates");
+        }
+        if !hostnames.insert(hostname) {
+            bail!(
+                "Duplicate hostname `{}` in ldaps_sni_certificates",
+                sni_certificate.hostname
+            );
+        }
+    }
+    Ok(())
+}
+
+fn get_tls_config(config: &Configuration) -> Result<Option<ServerConfig>> {
+    let (cert_file, key_file) = match (&config.ldaps_cert_file, &config.ldaps_key_file) {
+        (Some(cert_file), Some(key_file)) => (cert_file, key_file),
+        (None, None) if config.ldaps_sni_certificates.is_empty() => return Ok(None),
+        (None, None) => bail!(
+            "ldaps_sni_certificates needs ldaps_cert_file and ldaps_key_file to be set, for the \
+             clients without SNI"
+        ),
+        _ => bail!("ldaps_cert_file and ldaps_key_file must be set together"),
+    };
+    check_sni_hostnames(config)?;
+    let versions: &[&'static SupportedProtocolVersion] = match config.ldaps_min_tls_version {
+        TlsVersion::Tls12 => &[&TLS13, &TLS12],
+        TlsVersion::Tls13 => &[&TLS13],
+    };
+    let server_config = ServerConfig::builder()
+        .with_cipher_suites(&get_cipher_suites(config.ldaps_cipher_suites.as_deref())?)
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions)
+        .context("Invalid TLS settings: check ldaps_min_tls_version and ldaps_cipher_suites")?;
+    let server_config = match (
+        &config.ldaps_client_ca_file,
+        config.ldaps_require_client_cert,
+    ) {
+        (None, false) => server_config.with_no_client_auth(),
+        (None, true) => bail!("ldaps_require_client_cert needs ldaps_client_ca_file to be set"),
+        // The handshake fails without a client certificate signed by the CA.
+        (Some(ca_file), true) => server_config.with_client_cert_verifier(
+            AllowAnyAuthenticatedClient::new(read_root_certificates(ca_file)?),
+        ),
+        (Some(ca_file), false) => {
+            // Client certificates are optional, and can be used for SASL EXTERNAL binds.
+            server_config.with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(
+                read_root_certificates(ca_file)?,
+            ))
+        }
+    };
+    let certificates = config
+        .ldaps_sni_certificates
+        .iter()
+        .map(|sni_certificate| {
+            Ok((
+                sni_certificate.hostname.to_lowercase(),
+                Arc::new(
+                    read_certified_key(&sni_certificate.cert_file, &sni_certificate.key_file)
+                        .with_context(|| {
+                            format!("for the hostname `{}`", sni_certificate.hostname)
+                        })?,
+                ),
+            ))
+        })
+        .collect::<Result<HashMap<_, _>>>()?;
+    let resolver = SniCertificateResolver {
+        default: Arc::new(read_certified_key(cert_file, key_file)?),
+        certificates,
+    };
+    let mut server_config = server_config.with_cert_resolver(Arc::new(resolver));
+    let (session_storage, ticketer) = get_session_resumption(config)?;
+    server_config.session_storage = session_storage;
+    server_config.ticketer = ticketer;
+    Ok(Some(server_config))
+}
+
+/// Issues the session tickets with a key replaced every ticket lifetime: the tickets are
+/// accepted until the key after the one that encrypted them is replaced too, so for one to two
+/// lifetimes.
+struct RotatingTicketer {
+    lifetime: Duration,
+    keys: Mutex<RotatingKeys>,
+}
+
+type TicketProducer = Arc<dyn ProducesTickets>;
+type SessionStorage = Arc<dyn StoresServerSessions + Send + Sync>;
+
+#[derive(Clone)]
+struct RotatingKeys {
+    current: TicketProducer,
+    previous: Option<TicketProducer>,
+    created: Instant,
+}
+
+impl RotatingTicketer {
+    fn new(lifetime: Duration) -> Result<Self> {
+        Ok(Self {
+            lifetime,
+            keys: Mutex::new(RotatingKeys {
+                current: Self::make_key()?,
+                previous: None,
+                created: Instant::now(),
+            }),
+        })
+    }
+
+    fn make_key() -> Result<TicketProducer> {
+        Ticketer::new().map_err(|e| anyhow!("Could not create a session ticket key: {:?}", e))
+    }
+
+    /// The keys, after replacing the current one if it is too old. The previous one is dropped
+    /// too if it is older than two lifetimes, when there was no connection for a while.
+    fn get_keys(&self, now: Instant) -> RotatingKeys {
+        let mut keys = self.keys.lock().unwrap();
+        let age = now.saturating_duration_since(keys.created);
+        if age >= self.lifetime {
+            match Self::make_key() {
+                Ok(key) => {
+                    let previous = std::mem::replace(&mut keys.current, key);
+                    keys.previous = Some(previous).filter(|_| age < self.lifetime * 2);
+                    keys.created = now;
+                }
+                // Keep the old key until the next try rather than refuse the resumptions.
+                Err(e) => warn!("{:#}", e),
+            }
+        }
+        keys.clone()
+    }
+
+    fn encrypt_at(&self, plain: &[u8], now: Instant) -> Option<Vec<u8>> {
+        self.get_keys(now).current.encrypt(plain)
+    }
+
+    fn decrypt_at(&self, cipher: &[u8], now: Instant) -> Option<Vec<u8>> {
+        let keys = self.get_keys(now);
+        keys.current
+            .decrypt(cipher)
+            .or_else(|| keys.previous.and_then(|previous| previous.decrypt(cipher)))
+    }
+}
+
+impl ProducesTickets for RotatingTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        u32::try_from(self.lifetime.as_secs()).unwrap_or(u32::MAX)
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.encrypt_at(plain, Instant::now())
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        self.decrypt_at(cipher, Instant::now())
+    }
+}
+
+/// The session cache (TLS 1.2 session IDs) and the ticketer (TLS 1.3 and 1.2 tickets) of the
+/// resumptions, or none of them when the resumption is disabled: every connection then gets a
+/// full handshake, with fresh keys.
+fn get_session_resumption(config: &Configuration) -> Result<(SessionStorage, TicketProducer)> {
+    if !config.ldaps_session_resumption {
+        return Ok((Arc::new(NoServerSessionStorage {}), Arc::new(NoTickets)));
+    }
+    if config.ldaps_session_ticket_lifetime_seconds == 0 {
+        bail!("ldaps_session_ticket_lifetime_seconds must be positive");
+    }
+    Ok((
+        ServerSessionMemoryCache::new(config.ldaps_session_cache_size),
+        Arc::new(RotatingTicketer::new(Duration::from_secs(
+            config.ldaps_session_ticket_lifetime_seconds,
+        ))?),
+    ))
+}
+
+/// Never issues tickets, when the resumption is disabled.
+struct NoTickets;
+
+impl ProducesTickets for NoTickets {
+    fn enabled(&self) -> bool {
+        false
+    }
+
+    fn lifetime(&self) -> u32 {
+        0
+    }
+
+    fn encrypt(&self, _plain: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn decrypt(&self, _cipher: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// Reads the certificate and key files again when the server receives SIGHUP. The new
+/// connections use the new certificate, the existing ones keep theirs.
+async fn reload_tls_on_sighup(
+    mut sighup: Signal,
+    config: Arc<Configuration>,
+    tls_config: TlsConfig,
+) {
+    while sighup.recv().await.is_some() {
+        match get_tls_config(&config) {
+            Ok(Some(server_config)) => {
+                tls_config.store(Arc::new(server_config));
+                info!("Reloaded the LDAPS certificate");
+            }
+            Ok(None) => {
+                warn!("The LDAPS certificate is no longer configured, keeping the previous one")
+            }
+            Err(e) => error!(
+                "Could not reload the LDAPS certificate, keeping the previous one: {:#}",
+                e
+            ),
+        }
+    }
+}
+
+/// Creates the listening socket, with room for `backlog` pending connections. Listening on the
+/// unspecified IPv6 address (`::`) also accepts IPv4 connections, if the OS supports dual-stack
+/// sockets. With SO_REUSEADDR, a new instance can bind the port while the connections of the
+/// previous one are still in TIME_WAIT.
+fn bind_listener(host: &str, port: u16, backlog: u32) -> Result<std::net::TcpListener> {
+    use socket2::{Domain, Socket, Type};
+    let ip: IpAddr = host
+        .parse()
+        .with_context(|| format!("Invalid LDAP host `{}`: expected an IP address", host))?;
+    let addr = SocketAddr::new(ip, port);
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if ip.is_ipv6() && ip.is_unspecified() {
+        if let Err(e) = socket.set_only_v6(false) {
+            warn!("Could not enable IPv4 connections on `{}`: {:#}", host, e);
+        }
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    // The OS caps it anyway, e.g. to net.core.somaxconn on Linux.
+    socket.listen(i32::try_from(backlog).unwrap_or(i32::MAX))?;
+    Ok(socket.into())
+}
+
+/// Where the uidNumbers and the gidNumbers are, see `PosixIdRanges::check`.
+pub fn get_posix_id_ranges(config: &Configuration) -> PosixIdRanges {
+    PosixIdRanges {
+        uid_offset: config.ldap_posix_uid_offset,
+        gid_offset: config.ldap_posix_gid_offset,
+    }
+}
+
+/// The permissions of the Unix socket file, from their octal form ("660").
+fn get_unix_socket_mode(permissions: &str) -> Result<u32> {
+    match u32::from_str_radix(permissions, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => bail!(
+            r#"Invalid ldap_unix_socket_permissions "{}": expected octal permissions such as "660""#,
+            permissions
+        ),
+    }
+}
+
+/// Binds the Unix socket, replacing the socket left by a previous instance, but not another
+/// kind of file.
+fn bind_unix_listener(path: &str, mode: u32) -> Result<std::os::unix::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)
+            .with_context(|| format!("Could not remove the stale socket `{}`", path))?,
+        Ok(_) => bail!("`{}` already exists and is not a socket", path),
+        Err(_) => {}
+    }
+    let listener = std::os::unix::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .with_context(|| format!("Could not set the permissions of `{}`", path))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Notifies the connections when the server receives SIGTERM, so that they can finish their
+/// current operation and close. The server itself stops accepting new connections.
+async fn notify_on_shutdown(mut sigterm: Signal, shutdown: watch::Sender<bool>) {
+    sigterm.recv().await;
+    info!("Received SIGTERM, closing the LDAP connections");
+    // If there are no connections left, there is nobody to notify.
+    let _ = shutdown.send(true);
+}
+
+/// The name of the group of `ldap_admin_group_dn`.
+fn get_admin_group(config: &Configuration, dn: &str) -> Result<String> {
+    parse_group_dn(dn, &config.ldap_base_dn[0], &config.ldap_groups_ou)
+        .with_context(|| format!(r#"Invalid ldap_admin_group_dn "{}""#, dn))
+}
+
+/// Checks the settings that would otherwise only fail when the clients connect: the base DNs and
+/// the admin user. Also checks that at least one of the LDAP and LDAPS servers is started.
+fn check_ldap_settings(config: &Configuration) -> Result<()> {
+    if config.ldap_base_dn.is_empty() {
+        bail!("ldap_base_dn can't be an empty list");
+    }
+    for base_dn in &config.ldap_base_dn {
+        check_base_dn(base_dn).with_context(|| {
+            format!(
+                r#"Invalid ldap_base_dn "{}": expected a DN such as "dc=example,dc=com""#,
+                base_dn
+            )
+        })?;
+    }
+    // The admin is "uid=<ldap_user_dn>,<ldap_people_ou>,<base DN>".
+    let admin = config.ldap_user_dn.as_str();
+    if admin.is_empty() || admin.contains(|c: char| ",=+<>#;\\\"".contains(c)) {
+        bail!(
+            r#"Invalid ldap_user_dn "{}": expected the user ID of the admin, such as "admin", not a DN"#,
+            admin
+        );
+    }
+    for (name, ou) in [
+        ("ldap_people_ou", &config.ldap_people_ou),
+        ("ldap_groups_ou", &config.ldap_groups_ou),
+    ] {
+        parse_ou_rdn(ou).with_context(|| format!(r#"Invalid {} "{}""#, name, ou))?;
+    }
+    if config
+        .ldap_people_ou
+        .eq_ignore_ascii_case(&config.ldap_groups_ou)
+    {
+        bail!("ldap_people_ou and ldap_groups_ou must be different");
+    }
+    if let Some(dn) = &config.ldap_admin_group_dn {
+        get_admin_group(config, dn)?;
+    }
+    if let Some(template) = &config.ldap_cn_template {
+        if !["%display", "%first", "%last", "%uid"]
+            .iter()
+            .any(|token| template.contains(token))
+        {
+            bail!(
+                r#"Invalid ldap_cn_template "{}": expected "%display", "%first", "%last" or "%uid""#,
+                template
+            );
+        }
+    }
+    if let Some(template) = &config.ldap_bind_dn_template {
+        if !template.contains("%u") {
+            bail!(
+                r#"Invalid ldap_bind_dn_template "{}": "%u" should stand for the user name"#,
+                template
+            );
+        }
+    }
+    if config.ldap_tcp_backlog == 0 {
+        bail!("ldap_tcp_backlog must be at least 1");
+    }
+    if config.ldap_port == 0
+        && config.ldaps_cert_file.is_none()
+        && config.ldap_unix_socket_path.is_none()
+    {
+        bail!(
+            "ldap_port is 0 but neither LDAPS (ldaps_cert_file and ldaps_key_file) nor \
+             ldap_unix_socket_path is configured: no LDAP server would be started"
+        );
+    }
+    get_unix_socket_mode(&config.ldap_unix_socket_permissions)?;
+    if config.ldap_proxy_protocol && config.ldap_proxy_protocol_trusted_cidrs.is_empty() {
+        bail!("ldap_proxy_protocol needs ldap_proxy_protocol_trusted_cidrs to be set");
+    }
+    if let Some(replica_url) = &config.database_replica_url {
+        // The replica is opened with the SQLite driver, like the main database.
+        if !replica_url.starts_with("sqlite:") {
+            bail!(
+                r#"Invalid database_replica_url "{}": only SQLite is supported, e.g. "sqlite:///replica/users.db?mode=ro""#,
+                replica_url
+            );
+        }
+    }
+    // The caches are cleared on the writes to the main database, then filled again from the
+    // replica, which may not have the writes yet: they would keep the stale entries.
+    if config.database_replica_url.is_some()
+        && (config.ldap_group_cache_ttl_seconds.is_some()
+            || config.ldap_search_cache_ttl_seconds.is_some())
+    {
+        bail!(
+            "ldap_group_cache_ttl_seconds and ldap_search_cache_ttl_seconds can't be used with \
+             database_replica_url"
+        );
+    }
+    for operation in &config.ldap_disabled_operations {
+        if !DISABLEABLE_OPERATIONS
+            .iter()
+            .any(|op_type| op_type.eq_ignore_ascii_case(operation))
+        {
+            bail!(
+                r#"Invalid operation "{}" in ldap_disabled_operations: expected one of {}"#,
+                operation,
+                DISABLEABLE_OPERATIONS.join(", ")
+            );
+        }
+    }
+    get_posix_id_ranges(config)
+        .check()
+        .context("Invalid POSIX ID ranges")?;
+    Ok(())
+}
+
+/// Checks the LDAP settings, including the LDAPS certificate and key, without starting anything.
+pub fn check_config(config: &Configuration) -> Result<()> {
+    check_ldap_settings(config)?;
+    get_tls_config(config).context("while setting up LDAPS")?;
+    Ok(())
 }
 
 pub fn build_ldap_server<Backend>(
     config: &Configuration,
     backend_handler: Backend,
+    metrics: Option<Arc<LdapMetrics>>,
+    group_cache: Option<Arc<GroupCache>>,
     server_builder: ServerBuilder,
 ) -> Result<ServerBuilder>
 where
     Backend: BackendHandler + LoginHandler + OpaqueHandler + 'static,
 {
-    use futures_util::StreamExt;
-
-    let ldap_base_dn = config.ldap_base_dn.clone();
-    let ldap_user_dn = config.ldap_user_dn.clone();
+    check_ldap_settings(config).context("Invalid LDAP configuration")?;
+    let tls_config = get_tls_config(config)
+        .context("while setting up LDAPS")?
+        .map(|server_config| Arc::new(ArcSwap::from_pointee(server_config)));
+    let referrals = config
+        .ldap_referrals
+        .iter()
+        .map(|(dn, url)| LdapReferral::new(dn, url.clone()))
+        .collect::<Result<Vec<_>>>()
+        .context("while parsing ldap_referrals")?;
+    let search_restrictions = config
+        .ldap_search_restrictions
+        .iter()
+        .map(|(identity, bases)| LdapSearchRestriction::new(identity, bases))
+        .collect::<Result<Vec<_>>>()
+        .context("while parsing ldap_search_restrictions")?;
+    let readonly_account = match (&config.ldap_readonly_dn, &config.ldap_readonly_pass) {
+        (Some(dn), Some(password)) => Some(LdapReadOnlyAccount::new(dn, password.clone())?),
+        (None, None) => None,
+        _ => bail!("ldap_readonly_dn and ldap_readonly_pass must be set together"),
+    };
+    let admin_group = config
+        .ldap_admin_group_dn
+        .as_deref()
+        .map(|dn| get_admin_group(config, dn))
+        .transpose()?;
+    // Sorted, for a stable choice of OU for the users in several of the groups.
+    let mut user_ou_mapping = config
+        .ldap_user_ou_mapping
+        .iter()
+        .map(|(ou, group)| (ou.clone(), group.clone()))
+        .collect::<Vec<_>>();
+    user_ou_mapping.sort();
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+    let sigterm = signal(SignalKind::terminate()).context("while listening for SIGTERM")?;
+    actix_rt::spawn(notify_on_shutdown(sigterm, shutdown_sender));
+    let shared_config = Arc::new(config.clone());
+    if let Some(tls_config) = &tls_config {
+        let sighup = signal(SignalKind::hangup()).context("while listening for SIGHUP")?;
+        actix_rt::spawn(reload_tls_on_sighup(
+            sighup,
+            shared_config.clone(),
+            tls_config.clone(),
+        ));
+    }
+    let state = SharedState {
+        config: shared_config,
+        // Shared by both listeners, so that a client can't double its allowance.
+        bind_rate_limiter: config
+            .ldap_max_binds_per_minute_per_ip
+            .map(|max_per_minute| Arc::new(BindRateLimiter::new(max_per_minute))),
+        bind_failure_tracker: config.ldap_bind_failures_before_delay.map(|free_failures| {
+            Arc::new(BindFailureTracker::new(
+                free_failures,
+                Duration::from_secs(config.ldap_bind_failure_max_delay_seconds),
+            ))
+        }),
+        search_cache: config
+            .ldap_search_cache_ttl_seconds
+            .map(|ttl| Arc::new(SearchCache::new(Duration::from_secs(ttl), metrics.clone()))),
+        metrics,
+        referrals,
+        search_restrictions,
+        readonly_account,
+        admin_group,
+        user_ou_mapping,
+        group_cache,
+        connection_limit: config
+            .ldap_max_connections
+            .map(|max_connections| Arc::new(Semaphore::new(max_connections))),
+        user_connection_limiter: config
+            .ldap_max_connections_per_user
+            .map(|max_per_user| Arc::new(UserConnectionLimiter::new(max_per_user))),
+        // Shared by both listeners, to see the operations of all the connections.
+        operation_log: config
+            .ldap_operation_log_size
+            .map(|size| Arc::new(OperationLog::new(size))),
+        start_time: chrono::Utc::now(),
+        shutdown: ShutdownSignal {
+            receiver: shutdown_receiver,
+            message: config.ldap_shutdown_message.as_str().into(),
+        },
+    };
+    let server_builder = server_builder.shutdown_timeout(config.shutdown_grace_seconds);
+    let server_builder = if config.ldap_port == 0 {
+        info!("ldap_port is 0, not starting the plaintext LDAP server");
+        server_builder
+    } else {
+        let ldap_backend_handler = backend_handler.clone();
+        let ldap_state = state.clone();
+        let start_tls_config = tls_config.clone();
+        let ldap_listener =
+            bind_listener(&config.ldap_host, config.ldap_port, config.ldap_tcp_backlog)
+                .with_context(|| format!("while binding to the port {}", config.ldap_port))?;
+        server_builder
+            .listen("ldap", ldap_listener, move || {
+                let backend_handler = ldap_backend_handler.clone();
+                let state = ldap_state.clone();
+                let start_tls_config = start_tls_config.clone();
+                fn_service(move |stream: TcpStream| {
+                    handle_ldap_stream(
+                        stream,
+                        backend_handler.clone(),
+                        ListenerTls::StartTls(start_tls_config.clone()),
+                        state.clone(),
+                    )
+                })
+                .map_err(|err: anyhow::Error| error!("Service Error: {:#}", err))
+                .and_then(move |_| {
+                    // finally
+                    ok(())
+                })
+            })
+            .with_context(|| format!("while listening on the port {}", config.ldap_port))?
+    };
+    let server_builder = match &config.ldap_unix_socket_path {
+        None => server_builder,
+        Some(path) => {
+            let unix_backend_handler = backend_handler.clone();
+            let unix_state = state.clone();
+            let unix_listener = bind_unix_listener(
+                path,
+                get_unix_socket_mode(&config.ldap_unix_socket_permissions)?,
+            )
+            .with_context(|| format!("while binding to the Unix socket `{}`", path))?;
+            server_builder
+                .listen_uds("ldap-unix", unix_listener, move || {
+                    let backend_handler = unix_backend_handler.clone();
+                    let state = unix_state.clone();
+                    fn_service(move |stream: UnixStream| {
+                        handle_unix_stream(stream, backend_handler.clone(), state.clone())
+                    })
+                    .map_err(|err: anyhow::Error| error!("Service Error: {:#}", err))
+                    .and_then(move |_| {
+                        // finally
+                        ok(())
+                    })
+                })
+                .with_context(|| format!("while listening on the Unix socket `{}`", path))?
+        }
+    };
+    let tls_config = match tls_config {
+        Some(tls_config) => tls_config,
+        None => {
+            info!("No LDAPS certificate configured, not starting the LDAPS server");
+            return Ok(server_builder);
+        }
+    };
+    let ldaps_listener = bind_listener(
+        &config.ldap_host,
+        config.ldaps_port,
+        config.ldap_tcp_backlog,
+    )
+    .with_context(|| format!("while binding to the port {}", config.ldaps_port))?;
     server_builder
-        .bind("ldap", ("0.0.0.0", config.ldap_port), move || {
+        .listen("ldaps", ldaps_listener, move || {
             let backend_handler = backend_handler.clone();
-            let ldap_base_dn = ldap_base_dn.clone();
-            let ldap_user_dn = ldap_user_dn.clone();
-            fn_service(move |mut stream: TcpStream| {
-                let backend_handler = backend_handler.clone();
-                let ldap_base_dn = ldap_base_dn.clone();
-                let ldap_user_dn = ldap_user_dn.clone();
-                async move {
-                    // Configure the codec etc.
-                    let (r, w) = stream.split();
-                    let mut requests = FramedRead::new(r, LdapCodec);
-                    let mut resp = FramedWrite::new(w, LdapCodec);
-
-                    let mut session = LdapHandler::new(backend_handler, ldap_base_dn, ldap_user_dn);
-
-                    while let Some(msg) = requests.next().await {
-                        if !handle_incoming_message(msg, &mut resp, &mut session)
-                            .await
-                            .context("while handling incoming messages")?
-                        {
-                            break;
-                        }
-                    }
-
-                    Ok(stream)
-                }
+            let state = state.clone();
+            let tls_config = tls_config.clone();
+            fn_service(move |stream: TcpStream| {
+                handle_ldap_stream(
+                    stream,
+                    backend_handler.clone(),
+                    ListenerTls::Implicit(tls_config.clone()),
+                    state.clone(),
+                )
             })
             .map_err(|err: anyhow::Error| error!("Service Error: {:#}", err))
             .and_then(move |_| {
@@ -98,5 +1643,460 @@ where
                 ok(())
             })
         })
-        .with_context(|| format!("while binding to the port {}", config.ldap_port))
+        .with_context(|| format!("while listening on the port {}", config.ldaps_port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_catch_panic() {
+        // Another connection, served while the first one panics.
+        let (sender, mut receiver) = mpsc::channel(1);
+        let other = tokio::spawn(async move {
+            let mut served = 0;
+            while receiver.recv().await.is_some() {
+                served += 1;
+            }
+            served
+        });
+        sender.send(()).await.unwrap();
+        assert_eq!(
+            catch_panic(async { panic!("boom") }).await,
+            Err::<(), _>("boom".to_string())
+        );
+        assert_eq!(
+            catch_panic(async { panic!("{} failed", "search") }).await,
+            Err::<(), _>("search failed".to_string())
+        );
+        assert_eq!(catch_panic(async { 42 }).await, Ok(42));
+        sender.send(()).await.unwrap();
+        drop(sender);
+        assert_eq!(other.await.unwrap(), 2);
+    }
+
+    #[test]
+    fn test_get_cipher_suites() {
+        assert_eq!(
+            get_cipher_suites(None).unwrap().len(),
+            DEFAULT_CIPHER_SUITES.len()
+        );
+        assert!(get_cipher_suites(Some(&[])).is_err());
+        let suites = get_cipher_suites(Some(&[
+            "TLS13_AES_256_GCM_SHA384".to_string(),
+            "tls_ecdhe_rsa_with_aes_256_gcm_sha384".to_string(),
+        ]))
+        .unwrap();
+        assert_eq!(
+            suites
+                .iter()
+                .map(|suite| format!("{:?}", suite.suite()))
+                .collect::<Vec<_>>(),
+            vec![
+                "TLS13_AES_256_GCM_SHA384",
+                "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384"
+            ]
+        );
+        let error = get_cipher_suites(Some(&["TLS_RSA_WITH_RC4_128_SHA".to_string()]))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("TLS_RSA_WITH_RC4_128_SHA"), "{}", error);
+    }
+
+    #[test]
+    fn test_check_ldap_settings_listeners() {
+        use crate::infra::configuration::ConfigurationBuilder;
+        let config = ConfigurationBuilder::default().build().unwrap();
+        assert!(check_ldap_settings(&config).is_ok());
+        let config = ConfigurationBuilder::default()
+            .ldap_port(0)
+            .build()
+            .unwrap();
+        assert!(check_ldap_settings(&config).is_err());
+        // LDAPS only.
+        let config = ConfigurationBuilder::default()
+            .ldap_port(0)
+            .ldaps_cert_file(Some("cert.pem".to_string()))
+            .ldaps_key_file(Some("key.pem".to_string()))
+            .build()
+            .unwrap();
+        assert!(check_ldap_settings(&config).is_ok());
+        // Unix socket only.
+        let config = ConfigurationBuilder::default()
+            .ldap_port(0)
+            .ldap_unix_socket_path(Some("/run/lldap/ldap.sock".to_string()))
+            .build()
+            .unwrap();
+        assert!(check_ldap_settings(&config).is_ok());
+        let config = ConfigurationBuilder::default()
+            .ldap_unix_socket_permissions("rw-rw----".to_string())
+            .build()
+            .unwrap();
+        assert!(check_ldap_settings(&config).is_err());
+        let config = ConfigurationBuilder::default()
+            .ldap_tcp_backlog(0)
+            .build()
+            .unwrap();
+        assert!(check_ldap_settings(&config).is_err());
+        let config = ConfigurationBuilder::default()
+            .ldap_proxy_protocol(true)
+            .build()
+            .unwrap();
+        assert!(check_ldap_settings(&config).is_err());
+        let config = ConfigurationBuilder::default()
+            .ldap_proxy_protocol(true)
+            .ldap_proxy_protocol_trusted_cidrs(vec!["10.0.0.5/32".parse().unwrap()])
+            .build()
+            .unwrap();
+        assert!(check_ldap_settings(&config).is_ok());
+        let config = ConfigurationBuilder::default()
+            .ldap_disabled_operations(vec!["Add".to_string(), "modifydn".to_string()])
+            .build()
+            .unwrap();
+        assert!(check_ldap_settings(&config).is_ok());
+        let config = ConfigurationBuilder::default()
+            .ldap_disabled_operations(vec!["unbind".to_string()])
+            .build()
+            .unwrap();
+        assert!(check_ldap_settings(&config).is_err());
+        let config = ConfigurationBuilder::default()
+            .database_replica_url(Some("sqlite:///replica/users.db?mode=ro".to_string()))
+            .build()
+            .unwrap();
+        assert!(check_ldap_settings(&config).is_ok());
+        let config = ConfigurationBuilder::default()
+            .database_replica_url(Some("postgres://replica/lldap".to_string()))
+            .build()
+            .unwrap();
+        assert!(check_ldap_settings(&config).is_err());
+        let config = ConfigurationBuilder::default()
+            .database_replica_url(Some("sqlite:///replica/users.db?mode=ro".to_string()))
+            .ldap_search_cache_ttl_seconds(Some(10))
+            .build()
+            .unwrap();
+        assert!(check_ldap_settings(&config).is_err());
+    }
+
+    #[test]
+    fn test_bind_listener() {
+        let listener = bind_listener("127.0.0.1", 0, 16).unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(std::net::TcpStream::connect(addr).is_ok());
+        assert!(bind_listener("localhost", 0, 16).is_err());
+    }
+
+    #[test]
+    fn test_get_unix_socket_mode() {
+        assert_eq!(get_unix_socket_mode("660").unwrap(), 0o660);
+        assert_eq!(get_unix_socket_mode("0600").unwrap(), 0o600);
+        assert!(get_unix_socket_mode("680").is_err());
+        assert!(get_unix_socket_mode("1777").is_err());
+    }
+
+    #[test]
+    fn test_bind_unix_listener() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("lldap-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("ldap.sock");
+        let path = path.to_str().unwrap();
+        let listener = bind_unix_listener(path, 0o600).unwrap();
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(std::os::unix::net::UnixStream::connect(path).is_ok());
+        // The socket of a previous instance is replaced.
+        drop(listener);
+        let _listener = bind_unix_listener(path, 0o660).unwrap();
+        assert!(std::os::unix::net::UnixStream::connect(path).is_ok());
+        // Other files are kept.
+        let other = dir.join("other");
+        std::fs::write(&other, "data").unwrap();
+        assert!(bind_unix_listener(other.to_str().unwrap(), 0o660).is_err());
+        assert_eq!(std::fs::read_to_string(&other).unwrap(), "data");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_cn_template() {
+        use crate::infra::configuration::ConfigurationBuilder;
+        let config = ConfigurationBuilder::default()
+            .ldap_cn_template(Some("%first %last".to_string()))
+            .build()
+            .unwrap();
+        assert!(check_ldap_settings(&config).is_ok());
+        let config = ConfigurationBuilder::default()
+            .ldap_cn_template(Some("{first} {last}".to_string()))
+            .build()
+            .unwrap();
+        assert!(check_ldap_settings(&config).is_err());
+    }
+
+    #[test]
+    fn test_check_bind_dn_template() {
+        use crate::infra::configuration::ConfigurationBuilder;
+        let config = ConfigurationBuilder::default()
+            .ldap_bind_dn_template(Some("uid=%u,ou=people,dc=example,dc=com".to_string()))
+            .build()
+            .unwrap();
+        assert!(check_ldap_settings(&config).is_ok());
+        let config = ConfigurationBuilder::default()
+            .ldap_bind_dn_template(Some("uid=bob,ou=people,dc=example,dc=com".to_string()))
+            .build()
+            .unwrap();
+        assert!(check_ldap_settings(&config).is_err());
+    }
+
+    #[test]
+    fn test_check_admin_group_dn() {
+        use crate::infra::configuration::ConfigurationBuilder;
+        let config = ConfigurationBuilder::default()
+            .ldap_admin_group_dn(Some(
+                "cn=Domain Admins,ou=groups,dc=example,dc=com".to_string(),
+            ))
+            .build()
+            .unwrap();
+        assert_eq!(
+            get_admin_group(&config, "cn=Domain Admins,ou=groups,dc=example,dc=com").unwrap(),
+            "Domain Admins"
+        );
+        assert!(check_ldap_settings(&config).is_ok());
+        let config = ConfigurationBuilder::default()
+            .ldap_admin_group_dn(Some("cn=admins,ou=people,dc=example,dc=com".to_string()))
+            .build()
+            .unwrap();
+        assert!(check_ldap_settings(&config).is_err());
+    }
+
+    #[test]
+    fn test_check_people_and_groups_ou() {
+        use crate::infra::configuration::ConfigurationBuilder;
+        let config = ConfigurationBuilder::default()
+            .ldap_people_ou("cn=Users".to_string())
+            .build()
+            .unwrap();
+        assert!(check_ldap_settings(&config).is_ok());
+        for (people, groups) in [("ou=users,ou=people", "ou=groups"), ("ou=x", "OU=X")] {
+            let config = ConfigurationBuilder::default()
+                .ldap_people_ou(people.to_string())
+                .ldap_groups_ou(groups.to_string())
+                .build()
+                .unwrap();
+            assert!(check_ldap_settings(&config).is_err());
+        }
+    }
+
+    #[test]
+    fn test_require_client_cert_without_ca() {
+        use crate::infra::configuration::ConfigurationBuilder;
+        let config = ConfigurationBuilder::default()
+            .ldaps_cert_file(Some("cert.pem".to_string()))
+            .ldaps_key_file(Some("key.pem".to_string()))
+            .ldaps_require_client_cert(true)
+            .build()
+            .unwrap();
+        assert_eq!(
+            get_tls_config(&config).unwrap_err().to_string(),
+            "ldaps_require_client_cert needs ldaps_client_ca_file to be set"
+        );
+    }
+
+    #[test]
+    fn test_check_trusted_proxy() {
+        let trusted: Vec<IpNet> = vec!["10.0.0.5/32".parse().unwrap()];
+        let addr = |s: &str| Some(s.parse::<SocketAddr>().unwrap());
+        assert!(check_trusted_proxy(addr("10.0.0.5:40000"), &trusted).is_ok());
+        assert!(check_trusted_proxy(addr("[::ffff:10.0.0.5]:40000"), &trusted).is_ok());
+        assert!(check_trusted_proxy(addr("10.0.0.6:40000"), &trusted).is_err());
+        assert!(check_trusted_proxy(None, &trusted).is_err());
+        assert!(check_trusted_proxy(addr("10.0.0.5:40000"), &[]).is_err());
+    }
+
+    #[test]
+    fn test_check_peer_address() {
+        let allowed: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()];
+        let addr = |s: &str| Some(s.parse::<SocketAddr>().unwrap());
+        // An IPv4 client on a dual-stack listener.
+        assert_eq!(
+            check_peer_address(addr("[::ffff:10.1.2.3]:389"), &allowed).unwrap(),
+            addr("10.1.2.3:389")
+        );
+        assert!(check_peer_address(addr("[::ffff:192.168.1.1]:389"), &allowed).is_err());
+        assert_eq!(
+            check_peer_address(addr("10.1.2.3:389"), &allowed).unwrap(),
+            addr("10.1.2.3:389")
+        );
+        // A genuine IPv6 client.
+        assert_eq!(
+            check_peer_address(addr("[fd12::1]:389"), &allowed).unwrap(),
+            addr("[fd12::1]:389")
+        );
+        assert!(check_peer_address(addr("[2001:db8::1]:389"), &allowed).is_err());
+        // Not an IPv4-mapped address.
+        assert!(check_peer_address(addr("[::10.1.2.3]:389"), &allowed).is_err());
+        assert!(check_peer_address(None, &allowed).is_err());
+        assert_eq!(
+            check_peer_address(addr("[::ffff:192.168.1.1]:389"), &[]).unwrap(),
+            addr("192.168.1.1:389")
+        );
+    }
+
+    #[test]
+    fn test_sni_certificate_resolver() {
+        use tokio_rustls::rustls::{
+            sign::{Signer, SigningKey},
+            SignatureAlgorithm,
+        };
+        struct NoKey;
+        impl SigningKey for NoKey {
+            fn choose_scheme(&self, _: &[SignatureScheme]) -> Option<Box<dyn Signer>> {
+                None
+            }
+            fn algorithm(&self) -> SignatureAlgorithm {
+                SignatureAlgorithm::ECDSA
+            }
+        }
+        let make_key = |certificate: u8| {
+            Arc::new(CertifiedKey::new(
+                vec![Certificate(vec![certificate])],
+                Arc::new(NoKey),
+            ))
+        };
+        let mut certificates = HashMap::new();
+        certificates.insert("ldap.example.com".to_string(), make_key(1));
+        let resolver = SniCertificateResolver {
+            certificates,
+            default: make_key(0),
+        };
+        let selected = |server_name| resolver.select(server_name).cert[0].0[0];
+        assert_eq!(selected(Some("ldap.example.com")), 1);
+        assert_eq!(selected(Some("LDAP.example.com.")), 1);
+        assert_eq!(selected(Some("other.example.com")), 0);
+        assert_eq!(selected(None), 0);
+    }
+
+    #[test]
+    fn test_sni_certificates_settings() {
+        use crate::infra::configuration::{ConfigurationBuilder, SniCertificate};
+        let sni_certificate = |hostname: &str| SniCertificate {
+            hostname: hostname.to_string(),
+            cert_file: "cert.pem".to_string(),
+            key_file: "key.pem".to_string(),
+        };
+        let config = ConfigurationBuilder::default()
+            .ldaps_sni_certificates(vec![sni_certificate("ldap.example.com")])
+            .build()
+            .unwrap();
+        assert!(get_tls_config(&config)
+            .unwrap_err()
+            .to_string()
+            .starts_with("ldaps_sni_certificates needs ldaps_cert_file and ldaps_key_file"));
+        let config = ConfigurationBuilder::default()
+            .ldaps_cert_file(Some("cert.pem".to_string()))
+            .ldaps_key_file(Some("key.pem".to_string()))
+            .ldaps_sni_certificates(vec![
+                sni_certificate("ldap.example.com"),
+                sni_certificate("LDAP.example.com"),
+            ])
+            .build()
+            .unwrap();
+        assert_eq!(
+            get_tls_config(&config).unwrap_err().to_string(),
+            "Duplicate hostname `LDAP.example.com` in ldaps_sni_certificates"
+        );
+    }
+
+    #[test]
+    fn test_rotating_ticketer() {
+        let ticketer = RotatingTicketer::new(Duration::from_secs(60)).unwrap();
+        let start = Instant::now();
+        let ticket = ticketer.encrypt_at(b"session", start).unwrap();
+        assert_eq!(
+            ticketer.decrypt_at(&ticket, start + Duration::from_secs(30)),
+            Some(b"session".to_vec())
+        );
+        // Still accepted with the previous key, after one rotation.
+        assert_eq!(
+            ticketer.decrypt_at(&ticket, start + Duration::from_secs(90)),
+            Some(b"session".to_vec())
+        );
+        let newer = ticketer
+            .encrypt_at(b"newer", start + Duration::from_secs(90))
+            .unwrap();
+        assert_eq!(
+            ticketer.decrypt_at(&ticket, start + Duration::from_secs(150)),
+            None
+        );
+        assert_eq!(
+            ticketer.decrypt_at(&newer, start + Duration::from_secs(150)),
+            Some(b"newer".to_vec())
+        );
+        // After a long pause, the previous key is dropped too.
+        assert_eq!(
+            ticketer.decrypt_at(&newer, start + Duration::from_secs(1000)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_session_resumption_settings() {
+        use crate::infra::configuration::ConfigurationBuilder;
+        let config = ConfigurationBuilder::default()
+            .ldaps_session_ticket_lifetime_seconds(600)
+            .build()
+            .unwrap();
+        let (session_storage, ticketer) = get_session_resumption(&config).unwrap();
+        assert!(session_storage.can_cache());
+        assert!(ticketer.enabled());
+        assert_eq!(ticketer.lifetime(), 600);
+        let config = ConfigurationBuilder::default()
+            .ldaps_session_resumption(false)
+            .build()
+            .unwrap();
+        let (session_storage, ticketer) = get_session_resumption(&config).unwrap();
+        assert!(!session_storage.can_cache());
+        assert!(!ticketer.enabled());
+        let config = ConfigurationBuilder::default()
+            .ldaps_session_ticket_lifetime_seconds(0)
+            .build()
+            .unwrap();
+        assert!(get_session_resumption(&config).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_notice_of_disconnection_on_shutdown() {
+        use crate::domain::handler::MockTestBackendHandler;
+        use tokio::io::AsyncReadExt;
+        let (server, mut client) = tokio::io::duplex(1024);
+        let mut session = LdapHandler::new(
+            MockTestBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+            LdapHandlerOptions::default(),
+            None,
+        );
+        let (_sender, receiver) = watch::channel(true);
+        let mut shutdown = ShutdownSignal {
+            receiver,
+            message: "Going away".into(),
+        };
+        let (stream, action) = handle_ldap_messages(
+            server,
+            LdapFrameCodec::new(1024),
+            &mut session,
+            None,
+            None,
+            None,
+            &mut shutdown,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(action, ConnectionAction::Close));
+        drop(stream);
+        let mut notice = vec![];
+        client.read_to_end(&mut notice).await.unwrap();
+        let contains = |needle: &[u8]| notice.windows(needle.len()).any(|w| w == needle);
+        assert!(contains(NOTICE_OF_DISCONNECTION_OID.as_bytes()));
+        assert!(contains(b"Going away"));
+    }
 }
@@ -1,18 +1,83 @@
-use crate::infra::configuration::Configuration;
-use tracing_subscriber::prelude::*;
+use crate::infra::configuration::{Configuration, LogFormat};
+use anyhow::Context;
+use opentelemetry::{sdk::trace::Tracer, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::{filter::LevelFilter, prelude::*, registry::LookupSpan};
 
+/// Target of the LDAP access log entries, only emitted in JSON mode.
+pub const ACCESS_LOG_TARGET: &str = "lldap::access";
+
+/// Sets up the logs, and the export of the traces if enabled. The exporter needs a Tokio
+/// runtime: with `tracing_enabled`, this must be called from within one.
 pub fn init(config: &Configuration) -> anyhow::Result<()> {
     let max_log_level = log_level_from_config(config);
     let sqlx_max_log_level = sqlx_log_level_from_config(config);
     let filter = tracing_subscriber::filter::Targets::new()
         .with_target("lldap", max_log_level)
         .with_target("sqlx", sqlx_max_log_level);
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer().with_filter(filter))
-        .init();
+    // The spans of the backend calls are at the debug level, so they are exported even without
+    // the verbose logs.
+    let tracing_filter = tracing_subscriber::filter::Targets::new()
+        .with_target("lldap", tracing::Level::DEBUG)
+        .with_target(ACCESS_LOG_TARGET, LevelFilter::OFF);
+    match config.log_format {
+        LogFormat::Text => tracing_subscriber::registry()
+            .with(otlp_layer(config)?.with_filter(tracing_filter))
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .with_filter(filter.with_target(ACCESS_LOG_TARGET, LevelFilter::OFF)),
+            )
+            .init(),
+        LogFormat::Json => tracing_subscriber::registry()
+            .with(otlp_layer(config)?.with_filter(tracing_filter))
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_filter(filter.with_target(ACCESS_LOG_TARGET, LevelFilter::INFO)),
+            )
+            .init(),
+    }
     Ok(())
 }
 
+/// Exports the spans to the OTLP endpoint, if the tracing is enabled.
+fn otlp_layer<S>(config: &Configuration) -> anyhow::Result<Option<OpenTelemetryLayer<S, Tracer>>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    if !config.tracing_enabled {
+        return Ok(None);
+    }
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(&config.otlp_endpoint),
+        )
+        .with_trace_config(opentelemetry::sdk::trace::config().with_resource(
+            opentelemetry::sdk::Resource::new(vec![KeyValue::new("service.name", "lldap")]),
+        ))
+        .install_batch(opentelemetry::runtime::TokioCurrentThread)
+        .with_context(|| {
+            format!(
+                "while setting up the trace export to `{}`",
+                config.otlp_endpoint
+            )
+        })?;
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+/// Sends the remaining spans before exiting, if the tracing is enabled.
+pub async fn shutdown(config: &Configuration) {
+    if config.tracing_enabled {
+        // Blocks until the spans are exported, which needs the runtime to keep running.
+        let _ = tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider).await;
+    }
+}
+
 fn log_level_from_config(config: &Configuration) -> tracing::Level {
     if config.verbose {
         tracing::Level::DEBUG
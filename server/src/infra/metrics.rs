@@ -0,0 +1,192 @@
+//! Prometheus metrics of the LDAP server, served on a separate HTTP port.
+use crate::infra::configuration::Configuration;
+use actix_http::HttpServiceBuilder;
+use actix_server::ServerBuilder;
+use actix_service::map_config;
+use actix_web::{dev::AppConfig, web, App, HttpResponse};
+use anyhow::{Context, Result};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::{sync::Arc, time::Duration};
+
+pub struct LdapMetrics {
+    registry: Registry,
+    operations: IntCounterVec,
+    operation_duration: HistogramVec,
+    active_connections: IntGauge,
+    connection_failures: IntCounterVec,
+    bind_failures: IntCounter,
+    group_cache_lookups: IntCounterVec,
+    search_cache_lookups: IntCounterVec,
+}
+
+/// Counts a connection as active until dropped.
+pub struct ConnectionGuard(Arc<LdapMetrics>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.active_connections.dec();
+    }
+}
+
+impl LdapMetrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new();
+        let operations = IntCounterVec::new(
+            Opts::new("lldap_ldap_operations_total", "Number of LDAP operations"),
+            &["op_type"],
+        )?;
+        let operation_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "lldap_ldap_operation_duration_seconds",
+                "Time to handle an LDAP operation",
+            ),
+            &["op_type", "result_code"],
+        )?;
+        let active_connections = IntGauge::new(
+            "lldap_ldap_active_connections",
+            "Number of open LDAP connections",
+        )?;
+        let connection_failures = IntCounterVec::new(
+            Opts::new(
+                "lldap_ldap_connection_failures_total",
+                "Number of LDAP connections closed before any operation",
+            ),
+            &["reason"],
+        )?;
+        let bind_failures =
+            IntCounter::new("lldap_ldap_bind_failures_total", "Number of failed binds")?;
+        let group_cache_lookups = IntCounterVec::new(
+            Opts::new(
+                "lldap_group_cache_lookups_total",
+                "Number of group queries looked up in the cache",
+            ),
+            &["result"],
+        )?;
+        let search_cache_lookups = IntCounterVec::new(
+            Opts::new(
+                "lldap_search_cache_lookups_total",
+                "Number of LDAP searches looked up in the cache",
+            ),
+            &["result"],
+        )?;
+        registry.register(Box::new(operations.clone()))?;
+        registry.register(Box::new(operation_duration.clone()))?;
+        registry.register(Box::new(active_connections.clone()))?;
+        registry.register(Box::new(connection_failures.clone()))?;
+        registry.register(Box::new(bind_failures.clone()))?;
+        registry.register(Box::new(group_cache_lookups.clone()))?;
+        registry.register(Box::new(search_cache_lookups.clone()))?;
+        Ok(Self {
+            registry,
+            operations,
+            operation_duration,
+            active_connections,
+            connection_failures,
+            bind_failures,
+            group_cache_lookups,
+            search_cache_lookups,
+        })
+    }
+
+    pub fn record_operation(&self, op_type: &str, result_code: &str, duration: Duration) {
+        self.operations.with_label_values(&[op_type]).inc();
+        self.operation_duration
+            .with_label_values(&[op_type, result_code])
+            .observe(duration.as_secs_f64());
+        if op_type == "bind" && result_code != "Success" {
+            self.bind_failures.inc();
+        }
+    }
+
+    /// A connection that could not be established, e.g. because of the TLS handshake.
+    pub fn record_connection_failure(&self, reason: &str) {
+        self.connection_failures.with_label_values(&[reason]).inc();
+    }
+
+    pub fn record_group_cache_lookup(&self, hit: bool) {
+        self.group_cache_lookups
+            .with_label_values(&[if hit { "hit" } else { "miss" }])
+            .inc();
+    }
+
+    pub fn record_search_cache_lookup(&self, hit: bool) {
+        self.search_cache_lookups
+            .with_label_values(&[if hit { "hit" } else { "miss" }])
+            .inc();
+    }
+
+    pub fn connection_opened(self: &Arc<Self>) -> ConnectionGuard {
+        self.active_connections.inc();
+        ConnectionGuard(self.clone())
+    }
+
+    /// The metrics, in the Prometheus text format.
+    pub fn encode(&self) -> Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+async fn serve_metrics(metrics: web::Data<Arc<LdapMetrics>>) -> HttpResponse {
+    match metrics.encode() {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(TextEncoder::new().format_type())
+            .body(body),
+        Err(e) => HttpResponse::InternalServerError().body(format!("{:#}", e)),
+    }
+}
+
+/// Serves the metrics on `/metrics`, if `metrics_port` is set.
+pub fn build_metrics_server(
+    config: &Configuration,
+    metrics: Option<Arc<LdapMetrics>>,
+    server_builder: ServerBuilder,
+) -> Result<ServerBuilder> {
+    let (port, metrics) = match (config.metrics_port, metrics) {
+        (Some(port), Some(metrics)) => (port, metrics),
+        _ => return Ok(server_builder),
+    };
+    server_builder
+        .bind("metrics", ("0.0.0.0", port), move || {
+            let metrics = metrics.clone();
+            HttpServiceBuilder::new()
+                .finish(map_config(
+                    App::new()
+                        .app_data(web::Data::new(metrics))
+                        .route("/metrics", web::get().to(serve_metrics)),
+                    |_| AppConfig::default(),
+                ))
+                .tcp()
+        })
+        .with_context(|| format!("while binding the metrics server to the port {}", port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Arc::new(LdapMetrics::new().unwrap());
+        let connection = metrics.connection_opened();
+        metrics.record_operation("bind", "InvalidCredentials", Duration::from_millis(5));
+        metrics.record_operation("search", "Success", Duration::from_millis(2));
+        metrics.record_connection_failure("tls_handshake");
+        let encoded = metrics.encode().unwrap();
+        assert!(encoded.contains("lldap_ldap_active_connections 1"));
+        assert!(encoded.contains("lldap_ldap_bind_failures_total 1"));
+        assert!(encoded.contains(r#"lldap_ldap_operations_total{op_type="search"} 1"#));
+        assert!(
+            encoded.contains(r#"lldap_ldap_connection_failures_total{reason="tls_handshake"} 1"#)
+        );
+        drop(connection);
+        assert!(metrics
+            .encode()
+            .unwrap()
+            .contains("lldap_ldap_active_connections 0"));
+    }
+}
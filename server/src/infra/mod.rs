@@ -1,13 +1,24 @@
 pub mod auth_service;
+pub mod ber;
 pub mod cli;
+pub mod client_certificate;
 pub mod configuration;
+pub mod connection_limiter;
 pub mod db_cleaner;
 pub mod graphql;
+pub mod group_cache;
 pub mod jwt_sql_tables;
+pub mod ldap_codec;
 pub mod ldap_handler;
+pub mod ldap_schema;
 pub mod ldap_server;
 pub mod logging;
 pub mod mail;
+pub mod metrics;
+pub mod operation_log;
+pub mod proxy_protocol;
+pub mod rate_limiter;
+pub mod search_cache;
 pub mod sql_backend_handler;
 pub mod tcp_backend_handler;
 pub mod tcp_server;
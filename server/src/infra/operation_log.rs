@@ -0,0 +1,165 @@
+//! The last LDAP operations, kept in memory to see what a client actually sends without turning
+//! on the debug logs for all the connections.
+use ldap3_server::proto::LdapFilter;
+use serde::Serialize;
+use std::{collections::VecDeque, sync::Mutex};
+
+/// What is kept of an operation: never the credentials or the values being written.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct OperationRecord {
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub op_type: String,
+    pub bind_dn: String,
+    pub peer_ip: String,
+    /// The filter of the searches, in the string form of RFC 4515.
+    pub filter: Option<String>,
+    pub result_code: String,
+    pub duration_ms: f64,
+}
+
+/// A ring buffer of the last `capacity` operations, shared by all the connections.
+#[derive(Debug)]
+pub struct OperationLog {
+    capacity: usize,
+    records: Mutex<VecDeque<OperationRecord>>,
+}
+
+impl OperationLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            records: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Adds the operation, dropping the oldest one if the log is full.
+    pub fn record(&self, record: OperationRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut records = self.records.lock().unwrap();
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+
+    /// The last `limit` operations (all of them if None), the oldest first.
+    pub fn recent(&self, limit: Option<usize>) -> Vec<OperationRecord> {
+        let records = self.records.lock().unwrap();
+        let skip = limit.map_or(0, |limit| records.len().saturating_sub(limit));
+        records.iter().skip(skip).cloned().collect()
+    }
+}
+
+fn escape_filter_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '*' | '(' | ')' | '\\' | '\0' => escaped.push_str(&format!("\\{:02x}", c as u32)),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The string form of the filter (RFC 4515), e.g. "(&(objectClass=person)(uid=bob))".
+pub fn filter_to_string(filter: &LdapFilter) -> String {
+    match filter {
+        LdapFilter::And(filters) => format!(
+            "(&{})",
+            filters.iter().map(filter_to_string).collect::<String>()
+        ),
+        LdapFilter::Or(filters) => format!(
+            "(|{})",
+            filters.iter().map(filter_to_string).collect::<String>()
+        ),
+        LdapFilter::Not(filter) => format!("(!{})", filter_to_string(filter)),
+        LdapFilter::Equality(attribute, value) => {
+            format!("({}={})", attribute, escape_filter_value(value))
+        }
+        LdapFilter::Present(attribute) => format!("({}=*)", attribute),
+        LdapFilter::Substring(attribute, substring) => format!(
+            "({}={}*{}{})",
+            attribute,
+            substring
+                .initial
+                .as_deref()
+                .map(escape_filter_value)
+                .unwrap_or_default(),
+            substring
+                .any
+                .iter()
+                .map(|any| format!("{}*", escape_filter_value(any)))
+                .collect::<String>(),
+            substring
+                .final_
+                .as_deref()
+                .map(escape_filter_value)
+                .unwrap_or_default()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ldap3_server::proto::LdapSubstringFilter;
+
+    fn make_record(op_type: &str) -> OperationRecord {
+        OperationRecord {
+            time: chrono::Utc::now(),
+            op_type: op_type.to_string(),
+            bind_dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            peer_ip: "127.0.0.1".to_string(),
+            filter: None,
+            result_code: "Success".to_string(),
+            duration_ms: 1.0,
+        }
+    }
+
+    fn op_types(records: &[OperationRecord]) -> Vec<&str> {
+        records.iter().map(|r| r.op_type.as_str()).collect()
+    }
+
+    #[test]
+    fn test_oldest_operations_are_dropped() {
+        let log = OperationLog::new(2);
+        log.record(make_record("bind"));
+        log.record(make_record("search"));
+        log.record(make_record("unbind"));
+        assert_eq!(op_types(&log.recent(None)), vec!["search", "unbind"]);
+        assert_eq!(op_types(&log.recent(Some(1))), vec!["unbind"]);
+        assert_eq!(op_types(&log.recent(Some(5))), vec!["search", "unbind"]);
+    }
+
+    #[test]
+    fn test_empty_log() {
+        let log = OperationLog::new(0);
+        log.record(make_record("bind"));
+        assert!(log.recent(None).is_empty());
+    }
+
+    #[test]
+    fn test_filter_to_string() {
+        let filter = LdapFilter::And(vec![
+            LdapFilter::Equality("objectClass".to_string(), "person".to_string()),
+            LdapFilter::Not(Box::new(LdapFilter::Present("mail".to_string()))),
+            LdapFilter::Or(vec![
+                LdapFilter::Substring(
+                    "cn".to_string(),
+                    LdapSubstringFilter {
+                        initial: Some("a".to_string()),
+                        any: vec!["b".to_string()],
+                        final_: None,
+                    },
+                ),
+                LdapFilter::Equality("description".to_string(), "(x*)".to_string()),
+            ]),
+        ]);
+        assert_eq!(
+            filter_to_string(&filter),
+            "(&(objectClass=person)(!(mail=*))(|(cn=a*b*)(description=\\28x\\2a\\29)))"
+        );
+    }
+}
@@ -0,0 +1,162 @@
+//! Parsing of the PROXY protocol header (versions 1 and 2) that the load balancers send at the
+//! start of the connections, with the address of the actual client.
+//!
+//! See https://www.haproxy.org/download/2.6/doc/proxy-protocol.txt
+use anyhow::{bail, Context, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// The longest version 1 header, including the CRLF.
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_COMMAND_LOCAL: u8 = 0x20;
+const V2_COMMAND_PROXY: u8 = 0x21;
+const V2_FAMILY_TCP4: u8 = 0x11;
+const V2_FAMILY_TCP6: u8 = 0x21;
+
+/// Reads the header from the start of the stream, leaving the rest of it untouched.
+///
+/// Returns the source address, or None if the proxy didn't give any (e.g. for its own health
+/// checks). Fails if the stream doesn't start with a valid header.
+pub async fn read_proxy_header<Stream>(stream: &mut Stream) -> Result<Option<SocketAddr>>
+where
+    Stream: AsyncRead + Unpin,
+{
+    // Long enough to tell the versions apart, but shorter than the shortest header.
+    let mut header = vec![0; 8];
+    stream
+        .read_exact(&mut header)
+        .await
+        .context("while reading the PROXY protocol header")?;
+    if header.starts_with(V1_PREFIX) {
+        // The header is read byte by byte, so as not to consume the LDAP message after it.
+        while !header.ends_with(b"\r\n") {
+            if header.len() >= V1_MAX_LENGTH {
+                bail!("PROXY protocol header too long");
+            }
+            header.push(stream.read_u8().await?);
+        }
+        parse_v1_header(&header)
+    } else if V2_SIGNATURE.starts_with(&header) {
+        header.resize(16, 0);
+        stream.read_exact(&mut header[8..]).await?;
+        if !header.starts_with(V2_SIGNATURE) {
+            bail!("Invalid PROXY protocol signature");
+        }
+        let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+        let mut addresses = vec![0; length];
+        stream.read_exact(&mut addresses).await?;
+        parse_v2_header(header[12], header[13], &addresses)
+    } else {
+        bail!("Missing PROXY protocol header")
+    }
+}
+
+/// Parses e.g. "PROXY TCP4 192.168.0.1 192.168.0.11 56324 389\r\n".
+fn parse_v1_header(header: &[u8]) -> Result<Option<SocketAddr>> {
+    let header = std::str::from_utf8(header)
+        .context("Invalid PROXY protocol header")?
+        .trim_end_matches("\r\n");
+    let fields = header.split(' ').collect::<Vec<_>>();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), source, _destination, source_port, _destination_port] =>
+        {
+            let ip = source.parse::<IpAddr>().with_context(|| {
+                format!(
+                    "Invalid source address in PROXY protocol header: {}",
+                    source
+                )
+            })?;
+            if ip.is_ipv4() != (*protocol == "TCP4") {
+                bail!("Source address {} is not {}", ip, protocol);
+            }
+            let port = source_port.parse::<u16>().with_context(|| {
+                format!(
+                    "Invalid source port in PROXY protocol header: {}",
+                    source_port
+                )
+            })?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => bail!("Invalid PROXY protocol header: {:?}", header),
+    }
+}
+
+fn parse_v2_header(command: u8, family: u8, addresses: &[u8]) -> Result<Option<SocketAddr>> {
+    match command {
+        V2_COMMAND_LOCAL => return Ok(None),
+        V2_COMMAND_PROXY => (),
+        _ => bail!("Unsupported PROXY protocol command: {:#x}", command),
+    }
+    match family {
+        V2_FAMILY_TCP4 if addresses.len() >= 12 => {
+            let mut ip = [0; 4];
+            ip.copy_from_slice(&addresses[..4]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+        }
+        V2_FAMILY_TCP6 if addresses.len() >= 36 => {
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        // Other protocols (UDP, Unix sockets) or unspecified: there is no usable address.
+        0x00 | 0x12 | 0x22 | 0x31 | 0x32 => Ok(None),
+        _ => bail!(
+            "Invalid PROXY protocol addresses: family {:#x}, {} bytes",
+            family,
+            addresses.len()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read_header(data: &[u8]) -> (Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut stream = data;
+        let result = read_proxy_header(&mut stream).await;
+        (result, stream.to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_proxy_header_v1() {
+        let (result, rest) =
+            read_header(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 389\r\n0\x0c").await;
+        assert_eq!(result.unwrap(), Some("192.168.0.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"0\x0c");
+        let (result, _) = read_header(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 636\r\n").await;
+        assert_eq!(result.unwrap(), Some("[2001:db8::1]:4000".parse().unwrap()));
+        let (result, _) = read_header(b"PROXY UNKNOWN\r\n").await;
+        assert_eq!(result.unwrap(), None);
+        let (result, _) = read_header(b"PROXY TCP4 2001:db8::1 2001:db8::2 4000 636\r\n").await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_header_v2() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend([V2_COMMAND_PROXY, V2_FAMILY_TCP6, 0, 36]);
+        data.extend("2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        data.extend("2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        data.extend([0x0f, 0xa0, 0x02, 0x7c]);
+        data.extend(b"0\x0c");
+        let (result, rest) = read_header(&data).await;
+        assert_eq!(result.unwrap(), Some("[2001:db8::1]:4000".parse().unwrap()));
+        assert_eq!(rest, b"0\x0c");
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend([V2_COMMAND_LOCAL, 0, 0, 0]);
+        let (result, _) = read_header(&data).await;
+        assert_eq!(result.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_header_missing() {
+        let (result, _) = read_header(b"0\x0c\x02\x01\x01\x60\x07\x02\x01\x03").await;
+        assert!(result.is_err());
+    }
+}
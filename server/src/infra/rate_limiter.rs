@@ -0,0 +1,207 @@
+//! Per-IP rate limiting of the LDAP binds, to slow down password brute-forcing, and per-user
+//! throttling of the failed binds, to slow down targeted attacks against an account.
+use crate::domain::handler::UserId;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Above this number of tracked addresses, the buckets that are full again are dropped.
+const PRUNE_THRESHOLD: usize = 1024;
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// A token bucket per client address: each bind takes a token, and the bucket refills
+/// continuously up to the number of binds allowed per minute.
+#[derive(Debug)]
+pub struct BindRateLimiter {
+    max_per_minute: u32,
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+
+impl BindRateLimiter {
+    pub fn new(max_per_minute: u32) -> Self {
+        Self {
+            max_per_minute,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn refill(&self, bucket: &mut TokenBucket, now: Instant) {
+        let elapsed = now.saturating_duration_since(bucket.last_refill);
+        let capacity = self.max_per_minute as f64;
+        bucket.tokens = f64::min(
+            capacity,
+            bucket.tokens + elapsed.as_secs_f64() * capacity / 60.0,
+        );
+        bucket.last_refill = now;
+    }
+
+    /// Takes a token for a bind from the address. Returns false if the address is over the
+    /// limit.
+    pub fn try_acquire(&self, ip: IpAddr) -> bool {
+        self.try_acquire_at(ip, Instant::now())
+    }
+
+    fn try_acquire_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > PRUNE_THRESHOLD {
+            let capacity = self.max_per_minute as f64;
+            buckets.retain(|_, bucket| {
+                self.refill(bucket, now);
+                bucket.tokens < capacity
+            });
+        }
+        let bucket = buckets.entry(ip).or_insert_with(|| TokenBucket {
+            tokens: self.max_per_minute as f64,
+            last_refill: now,
+        });
+        self.refill(bucket, now);
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+/// The failures of a user are forgotten after that long without a new failure.
+const FAILURE_EXPIRY: Duration = Duration::from_secs(3600);
+
+#[derive(Debug)]
+struct BindFailures {
+    count: u32,
+    last_failure: Instant,
+}
+
+/// Counts the consecutive failed binds of each user: past a number of failures, each new failure
+/// is answered after a delay that doubles every time, up to a maximum.
+#[derive(Debug)]
+pub struct BindFailureTracker {
+    free_failures: u32,
+    max_delay: Duration,
+    failures: Mutex<HashMap<UserId, BindFailures>>,
+}
+
+impl BindFailureTracker {
+    pub fn new(free_failures: u32, max_delay: Duration) -> Self {
+        Self {
+            free_failures,
+            max_delay,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a failed bind of the user, and returns how long to wait before answering.
+    pub fn record_failure(&self, user_id: &UserId) -> Duration {
+        self.record_failure_at(user_id, Instant::now())
+    }
+
+    fn record_failure_at(&self, user_id: &UserId, now: Instant) -> Duration {
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() > PRUNE_THRESHOLD {
+            failures.retain(|_, f| now.saturating_duration_since(f.last_failure) < FAILURE_EXPIRY);
+        }
+        let entry = failures.entry(user_id.clone()).or_insert(BindFailures {
+            count: 0,
+            last_failure: now,
+        });
+        if now.saturating_duration_since(entry.last_failure) >= FAILURE_EXPIRY {
+            entry.count = 0;
+        }
+        entry.count = entry.count.saturating_add(1);
+        entry.last_failure = now;
+        if entry.count <= self.free_failures {
+            return Duration::ZERO;
+        }
+        // 1s for the first delayed failure, then 2s, 4s...
+        let exponent = (entry.count - self.free_failures - 1).min(31);
+        std::cmp::min(self.max_delay, Duration::from_secs(1 << exponent))
+    }
+
+    /// Whether the failed binds of the user are currently delayed, i.e. the account is
+    /// temporarily locked out.
+    pub fn is_throttled(&self, user_id: &UserId) -> bool {
+        self.is_throttled_at(user_id, Instant::now())
+    }
+
+    fn is_throttled_at(&self, user_id: &UserId, now: Instant) -> bool {
+        matches!(
+            self.failures.lock().unwrap().get(user_id),
+            Some(f) if f.count > self.free_failures
+                && now.saturating_duration_since(f.last_failure) < FAILURE_EXPIRY
+        )
+    }
+
+    /// Forgets the failures of the user, after a successful bind.
+    pub fn reset(&self, user_id: &UserId) {
+        self.failures.lock().unwrap().remove(user_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_bind_rate_limiter() {
+        let limiter = BindRateLimiter::new(2);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other_ip: IpAddr = "10.0.0.2".parse().unwrap();
+        let start = Instant::now();
+        assert!(limiter.try_acquire_at(ip, start));
+        assert!(limiter.try_acquire_at(ip, start));
+        assert!(!limiter.try_acquire_at(ip, start));
+        assert!(limiter.try_acquire_at(other_ip, start));
+        // A token is added every 30 seconds.
+        assert!(!limiter.try_acquire_at(ip, start + Duration::from_secs(20)));
+        assert!(limiter.try_acquire_at(ip, start + Duration::from_secs(31)));
+        assert!(!limiter.try_acquire_at(ip, start + Duration::from_secs(32)));
+    }
+
+    #[test]
+    fn test_bind_failure_tracker() {
+        let tracker = BindFailureTracker::new(2, Duration::from_secs(5));
+        let bob = UserId::new("bob");
+        let john = UserId::new("john");
+        let start = Instant::now();
+        assert_eq!(tracker.record_failure_at(&bob, start), Duration::ZERO);
+        assert_eq!(tracker.record_failure_at(&bob, start), Duration::ZERO);
+        assert!(!tracker.is_throttled_at(&bob, start));
+        assert_eq!(
+            tracker.record_failure_at(&bob, start),
+            Duration::from_secs(1)
+        );
+        assert!(tracker.is_throttled_at(&bob, start));
+        assert!(!tracker.is_throttled_at(&bob, start + FAILURE_EXPIRY));
+        assert_eq!(
+            tracker.record_failure_at(&bob, start),
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            tracker.record_failure_at(&bob, start),
+            Duration::from_secs(4)
+        );
+        // Capped.
+        assert_eq!(
+            tracker.record_failure_at(&bob, start),
+            Duration::from_secs(5)
+        );
+        assert_eq!(tracker.record_failure_at(&john, start), Duration::ZERO);
+        tracker.reset(&bob);
+        assert_eq!(tracker.record_failure_at(&bob, start), Duration::ZERO);
+        assert_eq!(tracker.record_failure_at(&bob, start), Duration::ZERO);
+        // The old failures expire.
+        assert_eq!(
+            tracker.record_failure_at(&bob, start + FAILURE_EXPIRY),
+            Duration::ZERO
+        );
+    }
+}
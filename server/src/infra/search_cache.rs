@@ -0,0 +1,216 @@
+//! Cache of the results of the LDAP searches. The apps repeating the same lookups, e.g. the user
+//! of every login, then only hit the database once per TTL.
+use crate::infra::metrics::LdapMetrics;
+use ldap3_server::proto::{LdapOp, LdapResultCode};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Above this number of cached searches, the least recently used one is evicted.
+const MAX_ENTRIES: usize = 1024;
+/// The searches returning more entries are not cached, to bound the memory of the cache.
+const MAX_CACHED_RESULTS: usize = 1000;
+
+/// What the results of a search depend on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SearchCacheKey {
+    /// The users see different entries and attributes, depending on their rights.
+    pub bound_dn: String,
+    pub base: String,
+    pub scope: String,
+    /// The string form of the filter, see `filter_to_string`.
+    pub filter: String,
+    pub attributes: Vec<String>,
+    pub types_only: bool,
+    pub size_limit: i32,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    results: Vec<LdapOp>,
+    /// The last change of the backend before the search, see `BackendHandler::subscribe_to_changes`.
+    change_id: u64,
+    inserted: Instant,
+    last_used: Instant,
+}
+
+/// The results of the successful searches. The entries expire after the TTL, and as soon as the
+/// backend changes: a user, a group or a membership, from the web UI or from LDAP.
+pub struct SearchCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<SearchCacheKey, CacheEntry>>,
+    metrics: Option<Arc<LdapMetrics>>,
+}
+
+impl std::fmt::Debug for SearchCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("SearchCache")
+            .field("ttl", &self.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SearchCache {
+    pub fn new(ttl: Duration, metrics: Option<Arc<LdapMetrics>>) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+            metrics,
+        }
+    }
+
+    /// The cached results of the search, unless the backend changed since (`change_id` is the
+    /// last change of the backend).
+    pub fn get(&self, key: &SearchCacheKey, change_id: u64) -> Option<Vec<LdapOp>> {
+        let results = self.get_at(key, change_id, Instant::now());
+        if let Some(metrics) = &self.metrics {
+            metrics.record_search_cache_lookup(results.is_some());
+        }
+        results
+    }
+
+    /// Caches the results of a search that started after the change `change_id`. Only the
+    /// successful searches are cached.
+    pub fn insert(&self, key: SearchCacheKey, results: &[LdapOp], change_id: u64) {
+        self.insert_at(key, results, change_id, Instant::now())
+    }
+
+    fn get_at(&self, key: &SearchCacheKey, change_id: u64, now: Instant) -> Option<Vec<LdapOp>> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get_mut(key) {
+            Some(entry)
+                if entry.change_id == change_id
+                    && now.saturating_duration_since(entry.inserted) < self.ttl =>
+            {
+                entry.last_used = now;
+                Some(entry.results.clone())
+            }
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert_at(&self, key: SearchCacheKey, results: &[LdapOp], change_id: u64, now: Instant) {
+        let succeeded = matches!(
+            results.last(),
+            Some(LdapOp::SearchResultDone(result)) if result.code == LdapResultCode::Success
+        );
+        if !succeeded || results.len() > MAX_CACHED_RESULTS {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        // The results of the searches that ran before a change are useless.
+        entries.retain(|_, entry| entry.change_id == change_id);
+        if entries.len() >= MAX_ENTRIES {
+            if let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            key,
+            CacheEntry {
+                results: results.to_vec(),
+                change_id,
+                inserted: now,
+                last_used: now,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ldap3_server::proto::{LdapResult, LdapSearchResultEntry};
+
+    fn make_key(filter: &str) -> SearchCacheKey {
+        SearchCacheKey {
+            bound_dn: "uid=admin,ou=people,dc=example,dc=com".to_string(),
+            base: "ou=people,dc=example,dc=com".to_string(),
+            scope: "Subtree".to_string(),
+            filter: filter.to_string(),
+            attributes: vec!["uid".to_string()],
+            types_only: false,
+            size_limit: 0,
+        }
+    }
+
+    fn make_results(code: LdapResultCode) -> Vec<LdapOp> {
+        vec![
+            LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                attributes: vec![],
+            }),
+            LdapOp::SearchResultDone(LdapResult {
+                code,
+                matcheddn: "".to_string(),
+                message: "".to_string(),
+                referral: vec![],
+            }),
+        ]
+    }
+
+    #[test]
+    fn test_search_cache_expiry() {
+        let cache = SearchCache::new(Duration::from_secs(10), None);
+        let start = Instant::now();
+        let key = make_key("(uid=bob)");
+        assert_eq!(cache.get_at(&key, 1, start), None);
+        cache.insert_at(
+            key.clone(),
+            &make_results(LdapResultCode::Success),
+            1,
+            start,
+        );
+        assert_eq!(
+            cache.get_at(&key, 1, start + Duration::from_secs(5)),
+            Some(make_results(LdapResultCode::Success))
+        );
+        assert_eq!(cache.get_at(&make_key("(uid=jim)"), 1, start), None);
+        assert_eq!(
+            cache.get_at(
+                &SearchCacheKey {
+                    bound_dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                    ..key.clone()
+                },
+                1,
+                start
+            ),
+            None
+        );
+        assert_eq!(cache.get_at(&key, 1, start + Duration::from_secs(10)), None);
+    }
+
+    #[test]
+    fn test_search_cache_invalidation() {
+        let cache = SearchCache::new(Duration::from_secs(10), None);
+        let start = Instant::now();
+        let key = make_key("(uid=bob)");
+        cache.insert_at(
+            key.clone(),
+            &make_results(LdapResultCode::Success),
+            1,
+            start,
+        );
+        // The backend changed.
+        assert_eq!(cache.get_at(&key, 2, start), None);
+        assert_eq!(cache.get_at(&key, 1, start), None);
+        // The failed searches are not cached.
+        cache.insert_at(
+            key.clone(),
+            &make_results(LdapResultCode::SizeLimitExceeded),
+            2,
+            start,
+        );
+        assert_eq!(cache.get_at(&key, 2, start), None);
+    }
+}
@@ -36,12 +36,21 @@ mockall::mock! {
     #[async_trait]
     impl BackendHandler for TestTcpBackendHandler {
         async fn list_users(&self, filters: Option<UserRequestFilter>) -> Result<Vec<User>>;
+        async fn list_users_batch(
+            &self,
+            filters: Option<UserRequestFilter>,
+            after: Option<UserId>,
+            limit: usize,
+        ) -> Result<Vec<User>>;
         async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>>;
         async fn get_user_details(&self, user_id: &UserId) -> Result<User>;
         async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName>;
         async fn get_user_groups(&self, user: &UserId) -> Result<HashSet<GroupIdAndName>>;
+        async fn get_password_metadata(&self, user_id: &UserId) -> Result<PasswordMetadata>;
+        async fn get_user_avatar(&self, user_id: &UserId) -> Result<Option<Vec<u8>>>;
         async fn create_user(&self, request: CreateUserRequest) -> Result<()>;
         async fn update_user(&self, request: UpdateUserRequest) -> Result<()>;
+        async fn rename_user(&self, user_id: &UserId, new_user_id: &UserId) -> Result<()>;
         async fn update_group(&self, request: UpdateGroupRequest) -> Result<()>;
         async fn delete_user(&self, user_id: &UserId) -> Result<()>;
         async fn create_group(&self, group_name: &str) -> Result<GroupId>;
@@ -5,16 +5,22 @@
 use crate::{
     domain::{
         handler::{BackendHandler, CreateUserRequest},
+        retrying_backend_handler::{RetryOptions, RetryingBackendHandler},
+        routing_backend_handler::RoutingBackendHandler,
         sql_backend_handler::SqlBackendHandler,
         sql_opaque_handler::register_password,
         sql_tables::PoolOptions,
     },
-    infra::{cli::*, configuration::Configuration, db_cleaner::Scheduler, mail},
+    infra::{
+        cli::*, configuration::Configuration, db_cleaner::Scheduler, group_cache::GroupCache, mail,
+        metrics::LdapMetrics,
+    },
 };
 use actix::Actor;
 use anyhow::{anyhow, Context, Result};
 use futures_util::TryFutureExt;
 use log::*;
+use std::{sync::Arc, time::Duration};
 
 mod domain;
 mod infra;
@@ -54,7 +60,19 @@ async fn run_server(config: Configuration) -> Result<()> {
     domain::sql_tables::init_table(&sql_pool)
         .await
         .context("while creating the tables")?;
-    let backend_handler = SqlBackendHandler::new(config.clone(), sql_pool.clone());
+    let metrics = match config.metrics_port {
+        Some(_) => Some(Arc::new(
+            LdapMetrics::new().context("while setting up the metrics")?,
+        )),
+        None => None,
+    };
+    let group_cache = config
+        .ldap_group_cache_ttl_seconds
+        .map(|ttl| Arc::new(GroupCache::new(Duration::from_secs(ttl), metrics.clone())));
+    let mut backend_handler = SqlBackendHandler::new(config.clone(), sql_pool.clone());
+    if let Some(group_cache) = &group_cache {
+        backend_handler = backend_handler.with_group_cache(group_cache.clone());
+    }
     if let Err(e) = backend_handler.get_user_details(&config.ldap_user_dn).await {
         warn!("Could not get admin user, trying to create it: {:#}", e);
         create_admin_user(&backend_handler, &config)
@@ -62,12 +80,41 @@ async fn run_server(config: Configuration) -> Result<()> {
             .map_err(|e| anyhow!("Error setting up admin login/account: {:#}", e))
             .context("while creating the admin user")?;
     }
-    let server_builder = infra::ldap_server::build_ldap_server(
-        &config,
-        backend_handler.clone(),
-        actix_server::Server::build(),
-    )
+    let retry_options = RetryOptions {
+        retries: config.ldap_backend_retry_attempts,
+        initial_delay: Duration::from_millis(config.ldap_backend_retry_initial_delay_ms),
+        max_delay: Duration::from_millis(config.ldap_backend_retry_max_delay_ms),
+    };
+    let server_builder = match &config.database_replica_url {
+        Some(replica_url) => {
+            let replica_pool = PoolOptions::new()
+                .max_connections(5)
+                .connect(replica_url)
+                .await
+                .context("while connecting to the replica DB")?;
+            let replica_handler = SqlBackendHandler::new(config.clone(), replica_pool);
+            infra::ldap_server::build_ldap_server(
+                &config,
+                RetryingBackendHandler::new(
+                    RoutingBackendHandler::new(replica_handler, backend_handler.clone()),
+                    retry_options,
+                ),
+                metrics.clone(),
+                group_cache,
+                actix_server::Server::build(),
+            )
+        }
+        None => infra::ldap_server::build_ldap_server(
+            &config,
+            RetryingBackendHandler::new(backend_handler.clone(), retry_options),
+            metrics.clone(),
+            group_cache,
+            actix_server::Server::build(),
+        ),
+    }
     .context("while binding the LDAP server")?;
+    let server_builder = infra::metrics::build_metrics_server(&config, metrics, server_builder)
+        .context("while binding the metrics server")?;
     infra::jwt_sql_tables::init_table(&sql_pool).await?;
     let server_builder =
         infra::tcp_server::build_tcp_server(&config, backend_handler, server_builder)
@@ -87,14 +134,28 @@ async fn run_server(config: Configuration) -> Result<()> {
 fn run_server_command(opts: RunOpts) -> Result<()> {
     debug!("CLI: {:#?}", &opts);
 
+    let check_config = opts.check_config;
     let config = infra::configuration::init(opts)?;
-    infra::logging::init(&config)?;
-
-    info!("Starting LLDAP....");
+    if check_config {
+        infra::ldap_server::check_config(&config).context("Invalid configuration")?;
+        println!("The configuration is valid");
+        return Ok(());
+    }
 
-    actix::run(
-        run_server(config).unwrap_or_else(|e| error!("Could not bring up the servers: {:#}", e)),
-    )?;
+    // The logs are set up in the runtime, that the trace exporter needs.
+    let mut logging_result = Ok(());
+    actix::run(async {
+        logging_result = infra::logging::init(&config);
+        if logging_result.is_err() {
+            return;
+        }
+        info!("Starting LLDAP....");
+        run_server(config.clone())
+            .unwrap_or_else(|e| error!("Could not bring up the servers: {:#}", e))
+            .await;
+        infra::logging::shutdown(&config).await;
+    })?;
+    logging_result?;
 
     info!("End.");
     Ok(())
@@ -102,7 +163,9 @@ fn run_server_command(opts: RunOpts) -> Result<()> {
 
 fn send_test_email_command(opts: TestEmailOpts) -> Result<()> {
     let to = opts.to.parse()?;
-    let config = infra::configuration::init(opts)?;
+    let mut config = infra::configuration::init(opts)?;
+    // There is nothing to trace, and no runtime for the exporter.
+    config.tracing_enabled = false;
     infra::logging::init(&config)?;
     mail::send_test_email(to, &config.smtp_options)
 }